@@ -0,0 +1,210 @@
+//! Workload analysis of captured traffic.
+//!
+//! `analyze` reads through the records of a `Capture` and tallies what its workload is made of:
+//! how often each command was sent, how many distinct keys were touched and which most often, how
+//! large keys and stored values are, and how deeply connections pipelined (how many requests
+//! each sent before reading a reply). `Analysis::report` renders the tallies as text, which is
+//! what `cabbage analyze` prints.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::{Duration, SystemTime};
+
+use tokio_util::bytes::Bytes;
+use uuid::Uuid;
+
+use crate::capture::{Direction, Record, RecordFilter};
+use crate::command;
+use crate::error::Result;
+
+/// Width of the widest bar drawn in a histogram
+static BAR_WIDTH: u64 = 40;
+
+/// Counts of sizes, in buckets doubling in width: 0, 1, 2-3, 4-7, ...
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    buckets: Vec<u64>,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, size: usize) {
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The lowest and highest size of each bucket, and how many sizes fell in it, smallest first
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, usize, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, &count)| match i {
+            0 => (0, 0, count),
+            i => (1 << (i - 1), (1 << i) - 1, count),
+        })
+    }
+
+    fn render(&self, report: &mut String) {
+        let most = self
+            .buckets
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        let total = self.count().max(1);
+        for (low, high, count) in self.buckets().skip_while(|&(_, _, count)| count == 0) {
+            let range = if low == high {
+                low.to_string()
+            } else {
+                format!("{low}-{high}")
+            };
+            let _ = writeln!(
+                report,
+                "  {range:>23} {count:>10} {:>5.1}% {}",
+                100.0 * count as f64 / total as f64,
+                "#".repeat((count * BAR_WIDTH).div_ceil(most) as usize)
+            );
+        }
+    }
+}
+
+/// The tallies of a capture's workload
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub requests: u64,
+    pub responses: u64,
+    /// When the first and last records picked were captured
+    pub first: Option<SystemTime>,
+    pub last: Option<SystemTime>,
+    /// Requests sent of each command
+    pub commands: HashMap<String, u64>,
+    /// Accesses of each key
+    pub keys: HashMap<Bytes, u64>,
+    /// Lengths of the keys accessed, each access counted
+    pub key_sizes: SizeHistogram,
+    /// Lengths of the values stored by `SET`-family commands
+    pub value_sizes: SizeHistogram,
+    /// Requests each connection sent in a row before a reply reached it
+    pub pipeline_depths: SizeHistogram,
+    /// Requests sent by each connection since it last received a reply
+    unanswered: HashMap<Uuid, usize>,
+}
+
+impl Analysis {
+    pub fn connections(&self) -> usize {
+        self.unanswered.len()
+    }
+
+    pub fn duration(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, record: &Record) {
+        self.first.get_or_insert(record.timestamp);
+        self.last = Some(record.timestamp);
+        let unanswered = self.unanswered.entry(record.connection_id).or_default();
+        match record.direction {
+            Direction::Request => {
+                self.requests += 1;
+                *unanswered += 1;
+                let req = &record.frame;
+                let name = command::name(req).unwrap_or_default();
+                *self.commands.entry(name).or_default() += 1;
+                for key in command::keys(req) {
+                    self.key_sizes.record(key.len());
+                    *self.keys.entry(key.clone()).or_default() += 1;
+                }
+                let args = command::args(req).unwrap_or_default();
+                for (value_at, _) in command::stored_values(req) {
+                    if let Some(value) = args.get(value_at).and_then(command::arg_bytes) {
+                        self.value_sizes.record(value.len());
+                    }
+                }
+            }
+            Direction::Response => {
+                self.responses += 1;
+                if *unanswered > 0 {
+                    self.pipeline_depths.record(*unanswered);
+                    *unanswered = 0;
+                }
+            }
+        }
+    }
+
+    /// Render the analysis as text, listing at most `top` commands and keys
+    pub fn report(&self, top: usize) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{} request(s) and {} response(s) from {} connection(s) over {:?}",
+            self.requests,
+            self.responses,
+            self.connections(),
+            self.duration()
+        );
+
+        let requests = self.requests.max(1) as f64;
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(report, "\nCommands ({} distinct):", commands.len());
+        for (name, &count) in commands.into_iter().take(top) {
+            let share = 100.0 * count as f64 / requests;
+            let _ = writeln!(report, "  {name:<24} {count:>10} {share:>5.1}%");
+        }
+
+        let accesses = self.key_sizes.count();
+        let _ = writeln!(
+            report,
+            "\nKeys: {} distinct across {accesses} access(es)",
+            self.keys.len()
+        );
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let _ = writeln!(report, "\nHottest keys:");
+        for (key, &count) in keys.into_iter().take(top) {
+            let _ = writeln!(
+                report,
+                "  {:<40} {count:>10} {:>5.1}%",
+                String::from_utf8_lossy(key),
+                100.0 * count as f64 / accesses.max(1) as f64
+            );
+        }
+
+        let _ = writeln!(report, "\nKey sizes (bytes):");
+        self.key_sizes.render(&mut report);
+        let _ = writeln!(report, "\nStored value sizes (bytes):");
+        self.value_sizes.render(&mut report);
+        let _ = writeln!(report, "\nPipeline depths (requests sent before a reply):");
+        self.pipeline_depths.render(&mut report);
+        report
+    }
+}
+
+/// Tally the records picked by `filter` from `records`
+pub fn analyze(
+    records: impl IntoIterator<Item = Result<Record>>,
+    filter: &mut RecordFilter,
+) -> Result<Analysis> {
+    let mut analysis = Analysis::default();
+    for record in records {
+        let record = record?;
+        if filter.picks(&record) {
+            analysis.record(&record);
+        }
+    }
+    // Requests still unanswered at the end of the capture were pipelined all the same
+    for unanswered in analysis.unanswered.values_mut() {
+        if *unanswered > 0 {
+            analysis.pipeline_depths.record(*unanswered);
+            *unanswered = 0;
+        }
+    }
+    Ok(analysis)
+}
@@ -0,0 +1,314 @@
+//! Tamper-evident audit logs.
+//!
+//! An `AuditLog` appends a JSON line to its file for every administrative or write command the
+//! proxy sees, naming the connection, client, and user it came from and how it was answered.
+//! Each line carries, as `prev`, the SHA-256 hash (in hex) of the line before it, or 64 zeros
+//! for the first, so altering, removing, or reordering any line breaks the chain at the line
+//! after it, which `verify` (and `cabbage verify-audit`) reports. Only the hash of the last line,
+//! the chain's head, can't be checked against the file itself; it's logged whenever the proxy
+//! stops writing, so a copy kept elsewhere shows the end of the log wasn't cut off or rewritten.
+//! A proxy restarted on an existing log carries its chain on.
+//!
+//! Records are handed to a dedicated writer thread, which chains them in the order they arrive
+//! and syncs them to disk in batches. Unlike captures, records are never dropped: if the disk
+//! falls behind, they queue up in memory.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead as _, BufReader, BufWriter, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use sha2::{Digest as _, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::error::{Error, Result, bail};
+
+/// How a command was answered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Error(String),
+    /// The client went away, or the proxy failed, before an answer came
+    NoReply,
+}
+
+/// A command to record
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    pub connection_id: Uuid,
+    /// The address the client connected from, if it has one
+    pub client: Option<String>,
+    /// The user the client had authenticated as, if any
+    pub user: Option<String>,
+    /// The command and its arguments, as recorded
+    pub command: Vec<String>,
+    pub outcome: Outcome,
+}
+
+impl AuditEntry {
+    /// This entry as the `seq`th line of a log, following a line hashing to `prev`
+    fn to_line(&self, seq: u64, prev: &str) -> String {
+        let (outcome, error) = match &self.outcome {
+            Outcome::Ok => ("ok", None),
+            Outcome::Error(message) => ("error", Some(message)),
+            Outcome::NoReply => ("no_reply", None),
+        };
+        json!({
+            "prev": prev,
+            "seq": seq,
+            "ts_us": self
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            "connection_id": self.connection_id.to_string(),
+            "client": self.client,
+            "user": self.user,
+            "command": self.command,
+            "outcome": outcome,
+            "error": error,
+        })
+        .to_string()
+    }
+}
+
+/// The end of a hash chain
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainHead {
+    /// Lines in the chain
+    pub records: u64,
+    /// The hash of the last line, in hex, or 64 zeros if there are none
+    pub hash: String,
+}
+
+impl Default for ChainHead {
+    fn default() -> Self {
+        Self {
+            records: 0,
+            hash: "0".repeat(64),
+        }
+    }
+}
+
+impl ChainHead {
+    fn push(&mut self, line: &str) {
+        let hash = Sha256::digest(line.as_bytes());
+        self.hash = hash.iter().map(|b| format!("{b:02x}")).collect();
+        self.records += 1;
+    }
+}
+
+/// A running audit log, shared by every connection being audited
+#[derive(Clone)]
+pub struct AuditLog {
+    entries: mpsc::UnboundedSender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Start appending to the audit log at `path`, carrying on the chain of any records it has
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path: PathBuf = path.into();
+        let head = match File::open(&path) {
+            Ok(file) => read_head(BufReader::new(file))
+                .map_err(|e| e.context(format!("Failed to read audit log {}", path.display())))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ChainHead::default(),
+            Err(e) => {
+                return Err(Error::io(
+                    format!("Failed to open audit log {}", path.display()),
+                    e,
+                ));
+            }
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::io(format!("Failed to open audit log {}", path.display()), e))?;
+        log::info!(
+            "Auditing to {}, following record {} (hash {})",
+            path.display(),
+            head.records,
+            head.hash
+        );
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("cabbage-audit".to_string())
+            .spawn(move || {
+                if let Err(e) = write_audit_log(&path, file, head, receiver) {
+                    log::error!("Audit log stopped: {e:#}");
+                }
+            })?;
+        Ok(Self { entries: sender })
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if self.entries.send(entry).is_err() {
+            log::error!("Audit log has stopped, so a command went unrecorded");
+        }
+    }
+}
+
+/// The head of the chain of lines read from `input`, without checking the chain
+fn read_head(input: impl std::io::BufRead) -> Result<ChainHead> {
+    let mut head = ChainHead::default();
+    for line in input.lines() {
+        head.push(&line?);
+    }
+    Ok(head)
+}
+
+/// Append entries to `file`, chained on from `head`, until every `AuditLog` is dropped
+fn write_audit_log(
+    path: &Path,
+    file: File,
+    mut head: ChainHead,
+    mut entries: mpsc::UnboundedReceiver<AuditEntry>,
+) -> Result<()> {
+    let mut out = BufWriter::new(file);
+    while let Some(entry) = entries.blocking_recv() {
+        let mut next = Some(entry);
+        while let Some(entry) = next {
+            let line = entry.to_line(head.records + 1, &head.hash);
+            out.write_all(line.as_bytes())?;
+            out.write_all(b"\n")?;
+            head.push(&line);
+            next = entries.try_recv().ok();
+        }
+        out.flush()?;
+        out.get_ref().sync_data()?;
+    }
+    log::info!(
+        "Audit log {} ends at record {} (hash {})",
+        path.display(),
+        head.records,
+        head.hash
+    );
+    Ok(())
+}
+
+/// Check the hash chain of the audit log at `path`, answering with its head if it's intact
+pub fn verify(path: impl AsRef<Path>) -> Result<ChainHead> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .map_err(|e| Error::io(format!("Failed to open audit log {}", path.display()), e))?;
+    let mut head = ChainHead::default();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.map_err(|e| Error::io(format!("Failed to read audit log {}", path.display()), e))?;
+        let number = i + 1;
+        let record: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| Error::Format(format!("Line {number} isn't an audit record: {e}")))?;
+        if record["prev"].as_str() != Some(head.hash.as_str()) {
+            bail!(
+                Format,
+                "Line {number} doesn't follow on from the line before it: the log was altered at \
+                 or before line {number}"
+            );
+        }
+        if record["seq"].as_u64() != Some(head.records + 1) {
+            bail!(Format, "Line {number} is numbered out of sequence");
+        }
+        head.push(&line);
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn entry(command: &[&str]) -> AuditEntry {
+        AuditEntry {
+            timestamp: SystemTime::now(),
+            connection_id: Uuid::new_v4(),
+            client: Some("127.0.0.1:56324".to_string()),
+            user: None,
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            outcome: Outcome::Ok,
+        }
+    }
+
+    /// The lines of a log of `commands`
+    fn chain(commands: &[&[&str]]) -> Vec<String> {
+        let mut head = ChainHead::default();
+        commands
+            .iter()
+            .map(|command| {
+                let line = entry(command).to_line(head.records + 1, &head.hash);
+                head.push(&line);
+                line
+            })
+            .collect()
+    }
+
+    fn verify_lines(lines: &[String]) -> Result<ChainHead> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), lines.join("\n") + "\n")?;
+        verify(file.path())
+    }
+
+    fn log() -> Vec<String> {
+        chain(&[&["SET", "a", "1"], &["SET", "b", "2"], &["DEL", "a"]])
+    }
+
+    #[test]
+    fn intact_logs_verify() -> Result<()> {
+        let lines = log();
+        let head = verify_lines(&lines)?;
+        assert_eq!(head, read_head(lines.join("\n").as_bytes())?);
+        assert_eq!(head.records, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn edited_records_break_the_chain() -> Result<()> {
+        let mut lines = log();
+        lines[1] = lines[1].replace(r#""SET""#, r#""GET""#);
+        assert!(verify_lines(&lines).is_err());
+
+        // The last record has no successor to catch it, but the head it leaves differs
+        let mut lines = log();
+        let head = verify_lines(&lines)?;
+        lines[2] = lines[2].replace(r#""DEL""#, r#""GET""#);
+        assert_ne!(verify_lines(&lines)?, head);
+        Ok(())
+    }
+
+    #[test]
+    fn removed_records_break_the_chain() {
+        for removed in [0, 1] {
+            let mut lines = log();
+            lines.remove(removed);
+            assert!(verify_lines(&lines).is_err(), "without line {removed}");
+        }
+    }
+
+    #[test]
+    fn reordered_records_break_the_chain() {
+        let mut lines = log();
+        lines.swap(0, 1);
+        assert!(verify_lines(&lines).is_err());
+    }
+
+    #[test]
+    fn reopened_logs_carry_the_chain_on() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("audit.log");
+        for (i, command) in [["SET", "a", "1"], ["SET", "b", "2"]].iter().enumerate() {
+            AuditLog::open(&path)?.record(entry(command));
+            // The writer thread finishes with the log once it's dropped
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while read_head(BufReader::new(File::open(&path)?))?.records <= i as u64 {
+                assert!(Instant::now() < deadline, "record {i} wasn't written");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(verify(&path)?.records, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+//! Balancing client connections across weighted targets.
+//!
+//! `BalancedTargets` holds targets which serve the same data, such as read replicas, each with a
+//! weight, and which of them are in rotation. `BalancedBackend` sends each client connection to
+//! a target picked from those in rotation by the `BalancePolicy`, and keeps to it, so
+//! connection-scoped state (transactions, `SELECT`, subscriptions) stays on one target. By
+//! default targets are picked at random in proportion to their weights; the other policies pick
+//! the least loaded target, by its open connections, its commands awaiting replies, or its
+//! recent latency, each divided by its weight, which evens out targets of differing capacity
+//! better than weights alone. Load is counted for each target as connections are made and
+//! commands answered.
+//!
+//! With `Stickiness`, clients instead go to a target chosen by hashing their address or user, so
+//! each client's connections all land on the same target, and keep to it across reconnections
+//! for as long as it stays in rotation. Targets are chosen by rendezvous hashing, weighted, so a
+//! target leaving or rejoining rotation only moves the clients hashed to it.
+//!
+//! Health checks take targets which fail out of rotation and put them back once they pass again;
+//! a failed connection to a target takes it out at once, and its client moves on to another. As
+//! with failover, reconnections switch to the database the client last selected.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash as _, Hasher as _};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures_util::StreamExt;
+use rand::Rng as _;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, bail};
+use crate::listener::ClientAddr;
+use crate::middleware::auth::{ClientUser, DEFAULT_USER};
+use crate::service::{Handshake, Resp2Backend, ResponseStream, SelectedDb};
+
+/// How much each latency sample moves a target's moving average
+static LATENCY_SMOOTHING: f64 = 0.2;
+
+/// How `BalancedTargets` picks a target for a new client connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BalancePolicy {
+    /// At random, in proportion to weight
+    #[default]
+    Weighted,
+    /// The fewest open client connections for its weight
+    Connections,
+    /// The fewest commands awaiting replies for its weight
+    Outstanding,
+    /// The lowest moving average of reply latency for its weight
+    Latency,
+}
+
+impl std::str::FromStr for BalancePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "weighted" => Ok(BalancePolicy::Weighted),
+            "connections" => Ok(BalancePolicy::Connections),
+            "outstanding" => Ok(BalancePolicy::Outstanding),
+            "latency" => Ok(BalancePolicy::Latency),
+            _ => bail!(Config, "Unrecognized balance policy '{s}'"),
+        }
+    }
+}
+
+/// What, if anything, ties a client to a balanced target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stickiness {
+    /// Nothing: each connection is balanced by the policy
+    #[default]
+    Off,
+    /// The client's IP address, ignoring its port (clients of Unix sockets aren't tied)
+    Client,
+    /// The user the client authenticated as, to the proxy or with its first command, or the
+    /// default user
+    User,
+}
+
+impl std::str::FromStr for Stickiness {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Stickiness::Off),
+            "client" => Ok(Stickiness::Client),
+            "user" => Ok(Stickiness::User),
+            _ => bail!(Config, "Unrecognized stickiness '{s}'"),
+        }
+    }
+}
+
+/// The user authenticated as by `req`, if it's an `AUTH`, or a `HELLO` with `AUTH`
+fn auth_user(req: &BytesFrame) -> Option<String> {
+    let args = command::args(req)?;
+    let user = match command::name(req)?.as_str() {
+        "AUTH" if args.len() == 2 => return Some(DEFAULT_USER.to_string()),
+        "AUTH" => args.get(1)?,
+        "HELLO" => {
+            let auth = args.iter().position(|arg| {
+                command::arg_bytes(arg).is_some_and(|a| a.eq_ignore_ascii_case(b"AUTH"))
+            })?;
+            args.get(auth + 1)?
+        }
+        _ => return None,
+    };
+    Some(String::from_utf8_lossy(command::arg_bytes(user)?).into_owned())
+}
+
+/// A target and its share of connections, parsed from `ADDRESS` or `ADDRESS=WEIGHT`, where the
+/// weight defaults to 1
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WeightedTarget {
+    address: String,
+    weight: u32,
+}
+
+impl WeightedTarget {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+}
+
+impl std::str::FromStr for WeightedTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, weight) = match s.rsplit_once('=') {
+            Some((address, weight)) => {
+                let weight = weight.trim().parse().map_err(|_| {
+                    Error::Config(format!("Target '{s}' should be ADDRESS or ADDRESS=WEIGHT"))
+                })?;
+                (address.trim(), weight)
+            }
+            None => (s.trim(), 1),
+        };
+        if address.is_empty() {
+            bail!(Config, "Target '{s}' should be ADDRESS or ADDRESS=WEIGHT");
+        }
+        if weight == 0 {
+            bail!(Config, "Target '{s}' needs a weight of at least 1");
+        }
+        Ok(Self {
+            address: address.to_string(),
+            weight,
+        })
+    }
+}
+
+impl TryFrom<String> for WeightedTarget {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The load on a target
+#[derive(Default)]
+struct TargetLoad {
+    connections: AtomicUsize,
+    outstanding: AtomicUsize,
+    /// Moving average of the time to a command's first reply, in microseconds, or 0 before the
+    /// first reply
+    latency_us: AtomicU64,
+}
+
+impl TargetLoad {
+    fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros() as f64;
+        let _ = self
+            .latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(if average == 0 {
+                    sample as u64
+                } else {
+                    (average as f64 + LATENCY_SMOOTHING * (sample - average as f64)) as u64
+                })
+            });
+    }
+}
+
+/// What a `Held` counts
+enum Holding {
+    Connection,
+    Command,
+}
+
+/// One of a target's connections or outstanding commands, counted until dropped
+struct Held {
+    load: Arc<TargetLoad>,
+    holding: Holding,
+}
+
+impl Held {
+    fn new(load: &Arc<TargetLoad>, holding: Holding) -> Self {
+        match holding {
+            Holding::Connection => load.connections.fetch_add(1, Ordering::Relaxed),
+            Holding::Command => load.outstanding.fetch_add(1, Ordering::Relaxed),
+        };
+        Self {
+            load: load.clone(),
+            holding,
+        }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        match self.holding {
+            Holding::Connection => self.load.connections.fetch_sub(1, Ordering::Relaxed),
+            Holding::Command => self.load.outstanding.fetch_sub(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// Weighted targets, and which are in rotation
+pub struct BalancedTargets {
+    targets: Vec<WeightedTarget>,
+    in_rotation: Vec<AtomicBool>,
+    load: Vec<Arc<TargetLoad>>,
+    policy: BalancePolicy,
+    stickiness: Stickiness,
+}
+
+impl BalancedTargets {
+    /// Balance across every one of `targets` until one fails
+    pub fn new(targets: Vec<WeightedTarget>) -> Self {
+        Self {
+            in_rotation: targets.iter().map(|_| AtomicBool::new(true)).collect(),
+            load: targets.iter().map(|_| Arc::default()).collect(),
+            targets,
+            policy: BalancePolicy::default(),
+            stickiness: Stickiness::default(),
+        }
+    }
+
+    /// Pick targets by `policy` rather than at random by weight
+    pub fn with_policy(mut self, policy: BalancePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Tie clients to targets by `stickiness`
+    pub fn with_stickiness(mut self, stickiness: Stickiness) -> Self {
+        self.stickiness = stickiness;
+        self
+    }
+
+    /// Every target's address, in rotation or not
+    pub fn addresses(&self) -> Vec<String> {
+        self.targets.iter().map(|t| t.address.clone()).collect()
+    }
+
+    fn set_in_rotation(&self, i: usize, in_rotation: bool) {
+        if self.in_rotation[i].swap(in_rotation, Ordering::Relaxed) != in_rotation {
+            let address = &self.targets[i].address;
+            if in_rotation {
+                log::info!("Returning target {address} to rotation");
+            } else {
+                log::warn!("Taking target {address} out of rotation");
+            }
+        }
+    }
+
+    /// Take `address` out of rotation after a connection to it failed
+    pub fn fail(&self, address: &str) {
+        if let Some(i) = self.targets.iter().position(|t| t.address == address) {
+            self.set_in_rotation(i, false);
+        }
+    }
+
+    /// Keep in rotation exactly the targets which just passed a health check
+    pub fn select(&self, healthy: &[String]) {
+        for (i, target) in self.targets.iter().enumerate() {
+            self.set_in_rotation(i, healthy.contains(&target.address));
+        }
+    }
+
+    /// Pick a target not among `tried`, from those in rotation if any are, by hashing `key` if
+    /// the client has one and by the policy otherwise
+    fn pick(&self, tried: &[usize], key: Option<&str>) -> Option<usize> {
+        let untried: Vec<usize> = (0..self.targets.len())
+            .filter(|i| !tried.contains(i))
+            .collect();
+        let in_rotation: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|&i| self.in_rotation[i].load(Ordering::Relaxed))
+            .collect();
+        // With every remaining target out of rotation, one may have recovered since its check
+        let candidates = if in_rotation.is_empty() {
+            untried
+        } else {
+            in_rotation
+        };
+        if let Some(key) = key {
+            return candidates
+                .into_iter()
+                .map(|i| (i, self.rendezvous_score(i, key)))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(i, _)| i);
+        }
+        if self.policy != BalancePolicy::Weighted {
+            // Ties go to the first of the least loaded, as loads are rarely equal for long
+            return candidates
+                .into_iter()
+                .map(|i| (i, self.load(i)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(i, _)| i);
+        }
+
+        let total: u64 = candidates
+            .iter()
+            .map(|&i| u64::from(self.targets[i].weight))
+            .sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        for i in candidates {
+            let weight = u64::from(self.targets[i].weight);
+            if point < weight {
+                return Some(i);
+            }
+            point -= weight;
+        }
+        None
+    }
+
+    /// How strongly `key` prefers target `i`: the highest scoring target is chosen, each in
+    /// proportion to its weight
+    fn rendezvous_score(&self, i: usize, key: &str) -> f64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.targets[i].address.hash(&mut hasher);
+        // Uniform in (0, 1), so its logarithm is negative
+        let unit = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        -f64::from(self.targets[i].weight) / unit.ln()
+    }
+
+    /// What ties a client to a target, if it's sticky
+    fn sticky_key(&self, client: &BalancedClient, req: &BytesFrame) -> Option<String> {
+        match self.stickiness {
+            Stickiness::Off => None,
+            Stickiness::Client => match client.addr.as_ref()? {
+                // IPv4 clients of a dual-stack listener hash alike whichever way they connect
+                ClientAddr::Tcp(addr) => Some(addr.ip().to_canonical().to_string()),
+                #[cfg(unix)]
+                ClientAddr::Unix(_) => None,
+                #[cfg(windows)]
+                ClientAddr::Pipe(_) => None,
+            },
+            Stickiness::User => Some(
+                client
+                    .user
+                    .get()
+                    .or_else(|| auth_user(req))
+                    .unwrap_or_else(|| DEFAULT_USER.to_string()),
+            ),
+        }
+    }
+
+    /// The load on target `i` as the policy measures it, for its weight
+    fn load(&self, i: usize) -> f64 {
+        let load = &self.load[i];
+        let value = match self.policy {
+            BalancePolicy::Weighted | BalancePolicy::Connections => {
+                load.connections.load(Ordering::Relaxed) as f64
+            }
+            BalancePolicy::Outstanding => load.outstanding.load(Ordering::Relaxed) as f64,
+            BalancePolicy::Latency => load.latency_us.load(Ordering::Relaxed) as f64,
+        };
+        value / f64::from(self.targets[i].weight)
+    }
+}
+
+/// A connection to a target, counted towards its load
+struct TargetConnection {
+    target: usize,
+    backend: Resp2Backend,
+    _held: Held,
+}
+
+/// Who a `BalancedBackend` is connected for
+#[derive(Clone, Default)]
+struct BalancedClient {
+    addr: Option<ClientAddr>,
+    user: ClientUser,
+}
+
+/// A backend which keeps each client connection on one of a `BalancedTargets`
+pub struct BalancedBackend {
+    targets: Arc<BalancedTargets>,
+    client: BalancedClient,
+    handshake: Arc<Handshake>,
+    selected: SelectedDb,
+    connection: Arc<tokio::sync::Mutex<Option<TargetConnection>>>,
+}
+
+impl BalancedBackend {
+    pub fn new(targets: Arc<BalancedTargets>) -> Self {
+        Self {
+            targets,
+            client: BalancedClient::default(),
+            handshake: Arc::new(Handshake::default()),
+            selected: SelectedDb::new(),
+            connection: Arc::new(tokio::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Perform `handshake` on every connection made to a target
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Arc::new(handshake);
+        self
+    }
+
+    /// Connect for the client at `addr` (if it has one) authenticating to the proxy as `user`,
+    /// which sticky targets are chosen by
+    pub fn with_client(mut self, addr: Option<ClientAddr>, user: ClientUser) -> Self {
+        self.client = BalancedClient { addr, user };
+        self
+    }
+}
+
+impl Service<BytesFrame> for BalancedBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let targets = self.targets.clone();
+        let client = self.client.clone();
+        let handshake = self.handshake.clone();
+        let selected = self.selected.clone();
+        let connection = self.connection.clone();
+
+        Box::pin(async move {
+            let mut connection = connection.lock().await;
+            // Each target is tried at most once for a request
+            let mut tried = vec![];
+            loop {
+                if connection.is_none() {
+                    let key = targets.sticky_key(&client, &req);
+                    let Some(i) = targets.pick(&tried, key.as_deref()) else {
+                        bail!(BackendUnavailable, "No target could be connected to");
+                    };
+                    tried.push(i);
+                    let address = targets.targets[i].address.clone();
+                    let handshake = selected.handshake(&handshake);
+                    match Resp2Backend::connect_with(&address, &handshake).await {
+                        Ok(backend) => {
+                            log::debug!("Connected to target at {address}");
+                            *connection = Some(TargetConnection {
+                                target: i,
+                                backend,
+                                _held: Held::new(&targets.load[i], Holding::Connection),
+                            });
+                        }
+                        Err(e) if tried.len() < targets.targets.len() => {
+                            log::warn!("Failed to connect to target at {address}: {e}");
+                            targets.fail(&address);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                let Some(TargetConnection {
+                    target, backend, ..
+                }) = connection.as_mut()
+                else {
+                    bail!(BackendUnavailable, "No connection to a target");
+                };
+                let target = *target;
+                let load = targets.load[target].clone();
+                let held = Held::new(&load, Holding::Command);
+                let started = Instant::now();
+                // A failed dispatch means the request never reached the target, so it is safe to
+                // resend it to another.
+                match backend.call(req.clone()).await {
+                    Ok(responses) => {
+                        let mut replied = false;
+                        let responses = responses
+                            .inspect(move |_| {
+                                // The command counts as outstanding until its replies are done
+                                let _ = &held;
+                                if !replied {
+                                    load.record_latency(started.elapsed());
+                                    replied = true;
+                                }
+                            })
+                            .boxed();
+                        return Ok(selected.track(&req, responses));
+                    }
+                    Err(e) if tried.len() < targets.targets.len() => {
+                        let address = &targets.targets[target].address;
+                        log::warn!("Connection to target at {address} failed: {e}");
+                        targets.fail(address);
+                        if !tried.contains(&target) {
+                            tried.push(target);
+                        }
+                        *connection = None;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,185 @@
+//! Load generation.
+//!
+//! `bench` drives a mix of `GET`s and `SET`s at a target over a number of connections, each
+//! keeping up to a pipeline's worth of requests in flight, and reports throughput and latency
+//! percentiles. Pointing it at a Redis server and then at a cabbage proxy in front of the same
+//! server shows what the proxy costs.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use hdrhistogram::Histogram;
+use rand::Rng as _;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::{Service as _, ServiceExt as _};
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::service::Resp2Backend;
+
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    /// Address of the target (or cabbage instance) to drive
+    pub target: String,
+    /// Concurrent connections
+    pub connections: usize,
+    /// Total requests to send, across every connection
+    pub requests: usize,
+    /// Requests each connection sends before waiting for their replies
+    pub pipeline: usize,
+    /// Fraction of requests which are `GET`s, the rest being `SET`s
+    pub get_ratio: f64,
+    /// Number of distinct keys, chosen uniformly at random
+    pub keys: u64,
+    /// Prepended to every key, keeping benchmark data apart from anything else on the target
+    pub key_prefix: String,
+    /// Size of each `SET` value in bytes
+    pub value_size: usize,
+}
+
+pub struct BenchSummary {
+    pub requests: usize,
+    /// Requests answered with an error reply
+    pub errors: usize,
+    /// Connections which failed before finishing, with the reason
+    pub failed: Vec<String>,
+    pub elapsed: Duration,
+    /// Time from sending each request to receiving its reply, in microseconds
+    pub latency: Histogram<u64>,
+}
+
+impl BenchSummary {
+    pub fn requests_per_sec(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        Duration::from_micros(self.latency.value_at_quantile(quantile))
+    }
+}
+
+/// Run the benchmark described by `options` to completion
+pub async fn bench(options: &BenchOptions) -> Result<BenchSummary> {
+    let value = Bytes::from(vec![b'x'; options.value_size]);
+    let issued = Arc::new(AtomicUsize::new(0));
+    let mut summary = BenchSummary {
+        requests: 0,
+        errors: 0,
+        failed: vec![],
+        elapsed: Duration::ZERO,
+        latency: Histogram::new(3).map_err(Error::other)?,
+    };
+    let started = Instant::now();
+    let workers = (0..options.connections.max(1))
+        .map(|_| {
+            let options = options.clone();
+            let value = value.clone();
+            let issued = issued.clone();
+            let latency = summary.latency.clone();
+            tokio::spawn(async move { run_connection(&options, &value, &issued, latency).await })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        let result = worker.await.map_err(Error::other)?;
+        summary.requests += result.requests;
+        summary.errors += result.errors;
+        summary.latency.add(&result.latency).map_err(Error::other)?;
+        if let Some(error) = result.error {
+            summary.failed.push(error);
+        }
+    }
+    summary.elapsed = started.elapsed();
+    Ok(summary)
+}
+
+struct ConnectionResult {
+    requests: usize,
+    errors: usize,
+    latency: Histogram<u64>,
+    error: Option<String>,
+}
+
+/// Send pipelines of requests over one connection until `issued` reaches the total
+async fn run_connection(
+    options: &BenchOptions,
+    value: &Bytes,
+    issued: &AtomicUsize,
+    latency: Histogram<u64>,
+) -> ConnectionResult {
+    let mut result = ConnectionResult {
+        requests: 0,
+        errors: 0,
+        latency,
+        error: None,
+    };
+    let mut backend = match Resp2Backend::connect(&options.target).await {
+        Ok(backend) => backend,
+        Err(e) => {
+            result.error = Some(format!("{e:#}"));
+            return result;
+        }
+    };
+
+    let pipeline = options.pipeline.max(1);
+    loop {
+        let first = issued.fetch_add(pipeline, Ordering::Relaxed);
+        if first >= options.requests {
+            if let Err(e) = backend.close().await {
+                log::debug!("Failed to close benchmark connection cleanly: {e:#}");
+            }
+            return result;
+        }
+        let batch = pipeline.min(options.requests - first);
+
+        let sent = Instant::now();
+        let mut replies = Vec::with_capacity(batch);
+        for _ in 0..batch {
+            let request = next_request(options, value);
+            let reply = match backend.ready().await {
+                Ok(backend) => backend.call(request).await,
+                Err(e) => Err(e),
+            };
+            match reply {
+                Ok(stream) => replies.push(stream),
+                Err(e) => {
+                    result.error = Some(format!("Failed to send request: {e:#}"));
+                    return result;
+                }
+            }
+        }
+        for mut reply in replies {
+            match reply.next().await {
+                Some(frame) => {
+                    result.requests += 1;
+                    if matches!(frame, BytesFrame::Error(_)) {
+                        result.errors += 1;
+                    }
+                    result
+                        .latency
+                        .saturating_record(sent.elapsed().as_micros() as u64);
+                }
+                None => {
+                    result.error = Some("Connection closed before replying".to_string());
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+fn next_request(options: &BenchOptions, value: &Bytes) -> BytesFrame {
+    let mut rng = rand::thread_rng();
+    let key = format!(
+        "{}{}",
+        options.key_prefix,
+        rng.gen_range(0..options.keys.max(1))
+    );
+    if rng.gen_bool(options.get_ratio.clamp(0.0, 1.0)) {
+        command::request([b"GET".as_slice(), key.as_bytes()])
+    } else {
+        command::request([b"SET".as_slice(), key.as_bytes(), value.as_ref()])
+    }
+}
@@ -1,11 +1,113 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context as _, Ok, Result, bail};
-use cabbage::proxy::handle_connection;
+use anyhow::{Context as _, Ok, Result, anyhow, bail};
+use cabbage::analyze;
+use cabbage::audit::{self, AuditLog};
+use cabbage::balance::{
+    BalancePolicy, BalancedBackend, BalancedTargets, Stickiness, WeightedTarget,
+};
+use cabbage::bench;
+use cabbage::capture::{Capture, CaptureReader, Direction, RecordFilter};
+use cabbage::check;
+use cabbage::cluster::{ClusterBackend, ClusterSlots};
+use cabbage::config::{
+    AuthConfig, CacheConfig, ChecksumConfig, CircuitBreakerConfig, ConcurrencyConfig, Config,
+    ConfigCommandConfig, EncryptionConfig, HealthCheckConfig, IsolationConfig, QuotaConfig,
+    RateLimitConfig, SaturationConfig, TargetConfig, ThrottleConfig,
+};
+use cabbage::export::{self, ScriptFormat};
+use cabbage::failover::{FailoverBackend, FailoverTargets};
+use cabbage::frame;
+use cabbage::health::{HealthTarget, check_health};
+use cabbage::listener::{ClientAddr, IpNetwork, Listener, is_pipe_address, is_unix_address};
+use cabbage::log_file::RotatingFile;
+use cabbage::middleware::acl::{Acl, AclLayer};
+use cabbage::middleware::admin::AdminLayer;
+use cabbage::middleware::audit::AuditLayer;
+use cabbage::middleware::auth::{ClientUser, Credentials, ProxyAuthLayer};
+use cabbage::middleware::breaker::{CircuitBreaker, CircuitBreakerLayer};
+use cabbage::middleware::cache::{CacheLayer, ReadCache, track_invalidations};
+use cabbage::middleware::canary::CanaryLayer;
+use cabbage::middleware::capture::CaptureLayer;
+use cabbage::middleware::chaos::{
+    DelayRule, ErrorInjection, ErrorInjectionLayer, ErrorRule, LatencyInjectionLayer,
+};
+use cabbage::middleware::checksum::ChecksumLayer;
+use cabbage::middleware::client_name::{ClientName, ClientNameLayer};
+use cabbage::middleware::command_cache::{CommandCacheLayer, CommandReplies};
+use cabbage::middleware::compress::{CompressionLayer, CompressionRule};
+use cabbage::middleware::concurrency::{
+    CommandConcurrency, ConcurrencyLayer, ConcurrencyLimit, ConcurrencyMode,
+};
+use cabbage::middleware::config_command::{ConfigCommandLayer, ConfigOverride, ConfigView};
+use cabbage::middleware::encrypt::EncryptionLayer;
+use cabbage::middleware::fair::FairLayer;
+use cabbage::middleware::fanout::{SubscriptionFanOutLayer, SubscriptionHub};
+use cabbage::middleware::filter::{CommandFilterLayer, CommandRules};
+use cabbage::middleware::health::HealthGateLayer;
+use cabbage::middleware::hotkeys::HotKeyLayer;
+use cabbage::middleware::isolate::{Isolation, IsolationLayer, IsolationMode};
+use cabbage::middleware::keyspace::KeySpaceLayer;
+use cabbage::middleware::latency::LatencyLayer;
+use cabbage::middleware::legacy::LegacyCommandLayer;
+use cabbage::middleware::limits::{RequestLimits, SizeLimitLayer};
+use cabbage::middleware::maintenance::{Maintenance, MaintenanceLayer};
+use cabbage::middleware::mirror::MirrorLayer;
+use cabbage::middleware::monitor::MonitorLayer;
+use cabbage::middleware::notifications::{KeyspaceNotificationLayer, NotificationSource};
+use cabbage::middleware::prefix::KeyPrefixLayer;
+use cabbage::middleware::quota::{QuotaKey, Quotas};
+use cabbage::middleware::ratelimit::{RateLimitLayer, RateLimitMode, RateLimits};
+use cabbage::middleware::read_only::{ReadOnlyLayer, ReadOnlyMode};
+use cabbage::middleware::redact::{RedactionRule, RedactionRules};
+use cabbage::middleware::retry::RetryLayer;
+use cabbage::middleware::rewrite::{RewriteLayer, RewriteRule};
+use cabbage::middleware::saturation::Saturation;
+use cabbage::middleware::script_cache::{LoadedScripts, ScriptCacheLayer};
+use cabbage::middleware::slowlog::SlowlogLayer;
+use cabbage::middleware::stack::{DEFAULT_STACK, StackLayer};
+use cabbage::middleware::throttle::{ThrottleLayer, ThrottleScope};
+use cabbage::middleware::timeout::TimeoutLayer;
+use cabbage::middleware::transcript::{TranscriptLayer, Transcripts};
+use cabbage::middleware::ttl::{TtlPolicy, TtlPolicyLayer};
+use cabbage::middleware::{
+    CommandLogLevels, LogFormat, LogLevelRule, LogSampleRule, LogSampling, LogTruncation,
+    ProxyLoggerLayer,
+};
+use cabbage::pool::{PoolOverflow, PoolTarget, TargetPool};
+use cabbage::proxy::{
+    Frontend, OutputBufferLimit, OverflowPolicy, ServeOptions, SlowClientPolicy, bind_reuseport,
+    relay_listeners, reload_on_signal, serve_listeners, shutdown_on_signal,
+};
+use cabbage::replay;
+use cabbage::routing::{KeyRouteRule, RouteRule, Routes, RoutingBackend};
+use cabbage::sentinel::{SentinelBackend, SentinelMaster};
+use cabbage::service::{
+    Handshake, LazyBackend, ProxyService, QueueCapacities, Resp2Backend, ResponseStream,
+};
+use cabbage::slo::{LatencySlo, SloMonitor, Webhook};
+use cabbage::stats::{self, Stats, StatsSnapshot};
+use cabbage::statsd::StatsdExporter;
+use cabbage::top::Top;
 use clap::Parser;
-use tokio::net::TcpListener;
+use futures::FutureExt as _;
+use tokio::sync::{Notify, watch};
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tokio_util::sync::CancellationToken;
+use tower::Layer as _;
+use tower::retry::budget::TpsBudget;
 use uuid::Uuid;
 
+/// Period over which the retry budget weighs retries against overall traffic
+static RETRY_BUDGET_WINDOW: Duration = Duration::from_secs(10);
+/// Retries always allowed per second, however little traffic there is
+static RETRY_BUDGET_MIN_PER_SEC: u32 = 10;
+
 #[derive(clap::Parser, Debug)]
 #[command(author, version, about, long_about = None, arg_required_else_help = true)]
 struct Args {
@@ -15,9 +117,17 @@ struct Args {
     /// Log level pairs of the form <MODULE>:<LEVEL>.
     #[arg(long)]
     log_levels: Option<Vec<String>>,
+
+    /// Log output format: "text", or "json" for one JSON object per line, each proxied frame
+    /// described by its own [default: text]
+    #[arg(long)]
+    log_format: Option<LogFormat>,
 }
 
-struct GlobalOptions {}
+struct GlobalOptions {
+    log_levels: Option<Vec<String>>,
+    log_format: Option<LogFormat>,
+}
 
 #[derive(clap::Parser, Debug)]
 struct HaikuOptions {
@@ -27,7 +137,499 @@ struct HaikuOptions {
 }
 
 async fn haiku(_context: &GlobalOptions, options: &HaikuOptions) -> anyhow::Result<()> {
-    cabbage::print_haiku(options.all)
+    cabbage::print_haiku(options.all);
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct ReplayOptions {
+    /// Capture files to replay, in order
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Address of the target to replay against
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    target: String,
+
+    /// Replay at most this many recorded client connections at once
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// Compare each reply with the recorded one and report where they differ
+    #[arg(long)]
+    compare: bool,
+
+    /// List at most this many differences
+    #[arg(long, default_value_t = 20)]
+    max_mismatches: usize,
+}
+
+async fn replay(_context: &GlobalOptions, options: &ReplayOptions) -> anyhow::Result<()> {
+    let readers = options
+        .files
+        .iter()
+        .map(CaptureReader::open)
+        .collect::<cabbage::Result<Vec<_>>>()?;
+    let sessions = replay::sessions(readers.into_iter().flatten())?;
+    let summary = replay::replay(
+        sessions,
+        &replay::ReplayOptions {
+            target: options.target.clone(),
+            concurrency: options.concurrency,
+            compare: options.compare,
+        },
+    )
+    .await;
+
+    println!(
+        "Replayed {} command(s) from {} connection(s) in {:?} ({:.0} commands/s)",
+        summary.commands,
+        summary.connections,
+        summary.elapsed,
+        summary.commands as f64 / summary.elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    if summary.skipped > 0 {
+        println!(
+            "Skipped {} command(s) sent after subscribing",
+            summary.skipped
+        );
+    }
+    if !summary.failed.is_empty() {
+        println!("{} connection(s) failed:", summary.failed.len());
+        for (connection_id, error) in &summary.failed {
+            println!("  {connection_id}: {error}");
+        }
+    }
+    if options.compare {
+        println!(
+            "Compared {} replies, {} differed (recorded vs replayed):",
+            summary.compared,
+            summary.mismatches.len()
+        );
+        for mismatch in summary.mismatches.iter().take(options.max_mismatches) {
+            println!(
+                "  {} #{} {}: {}",
+                mismatch.connection_id, mismatch.index, mismatch.command, mismatch.summary
+            );
+        }
+        if summary.mismatches.len() > options.max_mismatches {
+            println!(
+                "  ... and {} more",
+                summary.mismatches.len() - options.max_mismatches
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Which of a capture's records to take
+#[derive(clap::Args, Debug)]
+struct CaptureFilterOptions {
+    /// Only take the traffic of this connection (may be repeated)
+    #[arg(long)]
+    connection: Vec<Uuid>,
+
+    /// Only take these commands, and their replies (may be repeated)
+    #[arg(long)]
+    command: Vec<String>,
+
+    /// Only take traffic from this time on, in seconds since the Unix epoch
+    #[arg(long)]
+    since: Option<f64>,
+
+    /// Only take traffic up to this time, in seconds since the Unix epoch
+    #[arg(long)]
+    until: Option<f64>,
+}
+
+impl CaptureFilterOptions {
+    fn filter(&self) -> Result<RecordFilter> {
+        Ok(RecordFilter::new()
+            .with_connections(self.connection.iter().copied())
+            .with_commands(&self.command)
+            .with_time_range(
+                self.since.map(unix_time).transpose()?,
+                self.until.map(unix_time).transpose()?,
+            ))
+    }
+}
+
+/// `secs` seconds after the Unix epoch
+fn unix_time(secs: f64) -> Result<SystemTime> {
+    let since_epoch = Duration::try_from_secs_f64(secs)
+        .map_err(|_| anyhow!("{secs} isn't a time in seconds since the Unix epoch"))?;
+    Ok(UNIX_EPOCH + since_epoch)
+}
+
+#[derive(clap::Parser, Debug)]
+struct InspectOptions {
+    /// Capture files to print, in order
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    filter: CaptureFilterOptions,
+}
+
+/// Print the records of capture files picked out by `options`, one per line: the time, the
+/// connection, `>` for a request or `<` for a reply, then the frame
+fn inspect(_context: &GlobalOptions, options: &InspectOptions) -> anyhow::Result<()> {
+    let mut filter = options.filter.filter()?;
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let mut line = BytesMut::new();
+    for path in &options.files {
+        for record in CaptureReader::open(path)? {
+            let record = record.with_context(|| format!("Failed to read {}", path.display()))?;
+            if !filter.picks(&record) {
+                continue;
+            }
+            let at = record
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            line.clear();
+            line.put_slice(
+                format!(
+                    "{}.{:06} {} ",
+                    at.as_secs(),
+                    at.subsec_micros(),
+                    record.connection_id
+                )
+                .as_bytes(),
+            );
+            match record.direction {
+                Direction::Request => {
+                    line.put_slice(b"> ");
+                    frame::render_command(&record.frame, &mut line);
+                }
+                Direction::Response => {
+                    line.put_slice(b"< ");
+                    frame::render(&record.frame, &mut line);
+                }
+            }
+            line.put_u8(b'\n');
+            out.write_all(&line)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct ExportOptions {
+    /// Capture files to export, in order
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// "commands" for a redis-cli script, one command per line, or "resp" for
+    /// `redis-cli --pipe`
+    #[arg(long, default_value = "commands")]
+    format: ScriptFormat,
+
+    /// Write the script to this file rather than standard output
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    filter: CaptureFilterOptions,
+}
+
+/// Write the requests of capture files out as a script for redis-cli
+fn export_script(_context: &GlobalOptions, options: &ExportOptions) -> anyhow::Result<()> {
+    let mut filter = options.filter.filter()?;
+    let readers = options
+        .files
+        .iter()
+        .map(CaptureReader::open)
+        .collect::<cabbage::Result<Vec<_>>>()?;
+    let records = readers.into_iter().flatten();
+    let summary = match &options.output {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            export::export(
+                records,
+                &mut filter,
+                options.format,
+                &mut std::io::BufWriter::new(file),
+            )?
+        }
+        None => export::export(
+            records,
+            &mut filter,
+            options.format,
+            &mut std::io::BufWriter::new(std::io::stdout().lock()),
+        )?,
+    };
+    // The script may be going to standard output
+    eprintln!("Exported {} command(s)", summary.exported);
+    if summary.skipped > 0 {
+        eprintln!(
+            "Skipped {} command(s) which would have stopped the script (QUIT, or subscribing \
+             and what followed)",
+            summary.skipped
+        );
+    }
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct AnalyzeOptions {
+    /// Capture files to analyze, in order
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// List this many of the most sent commands and most accessed keys
+    #[arg(long, default_value_t = 20)]
+    top: usize,
+
+    #[command(flatten)]
+    filter: CaptureFilterOptions,
+}
+
+/// Report what the workload recorded in capture files is made of
+fn analyze_captures(_context: &GlobalOptions, options: &AnalyzeOptions) -> anyhow::Result<()> {
+    let mut filter = options.filter.filter()?;
+    let readers = options
+        .files
+        .iter()
+        .map(CaptureReader::open)
+        .collect::<cabbage::Result<Vec<_>>>()?;
+    let analysis = analyze::analyze(readers.into_iter().flatten(), &mut filter)?;
+    print!("{}", analysis.report(options.top));
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct VerifyAuditOptions {
+    /// Audit log to check
+    file: PathBuf,
+}
+
+fn verify_audit(_context: &GlobalOptions, options: &VerifyAuditOptions) -> anyhow::Result<()> {
+    let head = audit::verify(&options.file)?;
+    println!(
+        "{}: {} record(s), chain intact, head {}",
+        options.file.display(),
+        head.records,
+        head.hash
+    );
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct BenchOptions {
+    /// Address to drive: a Redis server, or a cabbage instance in front of one
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    target: String,
+
+    /// Concurrent connections
+    #[arg(long, default_value_t = 50)]
+    connections: usize,
+
+    /// Total requests to send
+    #[arg(long, default_value_t = 100_000)]
+    requests: usize,
+
+    /// Requests each connection sends before waiting for their replies
+    #[arg(long, default_value_t = 1)]
+    pipeline: usize,
+
+    /// Fraction of requests which are GETs, the rest being SETs
+    #[arg(long, default_value_t = 0.8)]
+    get_ratio: f64,
+
+    /// Number of distinct keys
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+
+    /// Prepended to every key
+    #[arg(long, default_value = "cabbage-bench:")]
+    key_prefix: String,
+
+    /// Size of each SET value in bytes
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+}
+
+async fn bench(_context: &GlobalOptions, options: &BenchOptions) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&options.get_ratio) {
+        bail!("--get-ratio must be between 0 and 1");
+    }
+    let summary = bench::bench(&bench::BenchOptions {
+        target: options.target.clone(),
+        connections: options.connections,
+        requests: options.requests,
+        pipeline: options.pipeline,
+        get_ratio: options.get_ratio,
+        keys: options.keys,
+        key_prefix: options.key_prefix.clone(),
+        value_size: options.value_size,
+    })
+    .await?;
+
+    println!(
+        "Sent {} request(s) over {} connection(s) in {:?} ({:.0} requests/s)",
+        summary.requests,
+        options.connections,
+        summary.elapsed,
+        summary.requests_per_sec()
+    );
+    if summary.errors > 0 {
+        println!("{} request(s) got an error reply", summary.errors);
+    }
+    if !summary.failed.is_empty() {
+        println!("{} connection(s) failed:", summary.failed.len());
+        for error in &summary.failed {
+            println!("  {error}");
+        }
+    }
+    println!(
+        "Latency p50 {:?}, p95 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+        summary.percentile(0.50),
+        summary.percentile(0.95),
+        summary.percentile(0.99),
+        summary.percentile(0.999),
+        summary.percentile(1.0)
+    );
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct CheckOptions {
+    /// Configuration file whose target to check [default: 127.0.0.1:6379, with no handshake]
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Check this tenant's target rather than the main one
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// Check this address rather than those configured, still with the configured handshake
+    #[arg(long)]
+    target: Option<String>,
+
+    /// PINGs timed at each address
+    #[arg(long, default_value_t = 5)]
+    samples: usize,
+
+    /// Fail an address not checked within this many milliseconds, connecting included
+    #[arg(long, default_value_t = 5000)]
+    timeout_ms: u64,
+}
+
+/// The addresses the proxy would connect to for `target`: the seed node of a cluster, the master
+/// Sentinel currently names, or every failover or balanced target
+async fn check_addresses(target: &TargetConfig) -> Result<Vec<String>> {
+    if let Some(master_name) = &target.master_name {
+        let master = SentinelMaster::new(target.sentinels.clone(), master_name.clone());
+        let address = master
+            .resolve()
+            .await
+            .context("Failed to resolve master through sentinel")?;
+        return Ok(vec![address]);
+    }
+    if !target.balance.is_empty() {
+        return Ok(target
+            .balance
+            .iter()
+            .map(|t| t.address().to_string())
+            .collect());
+    }
+    Ok(std::iter::once(&target.address)
+        .chain(&target.failover)
+        .cloned()
+        .collect())
+}
+
+async fn check_target(_context: &GlobalOptions, options: &CheckOptions) -> anyhow::Result<()> {
+    let mut config = match &options.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(tenant) = &options.tenant {
+        config = config.tenant(tenant)?;
+    }
+    config.validate()?;
+    let addresses = match &options.target {
+        Some(address) => vec![address.clone()],
+        None => check_addresses(&config.target).await?,
+    };
+    let check_options = check::CheckOptions {
+        samples: options.samples,
+        timeout: Duration::from_millis(options.timeout_ms),
+    };
+
+    let handshake = config.target.handshake();
+    let mut failed = 0;
+    for address in &addresses {
+        match check::check(address, &handshake, &check_options).await {
+            Result::Ok(summary) => println!(
+                "{address}: ok, {} ({}), round trip min {:?}, mean {:?}, max {:?} over {} PING(s)",
+                summary.version.as_deref().unwrap_or("unknown version"),
+                summary.role.as_deref().unwrap_or("unknown role"),
+                summary.min_round_trip(),
+                summary.mean_round_trip(),
+                summary.max_round_trip(),
+                summary.round_trips.len()
+            ),
+            Err(e) => {
+                println!("{address}: failed: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of {} address(es) failed their check",
+            addresses.len()
+        );
+    }
+    Ok(())
+}
+
+#[derive(clap::Parser, Debug)]
+struct TopOptions {
+    /// Address of the cabbage instance to watch
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    target: String,
+
+    /// Authenticate to the proxy as this user
+    #[arg(long)]
+    username: Option<String>,
+
+    /// Authenticate to the proxy with this password
+    #[arg(long)]
+    password: Option<String>,
+
+    /// Milliseconds between refreshes
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+
+    /// Rows shown in each table
+    #[arg(long, default_value_t = 10)]
+    rows: usize,
+}
+
+async fn top(_context: &GlobalOptions, options: &TopOptions) -> anyhow::Result<()> {
+    let handshake = Handshake {
+        username: options.username.clone(),
+        password: options.password.clone(),
+        ..Handshake::default()
+    };
+    let mut top = Top::connect(&options.target, &handshake, options.rows)
+        .await
+        .with_context(|| format!("Failed to connect to {}", options.target))?;
+    let mut ticker = tokio::time::interval(Duration::from_millis(options.interval_ms.max(1)));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut out = std::io::stdout();
+    loop {
+        ticker.tick().await;
+        let screen = top.poll().await?;
+        // Clear the terminal and draw from its top left, so each poll replaces the last
+        write!(out, "\x1b[2J\x1b[H{}", screen)?;
+        out.flush()?;
+    }
 }
 
 /// Convert a series of <MODULE>:<LEVEL> pairs into actionable `(module, LevelFilter)` pairs
@@ -54,96 +656,2586 @@ fn as_level_pairs(config: &[String]) -> Result<Vec<(&str, simplelog::LevelFilter
     Ok(pairs)
 }
 
+/// The default log levels, overridden by any <MODULE>:<LEVEL> pairs in `levels`
+fn log_levels(levels: Option<&Vec<String>>) -> Result<Vec<(&str, simplelog::LevelFilter)>> {
+    let mut log_levels = BTreeMap::from([
+        ("cabbage", simplelog::LevelFilter::Debug),
+        // If compiled via Bazel, "cabbage" binary crate module will be named "bin"
+        //("bin", simplelog::LevelFilter::Debug),
+    ]);
+
+    for (module, level) in levels
+        .map(|levels| as_level_pairs(levels))
+        .unwrap_or(Ok(vec![]))
+        .context("Log level override parsing failed")?
+    {
+        log_levels.insert(module, level);
+    }
+    Ok(log_levels.into_iter().collect())
+}
+
+/// The process's logger, which is swapped out when log levels are reloaded
+struct ReloadableLogger(RwLock<Option<Box<simplelog::CombinedLogger>>>);
+
+static LOGGER: ReloadableLogger = ReloadableLogger(RwLock::new(None));
+
+/// The file the log is also written to, if any, kept open across reloads
+static LOG_FILE: OnceLock<RotatingFile> = OnceLock::new();
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0
+            .read()
+            .is_ok_and(|logger| logger.as_ref().is_some_and(|l| l.enabled(metadata)))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Result::Ok(logger) = self.0.read()
+            && let Some(logger) = logger.as_ref()
+        {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Result::Ok(logger) = self.0.read()
+            && let Some(logger) = logger.as_ref()
+        {
+            logger.flush();
+        }
+    }
+}
+
+/// Writes each record of a module, at up to a level, as one JSON object per line: `ts`
+/// (milliseconds since the Unix epoch), `level`, `target`, and `message`. Records which are
+/// already JSON objects, as `ProxyLogger` writes in the JSON format, have `level` and `target`
+/// added to their own fields instead.
+struct JsonLogger {
+    module: String,
+    level: simplelog::LevelFilter,
+    file: Option<RotatingFile>,
+    /// Whether to write to stdout and stderr, as well as `file`
+    terminal: bool,
+}
+
+impl JsonLogger {
+    fn render(record: &log::Record) -> String {
+        let message = record.args().to_string();
+        let mut object = match serde_json::from_str(&message) {
+            Result::Ok(serde_json::Value::Object(object)) => object,
+            _ => {
+                let ts = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or_default();
+                serde_json::Map::from_iter([
+                    ("ts".to_string(), ts.into()),
+                    ("message".to_string(), message.into()),
+                ])
+            }
+        };
+        object.insert("level".to_string(), record.level().as_str().into());
+        object.insert("target".to_string(), record.target().into());
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with(&self.module)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = Self::render(record);
+        if let Some(file) = &self.file {
+            let _ = writeln!(file.clone(), "{line}");
+        }
+        if !self.terminal {
+            return;
+        }
+        // As simplelog's mixed terminal mode, errors and warnings go to stderr
+        if record.level() <= log::Level::Warn {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+        if let Some(file) = &self.file {
+            let _ = file.clone().flush();
+        }
+    }
+}
+
+impl simplelog::SharedLogger for JsonLogger {
+    fn level(&self) -> simplelog::LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
+
+/// Install the process's logger or, if it's already installed, replace its filters
 fn initialize_logging(
     module_path_filters: &[(&str, simplelog::LevelFilter)],
+    format: LogFormat,
 ) -> anyhow::Result<()> {
-    simplelog::CombinedLogger::init(
+    let mut loggers: Vec<Box<dyn simplelog::SharedLogger>> = vec![];
+    // A daemon has no terminal, and its stderr may be the log file itself
+    let terminal = !is_daemon();
+    for (module_path_filter, level) in module_path_filters {
+        if format == LogFormat::Json {
+            loggers.push(Box::new(JsonLogger {
+                module: module_path_filter.to_string(),
+                level: *level,
+                file: LOG_FILE.get().cloned(),
+                terminal,
+            }));
+            continue;
+        }
+        let config = simplelog::ConfigBuilder::new()
+            .add_filter_allow(module_path_filter.to_string())
+            .build();
+        if let Some(file) = LOG_FILE.get() {
+            loggers.push(simplelog::WriteLogger::new(
+                *level,
+                config.clone(),
+                file.clone(),
+            ));
+        }
+        if terminal {
+            loggers.push(simplelog::TermLogger::new(
+                *level,
+                config,
+                simplelog::TerminalMode::Mixed,
+                simplelog::ColorChoice::Auto,
+            ));
+        }
+    }
+    let logger = simplelog::CombinedLogger::new(loggers);
+    *LOGGER
+        .0
+        .write()
+        .map_err(|_| anyhow!("Logger lock poisoned"))? = Some(logger);
+    log::set_max_level(
         module_path_filters
             .iter()
-            .map(|(module_path_filter, level)| {
-                simplelog::TermLogger::new(
-                    *level,
-                    simplelog::ConfigBuilder::new()
-                        .add_filter_allow(module_path_filter.to_string())
-                        .build(),
-                    simplelog::TerminalMode::Mixed,
-                    simplelog::ColorChoice::Auto,
-                ) as Box<dyn simplelog::SharedLogger>
-            })
-            .collect(),
-    )
-    .map_err(|e| e.into())
+            .map(|(_, level)| *level)
+            .max()
+            .unwrap_or(simplelog::LevelFilter::Off),
+    );
+    // Only the first call installs the logger; later ones have already swapped it above
+    let _ = log::set_logger(&LOGGER);
+    Ok(())
 }
 
+/// Flags for `cabbage proxy`. Each overrides the corresponding setting of the `--config` file,
+/// if any; unset flags and settings take the defaults noted.
 #[derive(clap::Parser, Debug)]
 struct ProxyOptions {
-    #[arg(long, default_value = "127.0.0.1:5000")]
-    client: String,
+    /// TOML or YAML configuration file
+    #[arg(long)]
+    config: Option<PathBuf>,
 
-    #[arg(long, default_value = "127.0.0.1:6379")]
-    target: String,
-}
+    /// Address accepting client connections, unix:<PATH> for a Unix domain socket, or
+    /// pipe:<NAME> for a Windows named pipe; repeat to listen on several [default: 127.0.0.1:5000]
+    #[arg(long)]
+    client: Vec<String>,
 
-async fn proxy(_context: &GlobalOptions, options: &ProxyOptions) -> anyhow::Result<()> {
-    let client_listener = TcpListener::bind(options.client.clone()).await?;
+    /// Address of the target [default: 127.0.0.1:6379]
+    #[arg(long)]
+    target: Option<String>,
 
-    log::info!(
-        "Proxy listening on {} -> {}",
-        options.client,
-        options.target
-    );
+    /// Treat the target as a seed node of a Redis Cluster, following MOVED/ASK redirects
+    #[arg(long)]
+    cluster: bool,
 
-    loop {
-        let (client_socket, client_addr) = client_listener.accept().await?;
-        let target_addr = options.target.clone();
+    /// Sentinel address used to discover the target master (may be repeated)
+    #[arg(long)]
+    sentinel: Vec<String>,
 
-        let connection_id = Uuid::new_v4();
-        log::info!("New connection from {client_addr} (ID#{connection_id})");
+    /// Name of the Sentinel-managed master to proxy to
+    #[arg(long)]
+    master_name: Option<String>,
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(client_socket, target_addr, connection_id).await {
-                log::error!("Connection error: {}", e);
-            }
-        });
-    }
-}
+    /// Target failed over to, in order, while the target (and any given before it) is down,
+    /// and failed back from once they recover (may be repeated; requires a health check)
+    #[arg(long)]
+    failover_target: Vec<String>,
 
-#[derive(clap::Subcommand, Debug)]
-enum Command {
-    /// Print a random haiku
-    Haiku(HaikuOptions),
-    Proxy(ProxyOptions),
-}
+    /// Spread client connections across this target, by its weight, instead of --target (may be
+    /// repeated; ADDRESS or ADDRESS=WEIGHT, where the weight defaults to 1; requires a health
+    /// check)
+    #[arg(long)]
+    balance_target: Vec<WeightedTarget>,
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let levels_arg: Option<Vec<String>> = args.log_levels;
-
-    let log_levels: Vec<(&str, simplelog::LevelFilter)> = {
-        let mut log_levels = BTreeMap::from([
-            ("cabbage", simplelog::LevelFilter::Debug),
-            // If compiled via Bazel, "cabbage" binary crate module will be named "bin"
-            //("bin", simplelog::LevelFilter::Debug),
-        ]);
-
-        for (module, level) in levels_arg
-            .as_deref()
-            .map(as_level_pairs)
-            .unwrap_or(Ok(vec![]))
-            .context("Log level override parsing failed")?
-        {
-            log_levels.insert(module, level);
-        }
-        log_levels.into_iter().collect()
-    };
-    let _ = initialize_logging(&log_levels[..]);
-    log::trace!("Logging initialized, commands parsed...");
+    /// How each client connection's --balance-target is picked: weighted (at random, by
+    /// weight), connections (fewest open connections), outstanding (fewest commands awaiting
+    /// replies), or latency (lowest recent latency), the last three relative to weight
+    /// [default: weighted]
+    #[arg(long)]
+    balance_policy: Option<BalancePolicy>,
 
-    let context = GlobalOptions {};
-    match args.command {
-        Command::Haiku(options) => haiku(&context, &options).await?,
-        Command::Proxy(options) => proxy(&context, &options).await?,
+    /// Send every connection of a client to the same --balance-target, chosen by hashing its IP
+    /// address (client) or the user it authenticates as (user), for as long as that target
+    /// stays healthy (off, client, or user) [default: off]
+    #[arg(long)]
+    balance_stickiness: Option<Stickiness>,
+
+    /// Keep this many target connections connected and handshaken ahead of clients, making them
+    /// before accepting any [default: 0]
+    #[arg(long)]
+    target_pool_min_size: Option<usize>,
+
+    /// Keep no more than this many target connections open, whether handed to clients or ready
+    #[arg(long)]
+    target_pool_max_size: Option<usize>,
+
+    /// How long a client queues for a target connection once --target-pool-max-size are open
+    /// [default: 1000]
+    #[arg(long)]
+    target_pool_max_wait_ms: Option<u64>,
+
+    /// What a client gets once --target-pool-max-size target connections are open: a wait for
+    /// one to close (queue), a connection beyond the limit (temporary), or an error at once
+    /// (fail) [default: queue]
+    #[arg(long)]
+    target_pool_overflow: Option<PoolOverflow>,
+
+    /// On SIGTERM/SIGINT or CABBAGE.DRAIN, wait up to this many seconds for in-flight commands
+    /// to complete [default: 30]
+    #[arg(long)]
+    drain_timeout_secs: Option<u64>,
+
+    /// Serve at most this many clients at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Whether clients beyond --max-connections wait to be accepted (hold) or get an error
+    /// (reject) [default: hold]
+    #[arg(long)]
+    connection_overflow: Option<OverflowPolicy>,
+
+    /// Close client connections which have been idle for this many seconds
+    #[arg(long)]
+    client_idle_timeout_secs: Option<u64>,
+
+    /// Close client connections (and their target connections) once they've been open for
+    /// about this many seconds, as soon as no replies are outstanding, so clients reconnect
+    #[arg(long)]
+    connection_max_lifetime_secs: Option<u64>,
+
+    /// Deal with a client as soon as more than this many bytes of replies wait for it to read
+    /// them, like Redis's client-output-buffer-limit hard limit
+    #[arg(long)]
+    client_output_buffer_hard_bytes: Option<usize>,
+
+    /// Deal with a client once more than this many bytes of replies have waited for it for
+    /// --client-output-buffer-soft-secs
+    #[arg(long)]
+    client_output_buffer_soft_bytes: Option<usize>,
+
+    /// How long a client may stay over the soft output buffer limit [default: 60]
+    #[arg(long)]
+    client_output_buffer_soft_secs: Option<u64>,
+
+    /// Whether a client over its output buffer limit is disconnected or has replies dropped,
+    /// answered with errors in their place [default: disconnect]
+    #[arg(long)]
+    client_output_buffer_policy: Option<SlowClientPolicy>,
+
+    /// Read a PROXY protocol (v1 or v2) header from every client connection, logging and
+    /// serving the client address it carries rather than the load balancer's
+    #[arg(long)]
+    proxy_protocol: bool,
+
+    /// Only accept PROXY protocol headers from load balancers in this network, as a CIDR block or
+    /// address (may be repeated; required with --proxy-protocol)
+    #[arg(long)]
+    trusted_proxy: Vec<IpNetwork>,
+
+    /// Only accept TCP clients from this network, as a CIDR block or address (may be repeated)
+    #[arg(long)]
+    allow_source: Vec<IpNetwork>,
+
+    /// Close connections from TCP clients in this network, as a CIDR block or address, as soon
+    /// as they're accepted (may be repeated)
+    #[arg(long)]
+    deny_source: Vec<IpNetwork>,
+
+    /// Relay client connections to the target byte for byte, without decoding commands; the
+    /// fastest mode, but incompatible with any middleware, limits, or capture
+    #[arg(long)]
+    passthrough: bool,
+
+    /// The protocol clients speak: resp (RESP2, RESP3, or inline commands) or memcached (its text
+    /// protocol, translated to RESP for the target) [default: resp]
+    #[arg(long)]
+    frontend: Option<Frontend>,
+
+    /// Also serve an HTTP bridge on this address: GET and PUT /keys/{key} read and write a key,
+    /// and POST /command runs a command given as a JSON array of strings
+    #[arg(long)]
+    http_address: Option<String>,
+
+    /// Also serve the gRPC frontend (cabbage.v1.Proxy, in proto/cabbage.proto) on this address
+    #[arg(long)]
+    grpc_address: Option<String>,
+
+    /// Bind the listen address with SO_REUSEPORT this many times, accepting on each in its own
+    /// task (0 for one per CPU), to scale accepting under heavy connection churn
+    #[arg(long)]
+    reuseport_acceptors: Option<usize>,
+
+    /// Serve the listening sockets passed by systemd socket activation (LISTEN_FDS) rather than
+    /// binding --client, so restarts leave no gap where nothing listens
+    #[arg(long)]
+    systemd_socket: bool,
+
+    /// Detach from the terminal and run in the background, once the configuration has loaded,
+    /// logging only to --log-file (if given)
+    #[arg(long)]
+    daemonize: bool,
+
+    /// Write the proxy's process ID to this file, removing it on exit
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Also write the log to this file, rotating it by size and age
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file once it reaches this many bytes [default: 104857600]
+    #[arg(long)]
+    log_max_file_bytes: Option<u64>,
+
+    /// Rotate the log file once it's this many seconds old [default: 86400]
+    #[arg(long)]
+    log_max_file_secs: Option<u64>,
+
+    /// Rotated log files kept, beyond which the oldest are removed [default: 7]
+    #[arg(long)]
+    log_keep_files: Option<usize>,
+
+    /// Log a statistics report (per-command latency percentiles, ...) every N seconds
+    #[arg(long)]
+    stats_interval: Option<u64>,
+
+    /// Record commands taking at least this many milliseconds, end to end, in the slowlog
+    #[arg(long)]
+    slowlog_threshold_ms: Option<u64>,
+
+    /// Number of entries retained in the slowlog [default: 128]
+    #[arg(long)]
+    slowlog_max_len: Option<usize>,
+
+    /// Fraction of commands (0.0 to 1.0) whose keys are sampled for hot key detection
+    #[arg(long)]
+    hot_key_sample_rate: Option<f64>,
+
+    /// Number of distinct keys tracked by hot key detection [default: 256]
+    #[arg(long)]
+    hot_key_capacity: Option<usize>,
+
+    /// Fraction of commands (0.0 to 1.0) whose keys are counted by namespace (prefix up to the
+    /// first ':') for the stats report
+    #[arg(long)]
+    key_space_sample_rate: Option<f64>,
+
+    /// Push statistics to the StatsD server at this address (host:port) over UDP
+    #[arg(long)]
+    statsd_address: Option<String>,
+
+    /// Append a "# Cabbage" section of the proxy's statistics to the target's INFO replies
+    #[arg(long)]
+    info_section: bool,
+
+    /// Push statistics to StatsD every N seconds [default: 10]
+    #[arg(long)]
+    statsd_interval: Option<u64>,
+
+    /// Name StatsD metrics under this prefix [default: cabbage]
+    #[arg(long)]
+    statsd_prefix: Option<String>,
+
+    /// Alert when a command's p99 latency over an interval exceeds a threshold (may be repeated;
+    /// COMMAND=MS, where COMMAND may be * for every command; the first match applies)
+    #[arg(long)]
+    latency_slo: Vec<LatencySlo>,
+
+    /// Judge latency SLOs over N seconds at a time [default: 60]
+    #[arg(long)]
+    slo_interval: Option<u64>,
+
+    /// POST latency SLO alerts to this http:// URL as JSON, besides logging them
+    #[arg(long)]
+    slo_webhook: Option<Webhook>,
+
+    /// Keep cumulative statistics (per-command totals, the slowlog, ...) in this file across
+    /// restarts
+    #[arg(long)]
+    stats_snapshot_path: Option<PathBuf>,
+
+    /// Save the statistics snapshot every N seconds [default: 60]
+    #[arg(long)]
+    stats_snapshot_interval: Option<u64>,
+
+    /// Record all traffic to capture files in this directory
+    #[arg(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Start a new capture file once the current one reaches this many bytes
+    /// [default: 268435456]
+    #[arg(long)]
+    capture_max_file_bytes: Option<u64>,
+
+    /// Start a new capture file once the current one is this many seconds old [default: 3600]
+    #[arg(long)]
+    capture_max_file_secs: Option<u64>,
+
+    /// Append a hash-chained record of every administrative and write command to this file
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Reject requests larger than this many bytes, RESP encoded
+    #[arg(long)]
+    max_request_bytes: Option<usize>,
+
+    /// Reject requests with more than this many elements (command name and arguments)
+    #[arg(long)]
+    max_request_elements: Option<usize>,
+
+    /// Reject requests with an argument larger than this many bytes
+    #[arg(long)]
+    max_value_bytes: Option<usize>,
+
+    /// Require clients to AUTH with this password before forwarding their commands, as Redis's
+    /// requirepass does; AUTH is answered by the proxy, never the target
+    #[arg(long)]
+    client_password: Option<String>,
+
+    /// Only forward these commands (may be repeated; NAME or NAME|SUBCOMMAND)
+    #[arg(long)]
+    allow_command: Vec<String>,
+
+    /// Reject these commands with an error (may be repeated; NAME or NAME|SUBCOMMAND)
+    #[arg(long)]
+    deny_command: Vec<String>,
+
+    /// Mask matching values in logged traffic (may be repeated; NAME:POSITION[,POSITION...],
+    /// where POSITION is an argument index, an index followed by `+` for it and all later
+    /// arguments, or `reply`)
+    #[arg(long)]
+    redact: Vec<RedactionRule>,
+
+    /// Don't mask credentials (AUTH, HELLO, MIGRATE, ...) in logged traffic by default
+    #[arg(long)]
+    no_default_redaction: bool,
+
+    /// Write each connection's requests and replies, redacted, to a file of its own (named by
+    /// its connection ID) in this directory
+    #[arg(long)]
+    transcript_dir: Option<PathBuf>,
+
+    /// Only log one in N requests of a command (may be repeated; COMMAND=N, where COMMAND may be
+    /// * for every command; the first match applies). Errors are always logged.
+    #[arg(long)]
+    log_sample: Vec<LogSampleRule>,
+
+    /// Log requests left out by sampling after all if their replies take at least this many
+    /// milliseconds
+    #[arg(long)]
+    log_sample_slow_ms: Option<u64>,
+
+    /// Show at most this many bytes of each string in logged frames, noting how many more there
+    /// were
+    #[arg(long)]
+    log_max_arg_bytes: Option<usize>,
+
+    /// Show at most this many elements of each array in logged frames, noting how many more
+    /// there were
+    #[arg(long)]
+    log_max_args: Option<usize>,
+
+    /// Log a command's requests and replies at a level other than info (may be repeated;
+    /// COMMAND=LEVEL, where COMMAND is NAME or NAME|SUBCOMMAND and LEVEL is off, trace, debug, or
+    /// info; the first match applies). COMMAND|DOCS is logged at debug unless set otherwise.
+    #[arg(long)]
+    log_command_level: Vec<LogLevelRule>,
+
+    /// Password sent with AUTH on every new target connection
+    #[arg(long)]
+    target_password: Option<String>,
+
+    /// ACL username sent with AUTH on every new target connection
+    #[arg(long)]
+    target_username: Option<String>,
+
+    /// Database selected on every new target connection
+    #[arg(long)]
+    target_db: Option<u32>,
+
+    /// Name set with CLIENT SETNAME on every new target connection
+    #[arg(long)]
+    target_client_name: Option<String>,
+
+    /// Connect to the target when a client sends its first command rather than when it connects
+    #[arg(long)]
+    lazy_connect: bool,
+
+    /// Spread target connections across every address the target's name resolves to, rather
+    /// than preferring the first (names are resolved afresh for every connection either way)
+    #[arg(long)]
+    rotate_target_addresses: bool,
+
+    /// Pass bulk string replies of at least this many bytes on to clients in chunks as they
+    /// arrive from the target, rather than buffering each whole first
+    #[arg(long)]
+    stream_bulk_bytes: Option<usize>,
+
+    /// Speak RESP3 to the target (switching each connection with HELLO 3), translating its
+    /// replies to RESP2 for clients
+    #[arg(long)]
+    target_resp3: bool,
+
+    /// Send commands matching PATTERN to ADDRESS (may be repeated; PATTERN=ADDRESS, where a
+    /// pattern like FT.* matches by prefix; the first match applies)
+    #[arg(long = "route")]
+    routes: Vec<RouteRule>,
+
+    /// Send commands whose first key matches GLOB (as taken by KEYS) or REGEX to ADDRESS, after
+    /// any --route (may be repeated; GLOB=ADDRESS or /REGEX/=ADDRESS; the first match applies)
+    #[arg(long = "route-key")]
+    key_routes: Vec<KeyRouteRule>,
+
+    /// Retry read-only commands up to this many times when the target connection fails, for
+    /// targets which are reconnected to (not a single target) [default: 0]
+    #[arg(long)]
+    retries: Option<usize>,
+
+    /// Cap retries at this percentage of overall traffic (on top of 10 per second)
+    /// [default: 10]
+    #[arg(long)]
+    retry_budget_percent: Option<f32>,
+
+    /// Stop dispatching to the target for a while after this many consecutive failures
+    #[arg(long)]
+    circuit_breaker_failures: Option<usize>,
+
+    /// How long the circuit breaker stays open before probing the target again [default: 5000]
+    #[arg(long)]
+    circuit_breaker_cooldown_ms: Option<u64>,
+
+    /// PING the target every this many milliseconds, closing client connections to a target
+    /// which fails (or re-resolving a Sentinel master or cluster slot map, or switching between
+    /// failover targets)
+    #[arg(long)]
+    health_check_interval_ms: Option<u64>,
+
+    /// How long a health check PING may take before the check fails [default: 1000]
+    #[arg(long)]
+    health_check_timeout_ms: Option<u64>,
+
+    /// Answer with an error if the target hasn't started replying within this many milliseconds
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
+    /// Answer with an error if the target hasn't finished replying within this many milliseconds
+    #[arg(long)]
+    reply_timeout_ms: Option<u64>,
+
+    /// Stop waiting for a target reply after this many milliseconds, answering with an error and
+    /// carrying on with later requests
+    #[arg(long)]
+    target_timeout_ms: Option<u64>,
+
+    /// Run tasks on this many threads (by default, one per CPU)
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
+    /// Run at most this many threads of blocking work at once (by default, 512)
+    #[arg(long)]
+    max_blocking_threads: Option<usize>,
+
+    /// Have each worker poll for I/O and timer events after running this many tasks (by
+    /// default, 61)
+    #[arg(long)]
+    event_interval: Option<u32>,
+
+    /// Serve tokio-console's instrumentation of tasks on this address (needs the console
+    /// feature)
+    #[arg(long)]
+    console_address: Option<SocketAddr>,
+
+    /// Limit each client to this many commands per second
+    #[arg(long)]
+    rate_limit: Option<u32>,
+
+    /// Also limit each client to this many request bytes per second
+    #[arg(long)]
+    rate_limit_bytes: Option<u64>,
+
+    /// Whether commands over the rate limit are rejected with -BUSY or delayed [default: reject]
+    #[arg(long)]
+    rate_limit_mode: Option<RateLimitMode>,
+
+    /// Limit each client identity, across all its connections, to this many commands per second
+    #[arg(long)]
+    quota_commands_per_sec: Option<u32>,
+
+    /// Limit each client identity to this many commands awaiting replies at once
+    #[arg(long)]
+    quota_max_in_flight: Option<usize>,
+
+    /// Limit each client identity to this many request and reply bytes each UTC day
+    #[arg(long)]
+    quota_bytes_per_day: Option<u64>,
+
+    /// Whether quotas are kept per user or per client address [default: user]
+    #[arg(long)]
+    quota_key: Option<QuotaKey>,
+
+    /// Limit replies to this many bytes per second
+    #[arg(long)]
+    throttle_bytes: Option<u64>,
+
+    /// Whether the reply bandwidth limit applies to each connection or to all of them together
+    /// [default: connection]
+    #[arg(long)]
+    throttle_scope: Option<ThrottleScope>,
+
+    /// Let at most this many commands await replies from targets at once, with client
+    /// connections taking turns for them, so a heavily pipelining client can't starve others
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+
+    /// Refuse commands with -BUSY while their client connection has this many awaiting replies
+    #[arg(long)]
+    saturation_per_connection: Option<usize>,
+
+    /// Refuse commands with -BUSY while this many await replies across every client connection
+    #[arg(long)]
+    saturation_total: Option<usize>,
+
+    /// Let at most MAX of a command await replies at once, across every client connection
+    /// (may be repeated; COMMAND=MAX, as in KEYS=2)
+    #[arg(long = "concurrency-limit")]
+    concurrency_limits: Vec<ConcurrencyLimit>,
+
+    /// Whether commands beyond their concurrency limit are rejected with -BUSY or queued
+    /// [default: reject]
+    #[arg(long)]
+    concurrency_mode: Option<ConcurrencyMode>,
+
+    /// Serve repeated GETs from a proxy-local cache, keeping replies for this many milliseconds
+    #[arg(long)]
+    cache_ttl_ms: Option<u64>,
+
+    /// Answer COMMAND and COMMAND DOCS from the target's replies, kept for this many
+    /// milliseconds or until a health check fails
+    #[arg(long)]
+    command_cache_ttl_ms: Option<u64>,
+
+    /// Report VALUE for a parameter in CONFIG GET replies, whatever the target's is, and refuse
+    /// CONFIG SET of it (may be repeated; NAME=VALUE)
+    #[arg(long)]
+    config_override: Vec<ConfigOverride>,
+
+    /// Let clients CONFIG SET the parameters matching this glob on the target (may be repeated);
+    /// with this or --config-override, setting any other parameter is refused
+    #[arg(long)]
+    config_allow_set: Vec<String>,
+
+    /// Keep this command (NAME or NAME|SUBCOMMAND) off clients' target connections (may be
+    /// repeated) [default: DEBUG, SHUTDOWN, CLIENT|KILL, CLIENT|PAUSE with --isolate-mode]
+    #[arg(long)]
+    isolate_command: Vec<String>,
+
+    /// Send isolated commands over short-lived side connections ("side", single targets only)
+    /// or reject them ("reject") [default: side]
+    #[arg(long)]
+    isolate_mode: Option<IsolationMode>,
+
+    /// Remember scripts loaded with SCRIPT LOAD, sending EVALSHA again as EVAL when a target
+    /// hasn't loaded the script, and load them on every failover or balanced target
+    #[arg(long)]
+    track_scripts: bool,
+
+    /// Serve every client's subscriptions from a single subscribed target connection, fanning
+    /// messages out to them
+    #[arg(long)]
+    share_subscriptions: bool,
+
+    /// Refuse writes with -READONLY, forwarding only reads (CABBAGE.READONLY switches this at
+    /// runtime)
+    #[arg(long)]
+    read_only: bool,
+
+    /// The client-facing middleware layers commands pass through, outermost first, leaving out
+    /// any not listed (comma-separated; trace, transcripts, capture, audit, limits, auth, plugins,
+    /// scripts, acl, filter, monitor, admin, keyspace, hotkeys, slowlog, latency, and logging)
+    /// [default: all of them, in that order]
+    #[arg(long, value_delimiter = ',')]
+    stack: Vec<StackLayer>,
+
+    /// Start in maintenance, answering commands with an error instead of forwarding them
+    /// (CABBAGE.MAINTENANCE switches this at runtime)
+    #[arg(long)]
+    maintenance: bool,
+
+    /// The error commands are answered with during maintenance
+    /// [default: LOADING maintenance in progress]
+    #[arg(long)]
+    maintenance_error: Option<String>,
+
+    /// Keep forwarding this command (NAME or NAME|SUBCOMMAND) during maintenance (may be
+    /// repeated)
+    #[arg(long)]
+    maintenance_allow: Vec<String>,
+
+    /// Number of replies the cache holds before evicting the least recently used
+    /// [default: 10000]
+    #[arg(long)]
+    cache_max_entries: Option<usize>,
+
+    /// Never cache keys starting with this prefix (may be repeated)
+    #[arg(long)]
+    cache_bypass_prefix: Vec<String>,
+
+    /// Invalidate cached replies when the target reports their keys modified by any client,
+    /// using client-side caching in broadcast mode
+    #[arg(long)]
+    cache_track_invalidations: bool,
+
+    /// Answer GETs the target can't (being unavailable or timing out) with the last reply
+    /// cached, up to this many milliseconds past its expiry
+    #[arg(long)]
+    cache_serve_stale_ms: Option<u64>,
+
+    /// Compress values written under a key prefix (may be repeated; PREFIX=ALGORITHM[:MIN_BYTES],
+    /// where ALGORITHM is lz4 or zstd and MIN_BYTES defaults to 1024; the first match applies)
+    #[arg(long)]
+    compress: Vec<CompressionRule>,
+
+    /// Rewrite commands before forwarding them (may be repeated, applying in order;
+    /// rename:OLD=NEW, add-arg:COMMAND=ARG, remove-arg:COMMAND=ARG, key-prefix:OLD=NEW, or
+    /// key:PATTERN=TEMPLATE with {NAME} placeholders)
+    #[arg(long)]
+    rewrite: Vec<RewriteRule>,
+
+    /// Give keys SET without an expiry this one, in milliseconds (PERSIST is then refused)
+    #[arg(long)]
+    ttl_default_ms: Option<u64>,
+
+    /// Raise expiries set by writes (SET, GETEX, SETEX, PSETEX, and the EXPIRE family) to at
+    /// least this many milliseconds
+    #[arg(long)]
+    ttl_min_ms: Option<u64>,
+
+    /// Lower expiries set by writes to at most this many milliseconds
+    #[arg(long)]
+    ttl_max_ms: Option<u64>,
+
+    /// Translate deprecated commands (SETEX, PSETEX, SETNX, GETSET, HMSET, RPOPLPUSH,
+    /// BRPOPLPUSH) into their modern equivalents, for targets which have dropped them
+    #[arg(long)]
+    translate_legacy_commands: bool,
+
+    /// Delay the replies to a percentage of commands, for resilience testing (may be repeated;
+    /// COMMAND=PERCENT:MS[-MAX_MS], where COMMAND may be * for every command and a range picks a
+    /// random delay; the first match applies)
+    #[arg(long)]
+    inject_latency: Vec<DelayRule>,
+
+    /// Answer a percentage of commands with a synthetic error, for resilience testing (may be
+    /// repeated; COMMAND=PERCENT:FAULT, where COMMAND may be * for every command and FAULT is
+    /// LOADING, READONLY, BUSY, MASTERDOWN, OOM, RESET to close the connection, or any other
+    /// error text; the first match applies, and CABBAGE.FAULTS changes these at runtime)
+    #[arg(long)]
+    inject_errors: Vec<ErrorRule>,
+
+    /// Encrypt stored values with AES-256-GCM using the key in this file (32 raw bytes or 64
+    /// hexadecimal digits)
+    #[arg(long)]
+    encryption_key_file: Option<PathBuf>,
+
+    /// Only encrypt values of keys under this prefix (may be repeated) [default: every key]
+    #[arg(long)]
+    encrypt_prefix: Vec<String>,
+
+    /// Append a CRC32 checksum to stored values, verifying and stripping it when they're read
+    /// back, so values corrupted between the proxy and storage are reported rather than served
+    #[arg(long)]
+    checksum: bool,
+
+    /// Only checksum values of keys under this prefix (may be repeated) [default: every key]
+    #[arg(long)]
+    checksum_prefix: Vec<String>,
+
+    /// Treat values read back without a checksum as corrupt, rather than passing them through
+    #[arg(long)]
+    checksum_require: bool,
+
+    /// Namespace all keys under this prefix on the target
+    #[arg(long)]
+    key_prefix: Option<String>,
+
+    /// Also send every request to this shadow target, discarding its replies (for soak testing)
+    #[arg(long)]
+    mirror_target: Option<String>,
+
+    /// Also send commands which may write to this target, discarding its replies (for
+    /// migrating to it without client changes)
+    #[arg(long)]
+    dual_write_target: Option<String>,
+
+    /// Also send commands to this target, recording where its replies differ from the primary's
+    #[arg(long)]
+    canary_target: Option<String>,
+
+    /// OTLP/HTTP collector endpoint to export per-command trace spans to
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Pass commands and replies through this WebAssembly plugin (.wasm or .wat); repeat to
+    /// chain several, the first seeing commands first
+    #[cfg(feature = "plugins")]
+    #[arg(long)]
+    plugin: Vec<PathBuf>,
+
+    /// Pass commands and replies through this Lua script, after any plugins; repeat to chain
+    /// several, the first seeing commands first
+    #[cfg(feature = "lua")]
+    #[arg(long)]
+    script: Vec<PathBuf>,
+}
+
+impl ProxyOptions {
+    /// The `--config` file (or defaults), overridden by any flags given
+    fn config(&self, global: &GlobalOptions) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        fn set<T: Clone>(setting: &mut T, flag: &Option<T>) {
+            if let Some(value) = flag {
+                *setting = value.clone();
+            }
+        }
+        fn set_some<T: Clone>(setting: &mut Option<T>, flag: &Option<T>) {
+            if flag.is_some() {
+                setting.clone_from(flag);
+            }
+        }
+        fn set_all<T: Clone>(setting: &mut Vec<T>, flag: &[T]) {
+            if !flag.is_empty() {
+                *setting = flag.to_vec();
+            }
+        }
+
+        let listen = &mut config.listen;
+        if let Some((address, extra_addresses)) = self.client.split_first() {
+            listen.address.clone_from(address);
+            listen.extra_addresses = extra_addresses.to_vec();
+        }
+        set_some(&mut listen.max_connections, &self.max_connections);
+        set(&mut listen.overflow, &self.connection_overflow);
+        set_some(
+            &mut listen.idle_timeout_secs,
+            &self.client_idle_timeout_secs,
+        );
+        set_some(
+            &mut listen.max_lifetime_secs,
+            &self.connection_max_lifetime_secs,
+        );
+        if self.client_output_buffer_hard_bytes.is_some()
+            || self.client_output_buffer_soft_bytes.is_some()
+        {
+            let output_buffer = listen
+                .output_buffer
+                .get_or_insert_with(OutputBufferLimit::default);
+            set_some(
+                &mut output_buffer.hard_bytes,
+                &self.client_output_buffer_hard_bytes,
+            );
+            set_some(
+                &mut output_buffer.soft_bytes,
+                &self.client_output_buffer_soft_bytes,
+            );
+        }
+        if let Some(output_buffer) = &mut listen.output_buffer {
+            set(
+                &mut output_buffer.soft_secs,
+                &self.client_output_buffer_soft_secs,
+            );
+            set(&mut output_buffer.policy, &self.client_output_buffer_policy);
+        } else if self.client_output_buffer_soft_secs.is_some()
+            || self.client_output_buffer_policy.is_some()
+        {
+            bail!(
+                "--client-output-buffer-soft-secs and --client-output-buffer-policy require an \
+                 output buffer limit"
+            );
+        }
+        set(&mut listen.drain_timeout_secs, &self.drain_timeout_secs);
+        listen.proxy_protocol |= self.proxy_protocol;
+        set_all(&mut listen.trusted_proxies, &self.trusted_proxy);
+        set_all(&mut listen.allow_sources, &self.allow_source);
+        set_all(&mut listen.deny_sources, &self.deny_source);
+        listen.passthrough |= self.passthrough;
+        set(&mut listen.frontend, &self.frontend);
+        set_some(&mut listen.http_address, &self.http_address);
+        set_some(&mut listen.grpc_address, &self.grpc_address);
+        listen.systemd |= self.systemd_socket;
+        set_some(&mut listen.reuseport_acceptors, &self.reuseport_acceptors);
+
+        let target = &mut config.target;
+        set(&mut target.address, &self.target);
+        target.cluster |= self.cluster;
+        set_all(&mut target.sentinels, &self.sentinel);
+        set_some(&mut target.master_name, &self.master_name);
+        set_all(&mut target.failover, &self.failover_target);
+        set_all(&mut target.balance, &self.balance_target);
+        set(&mut target.balance_policy, &self.balance_policy);
+        set(&mut target.balance_stickiness, &self.balance_stickiness);
+        set(&mut target.pool.min_size, &self.target_pool_min_size);
+        set_some(&mut target.pool.max_size, &self.target_pool_max_size);
+        set(&mut target.pool.max_wait_ms, &self.target_pool_max_wait_ms);
+        set(&mut target.pool.overflow, &self.target_pool_overflow);
+        set_some(&mut target.username, &self.target_username);
+        set_some(&mut target.password, &self.target_password);
+        set_some(&mut target.db, &self.target_db);
+        set_some(&mut target.client_name, &self.target_client_name);
+        target.lazy_connect |= self.lazy_connect;
+        target.rotate_addresses |= self.rotate_target_addresses;
+        set_some(&mut target.stream_bulk_bytes, &self.stream_bulk_bytes);
+        target.resp3 |= self.target_resp3;
+        set_all(&mut target.routes, &self.routes);
+        set_all(&mut target.key_routes, &self.key_routes);
+        if let Some(interval_ms) = self.health_check_interval_ms {
+            target
+                .health_check
+                .get_or_insert_with(|| HealthCheckConfig::new(interval_ms))
+                .interval_ms = interval_ms;
+        }
+        if let Some(health_check) = &mut target.health_check {
+            set(&mut health_check.timeout_ms, &self.health_check_timeout_ms);
+        } else if self.health_check_timeout_ms.is_some() {
+            bail!("--health-check-timeout-ms requires a health check interval");
+        }
+
+        set_some(&mut config.timeouts.first_frame_ms, &self.timeout_ms);
+        set_some(&mut config.timeouts.reply_ms, &self.reply_timeout_ms);
+        set_some(&mut config.timeouts.target_ms, &self.target_timeout_ms);
+
+        let runtime = &mut config.runtime;
+        set_some(&mut runtime.worker_threads, &self.worker_threads);
+        set_some(
+            &mut runtime.max_blocking_threads,
+            &self.max_blocking_threads,
+        );
+        set_some(&mut runtime.event_interval, &self.event_interval);
+        set_some(&mut runtime.console_address, &self.console_address);
+
+        let logging = &mut config.logging;
+        set(&mut logging.format, &global.log_format);
+        set(&mut logging.levels, &global.log_levels);
+        set_all(&mut logging.redact, &self.redact);
+        logging.default_redaction &= !self.no_default_redaction;
+        set_some(&mut logging.transcript_dir, &self.transcript_dir);
+        set_all(&mut logging.sample, &self.log_sample);
+        set_some(&mut logging.sample_slow_ms, &self.log_sample_slow_ms);
+        set_some(&mut logging.max_arg_bytes, &self.log_max_arg_bytes);
+        set_some(&mut logging.max_args, &self.log_max_args);
+        set_all(&mut logging.command_levels, &self.log_command_level);
+        set_some(&mut logging.file.path, &self.log_file);
+        set(&mut logging.file.max_file_bytes, &self.log_max_file_bytes);
+        set(&mut logging.file.max_file_secs, &self.log_max_file_secs);
+        set(&mut logging.file.keep, &self.log_keep_files);
+
+        let stats = &mut config.stats;
+        set_some(&mut stats.interval_secs, &self.stats_interval);
+        set_some(&mut stats.slowlog_threshold_ms, &self.slowlog_threshold_ms);
+        set(&mut stats.slowlog_max_len, &self.slowlog_max_len);
+        set_some(&mut stats.hot_key_sample_rate, &self.hot_key_sample_rate);
+        set(&mut stats.hot_key_capacity, &self.hot_key_capacity);
+        set_some(
+            &mut stats.key_space_sample_rate,
+            &self.key_space_sample_rate,
+        );
+        set_some(&mut stats.statsd_address, &self.statsd_address);
+        set(&mut stats.statsd_interval_secs, &self.statsd_interval);
+        set(&mut stats.statsd_prefix, &self.statsd_prefix);
+        set_all(&mut stats.latency_slos, &self.latency_slo);
+        set(&mut stats.slo_interval_secs, &self.slo_interval);
+        set_some(&mut stats.slo_webhook, &self.slo_webhook);
+        set_some(&mut stats.snapshot_path, &self.stats_snapshot_path);
+        set(
+            &mut stats.snapshot_interval_secs,
+            &self.stats_snapshot_interval,
+        );
+        stats.info_section |= self.info_section;
+
+        let capture = &mut config.capture;
+        set_some(&mut capture.directory, &self.capture_dir);
+        set(&mut capture.max_file_bytes, &self.capture_max_file_bytes);
+        set(&mut capture.max_file_secs, &self.capture_max_file_secs);
+        set_some(&mut config.audit.path, &self.audit_log);
+
+        let limits = &mut config.limits;
+        set_some(&mut limits.max_frame_bytes, &self.max_request_bytes);
+        set_some(&mut limits.max_elements, &self.max_request_elements);
+        set_some(&mut limits.max_value_bytes, &self.max_value_bytes);
+
+        let middleware = &mut config.middleware;
+        if let Some(password) = &self.client_password {
+            middleware
+                .auth
+                .get_or_insert_with(AuthConfig::default)
+                .password = Some(password.clone());
+        }
+        set_all(&mut middleware.allow_commands, &self.allow_command);
+        set_all(&mut middleware.deny_commands, &self.deny_command);
+        set_some(&mut middleware.key_prefix, &self.key_prefix);
+        set_all(&mut middleware.compression, &self.compress);
+        set_all(&mut middleware.rewrite, &self.rewrite);
+        set_some(&mut middleware.ttl.default_ms, &self.ttl_default_ms);
+        set_some(&mut middleware.ttl.min_ms, &self.ttl_min_ms);
+        set_some(&mut middleware.ttl.max_ms, &self.ttl_max_ms);
+        middleware.translate_legacy_commands |= self.translate_legacy_commands;
+        set_all(&mut middleware.inject_latency, &self.inject_latency);
+        set_all(&mut middleware.inject_errors, &self.inject_errors);
+        if let Some(key_file) = &self.encryption_key_file {
+            let encryption = middleware
+                .encryption
+                .get_or_insert_with(EncryptionConfig::default);
+            encryption.key = None;
+            encryption.key_file = Some(key_file.clone());
+        }
+        if let Some(encryption) = &mut middleware.encryption {
+            set_all(&mut encryption.prefixes, &self.encrypt_prefix);
+        } else if !self.encrypt_prefix.is_empty() {
+            bail!("--encrypt-prefix requires an encryption key");
+        }
+        if self.checksum {
+            middleware
+                .checksum
+                .get_or_insert_with(ChecksumConfig::default);
+        }
+        if let Some(checksum) = &mut middleware.checksum {
+            set_all(&mut checksum.prefixes, &self.checksum_prefix);
+            checksum.require |= self.checksum_require;
+        } else if !self.checksum_prefix.is_empty() || self.checksum_require {
+            bail!("--checksum-prefix and --checksum-require require --checksum");
+        }
+        set_some(&mut middleware.mirror, &self.mirror_target);
+        set_some(&mut middleware.dual_write, &self.dual_write_target);
+        set_some(&mut middleware.canary, &self.canary_target);
+        if let Some(commands_per_sec) = self.rate_limit {
+            middleware
+                .rate_limit
+                .get_or_insert_with(|| RateLimitConfig::new(commands_per_sec))
+                .commands_per_sec = commands_per_sec;
+        }
+        if let Some(rate_limit) = &mut middleware.rate_limit {
+            set_some(&mut rate_limit.bytes_per_sec, &self.rate_limit_bytes);
+            set(&mut rate_limit.mode, &self.rate_limit_mode);
+        } else if self.rate_limit_bytes.is_some() || self.rate_limit_mode.is_some() {
+            bail!("--rate-limit-bytes and --rate-limit-mode require a rate limit");
+        }
+        if self.quota_commands_per_sec.is_some()
+            || self.quota_max_in_flight.is_some()
+            || self.quota_bytes_per_day.is_some()
+        {
+            let quota = middleware.quota.get_or_insert_with(QuotaConfig::default);
+            set_some(&mut quota.commands_per_sec, &self.quota_commands_per_sec);
+            set_some(&mut quota.max_in_flight, &self.quota_max_in_flight);
+            set_some(&mut quota.bytes_per_day, &self.quota_bytes_per_day);
+        }
+        if let Some(quota) = &mut middleware.quota {
+            set(&mut quota.key, &self.quota_key);
+        } else if self.quota_key.is_some() {
+            bail!("--quota-key requires a quota");
+        }
+        if let Some(bytes_per_sec) = self.throttle_bytes {
+            middleware
+                .throttle
+                .get_or_insert_with(|| ThrottleConfig::new(bytes_per_sec))
+                .bytes_per_sec = bytes_per_sec;
+        }
+        if let Some(throttle) = &mut middleware.throttle {
+            set(&mut throttle.scope, &self.throttle_scope);
+        } else if self.throttle_scope.is_some() {
+            bail!("--throttle-scope requires --throttle-bytes");
+        }
+        set(&mut middleware.retry.retries, &self.retries);
+        set(
+            &mut middleware.retry.budget_percent,
+            &self.retry_budget_percent,
+        );
+        if let Some(failures) = self.circuit_breaker_failures {
+            middleware
+                .circuit_breaker
+                .get_or_insert_with(|| CircuitBreakerConfig::new(failures))
+                .failures = failures;
+        }
+        if let Some(circuit_breaker) = &mut middleware.circuit_breaker {
+            set(
+                &mut circuit_breaker.cooldown_ms,
+                &self.circuit_breaker_cooldown_ms,
+            );
+        } else if self.circuit_breaker_cooldown_ms.is_some() {
+            bail!("--circuit-breaker-cooldown-ms requires a circuit breaker");
+        }
+        set_some(&mut middleware.max_in_flight, &self.max_in_flight);
+        if self.saturation_per_connection.is_some() || self.saturation_total.is_some() {
+            let saturation = middleware
+                .saturation
+                .get_or_insert_with(SaturationConfig::default);
+            set_some(
+                &mut saturation.per_connection,
+                &self.saturation_per_connection,
+            );
+            set_some(&mut saturation.total, &self.saturation_total);
+        }
+        if !self.concurrency_limits.is_empty() {
+            let concurrency = middleware
+                .concurrency
+                .get_or_insert_with(ConcurrencyConfig::default);
+            set_all(&mut concurrency.limits, &self.concurrency_limits);
+        }
+        if let Some(concurrency) = &mut middleware.concurrency {
+            set(&mut concurrency.mode, &self.concurrency_mode);
+        } else if self.concurrency_mode.is_some() {
+            bail!("--concurrency-mode requires a concurrency limit");
+        }
+        set_some(
+            &mut middleware.command_cache_ttl_ms,
+            &self.command_cache_ttl_ms,
+        );
+        if !self.config_override.is_empty() || !self.config_allow_set.is_empty() {
+            let config_command = middleware
+                .config_command
+                .get_or_insert_with(ConfigCommandConfig::default);
+            set_all(&mut config_command.overrides, &self.config_override);
+            set_all(&mut config_command.allow_set, &self.config_allow_set);
+        }
+        if !self.isolate_command.is_empty() || self.isolate_mode.is_some() {
+            let isolate = middleware
+                .isolate
+                .get_or_insert_with(IsolationConfig::default);
+            set_all(&mut isolate.commands, &self.isolate_command);
+            set(&mut isolate.mode, &self.isolate_mode);
+        }
+        middleware.track_scripts |= self.track_scripts;
+        middleware.share_subscriptions |= self.share_subscriptions;
+        middleware.read_only |= self.read_only;
+        middleware.maintenance.enabled |= self.maintenance;
+        if !self.stack.is_empty() {
+            middleware.stack = Some(self.stack.clone());
+        }
+        set(&mut middleware.maintenance.error, &self.maintenance_error);
+        set_all(&mut middleware.maintenance.allow, &self.maintenance_allow);
+        if let Some(ttl_ms) = self.cache_ttl_ms {
+            middleware
+                .cache
+                .get_or_insert_with(|| CacheConfig::new(ttl_ms))
+                .ttl_ms = ttl_ms;
+        }
+        if let Some(cache) = &mut middleware.cache {
+            set(&mut cache.max_entries, &self.cache_max_entries);
+            set_all(&mut cache.bypass_prefixes, &self.cache_bypass_prefix);
+            cache.track_invalidations |= self.cache_track_invalidations;
+            set_some(&mut cache.serve_stale_ms, &self.cache_serve_stale_ms);
+        } else if self.cache_max_entries.is_some()
+            || !self.cache_bypass_prefix.is_empty()
+            || self.cache_track_invalidations
+            || self.cache_serve_stale_ms.is_some()
+        {
+            bail!(
+                "--cache-max-entries, --cache-bypass-prefix, --cache-track-invalidations, and \
+                 --cache-serve-stale-ms require --cache-ttl-ms"
+            );
+        }
+        #[cfg(feature = "otel")]
+        set_some(&mut middleware.otlp_endpoint, &self.otlp_endpoint);
+        #[cfg(feature = "plugins")]
+        set_all(&mut middleware.plugins, &self.plugin);
+        #[cfg(feature = "lua")]
+        set_all(&mut middleware.scripts, &self.script);
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// How backend connections are established for each client connection
+#[derive(Clone)]
+enum Backend {
+    Single(String, Handshake),
+    Cluster(Arc<ClusterSlots>),
+    Sentinel(Arc<SentinelMaster>, Handshake),
+    Failover(Arc<FailoverTargets>, Handshake),
+    Balanced(Arc<BalancedTargets>, Handshake),
+}
+
+/// A second target which receives copies of client commands
+#[derive(Clone)]
+enum Secondary {
+    /// Every command is mirrored, for soak testing
+    Shadow(String),
+    /// Commands which may write are mirrored, for migrating to the target
+    DualWrite(String),
+    /// Commands are mirrored and the replies compared, for verifying the target
+    Canary(String),
+}
+
+/// Everything needed to assemble the service stack for a new client connection
+#[derive(Clone)]
+struct ServiceConfig {
+    backend: Backend,
+    log_format: LogFormat,
+    log_sampling: Arc<LogSampling>,
+    log_truncation: LogTruncation,
+    log_levels: Arc<CommandLogLevels>,
+    redaction: Arc<RedactionRules>,
+    stats: Arc<Stats>,
+    slowlog_threshold: Option<Duration>,
+    hot_key_sample_rate: Option<f64>,
+    key_space_sample_rate: Option<f64>,
+    info_section: bool,
+    command_rules: watch::Receiver<CommandRules>,
+    auth: Option<Arc<Credentials>>,
+    acl: Arc<Acl>,
+    retry: Option<RetryLayer>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    timeout: Option<(Duration, Option<Duration>)>,
+    target_timeout: Option<Duration>,
+    queues: QueueCapacities,
+    rate_limits: watch::Receiver<Option<RateLimits>>,
+    quotas: Option<Arc<Quotas>>,
+    saturation: Option<Arc<Saturation>>,
+    concurrency: Option<Arc<CommandConcurrency>>,
+    throttle: Option<Arc<ThrottleLayer>>,
+    fair: Option<FairLayer>,
+    cache: Option<Arc<ReadCache>>,
+    command_replies: Option<Arc<CommandReplies>>,
+    config_view: Option<Arc<ConfigView>>,
+    isolation: Option<Arc<Isolation>>,
+    loaded_scripts: Option<Arc<LoadedScripts>>,
+    subscription_hub: Option<Arc<SubscriptionHub>>,
+    reload: Arc<Notify>,
+    /// Cancelled by `CABBAGE.DRAIN` to shut the proxy down
+    shutdown: CancellationToken,
+    /// Where target connections come from, if they're pooled
+    pool: Option<Arc<TargetPool>>,
+    key_prefix: Option<String>,
+    compression: Vec<CompressionRule>,
+    rewrite: Vec<RewriteRule>,
+    ttl_policy: TtlPolicy,
+    translate_legacy_commands: bool,
+    inject_latency: Vec<DelayRule>,
+    faults: Arc<ErrorInjection>,
+    read_only: Arc<ReadOnlyMode>,
+    maintenance: Arc<Maintenance>,
+    encryption: Option<EncryptionLayer>,
+    checksums: Option<ChecksumLayer>,
+    secondary: Option<Secondary>,
+    capture: Option<Capture>,
+    audit: Option<AuditLog>,
+    transcripts: Option<Transcripts>,
+    limits: RequestLimits,
+    /// The client-facing layers, outermost first
+    stack: Vec<StackLayer>,
+    health_gate: bool,
+    lazy_connect: bool,
+    routes: Arc<Routes>,
+    notifications: Arc<NotificationSource>,
+    user_key_prefixes: Arc<HashMap<String, Bytes>>,
+    #[cfg(feature = "plugins")]
+    plugins: Vec<Arc<cabbage::middleware::plugin::Plugin>>,
+    #[cfg(feature = "lua")]
+    scripts: Vec<Arc<cabbage::middleware::script::Script>>,
+    #[cfg(feature = "otel")]
+    trace: bool,
+}
+
+/// Wrap `backend` to copy commands to `secondary`, or leave it be if that can't be reached
+async fn with_secondary(
+    backend: ProxyService,
+    secondary: &Secondary,
+    stats: Arc<Stats>,
+    connection_id: Uuid,
+) -> ProxyService {
+    let (Secondary::Shadow(addr) | Secondary::DualWrite(addr) | Secondary::Canary(addr)) =
+        secondary;
+    let connection = match Resp2Backend::connect(addr).await {
+        Result::Ok(connection) => ProxyService::new(connection),
+        Err(e) => {
+            log::error!(
+                "connection {connection_id}: not mirroring, failed to connect to secondary \
+                 target at {addr}: {e}"
+            );
+            return backend;
+        }
+    };
+    match secondary {
+        Secondary::Shadow(_) => {
+            ProxyService::new(MirrorLayer::new(connection, stats).layer(backend))
+        }
+        Secondary::DualWrite(_) => ProxyService::new(
+            MirrorLayer::new(connection, stats)
+                .writes_only()
+                .layer(backend),
+        ),
+        Secondary::Canary(_) => {
+            ProxyService::new(CanaryLayer::new(connection, stats).layer(backend))
+        }
+    }
+}
+
+/// A service answering every command with the error `message`, for a client which can't be
+/// given a target connection
+fn refuse_commands(message: String) -> ProxyService {
+    ProxyService::new(tower::service_fn(
+        move |_: redis_protocol::resp2::types::BytesFrame| {
+            let responses: ResponseStream =
+                Box::pin(futures::stream::iter([cabbage::command::error(
+                    message.clone(),
+                )]));
+            async move { cabbage::Result::Ok(responses) }
+        },
+    ))
+}
+
+async fn create_proxy_service(
+    config: ServiceConfig,
+    connection_id: Uuid,
+    client_addr: Option<ClientAddr>,
+) -> cabbage::Result<ProxyService> {
+    // Who the client authenticates to the proxy as, for the layers acting on it
+    let client_user = ClientUser::new();
+    let backend = match config.backend {
+        Backend::Single(target_addr, handshake) => {
+            // A client connecting during maintenance dials the target once a command is forwarded
+            let backend = if config.lazy_connect || config.maintenance.is_enabled() {
+                let backend =
+                    LazyBackend::new(&target_addr, handshake).with_capacities(config.queues);
+                ProxyService::new(match config.target_timeout {
+                    Some(timeout) => backend.with_request_timeout(timeout),
+                    None => backend,
+                })
+            } else {
+                let connected = match &config.pool {
+                    Some(pool) => pool.checkout(&target_addr, None).await,
+                    None => {
+                        Resp2Backend::connect_with_capacities(
+                            &target_addr,
+                            &handshake,
+                            config.queues,
+                        )
+                        .await
+                    }
+                };
+                let mut backend = match connected {
+                    Result::Ok(backend) => backend,
+                    Err(e @ cabbage::Error::PoolExhausted(_)) => {
+                        log::warn!("connection {connection_id}: {e}");
+                        // The client hears why rather than being hung up on
+                        return cabbage::Result::Ok(refuse_commands(format!("ERR {e}")));
+                    }
+                    Err(e) => return Err(e),
+                };
+                log::info!("connection {connection_id}: connected with target at: {target_addr}");
+                backend.close_client_on_disconnect(config.stats.clone(), connection_id);
+                if let Some(timeout) = config.target_timeout {
+                    backend = backend.with_request_timeout(timeout);
+                }
+                ProxyService::new(backend)
+            };
+            if config.health_gate {
+                ProxyService::new(HealthGateLayer::new(config.stats.clone()).layer(backend))
+            } else {
+                backend
+            }
+        }
+        Backend::Cluster(slots) => ProxyService::new(ClusterBackend::new(slots)),
+        Backend::Sentinel(master, handshake) => {
+            let mut backend = SentinelBackend::new(master).with_handshake(handshake);
+            if let Some(pool) = &config.pool {
+                backend = backend.with_pool(pool.clone());
+            }
+            ProxyService::new(backend)
+        }
+        Backend::Failover(targets, handshake) => {
+            let mut backend = FailoverBackend::new(targets).with_handshake(handshake);
+            if let Some(pool) = &config.pool {
+                backend = backend.with_pool(pool.clone());
+            }
+            ProxyService::new(backend)
+        }
+        Backend::Balanced(targets, handshake) => ProxyService::new(
+            BalancedBackend::new(targets)
+                .with_handshake(handshake)
+                .with_client(client_addr.clone(), client_user.clone()),
+        ),
+    };
+    // Only the main target's notifications are taken over, as routed targets serve others
+    let backend = ProxyService::new(
+        KeyspaceNotificationLayer::new(config.notifications.clone()).layer(backend),
+    );
+    let backend = match &config.subscription_hub {
+        Some(hub) => ProxyService::new(SubscriptionFanOutLayer::new(hub.clone()).layer(backend)),
+        None => backend,
+    };
+    // Side connections go to the main target, so commands routed elsewhere aren't isolated
+    let backend = match &config.isolation {
+        Some(isolation) => ProxyService::new(IsolationLayer::new(isolation.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = if config.routes.is_empty() {
+        backend
+    } else {
+        ProxyService::new(
+            RoutingBackend::new(backend, config.routes.clone()).with_user(client_user.clone()),
+        )
+    };
+    let backend = match &config.fair {
+        Some(fair) => ProxyService::new(fair.layer(backend)),
+        None => backend,
+    };
+    let backend = if config.inject_latency.is_empty() {
+        backend
+    } else {
+        ProxyService::new(LatencyInjectionLayer::new(config.inject_latency.clone()).layer(backend))
+    };
+    // Error injection can be switched on at runtime, so its layer is always present
+    let backend = ProxyService::new(ErrorInjectionLayer::new(config.faults.clone()).layer(backend));
+
+    let backend = match &config.secondary {
+        Some(secondary) => {
+            with_secondary(backend, secondary, config.stats.clone(), connection_id).await
+        }
+        None => backend,
+    };
+    let backend = match &config.key_prefix {
+        Some(prefix) => ProxyService::new(KeyPrefixLayer::new(prefix.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = if config.user_key_prefixes.is_empty() {
+        backend
+    } else {
+        ProxyService::new(
+            KeyPrefixLayer::per_user(config.user_key_prefixes.clone(), client_user.clone())
+                .layer(backend),
+        )
+    };
+    let backend = match &config.checksums {
+        Some(checksums) => ProxyService::new(checksums.layer(backend)),
+        None => backend,
+    };
+    let backend = match &config.encryption {
+        Some(encryption) => ProxyService::new(encryption.layer(backend)),
+        None => backend,
+    };
+    let backend = if config.compression.is_empty() {
+        backend
+    } else {
+        ProxyService::new(CompressionLayer::new(config.compression.clone()).layer(backend))
+    };
+    let backend = if config.rewrite.is_empty() {
+        backend
+    } else {
+        ProxyService::new(RewriteLayer::new(config.rewrite.clone()).layer(backend))
+    };
+    let backend = if config.ttl_policy.is_empty() {
+        backend
+    } else {
+        ProxyService::new(TtlPolicyLayer::new(config.ttl_policy).layer(backend))
+    };
+    let backend = if config.translate_legacy_commands {
+        ProxyService::new(LegacyCommandLayer::new().layer(backend))
+    } else {
+        backend
+    };
+    let backend = match &config.retry {
+        Some(retry) => ProxyService::new(retry.layer(backend)),
+        None => backend,
+    };
+    let backend = match &config.loaded_scripts {
+        Some(scripts) => ProxyService::new(ScriptCacheLayer::new(scripts.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = match config.timeout {
+        Some((first_frame, last_frame)) => {
+            let mut layer = TimeoutLayer::new(config.stats.clone(), first_frame);
+            if let Some(last_frame) = last_frame {
+                layer = layer.with_last_frame(last_frame);
+            }
+            ProxyService::new(layer.layer(backend))
+        }
+        None => backend,
+    };
+    // Outside of the timeouts, so that commands timing out count as failures
+    let backend = match &config.breaker {
+        Some(breaker) => {
+            ProxyService::new(CircuitBreakerLayer::new(breaker.clone()).layer(backend))
+        }
+        None => backend,
+    };
+    let backend = match &config.concurrency {
+        Some(concurrency) => {
+            ProxyService::new(ConcurrencyLayer::new(concurrency.clone()).layer(backend))
+        }
+        None => backend,
+    };
+    // Rate limits and command rules can be (un)set by a reload, so their layers are always present
+    let backend =
+        ProxyService::new(RateLimitLayer::watch(config.rate_limits.clone()).layer(backend));
+    let backend = match &config.quotas {
+        Some(quotas) => ProxyService::new(
+            quotas
+                .for_client(client_addr.clone(), client_user.clone())
+                .layer(backend),
+        ),
+        None => backend,
+    };
+    let backend = match &config.saturation {
+        Some(saturation) => ProxyService::new(saturation.for_connection().layer(backend)),
+        None => backend,
+    };
+    // Read-only mode can be switched on at runtime, so its layer is always present
+    let backend = ProxyService::new(ReadOnlyLayer::new(config.read_only.clone()).layer(backend));
+    let backend = match &config.cache {
+        Some(cache) => ProxyService::new(CacheLayer::new(cache.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = match &config.command_replies {
+        Some(replies) => ProxyService::new(CommandCacheLayer::new(replies.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = match &config.config_view {
+        Some(view) => ProxyService::new(ConfigCommandLayer::new(view.clone()).layer(backend)),
+        None => backend,
+    };
+    let backend = match &config.throttle {
+        Some(throttle) => ProxyService::new(throttle.layer(backend)),
+        None => backend,
+    };
+    // Maintenance can be switched on at runtime, so its layer is always present
+    let backend =
+        ProxyService::new(MaintenanceLayer::new(config.maintenance.clone()).layer(backend));
+
+    let client_name = ClientName::new();
+    let backend = ProxyService::new(
+        ClientNameLayer::new(connection_id, client_name.clone(), config.stats.clone())
+            .layer(backend),
+    );
+
+    // Innermost first, so each layer wraps those listed after it
+    let mut service = backend;
+    for layer in config.stack.iter().rev() {
+        service = match layer {
+            StackLayer::Logging => ProxyService::new(
+                ProxyLoggerLayer::new(connection_id.to_string())
+                    .with_format(config.log_format)
+                    .with_sampling(config.log_sampling.clone())
+                    .with_truncation(config.log_truncation)
+                    .with_command_levels(config.log_levels.clone())
+                    .with_redaction(config.redaction.clone())
+                    .with_client_name(client_name.clone())
+                    .layer(service),
+            ),
+            StackLayer::Latency => {
+                ProxyService::new(LatencyLayer::new(config.stats.clone()).layer(service))
+            }
+            StackLayer::Slowlog => match config.slowlog_threshold {
+                Some(threshold) => ProxyService::new(
+                    SlowlogLayer::new(config.stats.clone(), threshold, connection_id.to_string())
+                        .layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Hotkeys => match config.hot_key_sample_rate {
+                Some(sample_rate) => ProxyService::new(
+                    HotKeyLayer::new(config.stats.clone(), sample_rate).layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Keyspace => match config.key_space_sample_rate {
+                Some(sample_rate) => ProxyService::new(
+                    KeySpaceLayer::new(config.stats.clone(), sample_rate).layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Admin => {
+                let mut admin = AdminLayer::new(config.stats.clone())
+                    .with_reload(config.reload.clone())
+                    .with_faults(config.faults.clone())
+                    .with_read_only(config.read_only.clone())
+                    .with_maintenance(config.maintenance.clone())
+                    .with_drain(config.shutdown.clone());
+                if config.info_section {
+                    admin = admin.with_info_section();
+                }
+                ProxyService::new(admin.layer(service))
+            }
+            StackLayer::Monitor => ProxyService::new(
+                MonitorLayer::new(config.stats.clone(), connection_id, client_addr.clone())
+                    .with_redaction(config.redaction.clone())
+                    .layer(service),
+            ),
+            StackLayer::Filter => ProxyService::new(
+                CommandFilterLayer::watch(config.command_rules.clone()).layer(service),
+            ),
+            StackLayer::Acl if !config.acl.is_empty() => ProxyService::new(
+                AclLayer::new(config.acl.clone(), client_addr.clone(), client_user.clone())
+                    .layer(service),
+            ),
+            StackLayer::Acl => service,
+            // Innermost last, so the first script sees commands first
+            #[cfg(feature = "lua")]
+            StackLayer::Scripts => config
+                .scripts
+                .iter()
+                .rev()
+                .fold(service, |service, script| {
+                    ProxyService::new(
+                        cabbage::middleware::script::ScriptLayer::new(script.clone())
+                            .layer(service),
+                    )
+                }),
+            // Innermost last, so the first plugin sees commands first
+            #[cfg(feature = "plugins")]
+            StackLayer::Plugins => config
+                .plugins
+                .iter()
+                .rev()
+                .fold(service, |service, plugin| {
+                    ProxyService::new(
+                        cabbage::middleware::plugin::PluginLayer::new(plugin.clone())
+                            .layer(service),
+                    )
+                }),
+            StackLayer::Auth => match &config.auth {
+                Some(credentials) => ProxyService::new(
+                    ProxyAuthLayer::new(credentials.clone(), client_user.clone()).layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Limits if !config.limits.is_empty() => {
+                ProxyService::new(SizeLimitLayer::new(config.limits).layer(service))
+            }
+            StackLayer::Limits => service,
+            StackLayer::Audit => match &config.audit {
+                Some(audit) => ProxyService::new(
+                    AuditLayer::new(
+                        audit.clone(),
+                        connection_id,
+                        client_addr.clone(),
+                        client_user.clone(),
+                    )
+                    .with_redaction(config.redaction.clone())
+                    .layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Capture => match &config.capture {
+                Some(capture) => ProxyService::new(
+                    CaptureLayer::new(capture.clone(), connection_id)
+                        .with_redaction(config.redaction.clone())
+                        .layer(service),
+                ),
+                None => service,
+            },
+            StackLayer::Transcripts => match &config.transcripts {
+                Some(transcripts) => ProxyService::new(
+                    TranscriptLayer::new(transcripts.clone(), connection_id)
+                        .with_redaction(config.redaction.clone())
+                        .layer(service),
+                ),
+                None => service,
+            },
+            #[cfg(feature = "otel")]
+            StackLayer::Trace if config.trace => ProxyService::new(
+                cabbage::middleware::trace::ProxyTraceLayer::new(connection_id.to_string())
+                    .layer(service),
+            ),
+            // Layers of features this build lacks, or which aren't configured
+            _ => service,
+        };
+    }
+    cabbage::Result::Ok(service)
+}
+
+/// Load the configuration again with `load` each time `reload` is notified, applying log levels,
+/// command rules, and rate limits live and warning about changes which need a restart. Log levels
+/// are process-wide, so a tenant's reload leaves them to the main proxy's.
+async fn reload_config(
+    running: Config,
+    tenant: Option<String>,
+    load: impl Fn() -> Result<Config>,
+    reload: Arc<Notify>,
+    command_rules: watch::Sender<CommandRules>,
+    rate_limits: watch::Sender<Option<RateLimits>>,
+) {
+    let label = match &tenant {
+        Some(name) => format!(" for tenant '{name}'"),
+        None => String::new(),
+    };
+    loop {
+        reload.notified().await;
+        let config = match load() {
+            Result::Ok(config) => config,
+            Err(e) => {
+                log::error!("Configuration reload{label} failed, keeping the current one: {e:#}");
+                continue;
+            }
+        };
+        if tenant.is_none() {
+            let levels = log_levels(Some(&config.logging.levels))
+                .and_then(|levels| initialize_logging(&levels, running.logging.format));
+            if let Err(e) = levels {
+                log::error!("Failed to reload log levels: {e:#}");
+            }
+        }
+        command_rules.send_replace(config.middleware.command_rules());
+        rate_limits.send_replace(
+            config
+                .middleware
+                .rate_limit
+                .as_ref()
+                .map(RateLimitConfig::limits),
+        );
+
+        let restart_required = running.restart_required(&config);
+        if restart_required.is_empty() {
+            log::info!("Configuration reloaded{label}");
+        } else {
+            log::warn!(
+                "Configuration reloaded{label}, but changes to {} take effect only after a restart",
+                restart_required.join(", ")
+            );
+        }
+    }
+}
+
+/// Serve the main proxy and every tenant's alongside it until all have shut down, or one fails
+async fn proxy_tenants(
+    config: Config,
+    load: impl Fn() -> Result<Config> + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    let load = Arc::new(load);
+    let mut proxies = Vec::with_capacity(config.tenants.len() + 1);
+    for tenant in &config.tenants {
+        let name = tenant.name.clone();
+        let load = load.clone();
+        proxies.push(
+            proxy(config.tenant(&name)?, Some(name.clone()), move || {
+                Ok(load()?.tenant(&name)?)
+            })
+            .boxed(),
+        );
+    }
+    proxies.push(proxy(config, None, move || load()).boxed());
+    futures::future::try_join_all(proxies).await?;
+    Ok(())
+}
+
+/// Listen on `address`, with `reuseport_acceptors` listeners (0 for one per CPU) sharing it
+/// through `SO_REUSEPORT` if given and it's a TCP address
+async fn bind_listeners(
+    address: &str,
+    reuseport_acceptors: Option<usize>,
+) -> anyhow::Result<Vec<Listener>> {
+    let acceptors = match reuseport_acceptors {
+        Some(acceptors) if !is_unix_address(address) && !is_pipe_address(address) => acceptors,
+        _ => {
+            let listener = Listener::bind(address)
+                .await
+                .with_context(|| format!("Failed to listen on {address}"))?;
+            return Ok(vec![listener]);
+        }
+    };
+    let addr = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{address} didn't resolve"))?;
+    let acceptors = match acceptors {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    log::info!("Accepting on {address} with {acceptors} SO_REUSEPORT listeners");
+    Ok(bind_reuseport(addr, acceptors)?
+        .into_iter()
+        .map(Listener::from)
+        .collect())
+}
+
+/// Serve `config`'s listeners, as the tenant called `tenant` if it isn't the main proxy
+async fn proxy(
+    config: Config,
+    tenant: Option<String>,
+    load: impl Fn() -> Result<Config> + Send + 'static,
+) -> anyhow::Result<()> {
+    let mut client_listeners = vec![];
+    if config.listen.systemd {
+        client_listeners =
+            Listener::from_systemd().context("Failed to take sockets from systemd")?;
+    } else {
+        for address in config.listen.addresses() {
+            client_listeners
+                .extend(bind_listeners(address, config.listen.reuseport_acceptors).await?);
+        }
+    }
+    let mut addresses: Vec<_> = client_listeners
+        .iter()
+        .filter_map(|listener| Some(listener.local_addr().ok()?.to_string()))
+        .collect();
+    // SO_REUSEPORT listeners share an address
+    addresses.dedup();
+
+    match &tenant {
+        Some(name) => log::info!(
+            "Proxy for tenant '{name}' listening on {} -> {}",
+            addresses.join(", "),
+            config.target.address
+        ),
+        None => log::info!(
+            "Proxy listening on {} -> {}",
+            addresses.join(", "),
+            config.target.address
+        ),
+    }
+
+    let target = &config.target;
+    let handshake = target.handshake();
+    let backend = if target.cluster {
+        let slots = Arc::new(
+            ClusterSlots::new(vec![target.address.clone()]).with_handshake(handshake.clone()),
+        );
+        slots
+            .refresh()
+            .await
+            .context("Failed to load cluster slot map")?;
+        Backend::Cluster(slots)
+    } else if let Some(master_name) = &target.master_name {
+        let master = Arc::new(SentinelMaster::new(
+            target.sentinels.clone(),
+            master_name.clone(),
+        ));
+        master
+            .resolve()
+            .await
+            .context("Failed to resolve master through sentinel")?;
+        tokio::spawn(master.clone().watch_failovers());
+        Backend::Sentinel(master, handshake)
+    } else if !target.failover.is_empty() {
+        let addresses = std::iter::once(&target.address)
+            .chain(&target.failover)
+            .cloned()
+            .collect();
+        Backend::Failover(Arc::new(FailoverTargets::new(addresses)), handshake)
+    } else if !target.balance.is_empty() {
+        let targets = BalancedTargets::new(target.balance.clone())
+            .with_policy(target.balance_policy)
+            .with_stickiness(target.balance_stickiness);
+        Backend::Balanced(Arc::new(targets), handshake)
+    } else {
+        Backend::Single(target.address.clone(), handshake)
+    };
+
+    let (command_rules_tx, command_rules) = watch::channel(config.middleware.command_rules());
+    let (rate_limits_tx, rate_limits) = watch::channel(
+        config
+            .middleware
+            .rate_limit
+            .as_ref()
+            .map(RateLimitConfig::limits),
+    );
+    let reload = reload_on_signal();
+    tokio::spawn(reload_config(
+        config.clone(),
+        tenant.clone(),
+        load,
+        reload.clone(),
+        command_rules_tx,
+        rate_limits_tx,
+    ));
+
+    let middleware = &config.middleware;
+    // The exporter is process-wide, and tenants share the main proxy's
+    #[cfg(feature = "otel")]
+    let _tracer_provider = middleware
+        .otlp_endpoint
+        .as_deref()
+        .filter(|_| tenant.is_none())
+        .map(cabbage::middleware::trace::init_otlp)
+        .transpose()
+        .context("Failed to initialize OTLP exporter")?;
+    #[cfg(not(feature = "otel"))]
+    if tenant.is_none() && middleware.otlp_endpoint.is_some() {
+        log::warn!("Ignoring otlp_endpoint: cabbage was built without the otel feature");
+    }
+    #[cfg(feature = "plugins")]
+    let plugins = middleware
+        .plugins
+        .iter()
+        .map(|path| cabbage::middleware::plugin::Plugin::load(path))
+        .collect::<cabbage::Result<Vec<_>>>()?;
+    #[cfg(not(feature = "plugins"))]
+    if !middleware.plugins.is_empty() {
+        log::warn!("Ignoring plugins: cabbage was built without the plugins feature");
+    }
+    #[cfg(feature = "lua")]
+    let scripts = middleware
+        .scripts
+        .iter()
+        .map(|path| cabbage::middleware::script::Script::load(path))
+        .collect::<cabbage::Result<Vec<_>>>()?;
+    #[cfg(not(feature = "lua"))]
+    if !middleware.scripts.is_empty() {
+        log::warn!("Ignoring scripts: cabbage was built without the lua feature");
+    }
+
+    let stats = Stats::new();
+    stats.slowlog.set_capacity(config.stats.slowlog_max_len);
+    stats.hot_keys.set_capacity(config.stats.hot_key_capacity);
+    let snapshot_path = config.stats.snapshot_path.clone();
+    if let Some(path) = &snapshot_path {
+        // A snapshot which can't be read costs its history, not the proxy
+        match StatsSnapshot::load(path) {
+            Result::Ok(Some(snapshot)) => {
+                log::info!("Restoring statistics saved in {}", path.display());
+                stats.restore(snapshot);
+            }
+            Result::Ok(None) => {}
+            Err(e) => log::warn!("Not restoring statistics: {e}"),
+        }
+        tokio::spawn(stats::save_periodically(
+            stats.clone(),
+            path.clone(),
+            Duration::from_secs(config.stats.snapshot_interval_secs),
+        ));
+    }
+    if let Some(interval) = config.stats.interval_secs {
+        tokio::spawn(stats::log_periodically(
+            stats.clone(),
+            Duration::from_secs(interval),
+            tenant.clone(),
+        ));
+    }
+    if let Some(address) = &config.stats.statsd_address {
+        let exporter =
+            StatsdExporter::connect(address, &config.stats.statsd_prefix, tenant.as_deref())
+                .await?;
+        tokio::spawn(exporter.push_periodically(
+            stats.clone(),
+            Duration::from_secs(config.stats.statsd_interval_secs),
+        ));
+    }
+    if !config.stats.latency_slos.is_empty() {
+        let mut monitor = SloMonitor::new(
+            config.stats.latency_slos.clone(),
+            Duration::from_secs(config.stats.slo_interval_secs),
+        );
+        if let Some(webhook) = &config.stats.slo_webhook {
+            monitor = monitor.with_webhook(webhook.clone());
+        }
+        if let Some(tenant) = &tenant {
+            monitor = monitor.for_tenant(tenant);
+        }
+        tokio::spawn(monitor.watch_periodically(stats.clone()));
+    }
+    let target = &config.target;
+    let pool = if target.pool.is_enabled() {
+        let pool_target = match &backend {
+            Backend::Single(address, _) => PoolTarget::Single(address.clone()),
+            Backend::Sentinel(master, _) => PoolTarget::Sentinel(master.clone()),
+            Backend::Failover(targets, _) => PoolTarget::Failover(targets.clone()),
+            Backend::Cluster(_) | Backend::Balanced(..) => {
+                bail!("A target connection pool can't be kept for a cluster or balanced targets")
+            }
+        };
+        let mut pool = TargetPool::new(pool_target, target.handshake(), target.pool.min_size)
+            .with_capacities(config.listen.queues)
+            .with_stats(stats.clone());
+        if let Some(max_size) = target.pool.max_size {
+            pool = pool.with_max_size(
+                max_size,
+                Duration::from_millis(target.pool.max_wait_ms),
+                target.pool.overflow,
+            );
+        }
+        let pool = Arc::new(pool);
+        // Clients wait in the listen backlog meanwhile
+        match pool.fill().await {
+            Result::Ok(ready) => log::info!("Warmed {ready} target connections"),
+            Err(e) => log::warn!("{e:#}"),
+        }
+        tokio::spawn(pool.clone().keep_warm());
+        Some(pool)
+    } else {
+        None
+    };
+    if let Some(health_check) = &target.health_check {
+        let health_target = match &backend {
+            Backend::Single(target_addr, _) => HealthTarget::Single(target_addr.clone()),
+            Backend::Cluster(slots) => HealthTarget::Cluster(slots.clone()),
+            Backend::Sentinel(master, _) => HealthTarget::Sentinel(master.clone()),
+            Backend::Failover(targets, _) => HealthTarget::Failover(targets.clone()),
+            Backend::Balanced(targets, _) => HealthTarget::Balanced(targets.clone()),
+        };
+        tokio::spawn(check_health(
+            health_target,
+            target.handshake(),
+            Duration::from_millis(health_check.interval_ms),
+            Duration::from_millis(health_check.timeout_ms),
+            stats.clone(),
+        ));
+    }
+
+    let cache = middleware.cache.as_ref().map(|cache| {
+        ReadCache::new(
+            Duration::from_millis(cache.ttl_ms),
+            cache.serve_stale_ms.map(Duration::from_millis),
+            cache.max_entries,
+            &cache.bypass_prefixes,
+            stats.clone(),
+        )
+    });
+    if let (Some(cache), Some(cache_config), Backend::Single(target_addr, handshake)) =
+        (&cache, &middleware.cache, &backend)
+        && cache_config.track_invalidations
+    {
+        tokio::spawn(track_invalidations(
+            cache.clone(),
+            target_addr.clone(),
+            handshake.clone(),
+            middleware.key_prefix.clone(),
+        ));
+    }
+
+    let encryption = match &middleware.encryption {
+        Some(encryption) => {
+            Some(EncryptionLayer::new(&encryption.key()?).with_prefixes(&encryption.prefixes))
+        }
+        None => None,
+    };
+    let checksums = middleware.checksum.as_ref().map(|checksum| {
+        let layer = ChecksumLayer::new(stats.clone()).with_prefixes(&checksum.prefixes);
+        if checksum.require {
+            layer.requiring_trailers()
+        } else {
+            layer
+        }
+    });
+
+    let capture = config
+        .capture
+        .directory
+        .as_ref()
+        .map(|directory| Capture::start(directory, config.capture.rollover()))
+        .transpose()?;
+    let audit = config.audit.path.as_ref().map(AuditLog::open).transpose()?;
+    let transcripts = config
+        .logging
+        .transcript_dir
+        .as_ref()
+        .map(Transcripts::start)
+        .transpose()?;
+
+    let listen = &config.listen;
+    let shutdown = shutdown_on_signal();
+    let mut serve_options = ServeOptions::default()
+        .with_shutdown(
+            shutdown.clone(),
+            Duration::from_secs(listen.drain_timeout_secs),
+        )
+        .with_stats(stats.clone());
+    if let Some(max_connections) = listen.max_connections {
+        serve_options = serve_options.with_max_connections(max_connections, listen.overflow);
+    }
+    if let Some(idle_timeout) = listen.idle_timeout_secs {
+        serve_options = serve_options.with_idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    if let Some(max_lifetime) = listen.max_lifetime_secs {
+        serve_options = serve_options.with_max_lifetime(Duration::from_secs(max_lifetime));
+    }
+    if let Some(output_buffer) = listen.output_buffer {
+        serve_options = serve_options.with_output_buffer_limit(output_buffer);
+    }
+    if let Some(max_frame_bytes) = config.limits.max_frame_bytes {
+        serve_options = serve_options.with_max_frame_bytes(max_frame_bytes);
+    }
+    if listen.proxy_protocol {
+        serve_options = serve_options.with_proxy_protocol(listen.trusted_proxies.iter().copied());
+    }
+    serve_options = serve_options.with_source_rules(listen.source_rules());
+    serve_options = serve_options.with_frontend(listen.frontend);
+    serve_options = serve_options.with_queue_capacities(listen.queues);
+    serve_options = serve_options.with_socket_options(listen.socket.clone());
+    if listen.passthrough {
+        let Backend::Single(target_addr, handshake) = backend else {
+            bail!("Passthrough relaying needs a single target");
+        };
+        let relayed =
+            relay_listeners(client_listeners, target_addr, handshake, serve_options).await;
+        save_stats(&stats, snapshot_path.as_deref());
+        return Ok(relayed?);
+    }
+
+    let notifications = Arc::new(match &backend {
+        Backend::Single(address, handshake) => NotificationSource::Single {
+            address: address.clone(),
+            handshake: handshake.clone(),
+        },
+        Backend::Cluster(slots) => NotificationSource::Cluster(slots.clone()),
+        Backend::Sentinel(master, handshake) => NotificationSource::Sentinel {
+            master: master.clone(),
+            handshake: handshake.clone(),
+        },
+        Backend::Failover(targets, handshake) => NotificationSource::Failover {
+            targets: targets.clone(),
+            handshake: handshake.clone(),
+        },
+        Backend::Balanced(targets, handshake) => NotificationSource::Balanced {
+            targets: targets.clone(),
+            handshake: handshake.clone(),
+        },
+    });
+    let command_replies = middleware.command_cache_ttl_ms.map(|ttl_ms| {
+        Arc::new(CommandReplies::new(
+            Duration::from_millis(ttl_ms),
+            stats.clone(),
+        ))
+    });
+    let quotas = middleware
+        .quota
+        .as_ref()
+        .map(|quota| Arc::new(Quotas::new(quota.quota(), stats.clone())));
+    let saturation = middleware.saturation.as_ref().map(|saturation| {
+        Arc::new(Saturation::new(
+            saturation.per_connection,
+            saturation.total,
+            stats.clone(),
+        ))
+    });
+    let isolation = middleware.isolate.as_ref().map(|isolate| {
+        Arc::new(match (&backend, isolate.mode) {
+            (Backend::Single(address, handshake), IsolationMode::Side) => isolate
+                .isolation()
+                .on_side_connections(address, handshake.clone()),
+            _ => isolate.isolation(),
+        })
+    });
+    let loaded_scripts = middleware.track_scripts.then(|| {
+        Arc::new(match &backend {
+            Backend::Failover(targets, handshake) => {
+                LoadedScripts::new().with_replicas(targets.addresses().to_vec(), handshake.clone())
+            }
+            Backend::Balanced(targets, handshake) => {
+                LoadedScripts::new().with_replicas(targets.addresses(), handshake.clone())
+            }
+            _ => LoadedScripts::new(),
+        })
+    });
+    let subscription_hub = match &backend {
+        Backend::Single(address, handshake) if middleware.share_subscriptions => {
+            let hub = Arc::new(SubscriptionHub::new(address, handshake.clone()));
+            tokio::spawn(hub.clone().run());
+            Some(hub)
+        }
+        _ => None,
+    };
+    let service_config = ServiceConfig {
+        backend,
+        log_format: config.logging.format,
+        log_sampling: Arc::new(config.logging.sampling()),
+        log_truncation: config.logging.truncation(),
+        log_levels: Arc::new(config.logging.command_levels()),
+        redaction: {
+            let defaults = if config.logging.default_redaction {
+                RedactionRules::default()
+            } else {
+                RedactionRules::none()
+            };
+            Arc::new(
+                config
+                    .logging
+                    .redact
+                    .iter()
+                    .cloned()
+                    .fold(defaults, RedactionRules::with),
+            )
+        },
+        stats: stats.clone(),
+        slowlog_threshold: config.stats.slowlog_threshold_ms.map(Duration::from_millis),
+        hot_key_sample_rate: config.stats.hot_key_sample_rate,
+        key_space_sample_rate: config.stats.key_space_sample_rate,
+        info_section: config.stats.info_section,
+        command_rules,
+        auth: middleware
+            .auth
+            .as_ref()
+            .map(|auth| Arc::new(auth.credentials())),
+        acl: Arc::new(Acl::new(&middleware.acl)),
+        retry: (middleware.retry.retries > 0).then(|| {
+            let budget = TpsBudget::new(
+                RETRY_BUDGET_WINDOW,
+                RETRY_BUDGET_MIN_PER_SEC,
+                middleware.retry.budget_percent / 100.0,
+            );
+            RetryLayer::new(Arc::new(budget), middleware.retry.retries)
+        }),
+        breaker: middleware.circuit_breaker.as_ref().map(|circuit_breaker| {
+            CircuitBreaker::new(
+                circuit_breaker.failures,
+                Duration::from_millis(circuit_breaker.cooldown_ms),
+            )
+        }),
+        timeout: config.timeouts.first_frame_ms.map(|first_frame_ms| {
+            (
+                Duration::from_millis(first_frame_ms),
+                config.timeouts.reply_ms.map(Duration::from_millis),
+            )
+        }),
+        target_timeout: config.timeouts.target_ms.map(Duration::from_millis),
+        queues: config.listen.queues,
+        rate_limits,
+        quotas,
+        saturation,
+        concurrency: middleware.concurrency.as_ref().map(|concurrency| {
+            Arc::new(CommandConcurrency::new(
+                &concurrency.limits,
+                concurrency.mode,
+            ))
+        }),
+        throttle: middleware
+            .throttle
+            .as_ref()
+            .map(|throttle| Arc::new(ThrottleLayer::new(throttle.bytes_per_sec, throttle.scope))),
+        fair: middleware.max_in_flight.map(FairLayer::new),
+        cache,
+        command_replies,
+        config_view: middleware
+            .config_command
+            .as_ref()
+            .map(|config_command| Arc::new(config_command.view(&config.listen))),
+        isolation,
+        loaded_scripts,
+        subscription_hub,
+        reload,
+        shutdown: shutdown.clone(),
+        pool,
+        key_prefix: middleware.key_prefix.clone(),
+        compression: middleware.compression.clone(),
+        rewrite: middleware.rewrite.clone(),
+        ttl_policy: middleware.ttl,
+        translate_legacy_commands: middleware.translate_legacy_commands,
+        inject_latency: middleware.inject_latency.clone(),
+        faults: ErrorInjection::new(middleware.inject_errors.clone()),
+        read_only: ReadOnlyMode::new(middleware.read_only),
+        maintenance: middleware.maintenance.maintenance(),
+        encryption,
+        checksums,
+        capture,
+        audit,
+        transcripts,
+        limits: config.limits,
+        stack: middleware
+            .stack
+            .clone()
+            .unwrap_or_else(|| DEFAULT_STACK.to_vec()),
+        health_gate: config.target.health_check.is_some(),
+        lazy_connect: config.target.lazy_connect,
+        routes: Arc::new(
+            Routes::new(
+                config.target.routes.clone(),
+                config.target.key_routes.clone(),
+                config.target.handshake(),
+            )
+            .with_user_targets(middleware.auth.iter().flat_map(AuthConfig::user_targets)),
+        ),
+        notifications,
+        user_key_prefixes: Arc::new(
+            middleware
+                .auth
+                .as_ref()
+                .map(AuthConfig::user_key_prefixes)
+                .unwrap_or_default(),
+        ),
+        secondary: match (
+            &middleware.mirror,
+            &middleware.dual_write,
+            &middleware.canary,
+        ) {
+            (Some(addr), _, _) => Some(Secondary::Shadow(addr.clone())),
+            (None, Some(addr), _) => Some(Secondary::DualWrite(addr.clone())),
+            (None, None, Some(addr)) => Some(Secondary::Canary(addr.clone())),
+            (None, None, None) => None,
+        },
+        #[cfg(feature = "plugins")]
+        plugins,
+        #[cfg(feature = "lua")]
+        scripts,
+        #[cfg(feature = "otel")]
+        trace: middleware.otlp_endpoint.is_some(),
+    };
+    #[cfg(feature = "http")]
+    if let Some(address) = &listen.http_address {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to bind HTTP address {address}"))?;
+        log::info!("Serving HTTP requests on {address}");
+        let service_config = service_config.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let make_service = move |id| create_proxy_service(service_config.clone(), id, None);
+            if let Err(e) = cabbage::http::serve(listener, make_service, shutdown).await {
+                log::error!("HTTP bridge failed: {e:#}");
+            }
+        });
+    }
+    #[cfg(not(feature = "http"))]
+    if listen.http_address.is_some() {
+        log::warn!("Ignoring http_address: cabbage was built without the http feature");
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(address) = &listen.grpc_address {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to bind gRPC address {address}"))?;
+        log::info!("Serving gRPC calls on {address}");
+        let service_config = service_config.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let make_service = move |id| create_proxy_service(service_config.clone(), id, None);
+            if let Err(e) = cabbage::grpc::serve(listener, make_service, shutdown).await {
+                log::error!("gRPC frontend failed: {e:#}");
+            }
+        });
+    }
+    #[cfg(not(feature = "grpc"))]
+    if listen.grpc_address.is_some() {
+        log::warn!("Ignoring grpc_address: cabbage was built without the grpc feature");
+    }
+    let make_service = move |connection_id, client_addr| {
+        create_proxy_service(service_config.clone(), connection_id, Some(client_addr))
+    };
+    let served = serve_listeners(client_listeners, make_service, serve_options).await;
+    save_stats(&stats, snapshot_path.as_deref());
+    Ok(served?)
+}
+
+/// Save a last snapshot of `stats` to `path`, if statistics are kept across restarts
+fn save_stats(stats: &Stats, path: Option<&Path>) {
+    if let Some(path) = path
+        && let Err(e) = stats.save(path)
+    {
+        log::warn!("Failed to save statistics: {e}");
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print a random haiku
+    Haiku(HaikuOptions),
+    Proxy(Box<ProxyOptions>),
+    /// Replay captured traffic against a target
+    Replay(ReplayOptions),
+    /// Print captured traffic, optionally only that of certain connections, commands, or times
+    Inspect(InspectOptions),
+    /// Turn captured traffic into a script redis-cli can run
+    Export(ExportOptions),
+    /// Report the commands, keys, value sizes, and pipelining making up captured traffic
+    Analyze(AnalyzeOptions),
+    /// Check that an audit log's hash chain is intact, printing its head
+    VerifyAudit(VerifyAuditOptions),
+    /// Drive a mix of GETs and SETs at a target, reporting throughput and latency
+    Bench(BenchOptions),
+    /// Check that the configured target is reachable and answers, exiting nonzero if not
+    Check(CheckOptions),
+    /// Watch a running cabbage's command rates, latencies, hot keys, and connections live
+    Top(TopOptions),
+}
+
+/// Set in the environment of the background process `--daemonize` starts, so it runs the proxy
+/// rather than starting another
+static DAEMON_ENV: &str = "CABBAGE_DAEMON";
+
+/// Whether this is the background process `--daemonize` started
+fn is_daemon() -> bool {
+    std::env::var_os(DAEMON_ENV).is_some()
+}
+
+/// Run this command again as a background process, in a session of its own so the terminal's
+/// hangups and signals don't reach it. It logs only to `log_file`, to which anything else it
+/// writes to stderr (a panic, say) is appended too; without one, its output is discarded.
+fn daemonize(log_file: Option<&Path>) -> anyhow::Result<()> {
+    let errors = match log_file {
+        Some(path) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?
+            .into(),
+        None => std::process::Stdio::null(),
+    };
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DAEMON_ENV, "1")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(errors);
+    #[cfg(unix)]
+    // SAFETY: setsid is async-signal-safe, so may be called between fork and exec
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(&mut command, || {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            std::io::Result::Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .context("Failed to start the background process")?;
+    eprintln!(
+        "cabbage is running in the background as process {}",
+        child.id()
+    );
+    Ok(())
+}
+
+/// A file holding the process ID, removed when dropped
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: PathBuf) -> anyhow::Result<Self> {
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pid file {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!("Failed to remove pid file {}: {e}", self.0.display());
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let context = GlobalOptions {
+        log_levels: args.log_levels,
+        log_format: args.log_format,
+    };
+    let proxy_config = match &args.command {
+        Command::Proxy(options) => Some(options.config(&context)?),
+        Command::Haiku(_)
+        | Command::Replay(_)
+        | Command::Inspect(_)
+        | Command::Export(_)
+        | Command::Analyze(_)
+        | Command::VerifyAudit(_)
+        | Command::Bench(_)
+        | Command::Check(_)
+        | Command::Top(_) => None,
+    };
+    let (levels_arg, log_format) = match &proxy_config {
+        Some(config) => (Some(&config.logging.levels), config.logging.format),
+        None => (
+            context.log_levels.as_ref(),
+            context.log_format.unwrap_or_default(),
+        ),
+    };
+
+    if let Command::Proxy(options) = &args.command
+        && options.daemonize
+        && !is_daemon()
+    {
+        let log_file = proxy_config
+            .as_ref()
+            .and_then(|c| c.logging.file.path.as_deref());
+        return daemonize(log_file);
+    }
+
+    if let Some(config) = &proxy_config
+        && let Some(path) = &config.logging.file.path
+    {
+        let file = RotatingFile::open(path, config.logging.file.rotation())?;
+        let _ = LOG_FILE.set(file);
+    }
+    let _ = initialize_logging(&log_levels(levels_arg)?, log_format);
+    log::trace!("Logging initialized, commands parsed...");
+
+    // The console's layer must be installed before the runtime spawns anything it should see
+    #[cfg(feature = "console")]
+    if let Some(address) = proxy_config
+        .as_ref()
+        .and_then(|c| c.runtime.console_address)
+    {
+        console_subscriber::ConsoleLayer::builder()
+            .server_addr(address)
+            .init();
+        log::info!("Serving tokio-console on {address}");
+    }
+    #[cfg(not(feature = "console"))]
+    if proxy_config
+        .as_ref()
+        .is_some_and(|c| c.runtime.console_address.is_some())
+    {
+        log::warn!("Ignoring console_address: cabbage was built without the console feature");
+    }
+    // Only the proxy is tuned; other commands take the defaults
+    let runtime = proxy_config
+        .as_ref()
+        .map(|config| config.runtime.clone())
+        .unwrap_or_default()
+        .build()
+        .context("Failed to start the tokio runtime")?;
+    runtime.block_on(run(args.command, context, proxy_config))
+}
+
+async fn run(
+    command: Command,
+    context: GlobalOptions,
+    proxy_config: Option<Config>,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Haiku(options) => haiku(&context, &options).await?,
+        Command::Replay(options) => replay(&context, &options).await?,
+        Command::Inspect(options) => inspect(&context, &options)?,
+        Command::Export(options) => export_script(&context, &options)?,
+        Command::Analyze(options) => analyze_captures(&context, &options)?,
+        Command::VerifyAudit(options) => verify_audit(&context, &options)?,
+        Command::Bench(options) => bench(&context, &options).await?,
+        Command::Check(options) => check_target(&context, &options).await?,
+        Command::Top(options) => top(&context, &options).await?,
+        Command::Proxy(options) => {
+            let _pid_file = options.pid_file.clone().map(PidFile::create).transpose()?;
+            proxy_tenants(proxy_config.unwrap_or_default(), move || {
+                options.config(&context)
+            })
+            .await?
+        }
     }
     Ok(())
 }
@@ -0,0 +1,87 @@
+//! Reusable connection buffers.
+//!
+//! Each client and target connection reads and writes through a pair of buffers which grow to fit
+//! the largest frames it carries. `BUFFERS` keeps the buffers of closed connections for new ones
+//! to take over, so churning connections don't allocate (and regrow) fresh buffers every time.
+//! Buffers which have grown beyond `MAX_POOLED_CAPACITY` are freed instead, so a single huge
+//! request doesn't pin its memory for the life of the process.
+
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Encoder, Framed, FramedParts};
+
+/// Capacity of a newly allocated buffer, as `Framed` would give it
+static INITIAL_CAPACITY: usize = 8 * 1024;
+/// Buffers larger than this aren't kept
+static MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+/// Buffers kept at most; any more are freed
+static MAX_POOLED_BUFFERS: usize = 1024;
+
+lazy_static! {
+    /// The process-wide pool shared by every connection
+    pub static ref BUFFERS: BufferPool = BufferPool::new();
+}
+
+#[derive(Default)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An empty buffer, reused if one is available
+    pub fn get(&self) -> BytesMut {
+        self.buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_else(|| BytesMut::with_capacity(INITIAL_CAPACITY))
+    }
+
+    /// Return `buffer` to the pool
+    pub fn put(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        // A read buffer whose frames were split off may have little capacity left of its own
+        if !(INITIAL_CAPACITY..=MAX_POOLED_CAPACITY).contains(&buffer.capacity()) {
+            return;
+        }
+        if let Ok(mut buffers) = self.buffers.lock()
+            && buffers.len() < MAX_POOLED_BUFFERS
+        {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Buffers currently pooled
+    pub fn len(&self) -> usize {
+        self.buffers.lock().map(|b| b.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frame `io` with `codec`, which encodes `I`s, reading and writing through pooled buffers
+    pub fn framed<I, T, C>(&self, io: T, codec: C) -> Framed<T, C>
+    where
+        C: Encoder<I>,
+    {
+        let mut parts = FramedParts::new::<I>(io, codec);
+        parts.read_buf = self.get();
+        parts.write_buf = self.get();
+        Framed::from_parts(parts)
+    }
+
+    /// Return the buffers of a connection which is done with them, along with anything still
+    /// unread or unwritten in them
+    pub fn recycle<T, C>(&self, framed: Framed<T, C>) {
+        let parts = framed.into_parts();
+        self.put(parts.read_buf);
+        self.put(parts.write_buf);
+    }
+}
@@ -0,0 +1,288 @@
+//! Running the proxy in-process.
+//!
+//! `ProxyBuilder` assembles a proxy from a listen address, a target, and the middleware to run
+//! commands through, and starts serving it in the background, so tests and host applications
+//! can embed cabbage without reassembling what the `cabbage` binary does:
+//!
+//! ```no_run
+//! # async fn example() -> cabbage::Result<()> {
+//! use cabbage::ProxyBuilder;
+//! use cabbage::middleware::limits::RequestLimits;
+//!
+//! let proxy = ProxyBuilder::new("127.0.0.1:6379")
+//!     .listen("127.0.0.1:0")
+//!     .limits(RequestLimits {
+//!         max_value_bytes: Some(1 << 20),
+//!         ..RequestLimits::default()
+//!     })
+//!     .start()
+//!     .await?;
+//! println!("Proxying on {}", proxy.local_addr());
+//! proxy.shutdown();
+//! proxy.join().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::cluster::{ClusterBackend, ClusterSlots};
+use crate::error::{Error, Result};
+use crate::hooks::ConnectionHooks;
+use crate::listener::{ClientAddr, Listener};
+use crate::middleware::limits::{RequestLimits, SizeLimitLayer};
+use crate::middleware::notifications::{KeyspaceNotificationLayer, NotificationSource};
+use crate::proxy::{ServeOptions, serve_listeners};
+use crate::sentinel::{SentinelBackend, SentinelMaster};
+use crate::service::{Handshake, ProxyService, QueueCapacities, Resp2Backend, ResponseStream};
+use crate::stats::Stats;
+
+/// Wraps each connection's service in a layer given to `ProxyBuilder::layer`
+type BoxLayer = Arc<dyn Fn(ProxyService) -> ProxyService + Send + Sync>;
+
+enum Target {
+    Single(String),
+    Cluster(Vec<String>),
+    Sentinel {
+        sentinels: Vec<String>,
+        master_name: String,
+    },
+}
+
+/// Builds and starts a proxy; see the module documentation
+pub struct ProxyBuilder {
+    listen: String,
+    target: Target,
+    handshake: Handshake,
+    layers: Vec<BoxLayer>,
+    limits: RequestLimits,
+    capacities: QueueCapacities,
+    options: ServeOptions,
+}
+
+impl ProxyBuilder {
+    /// Proxy to the single Redis at `target`
+    pub fn new(target: impl Into<String>) -> Self {
+        Self::with_target(Target::Single(target.into()))
+    }
+
+    /// Proxy to a Redis Cluster, discovered from `seeds`
+    pub fn cluster(seeds: Vec<String>) -> Self {
+        Self::with_target(Target::Cluster(seeds))
+    }
+
+    /// Proxy to the master called `master_name`, as found through `sentinels`
+    pub fn sentinel(sentinels: Vec<String>, master_name: impl Into<String>) -> Self {
+        Self::with_target(Target::Sentinel {
+            sentinels,
+            master_name: master_name.into(),
+        })
+    }
+
+    fn with_target(target: Target) -> Self {
+        Self {
+            listen: "127.0.0.1:0".to_string(),
+            target,
+            handshake: Handshake::default(),
+            layers: vec![],
+            limits: RequestLimits::default(),
+            capacities: QueueCapacities::default(),
+            options: ServeOptions::default(),
+        }
+    }
+
+    /// Accept clients on `address` (`unix:<PATH>` for a Unix domain socket) rather than an
+    /// ephemeral local port
+    pub fn listen(mut self, address: impl Into<String>) -> Self {
+        self.listen = address.into();
+        self
+    }
+
+    /// Perform `handshake` on every connection made to the target
+    pub fn handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Run commands through `layer`, which sees them after any layers added before it
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<ProxyService> + Send + Sync + 'static,
+        L::Service: Service<BytesFrame, Response = ResponseStream, Error = Error> + Send + 'static,
+        <L::Service as Service<BytesFrame>>::Future: Send + 'static,
+    {
+        self.layers.push(Arc::new(move |service| {
+            ProxyService::new(layer.layer(service))
+        }));
+        self
+    }
+
+    /// Refuse requests over `limits` before they reach any layers
+    pub fn limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Size each connection's queues, and those of its connection to a single target, by
+    /// `capacities`
+    pub fn queue_capacities(mut self, capacities: QueueCapacities) -> Self {
+        self.capacities = capacities;
+        self
+    }
+
+    /// Run `hooks` as each connection is accepted, served, and closed
+    pub fn hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.options = self.options.with_hooks(hooks);
+        self
+    }
+
+    /// Serve connections according to `options`, whose shutdown token `ProxyHandle::shutdown`
+    /// cancels
+    pub fn serve_options(mut self, options: ServeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Bind the listen address, connect to the target as far as it needs up front, and start
+    /// serving clients in the background
+    pub async fn start(self) -> Result<ProxyHandle> {
+        let listener = Listener::bind(&self.listen)
+            .await
+            .map_err(|e| e.context(format!("Failed to listen on {}", self.listen)))?;
+        let local_addr = listener.local_addr()?;
+        let backend = match self.target {
+            Target::Single(address) => Backend::Single(address, self.handshake),
+            Target::Cluster(seeds) => {
+                let slots = Arc::new(ClusterSlots::new(seeds).with_handshake(self.handshake));
+                slots
+                    .refresh()
+                    .await
+                    .map_err(|e| e.context("Failed to load cluster slot map"))?;
+                Backend::Cluster(slots)
+            }
+            Target::Sentinel {
+                sentinels,
+                master_name,
+            } => {
+                let master = Arc::new(SentinelMaster::new(sentinels, master_name));
+                master
+                    .resolve()
+                    .await
+                    .map_err(|e| e.context("Failed to resolve master through sentinel"))?;
+                tokio::spawn(master.clone().watch_failovers());
+                Backend::Sentinel(master, self.handshake)
+            }
+        };
+        let notifications = Arc::new(match &backend {
+            Backend::Single(address, handshake) => NotificationSource::Single {
+                address: address.clone(),
+                handshake: handshake.clone(),
+            },
+            Backend::Cluster(slots) => NotificationSource::Cluster(slots.clone()),
+            Backend::Sentinel(master, handshake) => NotificationSource::Sentinel {
+                master: master.clone(),
+                handshake: handshake.clone(),
+            },
+        });
+        let stack = Arc::new(Stack {
+            backend,
+            notifications,
+            layers: self.layers,
+            limits: self.limits,
+            capacities: self.capacities,
+            stats: self.options.stats(),
+        });
+        let shutdown = self.options.shutdown_token();
+        let join = tokio::spawn(serve_listeners(
+            vec![listener],
+            move |connection_id, _client_addr| {
+                let stack = stack.clone();
+                async move { stack.service(connection_id).await }
+            },
+            self.options.with_queue_capacities(self.capacities),
+        ));
+        Ok(ProxyHandle {
+            local_addr,
+            shutdown,
+            join,
+        })
+    }
+}
+
+enum Backend {
+    Single(String, Handshake),
+    Cluster(Arc<ClusterSlots>),
+    Sentinel(Arc<SentinelMaster>, Handshake),
+}
+
+/// What each connection's service is assembled from
+struct Stack {
+    backend: Backend,
+    notifications: Arc<NotificationSource>,
+    layers: Vec<BoxLayer>,
+    limits: RequestLimits,
+    capacities: QueueCapacities,
+    stats: Arc<Stats>,
+}
+
+impl Stack {
+    async fn service(&self, connection_id: Uuid) -> Result<ProxyService> {
+        let mut service = match &self.backend {
+            Backend::Single(address, handshake) => {
+                let backend =
+                    Resp2Backend::connect_with_capacities(address, handshake, self.capacities)
+                        .await?;
+                log::info!("connection {connection_id}: connected with target at: {address}");
+                backend.close_client_on_disconnect(self.stats.clone(), connection_id);
+                ProxyService::new(backend)
+            }
+            Backend::Cluster(slots) => ProxyService::new(ClusterBackend::new(slots.clone())),
+            Backend::Sentinel(master, handshake) => ProxyService::new(
+                SentinelBackend::new(master.clone()).with_handshake(handshake.clone()),
+            ),
+        };
+        service = ProxyService::new(
+            KeyspaceNotificationLayer::new(self.notifications.clone()).layer(service),
+        );
+        // Innermost last, so the first layer added sees commands first
+        for layer in self.layers.iter().rev() {
+            service = layer(service);
+        }
+        if !self.limits.is_empty() {
+            service = ProxyService::new(SizeLimitLayer::new(self.limits).layer(service));
+        }
+        Ok(service)
+    }
+}
+
+/// A proxy started by `ProxyBuilder::start`
+pub struct ProxyHandle {
+    local_addr: ClientAddr,
+    shutdown: CancellationToken,
+    join: JoinHandle<Result<()>>,
+}
+
+impl ProxyHandle {
+    /// The address clients connect to, with the port picked if an ephemeral one was asked for
+    pub fn local_addr(&self) -> &ClientAddr {
+        &self.local_addr
+    }
+
+    /// Stop accepting clients and close connections once their outstanding replies are sent
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Wait for the proxy to stop, after `shutdown` or a failure
+    pub async fn join(self) -> Result<()> {
+        self.join
+            .await
+            .map_err(|e| Error::Other(format!("The proxy task panicked: {e}").into()))?
+    }
+}
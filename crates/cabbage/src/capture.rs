@@ -0,0 +1,329 @@
+//! Traffic capture files.
+//!
+//! A `Capture` records proxied frames to disk for offline analysis and replay, rolling over to a
+//! new file in its directory once the current one reaches a size or age limit. Frames are handed
+//! to a dedicated writer thread through a bounded queue; if the disk can't keep up, records are
+//! dropped (and counted) rather than slowing down clients.
+//!
+//! Each file starts with the 8-byte magic `CBGCAP01`, followed by records of:
+//!
+//! | bytes | contents                                              |
+//! |-------|-------------------------------------------------------|
+//! | 8     | timestamp, microseconds since the Unix epoch (LE u64) |
+//! | 16    | connection ID (UUID)                                  |
+//! | 1     | direction: 0 for a request, 1 for a response frame    |
+//! | 4     | length of the frame (LE u32)                          |
+//! | n     | the frame, RESP2 encoded                              |
+//!
+//! `cabbage inspect` prints captures, `cabbage analyze` reports on their workload, and
+//! `cabbage replay` replays them.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
+use tokio::sync::mpsc;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+
+static MAGIC: &[u8; 8] = b"CBGCAP01";
+/// Records waiting to be written beyond this many are dropped
+static QUEUE_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    Request,
+    /// Sent back to the client
+    Response,
+}
+
+/// A single captured frame
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub connection_id: Uuid,
+    pub direction: Direction,
+    pub frame: BytesFrame,
+}
+
+impl Record {
+    fn write_to(&self, out: &mut impl Write, scratch: &mut BytesMut) -> Result<usize> {
+        scratch.clear();
+        Resp2::default().encode(self.frame.clone(), scratch)?;
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        out.write_all(&micros.to_le_bytes())?;
+        out.write_all(self.connection_id.as_bytes())?;
+        out.write_all(&[match self.direction {
+            Direction::Request => 0,
+            Direction::Response => 1,
+        }])?;
+        out.write_all(&(scratch.len() as u32).to_le_bytes())?;
+        out.write_all(scratch)?;
+        Ok(8 + 16 + 1 + 4 + scratch.len())
+    }
+
+    /// Read the next record, or `None` at the end of the input
+    fn read_from(input: &mut impl Read) -> Result<Option<Self>> {
+        let malformed = || Error::Format("Capture record header is malformed".to_string());
+        let mut header = [0u8; 8 + 16 + 1 + 4];
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let micros = u64::from_le_bytes(header[0..8].try_into().map_err(|_| malformed())?);
+        let connection_id = Uuid::from_slice(&header[8..24]).map_err(|_| malformed())?;
+        let direction = match header[24] {
+            0 => Direction::Request,
+            1 => Direction::Response,
+            other => bail!(Format, "Unrecognized capture record direction {other}"),
+        };
+        let len = u32::from_le_bytes(header[25..29].try_into().map_err(|_| malformed())?) as usize;
+
+        let mut encoded = BytesMut::zeroed(len);
+        input
+            .read_exact(&mut encoded)
+            .map_err(|e| Error::io("Capture file ends mid-record", e))?;
+        let decoded = Resp2::default().decode(&mut encoded);
+        let Some(frame) = decoded.map_err(|e| Error::Format(e.to_string()))? else {
+            bail!(Format, "Capture record holds an incomplete frame");
+        };
+        Ok(Some(Self {
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            connection_id,
+            direction,
+            frame,
+        }))
+    }
+}
+
+/// When a capture moves on to a new file
+#[derive(Clone, Copy, Debug)]
+pub struct Rollover {
+    pub max_file_bytes: u64,
+    pub max_file_age: Duration,
+}
+
+/// A running capture, shared by every connection being recorded
+#[derive(Clone)]
+pub struct Capture {
+    records: mpsc::Sender<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Capture {
+    /// Start writing captures into `directory`, which is created if need be
+    pub fn start(directory: impl Into<PathBuf>, rollover: Rollover) -> Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory).map_err(|e| {
+            Error::io(
+                format!("Failed to create capture directory {}", directory.display()),
+                e,
+            )
+        })?;
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("cabbage-capture".to_string())
+            .spawn(move || {
+                if let Err(e) = write_captures(&directory, rollover, receiver) {
+                    log::error!("Capture stopped: {e:#}");
+                }
+            })?;
+        Ok(Self {
+            records: sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn record(&self, connection_id: Uuid, direction: Direction, frame: &BytesFrame) {
+        let record = Record {
+            timestamp: SystemTime::now(),
+            connection_id,
+            direction,
+            frame: frame.clone(),
+        };
+        if self.records.try_send(record).is_err()
+            && self.dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            log::warn!("Capture is falling behind, dropping records");
+        }
+    }
+
+    /// Records not captured because the writer had fallen behind (or stopped)
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Write records to a succession of files in `directory` until every `Capture` is dropped
+fn write_captures(
+    directory: &Path,
+    rollover: Rollover,
+    mut records: mpsc::Receiver<Record>,
+) -> Result<()> {
+    let mut scratch = BytesMut::new();
+    let mut file: Option<(BufWriter<File>, u64, Instant)> = None;
+    let mut sequence = 0;
+    while let Some(record) = records.blocking_recv() {
+        let expired = file.as_ref().is_none_or(|(_, written, opened)| {
+            *written >= rollover.max_file_bytes || opened.elapsed() >= rollover.max_file_age
+        });
+        if expired {
+            if let Some((mut out, _, _)) = file.take() {
+                out.flush()?;
+            }
+            sequence += 1;
+            let out = create_capture_file(directory, sequence)?;
+            file = Some((out, MAGIC.len() as u64, Instant::now()));
+        }
+        let Some((out, written, _)) = &mut file else {
+            continue;
+        };
+        *written += record.write_to(out, &mut scratch)? as u64;
+        if records.is_empty() {
+            out.flush()?;
+        }
+    }
+    if let Some((mut out, _, _)) = file {
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn create_capture_file(directory: &Path, sequence: u64) -> Result<BufWriter<File>> {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = directory.join(format!("cabbage-{started}-{sequence}.cap"));
+    log::info!("Capturing to {}", path.display());
+    let file = File::create(&path).map_err(|e| {
+        Error::io(
+            format!("Failed to create capture file {}", path.display()),
+            e,
+        )
+    })?;
+    let mut out = BufWriter::new(file);
+    out.write_all(MAGIC)?;
+    Ok(out)
+}
+
+/// Reads back the records of a capture file, in the order they were written
+pub struct CaptureReader<R> {
+    input: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|e| Error::io(format!("Failed to open capture file {}", path.display()), e))?;
+        Self::new(BufReader::new(file))
+            .map_err(|e| e.context(format!("Failed to read capture file {}", path.display())))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!(Format, "Not a cabbage capture file");
+        }
+        Ok(Self { input })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Record::read_from(&mut self.input).transpose()
+    }
+}
+
+/// Picks out the records of a capture sent by certain connections, within a time range, or for
+/// certain commands. Response frames go with the request they answer, so are picked out along
+/// with it.
+#[derive(Debug, Default)]
+pub struct RecordFilter {
+    connections: Vec<Uuid>,
+    commands: Vec<String>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    /// Whether each connection's latest request was picked
+    picked: HashMap<Uuid, bool>,
+}
+
+impl RecordFilter {
+    /// Pick every record
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only pick records of these connections
+    pub fn with_connections(mut self, connections: impl IntoIterator<Item = Uuid>) -> Self {
+        self.connections.extend(connections);
+        self
+    }
+
+    /// Only pick these commands (by name, in any case), and their replies
+    pub fn with_commands<S: AsRef<str>>(mut self, commands: impl IntoIterator<Item = S>) -> Self {
+        self.commands.extend(
+            commands
+                .into_iter()
+                .map(|c| c.as_ref().to_ascii_uppercase()),
+        );
+        self
+    }
+
+    /// Only pick records made within `since` to `until` (inclusive), either end being open if
+    /// `None`
+    pub fn with_time_range(mut self, since: Option<SystemTime>, until: Option<SystemTime>) -> Self {
+        self.since = since;
+        self.until = until;
+        self
+    }
+
+    /// Whether to pick `record`, given every record of its connection before it has been passed
+    /// here in order
+    pub fn picks(&mut self, record: &Record) -> bool {
+        let in_range = self.since.is_none_or(|since| record.timestamp >= since)
+            && self.until.is_none_or(|until| record.timestamp <= until);
+        let connection =
+            self.connections.is_empty() || self.connections.contains(&record.connection_id);
+        match record.direction {
+            Direction::Request => {
+                let command = self.commands.is_empty()
+                    || command::name(&record.frame)
+                        .is_some_and(|name| self.commands.contains(&name));
+                self.picked
+                    .insert(record.connection_id, connection && command);
+                in_range && connection && command
+            }
+            // Replies to requests from before the capture started can only be picked by connection
+            Direction::Response => {
+                in_range
+                    && self
+                        .picked
+                        .get(&record.connection_id)
+                        .copied()
+                        .unwrap_or(connection && self.commands.is_empty())
+            }
+        }
+    }
+}
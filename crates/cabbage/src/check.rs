@@ -0,0 +1,92 @@
+//! Target connectivity checks.
+//!
+//! `check` connects to a target address as the proxy would, performing its handshake (`AUTH`,
+//! `SELECT`, ...), times a number of `PING`s, and reads `INFO` for the server's version and role,
+//! so a deployment can confirm a configuration reaches its target before serving with it.
+//! `cabbage check` runs it against every address a configuration names, exiting nonzero if any
+//! fails, which makes it a ready-made readiness probe.
+
+use std::time::{Duration, Instant};
+
+use redis_protocol::resp2::types::BytesFrame;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::service::{CallOne as _, Handshake, Resp2Backend};
+
+#[derive(Clone, Debug)]
+pub struct CheckOptions {
+    /// `PING`s timed
+    pub samples: usize,
+    /// Fail a check which isn't done within this long, connecting included
+    pub timeout: Duration,
+}
+
+/// What a passed check found
+pub struct CheckSummary {
+    /// `redis_version` (or `valkey_version`) from `INFO`, if given
+    pub version: Option<String>,
+    /// `role` from `INFO`, if given
+    pub role: Option<String>,
+    /// Time from sending each `PING` to receiving its reply, in the order sent
+    pub round_trips: Vec<Duration>,
+}
+
+impl CheckSummary {
+    pub fn min_round_trip(&self) -> Duration {
+        self.round_trips.iter().min().copied().unwrap_or_default()
+    }
+
+    pub fn mean_round_trip(&self) -> Duration {
+        let total: Duration = self.round_trips.iter().sum();
+        total / self.round_trips.len().max(1) as u32
+    }
+
+    pub fn max_round_trip(&self) -> Duration {
+        self.round_trips.iter().max().copied().unwrap_or_default()
+    }
+}
+
+/// Connect to `address` with `handshake`, then `PING` it and read its `INFO`, failing on any
+/// error, error reply, or the whole taking longer than `options.timeout`
+pub async fn check(
+    address: &str,
+    handshake: &Handshake,
+    options: &CheckOptions,
+) -> Result<CheckSummary> {
+    let check = async {
+        // INFO is read whole, however the proxy would pass on long replies
+        let handshake = Handshake {
+            stream_bulk_bytes: None,
+            ..handshake.clone()
+        };
+        let mut backend = Resp2Backend::connect_with(address, &handshake).await?;
+        let mut round_trips = Vec::with_capacity(options.samples);
+        for _ in 0..options.samples {
+            let sent = Instant::now();
+            match backend.call_one(command::request(["PING"])).await? {
+                BytesFrame::Error(e) => bail!(BackendUnavailable, "PING failed: {e}"),
+                _ => round_trips.push(sent.elapsed()),
+            }
+        }
+        let info = match backend.call_one(command::request(["INFO"])).await? {
+            BytesFrame::Error(e) => bail!(BackendUnavailable, "INFO failed: {e}"),
+            BytesFrame::BulkString(info) => String::from_utf8_lossy(&info).into_owned(),
+            _ => String::new(),
+        };
+        let field = |names: &[&str]| {
+            info.lines().find_map(|line| {
+                let (name, value) = line.trim_end().split_once(':')?;
+                names.contains(&name).then(|| value.to_string())
+            })
+        };
+        Ok(CheckSummary {
+            version: field(&["redis_version", "valkey_version"]),
+            role: field(&["role"]),
+            round_trips,
+        })
+    };
+    tokio::time::timeout(options.timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(Error::Timeout(options.timeout)))
+}
@@ -0,0 +1,613 @@
+//! A Redis Cluster aware backend.
+//!
+//! `ClusterBackend` routes each command to the node owning its key's hash slot, following
+//! `-MOVED` and `-ASK` redirects transparently so that clients can speak to cabbage as if it were
+//! a single, non-clustered Redis. The slot→node map is shared between all connections and is
+//! refreshed with `CLUSTER SLOTS` whenever a redirect shows it to be stale. `SCAN` walks every
+//! node in turn, with cursors translated as described in `scan`.
+//!
+//! `MGET`, `MSET`, `DEL`, `UNLINK`, `EXISTS`, and `TOUCH` may name keys in different slots: such
+//! a command is split into one per slot, sent to their nodes at once, and their replies merged in
+//! the original key order. Split this way, an `MSET` is no longer atomic, and fails with the first
+//! failing slot's error although the other slots' keys may have been set.
+//!
+//! Every command is expected to produce exactly one reply frame; connection-scoped features such
+//! as pub/sub and `MONITOR` are not supported through a cluster backend.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::future::try_join_all;
+use futures::stream;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::{Service, ServiceExt as _};
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::scan::{self, ShardedScan};
+use crate::service::{CallOne as _, Handshake, Resp2Backend, ResponseStream};
+
+/// Number of hash slots in a Redis Cluster
+pub const SLOT_COUNT: usize = 16384;
+
+/// Upper bound on redirects followed for a single command before giving up
+static MAX_REDIRECTS: usize = 5;
+
+/// The cluster's slot→node assignment, shared by every `ClusterBackend`
+pub struct ClusterSlots {
+    seeds: Vec<String>,
+    slots: RwLock<Vec<Option<Arc<str>>>>,
+    handshake: Handshake,
+}
+
+impl ClusterSlots {
+    /// Build an empty slot map which will be populated from the given seed nodes
+    pub fn new(seeds: Vec<String>) -> Self {
+        Self {
+            seeds,
+            slots: RwLock::new(vec![None; SLOT_COUNT]),
+            handshake: Handshake::default(),
+        }
+    }
+
+    /// Perform `handshake` on every connection made to a cluster node
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// The node currently believed to own `slot`
+    pub fn node_for(&self, slot: u16) -> Option<Arc<str>> {
+        self.slots
+            .read()
+            .ok()
+            .and_then(|slots| slots.get(slot as usize).cloned().flatten())
+    }
+
+    /// The handshake performed on every connection to a cluster node
+    pub(crate) fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// A node to send keyless commands to
+    pub fn any_node(&self) -> Option<Arc<str>> {
+        self.slots
+            .read()
+            .ok()
+            .and_then(|slots| slots.iter().flatten().next().cloned())
+            .or_else(|| self.seeds.first().map(|s| Arc::from(s.as_str())))
+    }
+
+    /// Every node currently owning slots
+    pub fn nodes(&self) -> Vec<Arc<str>> {
+        let Ok(slots) = self.slots.read() else {
+            return vec![];
+        };
+        let mut nodes: Vec<Arc<str>> = slots.iter().flatten().cloned().collect();
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    fn assign(&self, slot: u16, node: &str) {
+        if let Ok(mut slots) = self.slots.write()
+            && let Some(entry) = slots.get_mut(slot as usize)
+        {
+            *entry = Some(Arc::from(node));
+        }
+    }
+
+    /// Rebuild the slot map by asking the known nodes for `CLUSTER SLOTS`, stopping at the
+    /// first one which answers.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut candidates: Vec<String> = self.seeds.clone();
+        if let Some(node) = self.any_node() {
+            candidates.insert(0, node.to_string());
+        }
+
+        let mut last_error = Error::Config("no cluster nodes configured".to_string());
+        for node in candidates {
+            match query_slots(&node, &self.handshake).await {
+                Ok(ranges) => {
+                    let mut slots = vec![None; SLOT_COUNT];
+                    for (start, end, owner) in ranges {
+                        let owner: Arc<str> = Arc::from(owner.as_str());
+                        for slot in slots.iter_mut().take(end as usize + 1).skip(start as usize) {
+                            *slot = Some(owner.clone());
+                        }
+                    }
+                    if let Ok(mut current) = self.slots.write() {
+                        *current = slots;
+                    }
+                    log::debug!("Refreshed cluster slot map from {node}");
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!("Failed to fetch cluster slots from {node}: {e}");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Issue `CLUSTER SLOTS` against `node`, returning `(start, end, "host:port")` master ranges
+async fn query_slots(node: &str, handshake: &Handshake) -> Result<Vec<(u16, u16, String)>> {
+    let mut backend = Resp2Backend::connect_with(node, handshake).await?;
+    let reply = backend
+        .call_one(command::request(["CLUSTER", "SLOTS"]))
+        .await?;
+
+    let BytesFrame::Array(entries) = reply else {
+        bail!(
+            Protocol,
+            "Unexpected CLUSTER SLOTS reply from {node}: {reply:?}"
+        );
+    };
+    let node_host = node.rsplit_once(':').map(|(host, _)| host).unwrap_or(node);
+
+    let mut ranges = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let BytesFrame::Array(fields) = entry else {
+            bail!(Protocol, "Malformed CLUSTER SLOTS entry from {node}");
+        };
+        let (
+            Some(BytesFrame::Integer(start)),
+            Some(BytesFrame::Integer(end)),
+            Some(BytesFrame::Array(master)),
+        ) = (fields.first(), fields.get(1), fields.get(2))
+        else {
+            bail!(Protocol, "Malformed CLUSTER SLOTS entry from {node}");
+        };
+        let host = master
+            .first()
+            .and_then(command::arg_bytes)
+            .map(|h| String::from_utf8_lossy(h).into_owned())
+            .filter(|h| !h.is_empty() && h != "?")
+            .unwrap_or_else(|| node_host.to_string());
+        let Some(BytesFrame::Integer(port)) = master.get(1) else {
+            bail!(Protocol, "Malformed CLUSTER SLOTS node from {node}");
+        };
+        ranges.push((*start as u16, *end as u16, format!("{host}:{port}")));
+    }
+    Ok(ranges)
+}
+
+/// A cluster redirect parsed from an error reply
+#[derive(Debug, PartialEq, Eq)]
+enum Redirect {
+    Moved { slot: u16, node: String },
+    Ask { slot: u16, node: String },
+}
+
+impl Redirect {
+    fn parse(frame: &BytesFrame) -> Option<Self> {
+        let BytesFrame::Error(message) = frame else {
+            return None;
+        };
+        let mut parts = message.split_whitespace();
+        let kind = parts.next()?;
+        let slot = parts.next()?.parse().ok()?;
+        let node = parts.next()?.to_string();
+        match kind {
+            "MOVED" => Some(Redirect::Moved { slot, node }),
+            "ASK" => Some(Redirect::Ask { slot, node }),
+            _ => None,
+        }
+    }
+}
+
+/// Per-connection handles to cluster nodes, dialed on first use
+#[derive(Default)]
+struct NodeConnections {
+    nodes: HashMap<String, Resp2Backend>,
+}
+
+impl NodeConnections {
+    async fn get(&mut self, node: &str, handshake: &Handshake) -> Result<&mut Resp2Backend> {
+        if !self.nodes.contains_key(node) {
+            let backend = Resp2Backend::connect_with(node, handshake).await?;
+            log::info!("Connected to cluster node {node}");
+            self.nodes.insert(node.to_string(), backend);
+        }
+        self.nodes
+            .get_mut(node)
+            .ok_or_else(|| Error::BackendUnavailable(format!("cluster node {node} vanished")))
+    }
+}
+
+/// A backend which speaks to a Redis Cluster on behalf of a single client connection
+pub struct ClusterBackend {
+    slots: Arc<ClusterSlots>,
+    connections: Arc<tokio::sync::Mutex<NodeConnections>>,
+}
+
+impl ClusterBackend {
+    pub fn new(slots: Arc<ClusterSlots>) -> Self {
+        Self {
+            slots,
+            connections: Arc::new(tokio::sync::Mutex::new(NodeConnections::default())),
+        }
+    }
+}
+
+/// Send `req` to `node` over this client's connection to it, and wait for the reply. The request
+/// is sent before the connections are unlocked, so each node sees requests in the order they're
+/// made, while replies from different nodes can be awaited at once.
+async fn send(
+    connections: &tokio::sync::Mutex<NodeConnections>,
+    node: &str,
+    handshake: &Handshake,
+    req: BytesFrame,
+    asking: bool,
+) -> Result<BytesFrame> {
+    let responses = {
+        let mut connections = connections.lock().await;
+        let backend = connections.get(node, handshake).await?;
+        if asking {
+            // ASKING only applies to the command right after it, whose reply is the one wanted
+            drop(backend.ready().await?.call(command::request(["ASKING"])));
+        }
+        backend.ready().await?.call(req)
+    };
+    responses
+        .await?
+        .next()
+        .await
+        .ok_or_else(|| Error::BackendUnavailable("Backend closed before replying".to_string()))
+}
+
+/// Send `req` to the node owning its first key's slot, following redirects, and return the reply
+async fn execute(
+    slots: &Arc<ClusterSlots>,
+    connections: &tokio::sync::Mutex<NodeConnections>,
+    req: BytesFrame,
+) -> Result<BytesFrame> {
+    let slot = command::first_key(&req).map(|key| redis_protocol::redis_keyslot(key));
+    let mut node = slot
+        .and_then(|slot| slots.node_for(slot))
+        .or_else(|| slots.any_node())
+        .ok_or_else(|| {
+            Error::BackendUnavailable("No cluster node known to route command".to_string())
+        })?
+        .to_string();
+    let mut asking = false;
+
+    // Replies are awaited here, rather than streamed, so that redirects can be followed before
+    // anything is handed back to the client.
+    for _ in 0..=MAX_REDIRECTS {
+        let reply = send(connections, &node, &slots.handshake, req.clone(), asking).await?;
+
+        match Redirect::parse(&reply) {
+            Some(Redirect::Moved { slot, node: owner }) => {
+                log::debug!("Slot {slot} moved to {owner}");
+                slots.assign(slot, &owner);
+                let slots = slots.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = slots.refresh().await {
+                        log::error!("Cluster slot refresh failed: {e}");
+                    }
+                });
+                node = owner;
+                asking = false;
+            }
+            Some(Redirect::Ask { slot, node: owner }) => {
+                log::debug!("Slot {slot} is migrating, asking {owner}");
+                node = owner;
+                asking = true;
+            }
+            None => return Ok(reply),
+        }
+    }
+    Ok(command::error("ERR cabbage: too many cluster redirects"))
+}
+
+/// How a multi-key command spanning several slots is split into one command per slot, and their
+/// replies merged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FanOut {
+    /// `MGET`: the values, in key order
+    Values,
+    /// `MSET`: OK once every slot's keys are set
+    Pairs,
+    /// `DEL`, `UNLINK`, `EXISTS`, and `TOUCH`: the sum of the counts
+    Count,
+}
+
+impl FanOut {
+    fn of(req: &BytesFrame) -> Option<Self> {
+        match command::name(req)?.as_str() {
+            "MGET" => Some(Self::Values),
+            "MSET" => Some(Self::Pairs),
+            "DEL" | "UNLINK" | "EXISTS" | "TOUCH" => Some(Self::Count),
+            _ => None,
+        }
+    }
+
+    /// Split `req`'s keys (or key-value pairs) by slot, into the command for each slot and the
+    /// positions its keys had, or `None` if they're all in the same slot
+    fn split(self, req: &BytesFrame) -> Option<Vec<(BytesFrame, Vec<usize>)>> {
+        let (name, args) = command::args(req)?.split_first()?;
+        let width = if self == Self::Pairs { 2 } else { 1 };
+        if args.is_empty() || args.len() % width != 0 {
+            return None;
+        }
+        let mut by_slot: Vec<(u16, Vec<BytesFrame>, Vec<usize>)> = vec![];
+        for (position, group) in args.chunks(width).enumerate() {
+            let slot = redis_protocol::redis_keyslot(command::arg_bytes(&group[0])?);
+            let i = match by_slot.iter().position(|(s, _, _)| *s == slot) {
+                Some(i) => i,
+                None => {
+                    by_slot.push((slot, vec![name.clone()], vec![]));
+                    by_slot.len() - 1
+                }
+            };
+            by_slot[i].1.extend_from_slice(group);
+            by_slot[i].2.push(position);
+        }
+        if by_slot.len() < 2 {
+            return None;
+        }
+        Some(
+            by_slot
+                .into_iter()
+                .map(|(_, parts, positions)| (BytesFrame::Array(parts), positions))
+                .collect(),
+        )
+    }
+
+    /// Merge the replies to the commands `split` made, each with the positions of its keys, into
+    /// the reply to the original command with `keys` keys
+    fn merge(self, replies: Vec<(BytesFrame, Vec<usize>)>, keys: usize) -> BytesFrame {
+        if let Some((error, _)) = replies
+            .iter()
+            .find(|(reply, _)| matches!(reply, BytesFrame::Error(_)))
+        {
+            return error.clone();
+        }
+        match self {
+            Self::Values => {
+                let mut values = vec![BytesFrame::Null; keys];
+                for (reply, positions) in replies {
+                    let BytesFrame::Array(parts) = reply else {
+                        return unexpected_reply();
+                    };
+                    if parts.len() != positions.len() {
+                        return unexpected_reply();
+                    }
+                    for (value, position) in parts.into_iter().zip(positions) {
+                        values[position] = value;
+                    }
+                }
+                BytesFrame::Array(values)
+            }
+            Self::Pairs => BytesFrame::SimpleString("OK".into()),
+            Self::Count => {
+                let mut total = 0;
+                for (reply, _) in replies {
+                    let BytesFrame::Integer(count) = reply else {
+                        return unexpected_reply();
+                    };
+                    total += count;
+                }
+                BytesFrame::Integer(total)
+            }
+        }
+    }
+}
+
+fn unexpected_reply() -> BytesFrame {
+    command::error("ERR cabbage: unexpected reply from a cluster node")
+}
+
+impl Service<BytesFrame> for ClusterBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let slots = self.slots.clone();
+        let connections = self.connections.clone();
+
+        Box::pin(async move {
+            let nodes = slots.nodes();
+            if scan::is_scan(&req) && !nodes.is_empty() {
+                let (scan, req) = match ShardedScan::start(&req, nodes.len()) {
+                    Ok(started) => started,
+                    Err(error) => return Ok(stream::once(async move { error }).boxed()),
+                };
+                let node = &nodes[scan.shard()];
+                let reply = send(&connections, node, &slots.handshake, req, false).await?;
+                return Ok(stream::once(async move { scan.finish(reply) }).boxed());
+            }
+
+            if let Some(fan_out) = FanOut::of(&req)
+                && let Some(commands) = fan_out.split(&req)
+            {
+                let keys = commands.iter().map(|(_, positions)| positions.len()).sum();
+                let replies = commands.into_iter().map(|(command, positions)| {
+                    let reply = execute(&slots, &connections, command);
+                    async move { Ok::<_, Error>((reply.await?, positions)) }
+                });
+                let reply = fan_out.merge(try_join_all(replies).await?, keys);
+                return Ok(stream::once(async move { reply }).boxed());
+            }
+
+            let reply = execute(&slots, &connections, req).await?;
+            Ok(stream::once(async move { reply }).boxed())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+    use crate::middleware::reply;
+    use crate::proxy::{ServeOptions, serve_with};
+
+    fn bulk(s: &str) -> BytesFrame {
+        BytesFrame::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn redirects_are_parsed_from_errors() {
+        assert_eq!(
+            Redirect::parse(&command::error("MOVED 3999 127.0.0.1:6381")),
+            Some(Redirect::Moved {
+                slot: 3999,
+                node: "127.0.0.1:6381".to_string()
+            })
+        );
+        assert_eq!(
+            Redirect::parse(&command::error("ASK 3999 127.0.0.1:6381")),
+            Some(Redirect::Ask {
+                slot: 3999,
+                node: "127.0.0.1:6381".to_string()
+            })
+        );
+        let others = [
+            command::error("ERR unknown command"),
+            command::error("MOVED slot 127.0.0.1:6381"),
+            command::error("MOVED 3999"),
+            BytesFrame::SimpleString("MOVED 3999 127.0.0.1:6381".into()),
+        ];
+        for frame in others {
+            assert_eq!(Redirect::parse(&frame), None, "{frame:?}");
+        }
+    }
+
+    #[test]
+    fn keys_are_split_by_slot_in_order() {
+        let req = command::request(["MGET", "{a}1", "{b}1", "{a}2", "{c}1"]);
+        let commands = FanOut::of(&req).and_then(|fan_out| fan_out.split(&req));
+        assert_eq!(
+            commands,
+            Some(vec![
+                (command::request(["MGET", "{a}1", "{a}2"]), vec![0, 2]),
+                (command::request(["MGET", "{b}1"]), vec![1]),
+                (command::request(["MGET", "{c}1"]), vec![3]),
+            ])
+        );
+    }
+
+    #[test]
+    fn pairs_are_split_together() {
+        let req = command::request(["MSET", "{a}1", "x", "{b}1", "y", "{a}2", "z"]);
+        assert_eq!(
+            FanOut::Pairs.split(&req),
+            Some(vec![
+                (
+                    command::request(["MSET", "{a}1", "x", "{a}2", "z"]),
+                    vec![0, 2]
+                ),
+                (command::request(["MSET", "{b}1", "y"]), vec![1]),
+            ])
+        );
+        // Half a pair is for the node to refuse
+        let odd = command::request(["MSET", "{a}1", "x", "{b}1"]);
+        assert_eq!(FanOut::Pairs.split(&odd), None);
+    }
+
+    #[test]
+    fn keys_in_one_slot_are_not_split() {
+        let req = command::request(["DEL", "{a}1", "{a}2"]);
+        assert_eq!(FanOut::Count.split(&req), None);
+        assert_eq!(FanOut::of(&command::request(["GET", "a"])), None);
+    }
+
+    #[test]
+    fn values_are_merged_in_key_order() {
+        let replies = vec![
+            (
+                BytesFrame::Array(vec![bulk("1"), BytesFrame::Null]),
+                vec![0, 2],
+            ),
+            (BytesFrame::Array(vec![bulk("2")]), vec![1]),
+        ];
+        assert_eq!(
+            FanOut::Values.merge(replies, 3),
+            BytesFrame::Array(vec![bulk("1"), bulk("2"), BytesFrame::Null])
+        );
+    }
+
+    #[test]
+    fn counts_are_summed() {
+        let replies = vec![
+            (BytesFrame::Integer(2), vec![0, 2]),
+            (BytesFrame::Integer(1), vec![1]),
+        ];
+        assert_eq!(FanOut::Count.merge(replies, 3), BytesFrame::Integer(3));
+    }
+
+    #[test]
+    fn the_first_error_is_the_merged_reply() {
+        let replies = vec![
+            (BytesFrame::SimpleString("OK".into()), vec![0]),
+            (command::error("OOM first"), vec![1]),
+            (command::error("OOM second"), vec![2]),
+        ];
+        assert_eq!(FanOut::Pairs.merge(replies, 3), command::error("OOM first"));
+    }
+
+    #[test]
+    fn mismatched_replies_are_refused() {
+        let replies = vec![(BytesFrame::Array(vec![bulk("1")]), vec![0, 1])];
+        assert_eq!(FanOut::Values.merge(replies, 2), unexpected_reply());
+        let replies = vec![(bulk("1"), vec![0])];
+        assert_eq!(FanOut::Count.merge(replies, 1), unexpected_reply());
+    }
+
+    #[tokio::test]
+    async fn redirects_are_followed_a_limited_number_of_times() -> Result<()> {
+        // A node which says every key has moved to itself
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let node = listener.local_addr()?.to_string();
+        let gets = Arc::new(AtomicUsize::new(0));
+        let options = ServeOptions::default();
+        let shutdown = options.shutdown_token();
+        let serving = tokio::spawn(serve_with(
+            listener,
+            {
+                let (node, gets) = (node.clone(), gets.clone());
+                move |_, _| {
+                    let (node, gets) = (node.clone(), gets.clone());
+                    async move {
+                        Ok(tower::service_fn(move |req: BytesFrame| {
+                            if command::name(&req).as_deref() == Some("GET") {
+                                gets.fetch_add(1, Ordering::Relaxed);
+                            }
+                            let moved = command::error(format!("MOVED 1 {node}"));
+                            async move { Ok::<_, Error>(reply(moved)) }
+                        }))
+                    }
+                }
+            },
+            options,
+        ));
+
+        let mut backend = ClusterBackend::new(Arc::new(ClusterSlots::new(vec![node])));
+        let answer = backend.call_one(command::request(["GET", "a"])).await?;
+        assert_eq!(
+            answer,
+            command::error("ERR cabbage: too many cluster redirects")
+        );
+        assert_eq!(gets.load(Ordering::Relaxed), MAX_REDIRECTS + 1);
+
+        shutdown.cancel();
+        serving.await.map_err(Error::other)?
+    }
+}
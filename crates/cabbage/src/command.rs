@@ -0,0 +1,563 @@
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+
+/// Errors meaning the target couldn't answer, rather than that it refused the request
+static UNAVAILABLE_ERRORS: [&str; 6] = [
+    "ERR proxy ",
+    "ERR cabbage: target unavailable",
+    "ERR circuit breaker open",
+    "LOADING",
+    "MASTERDOWN",
+    "TRYAGAIN",
+];
+
+/// Where the keys of a command live among its arguments, modeled on the `first`/`last`/`step`
+/// key specification reported by Redis's `COMMAND INFO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeySpec {
+    /// The command takes no keys
+    None,
+    /// Keys start at `first` and run until `last` (negative values count back from the end),
+    /// taking every `step`th argument.
+    Range {
+        first: usize,
+        last: isize,
+        step: usize,
+    },
+    /// The number of keys is given by the argument at `count_at`; keys immediately follow it.
+    Counted { count_at: usize },
+    /// A destination key, then keys counted as by `Counted` (`ZUNIONSTORE`, ...)
+    StoreCounted { count_at: usize },
+    /// A key, then one more following a `STORE` (or `STOREDIST`) argument if there is one
+    /// (`SORT`, `GEORADIUS`, ...). `SORT`'s `BY` and `GET` patterns name keys which can't be
+    /// known up front, so a `SORT` with either has an unknown layout.
+    Store,
+    /// As many keys as IDs follow a `STREAMS` argument, keys first (`XREAD`, `XREADGROUP`)
+    Streams,
+    /// Where the keys are can't be told (a command this table doesn't know, or `MIGRATE`)
+    Unknown,
+}
+
+fn key_spec(name: &str) -> KeySpec {
+    use KeySpec::*;
+
+    match name {
+        "ACL" | "AUTH" | "BGREWRITEAOF" | "BGSAVE" | "CLIENT" | "CLUSTER" | "COMMAND"
+        | "CONFIG" | "DBSIZE" | "DEBUG" | "DISCARD" | "ECHO" | "EXEC" | "FAILOVER" | "FLUSHALL"
+        | "FLUSHDB" | "FUNCTION" | "HELLO" | "INFO" | "KEYS" | "LASTSAVE" | "LATENCY"
+        | "LOLWUT" | "MODULE" | "MONITOR" | "MULTI" | "PING" | "PSUBSCRIBE" | "PUBLISH"
+        | "PUBSUB" | "PUNSUBSCRIBE" | "QUIT" | "RANDOMKEY" | "READONLY" | "READWRITE"
+        | "REPLICAOF" | "RESET" | "ROLE" | "SAVE" | "SCAN" | "SCRIPT" | "SELECT" | "SHUTDOWN"
+        | "SLAVEOF" | "SLOWLOG" | "SPUBLISH" | "SSUBSCRIBE" | "SUBSCRIBE" | "SUNSUBSCRIBE"
+        | "SWAPDB" | "TIME" | "UNSUBSCRIBE" | "UNWATCH" | "WAIT" | "WAITAOF" | "ASKING" => None,
+        // The proxy's own commands
+        name if name.starts_with("CABBAGE.") => None,
+        "APPEND"
+        | "BITCOUNT"
+        | "BITFIELD"
+        | "BITFIELD_RO"
+        | "BITPOS"
+        | "DECR"
+        | "DECRBY"
+        | "DUMP"
+        | "EXPIRE"
+        | "EXPIREAT"
+        | "EXPIRETIME"
+        | "GEOADD"
+        | "GEODIST"
+        | "GEOHASH"
+        | "GEOPOS"
+        | "GEORADIUSBYMEMBER_RO"
+        | "GEORADIUS_RO"
+        | "GEOSEARCH"
+        | "GET"
+        | "GETBIT"
+        | "GETDEL"
+        | "GETEX"
+        | "GETRANGE"
+        | "GETSET"
+        | "HDEL"
+        | "HEXISTS"
+        | "HEXPIRE"
+        | "HEXPIREAT"
+        | "HEXPIRETIME"
+        | "HGET"
+        | "HGETALL"
+        | "HGETDEL"
+        | "HGETEX"
+        | "HINCRBY"
+        | "HINCRBYFLOAT"
+        | "HKEYS"
+        | "HLEN"
+        | "HMGET"
+        | "HMSET"
+        | "HPERSIST"
+        | "HPEXPIRE"
+        | "HPEXPIREAT"
+        | "HPEXPIRETIME"
+        | "HPTTL"
+        | "HRANDFIELD"
+        | "HSCAN"
+        | "HSET"
+        | "HSETEX"
+        | "HSETNX"
+        | "HSTRLEN"
+        | "HTTL"
+        | "HVALS"
+        | "INCR"
+        | "INCRBY"
+        | "INCRBYFLOAT"
+        | "LINDEX"
+        | "LINSERT"
+        | "LLEN"
+        | "LPOP"
+        | "LPOS"
+        | "LPUSH"
+        | "LPUSHX"
+        | "LRANGE"
+        | "LREM"
+        | "LSET"
+        | "LTRIM"
+        | "MOVE"
+        | "PERSIST"
+        | "PEXPIRE"
+        | "PEXPIREAT"
+        | "PEXPIRETIME"
+        | "PFADD"
+        | "PSETEX"
+        | "PTTL"
+        | "RESTORE"
+        | "RPOP"
+        | "RPUSH"
+        | "RPUSHX"
+        | "SADD"
+        | "SCARD"
+        | "SET"
+        | "SETBIT"
+        | "SETEX"
+        | "SETNX"
+        | "SETRANGE"
+        | "SISMEMBER"
+        | "SMEMBERS"
+        | "SMISMEMBER"
+        | "SPOP"
+        | "SRANDMEMBER"
+        | "SREM"
+        | "SSCAN"
+        | "STRLEN"
+        | "SUBSTR"
+        | "TTL"
+        | "TYPE"
+        | "XACK"
+        | "XADD"
+        | "XAUTOCLAIM"
+        | "XCLAIM"
+        | "XDEL"
+        | "XLEN"
+        | "XPENDING"
+        | "XRANGE"
+        | "XREVRANGE"
+        | "XSETID"
+        | "XTRIM"
+        | "ZADD"
+        | "ZCARD"
+        | "ZCOUNT"
+        | "ZINCRBY"
+        | "ZLEXCOUNT"
+        | "ZMSCORE"
+        | "ZPOPMAX"
+        | "ZPOPMIN"
+        | "ZRANDMEMBER"
+        | "ZRANGE"
+        | "ZRANGEBYLEX"
+        | "ZRANGEBYSCORE"
+        | "ZRANK"
+        | "ZREM"
+        | "ZREMRANGEBYLEX"
+        | "ZREMRANGEBYRANK"
+        | "ZREMRANGEBYSCORE"
+        | "ZREVRANGE"
+        | "ZREVRANGEBYLEX"
+        | "ZREVRANGEBYSCORE"
+        | "ZREVRANK"
+        | "ZSCAN"
+        | "ZSCORE" => Range {
+            first: 1,
+            last: 1,
+            step: 1,
+        },
+        "DEL" | "EXISTS" | "MGET" | "PFCOUNT" | "PFMERGE" | "SDIFF" | "SDIFFSTORE" | "SINTER"
+        | "SINTERSTORE" | "SUNION" | "SUNIONSTORE" | "TOUCH" | "UNLINK" | "WATCH" => Range {
+            first: 1,
+            last: -1,
+            step: 1,
+        },
+        "BLPOP" | "BRPOP" | "BZPOPMAX" | "BZPOPMIN" => Range {
+            first: 1,
+            last: -2,
+            step: 1,
+        },
+        "BLMOVE" | "BRPOPLPUSH" | "COPY" | "GEOSEARCHSTORE" | "LCS" | "LMOVE" | "RENAME"
+        | "RENAMENX" | "RPOPLPUSH" | "SMOVE" | "ZRANGESTORE" => Range {
+            first: 1,
+            last: 2,
+            step: 1,
+        },
+        "MSET" | "MSETNX" => Range {
+            first: 1,
+            last: -1,
+            step: 2,
+        },
+        // The operation comes first, then the destination and source keys
+        "BITOP" => Range {
+            first: 2,
+            last: -1,
+            step: 1,
+        },
+        "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO" | "BLMPOP"
+        | "BZMPOP" => Counted { count_at: 2 },
+        "LMPOP" | "SINTERCARD" | "ZDIFF" | "ZINTER" | "ZINTERCARD" | "ZMPOP" | "ZUNION" => {
+            Counted { count_at: 1 }
+        }
+        "ZDIFFSTORE" | "ZINTERSTORE" | "ZUNIONSTORE" => StoreCounted { count_at: 2 },
+        "GEORADIUS" | "GEORADIUSBYMEMBER" | "SORT" | "SORT_RO" => Store,
+        "XREAD" | "XREADGROUP" => Streams,
+        // A subcommand, then the key (`OBJECT ENCODING key`, `XGROUP CREATE key ...`)
+        "MEMORY" | "OBJECT" | "XGROUP" | "XINFO" => Range {
+            first: 2,
+            last: 2,
+            step: 1,
+        },
+        _ => Unknown,
+    }
+}
+
+/// The position of the first of `args` from `from` on which is one of `keywords`, ignoring case
+fn keyword_at(args: &[BytesFrame], from: usize, keywords: &[&str]) -> Option<usize> {
+    (from..args.len()).find(|&i| {
+        arg_bytes(&args[i]).is_some_and(|a| {
+            keywords
+                .iter()
+                .any(|k| a.eq_ignore_ascii_case(k.as_bytes()))
+        })
+    })
+}
+
+/// The keys counted by the argument at `count_at`, or `None` if it isn't a count of arguments
+/// which follow it
+fn counted_keys(args: &[BytesFrame], count_at: usize) -> Option<Vec<usize>> {
+    let count = args
+        .get(count_at)
+        .and_then(arg_bytes)
+        .and_then(|c| std::str::from_utf8(c).ok())
+        .and_then(|c| c.parse::<usize>().ok())?;
+    let keys = count_at + 1..count_at + 1 + count;
+    (keys.end <= args.len()).then(|| keys.collect())
+}
+
+/// The elements of a request frame, if it is shaped like a command (an array of strings)
+pub fn args(frame: &BytesFrame) -> Option<&[BytesFrame]> {
+    match frame {
+        BytesFrame::Array(parts) if !parts.is_empty() => Some(parts),
+        _ => None,
+    }
+}
+
+/// The raw bytes of a command argument
+pub fn arg_bytes(arg: &BytesFrame) -> Option<&Bytes> {
+    match arg {
+        BytesFrame::BulkString(b) | BytesFrame::SimpleString(b) => Some(b),
+        _ => None,
+    }
+}
+
+/// The uppercased name of the command carried by a request frame
+pub fn name(frame: &BytesFrame) -> Option<String> {
+    let name = arg_bytes(args(frame)?.first()?)?;
+    Some(String::from_utf8_lossy(name).to_ascii_uppercase())
+}
+
+/// Positions within a request frame's arguments which hold keys, in argument order, or `None`
+/// if which arguments are keys can't be told. Anything which lets a client reach only certain
+/// keys (prefixing them, or checking them against patterns) must refuse such a command, as it
+/// could name any key.
+pub fn known_key_indices(frame: &BytesFrame) -> Option<Vec<usize>> {
+    let (Some(args), Some(name)) = (args(frame), name(frame)) else {
+        return Some(vec![]);
+    };
+
+    let (first, last, step) = match key_spec(&name) {
+        KeySpec::None => return Some(vec![]),
+        KeySpec::Unknown => return None,
+        KeySpec::Range { first, last, step } => {
+            let last = if last < 0 {
+                args.len() as isize + last
+            } else {
+                last
+            };
+            (first, last, step)
+        }
+        KeySpec::Counted { count_at } => return counted_keys(args, count_at),
+        KeySpec::StoreCounted { count_at } => {
+            let mut keys = vec![1];
+            keys.extend(counted_keys(args, count_at)?);
+            return Some(keys);
+        }
+        KeySpec::Store => {
+            let is_sort = name.starts_with("SORT");
+            if is_sort && keyword_at(args, 2, &["BY", "GET"]).is_some() {
+                return None;
+            }
+            let mut keys: Vec<usize> = (1..args.len().min(2)).collect();
+            if let Some(store_at) = keyword_at(args, 2, &["STORE", "STOREDIST"]) {
+                if store_at + 1 >= args.len() {
+                    return None;
+                }
+                keys.push(store_at + 1);
+            }
+            return Some(keys);
+        }
+        KeySpec::Streams => {
+            let streams_at = keyword_at(args, 1, &["STREAMS"])?;
+            let rest = args.len() - streams_at - 1;
+            if rest % 2 != 0 {
+                return None;
+            }
+            return Some((streams_at + 1..=streams_at + rest / 2).collect());
+        }
+    };
+    if last < first as isize {
+        return Some(vec![]);
+    }
+
+    Some(
+        (first..=(last as usize).min(args.len().saturating_sub(1)))
+            .step_by(step)
+            .collect(),
+    )
+}
+
+/// Positions within a request frame's arguments which hold keys, in argument order (none, for a
+/// command whose keys can't be told)
+pub fn key_indices(frame: &BytesFrame) -> Vec<usize> {
+    known_key_indices(frame).unwrap_or_default()
+}
+
+/// All keys referenced by a request frame, in argument order, or `None` if which arguments are
+/// keys can't be told
+pub fn known_keys(frame: &BytesFrame) -> Option<Vec<&Bytes>> {
+    let args = args(frame).unwrap_or_default();
+    Some(
+        known_key_indices(frame)?
+            .into_iter()
+            .filter_map(|i| args.get(i).and_then(arg_bytes))
+            .collect(),
+    )
+}
+
+/// All keys referenced by a request frame, in argument order (none, for a command whose keys
+/// can't be told)
+pub fn keys(frame: &BytesFrame) -> Vec<&Bytes> {
+    known_keys(frame).unwrap_or_default()
+}
+
+/// The first key referenced by a request frame
+pub fn first_key(frame: &BytesFrame) -> Option<&Bytes> {
+    keys(frame).into_iter().next()
+}
+
+/// Whether a request puts the connection into a mode where the target pushes frames without
+/// being asked (pub/sub or `MONITOR`)
+pub fn starts_push_mode(frame: &BytesFrame) -> bool {
+    matches!(
+        name(frame).as_deref(),
+        Some("SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "MONITOR")
+    )
+}
+
+/// Whether a request may legitimately leave the target silent for a long time, waiting on data
+/// or replicas (`BLPOP`, `XREAD BLOCK`, `WAIT`, ...)
+pub fn is_blocking(frame: &BytesFrame) -> bool {
+    match name(frame).as_deref() {
+        Some(
+            "BLPOP" | "BRPOP" | "BRPOPLPUSH" | "BLMOVE" | "BLMPOP" | "BZPOPMIN" | "BZPOPMAX"
+            | "BZMPOP" | "WAIT" | "WAITAOF",
+        ) => true,
+        Some("XREAD" | "XREADGROUP") => args(frame)
+            .unwrap_or_default()
+            .iter()
+            .any(|a| arg_bytes(a).is_some_and(|a| a.eq_ignore_ascii_case(b"BLOCK"))),
+        _ => false,
+    }
+}
+
+/// The whole string values a request stores, as (value position, key position) pairs within its
+/// arguments (`SET`, `SETEX`, `PSETEX`, `SETNX`, `GETSET`, `MSET`, `MSETNX`)
+pub fn stored_values(frame: &BytesFrame) -> Vec<(usize, usize)> {
+    let argc = args(frame).map(<[_]>::len).unwrap_or_default();
+    match name(frame).as_deref() {
+        Some("SET" | "SETNX" | "GETSET") if argc > 2 => vec![(2, 1)],
+        Some("SETEX" | "PSETEX") if argc > 3 => vec![(3, 1)],
+        Some("MSET" | "MSETNX") => (2..argc).step_by(2).map(|i| (i, i - 1)).collect(),
+        _ => vec![],
+    }
+}
+
+/// Whether a request's reply carries whole string values as stored (`GET`, `GETDEL`, `GETEX`,
+/// `GETSET`, `MGET`, and `SET ... GET`)
+pub fn returns_stored_values(frame: &BytesFrame) -> bool {
+    matches!(
+        name(frame).as_deref(),
+        Some("GET" | "GETDEL" | "GETEX" | "GETSET" | "MGET" | "SET")
+    )
+}
+
+/// The keys whose values a reply to a request which `returns_stored_values` carries, in order
+pub fn reply_value_keys(frame: &BytesFrame) -> Vec<Bytes> {
+    let args = args(frame).unwrap_or_default();
+    let keys = match name(frame).as_deref() {
+        Some("MGET") => args.get(1..).unwrap_or_default(),
+        _ => args.get(1..2).unwrap_or_default(),
+    };
+    keys.iter().filter_map(arg_bytes).cloned().collect()
+}
+
+/// Whether a request only reads data, so repeating it has no effect beyond its reply
+pub fn is_read_only(frame: &BytesFrame) -> bool {
+    matches!(
+        name(frame).as_deref(),
+        Some(
+            "BITCOUNT"
+                | "BITPOS"
+                | "DBSIZE"
+                | "EXISTS"
+                | "EXPIRETIME"
+                | "GEODIST"
+                | "GEOHASH"
+                | "GEOPOS"
+                | "GEOSEARCH"
+                | "GET"
+                | "GETBIT"
+                | "GETRANGE"
+                | "HEXISTS"
+                | "HGET"
+                | "HGETALL"
+                | "HKEYS"
+                | "HLEN"
+                | "HMGET"
+                | "HRANDFIELD"
+                | "HSCAN"
+                | "HSTRLEN"
+                | "HVALS"
+                | "KEYS"
+                | "LINDEX"
+                | "LLEN"
+                | "LPOS"
+                | "LRANGE"
+                | "MGET"
+                | "PEXPIRETIME"
+                | "PFCOUNT"
+                | "PTTL"
+                | "RANDOMKEY"
+                | "SCAN"
+                | "SCARD"
+                | "SDIFF"
+                | "SINTER"
+                | "SINTERCARD"
+                | "SISMEMBER"
+                | "SMEMBERS"
+                | "SMISMEMBER"
+                | "SRANDMEMBER"
+                | "SSCAN"
+                | "STRLEN"
+                | "SUNION"
+                | "TTL"
+                | "TYPE"
+                | "XLEN"
+                | "XRANGE"
+                | "XREVRANGE"
+                | "ZCARD"
+                | "ZCOUNT"
+                | "ZLEXCOUNT"
+                | "ZMSCORE"
+                | "ZRANDMEMBER"
+                | "ZRANGE"
+                | "ZRANGEBYLEX"
+                | "ZRANGEBYSCORE"
+                | "ZRANK"
+                | "ZREVRANGE"
+                | "ZREVRANGEBYLEX"
+                | "ZREVRANGEBYSCORE"
+                | "ZREVRANK"
+                | "ZSCAN"
+                | "ZSCORE"
+        )
+    )
+}
+
+/// Whether a request may write data, as Redis flags its write commands; scripts and functions
+/// count as writes unless sent with their `_RO` variants
+pub fn is_write(frame: &BytesFrame) -> bool {
+    let subcommand = || {
+        args(frame)
+            .and_then(|args| args.get(1))
+            .and_then(arg_bytes)
+            .map(|sub| sub.to_ascii_uppercase())
+    };
+    match name(frame).as_deref() {
+        Some(
+            "APPEND" | "BITFIELD" | "BITOP" | "BLMOVE" | "BLMPOP" | "BLPOP" | "BRPOP"
+            | "BRPOPLPUSH" | "BZMPOP" | "BZPOPMAX" | "BZPOPMIN" | "COPY" | "DECR" | "DECRBY"
+            | "DEL" | "EVAL" | "EVALSHA" | "EXPIRE" | "EXPIREAT" | "FCALL" | "FLUSHALL" | "FLUSHDB"
+            | "GEOADD" | "GEORADIUS" | "GEORADIUSBYMEMBER" | "GEOSEARCHSTORE" | "GETDEL" | "GETEX"
+            | "GETSET" | "HDEL" | "HEXPIRE" | "HEXPIREAT" | "HGETDEL" | "HGETEX" | "HINCRBY"
+            | "HINCRBYFLOAT" | "HMSET" | "HPERSIST" | "HPEXPIRE" | "HPEXPIREAT" | "HSET" | "HSETEX"
+            | "HSETNX" | "INCR" | "INCRBY" | "INCRBYFLOAT" | "LINSERT" | "LMOVE" | "LMPOP" | "LPOP"
+            | "LPUSH" | "LPUSHX" | "LREM" | "LSET" | "LTRIM" | "MIGRATE" | "MOVE" | "MSET"
+            | "MSETNX" | "PERSIST" | "PEXPIRE" | "PEXPIREAT" | "PFADD" | "PFMERGE" | "PSETEX"
+            | "RENAME" | "RENAMENX" | "RESTORE" | "RPOP" | "RPOPLPUSH" | "RPUSH" | "RPUSHX"
+            | "SADD" | "SDIFFSTORE" | "SET" | "SETBIT" | "SETEX" | "SETNX" | "SETRANGE"
+            | "SINTERSTORE" | "SMOVE" | "SORT" | "SPOP" | "SREM" | "SUNIONSTORE" | "SWAPDB"
+            | "UNLINK" | "XACK" | "XADD" | "XAUTOCLAIM" | "XCLAIM" | "XDEL" | "XREADGROUP"
+            | "XSETID" | "XTRIM" | "ZADD" | "ZDIFFSTORE" | "ZINCRBY" | "ZINTERSTORE" | "ZMPOP"
+            | "ZPOPMAX" | "ZPOPMIN" | "ZRANGESTORE" | "ZREM" | "ZREMRANGEBYLEX" | "ZREMRANGEBYRANK"
+            | "ZREMRANGEBYSCORE" | "ZUNIONSTORE",
+        ) => true,
+        Some("XGROUP") => subcommand().is_some_and(|sub| sub != b"HELP"),
+        Some("FUNCTION") => matches!(
+            subcommand().as_deref(),
+            Some(b"DELETE" | b"FLUSH" | b"LOAD" | b"RESTORE")
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `frame` is an error meaning the target couldn't answer (it timed out, its connection
+/// was lost, or it's loading or has lost its master), rather than that it refused the request
+pub fn is_unavailable(frame: &BytesFrame) -> bool {
+    let BytesFrame::Error(message) = frame else {
+        return false;
+    };
+    UNAVAILABLE_ERRORS
+        .iter()
+        .any(|prefix| message.starts_with(prefix))
+}
+
+/// Build a RESP request frame from a command and its arguments
+pub fn request<I, A>(parts: I) -> BytesFrame
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<[u8]>,
+{
+    BytesFrame::Array(
+        parts
+            .into_iter()
+            .map(|p| BytesFrame::BulkString(Bytes::copy_from_slice(p.as_ref())))
+            .collect(),
+    )
+}
+
+/// Build a RESP error frame
+pub fn error(message: impl Into<String>) -> BytesFrame {
+    BytesFrame::Error(message.into().into())
+}
@@ -0,0 +1,1850 @@
+//! Proxy configuration files.
+//!
+//! A `Config` is read from TOML or YAML (chosen by file extension) and covers everything the
+//! `cabbage proxy` command line does, grouped into sections. Every field is optional; omitted
+//! fields take the same defaults as the corresponding flags. For example:
+//!
+//! ```toml
+//! [listen]
+//! address = "0.0.0.0:6380"
+//! max_connections = 1000
+//!
+//! [target]
+//! address = "10.0.0.5:6379"
+//! password = "hunter2"
+//!
+//! [timeouts]
+//! first_frame_ms = 500
+//!
+//! [logging]
+//! format = "json"
+//! levels = ["cabbage:info"]
+//!
+//! [middleware]
+//! deny_commands = ["FLUSHALL", "CONFIG|SET"]
+//! rate_limit = { commands_per_sec = 5000, mode = "delay" }
+//! ```
+//!
+//! Further tenants, each with a listener and target of its own, can be served by the same
+//! process. A tenant shares the top-level sections it doesn't give itself:
+//!
+//! ```toml
+//! [[tenants]]
+//! name = "sessions"
+//! listen = { address = "0.0.0.0:6381" }
+//! target = { address = "10.0.0.6:6379" }
+//! middleware = { key_prefix = "sessions:" }
+//! ```
+//!
+//! Clients can be required to authenticate to the proxy, and users given a target and key
+//! prefix of their own, so teams can share a listener without sharing a keyspace:
+//!
+//! ```toml
+//! [middleware.auth]
+//! password = "hunter3"
+//! users = [
+//!     { name = "payments", password = "hunter4", target = "10.0.0.7:6379" },
+//!     { name = "search", password = "hunter5", key_prefix = "search:" },
+//! ]
+//! ```
+//!
+//! ACL rules restrict what each client may do, the first rule matching a client applying to it
+//! (see `middleware::acl`):
+//!
+//! ```toml
+//! [[middleware.acl]]
+//! client = "10.1.0.0/16"
+//! allow_commands = ["GET", "MGET"]
+//! keys = ["public:*"]
+//!
+//! [[middleware.acl]]
+//! client = "user:admin"
+//! ```
+//!
+//! A running proxy reloads its configuration on SIGHUP; see `Config::restart_required` for the
+//! settings that only take effect on restart.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio_util::bytes::Bytes;
+
+use crate::balance::{BalancePolicy, Stickiness, WeightedTarget};
+use crate::capture::Rollover;
+use crate::error::{Error, Result, bail};
+use crate::listener::{IpNetwork, SourceRules};
+use crate::log_file::Rotation;
+use crate::middleware::acl::AclRule;
+use crate::middleware::auth::{Credentials, DEFAULT_USER};
+use crate::middleware::chaos::{DelayRule, ErrorRule};
+use crate::middleware::compress::CompressionRule;
+use crate::middleware::concurrency::{ConcurrencyLimit, ConcurrencyMode};
+use crate::middleware::config_command::{ConfigOverride, ConfigView};
+use crate::middleware::encrypt::EncryptionKey;
+use crate::middleware::filter::CommandRules;
+use crate::middleware::isolate::{Isolation, IsolationMode};
+use crate::middleware::limits::RequestLimits;
+use crate::middleware::maintenance::Maintenance;
+use crate::middleware::quota::{Quota, QuotaKey};
+use crate::middleware::ratelimit::{RateLimitMode, RateLimits};
+use crate::middleware::redact::RedactionRule;
+use crate::middleware::rewrite::RewriteRule;
+use crate::middleware::stack::StackLayer;
+use crate::middleware::throttle::ThrottleScope;
+use crate::middleware::ttl::TtlPolicy;
+use crate::middleware::{
+    CommandLogLevels, LogFormat, LogLevelRule, LogSampleRule, LogSampling, LogTruncation,
+};
+use crate::pool::PoolOverflow;
+use crate::proxy::{Frontend, OutputBufferLimit, OverflowPolicy};
+use crate::routing::{KeyRouteRule, RouteRule};
+use crate::service::{Handshake, QueueCapacities, SocketOptions};
+use crate::slo::{LatencySlo, Webhook};
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub target: TargetConfig,
+    pub timeouts: TimeoutConfig,
+    pub logging: LoggingConfig,
+    pub stats: StatsConfig,
+    pub capture: CaptureConfig,
+    pub audit: AuditConfig,
+    pub limits: RequestLimits,
+    pub middleware: MiddlewareConfig,
+    /// The executor the whole process (every tenant included) runs on
+    pub runtime: RuntimeConfig,
+    /// Further listeners, each proxying to a target of its own
+    pub tenants: Vec<TenantConfig>,
+}
+
+impl Config {
+    /// Read a configuration file, as TOML (`.toml`) or YAML (`.yaml`/`.yml`)
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::io(format!("Failed to read config file {}", path.display()), e))?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| e.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+            _ => bail!(
+                Config,
+                "Config file {} should have a .toml, .yaml, or .yml extension",
+                path.display()
+            ),
+        };
+        config.map_err(|e| {
+            Error::Config(format!(
+                "Failed to parse config file {}: {e}",
+                path.display()
+            ))
+        })
+    }
+
+    /// The configuration of the tenant called `name`, as though it were the only one
+    pub fn tenant(&self, name: &str) -> Result<Config> {
+        let Some(tenant) = self.tenants.iter().find(|t| t.name == name) else {
+            bail!(Config, "No tenant called '{name}' is configured");
+        };
+        let logging = tenant
+            .logging
+            .clone()
+            .unwrap_or_else(|| self.logging.clone());
+        let mut middleware = tenant
+            .middleware
+            .clone()
+            .unwrap_or_else(|| self.middleware.clone());
+        // As is the trace exporter
+        middleware.otlp_endpoint = self.middleware.otlp_endpoint.clone();
+        Ok(Config {
+            listen: tenant.listen.clone(),
+            target: tenant.target.clone(),
+            timeouts: tenant
+                .timeouts
+                .clone()
+                .unwrap_or_else(|| self.timeouts.clone()),
+            // Logging is set up once per process, so its outputs are the main proxy's
+            logging: LoggingConfig {
+                format: self.logging.format,
+                levels: self.logging.levels.clone(),
+                file: self.logging.file.clone(),
+                ..logging
+            },
+            stats: StatsConfig {
+                snapshot_path: self
+                    .stats
+                    .snapshot_path
+                    .as_deref()
+                    .map(|path| tenant_file(path, name)),
+                ..self.stats.clone()
+            },
+            capture: tenant
+                .capture
+                .clone()
+                .unwrap_or_else(|| self.capture.clone()),
+            audit: tenant.audit.clone().unwrap_or_else(|| self.audit.clone()),
+            limits: tenant.limits.unwrap_or(self.limits),
+            middleware,
+            runtime: self.runtime.clone(),
+            tenants: vec![],
+        })
+    }
+
+    /// Check for settings which only make sense together (or not at all)
+    pub fn validate(&self) -> Result<()> {
+        let mut addresses: Vec<_> = self.listen.addresses().collect();
+        for (i, address) in addresses.iter().enumerate() {
+            if addresses[..i].contains(address) {
+                bail!(Config, "{address} is listed more than once");
+            }
+        }
+        let mut capture_directories: Vec<_> = self.capture.directory.iter().cloned().collect();
+        let mut audit_paths: Vec<_> = self.audit.path.iter().cloned().collect();
+        for tenant in &self.tenants {
+            if tenant.name.is_empty() {
+                bail!(Config, "Every tenant needs a name");
+            }
+            if self
+                .tenants
+                .iter()
+                .filter(|t| t.name == tenant.name)
+                .count()
+                > 1
+            {
+                bail!(Config, "More than one tenant is called '{}'", tenant.name);
+            }
+            for address in tenant.listen.addresses() {
+                if addresses.contains(&address) {
+                    bail!(
+                        Config,
+                        "Tenant '{}' listens on {address}, which is already taken",
+                        tenant.name
+                    );
+                }
+                addresses.push(address);
+            }
+            let config = self.tenant(&tenant.name)?;
+            config.validate().map_err(|e| {
+                e.context(format!(
+                    "Invalid configuration for tenant '{}'",
+                    tenant.name
+                ))
+            })?;
+            if let Some(directory) = config.capture.directory {
+                if capture_directories.contains(&directory) {
+                    bail!(
+                        Config,
+                        "Tenant '{}' captures to {}, which is already in use; give it a capture \
+                         directory of its own",
+                        tenant.name,
+                        directory.display()
+                    );
+                }
+                capture_directories.push(directory);
+            }
+            if let Some(path) = config.audit.path {
+                if audit_paths.contains(&path) {
+                    bail!(
+                        Config,
+                        "Tenant '{}' audits to {}, which is already in use; give it an audit log \
+                         of its own",
+                        tenant.name,
+                        path.display()
+                    );
+                }
+                audit_paths.push(path);
+            }
+        }
+
+        let listens = std::iter::once(&self.listen).chain(self.tenants.iter().map(|t| &t.listen));
+        if listens.filter(|listen| listen.systemd).count() > 1 {
+            bail!(
+                Config,
+                "Only one listener can take its sockets from systemd"
+            );
+        }
+        let listen = &self.listen;
+        if listen.systemd && !listen.extra_addresses.is_empty() {
+            bail!(
+                Config,
+                "Listening on extra addresses rules out taking sockets from systemd"
+            );
+        }
+        if listen.systemd && listen.reuseport_acceptors.is_some() {
+            bail!(
+                Config,
+                "SO_REUSEPORT listeners can't be bound for sockets taken from systemd"
+            );
+        }
+        if listen.proxy_protocol && listen.trusted_proxies.is_empty() {
+            bail!(
+                Config,
+                "The PROXY protocol requires trusted_proxies, the networks of the load balancers \
+                 allowed to send its headers"
+            );
+        }
+
+        let target = &self.target;
+        if target.cluster && target.master_name.is_some() {
+            bail!(
+                Config,
+                "A target can't be both a cluster and a Sentinel-managed master"
+            );
+        }
+        if target.master_name.is_some() == target.sentinels.is_empty() {
+            bail!(
+                Config,
+                "Sentinel addresses and a master name must be configured together"
+            );
+        }
+        if !target.failover.is_empty() && (target.cluster || target.master_name.is_some()) {
+            bail!(
+                Config,
+                "Failover targets can't be combined with a cluster or Sentinel-managed master"
+            );
+        }
+        if !target.failover.is_empty() && target.health_check.is_none() {
+            bail!(
+                Config,
+                "Failover targets require a health check, to notice the first target recover"
+            );
+        }
+        if !target.balance.is_empty()
+            && (target.cluster || target.master_name.is_some() || !target.failover.is_empty())
+        {
+            bail!(
+                Config,
+                "Balanced targets can't be combined with a cluster, Sentinel-managed master, or \
+                 failover targets"
+            );
+        }
+        if !target.balance.is_empty() && target.health_check.is_none() {
+            bail!(
+                Config,
+                "Balanced targets require a health check, to return failed targets to rotation"
+            );
+        }
+        let pool = &target.pool;
+        if pool.is_enabled() && (target.cluster || !target.balance.is_empty()) {
+            bail!(
+                Config,
+                "A target connection pool can't be kept for a cluster or balanced targets"
+            );
+        }
+        if pool.is_enabled() && target.lazy_connect {
+            bail!(
+                Config,
+                "Pooled connections are handed to clients as they connect, ruling out lazy connects"
+            );
+        }
+        if pool.max_size == Some(0) {
+            bail!(
+                Config,
+                "A target connection pool's max_size must be at least 1"
+            );
+        }
+        if pool.max_size.is_some_and(|max| max < pool.min_size) {
+            bail!(
+                Config,
+                "A target connection pool's min_size can't exceed its max_size"
+            );
+        }
+        if target.username.is_some() && target.password.is_none() {
+            bail!(Config, "A target username requires a target password");
+        }
+        if target
+            .health_check
+            .as_ref()
+            .is_some_and(|h| h.interval_ms == 0)
+        {
+            bail!(Config, "The health check interval must be positive");
+        }
+        self.listen.queues.validate()?;
+        self.listen.socket.validate()?;
+        self.runtime.validate()?;
+        if let Some(output_buffer) = &self.listen.output_buffer {
+            output_buffer.validate()?;
+        }
+        target.socket.validate()?;
+        if self.timeouts.reply_ms.is_some() && self.timeouts.first_frame_ms.is_none() {
+            bail!(Config, "A reply timeout requires a first frame timeout");
+        }
+        if self.timeouts.target_ms.is_some() && !target.is_single() {
+            bail!(
+                Config,
+                "A target timeout is only supported for a single target"
+            );
+        }
+        let middleware = &self.middleware;
+        let secondaries = [
+            &middleware.mirror,
+            &middleware.dual_write,
+            &middleware.canary,
+        ];
+        if secondaries.iter().filter(|s| s.is_some()).count() > 1 {
+            bail!(
+                Config,
+                "Only one of mirroring, dual-writing, and canary diffing can be configured"
+            );
+        }
+        if middleware.retry.retries > 0 && target.is_single() {
+            bail!(
+                Config,
+                "Retries need a target which is reconnected to when its connection is lost: \
+                 failover or balanced targets, a cluster, or a Sentinel-managed master"
+            );
+        }
+        if middleware.max_in_flight == Some(0) {
+            bail!(Config, "max_in_flight must be at least 1");
+        }
+        if middleware.command_cache_ttl_ms == Some(0) {
+            bail!(Config, "command_cache_ttl_ms must be at least 1");
+        }
+        if middleware.share_subscriptions && !target.is_single() {
+            bail!(
+                Config,
+                "Sharing subscriptions is only supported for a single target"
+            );
+        }
+        let ttl = &middleware.ttl;
+        if [ttl.default_ms, ttl.min_ms, ttl.max_ms].contains(&Some(0)) {
+            bail!(Config, "TTL policy expiries must be at least 1ms");
+        }
+        if let (Some(min), Some(max)) = (ttl.min_ms, ttl.max_ms)
+            && min > max
+        {
+            bail!(
+                Config,
+                "The TTL policy's minimum ({min}ms) exceeds its maximum ({max}ms)"
+            );
+        }
+        if let Some(default) = ttl.default_ms
+            && (ttl.min_ms.is_some_and(|min| default < min)
+                || ttl.max_ms.is_some_and(|max| default > max))
+        {
+            bail!(
+                Config,
+                "The TTL policy's default ({default}ms) is outside its minimum and maximum"
+            );
+        }
+        if let Some(stack) = &middleware.stack {
+            for (i, layer) in stack.iter().enumerate() {
+                if stack[..i].contains(layer) {
+                    bail!(
+                        Config,
+                        "Middleware layer '{layer}' is listed in the stack more than once"
+                    );
+                }
+            }
+            let configured = [
+                (StackLayer::Trace, middleware.otlp_endpoint.is_some()),
+                (
+                    StackLayer::Transcripts,
+                    self.logging.transcript_dir.is_some(),
+                ),
+                (StackLayer::Capture, self.capture.directory.is_some()),
+                (StackLayer::Audit, self.audit.path.is_some()),
+                (StackLayer::Limits, !self.limits.is_empty()),
+                (StackLayer::Auth, middleware.auth.is_some()),
+                (StackLayer::Plugins, !middleware.plugins.is_empty()),
+                (StackLayer::Scripts, !middleware.scripts.is_empty()),
+                (StackLayer::Acl, !middleware.acl.is_empty()),
+                (StackLayer::Filter, !middleware.command_rules().is_empty()),
+                (StackLayer::Admin, self.stats.info_section),
+                (
+                    StackLayer::Keyspace,
+                    self.stats.key_space_sample_rate.is_some(),
+                ),
+                (
+                    StackLayer::Hotkeys,
+                    self.stats.hot_key_sample_rate.is_some(),
+                ),
+                (
+                    StackLayer::Slowlog,
+                    self.stats.slowlog_threshold_ms.is_some(),
+                ),
+            ];
+            if let Some((layer, _)) = configured
+                .iter()
+                .find(|(layer, set)| *set && !stack.contains(layer))
+            {
+                bail!(
+                    Config,
+                    "Middleware layer '{layer}' is configured, but left out of the stack"
+                );
+            }
+        }
+        if middleware
+            .maintenance
+            .error
+            .trim_start_matches('-')
+            .is_empty()
+        {
+            bail!(Config, "The maintenance error can't be empty");
+        }
+        if let Some(isolate) = &middleware.isolate {
+            if isolate.commands.is_empty() {
+                bail!(Config, "Isolation needs some commands to isolate");
+            }
+            if isolate.mode == IsolationMode::Side && !target.is_single() {
+                bail!(
+                    Config,
+                    "Isolating commands on side connections is only supported for a single target"
+                );
+            }
+        }
+        if let Some(quota) = &middleware.quota {
+            if quota.commands_per_sec.is_none()
+                && quota.max_in_flight.is_none()
+                && quota.bytes_per_day.is_none()
+            {
+                bail!(
+                    Config,
+                    "A quota must limit commands per second, in flight, or bytes per day"
+                );
+            }
+            if quota.commands_per_sec == Some(0) || quota.max_in_flight == Some(0) {
+                bail!(
+                    Config,
+                    "Quotas of commands per second and in flight must be at least 1"
+                );
+            }
+        }
+        if let Some(saturation) = &middleware.saturation {
+            if saturation.per_connection.is_none() && saturation.total.is_none() {
+                bail!(
+                    Config,
+                    "Saturation must limit commands in flight per connection or in total"
+                );
+            }
+            if saturation.per_connection == Some(0) || saturation.total == Some(0) {
+                bail!(
+                    Config,
+                    "Saturation limits of commands in flight must be at least 1"
+                );
+            }
+        }
+        if let Some(concurrency) = &middleware.concurrency {
+            if concurrency.limits.is_empty() {
+                bail!(Config, "Concurrency limiting needs some commands to limit");
+            }
+            let limits = &concurrency.limits;
+            for (i, limit) in limits.iter().enumerate() {
+                if limits[..i].iter().any(|l| l.command == limit.command) {
+                    bail!(
+                        Config,
+                        "{} is given more than one concurrency limit",
+                        limit.command
+                    );
+                }
+            }
+        }
+        if middleware
+            .cache
+            .as_ref()
+            .is_some_and(|c| c.track_invalidations)
+            && !target.is_single()
+        {
+            bail!(
+                Config,
+                "Cache invalidation tracking is only supported for a single target"
+            );
+        }
+        if let Some(encryption) = &middleware.encryption
+            && encryption.key.is_some() == encryption.key_file.is_some()
+        {
+            bail!(
+                Config,
+                "Encryption needs exactly one of a key and a key file"
+            );
+        }
+        if let Some(auth) = &middleware.auth {
+            if auth.password.is_none() && auth.users.is_empty() {
+                bail!(
+                    Config,
+                    "Client authentication needs a password or some users"
+                );
+            }
+            let mut names: Vec<_> = auth.password.iter().map(|_| DEFAULT_USER).collect();
+            for user in &auth.users {
+                if names.contains(&user.name.as_str()) {
+                    bail!(
+                        Config,
+                        "More than one client user is called '{}'",
+                        user.name
+                    );
+                }
+                names.push(&user.name);
+            }
+            let per_user = auth
+                .users
+                .iter()
+                .any(|user| user.target.is_some() || user.key_prefix.is_some());
+            if per_user && middleware.cache.is_some() {
+                bail!(
+                    Config,
+                    "The read cache is shared by every client, so can't be combined with \
+                     per-user targets or key prefixes"
+                );
+            }
+        }
+        if let Some(sample_rate) = self.stats.hot_key_sample_rate
+            && !(0.0..=1.0).contains(&sample_rate)
+        {
+            bail!(
+                Config,
+                "The hot key sample rate must be between 0.0 and 1.0"
+            );
+        }
+        if let Some(sample_rate) = self.stats.key_space_sample_rate
+            && !(0.0..=1.0).contains(&sample_rate)
+        {
+            bail!(
+                Config,
+                "The key space sample rate must be between 0.0 and 1.0"
+            );
+        }
+        if self.stats.statsd_address.is_some() && self.stats.statsd_interval_secs == 0 {
+            bail!(Config, "The StatsD push interval must be at least a second");
+        }
+        if !self.stats.latency_slos.is_empty() && self.stats.slo_interval_secs == 0 {
+            bail!(Config, "The latency SLO interval must be at least a second");
+        }
+        if self.stats.slo_webhook.is_some() && self.stats.latency_slos.is_empty() {
+            bail!(Config, "A latency SLO webhook requires some latency SLOs");
+        }
+        if self.stats.snapshot_path.is_some() && self.stats.snapshot_interval_secs == 0 {
+            bail!(
+                Config,
+                "The statistics snapshot interval must be at least a second"
+            );
+        }
+        if self.listen.passthrough {
+            if !target.is_single() {
+                bail!(
+                    Config,
+                    "Passthrough relaying is only supported for a single target"
+                );
+            }
+            if !target.routes.is_empty() || !target.key_routes.is_empty() {
+                bail!(
+                    Config,
+                    "Passthrough relaying doesn't decode commands, so can't route them"
+                );
+            }
+            if target.lazy_connect {
+                bail!(
+                    Config,
+                    "Passthrough relaying always connects to the target on accept"
+                );
+            }
+            if target.pool.is_enabled() {
+                bail!(
+                    Config,
+                    "Passthrough relaying can't take pooled target connections"
+                );
+            }
+            if target.resp3 {
+                bail!(
+                    Config,
+                    "Passthrough relaying doesn't decode replies, so can't translate RESP3"
+                );
+            }
+            if self.listen.frontend != Frontend::Resp
+                || self.listen.http_address.is_some()
+                || self.listen.grpc_address.is_some()
+            {
+                bail!(
+                    Config,
+                    "Passthrough relaying doesn't decode commands, so can't translate them"
+                );
+            }
+            let per_command = [
+                ("middleware", *middleware != MiddlewareConfig::default()),
+                ("limits", !self.limits.is_empty()),
+                ("capture", self.capture.directory.is_some()),
+                ("auditing", self.audit.path.is_some()),
+                ("transcripts", self.logging.transcript_dir.is_some()),
+                ("timeouts", self.timeouts != TimeoutConfig::default()),
+                ("an idle timeout", self.listen.idle_timeout_secs.is_some()),
+                (
+                    "a connection lifetime",
+                    self.listen.max_lifetime_secs.is_some(),
+                ),
+                (
+                    "an output buffer limit",
+                    self.listen.output_buffer.is_some(),
+                ),
+                ("the slowlog", self.stats.slowlog_threshold_ms.is_some()),
+                ("hot key sampling", self.stats.hot_key_sample_rate.is_some()),
+                ("an INFO section", self.stats.info_section),
+                ("latency SLOs", !self.stats.latency_slos.is_empty()),
+                (
+                    "key space sampling",
+                    self.stats.key_space_sample_rate.is_some(),
+                ),
+            ];
+            if let Some((setting, _)) = per_command.iter().find(|(_, set)| *set) {
+                bail!(
+                    Config,
+                    "Passthrough relaying doesn't decode commands, so can't support {setting}"
+                );
+            }
+        }
+        if let Some(stream_bulk_bytes) = target.stream_bulk_bytes {
+            if stream_bulk_bytes == 0 {
+                bail!(Config, "stream_bulk_bytes must be at least 1");
+            }
+            if target.cluster {
+                bail!(
+                    Config,
+                    "Streaming bulk replies isn't supported for a cluster"
+                );
+            }
+            // Everything which reads the values in replies needs them whole
+            let whole_replies = [
+                ("a read cache", middleware.cache.is_some()),
+                ("compression", !middleware.compression.is_empty()),
+                ("encryption", middleware.encryption.is_some()),
+                ("checksums", middleware.checksum.is_some()),
+                ("canary diffing", middleware.canary.is_some()),
+                ("plugins", !middleware.plugins.is_empty()),
+                ("scripts", !middleware.scripts.is_empty()),
+                ("capture", self.capture.directory.is_some()),
+                ("transcripts", self.logging.transcript_dir.is_some()),
+                (
+                    "the memcached frontend",
+                    self.listen.frontend == Frontend::Memcached,
+                ),
+                ("the HTTP frontend", self.listen.http_address.is_some()),
+                ("the gRPC frontend", self.listen.grpc_address.is_some()),
+            ];
+            if let Some((setting, _)) = whole_replies.iter().find(|(_, set)| *set) {
+                bail!(
+                    Config,
+                    "Streaming bulk replies can't be combined with {setting}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings changed in `new` which a running proxy can't apply without a restart. Log
+    /// levels, the allow/deny lists, and the rate limit are applied live on reload; everything
+    /// else is fixed at startup.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let (old_mw, new_mw) = (&self.middleware, &new.middleware);
+        [
+            ("listen", self.listen != new.listen),
+            ("target", self.target != new.target),
+            ("timeouts", self.timeouts != new.timeouts),
+            ("logging.format", self.logging.format != new.logging.format),
+            ("logging.redact", self.logging.redact != new.logging.redact),
+            (
+                "logging.default_redaction",
+                self.logging.default_redaction != new.logging.default_redaction,
+            ),
+            (
+                "logging.transcript_dir",
+                self.logging.transcript_dir != new.logging.transcript_dir,
+            ),
+            ("logging.sample", self.logging.sample != new.logging.sample),
+            ("logging.file", self.logging.file != new.logging.file),
+            (
+                "logging.sample_slow_ms",
+                self.logging.sample_slow_ms != new.logging.sample_slow_ms,
+            ),
+            (
+                "logging.max_arg_bytes",
+                self.logging.max_arg_bytes != new.logging.max_arg_bytes,
+            ),
+            (
+                "logging.max_args",
+                self.logging.max_args != new.logging.max_args,
+            ),
+            (
+                "logging.command_levels",
+                self.logging.command_levels != new.logging.command_levels,
+            ),
+            ("stats", self.stats != new.stats),
+            ("capture", self.capture != new.capture),
+            ("audit", self.audit != new.audit),
+            ("limits", self.limits != new.limits),
+            ("runtime", self.runtime != new.runtime),
+            ("tenants", self.tenants != new.tenants),
+            (
+                "middleware.key_prefix",
+                old_mw.key_prefix != new_mw.key_prefix,
+            ),
+            ("middleware.auth", old_mw.auth != new_mw.auth),
+            ("middleware.acl", old_mw.acl != new_mw.acl),
+            ("middleware.mirror", old_mw.mirror != new_mw.mirror),
+            (
+                "middleware.dual_write",
+                old_mw.dual_write != new_mw.dual_write,
+            ),
+            ("middleware.canary", old_mw.canary != new_mw.canary),
+            ("middleware.quota", old_mw.quota != new_mw.quota),
+            ("middleware.throttle", old_mw.throttle != new_mw.throttle),
+            (
+                "middleware.max_in_flight",
+                old_mw.max_in_flight != new_mw.max_in_flight,
+            ),
+            (
+                "middleware.saturation",
+                old_mw.saturation != new_mw.saturation,
+            ),
+            (
+                "middleware.concurrency",
+                old_mw.concurrency != new_mw.concurrency,
+            ),
+            ("middleware.retry", old_mw.retry != new_mw.retry),
+            (
+                "middleware.circuit_breaker",
+                old_mw.circuit_breaker != new_mw.circuit_breaker,
+            ),
+            ("middleware.cache", old_mw.cache != new_mw.cache),
+            (
+                "middleware.command_cache_ttl_ms",
+                old_mw.command_cache_ttl_ms != new_mw.command_cache_ttl_ms,
+            ),
+            (
+                "middleware.config_command",
+                old_mw.config_command != new_mw.config_command,
+            ),
+            ("middleware.isolate", old_mw.isolate != new_mw.isolate),
+            (
+                "middleware.track_scripts",
+                old_mw.track_scripts != new_mw.track_scripts,
+            ),
+            (
+                "middleware.share_subscriptions",
+                old_mw.share_subscriptions != new_mw.share_subscriptions,
+            ),
+            ("middleware.read_only", old_mw.read_only != new_mw.read_only),
+            ("middleware.stack", old_mw.stack != new_mw.stack),
+            (
+                "middleware.maintenance",
+                old_mw.maintenance != new_mw.maintenance,
+            ),
+            (
+                "middleware.compression",
+                old_mw.compression != new_mw.compression,
+            ),
+            ("middleware.rewrite", old_mw.rewrite != new_mw.rewrite),
+            ("middleware.ttl", old_mw.ttl != new_mw.ttl),
+            (
+                "middleware.translate_legacy_commands",
+                old_mw.translate_legacy_commands != new_mw.translate_legacy_commands,
+            ),
+            (
+                "middleware.encryption",
+                old_mw.encryption != new_mw.encryption,
+            ),
+            ("middleware.checksum", old_mw.checksum != new_mw.checksum),
+            (
+                "middleware.inject_latency",
+                old_mw.inject_latency != new_mw.inject_latency,
+            ),
+            (
+                "middleware.inject_errors",
+                old_mw.inject_errors != new_mw.inject_errors,
+            ),
+            (
+                "middleware.otlp_endpoint",
+                old_mw.otlp_endpoint != new_mw.otlp_endpoint,
+            ),
+            ("middleware.plugins", old_mw.plugins != new_mw.plugins),
+            ("middleware.scripts", old_mw.scripts != new_mw.scripts),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+}
+
+/// `path` with `.<tenant>` added before its extension, if it has one
+fn tenant_file(path: &Path, tenant: &str) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{tenant}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Address accepting client connections (`unix:<PATH>` for a Unix domain socket,
+    /// `pipe:<NAME>` for a Windows named pipe)
+    pub address: String,
+    /// Further addresses accepting client connections, served alongside `address`
+    pub extra_addresses: Vec<String>,
+    /// Serve at most this many clients at once
+    pub max_connections: Option<usize>,
+    /// What to do with clients beyond `max_connections`
+    pub overflow: OverflowPolicy,
+    /// Close client connections idle for this many seconds
+    pub idle_timeout_secs: Option<u64>,
+    /// Close client connections, along with their target connections, once they've been open
+    /// for about this many seconds and have no replies outstanding
+    pub max_lifetime_secs: Option<u64>,
+    /// Deal with clients which let too many of their replies wait for them to read them
+    pub output_buffer: Option<OutputBufferLimit>,
+    /// On shutdown, wait up to this many seconds for in-flight commands to complete
+    pub drain_timeout_secs: u64,
+    /// Expect a PROXY protocol header on every connection, from a load balancer in front
+    pub proxy_protocol: bool,
+    /// Only accept connections carrying PROXY protocol headers from load balancers in these
+    /// networks (CIDR blocks or addresses), as any client could send one
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Only accept TCP clients from these networks (CIDR blocks or addresses)
+    pub allow_sources: Vec<IpNetwork>,
+    /// Refuse TCP clients from these networks (CIDR blocks or addresses)
+    pub deny_sources: Vec<IpNetwork>,
+    /// Bind this many listeners with `SO_REUSEPORT`, each accepting in its own task (0 for one
+    /// per CPU), rather than a single listener
+    pub reuseport_acceptors: Option<usize>,
+    /// Relay connections to the target without decoding them, which rules out any middleware
+    pub passthrough: bool,
+    /// The protocol clients speak
+    pub frontend: Frontend,
+    /// Also accept HTTP requests on this address, translating them into commands
+    pub http_address: Option<String>,
+    /// Also serve gRPC calls (`proto/cabbage.proto`) on this address
+    pub grpc_address: Option<String>,
+    /// Serve the sockets passed by systemd socket activation rather than binding any addresses
+    pub systemd: bool,
+    /// How much is queued for each client connection, and its connection to a single target
+    pub queues: QueueCapacities,
+    /// Options set on accepted TCP client sockets
+    pub socket: SocketOptions,
+}
+
+impl ListenConfig {
+    /// Every address accepting client connections
+    pub fn addresses(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.address).chain(&self.extra_addresses)
+    }
+
+    /// The allow and deny lists as source rules
+    pub fn source_rules(&self) -> SourceRules {
+        SourceRules::default()
+            .allow(self.allow_sources.iter().copied())
+            .deny(self.deny_sources.iter().copied())
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:5000".to_string(),
+            extra_addresses: vec![],
+            max_connections: None,
+            overflow: OverflowPolicy::default(),
+            idle_timeout_secs: None,
+            max_lifetime_secs: None,
+            output_buffer: None,
+            drain_timeout_secs: 30,
+            proxy_protocol: false,
+            trusted_proxies: vec![],
+            allow_sources: vec![],
+            deny_sources: vec![],
+            reuseport_acceptors: None,
+            passthrough: false,
+            frontend: Frontend::default(),
+            http_address: None,
+            grpc_address: None,
+            systemd: false,
+            queues: QueueCapacities::default(),
+            socket: SocketOptions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetConfig {
+    /// Address of the target (or, for a cluster, a seed node)
+    pub address: String,
+    /// Treat the target as a Redis Cluster seed node
+    pub cluster: bool,
+    /// Sentinels used to discover the target master
+    pub sentinels: Vec<String>,
+    /// Name of the Sentinel-managed master
+    pub master_name: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    pub client_name: Option<String>,
+    /// PING the target periodically to notice it failing
+    pub health_check: Option<HealthCheckConfig>,
+    /// Send commands matching these rules to other targets, using the same handshake
+    pub routes: Vec<RouteRule>,
+    /// Send commands whose first key matches these rules to other targets, after `routes`
+    pub key_routes: Vec<KeyRouteRule>,
+    /// Connect to a single target when a client sends its first command rather than when it
+    /// connects (cluster, Sentinel, failover, and balanced targets are always connected to on
+    /// demand)
+    pub lazy_connect: bool,
+    /// Further targets, in order of preference after `address`, failed over to while it's down
+    /// and failed back from once it recovers
+    pub failover: Vec<String>,
+    /// Spread client connections across these targets by weight, instead of using `address`,
+    /// leaving those failing health checks out
+    pub balance: Vec<WeightedTarget>,
+    /// How each client connection's balanced target is picked
+    pub balance_policy: BalancePolicy,
+    /// Tie clients to balanced targets by their address or user, rather than by the policy
+    pub balance_stickiness: Stickiness,
+    /// Where client connections' target connections come from
+    pub pool: PoolConfig,
+    /// Options set on every target connection's socket
+    pub socket: SocketOptions,
+    /// Spread target connections across every address the target's name resolves to, rather
+    /// than preferring the first
+    pub rotate_addresses: bool,
+    /// Pass bulk string replies of at least this many bytes on to clients in chunks as they
+    /// arrive, rather than once they've arrived whole
+    pub stream_bulk_bytes: Option<usize>,
+    /// Speak RESP3 to the target, translating its replies to RESP2, so features only offered to
+    /// RESP3 connections can be used whatever clients speak (Redis 6 and later)
+    pub resp3: bool,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:6379".to_string(),
+            cluster: false,
+            sentinels: vec![],
+            master_name: None,
+            username: None,
+            password: None,
+            db: None,
+            client_name: None,
+            health_check: None,
+            routes: vec![],
+            key_routes: vec![],
+            lazy_connect: false,
+            failover: vec![],
+            balance: vec![],
+            balance_policy: BalancePolicy::default(),
+            balance_stickiness: Stickiness::default(),
+            pool: PoolConfig::default(),
+            socket: SocketOptions::default(),
+            rotate_addresses: false,
+            stream_bulk_bytes: None,
+            resp3: false,
+        }
+    }
+}
+
+/// The target connection pool, used if either size is set
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Target connections kept connected and handshaken ahead of clients, made before clients
+    /// are accepted and topped up as they're taken, following the target through failovers
+    pub min_size: usize,
+    /// Target connections open at once, whether handed to clients or ready
+    pub max_size: Option<usize>,
+    /// How long a client queues for a connection once `max_size` are open
+    pub max_wait_ms: u64,
+    /// What a client gets once `max_size` connections are open
+    pub overflow: PoolOverflow,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 0,
+            max_size: None,
+            max_wait_ms: 1000,
+            overflow: PoolOverflow::default(),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.min_size > 0 || self.max_size.is_some()
+    }
+}
+
+impl TargetConfig {
+    /// Whether the target is a single address, rather than a cluster, a Sentinel-managed
+    /// master, a failover list, or balanced targets
+    pub fn is_single(&self) -> bool {
+        !self.cluster
+            && self.master_name.is_none()
+            && self.failover.is_empty()
+            && self.balance.is_empty()
+    }
+
+    /// The handshake performed on every new target connection
+    pub fn handshake(&self) -> Handshake {
+        Handshake {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            db: self.db,
+            client_name: self.client_name.clone(),
+            socket: self.socket.clone(),
+            rotate_addresses: self.rotate_addresses,
+            stream_bulk_bytes: self.stream_bulk_bytes,
+            resp3: self.resp3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub interval_ms: u64,
+    /// Fail a check which isn't answered within this long
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl HealthCheckConfig {
+    pub fn new(interval_ms: u64) -> Self {
+        Self {
+            interval_ms,
+            timeout_ms: default_health_check_timeout_ms(),
+        }
+    }
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1000
+}
+
+/// A listener proxying to a target of its own, with its own settings where given
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Identifies the tenant in logs and stats reports
+    pub name: String,
+    pub listen: ListenConfig,
+    pub target: TargetConfig,
+    pub timeouts: Option<TimeoutConfig>,
+    /// The log format, levels, and file don't apply, since they're process-wide
+    pub logging: Option<LoggingConfig>,
+    pub capture: Option<CaptureConfig>,
+    pub audit: Option<AuditConfig>,
+    pub limits: Option<RequestLimits>,
+    /// Everything but `otlp_endpoint`, which is process-wide
+    pub middleware: Option<MiddlewareConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Time allowed until the first frame of a reply
+    pub first_frame_ms: Option<u64>,
+    /// Time allowed until the last frame of a reply
+    pub reply_ms: Option<u64>,
+    /// Time a single target's connection waits for each reply before answering with a timeout,
+    /// so a hung reply doesn't hold up the requests behind it
+    pub target_ms: Option<u64>,
+}
+
+/// Tuning of the tokio runtime, whose defaults suit most machines. A proxy spreading traffic over
+/// many NIC queues may want a worker per queue, say, where a development run wants just one.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Threads running tasks (by default, one per CPU)
+    pub worker_threads: Option<usize>,
+    /// Most threads running blocking work (file writes, ...) at once (by default, 512)
+    pub max_blocking_threads: Option<usize>,
+    /// Tasks a worker runs between polls for I/O and timer events (by default, 61)
+    pub event_interval: Option<u32>,
+    /// Serve tokio-console's instrumentation of tasks here (needs the console feature)
+    pub console_address: Option<SocketAddr>,
+}
+
+impl RuntimeConfig {
+    fn validate(&self) -> Result<()> {
+        if self.worker_threads == Some(0) {
+            bail!(Config, "worker_threads must be at least 1");
+        }
+        if self.max_blocking_threads == Some(0) {
+            bail!(Config, "max_blocking_threads must be at least 1");
+        }
+        if self.event_interval == Some(0) {
+            bail!(Config, "event_interval must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Build a multi-threaded runtime so tuned, with I/O and timers enabled
+    pub fn build(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads);
+        }
+        if let Some(event_interval) = self.event_interval {
+            builder.event_interval(event_interval);
+        }
+        builder.build()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Log level pairs of the form <MODULE>:<LEVEL>
+    pub levels: Vec<String>,
+    /// Redaction rules applied on top of the defaults, to logs, transcripts, audit logs, `MONITOR`
+    /// output, and captures
+    pub redact: Vec<RedactionRule>,
+    /// Whether credentials are masked by default
+    pub default_redaction: bool,
+    /// Write each connection's requests and replies, redacted, to a file of its own (named by
+    /// its connection ID) in this directory
+    pub transcript_dir: Option<PathBuf>,
+    /// Only log one in so many requests of each command, as set by the first rule matching it
+    pub sample: Vec<LogSampleRule>,
+    /// Log requests left out by sampling after all if their replies take at least this long
+    pub sample_slow_ms: Option<u64>,
+    /// Show at most this many bytes of each string in logged frames
+    pub max_arg_bytes: Option<usize>,
+    /// Show at most this many elements of each array in logged frames
+    pub max_args: Option<usize>,
+    /// Log commands at the level set by the first rule matching them, ahead of the defaults
+    pub command_levels: Vec<LogLevelRule>,
+    /// Also write the log to a file
+    pub file: LogFileConfig,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            levels: vec![],
+            redact: vec![],
+            default_redaction: true,
+            transcript_dir: None,
+            sample: vec![],
+            sample_slow_ms: None,
+            max_arg_bytes: None,
+            max_args: None,
+            command_levels: vec![],
+            file: LogFileConfig::default(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Which requests are logged
+    pub fn sampling(&self) -> LogSampling {
+        let sampling = LogSampling::new(self.sample.clone());
+        match self.sample_slow_ms {
+            Some(ms) => sampling.with_slow_threshold(Duration::from_millis(ms)),
+            None => sampling,
+        }
+    }
+
+    /// The level each command is logged at
+    pub fn command_levels(&self) -> CommandLogLevels {
+        CommandLogLevels::new(self.command_levels.clone())
+    }
+
+    /// How much of each frame is logged
+    pub fn truncation(&self) -> LogTruncation {
+        let truncation = LogTruncation::default();
+        let truncation = match self.max_arg_bytes {
+            Some(max) => truncation.with_max_arg_bytes(max),
+            None => truncation,
+        };
+        match self.max_args {
+            Some(max) => truncation.with_max_args(max),
+            None => truncation,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// Write the log to this file as well as the terminal (or, daemonized, instead of it)
+    pub path: Option<PathBuf>,
+    /// Rotate the file once it reaches this size
+    pub max_file_bytes: u64,
+    /// Rotate the file once it's this old
+    pub max_file_secs: u64,
+    /// Rotated files kept, beyond which the oldest are removed
+    pub keep: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_file_bytes: 100 * 1024 * 1024,
+            max_file_secs: 24 * 3600,
+            keep: 7,
+        }
+    }
+}
+
+impl LogFileConfig {
+    /// When the log file is rotated
+    pub fn rotation(&self) -> Rotation {
+        Rotation {
+            max_file_bytes: self.max_file_bytes,
+            max_file_age: Duration::from_secs(self.max_file_secs),
+            keep: self.keep,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Log a statistics report every this many seconds
+    pub interval_secs: Option<u64>,
+    pub slowlog_threshold_ms: Option<u64>,
+    pub slowlog_max_len: usize,
+    pub hot_key_sample_rate: Option<f64>,
+    pub hot_key_capacity: usize,
+    /// Fraction of commands (0.0 to 1.0) whose keys are counted by namespace
+    pub key_space_sample_rate: Option<f64>,
+    /// Push statistics to the StatsD server at this address (`host:port`)
+    pub statsd_address: Option<String>,
+    /// Push statistics to StatsD every this many seconds
+    pub statsd_interval_secs: u64,
+    /// Name StatsD metrics under this prefix
+    pub statsd_prefix: String,
+    /// Append a `# Cabbage` section of the proxy's statistics to the target's `INFO` replies
+    pub info_section: bool,
+    /// Alert when a command's p99 latency over an interval exceeds the threshold of the first of
+    /// these matching it
+    pub latency_slos: Vec<LatencySlo>,
+    /// Judge latency SLOs over this many seconds at a time
+    pub slo_interval_secs: u64,
+    /// POST latency SLO alerts to this `http://` URL as JSON, besides logging them
+    pub slo_webhook: Option<Webhook>,
+    /// Keep the cumulative statistics (connection, traffic, and per-command totals, and the
+    /// slowlog) in this file, restoring them from it on startup, so restarts don't zero them. A
+    /// tenant keeps its own beside it, named with the tenant's name before the extension.
+    pub snapshot_path: Option<PathBuf>,
+    /// Save the statistics snapshot every this many seconds, and on shutdown
+    pub snapshot_interval_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: None,
+            slowlog_threshold_ms: None,
+            slowlog_max_len: 128,
+            hot_key_sample_rate: None,
+            hot_key_capacity: 256,
+            key_space_sample_rate: None,
+            statsd_address: None,
+            statsd_interval_secs: 10,
+            statsd_prefix: "cabbage".to_string(),
+            info_section: false,
+            latency_slos: vec![],
+            slo_interval_secs: 60,
+            slo_webhook: None,
+            snapshot_path: None,
+            snapshot_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Record all traffic to files in this directory
+    pub directory: Option<PathBuf>,
+    /// Start a new capture file once the current one reaches this size
+    pub max_file_bytes: u64,
+    /// Start a new capture file once the current one is this old
+    pub max_file_secs: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            max_file_bytes: 256 * 1024 * 1024,
+            max_file_secs: 3600,
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// When captures move on to a new file
+    pub fn rollover(&self) -> Rollover {
+        Rollover {
+            max_file_bytes: self.max_file_bytes,
+            max_file_age: Duration::from_secs(self.max_file_secs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Append a hash-chained record of every administrative and write command to this file
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiddlewareConfig {
+    /// Only forward these commands (NAME or NAME|SUBCOMMAND)
+    pub allow_commands: Vec<String>,
+    /// Reject these commands (NAME or NAME|SUBCOMMAND)
+    pub deny_commands: Vec<String>,
+    /// Require clients to authenticate to the proxy before sending commands
+    pub auth: Option<AuthConfig>,
+    /// Restrict the commands and keys of each client according to the first rule matching it
+    pub acl: Vec<AclRule>,
+    /// Namespace all keys under this prefix
+    pub key_prefix: Option<String>,
+    /// Also send every request to this shadow target, discarding its replies
+    pub mirror: Option<String>,
+    /// Also send every command which may write to this target, discarding its replies
+    pub dual_write: Option<String>,
+    /// Also send commands to this target, recording where its replies differ
+    pub canary: Option<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Hold each client identity, across all its connections, to these limits
+    pub quota: Option<QuotaConfig>,
+    /// Limit the bandwidth of replies to clients
+    pub throttle: Option<ThrottleConfig>,
+    /// Let at most this many commands await replies from targets at once, across every client
+    /// connection, which take turns for them
+    pub max_in_flight: Option<usize>,
+    /// Refuse commands with `-BUSY` beyond these many in flight, rather than queueing them
+    pub saturation: Option<SaturationConfig>,
+    /// Limit how many of some commands execute at once, across every client connection
+    pub concurrency: Option<ConcurrencyConfig>,
+    pub retry: RetryConfig,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub cache: Option<CacheConfig>,
+    /// Answer `COMMAND` and `COMMAND DOCS` (and `COUNT`, `INFO`, and `LIST`) from the target's
+    /// replies, kept this long
+    pub command_cache_ttl_ms: Option<u64>,
+    /// Curate what clients see of the target's configuration and may change with `CONFIG`
+    pub config_command: Option<ConfigCommandConfig>,
+    /// Keep commands which disrupt the connection they're sent on off clients' target connections
+    pub isolate: Option<IsolationConfig>,
+    /// Remember scripts loaded through the proxy, so `EVALSHA` works on targets which haven't
+    /// loaded them, and load them on every failover or balanced target
+    pub track_scripts: bool,
+    /// Serve every client's subscriptions (other than to keyspace notifications) from a single
+    /// subscribed target connection, fanning messages out to them (single targets only)
+    pub share_subscriptions: bool,
+    /// Refuse writes with `-READONLY`, forwarding only reads (`CABBAGE.READONLY` switches this at
+    /// runtime)
+    pub read_only: bool,
+    /// Answer commands with an error instead of forwarding them (`CABBAGE.MAINTENANCE` switches
+    /// this at runtime)
+    pub maintenance: MaintenanceConfig,
+    /// The client-facing layers commands pass through, outermost first, leaving out any not
+    /// listed (`DEFAULT_STACK` when unset)
+    pub stack: Option<Vec<StackLayer>>,
+    /// Compress stored values according to the first rule matching their key
+    pub compression: Vec<CompressionRule>,
+    /// Rewrite commands before they're forwarded, each rule in turn
+    pub rewrite: Vec<RewriteRule>,
+    /// Hold the expiries writes set to these bounds, giving keys `SET` without one the default
+    pub ttl: TtlPolicy,
+    /// Translate deprecated commands (SETEX, GETSET, HMSET, ...) into their modern equivalents
+    pub translate_legacy_commands: bool,
+    pub encryption: Option<EncryptionConfig>,
+    /// Append a checksum to stored values, verifying and stripping it when they're read back
+    pub checksum: Option<ChecksumConfig>,
+    /// Delay the replies to some commands according to the first rule matching them, for
+    /// resilience testing
+    pub inject_latency: Vec<DelayRule>,
+    /// Answer some commands with synthetic errors according to the first rule matching them,
+    /// for resilience testing (`CABBAGE.FAULTS` changes these at runtime)
+    pub inject_errors: Vec<ErrorRule>,
+    /// OTLP/HTTP collector endpoint for trace spans (requires the `otel` feature)
+    pub otlp_endpoint: Option<String>,
+    /// WebAssembly modules each command and reply pass through, in order (requires the
+    /// `plugins` feature)
+    pub plugins: Vec<PathBuf>,
+    /// Lua scripts each command and reply pass through, in order, after any plugins (requires
+    /// the `lua` feature)
+    pub scripts: Vec<PathBuf>,
+}
+
+impl MiddlewareConfig {
+    /// The allow and deny lists as filter rules
+    pub fn command_rules(&self) -> CommandRules {
+        let rules = CommandRules::default().deny(&self.deny_commands);
+        if self.allow_commands.is_empty() {
+            rules
+        } else {
+            rules.allow(&self.allow_commands)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub commands_per_sec: u32,
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    pub mode: RateLimitMode,
+}
+
+impl RateLimitConfig {
+    pub fn new(commands_per_sec: u32) -> Self {
+        Self {
+            commands_per_sec,
+            bytes_per_sec: None,
+            mode: RateLimitMode::default(),
+        }
+    }
+
+    /// The limits applied to each client connection
+    pub fn limits(&self) -> RateLimits {
+        let limits = RateLimits::new(self.commands_per_sec).with_mode(self.mode);
+        match self.bytes_per_sec {
+            Some(bytes_per_sec) => limits.with_bytes_per_sec(bytes_per_sec),
+            None => limits,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Whether clients are told apart by user or by address
+    pub key: QuotaKey,
+    pub commands_per_sec: Option<u32>,
+    /// Commands awaiting replies at once
+    pub max_in_flight: Option<usize>,
+    /// Request and reply bytes each UTC day
+    pub bytes_per_day: Option<u64>,
+}
+
+impl QuotaConfig {
+    /// The limits each client identity is held to
+    pub fn quota(&self) -> Quota {
+        let mut quota = Quota::new(self.key);
+        if let Some(commands_per_sec) = self.commands_per_sec {
+            quota = quota.with_commands_per_sec(commands_per_sec);
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            quota = quota.with_max_in_flight(max_in_flight);
+        }
+        if let Some(bytes_per_day) = self.bytes_per_day {
+            quota = quota.with_bytes_per_day(bytes_per_day);
+        }
+        quota
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SaturationConfig {
+    /// Commands awaiting replies at once on each client connection
+    pub per_connection: Option<usize>,
+    /// Commands awaiting replies at once across every client connection
+    pub total: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    /// How many of each command may await replies at once, as COMMAND=MAX
+    pub limits: Vec<ConcurrencyLimit>,
+    /// Whether commands beyond their limit are rejected with -BUSY or queued
+    pub mode: ConcurrencyMode,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigCommandConfig {
+    /// Values `CONFIG GET` reports in place of the target's, as NAME=VALUE
+    pub overrides: Vec<ConfigOverride>,
+    /// Parameters clients may `CONFIG SET` on the target, as globs; setting others is refused
+    pub allow_set: Vec<String>,
+}
+
+impl ConfigCommandConfig {
+    /// What clients see of the target's configuration, including the proxy's own settings in
+    /// `listen` which stand in for the target's
+    pub fn view(&self, listen: &ListenConfig) -> ConfigView {
+        let own = [
+            (
+                "timeout",
+                listen.idle_timeout_secs.map(|secs| secs.to_string()),
+            ),
+            (
+                "maxclients",
+                listen.max_connections.map(|max| max.to_string()),
+            ),
+        ];
+        let mut view = ConfigView::new();
+        for (parameter, value) in own {
+            if let Some(value) = value {
+                view = view.with_override(ConfigOverride::new(parameter, value));
+            }
+        }
+        let view = self
+            .overrides
+            .iter()
+            .cloned()
+            .fold(view, ConfigView::with_override);
+        self.allow_set
+            .iter()
+            .fold(view, |view, glob| view.with_settable(glob))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IsolationConfig {
+    /// The commands isolated (NAME or NAME|SUBCOMMAND)
+    pub commands: Vec<String>,
+    /// Whether they're sent over side connections (single targets only) or rejected
+    pub mode: IsolationMode,
+}
+
+impl Default for IsolationConfig {
+    fn default() -> Self {
+        Self {
+            commands: ["DEBUG", "SHUTDOWN", "CLIENT|KILL", "CLIENT|PAUSE"]
+                .map(String::from)
+                .to_vec(),
+            mode: IsolationMode::default(),
+        }
+    }
+}
+
+impl IsolationConfig {
+    /// The isolated commands, rejected unless given somewhere to send them
+    pub fn isolation(&self) -> Isolation {
+        Isolation::new(&self.commands)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Whether the proxy starts in maintenance
+    pub enabled: bool,
+    /// The error commands are answered with, starting with its code
+    pub error: String,
+    /// Commands still forwarded (NAME or NAME|SUBCOMMAND)
+    pub allow: Vec<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            error: "LOADING maintenance in progress".to_string(),
+            allow: vec![],
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    pub fn maintenance(&self) -> Arc<Maintenance> {
+        Maintenance::new(self.enabled, &self.error, self.allow.clone())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleConfig {
+    pub bytes_per_sec: u64,
+    /// Whether each connection gets `bytes_per_sec` or all of them share it
+    #[serde(default)]
+    pub scope: ThrottleScope,
+}
+
+impl ThrottleConfig {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            scope: ThrottleScope::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retry read-only commands up to this many times (not for a single target, which isn't
+    /// reconnected to)
+    pub retries: usize,
+    /// Cap retries at this percentage of overall traffic
+    pub budget_percent: f32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            budget_percent: 10.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failures: usize,
+    #[serde(default = "default_cooldown_ms")]
+    pub cooldown_ms: u64,
+}
+
+impl CircuitBreakerConfig {
+    pub fn new(failures: usize) -> Self {
+        Self {
+            failures,
+            cooldown_ms: default_cooldown_ms(),
+        }
+    }
+}
+
+fn default_cooldown_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Serve cached GET replies for this long
+    pub ttl_ms: u64,
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// Never cache keys starting with any of these
+    #[serde(default)]
+    pub bypass_prefixes: Vec<String>,
+    /// Follow the target's reports of modified keys, so writes by clients not using the proxy
+    /// invalidate cached replies too (single targets only)
+    #[serde(default)]
+    pub track_invalidations: bool,
+    /// Answer GETs the target can't with the last reply cached, up to this long past its expiry
+    #[serde(default)]
+    pub serve_stale_ms: Option<u64>,
+}
+
+impl CacheConfig {
+    pub fn new(ttl_ms: u64) -> Self {
+        Self {
+            ttl_ms,
+            max_entries: default_cache_max_entries(),
+            bypass_prefixes: vec![],
+            track_invalidations: false,
+            serve_stale_ms: None,
+        }
+    }
+}
+
+fn default_cache_max_entries() -> usize {
+    10_000
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Password of the default user, as `AUTH <password>` authenticates
+    pub password: Option<String>,
+    /// Further users, as `AUTH <username> <password>` authenticates
+    pub users: Vec<UserConfig>,
+}
+
+impl AuthConfig {
+    /// The targets of the users which have one, as `(user, address)` pairs
+    pub fn user_targets(&self) -> impl Iterator<Item = (String, String)> {
+        self.users.iter().filter_map(|user| {
+            let target = user.target.clone()?;
+            Some((user.name.clone(), target))
+        })
+    }
+
+    /// The key prefixes of the users which have one
+    pub fn user_key_prefixes(&self) -> HashMap<String, Bytes> {
+        self.users
+            .iter()
+            .filter_map(|user| {
+                let prefix = user.key_prefix.clone()?;
+                Some((user.name.clone(), prefix.into()))
+            })
+            .collect()
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        let credentials = match &self.password {
+            Some(password) => Credentials::new().with_user(DEFAULT_USER, password),
+            None => Credentials::new(),
+        };
+        self.users.iter().fold(credentials, |credentials, user| {
+            credentials.with_user(&user.name, &user.password)
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    /// Send every command of clients authenticated as this user to this target, rather than
+    /// the main one
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Namespace the keys of clients authenticated as this user under this prefix
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// AES-256 key as 64 hexadecimal digits
+    pub key: Option<EncryptionKey>,
+    /// File holding the key, as raw bytes or hexadecimal digits
+    pub key_file: Option<PathBuf>,
+    /// Only encrypt values of keys under these prefixes (by default, every value is)
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChecksumConfig {
+    /// Only checksum values of keys under these prefixes (by default, every value is)
+    pub prefixes: Vec<String>,
+    /// Treat values read back without a checksum as corrupt, rather than passing them through
+    pub require: bool,
+}
+
+impl EncryptionConfig {
+    /// The configured key, reading it from the key file if need be
+    pub fn key(&self) -> Result<EncryptionKey> {
+        match (&self.key, &self.key_file) {
+            (Some(key), _) => Ok(key.clone()),
+            (None, Some(key_file)) => EncryptionKey::load(key_file),
+            (None, None) => bail!(Config, "No encryption key configured"),
+        }
+    }
+}
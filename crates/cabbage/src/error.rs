@@ -0,0 +1,108 @@
+//! The library's errors.
+//!
+//! Everything cabbage does which can fail fails with an `Error`, whose variant says broadly what
+//! went wrong, so an embedding application can act on it (retrying a target which was
+//! unavailable, reporting a bad configuration to whoever wrote it, ...) without parsing
+//! messages. Each variant's message is written for people, as the `cabbage` binary prints it.
+//!
+//! Within the library, `bail!` returns early with a variant holding a formatted message, as
+//! `anyhow::bail!` does with an untyped error.
+
+use std::fmt;
+use std::time::Duration;
+
+use redis_protocol::error::{RedisProtocolError, RedisProtocolErrorKind};
+
+use crate::pool::PoolExhausted;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A target couldn't be connected to or refused the handshake, or its connection was lost or
+    /// stopped taking requests
+    #[error("{0}")]
+    BackendUnavailable(String),
+    /// A pooled target connection was wanted but none came free
+    #[error(transparent)]
+    PoolExhausted(#[from] PoolExhausted),
+    /// A client or target sent something the protocol doesn't allow, or not what was asked for
+    #[error("{0}")]
+    Protocol(String),
+    /// Something wasn't done within the time allowed
+    #[error("not done within {0:?}")]
+    Timeout(Duration),
+    /// Settings which don't make sense, from a configuration file, the command line, or an admin
+    /// command
+    #[error("{0}")]
+    Config(String),
+    /// Data read back (a stored value, a capture, an audit log, ...) isn't what was written
+    #[error("{0}")]
+    Format(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A failure of something else the proxy runs, such as a plugin or script
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// An I/O error which happened while doing `what`
+    pub(crate) fn io(what: impl fmt::Display, source: std::io::Error) -> Self {
+        Self::Io(std::io::Error::new(
+            source.kind(),
+            format!("{what}: {source}"),
+        ))
+    }
+
+    /// A failure of something else, as `Other`
+    pub(crate) fn other(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self::Other(source.into())
+    }
+
+    /// The same error, its message led by what was being done when it happened
+    pub(crate) fn context(self, what: impl fmt::Display) -> Self {
+        match self {
+            Self::BackendUnavailable(message) => {
+                Self::BackendUnavailable(format!("{what}: {message}"))
+            }
+            Self::Protocol(message) => Self::Protocol(format!("{what}: {message}")),
+            Self::Config(message) => Self::Config(format!("{what}: {message}")),
+            Self::Format(message) => Self::Format(format!("{what}: {message}")),
+            Self::Io(source) => Self::io(what, source),
+            Self::Other(source) => Self::Other(format!("{what}: {source}").into()),
+            Self::PoolExhausted(_) | Self::Timeout(_) => self,
+        }
+    }
+}
+
+impl From<RedisProtocolError> for Error {
+    fn from(e: RedisProtocolError) -> Self {
+        match e.kind() {
+            RedisProtocolErrorKind::IO(io) => {
+                Self::Io(std::io::Error::new(io.kind(), e.to_string()))
+            }
+            _ => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<tower::BoxError> for Error {
+    /// Errors boxed by tower's own layers are unboxed again if they were ours to begin with
+    fn from(e: tower::BoxError) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => *e,
+            Err(e) => Self::Other(e),
+        }
+    }
+}
+
+/// Return early with an error of the given variant, its message formatted from the rest
+macro_rules! bail {
+    ($variant:ident, $($message:tt)+) => {
+        return Err($crate::error::Error::$variant(format!($($message)+).into()))
+    };
+}
+
+pub(crate) use bail;
@@ -0,0 +1,114 @@
+//! Exporting captured traffic as scripts.
+//!
+//! `export` writes the requests recorded by a `Capture` out for standard tooling to re-run:
+//! either as a `redis-cli` script, one command per line with its arguments quoted as `redis-cli`
+//! reads them (`redis-cli < script`), or RESP encoded, for `redis-cli --pipe`.
+//!
+//! A script runs over a single connection, so the requests of every connection exported are
+//! written in the order they were captured, and state one of them sets (`SELECT`, `MULTI`, ...)
+//! carries over to the others; export a single connection to re-run its session faithfully.
+//! Requests which would leave the script's connection unable to carry on (subscribing,
+//! `MONITOR`, `QUIT`) are left out, along with the rest of their connection's requests once it's
+//! subscribed.
+
+use std::collections::HashSet;
+use std::io::Write;
+
+use redis_protocol::codec::Resp2;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{BufMut as _, BytesMut};
+use tokio_util::codec::Encoder as _;
+
+use crate::capture::{Direction, Record, RecordFilter};
+use crate::error::{Error, Result, bail};
+use crate::{command, frame};
+
+/// How `export` writes requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScriptFormat {
+    /// One command per line, as `redis-cli` reads them from its input
+    #[default]
+    Commands,
+    /// RESP encoded, as `redis-cli --pipe` reads them
+    Resp,
+}
+
+impl std::str::FromStr for ScriptFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "commands" => Ok(ScriptFormat::Commands),
+            "resp" => Ok(ScriptFormat::Resp),
+            _ => bail!(Config, "Unrecognized script format '{s}'"),
+        }
+    }
+}
+
+/// What an export wrote
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    /// Requests written
+    pub exported: usize,
+    /// Requests left out, since the script's connection couldn't carry on after them
+    pub skipped: usize,
+}
+
+/// Write the requests of `records` picked by `filter` to `out` in `format`
+pub fn export(
+    records: impl IntoIterator<Item = Result<Record>>,
+    filter: &mut RecordFilter,
+    format: ScriptFormat,
+    out: &mut impl Write,
+) -> Result<ExportSummary> {
+    let mut summary = ExportSummary::default();
+    let mut subscribed = HashSet::new();
+    let mut encoded = BytesMut::new();
+    for record in records {
+        let record = record?;
+        if !filter.picks(&record) || record.direction != Direction::Request {
+            continue;
+        }
+        if subscribed.contains(&record.connection_id) {
+            summary.skipped += 1;
+            continue;
+        }
+        if command::starts_push_mode(&record.frame) {
+            subscribed.insert(record.connection_id);
+            summary.skipped += 1;
+            continue;
+        }
+        if command::name(&record.frame).as_deref() == Some("QUIT") {
+            summary.skipped += 1;
+            continue;
+        }
+        encoded.clear();
+        match format {
+            ScriptFormat::Commands => {
+                write_command(&record.frame, &mut encoded);
+                encoded.put_u8(b'\n');
+            }
+            ScriptFormat::Resp => Resp2::default().encode(record.frame, &mut encoded)?,
+        }
+        out.write_all(&encoded)?;
+        summary.exported += 1;
+    }
+    out.flush()?;
+    Ok(summary)
+}
+
+/// Write the arguments of `req` to `line`, each quoted so `redis-cli` reads it back unchanged
+fn write_command(req: &BytesFrame, line: &mut BytesMut) {
+    for (i, arg) in command::args(req).unwrap_or_default().iter().enumerate() {
+        if i > 0 {
+            line.put_u8(b' ');
+        }
+        match arg {
+            BytesFrame::Integer(n) => frame::quote(n.to_string().as_bytes(), line),
+            arg => frame::quote(
+                command::arg_bytes(arg).map_or(&b""[..], |a| a.as_ref()),
+                line,
+            ),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Failing over between an ordered list of targets.
+//!
+//! `FailoverTargets` holds targets in order of preference and which of them is in use: the first
+//! which passed its last health check. A failed connection to the target in use moves on to the
+//! next at once, without waiting for a check, while checks move back to a more preferred target
+//! as soon as it recovers. `FailoverBackend` forwards each client connection's traffic to
+//! whichever target is in use, reconnecting when that changes, so clients follow both failovers
+//! and fail-backs at their next command. As with Sentinel, reconnections switch to the database
+//! the client last selected rather than the handshake's.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Service;
+
+use crate::error::{Error, bail};
+use crate::pool::TargetPool;
+use crate::service::{Handshake, Resp2Backend, ResponseStream, SelectedDb};
+
+/// Targets in order of preference, and which is in use
+pub struct FailoverTargets {
+    addresses: Vec<String>,
+    current: AtomicUsize,
+}
+
+impl FailoverTargets {
+    /// Use the first of `addresses` until it fails
+    pub fn new(addresses: Vec<String>) -> Self {
+        Self {
+            addresses,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Every target, most preferred first
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    /// The target in use
+    pub fn current(&self) -> String {
+        self.addresses[self.current.load(Ordering::Relaxed)].clone()
+    }
+
+    fn switch(&self, from: usize, to: usize) {
+        if to != from
+            && self
+                .current
+                .compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let direction = if to < from {
+                "Failing back"
+            } else {
+                "Failing over"
+            };
+            log::warn!(
+                "{direction} from target {} to {}",
+                self.addresses[from],
+                self.addresses[to]
+            );
+        }
+    }
+
+    /// Move on from `address` to the next target, if it's still the one in use, after a
+    /// connection to it failed
+    pub fn fail(&self, address: &str) {
+        let current = self.current.load(Ordering::Relaxed);
+        if self.addresses[current] == address {
+            self.switch(current, (current + 1) % self.addresses.len());
+        }
+    }
+
+    /// Use the most preferred of the targets which just passed a health check, if any did
+    pub fn select(&self, healthy: &[String]) {
+        if let Some(preferred) = self.addresses.iter().position(|a| healthy.contains(a)) {
+            self.switch(self.current.load(Ordering::Relaxed), preferred);
+        }
+    }
+}
+
+/// A backend which follows the target in use of a `FailoverTargets`
+pub struct FailoverBackend {
+    targets: Arc<FailoverTargets>,
+    handshake: Arc<Handshake>,
+    selected: SelectedDb,
+    connection: Arc<tokio::sync::Mutex<Option<(String, Resp2Backend)>>>,
+    pool: Option<Arc<TargetPool>>,
+}
+
+impl FailoverBackend {
+    pub fn new(targets: Arc<FailoverTargets>) -> Self {
+        Self {
+            targets,
+            handshake: Arc::new(Handshake::default()),
+            selected: SelectedDb::new(),
+            connection: Arc::new(tokio::sync::Mutex::new(None)),
+            pool: None,
+        }
+    }
+
+    /// Perform `handshake` on every connection made to a target
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Arc::new(handshake);
+        self
+    }
+
+    /// Check connections out of `pool` rather than making them directly
+    pub fn with_pool(mut self, pool: Arc<TargetPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+}
+
+impl Service<BytesFrame> for FailoverBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let targets = self.targets.clone();
+        let handshake = self.handshake.clone();
+        let selected = self.selected.clone();
+        let connection = self.connection.clone();
+        let pool = self.pool.clone();
+
+        Box::pin(async move {
+            let mut connection = connection.lock().await;
+            // Each target is tried at most once for a request
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                let address = targets.current();
+                let last_attempt = attempts >= targets.addresses().len();
+
+                if connection.as_ref().is_none_or(|(a, _)| a != &address) {
+                    *connection = None;
+                    let handshake = selected.handshake(&handshake);
+                    let connected = match &pool {
+                        Some(pool) => pool.checkout(&address, selected.get()).await,
+                        None => Resp2Backend::connect_with(&address, &handshake).await,
+                    };
+                    match connected {
+                        Ok(backend) => {
+                            log::info!("Connected to target at {address}");
+                            *connection = Some((address.clone(), backend));
+                        }
+                        Err(e) if !last_attempt => {
+                            log::warn!("Failed to connect to target at {address}: {e}");
+                            targets.fail(&address);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                let Some((_, backend)) = connection.as_mut() else {
+                    bail!(BackendUnavailable, "No connection to target at {address}");
+                };
+                // A failed dispatch means the request never reached the target, so it is safe to
+                // resend it to the next one.
+                match backend.call(req.clone()).await {
+                    Ok(responses) => return Ok(selected.track(&req, responses)),
+                    Err(e) if !last_attempt => {
+                        log::warn!("Connection to target at {address} failed: {e}");
+                        *connection = None;
+                        targets.fail(&address);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
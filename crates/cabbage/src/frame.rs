@@ -0,0 +1,131 @@
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{BufMut as _, BytesMut};
+
+use crate::command;
+
+fn digits(mut n: usize) -> usize {
+    let mut digits = 1;
+    while n >= 10 {
+        n /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+/// Number of bytes `frame` occupies on the wire when RESP2 encoded
+pub fn encoded_len(frame: &BytesFrame) -> usize {
+    match frame {
+        BytesFrame::SimpleString(s) => 1 + s.len() + 2,
+        BytesFrame::Error(s) => 1 + s.len() + 2,
+        BytesFrame::Integer(i) => 1 + digits(i.unsigned_abs() as usize) + usize::from(*i < 0) + 2,
+        BytesFrame::BulkString(b) => 1 + digits(b.len()) + 2 + b.len() + 2,
+        BytesFrame::Array(frames) => {
+            1 + digits(frames.len()) + 2 + frames.iter().map(encoded_len).sum::<usize>()
+        }
+        BytesFrame::Null => 5,
+    }
+}
+
+/// Longest rendering of a frame in a `diff` summary
+static MAX_DESCRIBED_LEN: usize = 64;
+
+/// Where `left` and `right` first differ, if they do, summarized as e.g.
+/// `at [1]: BulkString(b"a") vs BulkString(b"b")`
+pub fn diff(left: &BytesFrame, right: &BytesFrame) -> Option<String> {
+    diff_at(String::new(), left, right)
+}
+
+fn diff_at(path: String, left: &BytesFrame, right: &BytesFrame) -> Option<String> {
+    let at = if path.is_empty() {
+        String::new()
+    } else {
+        format!("at {path}: ")
+    };
+    match (left, right) {
+        (BytesFrame::Array(l), BytesFrame::Array(r)) if l.len() == r.len() => l
+            .iter()
+            .zip(r)
+            .enumerate()
+            .find_map(|(i, (l, r))| diff_at(format!("{path}[{i}]"), l, r)),
+        (BytesFrame::Array(l), BytesFrame::Array(r)) => {
+            Some(format!("{at}{} vs {} element(s)", l.len(), r.len()))
+        }
+        (l, r) if l == r => None,
+        (l, r) => Some(format!("{at}{} vs {}", describe(l), describe(r))),
+    }
+}
+
+/// `frame` in debug form, cut short if it's long
+pub fn describe(frame: &BytesFrame) -> String {
+    let mut described = format!("{frame:?}");
+    if described.len() > MAX_DESCRIBED_LEN {
+        let mut end = MAX_DESCRIBED_LEN;
+        while !described.is_char_boundary(end) {
+            end -= 1;
+        }
+        described.truncate(end);
+        described.push_str("...");
+    }
+    described
+}
+
+/// Write `arg` to `line` in double quotes, escaped as Redis shows arguments in `MONITOR`
+pub fn quote(arg: &[u8], line: &mut BytesMut) {
+    line.put_u8(b'"');
+    escape(arg, line);
+    line.put_u8(b'"');
+}
+
+/// Write `arg` to `line` with quotes, backslashes, and non-printable bytes escaped, as `quote`
+/// does but without the quotes
+pub fn escape(arg: &[u8], line: &mut BytesMut) {
+    for &byte in arg {
+        match byte {
+            b'\\' | b'"' => line.put_slice(&[b'\\', byte]),
+            b'\n' => line.put_slice(b"\\n"),
+            b'\r' => line.put_slice(b"\\r"),
+            b'\t' => line.put_slice(b"\\t"),
+            0x07 => line.put_slice(b"\\a"),
+            0x08 => line.put_slice(b"\\b"),
+            b' '..=b'~' => line.put_u8(byte),
+            _ => line.put_slice(format!("\\x{byte:02x}").as_bytes()),
+        }
+    }
+}
+
+/// Write `frame` to `line` on a single line, in the style of `redis-cli`
+pub fn render(frame: &BytesFrame, line: &mut BytesMut) {
+    match frame {
+        BytesFrame::SimpleString(s) => {
+            line.put_u8(b'+');
+            line.put_slice(s);
+        }
+        BytesFrame::Error(e) => {
+            line.put_u8(b'-');
+            line.put_slice(e.as_bytes());
+        }
+        BytesFrame::Integer(i) => line.put_slice(format!(":{i}").as_bytes()),
+        BytesFrame::BulkString(s) => quote(s, line),
+        BytesFrame::Array(frames) => {
+            line.put_u8(b'[');
+            for (i, frame) in frames.iter().enumerate() {
+                if i > 0 {
+                    line.put_slice(b", ");
+                }
+                render(frame, line);
+            }
+            line.put_u8(b']');
+        }
+        BytesFrame::Null => line.put_slice(b"(nil)"),
+    }
+}
+
+/// Write the command `req` to `line` as its arguments, space separated, in the style of `render`
+pub fn render_command(req: &BytesFrame, line: &mut BytesMut) {
+    for (i, arg) in command::args(req).unwrap_or_default().iter().enumerate() {
+        if i > 0 {
+            line.put_u8(b' ');
+        }
+        render(arg, line);
+    }
+}
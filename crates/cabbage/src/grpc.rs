@@ -0,0 +1,189 @@
+//! gRPC frontend.
+//!
+//! Serves the `cabbage.v1.Proxy` service described by `proto/cabbage.proto`, whose `Execute` call
+//! runs a command through the same service stack as RESP clients' commands and streams back its
+//! reply, for services in languages with poor RESP client support. A subscription or `MONITOR`
+//! streams each frame the target pushes until the call is cancelled.
+//!
+//! The messages and service are written out here rather than generated, so building needs no
+//! `protoc`; they must be kept in step with the `.proto` file.
+
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::stream::BoxStream;
+use futures_util::StreamExt;
+use prost::bytes::Bytes;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::sync::CancellationToken;
+use tonic::codegen::http;
+use tonic::{Request, Response, Status};
+use tower::{Service, ServiceExt as _};
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::service::ProxyService;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Command {
+    #[prost(bytes = "bytes", repeated, tag = "1")]
+    pub args: Vec<Bytes>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Frame {
+    #[prost(oneof = "Kind", tags = "1, 2, 3, 4, 5, 6")]
+    pub kind: Option<Kind>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Kind {
+    #[prost(bytes = "bytes", tag = "1")]
+    SimpleString(Bytes),
+    #[prost(string, tag = "2")]
+    Error(String),
+    #[prost(int64, tag = "3")]
+    Integer(i64),
+    #[prost(bytes = "bytes", tag = "4")]
+    BulkString(Bytes),
+    #[prost(message, tag = "5")]
+    Array(Array),
+    #[prost(bool, tag = "6")]
+    Null(bool),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Array {
+    #[prost(message, repeated, tag = "1")]
+    pub frames: Vec<Frame>,
+}
+
+impl From<BytesFrame> for Frame {
+    fn from(frame: BytesFrame) -> Self {
+        let kind = match frame {
+            BytesFrame::SimpleString(s) => Kind::SimpleString(s),
+            BytesFrame::Error(e) => Kind::Error(e.to_string()),
+            BytesFrame::Integer(i) => Kind::Integer(i),
+            BytesFrame::BulkString(b) => Kind::BulkString(b),
+            BytesFrame::Array(frames) => Kind::Array(Array {
+                frames: frames.into_iter().map(Frame::from).collect(),
+            }),
+            BytesFrame::Null => Kind::Null(true),
+        };
+        Self { kind: Some(kind) }
+    }
+}
+
+type FrameStream = BoxStream<'static, Result<Frame, Status>>;
+
+/// Serves `cabbage.v1.Proxy`, building a service with `make_service` for each call
+struct ProxyServer<M> {
+    make_service: Arc<M>,
+}
+
+impl<M> Clone for ProxyServer<M> {
+    fn clone(&self) -> Self {
+        Self {
+            make_service: self.make_service.clone(),
+        }
+    }
+}
+
+impl<M> tonic::server::NamedService for ProxyServer<M> {
+    const NAME: &'static str = "cabbage.v1.Proxy";
+}
+
+/// Answer gRPC calls from `listener` until `shutdown` is cancelled, running each one's command
+/// through a service built by `make_service` for it
+pub async fn serve<M, F>(
+    listener: TcpListener,
+    make_service: M,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    M: Fn(Uuid) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<ProxyService>> + Send + 'static,
+{
+    let server = ProxyServer {
+        make_service: Arc::new(make_service),
+    };
+    tonic::transport::Server::builder()
+        .add_service(server)
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown.cancelled_owned())
+        .await
+        .map_err(Error::other)?;
+    Ok(())
+}
+
+impl<M, F> Service<http::Request<tonic::body::Body>> for ProxyServer<M>
+where
+    M: Fn(Uuid) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<ProxyService>> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<tonic::body::Body>) -> Self::Future {
+        if req.uri().path() != "/cabbage.v1.Proxy/Execute" {
+            return Box::pin(async { Ok(Status::unimplemented("").into_http()) });
+        }
+        let execute = Execute(self.make_service.clone());
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+            Ok(grpc.server_streaming(execute, req).await)
+        })
+    }
+}
+
+struct Execute<M>(Arc<M>);
+
+impl<M, F> tonic::server::ServerStreamingService<Command> for Execute<M>
+where
+    M: Fn(Uuid) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<ProxyService>> + Send + 'static,
+{
+    type Response = Frame;
+    type ResponseStream = FrameStream;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<FrameStream>, Status>> + Send>>;
+
+    fn call(&mut self, request: Request<Command>) -> Self::Future {
+        let make_service = self.0.clone();
+        Box::pin(async move {
+            let args = request.into_inner().args;
+            if args.is_empty() {
+                return Err(Status::invalid_argument("The command is empty"));
+            }
+            let id = Uuid::new_v4();
+            let unavailable = |e: Error| {
+                log::error!("gRPC call {id} failed: {e:#}");
+                Status::unavailable(format!("{e:#}"))
+            };
+            let mut service = make_service(id).await.map_err(unavailable)?;
+            let responses = service
+                .ready()
+                .await
+                .map_err(unavailable)?
+                .call(command::request(args))
+                .await
+                .map_err(unavailable)?;
+            // The service lives as long as its replies are streamed, which for a subscription is
+            // until the call is cancelled
+            let frames = responses.map(move |frame| {
+                let _ = &service;
+                Ok(Frame::from(frame))
+            });
+            Ok(Response::new(frames.boxed()))
+        })
+    }
+}
@@ -0,0 +1,155 @@
+//! Target health checks.
+//!
+//! `check_health` PINGs the target on an interval over connections of its own, recording each
+//! outcome in `Stats::backend`, so a dead target is noticed before the next client command rather
+//! than by it. A failed check drops the checker's connection (the next check reconnects) and
+//! prompts recovery: a Sentinel-managed master is re-resolved and a cluster's slot map refreshed,
+//! which moves client connections to the new nodes, while `HealthGateLayer` closes client
+//! connections to a single target so their clients reconnect. Every target of a failover list is
+//! checked, and the most preferred which passes is used; the check only fails if none does.
+//! Likewise every balanced target is checked, and only those which pass are kept in rotation.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::{Service, ServiceExt as _};
+
+use crate::balance::BalancedTargets;
+use crate::cluster::ClusterSlots;
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::failover::FailoverTargets;
+use crate::sentinel::SentinelMaster;
+use crate::service::{Handshake, Resp2Backend};
+use crate::stats::Stats;
+
+/// What `check_health` checks
+pub enum HealthTarget {
+    Single(String),
+    /// Whichever address the master currently has
+    Sentinel(Arc<SentinelMaster>),
+    /// Every node owning slots
+    Cluster(Arc<ClusterSlots>),
+    /// Every target of a failover list
+    Failover(Arc<FailoverTargets>),
+    /// Every balanced target
+    Balanced(Arc<BalancedTargets>),
+}
+
+impl HealthTarget {
+    fn addresses(&self) -> Vec<String> {
+        match self {
+            Self::Single(address) => vec![address.clone()],
+            Self::Sentinel(master) => master.current().into_iter().collect(),
+            Self::Cluster(slots) => slots.nodes().iter().map(|n| n.to_string()).collect(),
+            Self::Failover(targets) => targets.addresses().to_vec(),
+            Self::Balanced(targets) => targets.addresses(),
+        }
+    }
+
+    /// Look for the target elsewhere after a failed check
+    async fn recover(&self) {
+        match self {
+            // Failover and balanced targets are switched between as they're checked
+            Self::Single(_) | Self::Failover(_) | Self::Balanced(_) => {}
+            Self::Sentinel(master) => {
+                if let Err(e) = master.resolve().await {
+                    log::error!(
+                        "Failed to re-resolve master '{}': {e}",
+                        master.master_name()
+                    );
+                }
+            }
+            Self::Cluster(slots) => {
+                if let Err(e) = slots.refresh().await {
+                    log::error!("Failed to refresh cluster slot map: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Check `target` every `interval` for as long as the process runs, failing any check not
+/// answered within `timeout`
+pub async fn check_health(
+    target: HealthTarget,
+    handshake: Handshake,
+    interval: Duration,
+    timeout: Duration,
+    stats: Arc<Stats>,
+) {
+    let mut connections = HashMap::new();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let mut failed = false;
+        let mut healthy = vec![];
+        for address in target.addresses() {
+            match ping(&mut connections, &address, &handshake, timeout).await {
+                Ok(()) => healthy.push(address),
+                Err(e) => {
+                    log::warn!("Health check of {address} failed: {e:#}");
+                    failed = true;
+                }
+            }
+        }
+        match &target {
+            HealthTarget::Failover(targets) => {
+                targets.select(&healthy);
+                failed = healthy.is_empty();
+            }
+            HealthTarget::Balanced(targets) => {
+                targets.select(&healthy);
+                failed = healthy.is_empty();
+            }
+            _ => {}
+        }
+
+        let was_healthy = stats.backend.is_healthy();
+        stats.backend.record_health_check(!failed);
+        if failed {
+            target.recover().await;
+        } else if !was_healthy {
+            log::info!("Target passed its health check again");
+        }
+    }
+}
+
+/// PING `address`, connecting first if there's no connection to it
+async fn ping(
+    connections: &mut HashMap<String, Resp2Backend>,
+    address: &str,
+    handshake: &Handshake,
+    timeout: Duration,
+) -> Result<()> {
+    let check = async {
+        if !connections.contains_key(address) {
+            let backend = Resp2Backend::connect_with(address, handshake).await?;
+            connections.insert(address.to_string(), backend);
+        }
+        let Some(backend) = connections.get_mut(address) else {
+            bail!(BackendUnavailable, "no connection");
+        };
+        let mut replies = backend
+            .ready()
+            .await?
+            .call(command::request(["PING"]))
+            .await?;
+        match replies.next().await {
+            Some(BytesFrame::Error(e)) => bail!(BackendUnavailable, "PING failed: {e}"),
+            Some(_) => Ok(()),
+            None => bail!(BackendUnavailable, "connection closed"),
+        }
+    };
+    let result = tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(Error::Timeout(timeout)));
+    if result.is_err() {
+        connections.remove(address);
+    }
+    result
+}
@@ -0,0 +1,120 @@
+//! Connection lifecycle hooks.
+//!
+//! `ConnectionHooks` lets library users observe and police connections without writing a tower
+//! layer: implement the callbacks of interest and register the hooks with
+//! `ServeOptions::with_hooks` (or `ProxyBuilder::hooks`). Callbacks run inline on the
+//! connection's task, so they should be quick; anything slow belongs on a task of its own.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::stream::Stream;
+use futures::{Future, TryFutureExt as _};
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::Error;
+use crate::listener::ClientAddr;
+use crate::middleware::{OnComplete, reply};
+use crate::service::ResponseStream;
+use crate::stats::Traffic;
+
+/// Callbacks run as a client connection is served. Every callback has a default doing nothing
+/// (or allowing everything), so implementations only override those they need.
+///
+/// Passthrough relaying never decodes commands, so only `on_accept` and `on_close` are called
+/// for relayed connections.
+pub trait ConnectionHooks: Send + Sync {
+    /// A client connected from `client_addr`; returning false closes the connection at once
+    fn on_accept(&self, _connection_id: Uuid, _client_addr: &ClientAddr) -> bool {
+        true
+    }
+
+    /// The service for the connection was built, so its commands can now reach the target
+    fn on_backend_connect(&self, _connection_id: Uuid) {}
+
+    /// The client sent `command`; returning `Err(message)` answers it with `ERR <message>` rather
+    /// than forwarding the command
+    fn on_command(&self, _connection_id: Uuid, _command: &BytesFrame) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// The reply to the command named `command` has been streamed in full, `elapsed` after the
+    /// command was sent (never called for commands refused by `on_command`)
+    fn on_response_complete(&self, _connection_id: Uuid, _command: &str, _elapsed: Duration) {}
+
+    /// The connection closed, having carried `traffic`
+    fn on_close(&self, _connection_id: Uuid, _traffic: &Traffic) {}
+}
+
+/// Runs each command through a connection's `on_command` and `on_response_complete` hooks, if
+/// there are any
+pub(crate) struct Hooked<S> {
+    inner: S,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    connection_id: Uuid,
+}
+
+impl<S> Hooked<S> {
+    pub(crate) fn new(
+        inner: S,
+        hooks: Option<Arc<dyn ConnectionHooks>>,
+        connection_id: Uuid,
+    ) -> Self {
+        Self {
+            inner,
+            hooks,
+            connection_id,
+        }
+    }
+}
+
+impl<S> Service<BytesFrame> for Hooked<S>
+where
+    S: Service<BytesFrame>,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let Some(hooks) = self.hooks.clone() else {
+            return Box::pin(
+                self.inner
+                    .call(req)
+                    .map_ok(StreamExt::boxed)
+                    .map_err(Into::into),
+            );
+        };
+        let connection_id = self.connection_id;
+        if let Err(message) = hooks.on_command(connection_id, &req) {
+            log::debug!("Connection {connection_id}: command refused by hooks: {message}");
+            return Box::pin(async move { Ok(reply(command::error(format!("ERR {message}")))) });
+        }
+        let name = command::name(&req).unwrap_or_default();
+        let start = Instant::now();
+        Box::pin(
+            self.inner
+                .call(req)
+                .map_ok(move |responses| {
+                    OnComplete::new(responses.boxed(), move || {
+                        hooks.on_response_complete(connection_id, &name, start.elapsed())
+                    })
+                    .boxed()
+                })
+                .map_err(Into::into),
+        )
+    }
+}
@@ -0,0 +1,190 @@
+//! HTTP bridge.
+//!
+//! Serves a few HTTP endpoints, each request translated into a command and run through the same
+//! service stack as RESP clients' commands, for scripts and webhooks without a Redis client:
+//!
+//! - `GET /keys/{key}` answers with the key's value (404 if there's none)
+//! - `PUT /keys/{key}` sets the key to the request body, expiring after `?ttl_secs=N` if given
+//! - `POST /command` runs the command given as a JSON array of strings (`["INCR", "hits"]`),
+//!   answering with its reply as JSON: strings, integers, arrays, and `null`
+//!
+//! A command the target refuses is answered 400 with `{"error": "..."}`, and one which can't be
+//! sent at all 502. Subscriptions and `MONITOR` never finish answering, so they're refused.
+
+use std::sync::Arc;
+
+use axum::Json;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tower::{Service, ServiceExt as _};
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::service::ResponseStream;
+
+/// Builds the service each HTTP request's command runs through
+type MakeService<S> =
+    Arc<dyn Fn(Uuid) -> std::pin::Pin<Box<dyn Future<Output = Result<S>> + Send>> + Send + Sync>;
+
+struct Bridge<S> {
+    make_service: MakeService<S>,
+}
+
+impl<S> Clone for Bridge<S> {
+    fn clone(&self) -> Self {
+        Self {
+            make_service: self.make_service.clone(),
+        }
+    }
+}
+
+/// Answer HTTP requests from `listener` until `shutdown` is cancelled, running each one's command
+/// through a service built by `make_service` for it
+pub async fn serve<M, F, S>(
+    listener: TcpListener,
+    make_service: M,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    M: Fn(Uuid) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<S>> + Send + 'static,
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    let bridge = Bridge {
+        make_service: Arc::new(move |id| Box::pin(make_service(id))),
+    };
+    let router = Router::new()
+        .route("/keys/{key}", get(get_key::<S>).put(put_key::<S>))
+        .route("/command", post(run_command::<S>))
+        .with_state(bridge);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+/// An HTTP response for a request which couldn't be answered
+fn failure(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(json!({ "error": message.into() }))).into_response()
+}
+
+impl<S> Bridge<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    /// Run `req` through a new service, answering with its reply, or with an HTTP response if
+    /// there is none or it's an error
+    async fn call(&self, req: BytesFrame) -> Result<BytesFrame, Response> {
+        if command::starts_push_mode(&req) {
+            return Err(failure(
+                StatusCode::BAD_REQUEST,
+                "Subscriptions and MONITOR aren't supported over HTTP",
+            ));
+        }
+        let id = Uuid::new_v4();
+        let reply = async {
+            let mut service = (self.make_service)(id).await?;
+            let responses = service
+                .ready()
+                .await
+                .map_err(Into::into)?
+                .call(req)
+                .await
+                .map_err(Into::into)?;
+            Ok::<_, Error>(responses.into_future().await.0)
+        };
+        match reply.await {
+            Ok(Some(BytesFrame::Error(e))) => Err(failure(StatusCode::BAD_REQUEST, e.to_string())),
+            Ok(Some(frame)) => Ok(frame),
+            Ok(None) => Err(failure(StatusCode::BAD_GATEWAY, "The target didn't reply")),
+            Err(e) => {
+                log::error!("HTTP request {id} failed: {e:#}");
+                Err(failure(StatusCode::BAD_GATEWAY, format!("{e:#}")))
+            }
+        }
+    }
+}
+
+async fn get_key<S>(State(bridge): State<Bridge<S>>, Path(key): Path<String>) -> Response
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    match bridge.call(command::request(["GET", &key])).await {
+        Ok(BytesFrame::BulkString(value)) => value.into_response(),
+        Ok(BytesFrame::Null) => StatusCode::NOT_FOUND.into_response(),
+        Ok(frame) => Json(to_json(frame)).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct PutOptions {
+    ttl_secs: Option<u64>,
+}
+
+async fn put_key<S>(
+    State(bridge): State<Bridge<S>>,
+    Path(key): Path<String>,
+    Query(options): Query<PutOptions>,
+    value: Bytes,
+) -> Response
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    let mut set = vec![Bytes::from_static(b"SET"), Bytes::from(key), value];
+    if let Some(ttl_secs) = options.ttl_secs {
+        set.extend([Bytes::from_static(b"EX"), Bytes::from(ttl_secs.to_string())]);
+    }
+    match bridge.call(command::request(set)).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn run_command<S>(State(bridge): State<Bridge<S>>, Json(parts): Json<Vec<String>>) -> Response
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    if parts.is_empty() {
+        return failure(StatusCode::BAD_REQUEST, "The command is empty");
+    }
+    match bridge.call(command::request(parts)).await {
+        Ok(frame) => Json(to_json(frame)).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `frame` as JSON, strings which aren't UTF-8 made so lossily
+fn to_json(frame: BytesFrame) -> Value {
+    match frame {
+        BytesFrame::SimpleString(s) | BytesFrame::BulkString(s) => {
+            Value::String(String::from_utf8_lossy(&s).into_owned())
+        }
+        BytesFrame::Error(e) => json!({ "error": e.to_string() }),
+        BytesFrame::Integer(i) => Value::from(i),
+        BytesFrame::Array(frames) => Value::Array(frames.into_iter().map(to_json).collect()),
+        BytesFrame::Null => Value::Null,
+    }
+}
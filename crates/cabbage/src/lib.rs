@@ -1,8 +1,47 @@
+pub mod analyze;
+pub mod audit;
+pub mod balance;
+pub mod bench;
+pub mod buffer;
+pub mod builder;
+pub mod capture;
+pub mod check;
+pub mod cluster;
+pub mod command;
+pub mod config;
+pub mod error;
+pub mod export;
+pub mod failover;
+pub mod frame;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod hooks;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod listener;
+pub mod log_file;
+pub mod memcached;
 pub mod middleware;
+pub mod pool;
+pub mod protocol;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod replay;
+pub mod resp3;
+pub mod routing;
+pub mod scan;
+pub mod sentinel;
 pub mod service;
+pub mod slo;
+pub mod stats;
+pub mod statsd;
+pub mod streaming;
+pub mod testing;
+pub mod top;
 
-use anyhow::anyhow;
+pub use builder::{ProxyBuilder, ProxyHandle};
+pub use error::{Error, Result};
 
 pub static HAIKUS: [[&str; 3]; 10] = [
     [
@@ -58,7 +97,7 @@ pub static HAIKUS: [[&str; 3]; 10] = [
 ];
 
 /// Print a random project-related haiku
-pub fn print_haiku(print_all: bool) -> anyhow::Result<()> {
+pub fn print_haiku(print_all: bool) {
     use rand::seq::SliceRandom as _;
 
     if print_all {
@@ -71,9 +110,8 @@ pub fn print_haiku(print_all: bool) -> anyhow::Result<()> {
             "{}",
             HAIKUS
                 .choose(&mut rng)
-                .ok_or(anyhow!("at least one haiku"))?
+                .expect("at least one haiku")
                 .join("\n")
         )
     }
-    Ok(())
 }
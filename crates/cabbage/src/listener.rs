@@ -0,0 +1,421 @@
+//! Client listeners.
+//!
+//! A `Listener` accepts client connections over TCP or, on Unix, a Unix domain socket (addressed
+//! as `unix:<PATH>`), yielding a `ClientStream` and the `ClientAddr` it came from so the rest of
+//! the proxy serves both alike. A stale socket file left by an earlier process is replaced when
+//! binding, as Redis does with its `unixsocket`. On Windows, where there are no Unix domain
+//! sockets, local clients can connect over a named pipe instead (addressed as `pipe:<NAME>`, for
+//! `\\.\pipe\<NAME>`), which the listener keeps a fresh instance of waiting for the next client.
+//!
+//! Under systemd socket activation, `Listener::from_systemd` takes over the sockets systemd has
+//! already bound instead, so the proxy can be restarted without a moment where nothing listens.
+//!
+//! `SourceRules` decide which TCP clients may connect at all, by the networks their addresses
+//! are in, so access can be restricted without changing a firewall.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+#[cfg(any(unix, windows))]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::error::{Error, Result, bail};
+
+/// Addresses with this prefix name a Unix domain socket
+static UNIX_PREFIX: &str = "unix:";
+/// Addresses with this prefix name a Windows named pipe
+static PIPE_PREFIX: &str = "pipe:";
+/// Where Windows keeps named pipes, prepended to names which aren't full paths already
+#[cfg(windows)]
+static PIPE_NAMESPACE: &str = r"\\.\pipe\";
+/// The first file descriptor systemd passes a socket-activated process, per sd_listen_fds(3)
+#[cfg(unix)]
+static SD_LISTEN_FDS_START: i32 = 3;
+
+/// Where a client connection came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    /// The path of the socket the client connected to, since its own end is unnamed
+    #[cfg(unix)]
+    Unix(Arc<Path>),
+    /// The full name of the pipe the client connected to
+    #[cfg(windows)]
+    Pipe(Arc<str>),
+}
+
+impl From<SocketAddr> for ClientAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+            #[cfg(windows)]
+            Self::Pipe(name) => write!(f, "{PIPE_PREFIX}{name}"),
+        }
+    }
+}
+
+/// Whether `address` names a Unix domain socket rather than a TCP address
+pub fn is_unix_address(address: &str) -> bool {
+    address.starts_with(UNIX_PREFIX)
+}
+
+/// Whether `address` names a Windows named pipe rather than a TCP address
+pub fn is_pipe_address(address: &str) -> bool {
+    address.starts_with(PIPE_PREFIX)
+}
+
+/// A block of IP addresses, parsed from CIDR notation (`10.0.0.0/8`) or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Whether `ip` is in the network, comparing IPv4-mapped IPv6 addresses as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (ip, network, bits) = match (ip.to_canonical(), self.address) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                (u32::from(ip) as u128, u32::from(network) as u128, u32::BITS)
+            }
+            (IpAddr::V6(ip), IpAddr::V6(network)) => {
+                (u128::from(ip), u128::from(network), u128::BITS)
+            }
+            _ => return false,
+        };
+        let ignored = bits - u32::from(self.prefix_len);
+        ip.checked_shr(ignored).unwrap_or(0) == network.checked_shr(ignored).unwrap_or(0)
+    }
+}
+
+impl std::str::FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| Error::Config(format!("'{s}' should be an IP address or a CIDR block")))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| Error::Config(format!("Invalid prefix length in '{s}'")))?,
+            None => max_len,
+        };
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Which clients may connect, by source address. When an allow list is given, only clients in
+/// one of its networks may connect; the deny list is applied on top of that. Clients of Unix
+/// domain sockets and named pipes have no source address, so are always allowed.
+#[derive(Clone, Debug, Default)]
+pub struct SourceRules {
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+}
+
+impl SourceRules {
+    /// Permit only clients in the given networks (in addition to any previously allowed)
+    pub fn allow(mut self, networks: impl IntoIterator<Item = IpNetwork>) -> Self {
+        self.allowed.extend(networks);
+        self
+    }
+
+    /// Refuse clients in the given networks
+    pub fn deny(mut self, networks: impl IntoIterator<Item = IpNetwork>) -> Self {
+        self.denied.extend(networks);
+        self
+    }
+
+    /// Whether a client connecting from `client_addr` may be served
+    pub fn permits(&self, client_addr: &ClientAddr) -> bool {
+        let ClientAddr::Tcp(addr) = client_addr else {
+            return true;
+        };
+        let ip = addr.ip();
+        (self.allowed.is_empty() || self.allowed.iter().any(|n| n.contains(ip)))
+            && !self.denied.iter().any(|n| n.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_empty() && self.denied.is_empty()
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: Arc<Path>,
+    },
+    #[cfg(windows)]
+    Pipe {
+        /// The instance of the pipe waiting for the next client
+        server: tokio::sync::Mutex<NamedPipeServer>,
+        name: Arc<str>,
+    },
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl Listener {
+    /// Listen on `address`, a TCP address, `unix:<PATH>`, or `pipe:<NAME>`
+    pub async fn bind(address: &str) -> Result<Self> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            return Self::bind_unix(path);
+        }
+        if let Some(name) = address.strip_prefix(PIPE_PREFIX) {
+            return Self::bind_pipe(name);
+        }
+        Ok(Self::Tcp(TcpListener::bind(address).await?))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &str) -> Result<Self> {
+        use std::os::unix::fs::FileTypeExt as _;
+
+        let path = PathBuf::from(path);
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                bail!(Config, "{} exists and isn't a socket", path.display());
+            }
+            std::fs::remove_file(&path)?;
+        }
+        Ok(Self::Unix {
+            listener: UnixListener::bind(&path)?,
+            path: path.into(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_path: &str) -> Result<Self> {
+        bail!(
+            Config,
+            "Unix domain sockets aren't supported on this platform"
+        )
+    }
+
+    #[cfg(windows)]
+    fn bind_pipe(name: &str) -> Result<Self> {
+        let name: Arc<str> = if name.starts_with(r"\\") {
+            name.into()
+        } else {
+            format!("{PIPE_NAMESPACE}{name}").into()
+        };
+        // Refuse to share a name with a pipe another process has created
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&*name)
+            .map_err(|e| Error::io(format!("Failed to create named pipe {name}"), e))?;
+        Ok(Self::Pipe {
+            server: tokio::sync::Mutex::new(server),
+            name,
+        })
+    }
+
+    #[cfg(not(windows))]
+    fn bind_pipe(_name: &str) -> Result<Self> {
+        bail!(Config, "Named pipes aren't supported on this platform")
+    }
+
+    /// The listening sockets systemd passed this process (`LISTEN_FDS`), in the order the socket
+    /// unit lists them
+    #[cfg(unix)]
+    pub fn from_systemd() -> Result<Vec<Self>> {
+        use std::os::fd::{FromRawFd as _, IntoRawFd as _};
+
+        let pid = std::env::var("LISTEN_PID")
+            .map_err(|_| Error::Config("LISTEN_PID isn't set".to_string()))?;
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            bail!(
+                Config,
+                "LISTEN_PID is {pid}, so the sockets are meant for another process"
+            );
+        }
+        let count: i32 = std::env::var("LISTEN_FDS")
+            .map_err(|_| Error::Config("LISTEN_FDS isn't set".to_string()))?
+            .parse()
+            .map_err(|_| Error::Config("LISTEN_FDS isn't a number".to_string()))?;
+        if count < 1 {
+            bail!(Config, "systemd passed no sockets");
+        }
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                // SAFETY: systemd hands these descriptors to this process, and each is taken
+                // over exactly once, here
+                let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                // Only an inet socket has an address a TCP listener can make sense of
+                if tcp.local_addr().is_ok() {
+                    tcp.set_nonblocking(true)?;
+                    return Ok(Self::Tcp(TcpListener::from_std(tcp)?));
+                }
+                // SAFETY: the descriptor passes straight from one owner to the other
+                let unix =
+                    unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.set_nonblocking(true)?;
+                let path = unix
+                    .local_addr()?
+                    .as_pathname()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                Ok(Self::Unix {
+                    listener: UnixListener::from_std(unix)?,
+                    path: path.into(),
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_systemd() -> Result<Vec<Self>> {
+        bail!(
+            Config,
+            "systemd socket activation isn't supported on this platform"
+        )
+    }
+
+    /// The address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<ClientAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(Into::into),
+            #[cfg(unix)]
+            Self::Unix { path, .. } => Ok(ClientAddr::Unix(path.clone())),
+            #[cfg(windows)]
+            Self::Pipe { name, .. } => Ok(ClientAddr::Pipe(name.clone())),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(ClientStream, ClientAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((ClientStream::Tcp(stream), addr.into()))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, path } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(stream), ClientAddr::Unix(path.clone())))
+            }
+            #[cfg(windows)]
+            Self::Pipe { server, name } => {
+                let mut server = server.lock().await;
+                server.connect().await?;
+                // The connected instance is the client's, so another waits for the next client
+                let next = ServerOptions::new().create(&**name)?;
+                let stream = std::mem::replace(&mut *server, next);
+                Ok((ClientStream::Pipe(stream), ClientAddr::Pipe(name.clone())))
+            }
+        }
+    }
+}
+
+/// A client connection accepted by a `Listener`
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    Pipe(NamedPipeServer),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(windows)]
+            Self::Pipe(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(windows)]
+            Self::Pipe(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(windows)]
+            Self::Pipe(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(windows)]
+            Self::Pipe(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
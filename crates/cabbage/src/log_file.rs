@@ -0,0 +1,125 @@
+//! Log files.
+//!
+//! A `RotatingFile` appends the proxy's log to a file, rotating it once it reaches a size or age
+//! limit: `cabbage.log` is renamed `cabbage.log.1` (and any `cabbage.log.1` to `cabbage.log.2`,
+//! and so on) before a fresh `cabbage.log` is started, and the oldest files beyond the number to
+//! keep are removed. Files are only rotated between lines, so no line is split across two.
+
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// When a log file is rotated, and how many rotated files are kept
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    pub max_file_bytes: u64,
+    pub max_file_age: Duration,
+    /// Rotated files kept, beyond which the oldest are removed
+    pub keep: usize,
+}
+
+struct Current {
+    out: LineWriter<File>,
+    written: u64,
+    opened: Instant,
+    /// Whether the last byte written ended a line
+    at_line_start: bool,
+}
+
+/// A log file, shared by every logger writing to it
+#[derive(Clone)]
+pub struct RotatingFile {
+    path: Arc<Path>,
+    rotation: Rotation,
+    current: Arc<Mutex<Current>>,
+}
+
+impl RotatingFile {
+    /// Append to the log file at `path`, creating it (and its directory) if need be
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> Result<Self> {
+        let path: PathBuf = path.into();
+        if let Some(directory) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(directory).map_err(|e| {
+                Error::io(
+                    format!("Failed to create log directory {}", directory.display()),
+                    e,
+                )
+            })?;
+        }
+        let current = open(&path)?;
+        Ok(Self {
+            path: path.into(),
+            rotation,
+            current: Arc::new(Mutex::new(current)),
+        })
+    }
+
+    /// `path` with the rotation suffix for its `n`th most recent rotated file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Move the current file aside, shifting older ones along, and start a new one
+    fn rotate(&self, current: &mut Current) -> std::io::Result<()> {
+        current.out.flush()?;
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        *current = open(&self.path).map_err(std::io::Error::other)?;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<Current> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| Error::io(format!("Failed to open log file {}", path.display()), e))?;
+    Ok(Current {
+        written: file.metadata()?.len(),
+        out: LineWriter::new(file),
+        opened: Instant::now(),
+        at_line_start: true,
+    })
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut current = self
+            .current
+            .lock()
+            .map_err(|_| std::io::Error::other("Log file lock poisoned"))?;
+        let due = current.written >= self.rotation.max_file_bytes
+            || current.opened.elapsed() >= self.rotation.max_file_age;
+        if current.at_line_start && due {
+            self.rotate(&mut current)?;
+        }
+        let written = current.out.write(buf)?;
+        current.written += written as u64;
+        if written > 0 {
+            current.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.current
+            .lock()
+            .map_err(|_| std::io::Error::other("Log file lock poisoned"))?
+            .out
+            .flush()
+    }
+}
@@ -0,0 +1,466 @@
+//! Memcached text protocol frontend.
+//!
+//! Serves clients speaking memcached's text protocol, translating their commands to RESP on the
+//! way to the target and the replies back, so legacy memcached clients can use a Redis fleet
+//! through the usual middleware:
+//!
+//! - `get <key>*` becomes `GET` (or `MGET` for several keys)
+//! - `set`, `add`, and `replace` become `SET`, with `NX` for `add` and `XX` for `replace`, and
+//!   an expiry time becoming `EX` (or `EXAT`, for times beyond 30 days, as memcached reads them)
+//! - `delete <key>` becomes `DEL`
+//!
+//! Redis has nowhere to keep memcached's client flags, so storing a value with flags other than 0
+//! is refused rather than losing them, and values are always returned with flags 0. `quit` is
+//! ignored, leaving the client to close the connection, and anything else is answered `ERROR`.
+
+use std::sync::Arc;
+
+use futures::stream::Stream;
+use redis_protocol::error::{RedisProtocolError, RedisProtocolErrorKind};
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::frame;
+use crate::listener::ClientStream;
+use crate::protocol::{ClientCodec, ClientRequest};
+use crate::proxy::{ConnectionLimits, serve_connection};
+use crate::stats::Traffic;
+
+/// Longest command line a client may send, before any data block
+static MAX_LINE_BYTES: usize = 64 * 1024;
+/// Longest key memcached accepts
+static MAX_KEY_BYTES: usize = 250;
+/// Expiry times beyond this many seconds are Unix timestamps rather than durations
+static MAX_RELATIVE_EXPIRY_SECS: i64 = 60 * 60 * 24 * 30;
+
+/// How a command's reply is rendered in memcached's protocol
+#[derive(Clone, Debug, Default)]
+pub enum Reply {
+    /// An error in the client's request
+    #[default]
+    ClientError,
+    /// A command cabbage has no translation for
+    UnknownCommand,
+    /// The values of these keys, as `VALUE` lines
+    Values(Arc<[Bytes]>),
+    /// Whether a value was stored
+    Stored { noreply: bool },
+    /// Whether a key was deleted
+    Deleted { noreply: bool },
+}
+
+/// Memcached text protocol framing for client connections, refusing to buffer more than
+/// `max_frame_bytes` of a request still being received and counting the requests passing through
+/// in `traffic`
+struct MemcachedCodec {
+    max_frame_bytes: Option<usize>,
+    traffic: Arc<Traffic>,
+}
+
+fn client_error(message: impl Into<String>) -> ClientRequest<Reply> {
+    ClientRequest::Answer(command::error(message), Reply::ClientError)
+}
+
+fn bulk(bytes: impl Into<Bytes>) -> BytesFrame {
+    BytesFrame::BulkString(bytes.into())
+}
+
+impl MemcachedCodec {
+    /// Translate the command on `line`, whose data block (if it takes one) starts at `src[0]`,
+    /// or `None` if that data block is yet to arrive in full
+    fn translate(&self, line: &[u8], src: &mut BytesMut) -> Option<ClientRequest<Reply>> {
+        let tokens: Vec<&[u8]> = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|token| !token.is_empty())
+            .collect();
+        let Some((&name, args)) = tokens.split_first() else {
+            return Some(ClientRequest::Answer(
+                BytesFrame::Null,
+                Reply::UnknownCommand,
+            ));
+        };
+        if args
+            .iter()
+            .take(if name == b"get" { args.len() } else { 1 })
+            .any(|key| key.len() > MAX_KEY_BYTES)
+        {
+            return Some(client_error("line too long"));
+        }
+        let noreply = args.last() == Some(&&b"noreply"[..]);
+        let request = match (name, args) {
+            (b"get", []) => ClientRequest::Answer(BytesFrame::Null, Reply::UnknownCommand),
+            (b"get", keys) => {
+                let name: &[u8] = if keys.len() == 1 { b"GET" } else { b"MGET" };
+                let frame = command::request(std::iter::once(name).chain(keys.iter().copied()));
+                let keys = keys.iter().map(|key| Bytes::copy_from_slice(key)).collect();
+                ClientRequest::Command(frame, Reply::Values(keys))
+            }
+            (b"set" | b"add" | b"replace", [key, flags, exptime, len, ..])
+                if args.len() == 4 || (args.len() == 5 && noreply) =>
+            {
+                let (Some(flags), Some(exptime), Some(len)) = (
+                    parse::<u32>(flags),
+                    parse::<i64>(exptime),
+                    parse::<usize>(len),
+                ) else {
+                    return Some(client_error("bad command line format"));
+                };
+                if src.len() < len + 2 {
+                    return None;
+                }
+                let data = src.split_to(len).freeze();
+                if !src.starts_with(b"\r\n") {
+                    return Some(client_error("bad data chunk"));
+                }
+                let _ = src.split_to(2);
+                if flags != 0 {
+                    return Some(client_error("cabbage: flags other than 0 can't be stored"));
+                }
+                let mut set = vec![bulk("SET"), bulk(Bytes::copy_from_slice(key)), bulk(data)];
+                match name {
+                    b"add" => set.push(bulk("NX")),
+                    b"replace" => set.push(bulk("XX")),
+                    _ => {}
+                }
+                if exptime < 0 {
+                    // A time in the past expires the value at once
+                    set.extend([bulk("EXAT"), bulk("1")]);
+                } else if exptime > MAX_RELATIVE_EXPIRY_SECS {
+                    set.extend([bulk("EXAT"), bulk(exptime.to_string())]);
+                } else if exptime > 0 {
+                    set.extend([bulk("EX"), bulk(exptime.to_string())]);
+                }
+                ClientRequest::Command(BytesFrame::Array(set), Reply::Stored { noreply })
+            }
+            // The legacy form carries a hold time, which may only be 0
+            (b"delete", [key] | [key, b"noreply"] | [key, b"0"] | [key, b"0", b"noreply"]) => {
+                let frame = command::request([&b"DEL"[..], key]);
+                ClientRequest::Command(frame, Reply::Deleted { noreply })
+            }
+            (b"set" | b"add" | b"replace" | b"delete", _) => {
+                client_error("bad command line format")
+            }
+            _ => ClientRequest::Answer(BytesFrame::Null, Reply::UnknownCommand),
+        };
+        Some(request)
+    }
+}
+
+fn parse<T: std::str::FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+impl Decoder for MemcachedCodec {
+    type Item = ClientRequest<Reply>;
+    type Error = RedisProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let Some(end) = src.iter().position(|&b| b == b'\n') else {
+                if src.len() > MAX_LINE_BYTES {
+                    return Err(RedisProtocolError::new(
+                        RedisProtocolErrorKind::DecodeError,
+                        "line too long",
+                    ));
+                }
+                return Ok(None);
+            };
+            let line = src[..end]
+                .strip_suffix(b"\r")
+                .unwrap_or(&src[..end])
+                .to_vec();
+            if line == b"quit" {
+                let _ = src.split_to(end + 1);
+                continue;
+            }
+            // The data block follows the line, so the line stays buffered until it's complete
+            let mut rest = src.split_off(end + 1);
+            let buffered = rest.len() + end + 1;
+            let request = self.translate(&line, &mut rest);
+            if request.is_none() {
+                src.unsplit(rest);
+                if let Some(max) = self.max_frame_bytes
+                    && src.len() > max
+                {
+                    return Err(RedisProtocolError::new(
+                        RedisProtocolErrorKind::DecodeError,
+                        format!("request exceeds the limit of {max} bytes"),
+                    ));
+                }
+                return Ok(None);
+            }
+            src.clear();
+            src.unsplit(rest);
+            self.traffic.record_command(buffered - src.len());
+            return Ok(request);
+        }
+    }
+}
+
+impl Encoder<(BytesFrame, Reply)> for MemcachedCodec {
+    type Error = RedisProtocolError;
+
+    fn encode(&mut self, item: (BytesFrame, Reply), dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let buffered = dst.len();
+        match item {
+            (BytesFrame::Error(e), Reply::ClientError) => line(dst, ["CLIENT_ERROR ", &e]),
+            (_, Reply::UnknownCommand) => line(dst, ["ERROR"]),
+            (BytesFrame::Error(e), _) => line(dst, ["SERVER_ERROR ", &e]),
+            (frame, Reply::Values(keys)) => {
+                let values = match frame {
+                    BytesFrame::Array(values) => values,
+                    value => vec![value],
+                };
+                for (key, value) in keys.iter().zip(values) {
+                    if let BytesFrame::BulkString(value) = value {
+                        dst.put_slice(b"VALUE ");
+                        dst.put_slice(key);
+                        line(dst, [format!(" 0 {}", value.len()).as_str()]);
+                        dst.put_slice(&value);
+                        dst.put_slice(b"\r\n");
+                    }
+                }
+                line(dst, ["END"]);
+            }
+            (_, Reply::Stored { noreply: true } | Reply::Deleted { noreply: true }) => {}
+            (BytesFrame::Null, Reply::Stored { .. }) => line(dst, ["NOT_STORED"]),
+            (_, Reply::Stored { .. }) => line(dst, ["STORED"]),
+            (BytesFrame::Integer(0), Reply::Deleted { .. }) => line(dst, ["NOT_FOUND"]),
+            (_, Reply::Deleted { .. }) => line(dst, ["DELETED"]),
+            (_, Reply::ClientError) => line(dst, ["SERVER_ERROR unexpected reply"]),
+        }
+        self.traffic.record_response(dst.len() - buffered);
+        Ok(())
+    }
+}
+
+impl ClientCodec<BytesFrame> for MemcachedCodec {
+    type Encoding = Reply;
+
+    fn decode_error_reply(error: &RedisProtocolError) -> BytesFrame {
+        // Answered as `CLIENT_ERROR <description>`, as memcached does
+        command::error(error.details())
+    }
+
+    /// Answered as `SERVER_ERROR <description>`
+    fn dispatch_error_reply() -> BytesFrame {
+        command::error("proxy backend unavailable")
+    }
+
+    /// Answered as `SERVER_ERROR <description>`
+    fn dropped_reply() -> BytesFrame {
+        command::error("reply dropped, client output buffer limit reached")
+    }
+
+    fn reply_len(reply: &BytesFrame) -> usize {
+        frame::encoded_len(reply)
+    }
+}
+
+/// Write `parts` to `dst` as one line
+fn line<'a>(dst: &mut BytesMut, parts: impl IntoIterator<Item = &'a str>) {
+    parts
+        .into_iter()
+        .for_each(|part| dst.put_slice(part.as_bytes()));
+    dst.put_slice(b"\r\n");
+}
+
+/// Serve a memcached client connection through `target_service`, as `proxy::handle_connection`
+/// serves a RESP one
+pub async fn handle_connection<S>(
+    client_socket: ClientStream,
+    target_service: S,
+    connection_id: Uuid,
+    shutdown: CancellationToken,
+    limits: ConnectionLimits,
+    traffic: Arc<Traffic>,
+) -> Result<()>
+where
+    S: Service<BytesFrame>,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error>,
+{
+    let codec = MemcachedCodec {
+        max_frame_bytes: limits.max_frame_bytes,
+        traffic: traffic.clone(),
+    };
+    serve_connection(
+        client_socket,
+        codec,
+        target_service,
+        connection_id,
+        shutdown,
+        limits,
+        traffic,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> MemcachedCodec {
+        MemcachedCodec {
+            max_frame_bytes: None,
+            traffic: Arc::new(Traffic::default()),
+        }
+    }
+
+    /// The command forwarded for `input`, with how its reply is rendered
+    fn forwarded(input: &str) -> (BytesFrame, Reply) {
+        let mut src = BytesMut::from(input);
+        match codec().decode(&mut src).unwrap() {
+            Some(ClientRequest::Command(frame, reply)) => {
+                assert!(src.is_empty(), "{input:?} left {src:?}");
+                (frame, reply)
+            }
+            _ => panic!("{input:?} wasn't forwarded"),
+        }
+    }
+
+    /// The memcached reply to `input`, when the codec answers it itself
+    fn answer(input: &str) -> String {
+        let mut codec = codec();
+        let mut src = BytesMut::from(input);
+        let Some(ClientRequest::Answer(frame, reply)) = codec.decode(&mut src).unwrap() else {
+            panic!("{input:?} wasn't answered");
+        };
+        encode(frame, reply)
+    }
+
+    fn encode(frame: BytesFrame, reply: Reply) -> String {
+        let mut dst = BytesMut::new();
+        codec().encode((frame, reply), &mut dst).unwrap();
+        String::from_utf8(dst.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn gets_become_get_or_mget() {
+        assert_eq!(forwarded("get a\r\n").0, command::request(["GET", "a"]));
+        let (frame, reply) = forwarded("get a b c\r\n");
+        assert_eq!(frame, command::request(["MGET", "a", "b", "c"]));
+
+        let values = BytesFrame::Array(vec![bulk("1"), BytesFrame::Null, bulk("three")]);
+        assert_eq!(
+            encode(values, reply),
+            "VALUE a 0 1\r\n1\r\nVALUE c 0 5\r\nthree\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn stores_become_set() {
+        let cases = [
+            ("set a 0 0 5\r\nhello\r\n", vec!["SET", "a", "hello"]),
+            ("add a 0 0 5\r\nhello\r\n", vec!["SET", "a", "hello", "NX"]),
+            (
+                "replace a 0 0 5\r\nhello\r\n",
+                vec!["SET", "a", "hello", "XX"],
+            ),
+            (
+                "set a 0 60 5\r\nhello\r\n",
+                vec!["SET", "a", "hello", "EX", "60"],
+            ),
+            (
+                "set a 0 2000000000 5\r\nhello\r\n",
+                vec!["SET", "a", "hello", "EXAT", "2000000000"],
+            ),
+            (
+                "set a 0 -1 5\r\nhello\r\n",
+                vec!["SET", "a", "hello", "EXAT", "1"],
+            ),
+            ("set a 0 0 2\r\n\r\n\r\n", vec!["SET", "a", "\r\n"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(forwarded(input).0, command::request(&expected), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn data_blocks_are_awaited() {
+        let mut codec = codec();
+        let mut src = BytesMut::from("set a 0 0 5\r\nhel");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(&src[..], b"set a 0 0 5\r\nhel");
+        src.extend_from_slice(b"lo\r\nget a\r\n");
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ClientRequest::Command(..))
+        ));
+        assert_eq!(&src[..], b"get a\r\n");
+    }
+
+    #[test]
+    fn deletes_become_del() {
+        for input in ["delete a\r\n", "delete a 0\r\n", "delete a noreply\r\n"] {
+            assert_eq!(
+                forwarded(input).0,
+                command::request(["DEL", "a"]),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn replies_are_rendered_as_memcached_does() {
+        let stored = || Reply::Stored { noreply: false };
+        let deleted = || Reply::Deleted { noreply: false };
+        let ok = || BytesFrame::SimpleString("OK".into());
+        assert_eq!(encode(ok(), stored()), "STORED\r\n");
+        assert_eq!(encode(BytesFrame::Null, stored()), "NOT_STORED\r\n");
+        assert_eq!(encode(BytesFrame::Integer(1), deleted()), "DELETED\r\n");
+        assert_eq!(encode(BytesFrame::Integer(0), deleted()), "NOT_FOUND\r\n");
+        assert_eq!(encode(ok(), Reply::Stored { noreply: true }), "");
+        assert_eq!(
+            encode(command::error("OOM full"), stored()),
+            "SERVER_ERROR OOM full\r\n"
+        );
+    }
+
+    #[test]
+    fn bad_requests_are_client_errors() {
+        let cases = [
+            ("set a 0 0 five\r\nhello\r\n", "bad command line format"),
+            ("set a 0 0\r\n", "bad command line format"),
+            ("set a 0 0 5\r\nhello!\r\n", "bad data chunk"),
+            ("set a 1 0 5\r\nhello\r\n", "flags other than 0"),
+            ("delete a 5\r\n", "bad command line format"),
+        ];
+        for (input, error) in cases {
+            let reply = answer(input);
+            assert!(
+                reply.starts_with("CLIENT_ERROR ") && reply.contains(error),
+                "{input:?}: {reply:?}"
+            );
+        }
+        let long_key = format!("get {}\r\n", "k".repeat(MAX_KEY_BYTES + 1));
+        assert_eq!(answer(&long_key), "CLIENT_ERROR line too long\r\n");
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        for input in ["incr a 1\r\n", "get\r\n", "\r\n"] {
+            assert_eq!(answer(input), "ERROR\r\n", "{input:?}");
+        }
+    }
+
+    #[test]
+    fn quit_is_ignored() {
+        assert_eq!(
+            forwarded("quit\r\nget a\r\n").0,
+            command::request(["GET", "a"])
+        );
+    }
+
+    #[test]
+    fn overlong_lines_are_refused() {
+        let mut src = BytesMut::from(&vec![b'a'; MAX_LINE_BYTES + 1][..]);
+        assert!(codec().decode(&mut src).is_err());
+    }
+}
@@ -1,71 +1,523 @@
+pub mod acl;
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod breaker;
+pub mod cache;
+pub mod canary;
+pub mod capture;
+pub mod chaos;
+pub mod checksum;
+pub mod client_name;
+pub mod command_cache;
+pub mod compress;
+pub mod concurrency;
+pub mod config_command;
+pub mod encrypt;
+pub mod fair;
+pub mod fanout;
+pub mod filter;
+pub mod health;
+pub mod hotkeys;
+pub mod isolate;
+pub mod keyspace;
+pub mod latency;
+pub mod legacy;
+pub mod limits;
+pub mod maintenance;
+pub mod mirror;
+pub mod monitor;
+pub mod notifications;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod prefix;
+pub mod quota;
+pub mod ratelimit;
+pub mod read_only;
+pub mod redact;
+pub mod retry;
+pub mod rewrite;
+pub mod saturation;
+#[cfg(feature = "lua")]
+pub mod script;
+pub mod script_cache;
+pub mod slowlog;
+pub mod stack;
+pub mod throttle;
+pub mod timeout;
+#[cfg(feature = "otel")]
+pub mod trace;
+pub mod transcript;
+pub mod ttl;
+
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic;
 use std::sync::atomic::AtomicU64;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use futures::Future;
 use futures::TryFutureExt as _;
 use futures::stream::Stream;
 use futures_util::StreamExt;
-use lazy_static::lazy_static;
 use redis_protocol::resp2::types::BytesFrame;
-use tokio_util::bytes::Bytes;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_util::bytes::{BufMut as _, BytesMut};
 use tower::Layer;
 use tower::Service;
 use uuid::Uuid;
 
-lazy_static! {
-    static ref DOC_REQUEST: BytesFrame = BytesFrame::Array(vec![
-        BytesFrame::BulkString(Bytes::from_static(b"COMMAND")),
-        BytesFrame::BulkString(Bytes::from_static(b"DOCS")),
-    ]);
+use crate::error::{Error, bail};
+use crate::middleware::client_name::ClientName;
+use crate::middleware::redact::RedactionRules;
+use crate::service::ResponseStream;
+use crate::{command, frame};
+
+/// How `ProxyLogger` renders the traffic it observes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines rendering the frames, binary data escaped
+    #[default]
+    Text,
+    /// One JSON object per line, describing each frame without its contents
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!(Config, "Unrecognized log format '{s}'"),
+        }
+    }
+}
+
+/// Log one in `every` of the requests named `command` (or of every request), parsed from
+/// `COMMAND=N` (`*` for every command)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LogSampleRule {
+    command: Option<String>,
+    every: u64,
 }
 
-pub struct ProxyLoggerLayer<'conn> {
-    connection_id: &'conn str,
+impl LogSampleRule {
+    fn matches(&self, name: Option<&str>) -> bool {
+        match &self.command {
+            Some(command) => name == Some(command.as_str()),
+            None => true,
+        }
+    }
 }
-impl<'conn> ProxyLoggerLayer<'conn> {
-    pub fn new(connection_id: &'conn str) -> Self {
-        Self { connection_id }
+
+impl std::str::FromStr for LogSampleRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, every)) = s.split_once('=') else {
+            bail!(Config, "Log sampling rule '{s}' should be COMMAND=N");
+        };
+        let every: u64 = every
+            .trim()
+            .parse()
+            .map_err(|_| Error::Config(format!("Invalid count '{every}' in log sampling rule")))?;
+        if every == 0 {
+            bail!(
+                Config,
+                "Log sampling rule '{s}' must log one in at least 1 request"
+            );
+        }
+        let command = match command.trim() {
+            "*" => None,
+            command => Some(command.to_ascii_uppercase()),
+        };
+        Ok(Self { command, every })
     }
 }
 
-impl<'conn, S> Layer<S> for ProxyLoggerLayer<'conn> {
-    type Service = ProxyLogger<'conn, S>;
+impl TryFrom<String> for LogSampleRule {
+    type Error = Error;
 
-    fn layer(&self, service: S) -> Self::Service {
-        ProxyLogger::new(service, self.connection_id)
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
     }
 }
 
-pub struct ProxyLogger<'conn, S> {
-    resp2_service: S,
-    connection_id: &'conn str,
-    request_count: u64,
-    response_count: Arc<AtomicU64>,
+/// The level `ProxyLogger` logs a command's requests and replies at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandLogLevel {
+    /// Not at all
+    Off,
+    Trace,
+    Debug,
+    #[default]
+    Info,
+}
+
+impl CommandLogLevel {
+    fn level(self) -> Option<log::Level> {
+        match self {
+            CommandLogLevel::Off => None,
+            CommandLogLevel::Trace => Some(log::Level::Trace),
+            CommandLogLevel::Debug => Some(log::Level::Debug),
+            CommandLogLevel::Info => Some(log::Level::Info),
+        }
+    }
+}
+
+impl std::str::FromStr for CommandLogLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(CommandLogLevel::Off),
+            "trace" => Ok(CommandLogLevel::Trace),
+            "debug" => Ok(CommandLogLevel::Debug),
+            "info" => Ok(CommandLogLevel::Info),
+            _ => bail!(Config, "Unrecognized command log level '{s}'"),
+        }
+    }
+}
+
+/// Log the command `command` (`NAME` or `NAME|SUBCOMMAND`) at `level`, parsed from
+/// `COMMAND=LEVEL`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LogLevelRule {
+    command: String,
+    level: CommandLogLevel,
+}
+
+impl std::str::FromStr for LogLevelRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, level)) = s.split_once('=') else {
+            bail!(
+                Config,
+                "Command log level rule '{s}' should be COMMAND=LEVEL"
+            );
+        };
+        Ok(Self {
+            command: command.trim().to_ascii_uppercase(),
+            level: level.trim().parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for LogLevelRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The level `ProxyLogger` logs each command at: as set by the first `LogLevelRule` matching it,
+/// or `info`. The configured rules are followed by defaults quieting the commands clients
+/// issue routinely on connecting, whose replies are large: `COMMAND|DOCS=debug`.
+#[derive(Clone, Debug)]
+pub struct CommandLogLevels {
+    rules: Vec<LogLevelRule>,
+}
+
+impl Default for CommandLogLevels {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl CommandLogLevels {
+    pub fn new(rules: Vec<LogLevelRule>) -> Self {
+        let defaults = [LogLevelRule {
+            command: "COMMAND|DOCS".to_string(),
+            level: CommandLogLevel::Debug,
+        }];
+        Self {
+            rules: rules.into_iter().chain(defaults).collect(),
+        }
+    }
+
+    /// The level to log `req` at, if it's logged at all
+    fn level(&self, req: &BytesFrame) -> Option<log::Level> {
+        let Some(name) = command::name(req) else {
+            return CommandLogLevel::default().level();
+        };
+        let full_name = command::args(req)
+            .and_then(|args| args.get(1))
+            .and_then(command::arg_bytes)
+            .map(|sub| {
+                format!(
+                    "{name}|{}",
+                    String::from_utf8_lossy(sub).to_ascii_uppercase()
+                )
+            });
+        self.rules
+            .iter()
+            .find(|rule| rule.command == name || full_name.as_ref() == Some(&rule.command))
+            .map_or(CommandLogLevel::default(), |rule| rule.level)
+            .level()
+    }
+}
+
+/// Which requests `ProxyLogger` logs: one in so many of each command, as set by the first
+/// `LogSampleRule` matching it (or all of a command no rule matches). Requests left out are
+/// logged after all, along with their replies, if a reply is an error or comes slowly.
+#[derive(Clone, Debug, Default)]
+pub struct LogSampling {
+    rules: Vec<LogSampleRule>,
+    slow: Option<Duration>,
+}
+
+impl LogSampling {
+    pub fn new(rules: Vec<LogSampleRule>) -> Self {
+        Self { rules, slow: None }
+    }
+
+    /// Log requests whose replies take at least `threshold`, whether they're sampled or not
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow = Some(threshold);
+        self
+    }
+
+    /// Whether to log a request for the command `name` from the start
+    fn samples(&self, name: Option<&str>) -> bool {
+        match self.rules.iter().find(|rule| rule.matches(name)) {
+            Some(rule) if rule.every > 1 => rand::random::<f64>() * (rule.every as f64) < 1.0,
+            _ => true,
+        }
+    }
+
+    /// Whether a reply frame is worth logging even though its request wasn't sampled
+    fn is_notable(&self, frame: &BytesFrame, elapsed: Duration) -> bool {
+        matches!(frame, BytesFrame::Error(_)) || self.slow.is_some_and(|slow| elapsed >= slow)
+    }
+}
+
+/// How much of each frame `ProxyLogger` shows in text logs: at most `max_arg_bytes` of each
+/// string and `max_args` elements of each array, with a note of how much was left out. Only the
+/// log is cut short; capture, audit, and transcripts are written from the full frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogTruncation {
+    max_arg_bytes: Option<usize>,
+    max_args: Option<usize>,
+}
+
+impl LogTruncation {
+    pub fn with_max_arg_bytes(mut self, max_arg_bytes: usize) -> Self {
+        self.max_arg_bytes = Some(max_arg_bytes);
+        self
+    }
+
+    pub fn with_max_args(mut self, max_args: usize) -> Self {
+        self.max_args = Some(max_args);
+        self
+    }
+
+    /// How many of `len` arguments or elements are shown
+    fn shown(&self, len: usize) -> usize {
+        self.max_args.map_or(len, |max| max.min(len))
+    }
+
+    /// Write `s` to `line` quoted and escaped, followed by how many bytes were left out if it's
+    /// too long
+    fn quote(&self, s: &[u8], line: &mut BytesMut) {
+        let shown = self.max_arg_bytes.map_or(s.len(), |max| max.min(s.len()));
+        frame::quote(&s[..shown], line);
+        if shown < s.len() {
+            line.put_slice(format!("...({} more bytes)", s.len() - shown).as_bytes());
+        }
+    }
+}
+
+/// Deepest an array in a logged frame is nested before it's summarized by its length
+static MAX_RENDERED_DEPTH: usize = 2;
+
+/// `req` as `ProxyLogger` shows it: the command's name, then its arguments quoted and escaped,
+/// with its keys marked `key=`, e.g. `SET key="user:1" "\x00\x01"`
+fn render_request(req: &BytesFrame, truncation: &LogTruncation) -> String {
+    let (Some(args), Some(name)) = (command::args(req), command::name(req)) else {
+        return render_reply(req, truncation);
+    };
+    let keys = command::key_indices(req);
+    let mut line = BytesMut::new();
+    frame::escape(name.as_bytes(), &mut line);
+    let shown = truncation.shown(args.len()).max(1);
+    for (i, arg) in args.iter().enumerate().take(shown).skip(1) {
+        line.put_u8(b' ');
+        if keys.contains(&i) {
+            line.put_slice(b"key=");
+        }
+        match command::arg_bytes(arg) {
+            Some(arg) => truncation.quote(arg, &mut line),
+            None => render_frame(arg, truncation, 1, &mut line),
+        }
+    }
+    if shown < args.len() {
+        line.put_slice(format!(" ...({} more args)", args.len() - shown).as_bytes());
+    }
+    String::from_utf8_lossy(&line).into_owned()
 }
 
-impl<'conn, S> ProxyLogger<'conn, S> {
-    fn new(resp2_service: S, connection_id: &'conn str) -> Self {
+/// A reply as `ProxyLogger` shows it, in the style of `redis-cli`: bulk strings quoted and
+/// escaped, other types labelled, and arrays nested more than `MAX_RENDERED_DEPTH` deep
+/// summarized by their length
+fn render_reply(frame: &BytesFrame, truncation: &LogTruncation) -> String {
+    let mut line = BytesMut::new();
+    render_frame(frame, truncation, 0, &mut line);
+    String::from_utf8_lossy(&line).into_owned()
+}
+
+fn render_frame(frame: &BytesFrame, truncation: &LogTruncation, depth: usize, line: &mut BytesMut) {
+    match frame {
+        BytesFrame::SimpleString(s) => frame::escape(s, line),
+        BytesFrame::Error(e) => {
+            line.put_slice(b"(error) ");
+            frame::escape(e.as_bytes(), line);
+        }
+        BytesFrame::Integer(i) => line.put_slice(format!("(integer) {i}").as_bytes()),
+        BytesFrame::BulkString(s) => truncation.quote(s, line),
+        BytesFrame::Null => line.put_slice(b"(nil)"),
+        BytesFrame::Array(frames) if frames.is_empty() => line.put_slice(b"(empty array)"),
+        BytesFrame::Array(frames) if depth >= MAX_RENDERED_DEPTH => {
+            line.put_slice(format!("({} elements)", frames.len()).as_bytes());
+        }
+        BytesFrame::Array(frames) => {
+            let shown = truncation.shown(frames.len());
+            line.put_u8(b'[');
+            for (i, frame) in frames[..shown].iter().enumerate() {
+                if i > 0 {
+                    line.put_slice(b", ");
+                }
+                render_frame(frame, truncation, depth + 1, line);
+            }
+            if shown < frames.len() {
+                if shown > 0 {
+                    line.put_slice(b", ");
+                }
+                line.put_slice(format!("...({} more elements)", frames.len() - shown).as_bytes());
+            }
+            line.put_u8(b']');
+        }
+    }
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+pub struct ProxyLoggerLayer {
+    connection_id: String,
+    format: LogFormat,
+    redaction: Arc<RedactionRules>,
+    client_name: ClientName,
+    sampling: Arc<LogSampling>,
+    truncation: LogTruncation,
+    levels: Arc<CommandLogLevels>,
+}
+impl ProxyLoggerLayer {
+    pub fn new(connection_id: impl Into<String>) -> Self {
         Self {
-            resp2_service,
-            connection_id,
-            request_count: 0,
+            connection_id: connection_id.into(),
+            format: LogFormat::default(),
+            redaction: Arc::new(RedactionRules::default()),
+            client_name: ClientName::new(),
+            sampling: Arc::new(LogSampling::default()),
+            truncation: LogTruncation::default(),
+            levels: Arc::new(CommandLogLevels::default()),
+        }
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Mask sensitive values according to `redaction` rather than the default rules
+    pub fn with_redaction(mut self, redaction: impl Into<Arc<RedactionRules>>) -> Self {
+        self.redaction = redaction.into();
+        self
+    }
+
+    /// Label traffic with the name the client has set, as tracked by `ClientNameLayer`
+    pub fn with_client_name(mut self, client_name: ClientName) -> Self {
+        self.client_name = client_name;
+        self
+    }
+
+    /// Only log the requests (and replies) picked by `sampling`, rather than every one
+    pub fn with_sampling(mut self, sampling: impl Into<Arc<LogSampling>>) -> Self {
+        self.sampling = sampling.into();
+        self
+    }
+
+    /// Cut long frames short in text logs according to `truncation`
+    pub fn with_truncation(mut self, truncation: LogTruncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Log each command at the level set by `levels`, rather than the defaults
+    pub fn with_command_levels(mut self, levels: impl Into<Arc<CommandLogLevels>>) -> Self {
+        self.levels = levels.into();
+        self
+    }
+}
+
+impl<S> Layer<S> for ProxyLoggerLayer {
+    type Service = ProxyLogger<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ProxyLogger {
+            resp2_service: service,
+            connection_id: self.connection_id.clone(),
+            format: self.format,
+            redaction: self.redaction.clone(),
+            client_name: self.client_name.clone(),
+            sampling: self.sampling.clone(),
+            truncation: self.truncation,
+            levels: self.levels.clone(),
+            request_count: Arc::new(AtomicU64::new(0)),
             response_count: Arc::new(AtomicU64::new(0)),
         }
     }
 }
 
-impl<'conn, S> Service<BytesFrame> for ProxyLogger<'conn, S>
+/// Clones share their request and reply counts, so a connection's requests are numbered in order
+/// however many clones (behind a `tower::buffer::Buffer`, say) they're spread across
+#[derive(Clone)]
+pub struct ProxyLogger<S> {
+    resp2_service: S,
+    connection_id: String,
+    format: LogFormat,
+    redaction: Arc<RedactionRules>,
+    client_name: ClientName,
+    sampling: Arc<LogSampling>,
+    truncation: LogTruncation,
+    levels: Arc<CommandLogLevels>,
+    request_count: Arc<AtomicU64>,
+    response_count: Arc<AtomicU64>,
+}
+
+impl<S> Service<BytesFrame> for ProxyLogger<S>
 where
     S: Service<BytesFrame>,
     S::Response: Stream<Item = BytesFrame> + Send + 'static,
-    S::Error: Into<anyhow::Error> + 'static,
+    S::Error: Into<Error> + 'static,
     S::Future: Send + 'static,
 {
-    type Response = Box<dyn Stream<Item = BytesFrame> + Send>;
-    type Error = anyhow::Error;
+    type Response = ResponseStream;
+    type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -73,49 +525,202 @@ where
     }
 
     fn call(&mut self, req: BytesFrame) -> Self::Future {
-        self.request_count += 1;
-        let req_num = self.request_count;
-        let command_id = Uuid::new_v4();
-
-        let is_doc_command = req == *DOC_REQUEST;
-        log::info!(
-            "Client -> Target: conn={} req#{} cmd={} - {:?}",
-            self.connection_id,
+        let req_num = self.request_count.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        let started = Instant::now();
+        let client_name = self.client_name.get();
+        let request = LoggedRequest {
+            format: self.format,
+            level: self.levels.level(&req),
+            truncation: self.truncation,
+            conn: match &client_name {
+                Some(name) => format!("{} name={name}", self.connection_id),
+                None => self.connection_id.clone(),
+            },
+            connection_id: self.connection_id.clone(),
+            client_name,
             req_num,
-            command_id,
-            req
-        );
+            command_id: Uuid::new_v4(),
+            command_name: command::name(&req),
+            slot: command::first_key(&req).map(|key| redis_protocol::redis_keyslot(key)),
+            size: frame::encoded_len(&req),
+        };
+        let mask_reply = self.redaction.masks_reply(&req);
+        // A request left out by sampling is held back, in case a reply shows it's worth logging
+        let mut held = if request.level.is_none() {
+            None
+        } else {
+            let logged_req = self.redaction.request(&req);
+            if self.sampling.samples(request.command_name.as_deref()) {
+                request.log(&logged_req);
+                None
+            } else {
+                Some(logged_req.into_owned())
+            }
+        };
 
         let fut = self.resp2_service.call(req);
 
-        let conn_id = self.connection_id.to_string();
+        let sampling = self.sampling.clone();
         let resp_count = self.response_count.clone();
         Box::pin(
             fut.map_ok(move |stream| {
                 let logged = stream.inspect(move |frame| {
                     let n = resp_count.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+                    let Some(level) = request.level else {
+                        return;
+                    };
+                    let elapsed = started.elapsed();
+                    if held.is_some() {
+                        if !sampling.is_notable(frame, elapsed) {
+                            return;
+                        }
+                        if let Some(req) = held.take() {
+                            request.log(&req);
+                        }
+                    }
 
-                    if is_doc_command {
-                        log::info!(
-                            "Target -> Client: conn={} resp#{} cmd={} - docs",
-                            conn_id,
+                    if request.format == LogFormat::Json {
+                        log::log!(
+                            level,
+                            "{}",
+                            json!({
+                                "ts": unix_millis(),
+                                "direction": "target_to_client",
+                                "connection_id": request.connection_id,
+                                "client_name": request.client_name,
+                                "resp_num": n,
+                                "req_num": request.req_num,
+                                "command_id": request.command_id.to_string(),
+                                "command": request.command_name,
+                                "slot": request.slot,
+                                "size": frame::encoded_len(frame),
+                                "error": matches!(frame, BytesFrame::Error(_)),
+                                "elapsed_us": elapsed.as_micros() as u64,
+                            })
+                        );
+                    } else if mask_reply {
+                        log::log!(
+                            level,
+                            "Target -> Client: conn={} resp#{} cmd={} - {}",
+                            request.conn,
                             n,
-                            command_id
+                            request.command_id,
+                            render_reply(&redact::reply(frame), &request.truncation)
                         );
                     } else {
-                        log::info!(
-                            "Target -> Client: conn={} resp#{} cmd={} - {:?}",
-                            conn_id,
+                        log::log!(
+                            level,
+                            "Target -> Client: conn={} resp#{} cmd={} - {}",
+                            request.conn,
                             n,
-                            command_id,
-                            frame
+                            request.command_id,
+                            render_reply(frame, &request.truncation)
                         );
                     }
                 });
 
-                Box::new(logged) as Self::Response
+                logged.boxed()
             })
             .map_err(Into::into),
         )
     }
 }
+
+/// What `ProxyLogger` logs of a request besides the request itself, kept for its replies
+struct LoggedRequest {
+    format: LogFormat,
+    /// What to log the request and its replies at, if anything
+    level: Option<log::Level>,
+    truncation: LogTruncation,
+    /// The connection, as named in text logs
+    conn: String,
+    connection_id: String,
+    client_name: Option<String>,
+    req_num: u64,
+    command_id: Uuid,
+    command_name: Option<String>,
+    /// The Redis Cluster slot of the request's first key
+    slot: Option<u16>,
+    /// Size of the request as received, RESP encoded
+    size: usize,
+}
+
+impl LoggedRequest {
+    /// Log the request, as `req` once redacted
+    fn log(&self, req: &BytesFrame) {
+        let Some(level) = self.level else {
+            return;
+        };
+        match self.format {
+            LogFormat::Text => log::log!(
+                level,
+                "Client -> Target: conn={} req#{} cmd={} - {}",
+                self.conn,
+                self.req_num,
+                self.command_id,
+                render_request(req, &self.truncation)
+            ),
+            LogFormat::Json => log::log!(
+                level,
+                "{}",
+                json!({
+                    "ts": unix_millis(),
+                    "direction": "client_to_target",
+                    "connection_id": self.connection_id,
+                    "client_name": self.client_name,
+                    "req_num": self.req_num,
+                    "command_id": self.command_id.to_string(),
+                    "command": self.command_name,
+                    "key": command::first_key(req).map(|k| String::from_utf8_lossy(k)),
+                    "slot": self.slot,
+                    "size": self.size,
+                })
+            ),
+        }
+    }
+}
+
+/// A response stream carrying a single frame, for replies produced by the proxy itself
+pub fn reply(frame: BytesFrame) -> ResponseStream {
+    futures::stream::once(async move { frame }).boxed()
+}
+
+/// A response stream which runs a callback once it has yielded its final frame (or is dropped
+/// before doing so)
+pub struct OnComplete {
+    inner: ResponseStream,
+    on_complete: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl OnComplete {
+    pub fn new(inner: ResponseStream, on_complete: impl FnOnce() + Send + 'static) -> Self {
+        Self {
+            inner,
+            on_complete: Some(Box::new(on_complete)),
+        }
+    }
+
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete()
+        }
+    }
+}
+
+impl Stream for OnComplete {
+    type Item = BytesFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(None) = next {
+            self.complete();
+        }
+        next
+    }
+}
+
+impl Drop for OnComplete {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
@@ -0,0 +1,303 @@
+//! Per-client access control.
+//!
+//! `AclLayer` restricts the commands a client may run, and the keys those commands may touch,
+//! according to the first `AclRule` matching the client. Clients are identified by the address
+//! they connected from (an IP address or CIDR block, or `unix` for Unix domain sockets), or by
+//! the user they authenticated as (`user:NAME`), whether to the proxy (`ProxyAuthLayer`) or by
+//! `AUTH` and `HELLO ... AUTH` requests the target accepts; `*` matches every client. Refused
+//! commands are answered with `NOPERM` errors, as Redis answers its own ACL failures.
+//!
+//! A rule's commands are allowed and denied as by `CommandRules`, and when it lists key globs
+//! (as taken by `KEYS`), every key of a command must match one of them. Clients matching no
+//! rule are unrestricted, so a trailing `*` rule makes the list deny-by-default. `AUTH` and
+//! `HELLO` are always forwarded, so a client can authenticate its way into a rule.
+//!
+//! TLS client certificates can't identify clients, since the proxy doesn't terminate TLS.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, bail};
+use crate::listener::{ClientAddr, IpNetwork};
+use crate::middleware::auth::{ClientUser, DEFAULT_USER};
+use crate::middleware::filter::CommandRules;
+use crate::middleware::reply;
+use crate::routing::glob_matches;
+use crate::service::ResponseStream;
+
+/// Which clients an `AclRule` applies to, parsed from `*`, `unix`, `user:NAME`, an IP address,
+/// or a CIDR block (`10.0.0.0/8`)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ClientMatch {
+    Any,
+    Unix,
+    Network(IpNetwork),
+    User(String),
+}
+
+impl ClientMatch {
+    fn matches(&self, client_addr: Option<&ClientAddr>, user: Option<&str>) -> bool {
+        match self {
+            Self::Any => true,
+            #[cfg(unix)]
+            Self::Unix => matches!(client_addr, Some(ClientAddr::Unix(_))),
+            #[cfg(not(unix))]
+            Self::Unix => false,
+            Self::Network(network) => match client_addr {
+                Some(ClientAddr::Tcp(addr)) => network.contains(addr.ip()),
+                _ => false,
+            },
+            Self::User(name) => user == Some(name.as_str()),
+        }
+    }
+}
+
+impl std::str::FromStr for ClientMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        if s.eq_ignore_ascii_case("unix") {
+            return Ok(Self::Unix);
+        }
+        if let Some(name) = s.strip_prefix("user:") {
+            if name.is_empty() {
+                bail!(Config, "ACL client '{s}' needs a user name");
+            }
+            return Ok(Self::User(name.to_string()));
+        }
+        if s.starts_with("cn:") {
+            bail!(
+                Config,
+                "ACL client '{s}' can't be matched: the proxy doesn't terminate TLS"
+            );
+        }
+        let network = s.parse().map_err(|_| {
+            Error::Config(format!(
+                "ACL client '{s}' should be *, unix, user:NAME, an IP address, or a CIDR block"
+            ))
+        })?;
+        Ok(Self::Network(network))
+    }
+}
+
+impl TryFrom<String> for ClientMatch {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// What the clients matching `client` may do
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    pub client: ClientMatch,
+    /// Only allow these commands (NAME or NAME|SUBCOMMAND)
+    #[serde(default)]
+    pub allow_commands: Vec<String>,
+    /// Refuse these commands (NAME or NAME|SUBCOMMAND)
+    #[serde(default)]
+    pub deny_commands: Vec<String>,
+    /// Only allow commands whose keys all match one of these globs. Commands whose keys can't be
+    /// found among their arguments are refused.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// An `AclRule` ready for checking commands against
+#[derive(Debug)]
+struct CompiledRule {
+    client: ClientMatch,
+    commands: CommandRules,
+    keys: Vec<Vec<u8>>,
+}
+
+/// The ACL rules shared by every connection, in order
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<CompiledRule>,
+}
+
+impl Acl {
+    pub fn new(rules: &[AclRule]) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let commands = CommandRules::default().deny(&rule.deny_commands);
+                CompiledRule {
+                    client: rule.client.clone(),
+                    commands: if rule.allow_commands.is_empty() {
+                        commands
+                    } else {
+                        commands.allow(&rule.allow_commands)
+                    },
+                    keys: rule.keys.iter().map(|k| k.as_bytes().to_vec()).collect(),
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the client may send `req`, returning the `NOPERM` error refusing it if not
+    fn check(
+        &self,
+        client_addr: Option<&ClientAddr>,
+        user: Option<&str>,
+        req: &BytesFrame,
+    ) -> Result<(), String> {
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.client.matches(client_addr, user))
+        else {
+            return Ok(());
+        };
+        if let Err(name) = rule.commands.check(req) {
+            return Err(format!(
+                "NOPERM this client has no permissions to run the '{}' command",
+                name.to_lowercase()
+            ));
+        }
+        if rule.keys.is_empty() {
+            return Ok(());
+        }
+        let Some(keys) = command::known_keys(req) else {
+            return Err(format!(
+                "NOPERM this client may only run commands whose keys can be checked, unlike '{}'",
+                command::name(req).unwrap_or_default().to_lowercase()
+            ));
+        };
+        let denied = keys
+            .into_iter()
+            .find(|key| !rule.keys.iter().any(|glob| glob_matches(glob, key)));
+        match denied {
+            Some(key) => Err(format!(
+                "NOPERM this client has no permissions to access the '{}' key",
+                String::from_utf8_lossy(key)
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The user a request authenticates as, if it's an `AUTH` or a `HELLO` with `AUTH`
+fn authenticates_as(req: &BytesFrame) -> Option<String> {
+    let args = command::args(req)?;
+    let user = match (command::name(req)?.as_str(), args) {
+        ("AUTH", [_, _password]) => return Some(DEFAULT_USER.to_string()),
+        ("AUTH", [_, user, _password]) => user,
+        ("HELLO", [_, _version, auth, user, _password, ..])
+            if command::arg_bytes(auth)?.eq_ignore_ascii_case(b"AUTH") =>
+        {
+            user
+        }
+        _ => return None,
+    };
+    Some(String::from_utf8_lossy(command::arg_bytes(user)?).into_owned())
+}
+
+pub struct AclLayer {
+    acl: Arc<Acl>,
+    client_addr: Option<ClientAddr>,
+    user: ClientUser,
+}
+
+impl AclLayer {
+    /// Check the commands of the client connected from `client_addr` (`None` for frontends
+    /// without one, which only `*` and `user:` rules match), and authenticated as `user`,
+    /// against `acl`
+    pub fn new(acl: Arc<Acl>, client_addr: Option<ClientAddr>, user: ClientUser) -> Self {
+        Self {
+            acl,
+            client_addr,
+            user,
+        }
+    }
+}
+
+impl<S> Layer<S> for AclLayer {
+    type Service = AccessControl<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AccessControl {
+            inner: service,
+            acl: self.acl.clone(),
+            client_addr: self.client_addr.clone(),
+            user: self.user.clone(),
+        }
+    }
+}
+
+pub struct AccessControl<S> {
+    inner: S,
+    acl: Arc<Acl>,
+    client_addr: Option<ClientAddr>,
+    user: ClientUser,
+}
+
+impl<S> Service<BytesFrame> for AccessControl<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if let Some(authenticated) = authenticates_as(&req) {
+            let user = self.user.clone();
+            let fut = self.inner.call(req).map_err(Into::into);
+            return Box::pin(async move {
+                let mut first = true;
+                Ok(fut
+                    .await?
+                    .inspect(move |frame| {
+                        if std::mem::take(&mut first) && !matches!(frame, BytesFrame::Error(_)) {
+                            user.set(authenticated.clone());
+                        }
+                    })
+                    .boxed())
+            });
+        }
+        let user = self.user.get();
+        if let Err(message) = self
+            .acl
+            .check(self.client_addr.as_ref(), user.as_deref(), &req)
+        {
+            log::debug!(
+                "Refused command from {} by ACL: {message}",
+                self.client_addr
+                    .as_ref()
+                    .map_or("a client".to_string(), ToString::to_string)
+            );
+            return Box::pin(async move { Ok(reply(command::error(message))) });
+        }
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
@@ -0,0 +1,903 @@
+//! Proxy administration over RESP.
+//!
+//! `AdminLayer` answers the `CABBAGE.*` command family itself instead of forwarding it, so a
+//! running proxy can be inspected with `redis-cli` pointed at its client address:
+//!
+//! - `CABBAGE.INFO`: an `INFO`-style summary of the proxy
+//! - `CABBAGE.CONNECTIONS`: a `CLIENT LIST`-style line per connected client
+//! - `CABBAGE.KILL ID <id> | ADDR <addr>`: close client connections at once, along with their
+//!   target connections, answering with how many were closed
+//! - `CABBAGE.SLOWLOG GET [count] | LEN | RESET`: the proxy's slowlog, shaped like Redis's
+//! - `CABBAGE.LATENCY`: per-command latency percentiles, in microseconds
+//! - `CABBAGE.TOPCOMMANDS [count]`: the most called commands with their error and latency totals
+//! - `CABBAGE.HOTKEYS [count]`: the hottest sampled keys with their counts and error bounds
+//! - `CABBAGE.KEYSPACE [count]`: the most used key namespaces among the keys sampled
+//! - `CABBAGE.KEYSLOTS [count]`: the cluster slots most used among the keys sampled
+//! - `CABBAGE.KEYSLOT <key>`: the cluster slot `key` hashes to, as `CLUSTER KEYSLOT` answers
+//! - `CABBAGE.CANARY [count]`: the most recent canary mismatches, one line each
+//! - `CABBAGE.RELOAD`: reload the configuration, when the proxy supports it
+//! - `CABBAGE.FAULTS [ON | OFF | CLEAR | ADD rule]`: show or change error injection, when the
+//!   proxy supports it
+//! - `CABBAGE.READONLY [ON | OFF]`: show whether writes are refused with `-READONLY`, or switch
+//!   it, when the proxy supports it
+//! - `CABBAGE.MAINTENANCE [ON [error] | OFF]`: show whether commands are answered with an error
+//!   instead of being forwarded, or switch it (with a new error), when the proxy supports it
+//! - `CABBAGE.DRAIN`: shut the proxy down gracefully, as on SIGTERM, when the proxy supports it:
+//!   it stops accepting connections, and closes each open one once it has answered the commands
+//!   already read (or the drain timeout passes), so a load balancer moves clients elsewhere
+//! - `CABBAGE.HAIKU [ALL]`: a random haiku, or every one, handy for checking the proxy answers
+//!   commands itself
+//! - `CABBAGE.HELP`
+//!
+//! These commands go through `CommandFilterLayer` like any other, so they can be denied there.
+//!
+//! With `with_info_section`, `INFO` is still forwarded to the target, but a `# Cabbage` section
+//! of the proxy's own statistics (its version, connections, command totals, and view of the
+//! target's health) is appended to the target's reply whenever the default sections are asked
+//! for (or `cabbage` is), so monitoring agents which already scrape `INFO` pick it up too.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use rand::seq::SliceRandom as _;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::Notify;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tokio_util::sync::CancellationToken;
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::HAIKUS;
+use crate::command;
+use crate::error::Error;
+use crate::middleware::chaos::{ErrorInjection, ErrorRule};
+use crate::middleware::maintenance::Maintenance;
+use crate::middleware::read_only::ReadOnlyMode;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::{QUEUES, RuntimeSnapshot, Stats};
+
+static PREFIX: &str = "CABBAGE.";
+/// Entries returned by `SLOWLOG GET` and `HOTKEYS` without a count, as with Redis's `SLOWLOG`
+static DEFAULT_COUNT: usize = 10;
+
+static HELP: &[&str] = &[
+    "CABBAGE.<subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "INFO",
+    "    Return a summary of the proxy's state.",
+    "CONNECTIONS",
+    "    Return information about connected clients, one per line.",
+    "KILL ID <id> | ADDR <addr>",
+    "    Close the client connections with the given ID or address, returning how many.",
+    "SLOWLOG GET [<count>] | LEN | RESET",
+    "    Return, count, or clear the proxy's slowlog entries.",
+    "LATENCY",
+    "    Return latency percentiles (in microseconds) for each command seen.",
+    "TOPCOMMANDS [<count>]",
+    "    Return the most called commands with their errors and total latency (in microseconds).",
+    "HOTKEYS [<count>]",
+    "    Return the most frequently accessed keys among those sampled.",
+    "KEYSPACE [<count>]",
+    "    Return the key namespaces (prefixes up to ':') most used among the keys sampled.",
+    "KEYSLOTS [<count>]",
+    "    Return the cluster slots most used among the keys sampled.",
+    "KEYSLOT <key>",
+    "    Return the cluster slot of the key.",
+    "CANARY [<count>]",
+    "    Return the most recent differences between the canary's and the primary's replies.",
+    "RELOAD",
+    "    Reload the proxy's configuration.",
+    "FAULTS [ON | OFF | CLEAR | ADD <COMMAND=PERCENT:FAULT>]",
+    "    Return the error injection rules and whether they're applied, or change them.",
+    "READONLY [ON | OFF]",
+    "    Return whether writes are refused, or switch refusing them on or off.",
+    "MAINTENANCE [ON [<error>] | OFF]",
+    "    Return whether commands are answered with an error rather than forwarded, or switch it.",
+    "DRAIN",
+    "    Stop accepting connections, and close each open one once its commands are answered.",
+    "HAIKU [ALL]",
+    "    Return a random haiku, or every haiku.",
+    "HELP",
+    "    Print this help.",
+];
+
+pub struct AdminLayer {
+    stats: Arc<Stats>,
+    reload: Option<Arc<Notify>>,
+    faults: Option<Arc<ErrorInjection>>,
+    read_only: Option<Arc<ReadOnlyMode>>,
+    maintenance: Option<Arc<Maintenance>>,
+    drain: Option<CancellationToken>,
+    info_section: bool,
+}
+
+impl AdminLayer {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self {
+            stats,
+            reload: None,
+            faults: None,
+            read_only: None,
+            maintenance: None,
+            drain: None,
+            info_section: false,
+        }
+    }
+
+    /// Answer `CABBAGE.RELOAD` by notifying `reload`
+    pub fn with_reload(mut self, reload: Arc<Notify>) -> Self {
+        self.reload = Some(reload);
+        self
+    }
+
+    /// Answer `CABBAGE.FAULTS` by showing or changing `faults`
+    pub fn with_faults(mut self, faults: Arc<ErrorInjection>) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Answer `CABBAGE.READONLY` by showing or switching `read_only`
+    pub fn with_read_only(mut self, read_only: Arc<ReadOnlyMode>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Answer `CABBAGE.MAINTENANCE` by showing or switching `maintenance`
+    pub fn with_maintenance(mut self, maintenance: Arc<Maintenance>) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Answer `CABBAGE.DRAIN` by cancelling `drain`, the token which shuts the proxy down
+    pub fn with_drain(mut self, drain: CancellationToken) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Append a `# Cabbage` section to the target's replies to `INFO`
+    pub fn with_info_section(mut self) -> Self {
+        self.info_section = true;
+        self
+    }
+}
+
+impl<S> Layer<S> for AdminLayer {
+    type Service = Admin<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Admin {
+            inner: service,
+            stats: self.stats.clone(),
+            reload: self.reload.clone(),
+            faults: self.faults.clone(),
+            read_only: self.read_only.clone(),
+            maintenance: self.maintenance.clone(),
+            drain: self.drain.clone(),
+            info_section: self.info_section,
+        }
+    }
+}
+
+pub struct Admin<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    reload: Option<Arc<Notify>>,
+    faults: Option<Arc<ErrorInjection>>,
+    read_only: Option<Arc<ReadOnlyMode>>,
+    maintenance: Option<Arc<Maintenance>>,
+    drain: Option<CancellationToken>,
+    info_section: bool,
+}
+
+impl<S> Service<BytesFrame> for Admin<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let subcommand =
+            command::name(&req).and_then(|name| name.strip_prefix(PREFIX).map(str::to_string));
+        let Some(subcommand) = subcommand else {
+            if self.info_section && shows_info_section(&req) {
+                let section = self.info_section();
+                let fut = self.inner.call(req).map_err(Into::into);
+                return Box::pin(async move {
+                    Ok(fut
+                        .await?
+                        .map(move |frame| append_section(frame, &section))
+                        .boxed())
+                });
+            }
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+
+        let args = command::args(&req).map_or(&[][..], |args| &args[1..]);
+        let answer = self.answer(&subcommand, args);
+        Box::pin(futures::future::ready(Ok(reply(answer))))
+    }
+}
+
+impl<S> Admin<S> {
+    fn answer(&self, subcommand: &str, args: &[BytesFrame]) -> BytesFrame {
+        match (subcommand, args) {
+            ("INFO", []) => bulk(self.info()),
+            ("CONNECTIONS", []) => bulk(self.connections()),
+            ("KILL", [filter, value]) => self.kill(subcommand, filter, value),
+            ("SLOWLOG", [sub, rest @ ..]) => match (upper(sub).as_deref(), rest) {
+                (Some("GET"), [] | [_]) => match rest.first().map(count).transpose() {
+                    Ok(n) => self.slowlog(n.unwrap_or(DEFAULT_COUNT)),
+                    Err(e) => e,
+                },
+                (Some("LEN"), []) => BytesFrame::Integer(self.stats.slowlog.len() as i64),
+                (Some("RESET"), []) => {
+                    self.stats.slowlog.reset();
+                    ok()
+                }
+                _ => unknown_subcommand(subcommand, sub),
+            },
+            ("LATENCY", []) => self.latency(),
+            ("TOPCOMMANDS", [] | [_]) => match args.first().map(count).transpose() {
+                Ok(n) => self.top_commands(n.unwrap_or(DEFAULT_COUNT)),
+                Err(e) => e,
+            },
+            ("HOTKEYS", [] | [_]) => match args.first().map(count).transpose() {
+                Ok(n) => self.hot_keys(n.unwrap_or(DEFAULT_COUNT)),
+                Err(e) => e,
+            },
+            ("KEYSPACE", [] | [_]) => match args.first().map(count).transpose() {
+                Ok(n) => self.key_space(n.unwrap_or(DEFAULT_COUNT)),
+                Err(e) => e,
+            },
+            ("KEYSLOTS", [] | [_]) => match args.first().map(count).transpose() {
+                Ok(n) => self.key_slots(n.unwrap_or(DEFAULT_COUNT)),
+                Err(e) => e,
+            },
+            ("KEYSLOT", [key]) => match command::arg_bytes(key) {
+                Some(key) => BytesFrame::Integer(redis_protocol::redis_keyslot(key).into()),
+                None => command::error("ERR invalid key"),
+            },
+            ("CANARY", [] | [_]) => match args.first().map(count).transpose() {
+                Ok(n) => BytesFrame::Array(
+                    self.stats
+                        .canary
+                        .mismatches(n.unwrap_or(DEFAULT_COUNT))
+                        .iter()
+                        .map(|m| bulk(m.description()))
+                        .collect(),
+                ),
+                Err(e) => e,
+            },
+            ("RELOAD", []) => match &self.reload {
+                Some(reload) => {
+                    log::info!("Configuration reload requested by CABBAGE.RELOAD");
+                    reload.notify_one();
+                    ok()
+                }
+                None => command::error("ERR configuration reload is not supported by this proxy"),
+            },
+            ("FAULTS", [] | [_] | [_, _]) => match &self.faults {
+                Some(faults) => self.faults(faults, subcommand, args),
+                None => command::error("ERR fault injection is not supported by this proxy"),
+            },
+            ("READONLY", [] | [_]) => match &self.read_only {
+                Some(read_only) => self.read_only(read_only, subcommand, args),
+                None => command::error("ERR read-only mode is not supported by this proxy"),
+            },
+            ("MAINTENANCE", [] | [_] | [_, _]) => match &self.maintenance {
+                Some(maintenance) => self.maintenance(maintenance, subcommand, args),
+                None => command::error("ERR maintenance mode is not supported by this proxy"),
+            },
+            ("DRAIN", []) => match &self.drain {
+                Some(drain) => {
+                    log::info!("Draining connections, as asked by CABBAGE.DRAIN");
+                    drain.cancel();
+                    ok()
+                }
+                None => command::error("ERR draining is not supported by this proxy"),
+            },
+            ("HAIKU", []) => {
+                let haiku = HAIKUS.choose(&mut rand::thread_rng()).unwrap_or(&HAIKUS[0]);
+                bulk(haiku.join("\n"))
+            }
+            ("HAIKU", [all]) => match upper(all).as_deref() {
+                Some("ALL") => {
+                    BytesFrame::Array(HAIKUS.iter().map(|h| bulk(h.join("\n"))).collect())
+                }
+                _ => unknown_subcommand(subcommand, all),
+            },
+            ("HELP", []) => BytesFrame::Array(
+                HELP.iter()
+                    .map(|line| BytesFrame::SimpleString(Bytes::from_static(line.as_bytes())))
+                    .collect(),
+            ),
+            (
+                "INFO" | "CONNECTIONS" | "KILL" | "SLOWLOG" | "LATENCY" | "TOPCOMMANDS" | "HOTKEYS"
+                | "KEYSPACE" | "KEYSLOTS" | "KEYSLOT" | "CANARY" | "RELOAD" | "FAULTS" | "READONLY"
+                | "MAINTENANCE" | "DRAIN" | "HAIKU" | "HELP",
+                _,
+            ) => command::error(format!(
+                "ERR wrong number of arguments for 'cabbage.{}' command",
+                subcommand.to_lowercase()
+            )),
+            _ => command::error(format!(
+                "ERR unknown command 'cabbage.{}', try CABBAGE.HELP",
+                subcommand.to_lowercase()
+            )),
+        }
+    }
+
+    fn info(&self) -> String {
+        let stats = &self.stats;
+        let commands: u64 = stats.latency.summary().iter().map(|l| l.count).sum();
+        let traffic = stats.connections.traffic();
+        let mut info = vec![
+            "# Server".to_string(),
+            format!("cabbage_version:{}", env!("CARGO_PKG_VERSION")),
+            format!("uptime_in_seconds:{}", stats.started.elapsed().as_secs()),
+            String::new(),
+            "# Clients".to_string(),
+            format!("connected_clients:{}", stats.connections.active()),
+            format!(
+                "total_connections_received:{}",
+                stats.connections.accepted()
+            ),
+            format!("rejected_connections:{}", stats.connections.rejected()),
+            format!("refused_connections:{}", stats.connections.refused()),
+            String::new(),
+            "# Stats".to_string(),
+            format!("total_commands_processed:{commands}"),
+            format!("total_client_commands:{}", traffic.commands()),
+            format!("total_client_responses:{}", traffic.responses()),
+            format!("total_net_input_bytes:{}", traffic.bytes_in()),
+            format!("total_net_output_bytes:{}", traffic.bytes_out()),
+            format!(
+                "client_output_buffer_limit_disconnections:{}",
+                traffic.output_limit_disconnections()
+            ),
+            format!(
+                "client_output_buffer_dropped_replies:{}",
+                traffic.dropped_replies()
+            ),
+            format!("slowlog_len:{}", stats.slowlog.len()),
+            String::new(),
+            "# Commandstats".to_string(),
+        ];
+        info.extend(command_stats(stats, "cmdstat_"));
+        info.extend([
+            String::new(),
+            "# Target".to_string(),
+            format!("target_timeouts:{}", stats.backend.timeouts()),
+            format!("target_suspect:{}", u8::from(stats.backend.is_suspect())),
+            format!("target_healthy:{}", u8::from(stats.backend.is_healthy())),
+            format!("target_health_checks:{}", stats.backend.health_checks()),
+            format!(
+                "target_failed_health_checks:{}",
+                stats.backend.failed_health_checks()
+            ),
+            String::new(),
+        ]);
+        if stats.mirror.sent() > 0 {
+            info.extend([
+                "# Mirror".to_string(),
+                format!("mirror_replied:{}", stats.mirror.replied()),
+                format!("mirror_errors:{}", stats.mirror.errors()),
+                format!("mirror_failed:{}", stats.mirror.failed()),
+                format!("mirror_dropped:{}", stats.mirror.dropped()),
+                String::new(),
+            ]);
+        }
+        let canary = &stats.canary;
+        if canary.compared() > 0 || canary.dropped() > 0 {
+            info.extend([
+                "# Canary".to_string(),
+                format!("canary_compared:{}", canary.compared()),
+                format!("canary_mismatched:{}", canary.mismatched()),
+                format!("canary_dropped:{}", canary.dropped()),
+                String::new(),
+            ]);
+        }
+        let cache = &stats.cache;
+        if cache.lookups() > 0 {
+            info.extend([
+                "# Cache".to_string(),
+                format!("cache_hits:{}", cache.hits()),
+                format!("cache_misses:{}", cache.misses()),
+                format!("cache_invalidations:{}", cache.invalidations()),
+                format!("cache_evictions:{}", cache.evictions()),
+                format!("cache_stale_hits:{}", cache.stale_hits()),
+                String::new(),
+            ]);
+        }
+        let command_cache = &stats.command_cache;
+        if command_cache.lookups() > 0 {
+            info.extend([
+                "# Command cache".to_string(),
+                format!("command_cache_hits:{}", command_cache.hits()),
+                format!("command_cache_misses:{}", command_cache.misses()),
+                format!(
+                    "command_cache_invalidations:{}",
+                    command_cache.invalidations()
+                ),
+                String::new(),
+            ]);
+        }
+        let pool = &stats.pool;
+        if pool.checkouts() > 0 || pool.exhausted() > 0 {
+            info.extend([
+                "# Pool".to_string(),
+                format!("pool_open:{}", pool.open()),
+                format!("pool_ready:{}", pool.ready()),
+                format!("pool_checkouts:{}", pool.checkouts()),
+                format!("pool_warm_checkouts:{}", pool.warm_checkouts()),
+                format!("pool_waits:{}", pool.waits()),
+                format!("pool_wait_usec:{}", pool.total_wait().as_micros()),
+                format!("pool_temporary:{}", pool.temporary()),
+                format!("pool_exhausted:{}", pool.exhausted()),
+                String::new(),
+            ]);
+        }
+        let quotas = &stats.quotas;
+        if quotas.identities() > 0 {
+            info.extend([
+                "# Quotas".to_string(),
+                format!("quota_identities:{}", quotas.identities()),
+                format!("quota_rate_refusals:{}", quotas.rate_refusals()),
+                format!("quota_in_flight_refusals:{}", quotas.in_flight_refusals()),
+                format!("quota_bytes_refusals:{}", quotas.bytes_refusals()),
+                String::new(),
+            ]);
+        }
+        let saturation = &stats.saturation;
+        if saturation.is_limited() {
+            info.extend([
+                "# Saturation".to_string(),
+                format!("saturation_in_flight:{}", saturation.in_flight()),
+                format!(
+                    "saturation_connection_refusals:{}",
+                    saturation.connection_refusals()
+                ),
+                format!("saturation_total_refusals:{}", saturation.total_refusals()),
+                String::new(),
+            ]);
+        }
+        let checksums = &stats.checksums;
+        if checksums.is_checking() {
+            info.extend([
+                "# Checksums".to_string(),
+                format!("checksums_verified:{}", checksums.verified()),
+                format!("checksums_corrupted:{}", checksums.corrupted()),
+                format!("checksums_unchecked:{}", checksums.unchecked()),
+                String::new(),
+            ]);
+        }
+        info.push("# Queues".to_string());
+        for (name, queue) in QUEUES.each() {
+            info.extend([
+                format!("queue_{name}_depth:{}", queue.depth()),
+                format!("queue_{name}_full:{}", queue.full()),
+            ]);
+        }
+        info.push(String::new());
+        if let Some(runtime) = RuntimeSnapshot::current() {
+            info.push("# Runtime".to_string());
+            info.extend(
+                runtime
+                    .fields()
+                    .into_iter()
+                    .map(|(name, value)| format!("runtime_{name}:{value}")),
+            );
+            info.push(String::new());
+        }
+        info.join("\r\n")
+    }
+
+    /// The `# Cabbage` section appended to the target's `INFO`, its fields prefixed so they
+    /// can't be mistaken for the target's own
+    fn info_section(&self) -> String {
+        let stats = &self.stats;
+        let mut section = vec![
+            "# Cabbage".to_string(),
+            format!("cabbage_version:{}", env!("CARGO_PKG_VERSION")),
+            format!(
+                "cabbage_uptime_in_seconds:{}",
+                stats.started.elapsed().as_secs()
+            ),
+            format!("cabbage_connected_clients:{}", stats.connections.active()),
+            format!(
+                "cabbage_total_connections_received:{}",
+                stats.connections.accepted()
+            ),
+            format!(
+                "cabbage_rejected_connections:{}",
+                stats.connections.rejected()
+            ),
+            format!(
+                "cabbage_refused_connections:{}",
+                stats.connections.refused()
+            ),
+            format!(
+                "cabbage_target_healthy:{}",
+                u8::from(stats.backend.is_healthy())
+            ),
+            format!(
+                "cabbage_target_suspect:{}",
+                u8::from(stats.backend.is_suspect())
+            ),
+            format!("cabbage_target_timeouts:{}", stats.backend.timeouts()),
+            format!(
+                "cabbage_target_failed_health_checks:{}",
+                stats.backend.failed_health_checks()
+            ),
+        ];
+        section.extend(command_stats(stats, "cabbage_cmdstat_"));
+        section.push(String::new());
+        section.join("\r\n")
+    }
+
+    fn connections(&self) -> String {
+        self.stats
+            .connections
+            .list()
+            .iter()
+            .map(|c| {
+                format!(
+                    "id={} addr={} age={} name={} {}\n",
+                    c.id,
+                    c.addr,
+                    c.since.elapsed().as_secs(),
+                    c.name.as_deref().unwrap_or_default(),
+                    c.traffic
+                )
+            })
+            .collect()
+    }
+
+    fn kill(&self, subcommand: &str, filter: &BytesFrame, value: &BytesFrame) -> BytesFrame {
+        let value = command::arg_bytes(value)
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .unwrap_or_default();
+        let matching: Vec<Uuid> = match upper(filter).as_deref() {
+            Some("ID") => match value.parse::<Uuid>() {
+                Ok(id) => vec![id],
+                Err(_) => return command::error("ERR invalid connection ID"),
+            },
+            Some("ADDR") => self
+                .stats
+                .connections
+                .list()
+                .into_iter()
+                .filter(|c| c.addr.to_string() == value)
+                .map(|c| c.id)
+                .collect(),
+            _ => return unknown_subcommand(subcommand, filter),
+        };
+        let killed = matching
+            .into_iter()
+            .filter(|&id| self.stats.connections.kill(id))
+            .inspect(|id| log::info!("Connection {id} killed by CABBAGE.KILL"))
+            .count();
+        BytesFrame::Integer(killed as i64)
+    }
+
+    fn slowlog(&self, count: usize) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .slowlog
+                .entries(count)
+                .into_iter()
+                .map(|entry| {
+                    let timestamp = entry
+                        .timestamp
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    BytesFrame::Array(vec![
+                        BytesFrame::Integer(entry.id as i64),
+                        BytesFrame::Integer(timestamp as i64),
+                        BytesFrame::Integer(entry.duration.as_micros() as i64),
+                        BytesFrame::Array(
+                            entry.args.into_iter().map(BytesFrame::BulkString).collect(),
+                        ),
+                        bulk(entry.connection_id),
+                        bulk(String::new()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn latency(&self) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .latency
+                .summary()
+                .into_iter()
+                .map(|l| {
+                    BytesFrame::Array(vec![
+                        bulk(l.command),
+                        BytesFrame::Integer(l.count as i64),
+                        BytesFrame::Integer(l.p50.as_micros() as i64),
+                        BytesFrame::Integer(l.p95.as_micros() as i64),
+                        BytesFrame::Integer(l.p99.as_micros() as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn faults(&self, faults: &ErrorInjection, subcommand: &str, args: &[BytesFrame]) -> BytesFrame {
+        let Some((sub, rest)) = args.split_first() else {
+            let state = if faults.is_enabled() { "on" } else { "off" };
+            return BytesFrame::Array(
+                std::iter::once(bulk(format!("injection:{state}")))
+                    .chain(faults.rules().iter().map(|rule| bulk(rule.to_string())))
+                    .collect(),
+            );
+        };
+        match (upper(sub).as_deref(), rest) {
+            (Some("ON"), []) => {
+                log::warn!("Error injection switched on by CABBAGE.FAULTS");
+                faults.set_enabled(true);
+                ok()
+            }
+            (Some("OFF"), []) => {
+                log::info!("Error injection switched off by CABBAGE.FAULTS");
+                faults.set_enabled(false);
+                ok()
+            }
+            (Some("CLEAR"), []) => {
+                faults.clear();
+                ok()
+            }
+            (Some("ADD"), [rule]) => {
+                let rule = command::arg_bytes(rule)
+                    .map(|r| String::from_utf8_lossy(r).parse::<ErrorRule>());
+                match rule {
+                    Some(Ok(rule)) => {
+                        log::warn!("Error injection rule '{rule}' added by CABBAGE.FAULTS");
+                        faults.add(rule);
+                        ok()
+                    }
+                    Some(Err(e)) => command::error(format!("ERR {e}")),
+                    None => command::error("ERR invalid error injection rule"),
+                }
+            }
+            _ => unknown_subcommand(subcommand, sub),
+        }
+    }
+
+    fn read_only(
+        &self,
+        read_only: &ReadOnlyMode,
+        subcommand: &str,
+        args: &[BytesFrame],
+    ) -> BytesFrame {
+        let Some(sub) = args.first() else {
+            let state = if read_only.is_enabled() { "on" } else { "off" };
+            return bulk(format!("read_only:{state}"));
+        };
+        match upper(sub).as_deref() {
+            Some("ON") => {
+                log::warn!("Read-only mode switched on by CABBAGE.READONLY");
+                read_only.set_enabled(true);
+                ok()
+            }
+            Some("OFF") => {
+                log::info!("Read-only mode switched off by CABBAGE.READONLY");
+                read_only.set_enabled(false);
+                ok()
+            }
+            _ => unknown_subcommand(subcommand, sub),
+        }
+    }
+
+    fn maintenance(
+        &self,
+        maintenance: &Maintenance,
+        subcommand: &str,
+        args: &[BytesFrame],
+    ) -> BytesFrame {
+        let Some((sub, rest)) = args.split_first() else {
+            let state = if maintenance.is_enabled() {
+                "on"
+            } else {
+                "off"
+            };
+            return BytesFrame::Array(
+                [
+                    format!("maintenance:{state}"),
+                    format!("error:{}", maintenance.error()),
+                ]
+                .into_iter()
+                .chain(maintenance.allowed().iter().map(|c| format!("allow:{c}")))
+                .map(bulk)
+                .collect(),
+            );
+        };
+        match (upper(sub).as_deref(), rest) {
+            (Some("ON"), []) => {
+                log::warn!("Maintenance switched on by CABBAGE.MAINTENANCE");
+                maintenance.set_enabled(true);
+                ok()
+            }
+            (Some("ON"), [error]) => match command::arg_bytes(error) {
+                Some(error) if error.iter().any(|&b| b != b'-') => {
+                    let error = String::from_utf8_lossy(error);
+                    log::warn!("Maintenance switched on by CABBAGE.MAINTENANCE, with '{error}'");
+                    maintenance.set_error(&error);
+                    maintenance.set_enabled(true);
+                    ok()
+                }
+                _ => command::error("ERR invalid maintenance error"),
+            },
+            (Some("OFF"), []) => {
+                log::info!("Maintenance switched off by CABBAGE.MAINTENANCE");
+                maintenance.set_enabled(false);
+                ok()
+            }
+            _ => unknown_subcommand(subcommand, sub),
+        }
+    }
+
+    fn top_commands(&self, count: usize) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .commands
+                .top(count)
+                .into_iter()
+                .map(|c| {
+                    BytesFrame::Array(vec![
+                        bulk(c.command),
+                        BytesFrame::Integer(c.calls as i64),
+                        BytesFrame::Integer(c.errors as i64),
+                        BytesFrame::Integer(c.total_latency.as_micros() as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn key_space(&self, count: usize) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .key_space
+                .top(count)
+                .into_iter()
+                .map(|ns| {
+                    BytesFrame::Array(vec![
+                        BytesFrame::BulkString(ns.namespace),
+                        BytesFrame::Integer(ns.reads as i64),
+                        BytesFrame::Integer(ns.writes as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn key_slots(&self, count: usize) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .key_slots
+                .top(count)
+                .into_iter()
+                .map(|(slot, sampled)| {
+                    BytesFrame::Array(vec![
+                        BytesFrame::Integer(slot.into()),
+                        BytesFrame::Integer(sampled as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn hot_keys(&self, count: usize) -> BytesFrame {
+        BytesFrame::Array(
+            self.stats
+                .hot_keys
+                .top(count)
+                .into_iter()
+                .map(|hot| {
+                    BytesFrame::Array(vec![
+                        BytesFrame::BulkString(hot.key),
+                        BytesFrame::Integer(hot.count as i64),
+                        BytesFrame::Integer(hot.error as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A line per command seen, busiest first so the workload's make-up reads from the top, named
+/// `prefix` and the command as in `INFO commandstats`
+fn command_stats(stats: &Stats, prefix: &str) -> Vec<String> {
+    stats
+        .commands
+        .top(usize::MAX)
+        .into_iter()
+        .map(|c| {
+            format!(
+                "{prefix}{}:calls={},usec={},usec_per_call={:.2},failed_calls={}",
+                c.command.to_lowercase(),
+                c.calls,
+                c.total_latency.as_micros(),
+                c.total_latency.as_micros() as f64 / c.calls as f64,
+                c.errors
+            )
+        })
+        .collect()
+}
+
+/// Whether `req` is an `INFO` asking for the sections the `# Cabbage` section goes with
+fn shows_info_section(req: &BytesFrame) -> bool {
+    if command::name(req).as_deref() != Some("INFO") {
+        return false;
+    }
+    let sections = command::args(req).map_or(&[][..], |args| &args[1..]);
+    sections.is_empty()
+        || sections.iter().filter_map(upper).any(|section| {
+            matches!(
+                section.as_str(),
+                "ALL" | "EVERYTHING" | "DEFAULT" | "CABBAGE"
+            )
+        })
+}
+
+/// The target's `reply` to `INFO` with `section` appended, after a blank line
+fn append_section(reply: BytesFrame, section: &str) -> BytesFrame {
+    let BytesFrame::BulkString(info) = reply else {
+        return reply;
+    };
+    let mut augmented = BytesMut::from(&info[..]);
+    if !augmented.is_empty() {
+        augmented.put_slice(b"\r\n");
+    }
+    augmented.put_slice(section.as_bytes());
+    BytesFrame::BulkString(augmented.freeze())
+}
+
+fn bulk(s: String) -> BytesFrame {
+    BytesFrame::BulkString(Bytes::from(s))
+}
+
+fn ok() -> BytesFrame {
+    BytesFrame::SimpleString(Bytes::from_static(b"OK"))
+}
+
+fn upper(arg: &BytesFrame) -> Option<String> {
+    command::arg_bytes(arg).map(|a| String::from_utf8_lossy(a).to_ascii_uppercase())
+}
+
+/// Parse a count argument, where a negative count means all entries (as in Redis 7's `SLOWLOG`)
+fn count(arg: &BytesFrame) -> Result<usize, BytesFrame> {
+    let count = command::arg_bytes(arg)
+        .and_then(|a| std::str::from_utf8(a).ok())
+        .and_then(|a| a.parse::<i64>().ok())
+        .ok_or_else(|| command::error("ERR value is not an integer or out of range"))?;
+    Ok(usize::try_from(count).unwrap_or(usize::MAX))
+}
+
+fn unknown_subcommand(command: &str, sub: &BytesFrame) -> BytesFrame {
+    command::error(format!(
+        "ERR unknown subcommand '{}' for 'cabbage.{}', try CABBAGE.HELP",
+        command::arg_bytes(sub)
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .unwrap_or_default(),
+        command.to_lowercase()
+    ))
+}
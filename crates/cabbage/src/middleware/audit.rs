@@ -0,0 +1,209 @@
+//! Auditing administrative and write commands.
+//!
+//! `AuditLayer` records every command which may change data or the target's (or the proxy's)
+//! configuration in an `AuditLog`, along with who sent it and how it was answered: every command
+//! not known to be read-only, other than connection housekeeping (`PING`, `SELECT`, `AUTH`, ...),
+//! pub/sub, and the proxy's own read-only `CABBAGE.*` commands. Commands refused by the proxy
+//! are recorded too, with the error they were refused with. Credentials are masked as in the
+//! logs, and long arguments are cut short.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::audit::{AuditEntry, AuditLog, Outcome};
+use crate::command;
+use crate::error::Error;
+use crate::listener::ClientAddr;
+use crate::middleware::auth::ClientUser;
+use crate::middleware::redact::RedactionRules;
+use crate::service::ResponseStream;
+
+/// Arguments are recorded up to this many bytes
+static MAX_ARG_BYTES: usize = 256;
+
+/// Whether `req` is recorded
+fn is_audited(req: &BytesFrame) -> bool {
+    let Some(name) = command::name(req) else {
+        return false;
+    };
+    let subcommand = command::args(req)
+        .and_then(|args| args.get(1))
+        .and_then(command::arg_bytes)
+        .map(|sub| String::from_utf8_lossy(sub).to_ascii_uppercase());
+    match (name.as_str(), subcommand.as_deref()) {
+        ("CLIENT", Some("KILL" | "PAUSE" | "UNPAUSE")) => true,
+        ("CONFIG", Some("GET")) => false,
+        ("CABBAGE.KILL" | "CABBAGE.RELOAD" | "CABBAGE.FAULTS" | "CABBAGE.DRAIN", _) => true,
+        ("CABBAGE.SLOWLOG", Some("RESET")) => true,
+        ("CABBAGE.READONLY" | "CABBAGE.MAINTENANCE", Some(_)) => true,
+        (name, _) if name.starts_with("CABBAGE.") => false,
+        (
+            "CLIENT" | "PING" | "ECHO" | "HELLO" | "AUTH" | "SELECT" | "QUIT" | "RESET" | "INFO"
+            | "COMMAND" | "TIME" | "ROLE" | "LASTSAVE" | "MULTI" | "DISCARD" | "WATCH" | "UNWATCH"
+            | "READONLY" | "READWRITE" | "ASKING" | "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE"
+            | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "PUBSUB",
+            _,
+        ) => false,
+        _ => !command::is_read_only(req),
+    }
+}
+
+/// `arg` as recorded
+fn recorded(arg: &BytesFrame) -> String {
+    let bytes = match arg {
+        BytesFrame::Integer(i) => return i.to_string(),
+        arg => command::arg_bytes(arg).map_or(&b""[..], |a| a.as_ref()),
+    };
+    if bytes.len() <= MAX_ARG_BYTES {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    format!(
+        "{}...({} more bytes)",
+        String::from_utf8_lossy(&bytes[..MAX_ARG_BYTES]),
+        bytes.len() - MAX_ARG_BYTES
+    )
+}
+
+pub struct AuditLayer {
+    audit: AuditLog,
+    connection_id: Uuid,
+    client_addr: Option<ClientAddr>,
+    user: ClientUser,
+    redaction: Arc<RedactionRules>,
+}
+
+impl AuditLayer {
+    /// Record the commands of connection `connection_id`, from `client_addr` (if it has one) and
+    /// authenticated as `user`, in `audit`
+    pub fn new(
+        audit: AuditLog,
+        connection_id: Uuid,
+        client_addr: Option<ClientAddr>,
+        user: ClientUser,
+    ) -> Self {
+        Self {
+            audit,
+            connection_id,
+            client_addr,
+            user,
+            redaction: Arc::new(RedactionRules::default()),
+        }
+    }
+
+    /// Mask sensitive values according to `redaction` rather than the default rules
+    pub fn with_redaction(mut self, redaction: impl Into<Arc<RedactionRules>>) -> Self {
+        self.redaction = redaction.into();
+        self
+    }
+}
+
+impl<S> Layer<S> for AuditLayer {
+    type Service = Auditor<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Auditor {
+            inner: service,
+            audit: self.audit.clone(),
+            connection_id: self.connection_id,
+            client: self.client_addr.as_ref().map(ToString::to_string),
+            user: self.user.clone(),
+            redaction: self.redaction.clone(),
+        }
+    }
+}
+
+pub struct Auditor<S> {
+    inner: S,
+    audit: AuditLog,
+    connection_id: Uuid,
+    client: Option<String>,
+    user: ClientUser,
+    redaction: Arc<RedactionRules>,
+}
+
+/// A command awaiting its answer, recorded once it comes, or as unanswered if it never does
+struct Pending {
+    audit: AuditLog,
+    entry: Option<AuditEntry>,
+}
+
+impl Pending {
+    fn settle(&mut self, outcome: Outcome) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.outcome = outcome;
+            self.audit.record(entry);
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.settle(Outcome::NoReply);
+    }
+}
+
+impl<S> Service<BytesFrame> for Auditor<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if !is_audited(&req) {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+        let command = command::args(&self.redaction.request(&req))
+            .unwrap_or_default()
+            .iter()
+            .map(recorded)
+            .collect();
+        let mut pending = Pending {
+            audit: self.audit.clone(),
+            entry: Some(AuditEntry {
+                timestamp: SystemTime::now(),
+                connection_id: self.connection_id,
+                client: self.client.clone(),
+                user: self.user.get(),
+                command,
+                outcome: Outcome::NoReply,
+            }),
+        };
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let stream = match fut.await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let e = e.into();
+                    pending.settle(Outcome::Error(format!("{e:#}")));
+                    return Err(e);
+                }
+            };
+            Ok(stream
+                .inspect(move |frame| {
+                    pending.settle(match frame {
+                        BytesFrame::Error(message) => Outcome::Error(message.to_string()),
+                        _ => Outcome::Ok,
+                    })
+                })
+                .boxed())
+        })
+    }
+}
@@ -0,0 +1,208 @@
+//! Authenticating clients to the proxy.
+//!
+//! `ProxyAuthLayer` requires clients to authenticate to cabbage itself before it forwards any of
+//! their commands, as Redis does with `requirepass` and ACL users. `AUTH` (and the `AUTH` option
+//! of `HELLO`) is answered by the proxy against its own credentials and never reaches the
+//! target, which the proxy authenticates to with credentials of its own (the target's
+//! `username` and `password`), so target passwords can be rotated without touching clients.
+//! Commands from clients which haven't authenticated are answered with `NOAUTH` errors.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// The user `AUTH <password>` authenticates as
+pub static DEFAULT_USER: &str = "default";
+
+/// The user a client connection has authenticated as, shared with the layers which act on it
+#[derive(Clone, Debug, Default)]
+pub struct ClientUser(Arc<Mutex<Option<String>>>);
+
+impl ClientUser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().ok().and_then(|user| user.clone())
+    }
+
+    pub(crate) fn set(&self, user: String) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = Some(user);
+        }
+    }
+}
+
+/// The users clients may authenticate to the proxy as, and their passwords
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    passwords: HashMap<String, String>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let clients authenticate as `user` with `password`
+    pub fn with_user(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.passwords.insert(user.into(), password.into());
+        self
+    }
+
+    fn verify(&self, user: &[u8], password: &[u8]) -> bool {
+        let Some(expected) = std::str::from_utf8(user)
+            .ok()
+            .and_then(|user| self.passwords.get(user))
+        else {
+            return false;
+        };
+        // Compare every byte, so how long a comparison takes says nothing of the password
+        expected.len() == password.len()
+            && expected
+                .bytes()
+                .zip(password)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+pub struct ProxyAuthLayer {
+    credentials: Arc<Credentials>,
+    user: ClientUser,
+}
+
+impl ProxyAuthLayer {
+    /// Authenticate a client connection against `credentials`, recording who as in `user`
+    pub fn new(credentials: Arc<Credentials>, user: ClientUser) -> Self {
+        Self { credentials, user }
+    }
+}
+
+impl<S> Layer<S> for ProxyAuthLayer {
+    type Service = ProxyAuth<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ProxyAuth {
+            inner: service,
+            credentials: self.credentials.clone(),
+            user: self.user.clone(),
+        }
+    }
+}
+
+pub struct ProxyAuth<S> {
+    inner: S,
+    credentials: Arc<Credentials>,
+    user: ClientUser,
+}
+
+impl<S> ProxyAuth<S> {
+    /// Authenticate as `user` (or the default user), answering whether that worked
+    fn authenticate(&self, user: Option<&BytesFrame>, password: &BytesFrame) -> Option<String> {
+        let user = match user {
+            Some(user) => command::arg_bytes(user)?.as_ref(),
+            None => DEFAULT_USER.as_bytes(),
+        };
+        let password = command::arg_bytes(password)?;
+        if !self.credentials.verify(user, password) {
+            return None;
+        }
+        let user = String::from_utf8_lossy(user).into_owned();
+        self.user.set(user.clone());
+        Some(user)
+    }
+}
+
+fn wrong_password() -> BytesFrame {
+    command::error("WRONGPASS invalid username-password pair or user is disabled.")
+}
+
+impl<S> Service<BytesFrame> for ProxyAuth<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        let args = command::args(&req).unwrap_or_default();
+        match name.as_deref() {
+            Some("AUTH") => {
+                let answer = match args {
+                    [_, password] => self.authenticate(None, password),
+                    [_, user, password] => self.authenticate(Some(user), password),
+                    _ => {
+                        let error = command::error("ERR wrong number of arguments for 'auth'");
+                        return Box::pin(async move { Ok(reply(error)) });
+                    }
+                };
+                let answer = match answer {
+                    Some(user) => {
+                        log::debug!("Client authenticated to the proxy as '{user}'");
+                        BytesFrame::SimpleString("OK".into())
+                    }
+                    None => wrong_password(),
+                };
+                Box::pin(async move { Ok(reply(answer)) })
+            }
+            Some("HELLO") => {
+                // Answer the AUTH option here, and forward the rest of the HELLO
+                let auth = args.iter().position(|arg| {
+                    command::arg_bytes(arg).is_some_and(|arg| arg.eq_ignore_ascii_case(b"AUTH"))
+                });
+                let req = match auth {
+                    Some(i) if i >= 2 => {
+                        let (Some(user), Some(password)) = (args.get(i + 1), args.get(i + 2))
+                        else {
+                            let error = command::error("ERR syntax error in HELLO option 'auth'");
+                            return Box::pin(async move { Ok(reply(error)) });
+                        };
+                        if self.authenticate(Some(user), password).is_none() {
+                            return Box::pin(async move { Ok(reply(wrong_password())) });
+                        }
+                        let mut args = args.to_vec();
+                        args.drain(i..i + 3);
+                        BytesFrame::Array(args)
+                    }
+                    _ if self.user.get().is_none() => {
+                        let error = command::error(
+                            "NOAUTH HELLO must be called with the client already authenticated, \
+                             otherwise the HELLO <proto> AUTH <user> <pass> option can be used",
+                        );
+                        return Box::pin(async move { Ok(reply(error)) });
+                    }
+                    _ => req,
+                };
+                Box::pin(self.inner.call(req).map_err(Into::into))
+            }
+            Some("QUIT") => Box::pin(self.inner.call(req).map_err(Into::into)),
+            _ if self.user.get().is_none() => {
+                let error = command::error("NOAUTH Authentication required.");
+                Box::pin(async move { Ok(reply(error)) })
+            }
+            _ => Box::pin(self.inner.call(req).map_err(Into::into)),
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Circuit breaking.
+//!
+//! A `CircuitBreaker`, shared by every connection to the same target, counts consecutive failed
+//! commands. A command fails if it can't be dispatched, or if its reply ends before any frame
+//! arrives or starts with an error meaning the target couldn't answer (`-ERR proxy timeout`, a
+//! lost connection, `-LOADING`, ...); errors refusing the command itself are the target
+//! answering, so count as successes. Blocking commands succeed once dispatched, as their replies
+//! may legitimately take as long as they like. Once failures reach the configured threshold the
+//! circuit opens: for the cool-down period `CircuitBreakerLayer` answers every command with an
+//! error without touching the target. After that the circuit is half-open and a single command is
+//! let through as a probe, with other connections held back in `poll_ready` until it resolves;
+//! success closes the circuit again, failure reopens it for another cool-down.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed {
+        consecutive_failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe is in flight
+    HalfOpen,
+}
+
+/// Whether a command may be dispatched
+enum Admission {
+    Pass,
+    Probe,
+    Reject,
+}
+
+struct Circuit {
+    state: State,
+    /// Tasks waiting in `poll_ready` for a probe to resolve
+    waiters: Vec<Waker>,
+}
+
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cool_down: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, cool_down: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            failure_threshold: failure_threshold.max(1),
+            cool_down,
+            circuit: Mutex::new(Circuit {
+                state: State::Closed {
+                    consecutive_failures: 0,
+                },
+                waiters: vec![],
+            }),
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.circuit
+            .lock()
+            .map(|c| !matches!(c.state, State::Closed { .. }))
+            .unwrap_or_default()
+    }
+
+    /// Ready unless a probe is in flight, in which case `cx` is woken once it resolves
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let Ok(mut circuit) = self.circuit.lock() else {
+            return Poll::Ready(());
+        };
+        if circuit.state == State::HalfOpen {
+            circuit.waiters.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    fn admit(&self) -> Admission {
+        let Ok(mut circuit) = self.circuit.lock() else {
+            return Admission::Pass;
+        };
+        match circuit.state {
+            State::Closed { .. } => Admission::Pass,
+            State::Open { until } if Instant::now() >= until => {
+                log::info!("Circuit half-open, probing target");
+                circuit.state = State::HalfOpen;
+                Admission::Probe
+            }
+            State::Open { .. } | State::HalfOpen => Admission::Reject,
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let Ok(mut circuit) = self.circuit.lock() else {
+            return;
+        };
+        let open = State::Open {
+            until: Instant::now() + self.cool_down,
+        };
+        circuit.state = match (circuit.state, success) {
+            (State::HalfOpen, true) => {
+                log::info!("Circuit closed, target is answering again");
+                State::Closed {
+                    consecutive_failures: 0,
+                }
+            }
+            (State::HalfOpen, false) => {
+                log::warn!("Circuit probe failed, reopening for {:?}", self.cool_down);
+                open
+            }
+            (State::Closed { .. }, true) => State::Closed {
+                consecutive_failures: 0,
+            },
+            (
+                State::Closed {
+                    consecutive_failures,
+                },
+                false,
+            ) => {
+                let consecutive_failures = consecutive_failures + 1;
+                if consecutive_failures >= self.failure_threshold {
+                    log::warn!(
+                        "Circuit opened for {:?} after {consecutive_failures} consecutive failures",
+                        self.cool_down
+                    );
+                    open
+                } else {
+                    State::Closed {
+                        consecutive_failures,
+                    }
+                }
+            }
+            (state @ State::Open { .. }, _) => state,
+        };
+        if circuit.state != State::HalfOpen {
+            circuit.waiters.drain(..).for_each(Waker::wake);
+        }
+    }
+
+    /// A probe was dropped before resolving; let the next command probe instead
+    fn abandon_probe(&self) {
+        let Ok(mut circuit) = self.circuit.lock() else {
+            return;
+        };
+        if circuit.state == State::HalfOpen {
+            circuit.state = State::Open {
+                until: Instant::now(),
+            };
+            circuit.waiters.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// Records a command's outcome, treating a probe dropped unresolved as abandoned
+struct Outcome {
+    breaker: Arc<CircuitBreaker>,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Outcome {
+    fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(success);
+    }
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.abandon_probe();
+        }
+    }
+}
+
+/// A reply stream recording its command's outcome from its first frame, or its end if none comes
+struct Recorded {
+    inner: ResponseStream,
+    outcome: Option<Outcome>,
+}
+
+impl Stream for Recorded {
+    type Item = BytesFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(frame) = &next
+            && let Some(outcome) = self.outcome.take()
+        {
+            outcome.record(frame.as_ref().is_some_and(|f| !command::is_unavailable(f)));
+        }
+        next
+    }
+}
+
+pub struct CircuitBreakerLayer {
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakerLayer {
+    pub fn new(breaker: Arc<CircuitBreaker>) -> Self {
+        Self { breaker }
+    }
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreak<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CircuitBreak {
+            inner: service,
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+pub struct CircuitBreak<S> {
+    inner: S,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl<S> Service<BytesFrame> for CircuitBreak<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.breaker.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let probe = match self.breaker.admit() {
+            Admission::Pass => false,
+            Admission::Probe => true,
+            Admission::Reject => {
+                return Box::pin(futures::future::ready(Ok(reply(command::error(
+                    "ERR circuit breaker open, target unavailable",
+                )))));
+            }
+        };
+
+        let outcome = Outcome {
+            breaker: self.breaker.clone(),
+            probe,
+            recorded: false,
+        };
+        let blocking = command::is_blocking(&req);
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            match fut.await {
+                Ok(stream) if blocking => {
+                    outcome.record(true);
+                    Ok(stream)
+                }
+                Ok(stream) => Ok(Recorded {
+                    inner: stream,
+                    outcome: Some(outcome),
+                }
+                .boxed()),
+                Err(e) => {
+                    outcome.record(false);
+                    Err(e.into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,500 @@
+//! Read caching.
+//!
+//! A `ReadCache`, shared by every connection, holds `GET` replies for a fixed time-to-live and
+//! evicts the least recently used once it reaches its capacity. `CacheLayer` answers `GET`s from
+//! it when it can and fills it from the target's replies when it can't. Commands sent through the
+//! proxy which may write invalidate the keys they touch (`FLUSHDB`, `FLUSHALL`, `SWAPDB`, and
+//! writes whose keys can't be told clear the whole cache), both when sent and once answered;
+//! writes queued in a transaction invalidate their keys when it's executed. Any invalidation also
+//! stops replies already in flight from being cached, since they may predate it. Writes which
+//! don't go through the proxy are only seen once entries expire, unless `track_invalidations` is
+//! following the target's reports of modified keys. Keys under the bypass prefixes are never
+//! cached. Cached keys aren't scoped to a database, so a connection stops reading from (and
+//! filling) the cache once it sends `SELECT`.
+//!
+//! With `serve_stale`, expired replies are kept that much longer, and when the target can't
+//! answer a `GET` (its connection is lost or unavailable, it times out, the circuit breaker is
+//! open, or it's loading or has lost its master) the client is answered with the last reply
+//! cached instead of the error, for reads where availability beats freshness. Replies served
+//! stale are counted in `Stats::cache`, and a reply invalidated by a write is never served.
+
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::{SinkExt, StreamExt};
+use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
+use tokio::net::TcpStream;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::{OnComplete, reply};
+use crate::service::{Handshake, ResponseStream};
+use crate::stats::Stats;
+
+/// Channel on which the target reports modified keys to tracking clients
+static INVALIDATION_CHANNEL: &str = "__redis__:invalidate";
+/// Wait between attempts to (re)establish invalidation tracking
+static TRACKING_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct Cached {
+    reply: BytesFrame,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<Bytes, Cached>,
+    /// Keys by when they were last used, least recent first
+    by_use: BTreeMap<u64, Bytes>,
+    clock: u64,
+    /// Advanced by every invalidation
+    epoch: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let Some(cached) = self.by_key.remove(key) else {
+            return false;
+        };
+        self.by_use.remove(&cached.last_used);
+        true
+    }
+
+    fn touch(&mut self, key: &Bytes) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(cached) = self.by_key.get_mut(key) {
+            self.by_use.remove(&cached.last_used);
+            cached.last_used = clock;
+            self.by_use.insert(clock, key.clone());
+        }
+    }
+}
+
+pub struct ReadCache {
+    ttl: Duration,
+    /// How long past expiry a reply may still be served while the target can't answer
+    serve_stale: Option<Duration>,
+    max_entries: usize,
+    bypass: Vec<Bytes>,
+    entries: Mutex<Entries>,
+    stats: Arc<Stats>,
+}
+
+impl ReadCache {
+    /// Cache up to `max_entries` replies for `ttl` each, except for keys starting with any of the
+    /// `bypass` prefixes, serving them for up to `serve_stale` longer while the target can't
+    /// answer
+    pub fn new(
+        ttl: Duration,
+        serve_stale: Option<Duration>,
+        max_entries: usize,
+        bypass: &[String],
+        stats: Arc<Stats>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            ttl,
+            serve_stale,
+            max_entries: max_entries.max(1),
+            bypass: bypass
+                .iter()
+                .map(|prefix| Bytes::copy_from_slice(prefix.as_bytes()))
+                .collect(),
+            entries: Mutex::new(Entries::default()),
+            stats,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|e| e.by_key.len())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_cacheable(&self, key: &[u8]) -> bool {
+        !self.bypass.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    fn lookup(&self, key: &Bytes) -> Option<BytesFrame> {
+        let mut entries = self.entries.lock().ok()?;
+        let expires = entries.by_key.get(key)?.expires;
+        let now = Instant::now();
+        if expires <= now {
+            // Kept while it may still be served stale
+            if self.serve_stale.is_none_or(|stale| expires + stale <= now) {
+                entries.remove(key);
+            }
+            return None;
+        }
+        entries.touch(key);
+        entries.by_key.get(key).map(|cached| cached.reply.clone())
+    }
+
+    /// The reply cached for `key`, expired or not, if it may be served while the target can't
+    /// answer
+    fn stale(&self, key: &Bytes) -> Option<BytesFrame> {
+        let stale = self.serve_stale?;
+        let entries = self.entries.lock().ok()?;
+        let cached = entries.by_key.get(key)?;
+        if cached.expires + stale <= Instant::now() {
+            return None;
+        }
+        self.stats.cache.record_stale_hit();
+        Some(cached.reply.clone())
+    }
+
+    /// The current epoch, to be passed to `fill` with the reply to a request sent after this
+    fn epoch(&self) -> u64 {
+        self.entries.lock().map(|e| e.epoch).unwrap_or_default()
+    }
+
+    /// Cache `reply` for `key`, unless something was invalidated since `epoch`
+    fn fill(&self, key: Bytes, reply: BytesFrame, epoch: u64) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.epoch != epoch {
+            return;
+        }
+        entries.remove(&key);
+        entries.by_key.insert(
+            key.clone(),
+            Cached {
+                reply,
+                expires: Instant::now() + self.ttl,
+                last_used: 0,
+            },
+        );
+        entries.touch(&key);
+        while entries.by_key.len() > self.max_entries {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.by_key.remove(&oldest);
+            self.stats.cache.record_eviction();
+        }
+    }
+
+    /// Drop any cached replies for `keys`
+    pub fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a Bytes>) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.epoch += 1;
+        for key in keys {
+            if entries.remove(key) {
+                self.stats.cache.record_invalidation();
+            }
+        }
+    }
+
+    /// Drop every cached reply
+    pub fn clear(&self) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let epoch = entries.epoch + 1;
+        *entries = Entries {
+            epoch,
+            ..Entries::default()
+        };
+    }
+}
+
+/// Keep `cache` coherent with writes made to the target at `target_addr` by any client, not just
+/// through this proxy, by following its client-side caching invalidations in broadcast mode.
+/// Replies were cached under the client's keys, so with a `key_prefix` only keys under it are
+/// tracked, and the prefix is removed before invalidating. The cache is cleared whenever tracking
+/// (re)starts, since writes may have been missed while it wasn't running. Runs until the process
+/// exits.
+pub async fn track_invalidations(
+    cache: Arc<ReadCache>,
+    target_addr: String,
+    handshake: Handshake,
+    key_prefix: Option<String>,
+) {
+    loop {
+        let error = follow_invalidations(&cache, &target_addr, &handshake, key_prefix.as_deref())
+            .await
+            .err()
+            .unwrap_or_else(|| {
+                Error::BackendUnavailable("target closed the connection".to_string())
+            });
+        log::warn!("Cache invalidation tracking interrupted, restarting: {error:#}");
+        cache.clear();
+        tokio::time::sleep(TRACKING_RETRY_DELAY).await;
+    }
+}
+
+/// Send `request` and wait for its reply, failing on an error reply
+async fn exchange(
+    framed: &mut Framed<TcpStream, Resp2>,
+    request: BytesFrame,
+) -> Result<BytesFrame> {
+    framed.send(request).await?;
+    match framed.next().await {
+        Some(Ok(BytesFrame::Error(e))) => bail!(BackendUnavailable, "{e}"),
+        Some(Ok(frame)) => Ok(frame),
+        Some(Err(e)) => Err(e.into()),
+        None => bail!(BackendUnavailable, "target closed the connection"),
+    }
+}
+
+/// Track invalidations until either connection involved fails
+async fn follow_invalidations(
+    cache: &ReadCache,
+    target_addr: &str,
+    handshake: &Handshake,
+    key_prefix: Option<&str>,
+) -> Result<()> {
+    // Over RESP2, invalidations are published to a subscribed connection, while tracking is
+    // enabled (and lasts) on another which redirects to it
+    let mut subscriber = Framed::new(handshake.connect(target_addr).await?, Resp2::default());
+    handshake.perform(&mut subscriber).await?;
+    let BytesFrame::Integer(subscriber_id) =
+        exchange(&mut subscriber, command::request(["CLIENT", "ID"])).await?
+    else {
+        bail!(Protocol, "Unexpected reply to CLIENT ID");
+    };
+    exchange(
+        &mut subscriber,
+        command::request(["SUBSCRIBE", INVALIDATION_CHANNEL]),
+    )
+    .await?;
+
+    let mut tracker = Framed::new(handshake.connect(target_addr).await?, Resp2::default());
+    handshake.perform(&mut tracker).await?;
+    let subscriber_id = subscriber_id.to_string();
+    let mut tracking = vec![
+        "CLIENT",
+        "TRACKING",
+        "ON",
+        "REDIRECT",
+        &subscriber_id,
+        "BCAST",
+    ];
+    if let Some(prefix) = key_prefix {
+        tracking.extend(["PREFIX", prefix]);
+    }
+    exchange(&mut tracker, command::request(tracking)).await?;
+    cache.clear();
+    log::info!("Tracking cache invalidations from {target_addr}");
+
+    loop {
+        tokio::select! {
+            message = subscriber.next() => match message {
+                Some(Ok(message)) => invalidate_from(cache, &message, key_prefix),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            // Nothing is expected here, but tracking ends with this connection
+            frame = tracker.next() => match frame {
+                Some(Ok(frame)) => {
+                    log::debug!("Unexpected frame from tracking connection: {frame:?}")
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Apply an invalidation message: an array of modified keys, or null when the database is flushed
+fn invalidate_from(cache: &ReadCache, message: &BytesFrame, key_prefix: Option<&str>) {
+    let BytesFrame::Array(parts) = message else {
+        return;
+    };
+    let [kind, _channel, payload] = parts.as_slice() else {
+        return;
+    };
+    if command::arg_bytes(kind).is_none_or(|kind| !kind.eq_ignore_ascii_case(b"message")) {
+        return;
+    }
+    match payload {
+        BytesFrame::Array(keys) => {
+            let prefix = key_prefix.unwrap_or_default().as_bytes();
+            let keys: Vec<Bytes> = keys
+                .iter()
+                .filter_map(command::arg_bytes)
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| key.slice(prefix.len()..))
+                .collect();
+            cache.invalidate(&keys);
+        }
+        BytesFrame::Null => cache.clear(),
+        _ => {}
+    }
+}
+
+pub struct CacheLayer {
+    cache: Arc<ReadCache>,
+}
+
+impl CacheLayer {
+    pub fn new(cache: Arc<ReadCache>) -> Self {
+        Self { cache }
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = Cache<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Cache {
+            inner: service,
+            cache: self.cache.clone(),
+            transaction: None,
+            selected_db: false,
+        }
+    }
+}
+
+pub struct Cache<S> {
+    inner: S,
+    cache: Arc<ReadCache>,
+    /// The combined effect of the writes queued since `MULTI`, while in a transaction
+    transaction: Option<Effect>,
+    /// Whether the client may have moved off the database the cache holds
+    selected_db: bool,
+}
+
+/// What a request means for the cache
+enum Effect {
+    /// A `GET` whose reply may be cached
+    Read(Bytes),
+    /// A write to these keys
+    Write(Vec<Bytes>),
+    /// A write to every key (or to keys which can't be told)
+    Flush,
+    None,
+}
+
+impl<S> Cache<S> {
+    fn effect(&mut self, req: &BytesFrame) -> Effect {
+        let Some(name) = command::name(req) else {
+            return Effect::None;
+        };
+        let effect = match name.as_str() {
+            "MULTI" => {
+                self.transaction = Some(Effect::Write(vec![]));
+                return Effect::None;
+            }
+            "EXEC" => return self.transaction.take().unwrap_or(Effect::None),
+            "DISCARD" => {
+                self.transaction = None;
+                return Effect::None;
+            }
+            "FLUSHDB" | "FLUSHALL" | "SWAPDB" => Effect::Flush,
+            "SELECT" => {
+                self.selected_db = true;
+                Effect::None
+            }
+            "GET" if self.transaction.is_none() && !self.selected_db => match command::args(req) {
+                Some([_, key]) => match command::arg_bytes(key) {
+                    Some(key) if self.cache.is_cacheable(key) => Effect::Read(key.clone()),
+                    _ => Effect::None,
+                },
+                _ => Effect::None,
+            },
+            _ if command::is_read_only(req) => Effect::None,
+            // A write whose keys can't be found could have written any cached key
+            _ => match command::known_keys(req) {
+                Some(keys) => Effect::Write(keys.into_iter().cloned().collect()),
+                None => Effect::Flush,
+            },
+        };
+
+        // Writes queued in a transaction take effect when it's executed
+        match (&mut self.transaction, effect) {
+            (None, effect) => effect,
+            (Some(queued), Effect::Flush) => {
+                *queued = Effect::Flush;
+                Effect::None
+            }
+            (Some(Effect::Write(queued)), Effect::Write(keys)) => {
+                queued.extend(keys);
+                Effect::None
+            }
+            (Some(_), _) => Effect::None,
+        }
+    }
+}
+
+impl<S> Service<BytesFrame> for Cache<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let cache = self.cache.clone();
+        match self.effect(&req) {
+            Effect::None => Box::pin(self.inner.call(req).map_err(Into::into)),
+            Effect::Read(key) => {
+                if let Some(cached) = cache.lookup(&key) {
+                    cache.stats.cache.record_hit();
+                    return Box::pin(async move { Ok(reply(cached)) });
+                }
+                cache.stats.cache.record_miss();
+                let epoch = cache.epoch();
+                let fut = self.inner.call(req).map_err(Into::into);
+                Box::pin(async move {
+                    let stream = match fut.await {
+                        Ok(stream) => stream,
+                        Err(e) => return cache.stale(&key).map(reply).ok_or(e),
+                    };
+                    Ok(stream
+                        .map(move |frame| {
+                            if matches!(frame, BytesFrame::BulkString(_) | BytesFrame::Null) {
+                                cache.fill(key.clone(), frame.clone(), epoch);
+                            }
+                            match command::is_unavailable(&frame).then(|| cache.stale(&key)) {
+                                Some(Some(stale)) => stale,
+                                _ => frame,
+                            }
+                        })
+                        .boxed())
+                })
+            }
+            Effect::Write(keys) => {
+                cache.invalidate(&keys);
+                let fut = self.inner.call(req).map_err(Into::into);
+                Box::pin(async move {
+                    let stream = fut.await?;
+                    Ok(OnComplete::new(stream, move || cache.invalidate(&keys)).boxed())
+                })
+            }
+            Effect::Flush => {
+                cache.clear();
+                let fut = self.inner.call(req).map_err(Into::into);
+                Box::pin(async move {
+                    let stream = fut.await?;
+                    Ok(OnComplete::new(stream, move || cache.clear()).boxed())
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Canary response diffing.
+//!
+//! `CanaryLayer` sends each command to a canary target as well as the primary, for verifying a
+//! replacement Redis-compatible store before switching to it. The client receives the primary's
+//! reply; once both replies are complete they're compared frame by frame, and any mismatch is
+//! logged and recorded in the shared `CanaryReport` along with the command, its first key, and
+//! where the replies diverge. Like mirroring, requests reach the canary through a bounded queue
+//! so it never slows the client down. Subscriptions, blocking commands, and commands whose
+//! replies legitimately differ between servers (`TIME`, `RANDOMKEY`, `INFO`, ...) are left out.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::{mpsc, oneshot};
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt as _;
+
+use crate::error::Error;
+use crate::service::ResponseStream;
+use crate::stats::{CanaryMismatch, Stats};
+use crate::{command, frame};
+
+/// Requests waiting for the canary beyond this many are dropped
+static QUEUE_LEN: usize = 1024;
+
+/// Commands whose replies depend on the server or on chance rather than on the data
+fn is_nondeterministic(name: &str) -> bool {
+    matches!(
+        name,
+        "CLIENT"
+            | "CLUSTER"
+            | "COMMAND"
+            | "CONFIG"
+            | "DEBUG"
+            | "HELLO"
+            | "HRANDFIELD"
+            | "INFO"
+            | "LASTSAVE"
+            | "LATENCY"
+            | "MEMORY"
+            | "MODULE"
+            | "OBJECT"
+            | "RANDOMKEY"
+            | "ROLE"
+            | "SCAN"
+            | "SLOWLOG"
+            | "SPOP"
+            | "SRANDMEMBER"
+            | "TIME"
+            | "ZRANDMEMBER"
+    )
+}
+
+/// A request in flight to both targets, and a channel for the primary's complete reply
+struct Comparison {
+    request: BytesFrame,
+    primary: oneshot::Receiver<Vec<BytesFrame>>,
+}
+
+pub struct CanaryLayer {
+    canary: mpsc::Sender<Comparison>,
+    stats: Arc<Stats>,
+}
+
+impl CanaryLayer {
+    /// Compare replies against `canary`, which is driven by a background task until this layer
+    /// and every service built from it have been dropped
+    pub fn new<T>(canary: T, stats: Arc<Stats>) -> Self
+    where
+        T: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+        T::Error: Into<Error>,
+        T::Future: Send,
+    {
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(drive_canary(canary, receiver, stats.clone()));
+        Self {
+            canary: sender,
+            stats,
+        }
+    }
+}
+
+/// Send each queued request to `canary` in turn and compare its reply with the primary's
+async fn drive_canary<T>(
+    mut canary: T,
+    mut comparisons: mpsc::Receiver<Comparison>,
+    stats: Arc<Stats>,
+) where
+    T: Service<BytesFrame, Response = ResponseStream>,
+    T::Error: Into<Error>,
+    T::Future: Send,
+{
+    while let Some(Comparison { request, primary }) = comparisons.recv().await {
+        let name = command::name(&request).unwrap_or_default();
+        let reply = match canary.ready().await.map_err(Into::<Error>::into) {
+            Ok(canary) => canary.call(request.clone()).map_err(Into::into).await,
+            Err(e) => Err(e),
+        };
+        let canary_frames = match reply {
+            Ok(stream) => stream.collect::<Vec<_>>().await,
+            Err(e) => {
+                log::warn!("Canary failed to dispatch {name}: {e}");
+                vec![]
+            }
+        };
+        // The client went away before the primary finished replying; nothing to compare
+        let Ok(primary_frames) = primary.await else {
+            continue;
+        };
+
+        let Some(summary) = diff_replies(&primary_frames, &canary_frames) else {
+            stats.canary.record_match();
+            continue;
+        };
+        let key = command::first_key(&request).cloned();
+        log::info!(
+            "Canary mismatch for {name} {}: {summary}",
+            key.as_ref()
+                .map(|k| String::from_utf8_lossy(k).into_owned())
+                .unwrap_or_default()
+        );
+        stats.canary.record_mismatch(CanaryMismatch {
+            command: name,
+            key,
+            summary,
+        });
+    }
+}
+
+/// Where two replies first diverge, if they do
+fn diff_replies(primary: &[BytesFrame], canary: &[BytesFrame]) -> Option<String> {
+    if primary.len() != canary.len() {
+        return Some(format!(
+            "primary sent {} frame(s), canary sent {}",
+            primary.len(),
+            canary.len()
+        ));
+    }
+    primary
+        .iter()
+        .zip(canary)
+        .enumerate()
+        .find_map(|(i, (p, c))| {
+            frame::diff(p, c).map(|diff| format!("frame {i} (primary vs canary): {diff}"))
+        })
+}
+
+impl<S> Layer<S> for CanaryLayer {
+    type Service = Canary<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Canary {
+            inner: service,
+            canary: self.canary.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+pub struct Canary<S> {
+    inner: S,
+    canary: mpsc::Sender<Comparison>,
+    stats: Arc<Stats>,
+}
+
+impl<S> Service<BytesFrame> for Canary<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let skipped = command::starts_push_mode(&req)
+            || command::is_blocking(&req)
+            || command::name(&req).is_none_or(|name| is_nondeterministic(&name));
+        if skipped {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let comparison = Comparison {
+            request: req.clone(),
+            primary: receiver,
+        };
+        if self.canary.try_send(comparison).is_err() {
+            self.stats.canary.record_dropped();
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        Box::pin(async move {
+            let stream = fut.await?;
+            // Pass the primary's frames through, keeping copies to hand over once it's done
+            let teed = futures::stream::unfold(
+                (stream, Vec::new(), sender),
+                |(mut stream, mut frames, sender)| async move {
+                    match stream.next().await {
+                        Some(frame) => {
+                            frames.push(frame.clone());
+                            Some((frame, (stream, frames, sender)))
+                        }
+                        None => {
+                            let _ = sender.send(frames);
+                            None
+                        }
+                    }
+                },
+            );
+            Ok(teed.boxed())
+        })
+    }
+}
@@ -0,0 +1,103 @@
+//! Traffic capture.
+//!
+//! `CaptureLayer` records every request a client sends and every response frame it receives into
+//! a shared `Capture`, tagged with the connection's ID, for offline analysis and replay. Sensitive
+//! values are masked by `RedactionRules` (by default, the credentials of `AUTH`, `HELLO`, ...)
+//! before anything is written, so a replayed capture authenticates with the redaction marker.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::capture::{Capture, Direction};
+use crate::error::Error;
+use crate::middleware::redact::{self, RedactionRules};
+use crate::service::ResponseStream;
+
+pub struct CaptureLayer {
+    capture: Capture,
+    connection_id: Uuid,
+    redaction: Arc<RedactionRules>,
+}
+
+impl CaptureLayer {
+    pub fn new(capture: Capture, connection_id: Uuid) -> Self {
+        Self {
+            capture,
+            connection_id,
+            redaction: Arc::new(RedactionRules::default()),
+        }
+    }
+
+    /// Mask sensitive values according to `redaction` rather than the default rules
+    pub fn with_redaction(mut self, redaction: impl Into<Arc<RedactionRules>>) -> Self {
+        self.redaction = redaction.into();
+        self
+    }
+}
+
+impl<S> Layer<S> for CaptureLayer {
+    type Service = Recorder<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Recorder {
+            inner: service,
+            capture: self.capture.clone(),
+            connection_id: self.connection_id,
+            redaction: self.redaction.clone(),
+        }
+    }
+}
+
+pub struct Recorder<S> {
+    inner: S,
+    capture: Capture,
+    connection_id: Uuid,
+    redaction: Arc<RedactionRules>,
+}
+
+impl<S> Service<BytesFrame> for Recorder<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        self.capture.record(
+            self.connection_id,
+            Direction::Request,
+            &self.redaction.request(&req),
+        );
+        let masks_reply = self.redaction.masks_reply(&req);
+        let capture = self.capture.clone();
+        let connection_id = self.connection_id;
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let stream = fut.await.map_err(Into::into)?;
+            Ok(stream
+                .inspect(move |frame| {
+                    if masks_reply {
+                        capture.record(connection_id, Direction::Response, &redact::reply(frame));
+                    } else {
+                        capture.record(connection_id, Direction::Response, frame);
+                    }
+                })
+                .boxed())
+        })
+    }
+}
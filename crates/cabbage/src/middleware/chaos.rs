@@ -0,0 +1,395 @@
+//! Fault injection for resilience testing.
+//!
+//! `LatencyInjectionLayer` delays the replies to a percentage of commands, as though the target
+//! were slow to answer them, according to the first `DelayRule` matching each command. The
+//! command is still forwarded at once and replies keep their order: a delayed reply holds back
+//! those pipelined behind it, as it would coming from a slow Redis. The delay counts towards the
+//! proxy's latency statistics and its timeouts.
+//!
+//! `ErrorInjectionLayer` answers a percentage of commands with a synthetic error (`-LOADING`,
+//! `-READONLY`, ...) instead of forwarding them, or resets the client's connection, according to
+//! the first `ErrorRule` matching each command. Its rules live in an `ErrorInjection` shared by
+//! every connection, which `CABBAGE.FAULTS` can switch on and off or change at runtime.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use rand::Rng as _;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// Delay the replies to `percent` of the commands named `command` (or of every command) by
+/// between `min` and `max`, parsed from `COMMAND=PERCENT:MS[-MAX_MS]` (`*` for every command)
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DelayRule {
+    command: Option<String>,
+    percent: f64,
+    min: Duration,
+    max: Duration,
+}
+
+impl DelayRule {
+    fn matches(&self, name: Option<&str>) -> bool {
+        match &self.command {
+            Some(command) => name == Some(command.as_str()),
+            None => true,
+        }
+    }
+
+    /// A delay for one command, if it's among those delayed
+    fn sample(&self) -> Option<Duration> {
+        let mut rng = rand::thread_rng();
+        if rng.r#gen::<f64>() * 100.0 >= self.percent {
+            return None;
+        }
+        Some(if self.min < self.max {
+            rng.gen_range(self.min..=self.max)
+        } else {
+            self.min
+        })
+    }
+}
+
+impl std::str::FromStr for DelayRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, delay)) = s.split_once('=') else {
+            bail!(
+                Config,
+                "Delay rule '{s}' should be COMMAND=PERCENT:MS[-MAX_MS]"
+            );
+        };
+        let Some((percent, millis)) = delay.split_once(':') else {
+            bail!(
+                Config,
+                "Delay rule '{s}' should be COMMAND=PERCENT:MS[-MAX_MS]"
+            );
+        };
+        let percent: f64 =
+            percent.trim().trim_end_matches('%').parse().map_err(|_| {
+                Error::Config(format!("Invalid percentage '{percent}' in delay rule"))
+            })?;
+        if !(0.0..=100.0).contains(&percent) {
+            bail!(
+                Config,
+                "The percentage in delay rule '{s}' must be between 0 and 100"
+            );
+        }
+        let parse_ms = |ms: &str| {
+            ms.trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| Error::Config(format!("Invalid delay '{ms}' in delay rule")))
+        };
+        let (min, max) = match millis.split_once('-') {
+            Some((min, max)) => (parse_ms(min)?, parse_ms(max)?),
+            None => (parse_ms(millis)?, parse_ms(millis)?),
+        };
+        if min > max {
+            bail!(Config, "The delay range in delay rule '{s}' is backwards");
+        }
+        let command = match command.trim() {
+            "*" => None,
+            command => Some(command.to_ascii_uppercase()),
+        };
+        Ok(Self {
+            command,
+            percent,
+            min,
+            max,
+        })
+    }
+}
+
+impl TryFrom<String> for DelayRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+pub struct LatencyInjectionLayer {
+    rules: Vec<DelayRule>,
+}
+
+impl LatencyInjectionLayer {
+    pub fn new(rules: Vec<DelayRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl<S> Layer<S> for LatencyInjectionLayer {
+    type Service = LatencyInjection<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LatencyInjection {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+pub struct LatencyInjection<S> {
+    inner: S,
+    rules: Vec<DelayRule>,
+}
+
+impl<S> Service<BytesFrame> for LatencyInjection<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        let delay = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(name.as_deref()))
+            .and_then(DelayRule::sample);
+        let fut = self.inner.call(req).map_err(Into::into);
+        let Some(delay) = delay else {
+            return Box::pin(fut);
+        };
+        Box::pin(fut.map_ok(move |responses| {
+            futures::stream::once(async move {
+                tokio::time::sleep(delay).await;
+                responses
+            })
+            .flatten()
+            .boxed()
+        }))
+    }
+}
+
+/// What an `ErrorRule` does to the commands it picks
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// Answer with this error
+    Error(String),
+    /// Close the client's connection without answering
+    Reset,
+}
+
+impl Fault {
+    fn parse(s: &str) -> Self {
+        let message = match s.trim().to_ascii_uppercase().as_str() {
+            "RESET" => return Self::Reset,
+            "LOADING" => "LOADING Redis is loading the dataset in memory",
+            "READONLY" => "READONLY You can't write against a read only replica.",
+            "BUSY" => concat!(
+                "BUSY Redis is busy running a script. ",
+                "You can only call SCRIPT KILL or FUNCTION KILL."
+            ),
+            "MASTERDOWN" => concat!(
+                "MASTERDOWN Link with MASTER is down ",
+                "and replica-serve-stale-data is set to 'no'."
+            ),
+            "OOM" => "OOM command not allowed when used memory > 'maxmemory'.",
+            _ => s.trim(),
+        };
+        Self::Error(message.to_string())
+    }
+}
+
+/// Inflict `fault` on `percent` of the commands named `command` (or of every command), parsed
+/// from `COMMAND=PERCENT:FAULT` (`*` for every command), where `FAULT` is `LOADING`, `READONLY`,
+/// `BUSY`, `MASTERDOWN`, `OOM`, `RESET`, or the text of any other error
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ErrorRule {
+    command: Option<String>,
+    percent: f64,
+    fault: Fault,
+    /// The rule as given, for listing
+    spec: String,
+}
+
+impl ErrorRule {
+    fn matches(&self, name: Option<&str>) -> bool {
+        match &self.command {
+            Some(command) => name == Some(command.as_str()),
+            None => true,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl std::str::FromStr for ErrorRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, fault)) = s.split_once('=') else {
+            bail!(Config, "Error rule '{s}' should be COMMAND=PERCENT:FAULT");
+        };
+        let Some((percent, fault)) = fault.split_once(':') else {
+            bail!(Config, "Error rule '{s}' should be COMMAND=PERCENT:FAULT");
+        };
+        let percent: f64 =
+            percent.trim().trim_end_matches('%').parse().map_err(|_| {
+                Error::Config(format!("Invalid percentage '{percent}' in error rule"))
+            })?;
+        if !(0.0..=100.0).contains(&percent) {
+            bail!(
+                Config,
+                "The percentage in error rule '{s}' must be between 0 and 100"
+            );
+        }
+        if fault.trim().is_empty() {
+            bail!(Config, "Error rule '{s}' has no fault");
+        }
+        let command = match command.trim() {
+            "*" => None,
+            command => Some(command.to_ascii_uppercase()),
+        };
+        Ok(Self {
+            command,
+            percent,
+            fault: Fault::parse(fault),
+            spec: s.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for ErrorRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The error rules in force across every connection, and whether they're being applied
+#[derive(Debug, Default)]
+pub struct ErrorInjection {
+    enabled: AtomicBool,
+    rules: RwLock<Vec<ErrorRule>>,
+}
+
+impl ErrorInjection {
+    /// Apply `rules`, enabled if there are any
+    pub fn new(rules: Vec<ErrorRule>) -> Arc<Self> {
+        Arc::new(Self {
+            enabled: AtomicBool::new(!rules.is_empty()),
+            rules: RwLock::new(rules),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn rules(&self) -> Vec<ErrorRule> {
+        self.rules.read().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    /// Add a rule after the existing ones
+    pub fn add(&self, rule: ErrorRule) {
+        if let Ok(mut rules) = self.rules.write() {
+            rules.push(rule);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut rules) = self.rules.write() {
+            rules.clear();
+        }
+    }
+
+    /// The fault to inflict on a command named `name`, if any
+    fn pick(&self, name: Option<&str>) -> Option<Fault> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let rules = self.rules.read().ok()?;
+        let rule = rules.iter().find(|rule| rule.matches(name))?;
+        (rand::random::<f64>() * 100.0 < rule.percent).then(|| rule.fault.clone())
+    }
+}
+
+pub struct ErrorInjectionLayer {
+    injection: Arc<ErrorInjection>,
+}
+
+impl ErrorInjectionLayer {
+    pub fn new(injection: Arc<ErrorInjection>) -> Self {
+        Self { injection }
+    }
+}
+
+impl<S> Layer<S> for ErrorInjectionLayer {
+    type Service = ErrorInjector<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ErrorInjector {
+            inner: service,
+            injection: self.injection.clone(),
+        }
+    }
+}
+
+pub struct ErrorInjector<S> {
+    inner: S,
+    injection: Arc<ErrorInjection>,
+}
+
+impl<S> Service<BytesFrame> for ErrorInjector<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        match self.injection.pick(name.as_deref()) {
+            None => Box::pin(self.inner.call(req).map_err(Into::into)),
+            Some(Fault::Error(message)) => {
+                Box::pin(async move { Ok(reply(command::error(message))) })
+            }
+            Some(Fault::Reset) => {
+                Box::pin(async { bail!(BackendUnavailable, "Injected connection reset") })
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Value checksums.
+//!
+//! `ChecksumLayer` appends a trailer to the whole string values stored by `SET`-family commands
+//! before they reach the target: the value's CRC32 (big-endian), then the magic `\0CK` and a
+//! format version (1), eight bytes in all. Values in replies to `GET`-family commands are checked
+//! against their trailers, which are stripped before clients see them, so bit-rot or truncation
+//! introduced anywhere between the proxy and storage is caught rather than served. A value which
+//! fails its check is logged, counted in `Stats::checksums`, and replaced in the reply by an
+//! error. Checksums may be limited to keys under given prefixes.
+//!
+//! Values without a trailer (stored before checksums were turned on, or by clients not going
+//! through the proxy) are passed through untouched, unless trailers are required, when they fail
+//! their check too: a value cut short loses its trailer along with its end. As with compression
+//! and encryption, commands working on part of a value (`APPEND`, `GETRANGE`, `STRLEN`, ...) and
+//! `GET`s inside a transaction see the stored bytes.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+static MAGIC: &[u8; 4] = b"\0CK\x01";
+static TRAILER_LEN: usize = 4 + 4;
+
+fn with_trailer(value: &[u8]) -> Bytes {
+    let mut out = BytesMut::with_capacity(value.len() + TRAILER_LEN);
+    out.put_slice(value);
+    out.put_u32(crc32fast::hash(value));
+    out.put_slice(MAGIC);
+    out.freeze()
+}
+
+/// The value `stored` holds without its trailer, or `None` if it has none. Fails with the
+/// checksum the value has if it doesn't match its trailer's.
+fn verify(stored: &Bytes) -> Option<Result<Bytes, u32>> {
+    if stored.len() < TRAILER_LEN || !stored.ends_with(MAGIC) {
+        return None;
+    }
+    let value_len = stored.len() - TRAILER_LEN;
+    let expected = u32::from_be_bytes(stored[value_len..value_len + 4].try_into().ok()?);
+    let actual = crc32fast::hash(&stored[..value_len]);
+    Some(if actual == expected {
+        Ok(stored.slice(..value_len))
+    } else {
+        Err(actual)
+    })
+}
+
+#[derive(Clone)]
+pub struct ChecksumLayer {
+    prefixes: Arc<[Bytes]>,
+    required: bool,
+    stats: Arc<Stats>,
+}
+
+impl ChecksumLayer {
+    /// Check values, counting what's checked in `stats`
+    pub fn new(stats: Arc<Stats>) -> Self {
+        stats.checksums.set_checking();
+        Self {
+            prefixes: Arc::new([]),
+            required: false,
+            stats,
+        }
+    }
+
+    /// Only check values of keys starting with one of `prefixes` (by default, every value is)
+    pub fn with_prefixes(mut self, prefixes: &[String]) -> Self {
+        self.prefixes = prefixes
+            .iter()
+            .map(|prefix| Bytes::copy_from_slice(prefix.as_bytes()))
+            .collect();
+        self
+    }
+
+    /// Fail the check of any value without a trailer, rather than passing it through
+    pub fn requiring_trailers(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    fn covers(&self, key: &[u8]) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// The value of `key` in a reply, checked against its trailer
+    fn check(&self, key: Option<&Bytes>, frame: BytesFrame) -> BytesFrame {
+        let BytesFrame::BulkString(stored) = frame else {
+            return frame;
+        };
+        let Some(key) = key.filter(|key| self.covers(key)) else {
+            return BytesFrame::BulkString(stored);
+        };
+        let checksums = &self.stats.checksums;
+        let key = String::from_utf8_lossy(key);
+        match verify(&stored) {
+            Some(Ok(value)) => {
+                checksums.record_verified();
+                BytesFrame::BulkString(value)
+            }
+            Some(Err(actual)) => {
+                checksums.record_corrupted();
+                log::warn!(
+                    "Value of '{key}' doesn't match its checksum ({actual:08x} stored as {:02x?})",
+                    &stored[stored.len() - TRAILER_LEN..stored.len() - 4]
+                );
+                command::error(format!("ERR value of '{key}' failed its checksum"))
+            }
+            None if self.required => {
+                checksums.record_corrupted();
+                log::warn!("Value of '{key}' has no checksum trailer, so may be truncated");
+                command::error(format!("ERR value of '{key}' has no checksum"))
+            }
+            None => {
+                checksums.record_unchecked();
+                BytesFrame::BulkString(stored)
+            }
+        }
+    }
+
+    /// A reply carrying the values of `keys`, each checked against its trailer
+    fn check_reply(&self, keys: &[Bytes], frame: BytesFrame) -> BytesFrame {
+        match frame {
+            BytesFrame::Array(items) => BytesFrame::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, item)| self.check(keys.get(i), item))
+                    .collect(),
+            ),
+            frame => self.check(keys.first(), frame),
+        }
+    }
+}
+
+impl<S> Layer<S> for ChecksumLayer {
+    type Service = Checksums<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Checksums {
+            inner: service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub struct Checksums<S> {
+    inner: S,
+    layer: ChecksumLayer,
+}
+
+impl<S> Checksums<S> {
+    /// Append trailers to the values `req` stores
+    fn append_trailers(&self, req: BytesFrame) -> BytesFrame {
+        let values = command::stored_values(&req);
+        let BytesFrame::Array(mut parts) = req else {
+            return req;
+        };
+        for (value_at, key_at) in values {
+            let (Some(key), Some(value)) = (
+                parts.get(key_at).and_then(command::arg_bytes),
+                parts.get(value_at).and_then(command::arg_bytes),
+            ) else {
+                continue;
+            };
+            if self.layer.covers(key) {
+                parts[value_at] = BytesFrame::BulkString(with_trailer(value));
+            }
+        }
+        BytesFrame::Array(parts)
+    }
+}
+
+impl<S> Service<BytesFrame> for Checksums<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let keys = command::returns_stored_values(&req).then(|| command::reply_value_keys(&req));
+        let req = self.append_trailers(req);
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        let Some(keys) = keys else {
+            return Box::pin(fut);
+        };
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            Ok(fut
+                .await?
+                .map(move |frame| layer.check_reply(&keys, frame))
+                .boxed())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::CallOne as _;
+    use crate::testing::MockRedis;
+
+    fn bulk(s: &str) -> BytesFrame {
+        BytesFrame::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn trailers_round_trip() {
+        let stored = with_trailer(b"hello");
+        assert_eq!(stored.len(), 5 + TRAILER_LEN);
+        assert_eq!(verify(&stored), Some(Ok(Bytes::from_static(b"hello"))));
+        assert_eq!(verify(&with_trailer(b"")), Some(Ok(Bytes::new())));
+    }
+
+    #[test]
+    fn corruption_is_caught() {
+        let mut corrupted = BytesMut::from(&with_trailer(b"hello")[..]);
+        corrupted[1] ^= 0x01;
+        let corrupted = corrupted.freeze();
+        assert_eq!(verify(&corrupted), Some(Err(crc32fast::hash(b"hdllo"))));
+
+        // Losing the start of a value is caught too, where losing its end loses the trailer
+        let truncated = with_trailer(b"hello").slice(1..);
+        assert!(matches!(verify(&truncated), Some(Err(_))));
+    }
+
+    #[test]
+    fn values_without_trailers_are_unverified() {
+        assert_eq!(verify(&Bytes::from_static(b"hello")), None);
+        assert_eq!(verify(&with_trailer(b"hello").slice(..10)), None);
+    }
+
+    #[tokio::test]
+    async fn values_are_stored_with_trailers_and_read_back() -> crate::Result<()> {
+        let stats = Stats::new();
+        let mock = MockRedis::new();
+        let mut service = ChecksumLayer::new(stats.clone()).layer(mock.clone());
+        service
+            .call_one(command::request(["SET", "greeting", "hello"]))
+            .await?;
+        assert_eq!(mock.get("greeting"), Some(with_trailer(b"hello")));
+
+        let value = service
+            .call_one(command::request(["GET", "greeting"]))
+            .await?;
+        assert_eq!(value, bulk("hello"));
+        assert_eq!(stats.checksums.verified(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn values_without_trailers_pass_unless_trailers_are_required() -> crate::Result<()> {
+        let mock = MockRedis::new();
+        mock.execute(&command::request(["SET", "greeting", "hello"]));
+        let get = || command::request(["GET", "greeting"]);
+
+        let stats = Stats::new();
+        let value = ChecksumLayer::new(stats.clone())
+            .layer(mock.clone())
+            .call_one(get())
+            .await?;
+        assert_eq!(value, bulk("hello"));
+        assert_eq!(stats.checksums.unchecked(), 1);
+
+        let stats = Stats::new();
+        let value = ChecksumLayer::new(stats.clone())
+            .requiring_trailers()
+            .layer(mock)
+            .call_one(get())
+            .await?;
+        assert!(matches!(value, BytesFrame::Error(_)), "{value:?}");
+        assert_eq!(stats.checksums.corrupted(), 1);
+        Ok(())
+    }
+}
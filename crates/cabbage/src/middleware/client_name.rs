@@ -0,0 +1,149 @@
+//! Client names.
+//!
+//! `ClientNameLayer` intercepts `CLIENT SETNAME` so the name a client gives itself is recorded
+//! against its proxy connection, for the logs and `CABBAGE.CONNECTIONS`, and forwards it to the
+//! target as `cabbage:<connection-id>:<name>` so the target's `CLIENT LIST` can be correlated
+//! with the proxy's logs. `CLIENT GETNAME` is answered with the name the client set rather than
+//! the augmented one. Other `CLIENT` subcommands (`LIST`, `INFO`) still show the target's view.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+/// The name a client connection has set for itself, shared with the layers which report it
+#[derive(Clone, Debug, Default)]
+pub struct ClientName(Arc<Mutex<Option<String>>>);
+
+impl ClientName {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().ok().and_then(|name| name.clone())
+    }
+
+    fn set(&self, name: Option<String>) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = name;
+        }
+    }
+}
+
+pub struct ClientNameLayer {
+    connection_id: Uuid,
+    name: ClientName,
+    stats: Arc<Stats>,
+}
+
+impl ClientNameLayer {
+    pub fn new(connection_id: Uuid, name: ClientName, stats: Arc<Stats>) -> Self {
+        Self {
+            connection_id,
+            name,
+            stats,
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientNameLayer {
+    type Service = ClientNaming<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ClientNaming {
+            inner: service,
+            connection_id: self.connection_id,
+            name: self.name.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+pub struct ClientNaming<S> {
+    inner: S,
+    connection_id: Uuid,
+    name: ClientName,
+    stats: Arc<Stats>,
+}
+
+impl<S> Service<BytesFrame> for ClientNaming<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if command::name(&req).as_deref() != Some("CLIENT") {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+        let args = command::args(&req).unwrap_or_default();
+        let subcommand = args
+            .get(1)
+            .and_then(command::arg_bytes)
+            .map(|s| String::from_utf8_lossy(s).to_ascii_uppercase());
+        match (subcommand.as_deref(), &args[1..]) {
+            (Some("GETNAME"), [_]) => {
+                let name = match self.name.get() {
+                    Some(name) => BytesFrame::BulkString(name.into()),
+                    None => BytesFrame::Null,
+                };
+                Box::pin(async move { Ok(reply(name)) })
+            }
+            (Some("SETNAME"), [_, name]) => {
+                let Some(name) = command::arg_bytes(name) else {
+                    return Box::pin(self.inner.call(req).map_err(Into::into));
+                };
+                // An empty name clears the client's name, but the target keeps the connection ID
+                let name = String::from_utf8_lossy(name).into_owned();
+                let forwarded = if name.is_empty() {
+                    format!("cabbage:{}", self.connection_id)
+                } else {
+                    format!("cabbage:{}:{name}", self.connection_id)
+                };
+                let fut = self
+                    .inner
+                    .call(command::request(["CLIENT", "SETNAME", forwarded.as_str()]))
+                    .map_err(Into::into);
+
+                let shared = self.name.clone();
+                let stats = self.stats.clone();
+                let connection_id = self.connection_id;
+                Box::pin(async move {
+                    let name = (!name.is_empty()).then_some(name);
+                    Ok(fut
+                        .await?
+                        .inspect(move |frame| {
+                            if matches!(frame, BytesFrame::SimpleString(ok) if ok == "OK") {
+                                shared.set(name.clone());
+                                stats.connections.set_name(connection_id, name.clone());
+                            }
+                        })
+                        .boxed())
+                })
+            }
+            _ => Box::pin(self.inner.call(req).map_err(Into::into)),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Caching of the target's command table.
+//!
+//! Many clients ask for the target's command table (`COMMAND`, or `COMMAND DOCS`) on every
+//! connection, and the reply runs to hundreds of kilobytes. `CommandReplies`, shared by every
+//! connection, keeps the target's replies to `COMMAND` and its `COUNT`, `DOCS`, `INFO`, and
+//! `LIST` subcommands for a time-to-live, and `CommandCacheLayer` answers them from it when it
+//! can, filling it from the target's replies when it can't. The table only changes when the
+//! target is upgraded or replaced, so the cache is also cleared whenever a health check fails,
+//! as the target may be restarting or failing over (without health checks, only the
+//! time-to-live bounds how long an old table is served). Commands queued in a transaction are
+//! always forwarded. Hits, misses, and invalidations are counted in `Stats::command_cache`.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+/// Distinct requests cached, beyond which further ones are forwarded without being cached
+static MAX_ENTRIES: usize = 1024;
+
+struct Cached {
+    reply: BytesFrame,
+    expires: Instant,
+}
+
+struct Entries {
+    by_request: HashMap<Vec<Bytes>, Cached>,
+    /// Failed health checks when the entries were filled
+    failed_checks: u64,
+}
+
+/// The target's replies to `COMMAND` requests, shared by every connection
+pub struct CommandReplies {
+    ttl: Duration,
+    stats: Arc<Stats>,
+    entries: Mutex<Entries>,
+}
+
+impl CommandReplies {
+    /// Keep replies for `ttl`, or until a health check recorded in `stats` fails
+    pub fn new(ttl: Duration, stats: Arc<Stats>) -> Self {
+        let failed_checks = stats.backend.failed_health_checks();
+        Self {
+            ttl,
+            stats,
+            entries: Mutex::new(Entries {
+                by_request: HashMap::new(),
+                failed_checks,
+            }),
+        }
+    }
+
+    /// The entries, cleared first if a health check has failed since they were filled
+    fn entries(&self) -> Option<MutexGuard<'_, Entries>> {
+        let mut entries = self.entries.lock().ok()?;
+        let failed_checks = self.stats.backend.failed_health_checks();
+        if entries.failed_checks != failed_checks {
+            entries.failed_checks = failed_checks;
+            if !entries.by_request.is_empty() {
+                entries.by_request.clear();
+                self.stats.command_cache.record_invalidation();
+            }
+        }
+        Some(entries)
+    }
+
+    fn lookup(&self, request: &[Bytes]) -> Option<BytesFrame> {
+        let mut entries = self.entries()?;
+        let expired = entries.by_request.get(request)?.expires <= Instant::now();
+        if expired {
+            entries.by_request.remove(request);
+            return None;
+        }
+        entries
+            .by_request
+            .get(request)
+            .map(|cached| cached.reply.clone())
+    }
+
+    /// Cache `reply` to `request`, unless a health check has failed since `failed_checks` were
+    fn fill(&self, request: Vec<Bytes>, reply: BytesFrame, failed_checks: u64) {
+        let Some(mut entries) = self.entries() else {
+            return;
+        };
+        if entries.failed_checks != failed_checks {
+            return;
+        }
+        let now = Instant::now();
+        if entries.by_request.len() >= MAX_ENTRIES {
+            entries.by_request.retain(|_, cached| cached.expires > now);
+            if entries.by_request.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.by_request.insert(
+            request,
+            Cached {
+                reply,
+                expires: now + self.ttl,
+            },
+        );
+    }
+}
+
+/// Answers `COMMAND` requests from a shared `CommandReplies` where it can
+#[derive(Clone)]
+pub struct CommandCacheLayer {
+    replies: Arc<CommandReplies>,
+}
+
+impl CommandCacheLayer {
+    pub fn new(replies: Arc<CommandReplies>) -> Self {
+        Self { replies }
+    }
+}
+
+impl<S> Layer<S> for CommandCacheLayer {
+    type Service = CommandCache<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CommandCache {
+            inner: service,
+            replies: self.replies.clone(),
+            in_transaction: false,
+        }
+    }
+}
+
+pub struct CommandCache<S> {
+    inner: S,
+    replies: Arc<CommandReplies>,
+    in_transaction: bool,
+}
+
+impl<S> CommandCache<S> {
+    /// What `req` is cached as, if its reply may be: its arguments, with the command and
+    /// subcommand names uppercased
+    fn cache_key(&mut self, req: &BytesFrame) -> Option<Vec<Bytes>> {
+        let name = command::name(req)?;
+        match name.as_str() {
+            "MULTI" => self.in_transaction = true,
+            "EXEC" | "DISCARD" => self.in_transaction = false,
+            _ => {}
+        }
+        if name != "COMMAND" || self.in_transaction {
+            return None;
+        }
+        let args = command::args(req)?
+            .iter()
+            .map(command::arg_bytes)
+            .collect::<Option<Vec<_>>>()?;
+        let subcommand = args.get(1).map(|sub| sub.to_ascii_uppercase());
+        if !matches!(
+            subcommand.as_deref(),
+            None | Some(b"COUNT" | b"DOCS" | b"INFO" | b"LIST")
+        ) {
+            return None;
+        }
+        let mut key = vec![Bytes::from_static(b"COMMAND")];
+        key.extend(subcommand.map(Bytes::from));
+        key.extend(args.into_iter().skip(2).cloned());
+        Some(key)
+    }
+}
+
+impl<S> Service<BytesFrame> for CommandCache<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let Some(key) = self.cache_key(&req) else {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+        let replies = self.replies.clone();
+        if let Some(cached) = replies.lookup(&key) {
+            replies.stats.command_cache.record_hit();
+            return Box::pin(async move { Ok(reply(cached)) });
+        }
+        replies.stats.command_cache.record_miss();
+        let failed_checks = replies.stats.backend.failed_health_checks();
+        let fut = self.inner.call(req).map_err(Into::into);
+        Box::pin(async move {
+            let stream = fut.await?;
+            Ok(stream
+                .inspect(move |frame| {
+                    if !matches!(frame, BytesFrame::Error(_)) {
+                        replies.fill(key.clone(), frame.clone(), failed_checks);
+                    }
+                })
+                .boxed())
+        })
+    }
+}
@@ -0,0 +1,259 @@
+//! Transparent value compression.
+//!
+//! `CompressionLayer` compresses the values written by `SET`-family commands (`SET`, `SETEX`,
+//! `PSETEX`, `SETNX`, `GETSET`, `MSET`, `MSETNX`) when their key matches a `CompressionRule` and
+//! they're at least its minimum size, and decompresses the values in replies to `GET`-family
+//! commands (`GET`, `GETDEL`, `GETEX`, `GETSET`, `MGET`, and `SET ... GET`). Compressed values
+//! start with an 8-byte header: the magic `\0CB`, the algorithm (1 for LZ4, 2 for Zstandard), and
+//! the uncompressed length (LE u32). Values without the header are passed through untouched, so
+//! compression can be enabled over existing data.
+//!
+//! Only whole values are handled: commands working on part of a value (`APPEND`, `GETRANGE`,
+//! `STRLEN`, `INCR`, ...) see the compressed bytes, as do `GET`s inside a transaction, whose
+//! replies arrive together with `EXEC`'s. Keys under compression should only be written whole.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::service::ResponseStream;
+
+static MAGIC: &[u8; 3] = b"\0CB";
+static HEADER_LEN: usize = 8;
+/// Redis's limit on the size of a string value
+static MAX_VALUE_LEN: usize = 512 * 1024 * 1024;
+/// Zstandard's default compression level
+static ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Lz4,
+    Zstd,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            _ => bail!(
+                Config,
+                "Unknown compression algorithm '{s}' (expected lz4 or zstd)"
+            ),
+        }
+    }
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Self::Lz4 => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Compress values of keys under `prefix` of at least `min_bytes`, parsed from
+/// `PREFIX=ALGORITHM[:MIN_BYTES]` (an empty prefix matches every key)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CompressionRule {
+    prefix: Bytes,
+    algorithm: Algorithm,
+    min_bytes: usize,
+}
+
+/// Values smaller than this aren't compressed unless a rule says otherwise
+static DEFAULT_MIN_BYTES: usize = 1024;
+
+impl std::str::FromStr for CompressionRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((prefix, compression)) = s.rsplit_once('=') else {
+            bail!(
+                Config,
+                "Compression rule '{s}' should be PREFIX=ALGORITHM[:MIN_BYTES]"
+            );
+        };
+        let (algorithm, min_bytes) = match compression.split_once(':') {
+            Some((algorithm, min_bytes)) => (
+                algorithm,
+                min_bytes.trim().parse().map_err(|_| {
+                    Error::Config(format!(
+                        "Invalid minimum size '{min_bytes}' in compression rule"
+                    ))
+                })?,
+            ),
+            None => (compression, DEFAULT_MIN_BYTES),
+        };
+        Ok(Self {
+            prefix: Bytes::copy_from_slice(prefix.as_bytes()),
+            algorithm: algorithm.trim().parse()?,
+            min_bytes,
+        })
+    }
+}
+
+impl TryFrom<String> for CompressionRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn compress(algorithm: Algorithm, value: &[u8]) -> Result<Bytes> {
+    let len = u32::try_from(value.len()).map_err(Error::other)?;
+    let compressed = match algorithm {
+        Algorithm::Lz4 => lz4_flex::block::compress(value),
+        Algorithm::Zstd => zstd::bulk::compress(value, ZSTD_LEVEL)?,
+    };
+    let mut out = BytesMut::with_capacity(HEADER_LEN + compressed.len());
+    out.put_slice(MAGIC);
+    out.put_u8(algorithm.id());
+    out.put_u32_le(len);
+    out.put_slice(&compressed);
+    Ok(out.freeze())
+}
+
+/// The original value, or `None` if `value` isn't compressed
+fn decompress(value: &Bytes) -> Option<Result<Bytes>> {
+    if value.len() < HEADER_LEN || !value.starts_with(MAGIC) {
+        return None;
+    }
+    let algorithm = Algorithm::from_id(value[3])?;
+    let len = u32::from_le_bytes([value[4], value[5], value[6], value[7]]) as usize;
+    if len > MAX_VALUE_LEN {
+        return Some(Err(Error::Format(format!(
+            "value claims to be {len} bytes uncompressed"
+        ))));
+    }
+    let compressed = &value[HEADER_LEN..];
+    let original = match algorithm {
+        Algorithm::Lz4 => lz4_flex::block::decompress(compressed, len).map_err(|e| e.to_string()),
+        Algorithm::Zstd => zstd::bulk::decompress(compressed, len).map_err(|e| e.to_string()),
+    };
+    Some(original.map(Bytes::from).map_err(Error::Format))
+}
+
+/// Undo compression of any values in a reply
+fn decompress_reply(frame: BytesFrame) -> BytesFrame {
+    match frame {
+        BytesFrame::BulkString(value) => match decompress(&value) {
+            None => BytesFrame::BulkString(value),
+            Some(Ok(original)) => BytesFrame::BulkString(original),
+            Some(Err(e)) => command::error(format!("ERR failed to decompress value: {e}")),
+        },
+        BytesFrame::Array(items) => {
+            BytesFrame::Array(items.into_iter().map(decompress_reply).collect())
+        }
+        frame => frame,
+    }
+}
+
+pub struct CompressionLayer {
+    rules: Arc<[CompressionRule]>,
+}
+
+impl CompressionLayer {
+    /// Compress values according to the first of `rules` matching their key
+    pub fn new(rules: impl Into<Arc<[CompressionRule]>>) -> Self {
+        Self {
+            rules: rules.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for CompressionLayer {
+    type Service = Compression<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Compression {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+pub struct Compression<S> {
+    inner: S,
+    rules: Arc<[CompressionRule]>,
+}
+
+impl<S> Compression<S> {
+    /// Compress the values `req` stores
+    fn compress_request(&self, req: BytesFrame) -> BytesFrame {
+        let values = command::stored_values(&req);
+        let BytesFrame::Array(mut parts) = req else {
+            return req;
+        };
+        for (value_at, key_at) in values {
+            let (Some(key), Some(value)) = (
+                parts.get(key_at).and_then(command::arg_bytes),
+                parts.get(value_at).and_then(command::arg_bytes),
+            ) else {
+                continue;
+            };
+            let Some(rule) = self.rules.iter().find(|rule| key.starts_with(&rule.prefix)) else {
+                continue;
+            };
+            if value.len() < rule.min_bytes {
+                continue;
+            }
+            match compress(rule.algorithm, value) {
+                Ok(compressed) => parts[value_at] = BytesFrame::BulkString(compressed),
+                Err(e) => log::warn!("Storing value uncompressed, compression failed: {e}"),
+            }
+        }
+        BytesFrame::Array(parts)
+    }
+}
+
+impl<S> Service<BytesFrame> for Compression<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let decompress = command::returns_stored_values(&req);
+        let req = self.compress_request(req);
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        if !decompress {
+            return Box::pin(fut);
+        }
+        Box::pin(async move { Ok(fut.await?.map(decompress_reply).boxed()) })
+    }
+}
@@ -0,0 +1,206 @@
+//! Per-command concurrency limits.
+//!
+//! `ConcurrencyLayer` caps how many of some commands may be executing at once, across every
+//! client connection, to protect the target from storms of expensive commands (`KEYS`, `SCAN`
+//! with a huge `COUNT`, `SORT`, ...). Each `ConcurrencyLimit` names a command and how many may
+//! await replies at once; commands without a limit pass straight through. A command arriving
+//! while its limit is reached is either rejected with a `-BUSY` error or held back until one of
+//! those executing has been answered. Since the proxy waits for each dispatch before reading the
+//! client's next command, holding one back holds up the whole connection, keeping its commands
+//! in order. A command holds its place until its replies have been forwarded.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// What to do with a command arriving while its limit is reached
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrencyMode {
+    /// Answer with a `-BUSY` error without forwarding the command
+    #[default]
+    Reject,
+    /// Forward the command once one of those executing has been answered
+    Queue,
+}
+
+impl std::str::FromStr for ConcurrencyMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(ConcurrencyMode::Reject),
+            "queue" => Ok(ConcurrencyMode::Queue),
+            _ => bail!(Config, "Unrecognized concurrency mode '{s}'"),
+        }
+    }
+}
+
+/// At most `max` of the command named `command` executing at once, parsed from `COMMAND=MAX`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ConcurrencyLimit {
+    pub command: String,
+    pub max: usize,
+}
+
+impl std::str::FromStr for ConcurrencyLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, max)) = s.split_once('=') else {
+            bail!(Config, "Concurrency limit '{s}' should be COMMAND=MAX");
+        };
+        let max: usize = max
+            .trim()
+            .parse()
+            .map_err(|_| Error::Config(format!("Invalid maximum '{max}' in concurrency limit")))?;
+        if max == 0 {
+            bail!(
+                Config,
+                "Concurrency limit '{s}' must allow at least one command"
+            );
+        }
+        let command = command.trim();
+        if command.is_empty() {
+            bail!(Config, "Concurrency limit '{s}' doesn't name a command");
+        }
+        Ok(Self {
+            command: command.to_ascii_uppercase(),
+            max,
+        })
+    }
+}
+
+impl TryFrom<String> for ConcurrencyLimit {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The commands executing under each limit, shared by every connection
+pub struct CommandConcurrency {
+    slots: HashMap<String, Arc<Semaphore>>,
+    mode: ConcurrencyMode,
+}
+
+impl CommandConcurrency {
+    pub fn new(limits: &[ConcurrencyLimit], mode: ConcurrencyMode) -> Self {
+        let slots = limits
+            .iter()
+            .map(|limit| (limit.command.clone(), Arc::new(Semaphore::new(limit.max))))
+            .collect();
+        Self { slots, mode }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLayer {
+    concurrency: Arc<CommandConcurrency>,
+}
+
+impl ConcurrencyLayer {
+    pub fn new(concurrency: Arc<CommandConcurrency>) -> Self {
+        Self { concurrency }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLayer {
+    type Service = ConcurrencyLimited<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimited {
+            inner: Arc::new(tokio::sync::Mutex::new(service)),
+            concurrency: self.concurrency.clone(),
+        }
+    }
+}
+
+/// The wrapped service is only made ready for a command once it may execute, within the
+/// command's future, so a queued command isn't overtaken by those behind it
+pub struct ConcurrencyLimited<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    concurrency: Arc<CommandConcurrency>,
+}
+
+/// Dispatch `req` to `inner` once it's ready, holding `slot` until its replies are done with
+async fn dispatch<S>(
+    inner: &tokio::sync::Mutex<S>,
+    req: BytesFrame,
+    slot: Option<OwnedSemaphorePermit>,
+) -> Result<ResponseStream>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error>,
+{
+    let fut = {
+        let mut inner = inner.lock().await;
+        futures::future::poll_fn(|cx| inner.poll_ready(cx))
+            .await
+            .map_err(Into::into)?;
+        inner.call(req)
+    };
+    let responses = fut.await.map_err(Into::into)?;
+    let Some(slot) = slot else {
+        return Ok(responses);
+    };
+    Ok(responses
+        .inspect(move |_| {
+            let _ = &slot;
+        })
+        .boxed())
+}
+
+impl<S> Service<BytesFrame> for ConcurrencyLimited<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let inner = self.inner.clone();
+        let Some((name, slots)) = command::name(&req)
+            .and_then(|name| Some((name.clone(), self.concurrency.slots.get(&name)?.clone())))
+        else {
+            return Box::pin(async move { dispatch(&inner, req, None).await });
+        };
+        match self.concurrency.mode {
+            ConcurrencyMode::Reject => match slots.try_acquire_owned() {
+                Ok(slot) => Box::pin(async move { dispatch(&inner, req, Some(slot)).await }),
+                Err(_) => Box::pin(async move {
+                    Ok(reply(command::error(format!(
+                        "BUSY too many {name} commands executing, try again later"
+                    ))))
+                }),
+            },
+            ConcurrencyMode::Queue => Box::pin(async move {
+                let slot = slots.acquire_owned().await.map_err(Error::other)?;
+                dispatch(&inner, req, Some(slot)).await
+            }),
+        }
+    }
+}
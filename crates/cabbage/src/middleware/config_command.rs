@@ -0,0 +1,224 @@
+//! Interception of `CONFIG`.
+//!
+//! `ConfigCommandLayer` gives clients a curated view of the target's configuration. `CONFIG GET`
+//! is forwarded, and the parameters the target reports are overlaid with a `ConfigView`'s
+//! overrides: values set by the operator, and those of settings the proxy enforces in the
+//! target's place (its idle timeout as `timeout`, and its connection limit as `maxclients`).
+//! Overridden parameters matching a pattern are reported even if the target has no such
+//! parameter. `CONFIG SET` is only forwarded if every parameter it sets matches one of the
+//! view's settable globs and none is overridden; otherwise it's refused whole, so with no
+//! settable globs clients can't reconfigure the target at all. Other `CONFIG` subcommands are
+//! forwarded unchanged.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, bail};
+use crate::middleware::reply;
+use crate::routing::glob_matches;
+use crate::service::ResponseStream;
+
+/// Report `value` for the parameter `parameter`, parsed from `NAME=VALUE`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ConfigOverride {
+    parameter: String,
+    value: String,
+}
+
+impl ConfigOverride {
+    pub fn new(parameter: &str, value: impl Into<String>) -> Self {
+        Self {
+            parameter: parameter.to_ascii_lowercase(),
+            value: value.into(),
+        }
+    }
+}
+
+impl std::str::FromStr for ConfigOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((parameter, value)) = s.split_once('=') else {
+            bail!(Config, "CONFIG override '{s}' should be NAME=VALUE");
+        };
+        let parameter = parameter.trim();
+        if parameter.is_empty() {
+            bail!(Config, "CONFIG override '{s}' doesn't name a parameter");
+        }
+        Ok(Self::new(parameter, value))
+    }
+}
+
+impl TryFrom<String> for ConfigOverride {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// What clients see of the target's configuration, and may change
+#[derive(Clone, Debug, Default)]
+pub struct ConfigView {
+    overrides: BTreeMap<String, String>,
+    settable: Vec<String>,
+}
+
+impl ConfigView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report the override's value for its parameter, whatever the target's is
+    pub fn with_override(mut self, config_override: ConfigOverride) -> Self {
+        self.overrides
+            .insert(config_override.parameter, config_override.value);
+        self
+    }
+
+    /// Let clients set the parameters matching `glob` on the target
+    pub fn with_settable(mut self, glob: &str) -> Self {
+        self.settable.push(glob.to_ascii_lowercase());
+        self
+    }
+
+    /// Why setting `parameter` is refused, if it is
+    fn refuses_set(&self, parameter: &str) -> Option<String> {
+        if self.overrides.contains_key(parameter) {
+            return Some(format!(
+                "ERR CONFIG parameter '{parameter}' is managed by this proxy"
+            ));
+        }
+        if !self
+            .settable
+            .iter()
+            .any(|glob| glob_matches(glob.as_bytes(), parameter.as_bytes()))
+        {
+            return Some(format!(
+                "ERR CONFIG SET of '{parameter}' is not allowed through this proxy"
+            ));
+        }
+        None
+    }
+
+    /// The target's reply to `CONFIG GET` of `patterns` with the overrides applied
+    fn overlay(&self, reply: BytesFrame, patterns: &[String]) -> BytesFrame {
+        let BytesFrame::Array(mut pairs) = reply else {
+            return reply;
+        };
+        let mut reported = vec![];
+        for pair in pairs.chunks_mut(2) {
+            let [BytesFrame::BulkString(parameter), value] = pair else {
+                continue;
+            };
+            let parameter = String::from_utf8_lossy(parameter).to_ascii_lowercase();
+            if let Some(overridden) = self.overrides.get(&parameter) {
+                *value = BytesFrame::BulkString(Bytes::from(overridden.clone()));
+            }
+            reported.push(parameter);
+        }
+        for (parameter, value) in &self.overrides {
+            let matched = patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern.as_bytes(), parameter.as_bytes()));
+            if matched && !reported.contains(parameter) {
+                pairs.push(BytesFrame::BulkString(Bytes::from(parameter.clone())));
+                pairs.push(BytesFrame::BulkString(Bytes::from(value.clone())));
+            }
+        }
+        BytesFrame::Array(pairs)
+    }
+}
+
+#[derive(Clone)]
+pub struct ConfigCommandLayer {
+    view: Arc<ConfigView>,
+}
+
+impl ConfigCommandLayer {
+    pub fn new(view: Arc<ConfigView>) -> Self {
+        Self { view }
+    }
+}
+
+impl<S> Layer<S> for ConfigCommandLayer {
+    type Service = ConfigCommand<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConfigCommand {
+            inner: service,
+            view: self.view.clone(),
+        }
+    }
+}
+
+pub struct ConfigCommand<S> {
+    inner: S,
+    view: Arc<ConfigView>,
+}
+
+impl<S> Service<BytesFrame> for ConfigCommand<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if command::name(&req).as_deref() != Some("CONFIG") {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+        // Parameters, and the patterns matching them, are case-insensitive
+        let args: Vec<String> = command::args(&req)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(command::arg_bytes)
+            .map(|arg| String::from_utf8_lossy(arg).to_ascii_lowercase())
+            .collect();
+        match args.get(1).map(String::as_str) {
+            Some("get") if args.len() > 2 => {
+                let patterns = args[2..].to_vec();
+                let view = self.view.clone();
+                let fut = self.inner.call(req).map_err(Into::into);
+                Box::pin(async move {
+                    Ok(fut
+                        .await?
+                        .map(move |frame| view.overlay(frame, &patterns))
+                        .boxed())
+                })
+            }
+            // Malformed requests are left to the target to answer
+            Some("set") if args.len() > 2 && args.len().is_multiple_of(2) => {
+                let refused = args[2..]
+                    .iter()
+                    .step_by(2)
+                    .find_map(|parameter| self.view.refuses_set(parameter));
+                match refused {
+                    Some(message) => Box::pin(async move { Ok(reply(command::error(message))) }),
+                    None => Box::pin(self.inner.call(req).map_err(Into::into)),
+                }
+            }
+            _ => Box::pin(self.inner.call(req).map_err(Into::into)),
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! Transparent value encryption.
+//!
+//! `EncryptionLayer` encrypts the whole string values stored by `SET`-family commands with
+//! AES-256-GCM before they reach the target, and decrypts them in replies to `GET`-family
+//! commands, so clients read and write plaintext while the target only ever holds ciphertext.
+//! Encryption may be limited to keys under given prefixes. Encrypted values are the magic `\0CE`,
+//! a format version (2), a random 12-byte nonce, and the ciphertext with its authentication tag.
+//! The key name is authenticated along with the value, so a ciphertext copied or renamed to
+//! another key fails authentication there. Values of format 1, which weren't bound to their keys,
+//! are still read. Values without the header are passed through untouched, so existing plaintext
+//! stays readable.
+//!
+//! A request whose value can't be encrypted is answered with an error rather than forwarded, and
+//! a stored value which fails authentication is replaced by an error in the reply. So that nothing
+//! is stored in the clear under an encrypted key, any other write to one is refused too, apart
+//! from those which store no value of the client's (`DEL`, `EXPIRE`, `GETDEL`, ...). That rules
+//! out other data types, partial updates (`APPEND`, `SETRANGE`, `INCR`, ...), scripts, and moving
+//! values between keys (`RENAME`, `COPY`). Reads working on part of a value (`GETRANGE`,
+//! `STRLEN`, ...) and `GET`s inside a transaction see the stored bytes.
+
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use aes_gcm::aead::{Aead as _, AeadCore as _, KeyInit as _, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+static MAGIC: &[u8; 3] = b"\0CE";
+static VERSION: u8 = 2;
+/// The format of values encrypted without their key names
+static UNBOUND_VERSION: u8 = 1;
+static NONCE_LEN: usize = 12;
+static HEADER_LEN: usize = 3 + 1 + 12;
+
+/// A 256-bit key, parsed from 64 hexadecimal digits
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+impl std::str::FromStr for EncryptionKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            bail!(
+                Config,
+                "An encryption key must be 64 hexadecimal digits (256 bits)"
+            );
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| {
+                Error::Config("An encryption key must be hexadecimal digits".to_string())
+            })?;
+        }
+        Ok(Self(key))
+    }
+}
+
+impl TryFrom<String> for EncryptionKey {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl EncryptionKey {
+    /// Read a key file, as written by a secrets manager or KMS agent: either the 32 raw key bytes
+    /// or 64 hexadecimal digits
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read(path).map_err(|e| {
+            Error::io(
+                format!("Failed to read encryption key file {}", path.display()),
+                e,
+            )
+        })?;
+        if let Ok(raw) = <[u8; 32]>::try_from(contents.as_slice()) {
+            return Ok(Self(raw));
+        }
+        std::str::from_utf8(&contents)
+            .map_err(|e| Error::Config(e.to_string()))
+            .and_then(str::parse)
+            .map_err(|e| {
+                Error::Config(format!(
+                    "Invalid encryption key file {}: {e}",
+                    path.display()
+                ))
+            })
+    }
+}
+
+/// Encrypt the value of `key`, binding it to the key name
+fn encrypt(cipher: &Aes256Gcm, key: &[u8], value: &[u8]) -> Result<Bytes> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: value,
+                aad: key,
+            },
+        )
+        .map_err(|_| Error::Other("encryption failed".into()))?;
+    let mut out = BytesMut::with_capacity(HEADER_LEN + ciphertext.len());
+    out.put_slice(MAGIC);
+    out.put_u8(VERSION);
+    out.put_slice(&nonce);
+    out.put_slice(&ciphertext);
+    Ok(out.freeze())
+}
+
+/// The plaintext of the value of `key`, or `None` if `value` isn't encrypted
+fn decrypt(cipher: &Aes256Gcm, key: Option<&Bytes>, value: &Bytes) -> Option<Result<Bytes>> {
+    if value.len() < HEADER_LEN || !value.starts_with(MAGIC) {
+        return None;
+    }
+    let aad: &[u8] = if value[3] == VERSION {
+        match key {
+            Some(key) => key,
+            None => {
+                return Some(Err(Error::Format(
+                    "no key to authenticate value against".to_string(),
+                )));
+            }
+        }
+    } else if value[3] == UNBOUND_VERSION {
+        &[]
+    } else {
+        return None;
+    };
+    let nonce = Nonce::from_slice(&value[4..4 + NONCE_LEN]);
+    Some(
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &value[HEADER_LEN..],
+                    aad,
+                },
+            )
+            .map(Bytes::from)
+            .map_err(|_| Error::Format("value failed authentication".to_string())),
+    )
+}
+
+/// Undo encryption of the value of `key` in a reply
+fn decrypt_value(cipher: &Aes256Gcm, key: Option<&Bytes>, frame: BytesFrame) -> BytesFrame {
+    let BytesFrame::BulkString(value) = frame else {
+        return frame;
+    };
+    match decrypt(cipher, key, &value) {
+        None => BytesFrame::BulkString(value),
+        Some(Ok(plaintext)) => BytesFrame::BulkString(plaintext),
+        Some(Err(e)) => command::error(format!("ERR failed to decrypt value: {e}")),
+    }
+}
+
+/// Undo encryption of any values in a reply carrying the values of `keys`
+fn decrypt_reply(cipher: &Aes256Gcm, keys: &[Bytes], frame: BytesFrame) -> BytesFrame {
+    match frame {
+        BytesFrame::Array(items) => BytesFrame::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| decrypt_value(cipher, keys.get(i), item))
+                .collect(),
+        ),
+        frame => decrypt_value(cipher, keys.first(), frame),
+    }
+}
+
+/// Whether a write stores no value given by the client, so may be made to an encrypted key
+fn stores_no_value(name: &str) -> bool {
+    matches!(
+        name,
+        "DEL"
+            | "EXPIRE"
+            | "EXPIREAT"
+            | "GETDEL"
+            | "GETEX"
+            | "MOVE"
+            | "PERSIST"
+            | "PEXPIRE"
+            | "PEXPIREAT"
+            | "UNLINK"
+    )
+}
+
+#[derive(Clone)]
+pub struct EncryptionLayer {
+    cipher: Arc<Aes256Gcm>,
+    prefixes: Arc<[Bytes]>,
+}
+
+impl EncryptionLayer {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            cipher: Arc::new(Aes256Gcm::new(&key.0.into())),
+            prefixes: Arc::new([]),
+        }
+    }
+
+    /// Only encrypt values of keys starting with one of `prefixes` (by default, every value is)
+    pub fn with_prefixes(mut self, prefixes: &[String]) -> Self {
+        self.prefixes = prefixes
+            .iter()
+            .map(|prefix| Bytes::copy_from_slice(prefix.as_bytes()))
+            .collect();
+        self
+    }
+}
+
+impl<S> Layer<S> for EncryptionLayer {
+    type Service = Encryption<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Encryption {
+            inner: service,
+            cipher: self.cipher.clone(),
+            prefixes: self.prefixes.clone(),
+        }
+    }
+}
+
+pub struct Encryption<S> {
+    inner: S,
+    cipher: Arc<Aes256Gcm>,
+    prefixes: Arc<[Bytes]>,
+}
+
+impl<S> Encryption<S> {
+    fn covers(&self, key: &[u8]) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// The name of the command `req` runs, if it's a write to an encrypted key which wouldn't be
+    /// encrypted. Commands whose keys can't be found may write anywhere, so count too, as do
+    /// scripts, which may write keys besides those they declare.
+    fn unencrypted_write(&self, req: &BytesFrame) -> Option<String> {
+        let name = command::name(req)?;
+        let keys = command::known_keys(req);
+        if (keys.is_some() && !command::is_write(req))
+            || !command::stored_values(req).is_empty()
+            || stores_no_value(&name)
+        {
+            return None;
+        }
+        let covered = matches!(name.as_str(), "EVAL" | "EVALSHA" | "FCALL")
+            || keys.is_none_or(|keys| keys.into_iter().any(|key| self.covers(key)));
+        covered.then_some(name)
+    }
+
+    /// Encrypt the values `req` stores
+    fn encrypt_request(&self, req: BytesFrame) -> Result<BytesFrame> {
+        let values = command::stored_values(&req);
+        let BytesFrame::Array(mut parts) = req else {
+            return Ok(req);
+        };
+        for (value_at, key_at) in values {
+            let (Some(key), Some(value)) = (
+                parts.get(key_at).and_then(command::arg_bytes),
+                parts.get(value_at).and_then(command::arg_bytes),
+            ) else {
+                continue;
+            };
+            if self.covers(key) {
+                parts[value_at] = BytesFrame::BulkString(encrypt(&self.cipher, key, value)?);
+            }
+        }
+        Ok(BytesFrame::Array(parts))
+    }
+}
+
+impl<S> Service<BytesFrame> for Encryption<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if let Some(name) = self.unencrypted_write(&req) {
+            let error = command::error(format!(
+                "ERR '{}' would store a value in the clear under an encrypted key",
+                name.to_lowercase()
+            ));
+            return Box::pin(async move { Ok(reply(error)) });
+        }
+        let keys = command::returns_stored_values(&req).then(|| command::reply_value_keys(&req));
+        let req = match self.encrypt_request(req) {
+            Ok(req) => req,
+            Err(e) => {
+                let error = command::error(format!("ERR failed to encrypt value: {e}"));
+                return Box::pin(async move { Ok(reply(error)) });
+            }
+        };
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        let Some(keys) = keys else {
+            return Box::pin(fut);
+        };
+        let cipher = self.cipher.clone();
+        Box::pin(async move {
+            Ok(fut
+                .await?
+                .map(move |frame| decrypt_reply(&cipher, &keys, frame))
+                .boxed())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+    use crate::service::CallOne as _;
+    use crate::testing::MockRedis;
+
+    static KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn layer() -> EncryptionLayer {
+        EncryptionLayer::new(&KEY.parse().unwrap())
+    }
+
+    fn bulk(s: &str) -> BytesFrame {
+        BytesFrame::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn keys_must_be_64_hexadecimal_digits() {
+        assert!(KEY.parse::<EncryptionKey>().is_ok());
+        assert!(KEY[..62].parse::<EncryptionKey>().is_err());
+        assert!(KEY.replace('0', "g").parse::<EncryptionKey>().is_err());
+    }
+
+    #[tokio::test]
+    async fn values_are_stored_encrypted_and_read_back() -> Result<()> {
+        let mock = MockRedis::new();
+        let mut service = layer().layer(mock.clone());
+        service
+            .call_one(command::request(["SET", "greeting", "hello"]))
+            .await?;
+        let stored = mock.get("greeting").expect("SET stored a value");
+        assert!(stored.starts_with(MAGIC) && stored[3] == VERSION);
+        assert!(!stored.windows(5).any(|w| w == b"hello"));
+
+        let value = service
+            .call_one(command::request(["GET", "greeting"]))
+            .await?;
+        assert_eq!(value, bulk("hello"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn values_moved_to_another_key_fail_authentication() -> Result<()> {
+        let mock = MockRedis::new();
+        let mut service = layer().layer(mock.clone());
+        service
+            .call_one(command::request(["SET", "greeting", "hello"]))
+            .await?;
+        let stored = mock.get("greeting").expect("SET stored a value");
+        let copy = BytesFrame::Array(vec![
+            bulk("SET"),
+            bulk("farewell"),
+            BytesFrame::BulkString(stored),
+        ]);
+        mock.execute(&copy);
+
+        let value = service
+            .call_one(command::request(["GET", "farewell"]))
+            .await?;
+        assert!(matches!(value, BytesFrame::Error(_)), "{value:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unbound_values_are_still_read() -> Result<()> {
+        let key: EncryptionKey = KEY.parse()?;
+        let cipher = Aes256Gcm::new(&key.0.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, &b"hello"[..]).unwrap();
+        let mut stored = MAGIC.to_vec();
+        stored.push(UNBOUND_VERSION);
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+
+        let mock = MockRedis::new();
+        let set = BytesFrame::Array(vec![
+            bulk("SET"),
+            bulk("greeting"),
+            BytesFrame::BulkString(stored.into()),
+        ]);
+        mock.execute(&set);
+        let value = layer()
+            .layer(mock)
+            .call_one(command::request(["GET", "greeting"]))
+            .await?;
+        assert_eq!(value, bulk("hello"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plaintext_values_pass_through() -> Result<()> {
+        let mock = MockRedis::new();
+        mock.execute(&command::request(["SET", "greeting", "hello"]));
+        let value = layer()
+            .layer(mock)
+            .call_one(command::request(["GET", "greeting"]))
+            .await?;
+        assert_eq!(value, bulk("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_bypassing_encryption_are_refused() {
+        let service = layer().layer(MockRedis::new());
+        let refused = [
+            vec!["APPEND", "greeting", "!"],
+            vec!["SETRANGE", "greeting", "0", "j"],
+            vec!["RENAME", "greeting", "farewell"],
+            vec!["COPY", "greeting", "farewell"],
+            vec!["EVAL", "return 1", "1", "greeting"],
+            vec!["EVAL", "return redis.call('SET', 'greeting', 'hello')", "0"],
+        ];
+        for req in refused {
+            assert!(
+                service.unencrypted_write(&command::request(&req)).is_some(),
+                "{req:?}"
+            );
+        }
+        let allowed = [
+            vec!["SET", "greeting", "hello"],
+            vec!["GET", "greeting"],
+            vec!["DEL", "greeting"],
+            vec!["EXPIRE", "greeting", "10"],
+        ];
+        for req in allowed {
+            assert_eq!(
+                service.unencrypted_write(&command::request(&req)),
+                None,
+                "{req:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_outside_the_prefixes_are_allowed() {
+        let service = layer()
+            .with_prefixes(&["secret:".to_string()])
+            .layer(MockRedis::new());
+        let append = |key| command::request(["APPEND", key, "!"]);
+        assert_eq!(service.unencrypted_write(&append("public:a")), None);
+        assert!(service.unencrypted_write(&append("secret:a")).is_some());
+    }
+}
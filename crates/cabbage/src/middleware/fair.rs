@@ -0,0 +1,106 @@
+//! Fair scheduling of commands across client connections.
+//!
+//! `FairLayer` limits the commands awaiting replies from targets across every client connection
+//! sharing it. A connection only dispatches its next command once it holds one of the slots, and
+//! waits for one in line with the other waiting connections. Each connection waits with at most
+//! one command at a time, so slots go to the waiting connections in turn: a client pipelining
+//! thousands of commands gets one slot a round like any other, and a light client waits for no
+//! more than one command of each busier client ahead of it. A slot is held until its command's
+//! replies have been forwarded, except for blocking commands and those starting push mode, which
+//! take their turn but give the slot back once dispatched, as their replies may be a long time
+//! coming.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::service::ResponseStream;
+
+/// The commands in flight to targets, shared by every connection
+#[derive(Clone)]
+pub struct FairLayer {
+    slots: Arc<Semaphore>,
+}
+
+impl FairLayer {
+    /// Let at most `max_in_flight` commands await replies at once
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_in_flight)),
+        }
+    }
+}
+
+impl<S> Layer<S> for FairLayer {
+    type Service = FairScheduled<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FairScheduled {
+            inner: service,
+            slots: PollSemaphore::new(self.slots.clone()),
+            slot: None,
+        }
+    }
+}
+
+pub struct FairScheduled<S> {
+    inner: S,
+    slots: PollSemaphore,
+    /// The slot for the next command, once its turn has come
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+impl<S> Service<BytesFrame> for FairScheduled<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.slot.is_none() {
+            match ready!(self.slots.poll_acquire(cx)) {
+                Some(slot) => self.slot = Some(slot),
+                None => {
+                    return Poll::Ready(Err(Error::BackendUnavailable(
+                        "Fair scheduler closed".to_string(),
+                    )));
+                }
+            }
+        }
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let mut slot = self.slot.take();
+        if command::is_blocking(&req) || command::starts_push_mode(&req) {
+            slot = None;
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let responses = fut.await.map_err(Into::into)?;
+            let Some(slot) = slot else {
+                return Ok(responses);
+            };
+            // The slot is given back once the replies are done with
+            Ok(responses
+                .inspect(move |_| {
+                    let _ = &slot;
+                })
+                .boxed())
+        })
+    }
+}
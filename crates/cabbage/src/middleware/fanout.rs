@@ -0,0 +1,413 @@
+//! Shared pub/sub subscriptions.
+//!
+//! A subscribed Redis connection serves one subscriber, so a thousand clients subscribed to the
+//! same channel through the proxy would otherwise hold a thousand target connections. With
+//! `SubscriptionFanOutLayer`, a `SUBSCRIBE` or `PSUBSCRIBE` naming no keyspace notification
+//! channels (those are left to `KeyspaceNotificationLayer`) is taken over: the client is
+//! registered with a `SubscriptionHub`, shared by every connection, which keeps a single
+//! connection to the target subscribed to every channel and pattern any client wants, and fans
+//! each message out to the clients subscribed to its channel or pattern. The hub connects once
+//! the first client subscribes, and disconnects when the last unsubscribes; when its connection
+//! is lost, it reconnects and subscribes again, though messages published in the meantime are
+//! lost, as pub/sub is fire-and-forget.
+//!
+//! Subscription replies are the proxy's own, counting the client's subscriptions, and a client
+//! is sent messages from the moment it's confirmed, which may be before the hub has subscribed
+//! on its behalf. A client which falls `MAX_BUFFERED_MESSAGES` behind misses messages rather
+//! than holding up the others. While subscribed, a client can (un)subscribe to further channels
+//! and `PING`, as on a subscribed Redis connection; other commands are refused until it has
+//! unsubscribed from everything, or ended the subscription with `QUIT` or `RESET`.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream;
+use futures::{Future, SinkExt as _, TryFutureExt as _};
+use futures_util::StreamExt;
+use redis_protocol::codec::Resp2;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::{Notify, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::notifications::{Kind, Subscriptions, is_keyspace_channel};
+use crate::service::{Handshake, ResponseStream};
+
+/// Delay before reconnecting after losing the hub's connection
+static RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+/// Messages buffered for a client before further ones are dropped for it
+const MAX_BUFFERED_MESSAGES: usize = 1024;
+
+/// The clients subscribed to each channel or pattern, by ID
+type Subscribers = HashMap<Bytes, HashMap<u64, mpsc::Sender<BytesFrame>>>;
+
+#[derive(Default)]
+struct HubState {
+    channels: Subscribers,
+    patterns: Subscribers,
+    next_id: u64,
+}
+
+impl HubState {
+    fn of(&mut self, kind: Kind) -> &mut Subscribers {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+
+    fn wanted_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Every channel and pattern with a subscriber
+    fn wanted(&self) -> Subscriptions {
+        Subscriptions {
+            channels: self.channels.keys().cloned().collect(),
+            patterns: self.patterns.keys().cloned().collect(),
+        }
+    }
+}
+
+/// A subscription to the target shared by every client connection
+pub struct SubscriptionHub {
+    address: String,
+    handshake: Handshake,
+    state: Mutex<HubState>,
+    /// Notified when the channels and patterns with subscribers change
+    changed: Notify,
+}
+
+impl SubscriptionHub {
+    pub fn new(address: &str, handshake: Handshake) -> Self {
+        Self {
+            address: address.to_string(),
+            handshake,
+            state: Mutex::new(HubState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Keep the target subscribed to whatever clients are, while any are, fanning out messages
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            while self.wanted().count() == 0 {
+                self.changed.notified().await;
+            }
+            match self.relay().await {
+                Ok(()) => continue,
+                Err(e) => log::warn!(
+                    "Lost shared subscription to {}, resubscribing: {e:#}",
+                    self.address
+                ),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    fn wanted(&self) -> Subscriptions {
+        self.state
+            .lock()
+            .map(|state| state.wanted())
+            .unwrap_or_default()
+    }
+
+    /// Subscribe on a connection of its own to what clients are, until none are, failing once
+    /// the connection is lost
+    async fn relay(&self) -> Result<()> {
+        let socket = self.handshake.connect(&self.address).await?;
+        let mut framed = Framed::new(socket, Resp2::default());
+        self.handshake.perform(&mut framed).await?;
+        log::info!("Sharing subscriptions on a connection to {}", self.address);
+        let (mut sink, mut published) = framed.split();
+        let mut subscribed = Subscriptions::default();
+        loop {
+            let wanted = self.wanted();
+            if wanted.count() == 0 {
+                return Ok(());
+            }
+            for request in subscribed.changes_to(&wanted) {
+                sink.send(request).await?;
+            }
+            subscribed = wanted;
+            tokio::select! {
+                () = self.changed.notified() => {}
+                frame = published.next() => match frame {
+                    Some(Ok(frame)) => self.fan_out(frame),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => bail!(BackendUnavailable, "The target closed the connection"),
+                },
+            }
+        }
+    }
+
+    /// Send a published message to each client subscribed to its channel or pattern
+    fn fan_out(&self, frame: BytesFrame) {
+        let BytesFrame::Array(parts) = &frame else {
+            return;
+        };
+        let kind = match parts
+            .first()
+            .and_then(command::arg_bytes)
+            .map(|k| k.as_ref())
+        {
+            Some(b"message") => Kind::Channel,
+            Some(b"pmessage") => Kind::Pattern,
+            // The target confirming the hub's own subscription changes
+            _ => return,
+        };
+        let Some(subscribed_to) = parts.get(1).and_then(command::arg_bytes) else {
+            return;
+        };
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        for client in state
+            .of(kind)
+            .get(subscribed_to)
+            .into_iter()
+            .flat_map(|c| c.values())
+        {
+            if client.try_send(frame.clone()).is_err() {
+                log::debug!("Dropped a message for a client falling behind its subscription");
+            }
+        }
+    }
+
+    /// Register a new subscriber, whose messages are sent to `push`
+    fn register(self: &Arc<Self>, push: mpsc::Sender<BytesFrame>) -> Subscriber {
+        let id = self.state.lock().map_or(0, |mut state| {
+            state.next_id += 1;
+            state.next_id
+        });
+        Subscriber {
+            id,
+            hub: self.clone(),
+            push,
+            own: Subscriptions::default(),
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut HubState)) {
+        if let Ok(mut state) = self.state.lock() {
+            let before = state.wanted_count();
+            update(&mut state);
+            // Only a channel or pattern gaining its first subscriber or losing its last matters
+            if state.wanted_count() != before {
+                self.changed.notify_one();
+            }
+        }
+    }
+}
+
+/// A client's share of the hub's subscription, withdrawn when dropped
+struct Subscriber {
+    id: u64,
+    hub: Arc<SubscriptionHub>,
+    /// The client's subscription stream, carrying messages and the replies to its commands
+    push: mpsc::Sender<BytesFrame>,
+    own: Subscriptions,
+}
+
+impl Subscriber {
+    fn subscribe(&mut self, kind: Kind, channels: Vec<Bytes>) -> Vec<BytesFrame> {
+        let mut confirmations = vec![];
+        self.hub.update(|state| {
+            for channel in channels {
+                state
+                    .of(kind)
+                    .entry(channel.clone())
+                    .or_default()
+                    .insert(self.id, self.push.clone());
+                self.own.of(kind).insert(channel.clone());
+                confirmations.push(kind.confirmation(true, Some(channel), self.own.count()));
+            }
+        });
+        confirmations
+    }
+
+    /// Unsubscribe from `channels`, or from everything of `kind` if there are none
+    fn unsubscribe(&mut self, kind: Kind, channels: Vec<Bytes>) -> Vec<BytesFrame> {
+        let channels = if channels.is_empty() {
+            std::mem::take(self.own.of(kind)).into_iter().collect()
+        } else {
+            channels
+        };
+        let mut confirmations = vec![];
+        self.hub.update(|state| {
+            for channel in channels {
+                withdraw(state.of(kind), &channel, self.id);
+                self.own.of(kind).remove(&channel);
+                confirmations.push(kind.confirmation(false, Some(channel), self.own.count()));
+            }
+        });
+        if confirmations.is_empty() {
+            confirmations.push(kind.confirmation(false, None, self.own.count()));
+        }
+        confirmations
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let own = std::mem::take(&mut self.own);
+        self.hub.update(|state| {
+            for (kind, channels) in [(Kind::Channel, own.channels), (Kind::Pattern, own.patterns)] {
+                for channel in channels {
+                    withdraw(state.of(kind), &channel, self.id);
+                }
+            }
+        });
+    }
+}
+
+/// Remove client `id` from the subscribers to `channel`, forgetting the channel once it has none
+fn withdraw(subscribers: &mut Subscribers, channel: &Bytes, id: u64) {
+    if let Some(clients) = subscribers.get_mut(channel) {
+        clients.remove(&id);
+        if clients.is_empty() {
+            subscribers.remove(channel);
+        }
+    }
+}
+
+pub struct SubscriptionFanOutLayer {
+    hub: Arc<SubscriptionHub>,
+}
+
+impl SubscriptionFanOutLayer {
+    pub fn new(hub: Arc<SubscriptionHub>) -> Self {
+        Self { hub }
+    }
+}
+
+impl<S> Layer<S> for SubscriptionFanOutLayer {
+    type Service = SubscriptionFanOut<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SubscriptionFanOut {
+            inner: service,
+            hub: self.hub.clone(),
+            subscribed: None,
+        }
+    }
+}
+
+pub struct SubscriptionFanOut<S> {
+    inner: S,
+    hub: Arc<SubscriptionHub>,
+    subscribed: Option<Subscriber>,
+}
+
+impl<S> SubscriptionFanOut<S> {
+    /// Send `replies` on the client's subscription stream, answering the request itself with
+    /// nothing, as its replies are on that stream
+    fn push(
+        push: mpsc::Sender<BytesFrame>,
+        replies: Vec<BytesFrame>,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponseStream>> + Send>> {
+        Box::pin(async move {
+            for reply in replies {
+                // A closed stream means the client is gone
+                let _ = push.send(reply).await;
+            }
+            Ok(stream::empty().boxed())
+        })
+    }
+}
+
+impl<S> Service<BytesFrame> for SubscriptionFanOut<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        let channels: Vec<Bytes> = command::args(&req)
+            .and_then(|args| args.get(1..))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(command::arg_bytes)
+            .cloned()
+            .collect();
+        let kind = match name.as_deref() {
+            Some("SUBSCRIBE" | "UNSUBSCRIBE") => Some(Kind::Channel),
+            Some("PSUBSCRIBE" | "PUNSUBSCRIBE") => Some(Kind::Pattern),
+            _ => None,
+        };
+        let subscribing = matches!(name.as_deref(), Some("SUBSCRIBE" | "PSUBSCRIBE"));
+        let shareable = !channels.is_empty() && !channels.iter().any(|c| is_keyspace_channel(c));
+
+        let Some(subscribed) = &mut self.subscribed else {
+            let Some(kind) = kind.filter(|_| subscribing && shareable) else {
+                return Box::pin(self.inner.call(req).map_err(Into::into));
+            };
+            let (push, messages) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+            let mut subscriber = self.hub.register(push);
+            let confirmations = subscriber.subscribe(kind, channels);
+            self.subscribed = Some(subscriber);
+            let responses = stream::iter(confirmations).chain(ReceiverStream::new(messages));
+            return Box::pin(async move { Ok(responses.boxed()) });
+        };
+
+        let push = subscribed.push.clone();
+        let replies = match (name.as_deref(), kind) {
+            (Some("QUIT" | "RESET"), _) => {
+                // Ending the subscription ends its stream, so the reply follows it
+                self.subscribed = None;
+                return Box::pin(self.inner.call(req).map_err(Into::into));
+            }
+            (_, Some(kind)) if subscribing => {
+                if !shareable {
+                    let error = command::error(
+                        "ERR cabbage: keyspace notification channels can't be added to this \
+                         subscription",
+                    );
+                    return Self::push(push, vec![error]);
+                }
+                subscribed.subscribe(kind, channels)
+            }
+            (_, Some(kind)) => {
+                let confirmations = subscribed.unsubscribe(kind, channels);
+                if subscribed.own.count() == 0 {
+                    // The stream ends once the last confirmation is sent
+                    self.subscribed = None;
+                }
+                confirmations
+            }
+            (Some("PING"), _) => {
+                let message = channels.into_iter().next().unwrap_or_default();
+                vec![BytesFrame::Array(vec![
+                    BytesFrame::BulkString(Bytes::from_static(b"pong")),
+                    BytesFrame::BulkString(message),
+                ])]
+            }
+            _ => {
+                let error = command::error(format!(
+                    "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / \
+                     RESET are allowed in this context",
+                    name.unwrap_or_default().to_lowercase()
+                ));
+                vec![error]
+            }
+        };
+        Self::push(push, replies)
+    }
+}
@@ -0,0 +1,146 @@
+//! Command allow/deny lists.
+//!
+//! `CommandFilterLayer` rejects commands by name before they reach the target, answering the
+//! client with an error instead. Entries are command names (`FLUSHALL`) or, as in Redis ACLs, a
+//! command and subcommand separated by a pipe (`CONFIG|SET`). When an allow list is configured,
+//! only commands on it are forwarded; the deny list is applied on top of that. Rules can be
+//! replaced on live connections with `CommandFilterLayer::watch`.
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::watch;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+#[derive(Clone, Debug, Default)]
+pub struct CommandRules {
+    allowed: Option<HashSet<String>>,
+    denied: HashSet<String>,
+}
+
+impl CommandRules {
+    /// Permit only the given commands (in addition to any previously allowed)
+    pub fn allow<I: IntoIterator<Item = S>, S: AsRef<str>>(mut self, commands: I) -> Self {
+        self.allowed.get_or_insert_with(HashSet::new).extend(
+            commands
+                .into_iter()
+                .map(|c| c.as_ref().to_ascii_uppercase()),
+        );
+        self
+    }
+
+    /// Reject the given commands
+    pub fn deny<I: IntoIterator<Item = S>, S: AsRef<str>>(mut self, commands: I) -> Self {
+        self.denied.extend(
+            commands
+                .into_iter()
+                .map(|c| c.as_ref().to_ascii_uppercase()),
+        );
+        self
+    }
+
+    /// Whether a request may be forwarded, returning the matched rule name when it may not
+    pub fn check(&self, req: &BytesFrame) -> Result<(), String> {
+        let Some(name) = command::name(req) else {
+            return Ok(());
+        };
+        let full_name = command::args(req)
+            .and_then(|args| args.get(1))
+            .and_then(command::arg_bytes)
+            .map(|sub| {
+                format!(
+                    "{name}|{}",
+                    String::from_utf8_lossy(sub).to_ascii_uppercase()
+                )
+            });
+
+        let matches = |set: &HashSet<String>| {
+            set.contains(&name) || full_name.as_ref().is_some_and(|f| set.contains(f))
+        };
+        if let Some(allowed) = &self.allowed
+            && !matches(allowed)
+        {
+            return Err(full_name.unwrap_or(name));
+        }
+        if matches(&self.denied) {
+            return Err(full_name
+                .filter(|f| self.denied.contains(f))
+                .unwrap_or(name));
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_none() && self.denied.is_empty()
+    }
+}
+
+#[derive(Clone)]
+pub struct CommandFilterLayer {
+    rules: watch::Receiver<CommandRules>,
+}
+
+impl CommandFilterLayer {
+    pub fn new(rules: CommandRules) -> Self {
+        Self::watch(watch::channel(rules).1)
+    }
+
+    /// Filter by the latest rules sent on `rules`, so they can be changed on live connections
+    pub fn watch(rules: watch::Receiver<CommandRules>) -> Self {
+        Self { rules }
+    }
+}
+
+impl<S> Layer<S> for CommandFilterLayer {
+    type Service = CommandFilter<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CommandFilter {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+pub struct CommandFilter<S> {
+    inner: S,
+    rules: watch::Receiver<CommandRules>,
+}
+
+impl<S> Service<BytesFrame> for CommandFilter<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let checked = self.rules.borrow().check(&req);
+        if let Err(rule) = checked {
+            log::debug!("Rejected command '{rule}' by filter rules");
+            let error = command::error(format!(
+                "ERR command '{}' is not allowed through this proxy",
+                rule.to_lowercase()
+            ));
+            return Box::pin(async move { Ok(reply(error)) });
+        }
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
@@ -0,0 +1,73 @@
+//! Closing connections to a target which failed a health check.
+//!
+//! `HealthGateLayer` notes how many health checks had failed when a client connection's service
+//! was built. Once another fails, the service stops being ready, which closes the client
+//! connection along with its (probably dead) target connection; the client reconnects through a
+//! fresh one. Connections made after the failure are unaffected.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::Error;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+pub struct HealthGateLayer {
+    stats: Arc<Stats>,
+}
+
+impl HealthGateLayer {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self { stats }
+    }
+}
+
+impl<S> Layer<S> for HealthGateLayer {
+    type Service = HealthGate<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HealthGate {
+            inner: service,
+            failed_checks: self.stats.backend.failed_health_checks(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+pub struct HealthGate<S> {
+    inner: S,
+    /// Health checks which had failed when the service was built
+    failed_checks: u64,
+    stats: Arc<Stats>,
+}
+
+impl<S> Service<BytesFrame> for HealthGate<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.stats.backend.failed_health_checks() > self.failed_checks {
+            return Poll::Ready(Err(Error::BackendUnavailable(
+                "Target failed a health check, closing its connection".to_string(),
+            )));
+        }
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
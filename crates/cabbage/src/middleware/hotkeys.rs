@@ -0,0 +1,69 @@
+//! Hot key detection.
+//!
+//! `HotKeyLayer` samples a fraction of commands and feeds the keys they touch into the shared
+//! `HotKeys` sketch, which every connection contributes to.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::stats::Stats;
+
+pub struct HotKeyLayer {
+    stats: Arc<Stats>,
+    sample_rate: f64,
+}
+
+impl HotKeyLayer {
+    /// Track the keys of roughly `sample_rate` (0.0 to 1.0) of all commands
+    pub fn new(stats: Arc<Stats>, sample_rate: f64) -> Self {
+        Self {
+            stats,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl<S> Layer<S> for HotKeyLayer {
+    type Service = HotKeyTracker<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HotKeyTracker {
+            inner: service,
+            stats: self.stats.clone(),
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+pub struct HotKeyTracker<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    sample_rate: f64,
+}
+
+impl<S> Service<BytesFrame> for HotKeyTracker<S>
+where
+    S: Service<BytesFrame>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if rand::random::<f64>() < self.sample_rate {
+            for key in command::keys(&req) {
+                self.stats.hot_keys.record(key);
+            }
+        }
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,175 @@
+//! Isolation of disruptive commands.
+//!
+//! Some commands leave the connection they're sent on unusable for a while, or for good:
+//! `DEBUG SLEEP` holds up every command pipelined behind it, `CLIENT PAUSE` and `CLIENT KILL`
+//! can stall or close it, and `SHUTDOWN` takes the target down with it. `IsolationLayer` keeps
+//! commands matching an `Isolation`'s rules (by name, or as `NAME|SUBCOMMAND`) off the client's
+//! target connection: with `IsolationMode::Side`, each is sent over a connection of its own to
+//! the target, made with the main connection's handshake and closed once it has been answered,
+//! and with `IsolationMode::Reject` it's answered with an error without being forwarded.
+//!
+//! A side connection runs its command alongside the client's other commands rather than after
+//! them, and in the database the handshake selected, not any the client has `SELECT`ed since.
+//! Its reply still reaches the client in order. Commands which subscribe or monitor are always
+//! sent on the client's own connection, as their replies never end.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt as _;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::filter::CommandRules;
+use crate::middleware::reply;
+use crate::service::{Handshake, Resp2Backend, ResponseStream};
+
+/// What's done with a command kept off the client's target connection
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IsolationMode {
+    /// Send it over a short-lived connection of its own
+    #[default]
+    Side,
+    /// Answer it with an error
+    Reject,
+}
+
+impl std::str::FromStr for IsolationMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "side" => Ok(IsolationMode::Side),
+            "reject" => Ok(IsolationMode::Reject),
+            _ => bail!(Config, "Unrecognized isolation mode '{s}'"),
+        }
+    }
+}
+
+/// Where the side connections of `IsolationMode::Side` go
+struct SideTarget {
+    address: String,
+    handshake: Handshake,
+}
+
+/// The commands kept off clients' target connections, and how
+pub struct Isolation {
+    /// Denying exactly the isolated commands, so those they reject are the ones to isolate
+    rules: CommandRules,
+    side: Option<Arc<SideTarget>>,
+}
+
+impl Isolation {
+    /// Reject the given commands (NAME or NAME|SUBCOMMAND)
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(commands: I) -> Self {
+        Self {
+            rules: CommandRules::default().deny(commands),
+            side: None,
+        }
+    }
+
+    /// Send the commands over connections of their own to `address`, made with `handshake`,
+    /// instead of rejecting them
+    pub fn on_side_connections(mut self, address: &str, handshake: Handshake) -> Self {
+        self.side = Some(Arc::new(SideTarget {
+            address: address.to_string(),
+            handshake,
+        }));
+        self
+    }
+
+    /// The isolated command `req` is, as named by its rule, if it is one
+    fn isolates(&self, req: &BytesFrame) -> Option<String> {
+        if command::starts_push_mode(req) {
+            return None;
+        }
+        self.rules.check(req).err()
+    }
+}
+
+/// Send `req` over a connection of its own to `side`, closing it once it has been answered
+async fn on_side_connection(side: &SideTarget, req: BytesFrame) -> Result<ResponseStream> {
+    let mut backend = Resp2Backend::connect_with(&side.address, &side.handshake).await?;
+    let replies: Vec<_> = backend.ready().await?.call(req).await?.collect().await;
+    if let Err(e) = backend.close().await {
+        log::debug!("Failed to close side connection to {}: {e}", side.address);
+    }
+    Ok(futures::stream::iter(replies).boxed())
+}
+
+#[derive(Clone)]
+pub struct IsolationLayer {
+    isolation: Arc<Isolation>,
+}
+
+impl IsolationLayer {
+    pub fn new(isolation: Arc<Isolation>) -> Self {
+        Self { isolation }
+    }
+}
+
+impl<S> Layer<S> for IsolationLayer {
+    type Service = Isolated<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Isolated {
+            inner: service,
+            isolation: self.isolation.clone(),
+        }
+    }
+}
+
+pub struct Isolated<S> {
+    inner: S,
+    isolation: Arc<Isolation>,
+}
+
+impl<S> Service<BytesFrame> for Isolated<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let Some(rule) = self.isolation.isolates(&req) else {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+        let rule = rule.to_lowercase();
+        let Some(side) = self.isolation.side.clone() else {
+            log::debug!("Rejected isolated command '{rule}'");
+            let error = command::error(format!(
+                "ERR command '{rule}' may only be sent to the target directly"
+            ));
+            return Box::pin(async move { Ok(reply(error)) });
+        };
+        log::debug!("Sending isolated command '{rule}' over a side connection");
+        Box::pin(async move {
+            match on_side_connection(&side, req).await {
+                Ok(replies) => Ok(replies),
+                Err(e) => {
+                    log::warn!("Failed to send '{rule}' over a side connection: {e}");
+                    Ok(reply(command::error(format!(
+                        "ERR cabbage: side connection for '{rule}' failed: {e}"
+                    ))))
+                }
+            }
+        })
+    }
+}
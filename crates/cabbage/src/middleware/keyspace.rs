@@ -0,0 +1,75 @@
+//! Key-space analytics.
+//!
+//! `KeySpaceLayer` samples a fraction of commands and counts the keys they touch against their
+//! namespace (the key up to its first `:`) in the shared `KeySpace`, so the periodic stats report
+//! and `CABBAGE.KEYSPACE` show which parts of the key space a workload actually uses. The keys
+//! are counted against their Redis Cluster slot in `KeySlots` too, so `CABBAGE.KEYSLOTS` shows
+//! whether a workload would crowd a few of a cluster's shards. Slots are those of the keys as
+//! clients send them, before any key prefix is applied.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::stats::Stats;
+
+pub struct KeySpaceLayer {
+    stats: Arc<Stats>,
+    sample_rate: f64,
+}
+
+impl KeySpaceLayer {
+    /// Count the keys of roughly `sample_rate` (0.0 to 1.0) of all commands
+    pub fn new(stats: Arc<Stats>, sample_rate: f64) -> Self {
+        Self {
+            stats,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl<S> Layer<S> for KeySpaceLayer {
+    type Service = KeySpaceSampler<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        KeySpaceSampler {
+            inner: service,
+            stats: self.stats.clone(),
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+pub struct KeySpaceSampler<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    sample_rate: f64,
+}
+
+impl<S> Service<BytesFrame> for KeySpaceSampler<S>
+where
+    S: Service<BytesFrame>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if rand::random::<f64>() < self.sample_rate {
+            let read_only = command::is_read_only(&req);
+            for key in command::keys(&req) {
+                self.stats.key_space.record(key, read_only);
+                self.stats.key_slots.record(key);
+            }
+        }
+        self.inner.call(req)
+    }
+}
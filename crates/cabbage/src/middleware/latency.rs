@@ -0,0 +1,96 @@
+//! Per-command latency measurement.
+//!
+//! `LatencyLayer` times each command from dispatch until the final frame of its response stream
+//! has been consumed and records the result in the shared `Stats`, both in the command's latency
+//! histogram and in its call totals (along with whether it was answered with an error).
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::OnComplete;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+pub struct LatencyLayer {
+    stats: Arc<Stats>,
+}
+
+impl LatencyLayer {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self { stats }
+    }
+}
+
+impl<S> Layer<S> for LatencyLayer {
+    type Service = Latency<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Latency {
+            inner: service,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+pub struct Latency<S> {
+    inner: S,
+    stats: Arc<Stats>,
+}
+
+impl<S> Service<BytesFrame> for Latency<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req).unwrap_or_else(|| "UNKNOWN".to_string());
+        let stats = self.stats.clone();
+        let start = Instant::now();
+
+        Box::pin(
+            self.inner
+                .call(req)
+                .map_ok(move |stream| {
+                    let errored = Arc::new(AtomicBool::new(false));
+                    let saw_error = errored.clone();
+                    let stream = stream
+                        .inspect(move |frame| {
+                            if matches!(frame, BytesFrame::Error(_)) {
+                                saw_error.store(true, Ordering::Relaxed);
+                            }
+                        })
+                        .boxed();
+                    OnComplete::new(stream, move || {
+                        let elapsed = start.elapsed();
+                        stats.latency.record(&name, elapsed);
+                        stats
+                            .commands
+                            .record(&name, elapsed, errored.load(Ordering::Relaxed));
+                    })
+                    .boxed()
+                })
+                .map_err(Into::into),
+        )
+    }
+}
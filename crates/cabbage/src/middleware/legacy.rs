@@ -0,0 +1,152 @@
+//! Deprecated command translation.
+//!
+//! `LegacyCommandLayer` rewrites commands Redis has deprecated into their modern equivalents, so
+//! old clients keep working against stores which have dropped them, translating the replies back
+//! where their shapes differ:
+//!
+//! - `SETEX key seconds value` becomes `SET key value EX seconds`
+//! - `PSETEX key ms value` becomes `SET key value PX ms`
+//! - `SETNX key value` becomes `SET key value NX`, its `OK` or nil reply answered as 1 or 0
+//! - `GETSET key value` becomes `SET key value GET`
+//! - `HMSET key field value ...` becomes `HSET key field value ...`, answered with `OK`
+//! - `RPOPLPUSH source destination` becomes `LMOVE source destination RIGHT LEFT`
+//! - `BRPOPLPUSH source destination timeout` becomes
+//!   `BLMOVE source destination RIGHT LEFT timeout`
+//!
+//! Commands with the wrong number of arguments are forwarded untouched, for the target to reject.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::service::ResponseStream;
+
+/// How the reply to a translated command differs from the legacy command's
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reply {
+    Same,
+    /// `SET ... NX` answers `OK` or nil where `SETNX` answers 1 or 0
+    SetNx,
+    /// `HSET` counts the fields it added where `HMSET` answers `OK`
+    Ok,
+}
+
+/// The modern equivalent of `req`, if it's a legacy command
+fn translate(req: &BytesFrame) -> Option<(BytesFrame, Reply)> {
+    let name = command::name(req)?;
+    let args = command::args(req)?;
+    let arg = |i: usize| args.get(i).cloned();
+    let bulk = |s: &'static str| BytesFrame::BulkString(Bytes::from_static(s.as_bytes()));
+    let (translated, reply) = match (name.as_str(), args.len()) {
+        ("SETEX", 4) => (
+            vec![bulk("SET"), arg(1)?, arg(3)?, bulk("EX"), arg(2)?],
+            Reply::Same,
+        ),
+        ("PSETEX", 4) => (
+            vec![bulk("SET"), arg(1)?, arg(3)?, bulk("PX"), arg(2)?],
+            Reply::Same,
+        ),
+        ("SETNX", 3) => (
+            vec![bulk("SET"), arg(1)?, arg(2)?, bulk("NX")],
+            Reply::SetNx,
+        ),
+        ("GETSET", 3) => (
+            vec![bulk("SET"), arg(1)?, arg(2)?, bulk("GET")],
+            Reply::Same,
+        ),
+        ("HMSET", n) if n >= 4 && n % 2 == 0 => {
+            let mut hset = args.to_vec();
+            hset[0] = bulk("HSET");
+            (hset, Reply::Ok)
+        }
+        ("RPOPLPUSH", 3) => (
+            vec![bulk("LMOVE"), arg(1)?, arg(2)?, bulk("RIGHT"), bulk("LEFT")],
+            Reply::Same,
+        ),
+        ("BRPOPLPUSH", 4) => (
+            vec![
+                bulk("BLMOVE"),
+                arg(1)?,
+                arg(2)?,
+                bulk("RIGHT"),
+                bulk("LEFT"),
+                arg(3)?,
+            ],
+            Reply::Same,
+        ),
+        _ => return None,
+    };
+    Some((BytesFrame::Array(translated), reply))
+}
+
+fn translate_reply(reply: Reply, frame: BytesFrame) -> BytesFrame {
+    match (reply, frame) {
+        (Reply::SetNx, BytesFrame::SimpleString(_)) => BytesFrame::Integer(1),
+        (Reply::SetNx, BytesFrame::Null) => BytesFrame::Integer(0),
+        (Reply::Ok, BytesFrame::Integer(_)) => BytesFrame::SimpleString(Bytes::from_static(b"OK")),
+        (_, frame) => frame,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LegacyCommandLayer;
+
+impl LegacyCommandLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for LegacyCommandLayer {
+    type Service = LegacyCommands<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        LegacyCommands { inner: service }
+    }
+}
+
+pub struct LegacyCommands<S> {
+    inner: S,
+}
+
+impl<S> Service<BytesFrame> for LegacyCommands<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let Some((req, reply)) = translate(&req) else {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+        let fut = self.inner.call(req);
+        if reply == Reply::Same {
+            return Box::pin(fut.map_err(Into::into));
+        }
+        Box::pin(
+            fut.map_ok(move |stream| {
+                stream
+                    .map(move |frame| translate_reply(reply, frame))
+                    .boxed()
+            })
+            .map_err(Into::into),
+        )
+    }
+}
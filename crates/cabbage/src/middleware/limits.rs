@@ -0,0 +1,123 @@
+//! Request size limits.
+//!
+//! `SizeLimitLayer` answers requests which are too large, have too many elements, or carry an
+//! oversized argument with an error instead of forwarding them, so a single client can't make the
+//! target buffer arbitrarily large commands. Frames still being received are limited by the
+//! client connection itself (see `ServeOptions::with_max_frame_bytes`), since they never reach a
+//! service.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::{command, frame};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestLimits {
+    /// Largest request accepted, RESP encoded
+    pub max_frame_bytes: Option<usize>,
+    /// Most elements (command name and arguments) in a request
+    pub max_elements: Option<usize>,
+    /// Largest single argument
+    pub max_value_bytes: Option<usize>,
+}
+
+impl RequestLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Why `req` is over the limits, if it is
+    fn violation(&self, req: &BytesFrame) -> Option<String> {
+        if let Some(max) = self.max_frame_bytes {
+            let len = frame::encoded_len(req);
+            if len > max {
+                return Some(format!("request of {len} bytes exceeds the limit of {max}"));
+            }
+        }
+        let args = command::args(req).unwrap_or_default();
+        if let Some(max) = self.max_elements
+            && args.len() > max
+        {
+            return Some(format!(
+                "request of {} elements exceeds the limit of {max}",
+                args.len()
+            ));
+        }
+        if let Some(max) = self.max_value_bytes
+            && let Some(len) = args
+                .iter()
+                .filter_map(command::arg_bytes)
+                .map(|arg| arg.len())
+                .find(|len| *len > max)
+        {
+            return Some(format!(
+                "argument of {len} bytes exceeds the limit of {max}"
+            ));
+        }
+        None
+    }
+}
+
+pub struct SizeLimitLayer {
+    limits: RequestLimits,
+}
+
+impl SizeLimitLayer {
+    pub fn new(limits: RequestLimits) -> Self {
+        Self { limits }
+    }
+}
+
+impl<S> Layer<S> for SizeLimitLayer {
+    type Service = SizeLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SizeLimit {
+            inner: service,
+            limits: self.limits,
+        }
+    }
+}
+
+pub struct SizeLimit<S> {
+    inner: S,
+    limits: RequestLimits,
+}
+
+impl<S> Service<BytesFrame> for SizeLimit<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if let Some(violation) = self.limits.violation(&req) {
+            log::debug!(
+                "Rejecting {}: {violation}",
+                command::name(&req).unwrap_or_default()
+            );
+            let error = command::error(format!("ERR {violation}"));
+            return Box::pin(async move { Ok(reply(error)) });
+        }
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
@@ -0,0 +1,151 @@
+//! Maintenance mode.
+//!
+//! While the `Maintenance` shared by every connection is on, `MaintenanceLayer` answers each
+//! command with its error (`-LOADING maintenance in progress` unless configured otherwise)
+//! without forwarding it, other than those its rules allow (by name, or as `NAME|SUBCOMMAND`).
+//! `CABBAGE.MAINTENANCE` switches it on and off, and changes the error, at runtime, so the
+//! target can be taken down while clients stay connected and hear why instead of being refused
+//! connections.
+//!
+//! The target isn't contacted until a command is forwarded: a client connecting during
+//! maintenance is given its target connection lazily, whatever `lazy_connect` says. Clients
+//! connected beforehand keep theirs, and are still closed with them if the target goes away.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::middleware::filter::CommandRules;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// Whether commands are answered with an error instead of being forwarded, across every
+/// connection, and which are forwarded regardless
+#[derive(Debug)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    error: RwLock<String>,
+    /// Allowing exactly the commands forwarded during maintenance
+    allowed: CommandRules,
+    allow: Vec<String>,
+}
+
+impl Maintenance {
+    /// Answer commands other than those in `allow` (NAME or NAME|SUBCOMMAND) with `error`
+    /// while enabled
+    pub fn new(enabled: bool, error: &str, allow: Vec<String>) -> Arc<Self> {
+        Arc::new(Self {
+            enabled: AtomicBool::new(enabled),
+            error: RwLock::new(error.trim_start_matches('-').to_string()),
+            allowed: CommandRules::default().allow(&allow),
+            allow,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) -> String {
+        self.error.read().map(|e| e.clone()).unwrap_or_default()
+    }
+
+    /// Answer commands with `error` from now on, with or without its leading `-`
+    pub fn set_error(&self, error: &str) {
+        if let Ok(mut current) = self.error.write() {
+            *current = error.trim_start_matches('-').to_string();
+        }
+    }
+
+    /// The commands forwarded during maintenance
+    pub fn allowed(&self) -> &[String] {
+        &self.allow
+    }
+
+    /// The error `req` is answered with, if it isn't forwarded
+    fn refuses(&self, req: &BytesFrame) -> Option<String> {
+        if !self.is_enabled() || self.allowed.check(req).is_ok() {
+            return None;
+        }
+        Some(self.error())
+    }
+}
+
+pub struct MaintenanceLayer {
+    maintenance: Arc<Maintenance>,
+}
+
+impl MaintenanceLayer {
+    pub fn new(maintenance: Arc<Maintenance>) -> Self {
+        Self { maintenance }
+    }
+}
+
+impl<S> Layer<S> for MaintenanceLayer {
+    type Service = UnderMaintenance<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        UnderMaintenance {
+            inner: Arc::new(tokio::sync::Mutex::new(service)),
+            maintenance: self.maintenance.clone(),
+        }
+    }
+}
+
+/// The wrapped service is only made ready for the commands forwarded to it, within each one's
+/// future, so a lazily connected target isn't dialled for commands answered here
+pub struct UnderMaintenance<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    maintenance: Arc<Maintenance>,
+}
+
+/// Dispatch `req` to `inner` once it's ready
+async fn dispatch<S>(inner: &tokio::sync::Mutex<S>, req: BytesFrame) -> Result<ResponseStream>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error>,
+{
+    let fut = {
+        let mut inner = inner.lock().await;
+        futures::future::poll_fn(|cx| inner.poll_ready(cx))
+            .await
+            .map_err(Into::into)?;
+        inner.call(req)
+    };
+    fut.await.map_err(Into::into)
+}
+
+impl<S> Service<BytesFrame> for UnderMaintenance<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if let Some(error) = self.maintenance.refuses(&req) {
+            return Box::pin(async move { Ok(reply(command::error(error))) });
+        }
+        let inner = self.inner.clone();
+        Box::pin(async move { dispatch(&inner, req).await })
+    }
+}
@@ -0,0 +1,167 @@
+//! Traffic mirroring.
+//!
+//! `MirrorLayer` copies each request to a shadow target, such as a new Redis version being soak
+//! tested with production traffic, without the client noticing: the primary's replies are
+//! forwarded as usual while the shadow's are read and discarded, counted in `Stats` and logged at
+//! debug level. Requests reach the shadow through a bounded queue, so a slow or unavailable
+//! shadow only ever costs mirrored requests (counted as dropped), never client latency.
+//! Subscriptions and blocking commands aren't mirrored, since their replies would hold up the
+//! rest of the queue.
+//!
+//! For live migrations, `MirrorLayer::writes_only` turns this into dual-writing: every command
+//! which isn't known to be read-only (so including `SELECT`, `MULTI`, and the like) is copied to
+//! the new backend, while reads are served by the primary alone. Writes the new backend fails or
+//! has to drop are logged as well as counted, since they leave it out of sync.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::mpsc;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt as _;
+
+use crate::command;
+use crate::error::Error;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+/// Requests waiting for the shadow beyond this many are dropped
+static QUEUE_LEN: usize = 1024;
+
+pub struct MirrorLayer {
+    shadow: mpsc::Sender<BytesFrame>,
+    stats: Arc<Stats>,
+    writes_only: bool,
+}
+
+impl MirrorLayer {
+    /// Mirror requests to `shadow`, which is driven by a background task until this layer and
+    /// every service built from it have been dropped
+    pub fn new<T>(shadow: T, stats: Arc<Stats>) -> Self
+    where
+        T: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+        T::Error: Into<Error>,
+        T::Future: Send,
+    {
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(drive_shadow(shadow, receiver, stats.clone()));
+        Self {
+            shadow: sender,
+            stats,
+            writes_only: false,
+        }
+    }
+
+    /// Only mirror commands which may write, for dual-writing during a migration
+    pub fn writes_only(mut self) -> Self {
+        self.writes_only = true;
+        self
+    }
+}
+
+/// Send each queued request to `shadow` in turn, reading its reply in full before the next
+async fn drive_shadow<T>(mut shadow: T, mut requests: mpsc::Receiver<BytesFrame>, stats: Arc<Stats>)
+where
+    T: Service<BytesFrame, Response = ResponseStream>,
+    T::Error: Into<Error>,
+    T::Future: Send,
+{
+    let mut healthy = true;
+    while let Some(req) = requests.recv().await {
+        let name = command::name(&req).unwrap_or_default();
+        let fut = match shadow.ready().await {
+            Ok(shadow) => shadow.call(req),
+            Err(e) => {
+                stats.mirror.record_failed();
+                if healthy {
+                    log::warn!("Shadow target unavailable: {}", e.into());
+                }
+                healthy = false;
+                continue;
+            }
+        };
+        let frames = match fut.await.map_err(Into::<Error>::into) {
+            Ok(stream) => stream.collect::<Vec<_>>().await,
+            Err(e) => {
+                stats.mirror.record_failed();
+                if healthy {
+                    log::warn!("Mirroring {name} failed: {e}");
+                }
+                healthy = false;
+                continue;
+            }
+        };
+
+        if frames.is_empty() {
+            stats.mirror.record_failed();
+            if healthy {
+                log::warn!("Shadow target closed its connection before replying to {name}");
+            }
+            healthy = false;
+            continue;
+        }
+        if !healthy {
+            log::info!("Shadow target is replying again");
+            healthy = true;
+        }
+        if frames.iter().any(|f| matches!(f, BytesFrame::Error(_))) {
+            stats.mirror.record_error();
+            log::debug!("Shadow replied to {name} with an error: {frames:?}");
+        } else {
+            stats.mirror.record_replied();
+            log::trace!("Shadow replied to {name}: {frames:?}");
+        }
+    }
+}
+
+impl<S> Layer<S> for MirrorLayer {
+    type Service = Mirror<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Mirror {
+            inner: service,
+            shadow: self.shadow.clone(),
+            stats: self.stats.clone(),
+            writes_only: self.writes_only,
+        }
+    }
+}
+
+pub struct Mirror<S> {
+    inner: S,
+    shadow: mpsc::Sender<BytesFrame>,
+    stats: Arc<Stats>,
+    writes_only: bool,
+}
+
+impl<S> Service<BytesFrame> for Mirror<S>
+where
+    S: Service<BytesFrame>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let skipped = command::starts_push_mode(&req)
+            || command::is_blocking(&req)
+            || (self.writes_only && command::is_read_only(&req));
+        if !skipped && self.shadow.try_send(req.clone()).is_err() {
+            self.stats.mirror.record_dropped();
+            if self.writes_only {
+                log::warn!(
+                    "Dropped {} for the secondary target, which has fallen behind",
+                    command::name(&req).unwrap_or_default()
+                );
+            }
+        }
+        self.inner.call(req)
+    }
+}
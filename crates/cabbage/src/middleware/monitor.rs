@@ -0,0 +1,187 @@
+//! `MONITOR`, answered by the proxy.
+//!
+//! `MonitorLayer` never lets `MONITOR` reach the target. It streams the client a live feed of
+//! every command sent through this proxy instead, across all connections, each line naming the
+//! connection and client the command came from:
+//!
+//! ```text
+//! 1718030000.123456 [6f1c2d4e-8e0b-4a2b-9a51-3f5cb1c6d2aa 10.0.0.8:51234] "set" "k" "v"
+//! ```
+//!
+//! so the target is spared the cost of monitoring, and commands are shown whichever target they
+//! go to. Credentials are masked as in the logs. A monitoring client can only end the feed, with
+//! `QUIT` or `RESET`; its other commands are refused. Clients too slow to keep up with the feed
+//! miss lines rather than holding up anything else.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures::stream;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tokio_util::sync::CancellationToken;
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::Error;
+use crate::frame::quote;
+use crate::listener::ClientAddr;
+use crate::middleware::redact::RedactionRules;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+/// Replies to a monitoring client's own commands buffered before it reads them
+static MAX_BUFFERED_REPLIES: usize = 16;
+
+pub struct MonitorLayer {
+    stats: Arc<Stats>,
+    connection_id: Uuid,
+    client_addr: Option<ClientAddr>,
+    redaction: Arc<RedactionRules>,
+}
+
+impl MonitorLayer {
+    /// Show the commands of connection `connection_id`, from `client_addr` (if it has one), on
+    /// the feed in `stats`
+    pub fn new(stats: Arc<Stats>, connection_id: Uuid, client_addr: Option<ClientAddr>) -> Self {
+        Self {
+            stats,
+            connection_id,
+            client_addr,
+            redaction: Arc::new(RedactionRules::default()),
+        }
+    }
+
+    /// Mask sensitive values according to `redaction` rather than the default rules
+    pub fn with_redaction(mut self, redaction: impl Into<Arc<RedactionRules>>) -> Self {
+        self.redaction = redaction.into();
+        self
+    }
+}
+
+impl<S> Layer<S> for MonitorLayer {
+    type Service = Monitor<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let client = match &self.client_addr {
+            Some(addr) => format!("{} {addr}", self.connection_id),
+            None => self.connection_id.to_string(),
+        };
+        Monitor {
+            inner: service,
+            stats: self.stats.clone(),
+            client,
+            redaction: self.redaction.clone(),
+            monitoring: None,
+        }
+    }
+}
+
+/// A connection's monitoring feed
+struct Monitoring {
+    /// Carries replies to the client's own commands into the feed
+    replies: mpsc::Sender<BytesFrame>,
+    /// Ends the feed
+    end: CancellationToken,
+}
+
+pub struct Monitor<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    /// How the connection is named on the feed
+    client: String,
+    redaction: Arc<RedactionRules>,
+    monitoring: Option<Monitoring>,
+}
+
+impl<S> Monitor<S> {
+    /// The feed's line for `req`
+    fn line(&self, req: &BytesFrame) -> Bytes {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = BytesMut::new();
+        line.put_slice(
+            format!(
+                "{}.{:06} [{}]",
+                now.as_secs(),
+                now.subsec_micros(),
+                self.client
+            )
+            .as_bytes(),
+        );
+        let req = self.redaction.request(req);
+        for arg in command::args(&req).unwrap_or_default() {
+            line.put_u8(b' ');
+            match arg {
+                BytesFrame::Integer(i) => quote(i.to_string().as_bytes(), &mut line),
+                arg => quote(
+                    command::arg_bytes(arg).map_or(&b""[..], |a| a.as_ref()),
+                    &mut line,
+                ),
+            }
+        }
+        line.freeze()
+    }
+}
+
+impl<S> Service<BytesFrame> for Monitor<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        if let Some(monitoring) = &self.monitoring {
+            if matches!(name.as_deref(), Some("QUIT" | "RESET")) {
+                // Ending the feed lets the reply follow it
+                monitoring.end.cancel();
+                self.monitoring = None;
+                return Box::pin(self.inner.call(req).map_err(Into::into));
+            }
+            let replies = monitoring.replies.clone();
+            return Box::pin(async move {
+                let error =
+                    command::error("ERR cabbage: only QUIT and RESET are allowed while monitoring");
+                // A closed feed means the client is gone
+                let _ = replies.send(error).await;
+                Ok(stream::empty().boxed())
+            });
+        }
+        if name.as_deref() == Some("MONITOR") {
+            let (replies, replies_receiver) = mpsc::channel(MAX_BUFFERED_REPLIES);
+            let end = CancellationToken::new();
+            let lines = BroadcastStream::new(self.stats.monitor.watch()).filter_map(|line| async {
+                // Lines a slow client missed are skipped
+                line.ok().map(BytesFrame::SimpleString)
+            });
+            let feed = stream::once(async { BytesFrame::SimpleString("OK".into()) })
+                .chain(stream::select(lines, ReceiverStream::new(replies_receiver)))
+                .take_until(end.clone().cancelled_owned());
+            self.monitoring = Some(Monitoring { replies, end });
+            return Box::pin(async move { Ok(feed.boxed()) });
+        }
+        if self.stats.monitor.is_watched() {
+            self.stats.monitor.publish(self.line(&req));
+        }
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
@@ -0,0 +1,441 @@
+//! Keyspace notifications.
+//!
+//! Redis publishes keyspace events on `__keyspace@<db>__:<key>` and `__keyevent@<db>__:<event>`
+//! channels, but only to subscribers on the node holding the key, and a subscription dies with
+//! the connection carrying it. `KeyspaceNotificationLayer` takes over each `SUBSCRIBE` or
+//! `PSUBSCRIBE` naming only such channels: it subscribes on connections of its own to every node
+//! the events can come from (each cluster node owning slots, or the current master of a
+//! Sentinel deployment), merges their messages into the client's subscription, and when any of
+//! those connections is lost, reconnects and subscribes again. A client's subscription so
+//! survives failovers and target restarts, though events published while reconnecting are lost,
+//! as pub/sub is fire-and-forget.
+//!
+//! Subscription replies are the proxy's own, counting the client's subscriptions rather than
+//! any one node's. While subscribed, a client can (un)subscribe to further keyspace channels and
+//! `PING`, as on a subscribed Redis connection; other commands are refused until it has
+//! unsubscribed from everything, or ended the subscription with `QUIT` or `RESET`.
+
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::stream::{self, SplitSink};
+use futures::{Future, SinkExt as _, TryFutureExt as _};
+use futures_util::StreamExt;
+use redis_protocol::codec::Resp2;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+use tokio_util::task::AbortOnDropHandle;
+use tower::Layer;
+use tower::Service;
+
+use crate::balance::BalancedTargets;
+use crate::cluster::ClusterSlots;
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::failover::FailoverTargets;
+use crate::sentinel::SentinelMaster;
+use crate::service::{Handshake, ResponseStream};
+
+/// Delay before reconnecting after losing a node's subscription
+static RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+/// Channels (and patterns) carrying keyspace events start with one of these
+static KEYSPACE_PREFIXES: [&[u8]; 2] = [b"__keyspace@", b"__keyevent@"];
+/// Events buffered for a client before reading from the nodes waits for it
+const MAX_BUFFERED_EVENTS: usize = 1024;
+
+/// Where keyspace events are published
+pub enum NotificationSource {
+    Single {
+        address: String,
+        handshake: Handshake,
+    },
+    /// The current master of a Sentinel deployment
+    Sentinel {
+        master: Arc<SentinelMaster>,
+        handshake: Handshake,
+    },
+    /// Every node of a cluster owning slots
+    Cluster(Arc<ClusterSlots>),
+    /// The target in use of a failover list
+    Failover {
+        targets: Arc<FailoverTargets>,
+        handshake: Handshake,
+    },
+    /// Every balanced target, as keys may change on any of them
+    Balanced {
+        targets: Arc<BalancedTargets>,
+        handshake: Handshake,
+    },
+}
+
+impl NotificationSource {
+    /// The nodes to subscribe on, and the handshake for each connection
+    async fn nodes(&self) -> Result<(Vec<Arc<str>>, &Handshake)> {
+        match self {
+            Self::Single { address, handshake } => Ok((vec![address.as_str().into()], handshake)),
+            Self::Sentinel { master, handshake } => {
+                Ok((vec![master.resolve().await?.into()], handshake))
+            }
+            Self::Cluster(slots) => {
+                // Nodes may have come and gone since the subscription was lost
+                slots.refresh().await?;
+                Ok((slots.nodes(), slots.handshake()))
+            }
+            Self::Failover { targets, handshake } => {
+                Ok((vec![targets.current().into()], handshake))
+            }
+            Self::Balanced { targets, handshake } => Ok((
+                targets
+                    .addresses()
+                    .iter()
+                    .map(|a| a.as_str().into())
+                    .collect(),
+                handshake,
+            )),
+        }
+    }
+}
+
+/// Whether `channel` (or a pattern) names keyspace events
+pub(crate) fn is_keyspace_channel(channel: &[u8]) -> bool {
+    KEYSPACE_PREFIXES
+        .iter()
+        .any(|prefix| channel.starts_with(prefix))
+}
+
+/// The channels a client is subscribed to, and the patterns
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Subscriptions {
+    pub(crate) channels: BTreeSet<Bytes>,
+    pub(crate) patterns: BTreeSet<Bytes>,
+}
+
+impl Subscriptions {
+    pub(crate) fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub(crate) fn of(&mut self, kind: Kind) -> &mut BTreeSet<Bytes> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+
+    /// The requests taking a connection subscribed to `self` to being subscribed to `wanted`
+    pub(crate) fn changes_to(&self, wanted: &Subscriptions) -> Vec<BytesFrame> {
+        let mut requests = vec![];
+        for (kind, have, want) in [
+            (Kind::Channel, &self.channels, &wanted.channels),
+            (Kind::Pattern, &self.patterns, &wanted.patterns),
+        ] {
+            let (subscribe, unsubscribe) = kind.commands();
+            let added: Vec<_> = want.difference(have).collect();
+            if !added.is_empty() {
+                requests.push(command::request(
+                    std::iter::once(subscribe.as_bytes()).chain(added.iter().map(|c| c.as_ref())),
+                ));
+            }
+            let removed: Vec<_> = have.difference(want).collect();
+            if !removed.is_empty() {
+                requests.push(command::request(
+                    std::iter::once(unsubscribe.as_bytes())
+                        .chain(removed.iter().map(|c| c.as_ref())),
+                ));
+            }
+        }
+        requests
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Channel,
+    Pattern,
+}
+
+impl Kind {
+    /// The commands subscribing and unsubscribing to this kind of subscription
+    fn commands(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Channel => ("SUBSCRIBE", "UNSUBSCRIBE"),
+            Self::Pattern => ("PSUBSCRIBE", "PUNSUBSCRIBE"),
+        }
+    }
+
+    /// The reply confirming a subscription change, leaving the client with `count`
+    pub(crate) fn confirmation(
+        self,
+        subscribed: bool,
+        channel: Option<Bytes>,
+        count: usize,
+    ) -> BytesFrame {
+        let kind = match (self, subscribed) {
+            (Self::Channel, true) => "subscribe",
+            (Self::Channel, false) => "unsubscribe",
+            (Self::Pattern, true) => "psubscribe",
+            (Self::Pattern, false) => "punsubscribe",
+        };
+        BytesFrame::Array(vec![
+            BytesFrame::BulkString(Bytes::from_static(kind.as_bytes())),
+            channel.map_or(BytesFrame::Null, BytesFrame::BulkString),
+            BytesFrame::Integer(count as i64),
+        ])
+    }
+}
+
+/// Whether `frame` is a published message, rather than a node confirming a subscription
+fn is_message(frame: &BytesFrame) -> bool {
+    let BytesFrame::Array(parts) = frame else {
+        return false;
+    };
+    parts
+        .first()
+        .and_then(command::arg_bytes)
+        .is_some_and(|kind| kind.as_ref() == b"message" || kind.as_ref() == b"pmessage")
+}
+
+/// Keep subscribed to `wanted` on every node of `source`, sending the events to `events`, until
+/// the client unsubscribes or goes away
+async fn relay_events(
+    source: Arc<NotificationSource>,
+    mut wanted: watch::Receiver<Subscriptions>,
+    events: mpsc::Sender<BytesFrame>,
+) {
+    loop {
+        match subscribe_nodes(&source, &mut wanted, &events).await {
+            Ok(()) => return,
+            Err(e) => log::warn!("Lost keyspace notification subscription, resubscribing: {e:#}"),
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+/// Subscribe to `wanted` on every node of `source` until the client is done with the
+/// subscription, failing once any node's connection is lost
+async fn subscribe_nodes(
+    source: &NotificationSource,
+    wanted: &mut watch::Receiver<Subscriptions>,
+    events: &mpsc::Sender<BytesFrame>,
+) -> Result<()> {
+    let (nodes, handshake) = source.nodes().await?;
+    let mut sinks: Vec<SplitSink<Framed<TcpStream, Resp2>, BytesFrame>> = vec![];
+    let mut streams = vec![];
+    for node in nodes {
+        let socket = handshake
+            .connect(&node)
+            .await
+            .map_err(|e| Error::BackendUnavailable(format!("Failed to connect to {node}: {e}")))?;
+        let mut framed = Framed::new(socket, Resp2::default());
+        handshake.perform(&mut framed).await?;
+        let (sink, stream) = framed.split();
+        sinks.push(sink);
+        streams.push(stream);
+    }
+    let mut published = stream::select_all(streams);
+    let mut subscribed = Subscriptions::default();
+    loop {
+        let current = wanted.borrow_and_update().clone();
+        for request in subscribed.changes_to(&current) {
+            for sink in &mut sinks {
+                sink.send(request.clone()).await?;
+            }
+        }
+        subscribed = current;
+        tokio::select! {
+            changed = wanted.changed() => if changed.is_err() {
+                return Ok(());
+            },
+            frame = published.next() => match frame {
+                Some(Ok(frame)) => {
+                    if is_message(&frame) && events.send(frame).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!(BackendUnavailable, "A node closed its connection"),
+            },
+        }
+    }
+}
+
+/// A client's keyspace subscription
+struct Subscribed {
+    /// The client's subscription stream, carrying events and the replies to its commands
+    push: mpsc::Sender<BytesFrame>,
+    wanted: watch::Sender<Subscriptions>,
+    _relay: AbortOnDropHandle<()>,
+}
+
+pub struct KeyspaceNotificationLayer {
+    source: Arc<NotificationSource>,
+}
+
+impl KeyspaceNotificationLayer {
+    pub fn new(source: Arc<NotificationSource>) -> Self {
+        Self { source }
+    }
+}
+
+impl<S> Layer<S> for KeyspaceNotificationLayer {
+    type Service = KeyspaceNotifications<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        KeyspaceNotifications {
+            inner: service,
+            source: self.source.clone(),
+            subscribed: None,
+        }
+    }
+}
+
+pub struct KeyspaceNotifications<S> {
+    inner: S,
+    source: Arc<NotificationSource>,
+    subscribed: Option<Subscribed>,
+}
+
+impl<S> KeyspaceNotifications<S> {
+    /// Send `replies` on the client's subscription stream, answering the request itself with
+    /// nothing, as its replies are on that stream
+    fn push(
+        push: mpsc::Sender<BytesFrame>,
+        replies: Vec<BytesFrame>,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponseStream>> + Send>> {
+        Box::pin(async move {
+            for reply in replies {
+                // A closed stream means the client is gone
+                let _ = push.send(reply).await;
+            }
+            Ok(stream::empty().boxed())
+        })
+    }
+}
+
+impl<S> Service<BytesFrame> for KeyspaceNotifications<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let name = command::name(&req);
+        let channels: Vec<Bytes> = command::args(&req)
+            .and_then(|args| args.get(1..))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(command::arg_bytes)
+            .cloned()
+            .collect();
+        let kind = match name.as_deref() {
+            Some("SUBSCRIBE" | "UNSUBSCRIBE") => Some(Kind::Channel),
+            Some("PSUBSCRIBE" | "PUNSUBSCRIBE") => Some(Kind::Pattern),
+            _ => None,
+        };
+        let subscribing = matches!(name.as_deref(), Some("SUBSCRIBE" | "PSUBSCRIBE"));
+        let keyspace_only = !channels.is_empty() && channels.iter().all(|c| is_keyspace_channel(c));
+
+        let Some(subscribed) = &self.subscribed else {
+            let Some(kind) = kind.filter(|_| subscribing && keyspace_only) else {
+                return Box::pin(self.inner.call(req).map_err(Into::into));
+            };
+            let mut wanted = Subscriptions::default();
+            let confirmations: Vec<_> = channels
+                .into_iter()
+                .map(|channel| {
+                    wanted.of(kind).insert(channel.clone());
+                    kind.confirmation(true, Some(channel), wanted.count())
+                })
+                .collect();
+            let (push, events) = mpsc::channel(MAX_BUFFERED_EVENTS);
+            let (wanted, watching) = watch::channel(wanted);
+            let relay = tokio::spawn(relay_events(self.source.clone(), watching, push.clone()));
+            self.subscribed = Some(Subscribed {
+                push,
+                wanted,
+                _relay: AbortOnDropHandle::new(relay),
+            });
+            let responses = stream::iter(confirmations).chain(ReceiverStream::new(events));
+            return Box::pin(async move { Ok(responses.boxed()) });
+        };
+
+        let push = subscribed.push.clone();
+        let replies = match (name.as_deref(), kind) {
+            (Some("QUIT" | "RESET"), _) => {
+                // Ending the subscription ends its stream, so the reply follows it
+                self.subscribed = None;
+                return Box::pin(self.inner.call(req).map_err(Into::into));
+            }
+            (_, Some(kind)) if subscribing => {
+                if !keyspace_only {
+                    let error = command::error(
+                        "ERR cabbage: only keyspace notification channels can be added to this \
+                         subscription",
+                    );
+                    return Self::push(push, vec![error]);
+                }
+                let mut confirmations = vec![];
+                subscribed.wanted.send_modify(|wanted| {
+                    for channel in channels {
+                        wanted.of(kind).insert(channel.clone());
+                        confirmations.push(kind.confirmation(true, Some(channel), wanted.count()));
+                    }
+                });
+                confirmations
+            }
+            (_, Some(kind)) => {
+                let mut confirmations = vec![];
+                subscribed.wanted.send_modify(|wanted| {
+                    let channels = if channels.is_empty() {
+                        std::mem::take(wanted.of(kind)).into_iter().collect()
+                    } else {
+                        channels
+                    };
+                    for channel in channels {
+                        wanted.of(kind).remove(&channel);
+                        confirmations.push(kind.confirmation(false, Some(channel), wanted.count()));
+                    }
+                    if confirmations.is_empty() {
+                        confirmations.push(kind.confirmation(false, None, wanted.count()));
+                    }
+                });
+                if subscribed.wanted.borrow().count() == 0 {
+                    // The stream ends once the last confirmation is sent
+                    self.subscribed = None;
+                }
+                confirmations
+            }
+            (Some("PING"), _) => {
+                let message = channels.into_iter().next().unwrap_or_default();
+                vec![BytesFrame::Array(vec![
+                    BytesFrame::BulkString(Bytes::from_static(b"pong")),
+                    BytesFrame::BulkString(message),
+                ])]
+            }
+            _ => {
+                let error = command::error(format!(
+                    "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / \
+                     RESET are allowed in this context",
+                    name.unwrap_or_default().to_lowercase()
+                ));
+                vec![error]
+            }
+        };
+        Self::push(push, replies)
+    }
+}
@@ -0,0 +1,265 @@
+//! WebAssembly plugins.
+//!
+//! `PluginLayer` passes each command, and each frame of its reply, through a WebAssembly module,
+//! so filtering and rewriting logic can be deployed without rebuilding cabbage. A plugin module
+//! exports its `memory`, an `alloc(len: i32) -> i32` returning where the proxy may write `len`
+//! bytes, and either or both of the hooks:
+//!
+//! - `on_request(ptr: i32, len: i32) -> i64`, given a RESP2-encoded command
+//! - `on_response(ptr: i32, len: i32) -> i64`, given a RESP2-encoded reply frame
+//!
+//! A hook returns 0 to let its frame through unchanged, or the location of a RESP2-encoded
+//! replacement in its memory, packed as `ptr << 32 | len`. A command replaced by anything other
+//! than an array isn't forwarded at all; the replacement is the client's reply, so returning an
+//! error frame refuses the command. A hook which traps is answered with an error in place of its
+//! frame.
+//!
+//! Each plugin keeps a pool of instances, so hooks run on as many worker threads at once as need
+//! them; an instance is only made when none is idle. State a plugin keeps between calls is kept by
+//! the instance which happened to serve them, so hooks shouldn't rely on it. Hooks are metered: one
+//! which runs more than `FUEL` WebAssembly operators on a frame is stopped, and fails like one
+//! which traps, so a plugin stuck in a loop can't take over a worker thread. An instance whose
+//! hook failed is dropped rather than reused.
+
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder as _, Encoder as _};
+use tower::Layer;
+use tower::Service;
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    CompilerConfig as _, Cranelift, Instance, Memory, Module, NativeFunc, Store, Universal, imports,
+};
+use wasmer_middlewares::Metering;
+use wasmer_middlewares::metering::{MeteringPoints, get_remaining_points, set_remaining_points};
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+type Hook = NativeFunc<(i32, i32), i64>;
+
+/// The most WebAssembly operators a hook may run (counting the `alloc` before it) on one frame
+static FUEL: u64 = 10_000_000;
+
+/// A loaded plugin module
+pub struct Plugin {
+    name: String,
+    module: Module,
+    /// Instances not running a hook
+    idle: Mutex<Vec<PluginInstance>>,
+    handles_requests: bool,
+    handles_responses: bool,
+}
+
+impl Plugin {
+    /// Compile and instantiate the module at `path`, a `.wasm` binary or `.wat` text
+    pub fn load(path: &Path) -> Result<Arc<Self>> {
+        let name = path.display().to_string();
+        let mut compiler = Cranelift::default();
+        compiler.push_middleware(Arc::new(Metering::new(FUEL, |_: &Operator| 1)));
+        let store = Store::new(&Universal::new(compiler).engine());
+        let module = Module::from_file(&store, path)
+            .map_err(|e| Error::Other(format!("Failed to compile plugin {name}: {e}").into()))?;
+        let instance = PluginInstance::new(&module).map_err(|e| {
+            Error::Other(format!("Failed to instantiate plugin {name}: {e}").into())
+        })?;
+        if instance.on_request.is_none() && instance.on_response.is_none() {
+            bail!(
+                Config,
+                "Plugin {name} exports neither on_request nor on_response"
+            );
+        }
+        Ok(Arc::new(Self {
+            name,
+            module,
+            handles_requests: instance.on_request.is_some(),
+            handles_responses: instance.on_response.is_some(),
+            idle: Mutex::new(vec![instance]),
+        }))
+    }
+
+    /// Pass `req` through the `on_request` hook, if there is one
+    fn on_request(&self, req: &BytesFrame) -> Option<BytesFrame> {
+        if !self.handles_requests {
+            return None;
+        }
+        self.run(req, |instance| instance.on_request.as_ref())
+    }
+
+    /// Pass `frame` through the `on_response` hook, if there is one
+    fn on_response(&self, frame: &BytesFrame) -> Option<BytesFrame> {
+        self.run(frame, |instance| instance.on_response.as_ref())
+    }
+
+    /// Run the hook `hook` picks on `frame` with an idle instance, or a new one if none is,
+    /// answering with an error if it fails
+    fn run(
+        &self,
+        frame: &BytesFrame,
+        hook: impl FnOnce(&PluginInstance) -> Option<&Hook>,
+    ) -> Option<BytesFrame> {
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let instance = match idle.map_or_else(|| PluginInstance::new(&self.module), Ok) {
+            Ok(instance) => instance,
+            Err(e) => {
+                log::error!("Failed to instantiate plugin {}: {e:#}", self.name);
+                return Some(command::error("ERR cabbage: plugin unavailable"));
+            }
+        };
+        let Some(hook) = hook(&instance) else {
+            self.release(instance);
+            return None;
+        };
+        match instance.run(hook, frame) {
+            Ok(replacement) => {
+                self.release(instance);
+                replacement
+            }
+            Err(e) => {
+                log::error!("Plugin {} failed: {e:#}", self.name);
+                Some(command::error(format!("ERR cabbage: plugin failed: {e}")))
+            }
+        }
+    }
+
+    fn release(&self, instance: PluginInstance) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push(instance);
+        }
+    }
+}
+
+struct PluginInstance {
+    instance: Instance,
+    memory: Memory,
+    alloc: NativeFunc<i32, i32>,
+    on_request: Option<Hook>,
+    on_response: Option<Hook>,
+}
+
+impl PluginInstance {
+    fn new(module: &Module) -> Result<Self> {
+        let instance = Instance::new(module, &imports! {}).map_err(Error::other)?;
+        let exports = &instance.exports;
+        Ok(Self {
+            memory: exports.get_memory("memory").map_err(Error::other)?.clone(),
+            alloc: exports.get_native_function("alloc").map_err(Error::other)?,
+            on_request: exports.get_native_function("on_request").ok(),
+            on_response: exports.get_native_function("on_response").ok(),
+            instance,
+        })
+    }
+
+    /// Run `hook` on `frame`, returning its replacement if it has one
+    fn run(&self, hook: &Hook, frame: &BytesFrame) -> Result<Option<BytesFrame>> {
+        set_remaining_points(&self.instance, FUEL);
+        let result = self.run_metered(hook, frame);
+        if result.is_err() && get_remaining_points(&self.instance) == MeteringPoints::Exhausted {
+            bail!(Other, "hook ran more than {FUEL} operators");
+        }
+        result
+    }
+
+    fn run_metered(&self, hook: &Hook, frame: &BytesFrame) -> Result<Option<BytesFrame>> {
+        let mut encoded = BytesMut::new();
+        Resp2::default().encode(frame.clone(), &mut encoded)?;
+        let len = i32::try_from(encoded.len()).map_err(Error::other)?;
+        let ptr = self.alloc.call(len).map_err(Error::other)?;
+
+        let view = self.memory.view::<u8>();
+        let start = u32::try_from(ptr).map_err(Error::other)? as usize;
+        let Some(input) = view.get(start..start + encoded.len()) else {
+            bail!(Other, "alloc returned memory out of bounds");
+        };
+        input
+            .iter()
+            .zip(encoded.iter())
+            .for_each(|(cell, byte)| cell.set(*byte));
+
+        let packed = hook.call(ptr, len).map_err(Error::other)? as u64;
+        if packed == 0 {
+            return Ok(None);
+        }
+        let start = (packed >> 32) as usize;
+        let len = (packed & 0xffff_ffff) as usize;
+        let view = self.memory.view::<u8>();
+        let Some(output) = view.get(start..start + len) else {
+            bail!(Other, "replacement frame is out of bounds");
+        };
+        let mut replacement: BytesMut = output.iter().map(|cell| cell.get()).collect();
+        match Resp2::default().decode(&mut replacement)? {
+            Some(frame) => Ok(Some(frame)),
+            None => bail!(Other, "replacement frame is incomplete"),
+        }
+    }
+}
+
+pub struct PluginLayer {
+    plugin: Arc<Plugin>,
+}
+
+impl PluginLayer {
+    pub fn new(plugin: Arc<Plugin>) -> Self {
+        Self { plugin }
+    }
+}
+
+impl<S> Layer<S> for PluginLayer {
+    type Service = PluginService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        PluginService {
+            inner: service,
+            plugin: self.plugin.clone(),
+        }
+    }
+}
+
+pub struct PluginService<S> {
+    inner: S,
+    plugin: Arc<Plugin>,
+}
+
+impl<S> Service<BytesFrame> for PluginService<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let req = match self.plugin.on_request(&req) {
+            None => req,
+            Some(rewritten @ BytesFrame::Array(_)) => rewritten,
+            Some(answer) => return Box::pin(async move { Ok(reply(answer)) }),
+        };
+        let fut = self.inner.call(req).map_err(Into::into);
+        if !self.plugin.handles_responses {
+            return Box::pin(fut);
+        }
+        let plugin = self.plugin.clone();
+        Box::pin(async move {
+            Ok(fut
+                .await?
+                .map(move |frame| plugin.on_response(&frame).unwrap_or(frame))
+                .boxed())
+        })
+    }
+}
@@ -0,0 +1,267 @@
+//! Transparent key namespacing.
+//!
+//! `KeyPrefixLayer` prepends a fixed prefix to every key a command references before it reaches
+//! the target, and scopes keyspace-wide lookups (`KEYS`, `SCAN`) to that prefix, stripping it
+//! again from the key names they return. Several applications can then share one
+//! Redis, each through its own cabbage, without their keys colliding. With
+//! `KeyPrefixLayer::per_user`, the prefix is instead the one given to the user the client has
+//! authenticated to the proxy as, so they can share a cabbage too. Commands whose keys can't be
+//! found among their arguments (`MIGRATE`, `SORT ... BY`, commands cabbage doesn't know) are
+//! refused while a prefix applies, as they could reach keys outside of it, and so are commands
+//! acting on the whole database (`FLUSHDB`, `FLUSHALL`, `SWAPDB`, `DBSIZE`, `RANDOMKEY`) or
+//! moving keys between databases (`MOVE`).
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::auth::ClientUser;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// Where a reply holds key names which need to be un-prefixed (or otherwise mapped back)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReplyKeys {
+    None,
+    /// The reply is a bulk string key (`RANDOMKEY`)
+    Single,
+    /// The reply is an array of keys (`KEYS`)
+    Array,
+    /// The reply is a `[cursor, [keys...]]` pair (`SCAN`)
+    Scan,
+}
+
+impl ReplyKeys {
+    /// Where the reply to the command called `name` holds key names
+    pub(crate) fn of(name: Option<&str>) -> Self {
+        match name {
+            Some("KEYS") => Self::Array,
+            Some("SCAN") => Self::Scan,
+            Some("RANDOMKEY") => Self::Single,
+            _ => Self::None,
+        }
+    }
+
+    /// Pass each key name in `frame`, a reply, through `map`
+    pub(crate) fn map(self, frame: BytesFrame, map: impl Fn(Bytes) -> Bytes) -> BytesFrame {
+        let map_key = |frame| match frame {
+            BytesFrame::BulkString(key) => BytesFrame::BulkString(map(key)),
+            other => other,
+        };
+        match (self, frame) {
+            (Self::Single, frame) => map_key(frame),
+            (Self::Array, BytesFrame::Array(keys)) => {
+                BytesFrame::Array(keys.into_iter().map(map_key).collect())
+            }
+            (Self::Scan, BytesFrame::Array(mut parts)) => {
+                if let Some(BytesFrame::Array(keys)) = parts.get_mut(1) {
+                    *keys = std::mem::take(keys).into_iter().map(map_key).collect();
+                }
+                BytesFrame::Array(parts)
+            }
+            (_, frame) => frame,
+        }
+    }
+}
+
+/// Escape glob metacharacters so a literal prefix can lead a `KEYS`/`SCAN` pattern
+pub(crate) fn glob_escape(literal: &[u8]) -> BytesMut {
+    let mut escaped = BytesMut::with_capacity(literal.len());
+    for &b in literal {
+        if matches!(b, b'*' | b'?' | b'[' | b']' | b'\\') {
+            escaped.extend_from_slice(b"\\");
+        }
+        escaped.extend_from_slice(&[b]);
+    }
+    escaped
+}
+
+/// Where a `KeyPrefix` gets its prefix
+#[derive(Clone, Debug)]
+enum Prefix {
+    Fixed(Bytes),
+    /// The prefix of the user the client authenticated as, if it has one
+    PerUser {
+        prefixes: Arc<HashMap<String, Bytes>>,
+        user: ClientUser,
+    },
+}
+
+impl Prefix {
+    fn get(&self) -> Option<Bytes> {
+        match self {
+            Self::Fixed(prefix) => Some(prefix.clone()),
+            Self::PerUser { prefixes, user } => prefixes.get(&user.get()?).cloned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyPrefixLayer {
+    prefix: Prefix,
+}
+
+impl KeyPrefixLayer {
+    pub fn new(prefix: impl Into<Bytes>) -> Self {
+        Self {
+            prefix: Prefix::Fixed(prefix.into()),
+        }
+    }
+
+    /// Prefix the keys of a client authenticated as a user in `prefixes` with that user's
+    /// prefix, the user being recorded in `user`, and leave other clients' keys be
+    pub fn per_user(prefixes: Arc<HashMap<String, Bytes>>, user: ClientUser) -> Self {
+        Self {
+            prefix: Prefix::PerUser { prefixes, user },
+        }
+    }
+}
+
+impl<S> Layer<S> for KeyPrefixLayer {
+    type Service = KeyPrefix<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        KeyPrefix {
+            inner: service,
+            prefix: self.prefix.clone(),
+        }
+    }
+}
+
+pub struct KeyPrefix<S> {
+    inner: S,
+    prefix: Prefix,
+}
+
+fn prefixed(prefix: &[u8], arg: &Bytes) -> Bytes {
+    let mut key = BytesMut::with_capacity(prefix.len() + arg.len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(arg);
+    key.freeze()
+}
+
+fn prefixed_pattern(prefix: &[u8], pattern: &[u8]) -> Bytes {
+    let mut escaped = glob_escape(prefix);
+    escaped.extend_from_slice(pattern);
+    escaped.freeze()
+}
+
+/// `req` with its keys prefixed, and where its reply holds key names to strip the prefix from
+fn rewrite_request(prefix: &[u8], req: BytesFrame) -> (BytesFrame, ReplyKeys) {
+    let key_indices = command::key_indices(&req);
+    let name = command::name(&req);
+    let BytesFrame::Array(mut args) = req else {
+        return (req, ReplyKeys::None);
+    };
+
+    for i in key_indices {
+        if let Some(BytesFrame::BulkString(key)) = args.get(i) {
+            args[i] = BytesFrame::BulkString(prefixed(prefix, key));
+        }
+    }
+
+    let reply_keys = ReplyKeys::of(name.as_deref());
+    match reply_keys {
+        ReplyKeys::Array => {
+            if let Some(pattern) = args.get(1).and_then(command::arg_bytes) {
+                args[1] = BytesFrame::BulkString(prefixed_pattern(prefix, pattern));
+            }
+        }
+        ReplyKeys::Scan => {
+            let match_at = args.iter().skip(2).position(|a| {
+                command::arg_bytes(a).is_some_and(|a| a.eq_ignore_ascii_case(b"MATCH"))
+            });
+            match match_at.map(|i| i + 3) {
+                Some(pattern_at) if pattern_at < args.len() => {
+                    if let Some(pattern) = command::arg_bytes(&args[pattern_at]) {
+                        args[pattern_at] =
+                            BytesFrame::BulkString(prefixed_pattern(prefix, pattern));
+                    }
+                }
+                _ => {
+                    args.push(BytesFrame::BulkString(Bytes::from_static(b"MATCH")));
+                    args.push(BytesFrame::BulkString(prefixed_pattern(prefix, b"*")));
+                }
+            }
+        }
+        ReplyKeys::Single | ReplyKeys::None => {}
+    }
+    (BytesFrame::Array(args), reply_keys)
+}
+
+fn strip(prefix: &Bytes, key: Bytes) -> Bytes {
+    if key.starts_with(prefix) {
+        key.slice(prefix.len()..)
+    } else {
+        key
+    }
+}
+
+/// Whether the command called `name` acts on or reveals the whole database, or takes keys out of
+/// it, rather than keeping to keys of its own
+fn spans_keyspace(name: &str) -> bool {
+    matches!(
+        name,
+        "DBSIZE" | "FLUSHALL" | "FLUSHDB" | "MOVE" | "RANDOMKEY" | "SWAPDB"
+    )
+}
+
+impl<S> Service<BytesFrame> for KeyPrefix<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let Some(prefix) = self.prefix.get() else {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+        // Commands spanning the database, or whose keys can't be found, could reach keys outside
+        // of the prefix
+        let name = command::name(&req).unwrap_or_default();
+        let refusal = if spans_keyspace(&name) {
+            Some("it reaches beyond the prefix")
+        } else if command::known_key_indices(&req).is_none() {
+            Some("its keys can't be found")
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            let message = format!("ERR '{name}' can't be used with key prefixing, as {reason}");
+            return Box::pin(async move { Ok(reply(command::error(message))) });
+        }
+        let (req, reply_keys) = rewrite_request(&prefix, req);
+        let fut = self.inner.call(req);
+        if reply_keys == ReplyKeys::None {
+            return Box::pin(fut.map_err(Into::into));
+        }
+
+        Box::pin(
+            fut.map_ok(move |stream| {
+                stream
+                    .map(move |frame| reply_keys.map(frame, |key| strip(&prefix, key)))
+                    .boxed()
+            })
+            .map_err(Into::into),
+        )
+    }
+}
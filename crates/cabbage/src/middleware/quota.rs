@@ -0,0 +1,314 @@
+//! Per-client quotas.
+//!
+//! Where the rate limiter meters each connection on its own, `QuotaLayer` meters everything a
+//! client does, across all of its connections, keyed on who it is: the user it authenticated as
+//! (the default user if it didn't), or the address it connects from. Each identity may be held
+//! to a number of commands per second, a number of commands awaiting replies at once, and a
+//! number of bytes a day, counting both its requests and the replies to them. A command beyond
+//! any of them is answered with an error without being forwarded: `-BUSY` for the first two,
+//! which free up soon, and `-ERR` once the day's bytes are spent, which only come back at
+//! midnight UTC. Refusals are counted in `Stats::quotas`.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::{Error, bail};
+use crate::listener::ClientAddr;
+use crate::middleware::auth::{ClientUser, DEFAULT_USER};
+use crate::middleware::ratelimit::TokenBucket;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+use crate::{command, frame};
+
+/// Identities tracked before those idle today are forgotten
+static MAX_IDLE_IDENTITIES: usize = 10_000;
+static SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What a client's quota is keyed on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaKey {
+    /// The user the client authenticated as, or the default user
+    #[default]
+    User,
+    /// The client's IP address; clients on Unix sockets share one quota
+    Client,
+}
+
+impl std::str::FromStr for QuotaKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(QuotaKey::User),
+            "client" => Ok(QuotaKey::Client),
+            _ => bail!(Config, "Unrecognized quota key '{s}'"),
+        }
+    }
+}
+
+/// The limits each client identity is held to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Quota {
+    key: QuotaKey,
+    commands_per_sec: Option<u32>,
+    max_in_flight: Option<usize>,
+    bytes_per_day: Option<u64>,
+}
+
+impl Quota {
+    pub fn new(key: QuotaKey) -> Self {
+        Self {
+            key,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_commands_per_sec(mut self, commands_per_sec: u32) -> Self {
+        self.commands_per_sec = Some(commands_per_sec.max(1));
+        self
+    }
+
+    /// Limit the commands awaiting replies at once
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight.max(1));
+        self
+    }
+
+    /// Limit the request and reply bytes each UTC day
+    pub fn with_bytes_per_day(mut self, bytes_per_day: u64) -> Self {
+        self.bytes_per_day = Some(bytes_per_day);
+        self
+    }
+}
+
+/// What an identity has used of its quota
+struct Usage {
+    commands: Option<Mutex<TokenBucket>>,
+    in_flight: AtomicUsize,
+    /// The day `bytes` were counted on, in days since the epoch
+    day: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Usage {
+    fn new(quota: &Quota, today: u64) -> Self {
+        Self {
+            commands: quota
+                .commands_per_sec
+                .map(|rate| Mutex::new(TokenBucket::new(f64::from(rate)))),
+            in_flight: AtomicUsize::new(0),
+            day: AtomicU64::new(today),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes counted today, starting afresh on a new day
+    fn bytes_today(&self, today: u64) -> u64 {
+        if self.day.swap(today, Ordering::Relaxed) != today {
+            self.bytes.store(0, Ordering::Relaxed);
+        }
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Take a token for a command, if there's one
+    fn take_command(&self) -> bool {
+        let Some(commands) = &self.commands else {
+            return true;
+        };
+        let Ok(mut bucket) = commands.lock() else {
+            return true;
+        };
+        bucket.refill(Instant::now());
+        if !bucket.wait_for(1.0).is_zero() {
+            return false;
+        }
+        bucket.take(1.0);
+        true
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / SECONDS_PER_DAY)
+}
+
+/// Usage of the quota by every client identity, shared by their connections
+pub struct Quotas {
+    quota: Quota,
+    stats: Arc<Stats>,
+    usage: Mutex<HashMap<String, Arc<Usage>>>,
+}
+
+impl Quotas {
+    pub fn new(quota: Quota, stats: Arc<Stats>) -> Self {
+        Self {
+            quota,
+            stats,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply the quota to a client connecting from `addr`, authenticating as `user`
+    pub fn for_client(self: &Arc<Self>, addr: Option<ClientAddr>, user: ClientUser) -> QuotaLayer {
+        QuotaLayer {
+            quotas: self.clone(),
+            addr,
+            user,
+        }
+    }
+
+    /// What `identity` has used, tracking it afresh if it isn't yet
+    fn usage(&self, identity: &str, today: u64) -> Option<Arc<Usage>> {
+        let mut usage = self.usage.lock().ok()?;
+        if let Some(used) = usage.get(identity) {
+            return Some(used.clone());
+        }
+        if usage.len() >= MAX_IDLE_IDENTITIES {
+            // Those with nothing in flight and nothing counted today have nothing to remember
+            usage.retain(|_, used| {
+                Arc::strong_count(used) > 1
+                    || (used.day.load(Ordering::Relaxed) == today
+                        && used.bytes.load(Ordering::Relaxed) > 0)
+            });
+        }
+        let used = Arc::new(Usage::new(&self.quota, today));
+        usage.insert(identity.to_string(), used.clone());
+        self.stats.quotas.set_identities(usage.len());
+        Some(used)
+    }
+}
+
+/// The quota applied to a single client connection
+#[derive(Clone)]
+pub struct QuotaLayer {
+    quotas: Arc<Quotas>,
+    addr: Option<ClientAddr>,
+    user: ClientUser,
+}
+
+impl QuotaLayer {
+    /// Who the client is, as the quota is keyed
+    fn identity(&self) -> String {
+        match self.quotas.quota.key {
+            QuotaKey::User => self.user.get().unwrap_or_else(|| DEFAULT_USER.to_string()),
+            QuotaKey::Client => match &self.addr {
+                // IPv4 clients of a dual-stack listener count alike whichever way they connect
+                Some(ClientAddr::Tcp(addr)) => addr.ip().to_canonical().to_string(),
+                _ => "local".to_string(),
+            },
+        }
+    }
+}
+
+impl<S> Layer<S> for QuotaLayer {
+    type Service = QuotaLimited<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        QuotaLimited {
+            inner: service,
+            client: self.clone(),
+        }
+    }
+}
+
+pub struct QuotaLimited<S> {
+    inner: S,
+    client: QuotaLayer,
+}
+
+/// A command counted against an identity's in-flight quota until its replies are done with
+struct InFlight(Arc<Usage>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> Service<BytesFrame> for QuotaLimited<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let quotas = &self.client.quotas;
+        let quota = &quotas.quota;
+        let identity = self.client.identity();
+        let today = today();
+        let Some(usage) = quotas.usage(&identity, today) else {
+            return Box::pin(futures::future::ready(Ok(reply(command::error(
+                "ERR quota tracking failed",
+            )))));
+        };
+        let refuse = |error: String| -> Self::Future {
+            Box::pin(futures::future::ready(Ok(reply(command::error(&error)))))
+        };
+
+        if let Some(bytes_per_day) = quota.bytes_per_day
+            && usage.bytes_today(today) >= bytes_per_day
+        {
+            quotas.stats.quotas.record_bytes_refusal();
+            return refuse(format!(
+                "ERR daily bytes quota of '{identity}' exceeded, try again tomorrow"
+            ));
+        }
+        let in_flight = usage.in_flight.fetch_add(1, Ordering::Relaxed);
+        let held = InFlight(usage.clone());
+        if quota.max_in_flight.is_some_and(|max| in_flight >= max) {
+            quotas.stats.quotas.record_in_flight_refusal();
+            return refuse(format!(
+                "BUSY in-flight commands quota of '{identity}' exceeded, try again later"
+            ));
+        }
+        if !usage.take_command() {
+            quotas.stats.quotas.record_rate_refusal();
+            return refuse(format!(
+                "BUSY commands per second quota of '{identity}' exceeded, try again later"
+            ));
+        }
+
+        if quota.bytes_per_day.is_some() {
+            usage
+                .bytes
+                .fetch_add(frame::encoded_len(&req) as u64, Ordering::Relaxed);
+        }
+        let count_replies = quota.bytes_per_day.is_some();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let responses = fut.await.map_err(Into::into)?;
+            // The command is in flight until its replies are done with
+            Ok(responses
+                .inspect(move |frame| {
+                    if count_replies {
+                        held.0
+                            .bytes
+                            .fetch_add(frame::encoded_len(frame) as u64, Ordering::Relaxed);
+                    }
+                })
+                .boxed())
+        })
+    }
+}
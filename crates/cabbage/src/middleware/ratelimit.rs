@@ -0,0 +1,230 @@
+//! Per-client rate limiting.
+//!
+//! `RateLimitLayer` meters each client connection with token buckets, one refilled at a number of
+//! commands per second and optionally one refilled at a number of request bytes per second, each
+//! holding at most one second's worth of tokens. A command arriving when a bucket is empty is
+//! either rejected with a `-BUSY` error or held back until the buckets have refilled. Since the
+//! proxy waits for each dispatch before reading the client's next command, holding one back
+//! throttles the whole connection. Limits can be changed (or lifted) on live connections with
+//! `RateLimitLayer::watch`, which starts each connection's buckets afresh.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::{Error, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::{command, frame};
+
+/// What to do with a command which exceeds the rate limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitMode {
+    /// Answer with a `-BUSY` error without forwarding the command
+    #[default]
+    Reject,
+    /// Forward the command once enough tokens have accumulated
+    Delay,
+}
+
+impl std::str::FromStr for RateLimitMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(RateLimitMode::Reject),
+            "delay" => Ok(RateLimitMode::Delay),
+            _ => bail!(Config, "Unrecognized rate limit mode '{s}'"),
+        }
+    }
+}
+
+/// Tokens refilled at `rate` per second, holding at most one second's worth
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    pub(crate) fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// How long until `cost` tokens are available
+    pub(crate) fn wait_for(&self, cost: f64) -> Duration {
+        if self.tokens >= cost {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((cost - self.tokens) / self.rate)
+        }
+    }
+
+    /// Take `cost` tokens, going into debt if there aren't enough, and return how long until
+    /// the bucket would have had them. A cost above a second's worth only waits for a full bucket.
+    pub(crate) fn take(&mut self, cost: f64) -> Duration {
+        self.refill(Instant::now());
+        let wait = self.wait_for(cost.min(self.rate));
+        self.tokens -= cost;
+        wait
+    }
+}
+
+/// The limits applied to each client connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimits {
+    commands_per_sec: f64,
+    bytes_per_sec: Option<f64>,
+    mode: RateLimitMode,
+}
+
+impl RateLimits {
+    pub fn new(commands_per_sec: u32) -> Self {
+        Self {
+            commands_per_sec: f64::from(commands_per_sec.max(1)),
+            bytes_per_sec: None,
+            mode: RateLimitMode::default(),
+        }
+    }
+
+    /// Also limit the volume of requests a client may send
+    pub fn with_bytes_per_sec(mut self, bytes_per_sec: u64) -> Self {
+        self.bytes_per_sec = Some(bytes_per_sec.max(1) as f64);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: RateLimitMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// A connection's buckets, filled according to its current limits
+struct Buckets {
+    commands: TokenBucket,
+    bytes: Option<TokenBucket>,
+    mode: RateLimitMode,
+}
+
+impl Buckets {
+    fn new(limits: &RateLimits) -> Self {
+        Self {
+            commands: TokenBucket::new(limits.commands_per_sec),
+            bytes: limits.bytes_per_sec.map(TokenBucket::new),
+            mode: limits.mode,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limits: watch::Receiver<Option<RateLimits>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limits: RateLimits) -> Self {
+        Self::watch(watch::channel(Some(limits)).1)
+    }
+
+    /// Apply the latest limits sent on `limits`, if any, so they can be changed on live
+    /// connections
+    pub fn watch(limits: watch::Receiver<Option<RateLimits>>) -> Self {
+        Self { limits }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let mut limits = self.limits.clone();
+        let buckets = limits.borrow_and_update().as_ref().map(Buckets::new);
+        RateLimit {
+            inner: service,
+            limits,
+            buckets,
+        }
+    }
+}
+
+pub struct RateLimit<S> {
+    inner: S,
+    limits: watch::Receiver<Option<RateLimits>>,
+    buckets: Option<Buckets>,
+}
+
+impl<S> Service<BytesFrame> for RateLimit<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if self.limits.has_changed().unwrap_or_default() {
+            self.buckets = self.limits.borrow_and_update().as_ref().map(Buckets::new);
+        }
+        let Some(buckets) = &mut self.buckets else {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        };
+
+        let now = Instant::now();
+        let size = frame::encoded_len(&req) as f64;
+        buckets.commands.refill(now);
+        let mut wait = buckets.commands.wait_for(1.0);
+        if let Some(bytes) = &mut buckets.bytes {
+            bytes.refill(now);
+            // A single request larger than a second's allowance can never fit; charge it fully
+            // but only wait for the bucket to fill up
+            wait = wait.max(bytes.wait_for(size.min(bytes.rate)));
+        }
+
+        if !wait.is_zero() && buckets.mode == RateLimitMode::Reject {
+            return Box::pin(futures::future::ready(Ok(reply(command::error(
+                "BUSY client rate limit exceeded, try again later",
+            )))));
+        }
+
+        // Tokens are taken up front, going into debt when delaying, so commands arriving during
+        // the wait queue up behind this one
+        buckets.commands.tokens -= 1.0;
+        if let Some(bytes) = &mut buckets.bytes {
+            bytes.tokens -= size;
+        }
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        if wait.is_zero() {
+            return Box::pin(fut);
+        }
+        Box::pin(async move {
+            tokio::time::sleep(wait).await;
+            fut.await
+        })
+    }
+}
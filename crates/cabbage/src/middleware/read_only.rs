@@ -0,0 +1,119 @@
+//! Read-only mode.
+//!
+//! `ReadOnlyLayer` answers every write (as `command::is_write` classifies it) with a `-READONLY`
+//! error while a `ReadOnlyMode` shared by every connection is on, forwarding everything else, so
+//! clients see what they would from a Redis replica during target maintenance or a failover
+//! window. `CABBAGE.READONLY` switches it on and off at runtime.
+//!
+//! A write refused while a transaction is queued aborts it as Redis would: its `EXEC` is
+//! forwarded as `DISCARD`, and answered with `-EXECABORT`. Writes already queued when the mode is
+//! switched on still run with their `EXEC`.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+static READONLY_ERROR: &str = "READONLY You can't write through this proxy while it's read-only";
+static EXECABORT_ERROR: &str = "EXECABORT Transaction discarded because of previous errors.";
+
+/// Whether writes are refused, across every connection
+#[derive(Debug, Default)]
+pub struct ReadOnlyMode {
+    enabled: AtomicBool,
+}
+
+impl ReadOnlyMode {
+    pub fn new(enabled: bool) -> Arc<Self> {
+        Arc::new(Self {
+            enabled: AtomicBool::new(enabled),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+pub struct ReadOnlyLayer {
+    mode: Arc<ReadOnlyMode>,
+}
+
+impl ReadOnlyLayer {
+    pub fn new(mode: Arc<ReadOnlyMode>) -> Self {
+        Self { mode }
+    }
+}
+
+impl<S> Layer<S> for ReadOnlyLayer {
+    type Service = ReadOnly<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ReadOnly {
+            inner: service,
+            mode: self.mode.clone(),
+            transaction: None,
+        }
+    }
+}
+
+pub struct ReadOnly<S> {
+    inner: S,
+    mode: Arc<ReadOnlyMode>,
+    /// Whether a write queued since `MULTI` was refused, while in a transaction
+    transaction: Option<bool>,
+}
+
+impl<S> Service<BytesFrame> for ReadOnly<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        match command::name(&req).as_deref() {
+            Some("MULTI") => self.transaction = Some(false),
+            Some("DISCARD") => self.transaction = None,
+            Some("EXEC") if self.transaction.take() == Some(true) => {
+                let fut = self.inner.call(command::request(["DISCARD"]));
+                return Box::pin(async move {
+                    fut.await.map_err(Into::into)?.count().await;
+                    Ok(reply(command::error(EXECABORT_ERROR)))
+                });
+            }
+            Some("EXEC") => {}
+            _ if self.mode.is_enabled() && command::is_write(&req) => {
+                if let Some(aborted) = &mut self.transaction {
+                    *aborted = true;
+                }
+                return Box::pin(async { Ok(reply(command::error(READONLY_ERROR))) });
+            }
+            _ => {}
+        }
+        Box::pin(self.inner.call(req).map_err(Into::into))
+    }
+}
@@ -0,0 +1,233 @@
+//! Masking of sensitive values in logged traffic.
+//!
+//! `RedactionRules` select arguments by command name (or `NAME|SUBCOMMAND`) and position, as
+//! counted in the command's argv with the name at position 0, and replace them with a marker
+//! before `ProxyLogger` renders a frame. Rules may also mask a command's replies wholesale. The
+//! default rules cover the credentials Redis itself accepts in-band (`AUTH`, `HELLO ... AUTH`,
+//! `MIGRATE ... AUTH/AUTH2`, `CONFIG SET requirepass/masterauth`, `ACL SETUSER`).
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::Bytes;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+
+static REDACTED: &[u8] = b"<redacted>";
+
+/// Which parts of a command a rule masks
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Selector {
+    /// The argument at this position
+    At(usize),
+    /// Every argument from this position on
+    From(usize),
+    /// The `count` arguments following each occurrence of `token` (matched case-insensitively)
+    AfterToken { token: String, count: usize },
+    /// Every frame of the reply
+    Reply,
+}
+
+/// A single redaction rule, parsed from `NAME:SELECTOR[,SELECTOR...]`.
+///
+/// Selectors are an argument position (`SET:2`), a position followed by `+` to mask it and all
+/// later arguments (`AUTH:1+`), or `reply` to mask the command's replies (`GET:reply`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RedactionRule {
+    command: String,
+    selectors: Vec<Selector>,
+}
+
+impl std::str::FromStr for RedactionRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, selectors)) = s.split_once(':') else {
+            bail!(
+                Config,
+                "Redaction rule '{s}' is not of the form NAME:SELECTOR[,SELECTOR...]"
+            );
+        };
+        let selectors = selectors
+            .split(',')
+            .map(|selector| {
+                let selector = selector.trim();
+                if selector.eq_ignore_ascii_case("reply") {
+                    return Ok(Selector::Reply);
+                }
+                let (position, from) = match selector.strip_suffix('+') {
+                    Some(position) => (position, true),
+                    None => (selector, false),
+                };
+                let position: usize = position.parse().map_err(|_| {
+                    Error::Config(format!(
+                        "Invalid argument position '{selector}' in redaction rule '{s}'"
+                    ))
+                })?;
+                if position == 0 {
+                    bail!(Config, "Redaction rule '{s}' would mask the command name");
+                }
+                Ok(if from {
+                    Selector::From(position)
+                } else {
+                    Selector::At(position)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            command: command.trim().to_ascii_uppercase(),
+            selectors,
+        })
+    }
+}
+
+impl TryFrom<String> for RedactionRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RedactionRules {
+    rules: HashMap<String, Vec<Selector>>,
+}
+
+impl Default for RedactionRules {
+    /// Mask the credentials Redis accepts as command arguments
+    fn default() -> Self {
+        let after = |token: &str, count| Selector::AfterToken {
+            token: token.to_string(),
+            count,
+        };
+        Self::none()
+            .with_selectors("AUTH", [Selector::From(1)])
+            .with_selectors("HELLO", [after("AUTH", 2)])
+            .with_selectors("MIGRATE", [after("AUTH", 1), after("AUTH2", 2)])
+            .with_selectors(
+                "CONFIG|SET",
+                [after("REQUIREPASS", 1), after("MASTERAUTH", 1)],
+            )
+            .with_selectors("ACL|SETUSER", [Selector::From(3)])
+    }
+}
+
+impl RedactionRules {
+    /// No redaction at all
+    pub fn none() -> Self {
+        Self {
+            rules: HashMap::new(),
+        }
+    }
+
+    /// Add a rule on top of those already configured
+    pub fn with(self, rule: RedactionRule) -> Self {
+        self.with_selectors(&rule.command, rule.selectors)
+    }
+
+    fn with_selectors(
+        mut self,
+        command: &str,
+        selectors: impl IntoIterator<Item = Selector>,
+    ) -> Self {
+        self.rules
+            .entry(command.to_ascii_uppercase())
+            .or_default()
+            .extend(selectors);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Every selector matching `req`, by bare name and by `NAME|SUBCOMMAND`
+    fn selectors(&self, req: &BytesFrame) -> Vec<&Selector> {
+        let Some(name) = command::name(req) else {
+            return vec![];
+        };
+        let full_name = command::args(req)
+            .and_then(|args| args.get(1))
+            .and_then(command::arg_bytes)
+            .map(|sub| {
+                format!(
+                    "{name}|{}",
+                    String::from_utf8_lossy(sub).to_ascii_uppercase()
+                )
+            });
+        [Some(name), full_name]
+            .into_iter()
+            .flatten()
+            .filter_map(|n| self.rules.get(&n))
+            .flatten()
+            .collect()
+    }
+
+    /// `req` with every argument selected by a matching rule masked
+    pub fn request<'a>(&self, req: &'a BytesFrame) -> Cow<'a, BytesFrame> {
+        let selectors = self.selectors(req);
+        let Some(args) = command::args(req) else {
+            return Cow::Borrowed(req);
+        };
+        let mut masked = vec![false; args.len()];
+        for selector in selectors {
+            match selector {
+                Selector::At(position) => {
+                    if let Some(m) = masked.get_mut(*position) {
+                        *m = true;
+                    }
+                }
+                Selector::From(position) => {
+                    masked.iter_mut().skip(*position).for_each(|m| *m = true);
+                }
+                Selector::AfterToken { token, count } => {
+                    for (i, arg) in args.iter().enumerate().skip(1) {
+                        if command::arg_bytes(arg)
+                            .is_some_and(|a| a.eq_ignore_ascii_case(token.as_bytes()))
+                        {
+                            masked
+                                .iter_mut()
+                                .skip(i + 1)
+                                .take(*count)
+                                .for_each(|m| *m = true);
+                        }
+                    }
+                }
+                Selector::Reply => {}
+            }
+        }
+        if !masked.contains(&true) {
+            return Cow::Borrowed(req);
+        }
+
+        Cow::Owned(BytesFrame::Array(
+            args.iter()
+                .zip(masked)
+                .map(|(arg, masked)| if masked { redacted() } else { arg.clone() })
+                .collect(),
+        ))
+    }
+
+    /// Whether replies to `req` should be masked with `reply`
+    pub fn masks_reply(&self, req: &BytesFrame) -> bool {
+        self.selectors(req).contains(&&Selector::Reply)
+    }
+}
+
+fn redacted() -> BytesFrame {
+    BytesFrame::BulkString(Bytes::from_static(REDACTED))
+}
+
+/// `frame` with every string it carries masked, keeping its shape
+pub fn reply(frame: &BytesFrame) -> BytesFrame {
+    match frame {
+        BytesFrame::SimpleString(_) | BytesFrame::BulkString(_) => redacted(),
+        BytesFrame::Array(frames) => BytesFrame::Array(frames.iter().map(reply).collect()),
+        other => other.clone(),
+    }
+}
@@ -0,0 +1,170 @@
+//! Retries of read-only commands.
+//!
+//! `RetryLayer` re-sends read-only commands (`GET`, `EXISTS`, `TTL`, ...) when dispatching them
+//! fails or the target's connection is lost before any reply arrives, up to a fixed number of
+//! times. Every command deposits into a `TpsBudget` shared across all connections and every retry
+//! withdraws from it, so when the target is down retries are capped at a fraction of the normal
+//! traffic rather than multiplying it. Other commands pass through untouched.
+//!
+//! Commands are dispatched as they're called, with the wrapped service's backpressure passed
+//! through `poll_ready`; only the reply stream waits for the first frame, so pipelined commands
+//! behind a read aren't held up by it. A retry is only of use through a backend which reconnects
+//! (to a failover target, a new Sentinel master, another balanced target, or a cluster node), so
+//! configuring retries for a single target is refused.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use std::sync::Mutex;
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+use tower::retry::budget::{Budget as _, TpsBudget};
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::protocol::{Protocol as _, Resp2Protocol};
+use crate::service::ResponseStream;
+
+#[derive(Clone)]
+pub struct RetryLayer {
+    budget: Arc<TpsBudget>,
+    max_retries: usize,
+}
+
+impl RetryLayer {
+    pub fn new(budget: Arc<TpsBudget>, max_retries: usize) -> Self {
+        Self {
+            budget,
+            max_retries,
+        }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Retry {
+            inner: Arc::new(Mutex::new(service)),
+            budget: self.budget.clone(),
+            max_retries: self.max_retries,
+        }
+    }
+}
+
+/// The wrapped service is shared with in-flight requests' reply streams, which call it again to
+/// retry
+pub struct Retry<S> {
+    inner: Arc<Mutex<S>>,
+    budget: Arc<TpsBudget>,
+    max_retries: usize,
+}
+
+fn poisoned() -> Error {
+    Error::BackendUnavailable("Retried service poisoned".to_string())
+}
+
+/// Dispatch `req` to `inner` once it's ready
+async fn dispatch<S>(inner: &Mutex<S>, req: BytesFrame) -> Result<ResponseStream>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error>,
+{
+    futures::future::poll_fn(|cx| match inner.lock() {
+        Ok(mut inner) => inner.poll_ready(cx).map_err(Into::into),
+        Err(_) => Poll::Ready(Err(poisoned())),
+    })
+    .await?;
+    let fut = inner.lock().map_err(|_| poisoned())?.call(req);
+    fut.await.map_err(Into::into)
+}
+
+/// The reply to `req`, first dispatched as `first`, re-dispatching it while that fails or the
+/// target connection is lost before any reply arrives, as long as `max_retries` and `budget`
+/// allow
+fn replies<S>(
+    inner: Arc<Mutex<S>>,
+    budget: Arc<TpsBudget>,
+    max_retries: usize,
+    req: BytesFrame,
+    first: Result<ResponseStream>,
+) -> ResponseStream
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error>,
+    S::Future: Send,
+{
+    let reply = async move {
+        let mut dispatched = first;
+        let mut retries = 0;
+        loop {
+            let failure = match dispatched {
+                Ok(mut stream) => match stream.next().await {
+                    Some(frame) if frame != Resp2Protocol::disconnected_reply() => {
+                        return futures::stream::once(async move { frame })
+                            .chain(stream)
+                            .boxed();
+                    }
+                    Some(frame) => frame,
+                    None => Resp2Protocol::disconnected_reply(),
+                },
+                Err(e) => command::error(format!("ERR cabbage: target unavailable: {e}")),
+            };
+            if retries >= max_retries || !budget.withdraw() {
+                return futures::stream::once(async move { failure }).boxed();
+            }
+            retries += 1;
+            log::warn!(
+                "Retrying {} (attempt {}) after backend failure",
+                command::name(&req).unwrap_or_default(),
+                retries + 1
+            );
+            dispatched = dispatch(&inner, req.clone()).await;
+        }
+    };
+    futures::stream::once(reply).flatten().boxed()
+}
+
+impl<S> Service<BytesFrame> for Retry<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return Poll::Ready(Err(poisoned()));
+        };
+        inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        self.budget.deposit();
+        let read_only = command::is_read_only(&req);
+        let retried = read_only.then(|| req.clone());
+        let fut = match self.inner.lock() {
+            Ok(mut inner) => inner.call(req),
+            Err(_) => return Box::pin(async { Err(poisoned()) }),
+        };
+        let Some(req) = retried else {
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        let inner = self.inner.clone();
+        let budget = self.budget.clone();
+        let max_retries = self.max_retries;
+        Box::pin(async move {
+            let first = fut.await.map_err(Into::into);
+            Ok(replies(inner, budget, max_retries, req, first))
+        })
+    }
+}
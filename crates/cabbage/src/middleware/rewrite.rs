@@ -0,0 +1,393 @@
+//! Declarative command rewriting.
+//!
+//! `RewriteLayer` applies `RewriteRule`s to each command before it's forwarded, covering the
+//! usual compatibility shims without custom code. Rules apply in order, each to the command as
+//! the rules before it left it:
+//!
+//! - `rename:OLD=NEW` renames the command `OLD` to `NEW`
+//! - `add-arg:COMMAND=ARG` appends `ARG` to every `COMMAND`
+//! - `remove-arg:COMMAND=ARG` drops every argument of `COMMAND` equal to `ARG`, ignoring case
+//! - `key-prefix:OLD=NEW` replaces the prefix `OLD` of any key with `NEW`
+//! - `key:PATTERN=TEMPLATE` maps keys matching `PATTERN` through `TEMPLATE`, each `{NAME}` in
+//!   the pattern capturing what takes its place in the template (`key:user:{id}:cart=cart:{id}`
+//!   turns `user:7:cart` into `cart:7`)
+//!
+//! Key rules are reversed on the key names in `KEYS`, `SCAN`, and `RANDOMKEY` replies, so clients
+//! see the names they know, and `key-prefix` rules also rewrite `KEYS` and `SCAN` patterns which
+//! start with the old prefix.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::{Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, bail};
+use crate::middleware::prefix::{ReplyKeys, glob_escape};
+use crate::service::ResponseStream;
+
+/// A single rewrite, parsed from `KIND:FROM=TO` as described in the module documentation
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RewriteRule {
+    Rename {
+        from: String,
+        to: Bytes,
+    },
+    AddArg {
+        command: String,
+        arg: Bytes,
+    },
+    RemoveArg {
+        command: String,
+        arg: Bytes,
+    },
+    KeyPrefix {
+        from: Bytes,
+        to: Bytes,
+    },
+    Key {
+        pattern: KeyTemplate,
+        template: KeyTemplate,
+    },
+}
+
+impl std::str::FromStr for RewriteRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, from, to)) = s
+            .split_once(':')
+            .and_then(|(kind, spec)| Some((kind, spec.split_once('=')?)))
+            .map(|(kind, (from, to))| (kind, from, to))
+        else {
+            bail!(Config, "Rewrite rule '{s}' should be KIND:FROM=TO");
+        };
+        let bytes = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+        Ok(match kind.trim().to_ascii_lowercase().as_str() {
+            "rename" => Self::Rename {
+                from: from.trim().to_ascii_uppercase(),
+                to: bytes(to.trim()),
+            },
+            "add-arg" => Self::AddArg {
+                command: from.trim().to_ascii_uppercase(),
+                arg: bytes(to),
+            },
+            "remove-arg" => Self::RemoveArg {
+                command: from.trim().to_ascii_uppercase(),
+                arg: bytes(to),
+            },
+            "key-prefix" => Self::KeyPrefix {
+                from: bytes(from),
+                to: bytes(to),
+            },
+            "key" => {
+                let pattern: KeyTemplate = from.parse()?;
+                let template: KeyTemplate = to.parse()?;
+                if pattern.placeholders() != template.placeholders() {
+                    bail!(
+                        Config,
+                        "'{from}' and '{to}' must have the same placeholders"
+                    );
+                }
+                Self::Key { pattern, template }
+            }
+            _ => bail!(
+                Config,
+                "Unrecognized rewrite '{kind}' (expected rename, add-arg, remove-arg, key-prefix, \
+                 or key)"
+            ),
+        })
+    }
+}
+
+impl TryFrom<String> for RewriteRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl RewriteRule {
+    fn rewrites_keys(&self) -> bool {
+        matches!(self, Self::KeyPrefix { .. } | Self::Key { .. })
+    }
+
+    fn apply(&self, req: BytesFrame) -> BytesFrame {
+        let name = command::name(&req);
+        let key_indices = command::key_indices(&req);
+        let BytesFrame::Array(mut args) = req else {
+            return req;
+        };
+        match self {
+            Self::Rename { from, to } if name.as_ref() == Some(from) => {
+                args[0] = BytesFrame::BulkString(to.clone());
+            }
+            Self::AddArg { command, arg } if name.as_ref() == Some(command) => {
+                args.push(BytesFrame::BulkString(arg.clone()));
+            }
+            Self::RemoveArg { command, arg } if name.as_ref() == Some(command) => {
+                let mut position = 0;
+                args.retain(|a| {
+                    position += 1;
+                    position == 1
+                        || !command::arg_bytes(a).is_some_and(|a| a.eq_ignore_ascii_case(arg))
+                });
+            }
+            Self::KeyPrefix { .. } | Self::Key { .. } => {
+                for i in key_indices {
+                    if let Some(key) = args.get(i).and_then(command::arg_bytes)
+                        && let Some(key) = self.forward(key)
+                    {
+                        args[i] = BytesFrame::BulkString(key);
+                    }
+                }
+                if let Self::KeyPrefix { from, to } = self
+                    && let Some(at) = pattern_at(name.as_deref(), &args)
+                    && let Some(pattern) = command::arg_bytes(&args[at])
+                {
+                    let from = glob_escape(from);
+                    if pattern.starts_with(&from) {
+                        let mut rewritten = glob_escape(to);
+                        rewritten.extend_from_slice(&pattern[from.len()..]);
+                        args[at] = BytesFrame::BulkString(rewritten.freeze());
+                    }
+                }
+            }
+            _ => {}
+        }
+        BytesFrame::Array(args)
+    }
+
+    /// `key` as this rule rewrites it, if it does
+    fn forward(&self, key: &[u8]) -> Option<Bytes> {
+        match self {
+            Self::KeyPrefix { from, to } => replace_prefix(key, from, to),
+            Self::Key { pattern, template } => Some(template.fill(&pattern.capture(key)?)),
+            _ => None,
+        }
+    }
+
+    /// `key` as it was before this rule rewrote it, if it was
+    fn reverse(&self, key: &[u8]) -> Option<Bytes> {
+        match self {
+            Self::KeyPrefix { from, to } => replace_prefix(key, to, from),
+            Self::Key { pattern, template } => Some(pattern.fill(&template.capture(key)?)),
+            _ => None,
+        }
+    }
+}
+
+fn replace_prefix(key: &[u8], from: &[u8], to: &[u8]) -> Option<Bytes> {
+    let rest = key.strip_prefix(from)?;
+    let mut key = BytesMut::with_capacity(to.len() + rest.len());
+    key.extend_from_slice(to);
+    key.extend_from_slice(rest);
+    Some(key.freeze())
+}
+
+/// Where the key pattern of a `KEYS` or `SCAN ... MATCH` command is
+fn pattern_at(name: Option<&str>, args: &[BytesFrame]) -> Option<usize> {
+    match name? {
+        "KEYS" => Some(1).filter(|&at| at < args.len()),
+        "SCAN" => args
+            .iter()
+            .skip(2)
+            .position(|a| command::arg_bytes(a).is_some_and(|a| a.eq_ignore_ascii_case(b"MATCH")))
+            .map(|i| i + 3)
+            .filter(|&at| at < args.len()),
+        _ => None,
+    }
+}
+
+/// A key shape with `{NAME}` placeholders, which can capture the parts of a key it matches and
+/// fill its placeholders from such captures
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyTemplate(Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(Bytes),
+    Placeholder(String),
+}
+
+impl std::str::FromStr for KeyTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(open) = rest.find('{') {
+            let Some(len) = rest[open..].find('}') else {
+                bail!(Config, "Unclosed '{{' in key template '{s}'");
+            };
+            if open > 0 {
+                parts.push(Part::Literal(Bytes::copy_from_slice(
+                    &rest.as_bytes()[..open],
+                )));
+            }
+            let name = &rest[open + 1..open + len];
+            if name.is_empty() {
+                bail!(Config, "Unnamed placeholder in key template '{s}'");
+            }
+            // Where one placeholder ends and the next begins would be anyone's guess
+            if matches!(parts.last(), Some(Part::Placeholder(_))) {
+                bail!(
+                    Config,
+                    "Placeholders must be separated in key template '{s}'"
+                );
+            }
+            if parts.contains(&Part::Placeholder(name.to_string())) {
+                bail!(
+                    Config,
+                    "Placeholder '{name}' appears twice in key template '{s}'"
+                );
+            }
+            parts.push(Part::Placeholder(name.to_string()));
+            rest = &rest[open + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(Bytes::copy_from_slice(rest.as_bytes())));
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl KeyTemplate {
+    fn placeholders(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .0
+            .iter()
+            .filter_map(|part| match part {
+                Part::Placeholder(name) => Some(name.as_str()),
+                Part::Literal(_) => None,
+            })
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// What each placeholder stands for in `key`, if `key` matches. A placeholder takes as
+    /// little as it can, up to the next literal part.
+    fn capture<'k>(&self, key: &'k [u8]) -> Option<HashMap<&str, &'k [u8]>> {
+        let mut captures = HashMap::new();
+        let mut at = 0;
+        let mut parts = self.0.iter().peekable();
+        while let Some(part) = parts.next() {
+            match part {
+                Part::Literal(literal) => {
+                    if !key[at..].starts_with(literal) {
+                        return None;
+                    }
+                    at += literal.len();
+                }
+                Part::Placeholder(name) => {
+                    let end = match parts.peek() {
+                        Some(Part::Literal(literal)) => {
+                            at + key[at..]
+                                .windows(literal.len())
+                                .position(|window| window == literal.as_ref())?
+                        }
+                        _ => key.len(),
+                    };
+                    captures.insert(name.as_str(), &key[at..end]);
+                    at = end;
+                }
+            }
+        }
+        (at == key.len()).then_some(captures)
+    }
+
+    fn fill(&self, captures: &HashMap<&str, &[u8]>) -> Bytes {
+        let mut key = BytesMut::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(literal) => key.extend_from_slice(literal),
+                Part::Placeholder(name) => {
+                    key.extend_from_slice(captures.get(name.as_str()).copied().unwrap_or_default())
+                }
+            }
+        }
+        key.freeze()
+    }
+}
+
+pub struct RewriteLayer {
+    rules: Arc<[RewriteRule]>,
+}
+
+impl RewriteLayer {
+    /// Rewrite commands with each of `rules` in turn
+    pub fn new(rules: impl Into<Arc<[RewriteRule]>>) -> Self {
+        Self {
+            rules: rules.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for RewriteLayer {
+    type Service = Rewrite<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Rewrite {
+            inner: service,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+pub struct Rewrite<S> {
+    inner: S,
+    rules: Arc<[RewriteRule]>,
+}
+
+impl<S> Service<BytesFrame> for Rewrite<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let req = self.rules.iter().fold(req, |req, rule| rule.apply(req));
+        let reply_keys = ReplyKeys::of(command::name(&req).as_deref());
+        let fut = self.inner.call(req);
+        if reply_keys == ReplyKeys::None || !self.rules.iter().any(RewriteRule::rewrites_keys) {
+            return Box::pin(fut.map_err(Into::into));
+        }
+
+        let rules = self.rules.clone();
+        Box::pin(
+            fut.map_ok(move |stream| {
+                stream
+                    .map(move |frame| {
+                        reply_keys.map(frame, |key| {
+                            rules
+                                .iter()
+                                .rev()
+                                .fold(key, |key, rule| rule.reverse(&key).unwrap_or(key))
+                        })
+                    })
+                    .boxed()
+            })
+            .map_err(Into::into),
+        )
+    }
+}
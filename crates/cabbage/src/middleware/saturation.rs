@@ -0,0 +1,158 @@
+//! Load shedding.
+//!
+//! Where `FairLayer` has commands beyond its limit wait their turn, `SaturationLayer` refuses
+//! them: a command arriving while its connection, or every connection together, has as many
+//! commands awaiting replies as allowed is answered with `-BUSY cabbage saturated` at once,
+//! without being forwarded, so a saturated proxy tells clients to back off rather than queueing
+//! their commands without bound. A command is in flight until its replies have been forwarded,
+//! except for blocking commands and those starting push mode, which are admitted like any other
+//! but not counted once dispatched, as their replies may be a long time coming. Refusals are
+//! counted in `Stats::saturation`.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+static BUSY_ERROR: &str = "BUSY cabbage saturated";
+
+/// The commands in flight across every connection, and the limits they're held to
+pub struct Saturation {
+    per_connection: Option<usize>,
+    total: Option<usize>,
+    in_flight: AtomicUsize,
+    stats: Arc<Stats>,
+}
+
+impl Saturation {
+    /// Refuse commands beyond `per_connection` in flight on a connection, or `total` across
+    /// every connection
+    pub fn new(per_connection: Option<usize>, total: Option<usize>, stats: Arc<Stats>) -> Self {
+        stats.saturation.set_limited();
+        Self {
+            per_connection,
+            total,
+            in_flight: AtomicUsize::new(0),
+            stats,
+        }
+    }
+
+    /// Apply the limits to a new client connection
+    pub fn for_connection(self: &Arc<Self>) -> SaturationLayer {
+        SaturationLayer {
+            saturation: self.clone(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// The limits applied to a single client connection
+#[derive(Clone)]
+pub struct SaturationLayer {
+    saturation: Arc<Saturation>,
+    /// The connection's commands in flight
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<S> Layer<S> for SaturationLayer {
+    type Service = Saturable<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Saturable {
+            inner: service,
+            connection: self.clone(),
+        }
+    }
+}
+
+pub struct Saturable<S> {
+    inner: S,
+    connection: SaturationLayer,
+}
+
+/// A command counted as in flight, on its connection and in total, until dropped
+struct InFlight(SaturationLayer);
+
+impl InFlight {
+    fn new(connection: &SaturationLayer) -> Self {
+        connection.in_flight.fetch_add(1, Ordering::Relaxed);
+        let total = connection
+            .saturation
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        connection.saturation.stats.saturation.set_in_flight(total);
+        Self(connection.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let saturation = &self.0.saturation;
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let total = saturation.in_flight.fetch_sub(1, Ordering::Relaxed) - 1;
+        saturation.stats.saturation.set_in_flight(total);
+    }
+}
+
+impl<S> Service<BytesFrame> for Saturable<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let saturation = &self.connection.saturation;
+        let refusals = &saturation.stats.saturation;
+        if saturation
+            .per_connection
+            .is_some_and(|max| self.connection.in_flight.load(Ordering::Relaxed) >= max)
+        {
+            refusals.record_connection_refusal();
+            return Box::pin(async { Ok(reply(command::error(BUSY_ERROR))) });
+        }
+        if saturation
+            .total
+            .is_some_and(|max| saturation.in_flight.load(Ordering::Relaxed) >= max)
+        {
+            refusals.record_total_refusal();
+            return Box::pin(async { Ok(reply(command::error(BUSY_ERROR))) });
+        }
+
+        let long_lived = command::is_blocking(&req) || command::starts_push_mode(&req);
+        let held = InFlight::new(&self.connection);
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let responses = fut.await.map_err(Into::into)?;
+            if long_lived {
+                return Ok(responses);
+            }
+            // The command is in flight until its replies are done with
+            Ok(responses
+                .inspect(move |_| {
+                    let _ = &held;
+                })
+                .boxed())
+        })
+    }
+}
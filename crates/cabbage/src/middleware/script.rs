@@ -0,0 +1,227 @@
+//! Lua scripting hooks.
+//!
+//! `ScriptLayer` hands each command, and each frame of its reply, to a Lua script, for quick
+//! operational fixes (tagging keys, turning away a troublesome pattern) without rebuilding
+//! cabbage. A script defines either or both of the global functions:
+//!
+//! - `on_request(command)`, given the command as a table of its arguments
+//! - `on_response(command, reply)`, given the command and a reply frame
+//!
+//! Frames become Lua values as they do for Redis's own scripts: bulk strings are strings,
+//! integers are integers, arrays are tables, a nil reply is `false`, and status and error replies
+//! are tables with an `ok` or `err` field. Returning `nil` lets the frame through unchanged;
+//! anything else replaces it, converted back the same way. A command replaced by anything other
+//! than an array isn't forwarded at all; the replacement is the client's reply, so
+//! `return {err = "ERR not here"}` refuses the command. A script which raises an error is
+//! answered with an error in place of its frame.
+//!
+//! Each script runs in an interpreter of its own, which serves every connection one call at a
+//! time, so globals it sets are shared by all of them.
+
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use mlua::{Function, Lua, Value};
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+/// A loaded script
+pub struct Script {
+    name: String,
+    lua: Mutex<Lua>,
+    handles_responses: bool,
+}
+
+impl Script {
+    /// Load and run the script at `path`, which defines its hooks
+    pub fn load(path: &Path) -> Result<Arc<Self>> {
+        let name = path.display().to_string();
+        let source = std::fs::read(path)
+            .map_err(|e| Error::io(format!("Failed to read script {name}"), e))?;
+        let lua = Lua::new();
+        lua.load(source)
+            .set_name(name.as_str())
+            .exec()
+            .map_err(|e| Error::Other(format!("Failed to run script {name}: {e}").into()))?;
+        let has_hook = |hook| {
+            lua.globals()
+                .get::<_, Option<Function>>(hook)
+                .map(|f| f.is_some())
+                .map_err(Error::other)
+        };
+        let handles_requests = has_hook("on_request")?;
+        let handles_responses = has_hook("on_response")?;
+        if !handles_requests && !handles_responses {
+            bail!(
+                Config,
+                "Script {name} defines neither on_request nor on_response"
+            );
+        }
+        Ok(Arc::new(Self {
+            name,
+            lua: Mutex::new(lua),
+            handles_responses,
+        }))
+    }
+
+    /// Pass `req` through `on_request`, if the script defines it
+    fn on_request(&self, req: &BytesFrame) -> Option<BytesFrame> {
+        self.run("on_request", &[req])
+    }
+
+    /// Pass `frame`, a reply to `req`, through `on_response`, if the script defines it
+    fn on_response(&self, req: &BytesFrame, frame: &BytesFrame) -> Option<BytesFrame> {
+        self.run("on_response", &[req, frame])
+    }
+
+    /// Call the global function `hook` with `frames`, answering with an error if it fails
+    fn run(&self, hook: &str, frames: &[&BytesFrame]) -> Option<BytesFrame> {
+        let Ok(lua) = self.lua.lock() else {
+            return Some(command::error("ERR cabbage: script unavailable"));
+        };
+        let call = || -> mlua::Result<Option<BytesFrame>> {
+            let Some(hook) = lua.globals().get::<_, Option<Function>>(hook)? else {
+                return Ok(None);
+            };
+            let args = frames
+                .iter()
+                .map(|frame| to_lua(&lua, frame))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            match hook.call::<_, Value>(mlua::MultiValue::from_vec(args))? {
+                Value::Nil => Ok(None),
+                value => from_lua(value).map(Some),
+            }
+        };
+        call().unwrap_or_else(|e| {
+            log::error!("Script {} failed in {hook}: {e}", self.name);
+            Some(command::error(format!("ERR cabbage: script failed: {e}")))
+        })
+    }
+}
+
+/// `frame` as a Lua value, converted as Redis converts replies for its scripts
+fn to_lua<'lua>(lua: &'lua Lua, frame: &BytesFrame) -> mlua::Result<Value<'lua>> {
+    Ok(match frame {
+        BytesFrame::SimpleString(s) => {
+            Value::Table(lua.create_table_from([("ok", lua.create_string(s)?)])?)
+        }
+        BytesFrame::Error(e) => {
+            Value::Table(lua.create_table_from([("err", lua.create_string(e.as_bytes())?)])?)
+        }
+        BytesFrame::Integer(i) => Value::Integer(*i),
+        BytesFrame::BulkString(b) => Value::String(lua.create_string(b)?),
+        BytesFrame::Array(frames) => Value::Table(
+            lua.create_sequence_from(
+                frames
+                    .iter()
+                    .map(|frame| to_lua(lua, frame))
+                    .collect::<mlua::Result<Vec<_>>>()?,
+            )?,
+        ),
+        BytesFrame::Null => Value::Boolean(false),
+    })
+}
+
+/// The frame a Lua value returned by a script stands for, converted as Redis converts the
+/// results of its scripts
+fn from_lua(value: Value) -> mlua::Result<BytesFrame> {
+    Ok(match value {
+        Value::Nil | Value::Boolean(false) => BytesFrame::Null,
+        Value::Boolean(true) => BytesFrame::Integer(1),
+        Value::Integer(i) => BytesFrame::Integer(i),
+        Value::Number(n) => BytesFrame::Integer(n as i64),
+        Value::String(s) => BytesFrame::BulkString(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(table) => {
+            if let Some(err) = table.get::<_, Option<mlua::String>>("err")? {
+                command::error(err.to_str()?)
+            } else if let Some(ok) = table.get::<_, Option<mlua::String>>("ok")? {
+                BytesFrame::SimpleString(Bytes::copy_from_slice(ok.as_bytes()))
+            } else {
+                BytesFrame::Array(
+                    table
+                        .sequence_values::<Value>()
+                        .map(|value| from_lua(value?))
+                        .collect::<mlua::Result<_>>()?,
+                )
+            }
+        }
+        other => {
+            return Err(mlua::Error::runtime(format!(
+                "can't reply with a {}",
+                other.type_name()
+            )));
+        }
+    })
+}
+
+pub struct ScriptLayer {
+    script: Arc<Script>,
+}
+
+impl ScriptLayer {
+    pub fn new(script: Arc<Script>) -> Self {
+        Self { script }
+    }
+}
+
+impl<S> Layer<S> for ScriptLayer {
+    type Service = Scripted<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Scripted {
+            inner: service,
+            script: self.script.clone(),
+        }
+    }
+}
+
+pub struct Scripted<S> {
+    inner: S,
+    script: Arc<Script>,
+}
+
+impl<S> Service<BytesFrame> for Scripted<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let req = match self.script.on_request(&req) {
+            None => req,
+            Some(rewritten @ BytesFrame::Array(_)) => rewritten,
+            Some(answer) => return Box::pin(async move { Ok(reply(answer)) }),
+        };
+        if !self.script.handles_responses {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+        let fut = self.inner.call(req.clone()).map_err(Into::into);
+        let script = self.script.clone();
+        Box::pin(async move {
+            Ok(fut
+                .await?
+                .map(move |frame| script.on_response(&req, &frame).unwrap_or(frame))
+                .boxed())
+        })
+    }
+}
@@ -0,0 +1,253 @@
+//! Script cache awareness across targets.
+//!
+//! Redis keeps the scripts loaded with `SCRIPT LOAD` per server, so once a client's connection
+//! lands on another target (a different balanced target, or the next of a failover list),
+//! `EVALSHA` of a script it loaded fails with `NOSCRIPT`. `LoadedScripts`, shared by every
+//! connection, remembers the body of each script loaded through the proxy by its SHA1 digest, as
+//! the target reported it. `ScriptCacheLayer` answers an `EVALSHA` (or `EVALSHA_RO`) which fails
+//! with `NOSCRIPT` by sending it again as `EVAL` (or `EVAL_RO`) with the remembered body, which
+//! also loads the script on the new target, and copies each newly loaded script to every other
+//! target given with `LoadedScripts::with_replicas` in the background, so most `EVALSHA`s never
+//! fail at all. `SCRIPT FLUSH` forgets every script.
+//!
+//! Scripts sent with `EVAL` aren't remembered, as that would mean computing their digests here.
+//! The client's commands after an `EVALSHA` aren't forwarded until it has been answered, so a
+//! retried one keeps its place, at the cost of pipelining through it. One queued in a
+//! transaction is left to fail, as its error only arrives with `EXEC`'s reply.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+use tower::ServiceExt as _;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::service::{Handshake, Resp2Backend, ResponseStream};
+
+/// Scripts remembered, beyond which further ones are still loaded but not remembered
+static MAX_SCRIPTS: usize = 10_000;
+
+/// The scripts loaded through the proxy, shared by every connection
+#[derive(Default)]
+pub struct LoadedScripts {
+    /// Bodies by lowercase hex digest
+    bodies: Mutex<HashMap<String, Bytes>>,
+    replicas: Vec<String>,
+    handshake: Handshake,
+}
+
+impl LoadedScripts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also load each newly loaded script on the targets at `addresses`, connecting to them
+    /// with `handshake`
+    pub fn with_replicas(mut self, addresses: Vec<String>, handshake: Handshake) -> Self {
+        self.replicas = addresses;
+        self.handshake = handshake;
+        self
+    }
+
+    fn body(&self, digest: &[u8]) -> Option<Bytes> {
+        let digest = String::from_utf8_lossy(digest).to_ascii_lowercase();
+        self.bodies.lock().ok()?.get(&digest).cloned()
+    }
+
+    /// Remember `body` as the script with `digest`, returning whether it's new
+    fn record(&self, digest: &[u8], body: Bytes) -> bool {
+        let Ok(mut bodies) = self.bodies.lock() else {
+            return false;
+        };
+        let digest = String::from_utf8_lossy(digest).to_ascii_lowercase();
+        if bodies.contains_key(&digest) || bodies.len() >= MAX_SCRIPTS {
+            return false;
+        }
+        bodies.insert(digest, body);
+        true
+    }
+
+    fn forget_all(&self) {
+        if let Ok(mut bodies) = self.bodies.lock() {
+            bodies.clear();
+        }
+    }
+
+    /// Load `body` on every replica in the background
+    fn replicate(self: &Arc<Self>, body: Bytes) {
+        for address in &self.replicas {
+            let scripts = self.clone();
+            let address = address.clone();
+            let body = body.clone();
+            tokio::spawn(async move {
+                if let Err(e) = scripts.load_on(&address, body).await {
+                    log::warn!("Failed to load a script on {address}: {e}");
+                }
+            });
+        }
+    }
+
+    async fn load_on(&self, address: &str, body: Bytes) -> Result<()> {
+        let mut backend = Resp2Backend::connect_with(address, &self.handshake).await?;
+        let load = command::request([&b"SCRIPT"[..], b"LOAD", &body]);
+        let reply = backend.ready().await?.call(load).await?.next().await;
+        backend.close().await?;
+        match reply {
+            Some(BytesFrame::Error(e)) => bail!(BackendUnavailable, "{e}"),
+            Some(_) => Ok(()),
+            None => bail!(
+                BackendUnavailable,
+                "Target connection closed before replying"
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScriptCacheLayer {
+    scripts: Arc<LoadedScripts>,
+}
+
+impl ScriptCacheLayer {
+    pub fn new(scripts: Arc<LoadedScripts>) -> Self {
+        Self { scripts }
+    }
+}
+
+impl<S> Layer<S> for ScriptCacheLayer {
+    type Service = ScriptCache<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ScriptCache {
+            inner: Arc::new(tokio::sync::Mutex::new(service)),
+            scripts: self.scripts.clone(),
+            in_transaction: false,
+        }
+    }
+}
+
+/// The wrapped service is shared with in-flight requests, which call it again to retry as
+/// `EVAL`; its readiness is awaited within each request's future
+pub struct ScriptCache<S> {
+    inner: Arc<tokio::sync::Mutex<S>>,
+    scripts: Arc<LoadedScripts>,
+    in_transaction: bool,
+}
+
+/// Dispatch `req` to `inner` once it's ready
+async fn dispatch<S>(inner: &tokio::sync::Mutex<S>, req: BytesFrame) -> Result<ResponseStream>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error>,
+{
+    let fut = {
+        let mut inner = inner.lock().await;
+        futures::future::poll_fn(|cx| inner.poll_ready(cx))
+            .await
+            .map_err(Into::into)?;
+        inner.call(req)
+    };
+    fut.await.map_err(Into::into)
+}
+
+/// `req`, an `EVALSHA` or `EVALSHA_RO`, as the `EVAL` or `EVAL_RO` of `body`
+fn as_eval(req: &BytesFrame, body: Bytes) -> Option<BytesFrame> {
+    let mut args = command::args(req)?.to_vec();
+    let name = if command::name(req)? == "EVALSHA_RO" {
+        "EVAL_RO"
+    } else {
+        "EVAL"
+    };
+    args[0] = BytesFrame::BulkString(Bytes::from_static(name.as_bytes()));
+    *args.get_mut(1)? = BytesFrame::BulkString(body);
+    Some(BytesFrame::Array(args))
+}
+
+fn is_noscript(frame: &BytesFrame) -> bool {
+    matches!(frame, BytesFrame::Error(e) if e.starts_with("NOSCRIPT"))
+}
+
+impl<S> Service<BytesFrame> for ScriptCache<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream> + Send + 'static,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let inner = self.inner.clone();
+        let scripts = self.scripts.clone();
+        let name = command::name(&req).unwrap_or_default();
+        let subcommand = command::args(&req)
+            .and_then(|args| args.get(1))
+            .and_then(command::arg_bytes)
+            .map(|sub| sub.to_ascii_uppercase());
+        match name.as_str() {
+            "MULTI" => self.in_transaction = true,
+            "EXEC" | "DISCARD" => self.in_transaction = false,
+            _ => {}
+        }
+
+        match (name.as_str(), subcommand.as_deref()) {
+            ("SCRIPT", Some(b"LOAD")) => {
+                let body = command::args(&req)
+                    .and_then(|args| args.get(2))
+                    .and_then(command::arg_bytes)
+                    .cloned();
+                Box::pin(async move {
+                    let responses = dispatch(&inner, req).await?;
+                    Ok(responses
+                        .inspect(move |frame| {
+                            if let (BytesFrame::BulkString(digest), Some(body)) = (frame, &body)
+                                && scripts.record(digest, body.clone())
+                            {
+                                scripts.replicate(body.clone());
+                            }
+                        })
+                        .boxed())
+                })
+            }
+            ("SCRIPT", Some(b"FLUSH")) => Box::pin(async move {
+                let responses = dispatch(&inner, req).await?;
+                Ok(responses
+                    .inspect(move |frame| {
+                        if matches!(frame, BytesFrame::SimpleString(ok) if ok == "OK") {
+                            scripts.forget_all();
+                        }
+                    })
+                    .boxed())
+            }),
+            ("EVALSHA" | "EVALSHA_RO", Some(_)) if !self.in_transaction => Box::pin(async move {
+                let mut responses = dispatch(&inner, req.clone()).await?;
+                let first = responses.next().await;
+                let eval = first
+                    .as_ref()
+                    .filter(|first| is_noscript(first))
+                    .and_then(|_| command::args(&req)?.get(1).and_then(command::arg_bytes))
+                    .and_then(|digest| scripts.body(digest))
+                    .and_then(|body| as_eval(&req, body));
+                if let Some(eval) = eval {
+                    log::debug!("Sending {name} again as EVAL, the target not having the script");
+                    return dispatch(&inner, eval).await;
+                }
+                Ok(futures::stream::iter(first).chain(responses).boxed())
+            }),
+            _ => Box::pin(async move { dispatch(&inner, req).await }),
+        }
+    }
+}
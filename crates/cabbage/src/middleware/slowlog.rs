@@ -0,0 +1,96 @@
+//! Proxy-measured slow command log.
+//!
+//! `SlowlogLayer` records every command whose end-to-end latency through the proxy (dispatch to
+//! final response frame) meets or exceeds a threshold into the shared `Slowlog`, similar to
+//! Redis's own SLOWLOG but including any time spent in the proxy and on the network.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::Error;
+use crate::middleware::OnComplete;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+pub struct SlowlogLayer {
+    stats: Arc<Stats>,
+    threshold: Duration,
+    connection_id: String,
+}
+
+impl SlowlogLayer {
+    pub fn new(stats: Arc<Stats>, threshold: Duration, connection_id: impl Into<String>) -> Self {
+        Self {
+            stats,
+            threshold,
+            connection_id: connection_id.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for SlowlogLayer {
+    type Service = Slowlog<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Slowlog {
+            inner: service,
+            stats: self.stats.clone(),
+            threshold: self.threshold,
+            connection_id: Arc::from(self.connection_id.as_str()),
+        }
+    }
+}
+
+pub struct Slowlog<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    threshold: Duration,
+    connection_id: Arc<str>,
+}
+
+impl<S> Service<BytesFrame> for Slowlog<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let stats = self.stats.clone();
+        let threshold = self.threshold;
+        let connection_id = self.connection_id.clone();
+        let request = req.clone();
+        let start = Instant::now();
+
+        Box::pin(
+            self.inner
+                .call(req)
+                .map_ok(move |stream| {
+                    OnComplete::new(stream, move || {
+                        let elapsed = start.elapsed();
+                        if elapsed >= threshold {
+                            stats.slowlog.record(&request, elapsed, &connection_id);
+                        }
+                    })
+                    .boxed()
+                })
+                .map_err(Into::into),
+        )
+    }
+}
@@ -0,0 +1,117 @@
+//! Composition of the client-facing middleware stack.
+//!
+//! Each `StackLayer` names one of the built-in layers a client's commands pass through before
+//! reaching those which act on the target's behalf. `DEFAULT_STACK` is the order they're applied
+//! in unless a configuration lists its own (outermost first, as commands pass through them), in
+//! which case only the layers listed apply. Layers which need settings (auth, the slowlog,
+//! auditing, ...) still take them from their own sections, and apply only when configured.
+//!
+//! The layers nearer the target (caching, rewriting, key prefixes, encryption, retries, ...) keep
+//! a fixed order, as each relies on what those around it do: the cache has to see keys before
+//! they're prefixed and values before they're encrypted, and retries have to see the commands
+//! as they're finally sent.
+
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// A built-in client-facing layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StackLayer {
+    /// Export a span per command over OTLP (requires the `otel` feature)
+    Trace,
+    /// Record per-client transcripts
+    Transcripts,
+    /// Record all traffic to capture files
+    Capture,
+    /// Record administrative and writing commands to the audit log
+    Audit,
+    /// Refuse oversized commands
+    Limits,
+    /// Authenticate clients to the proxy
+    Auth,
+    /// WebAssembly plugins (requires the `plugins` feature)
+    Plugins,
+    /// Lua scripts (requires the `lua` feature)
+    Scripts,
+    /// Per-user command ACLs
+    Acl,
+    /// Allowed and denied commands
+    Filter,
+    /// `MONITOR`
+    Monitor,
+    /// `CABBAGE.*` commands, and the `INFO` section
+    Admin,
+    /// Key space sampling
+    Keyspace,
+    /// Hot key sampling
+    Hotkeys,
+    /// The slowlog
+    Slowlog,
+    /// Latency histograms
+    Latency,
+    /// Logging commands and replies
+    Logging,
+}
+
+/// The client-facing layers in their default order, outermost first: auditing outside every
+/// layer which may refuse commands, so refusals are audited too, authentication outside plugins
+/// and scripts, so they only see authenticated clients' commands, and the filter inside them, so
+/// it sees what they forward
+pub static DEFAULT_STACK: &[StackLayer] = &[
+    StackLayer::Trace,
+    StackLayer::Transcripts,
+    StackLayer::Capture,
+    StackLayer::Audit,
+    StackLayer::Limits,
+    StackLayer::Auth,
+    StackLayer::Plugins,
+    StackLayer::Scripts,
+    StackLayer::Acl,
+    StackLayer::Filter,
+    StackLayer::Monitor,
+    StackLayer::Admin,
+    StackLayer::Keyspace,
+    StackLayer::Hotkeys,
+    StackLayer::Slowlog,
+    StackLayer::Latency,
+    StackLayer::Logging,
+];
+
+impl std::str::FromStr for StackLayer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DEFAULT_STACK
+            .iter()
+            .find(|layer| layer.to_string() == s.to_lowercase())
+            .copied()
+            .ok_or_else(|| Error::Config(format!("Unrecognized middleware layer '{s}'")))
+    }
+}
+
+impl std::fmt::Display for StackLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            StackLayer::Trace => "trace",
+            StackLayer::Transcripts => "transcripts",
+            StackLayer::Capture => "capture",
+            StackLayer::Audit => "audit",
+            StackLayer::Limits => "limits",
+            StackLayer::Auth => "auth",
+            StackLayer::Plugins => "plugins",
+            StackLayer::Scripts => "scripts",
+            StackLayer::Acl => "acl",
+            StackLayer::Filter => "filter",
+            StackLayer::Monitor => "monitor",
+            StackLayer::Admin => "admin",
+            StackLayer::Keyspace => "keyspace",
+            StackLayer::Hotkeys => "hotkeys",
+            StackLayer::Slowlog => "slowlog",
+            StackLayer::Latency => "latency",
+            StackLayer::Logging => "logging",
+        };
+        f.write_str(name)
+    }
+}
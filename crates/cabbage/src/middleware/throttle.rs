@@ -0,0 +1,154 @@
+//! Response bandwidth throttling.
+//!
+//! `ThrottleLayer` meters the bytes of the replies sent to clients with a token bucket refilled
+//! at a number of bytes per second, holding each reply frame back until the bucket can pay for
+//! it, so a client fetching huge `MGET` or `SCAN` results can't saturate the proxy's network.
+//! Each connection can get a bucket of its own, or all of them can share one. A frame larger than
+//! a second's allowance waits only for a full bucket. Replies keep their order, so a throttled
+//! reply holds back those pipelined behind it.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures::stream::Stream;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio::time::Sleep;
+use tower::Layer;
+use tower::Service;
+
+use crate::error::{Error, bail};
+use crate::frame;
+use crate::middleware::ratelimit::TokenBucket;
+use crate::service::ResponseStream;
+
+/// Whose replies share a bandwidth allowance
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleScope {
+    /// Each client connection gets the full allowance
+    #[default]
+    Connection,
+    /// Every connection draws on a single allowance
+    Global,
+}
+
+impl std::str::FromStr for ThrottleScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "connection" => Ok(ThrottleScope::Connection),
+            "global" => Ok(ThrottleScope::Global),
+            _ => bail!(Config, "Unrecognized throttle scope '{s}'"),
+        }
+    }
+}
+
+pub struct ThrottleLayer {
+    bytes_per_sec: f64,
+    /// The bucket shared by every connection, for a global throttle
+    shared: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl ThrottleLayer {
+    pub fn new(bytes_per_sec: u64, scope: ThrottleScope) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            shared: (scope == ThrottleScope::Global)
+                .then(|| Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec)))),
+        }
+    }
+}
+
+impl<S> Layer<S> for ThrottleLayer {
+    type Service = Throttle<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Throttle {
+            inner: service,
+            bucket: self
+                .shared
+                .clone()
+                .unwrap_or_else(|| Arc::new(Mutex::new(TokenBucket::new(self.bytes_per_sec)))),
+        }
+    }
+}
+
+pub struct Throttle<S> {
+    inner: S,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl<S> Service<BytesFrame> for Throttle<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let bucket = self.bucket.clone();
+        Box::pin(
+            self.inner
+                .call(req)
+                .map_ok(move |responses| {
+                    Throttled {
+                        inner: responses,
+                        bucket,
+                        held: None,
+                    }
+                    .boxed()
+                })
+                .map_err(Into::into),
+        )
+    }
+}
+
+/// A response stream yielding each frame once the bucket has paid for it
+struct Throttled {
+    inner: ResponseStream,
+    bucket: Arc<Mutex<TokenBucket>>,
+    /// A frame waiting for its tokens
+    held: Option<(BytesFrame, Pin<Box<Sleep>>)>,
+}
+
+impl Stream for Throttled {
+    type Item = BytesFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some((_, wait)) = &mut self.held {
+            ready!(wait.as_mut().poll(cx));
+            return Poll::Ready(self.held.take().map(|(frame, _)| frame));
+        }
+        let Some(frame) = ready!(self.inner.poll_next_unpin(cx)) else {
+            return Poll::Ready(None);
+        };
+        let size = frame::encoded_len(&frame) as f64;
+        let wait = match self.bucket.lock() {
+            Ok(mut bucket) => bucket.take(size),
+            Err(_) => return Poll::Ready(Some(frame)),
+        };
+        if wait.is_zero() {
+            return Poll::Ready(Some(frame));
+        }
+        let mut wait = Box::pin(tokio::time::sleep(wait));
+        if wait.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(frame));
+        }
+        self.held = Some((frame, wait));
+        Poll::Pending
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-request timeouts.
+//!
+//! `TimeoutLayer` bounds how long the target may take to answer a command: a deadline for the
+//! first reply frame, measured from dispatch, and optionally one for the final frame. When either
+//! passes, the client receives `-ERR proxy timeout` in place of the rest of the reply (anything
+//! the target sends later is discarded) and the target is flagged in `Stats` for health checking.
+//! Blocking commands are exempt, and subscriptions only have their first frame bounded.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::time::{Instant, timeout_at};
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+use crate::stats::Stats;
+
+fn timeout_error() -> BytesFrame {
+    command::error("ERR proxy timeout")
+}
+
+pub struct TimeoutLayer {
+    stats: Arc<Stats>,
+    first_frame: Duration,
+    last_frame: Option<Duration>,
+}
+
+impl TimeoutLayer {
+    pub fn new(stats: Arc<Stats>, first_frame: Duration) -> Self {
+        Self {
+            stats,
+            first_frame,
+            last_frame: None,
+        }
+    }
+
+    /// Also bound the time until the final frame of a reply
+    pub fn with_last_frame(mut self, last_frame: Duration) -> Self {
+        self.last_frame = Some(last_frame);
+        self
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Timeout {
+            inner: service,
+            stats: self.stats.clone(),
+            first_frame: self.first_frame,
+            last_frame: self.last_frame,
+        }
+    }
+}
+
+pub struct Timeout<S> {
+    inner: S,
+    stats: Arc<Stats>,
+    first_frame: Duration,
+    last_frame: Option<Duration>,
+}
+
+impl<S> Service<BytesFrame> for Timeout<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        if command::is_blocking(&req) {
+            return Box::pin(self.inner.call(req).map_err(Into::into));
+        }
+
+        let start = Instant::now();
+        let last_deadline = self
+            .last_frame
+            .filter(|_| !command::starts_push_mode(&req))
+            .map(|d| start + d);
+        let first_deadline = last_deadline.map_or(start + self.first_frame, |last| {
+            last.min(start + self.first_frame)
+        });
+        let stats = self.stats.clone();
+
+        let fut = self.inner.call(req).map_err(Into::into);
+        Box::pin(async move {
+            let stream = match timeout_at(first_deadline, fut).await {
+                Ok(stream) => stream?,
+                Err(_) => {
+                    stats.backend.record_timeout();
+                    return Ok(reply(timeout_error()));
+                }
+            };
+
+            let bounded = futures::stream::unfold(Some((stream, true)), move |state| {
+                let stats = stats.clone();
+                async move {
+                    let (mut stream, first) = state?;
+                    let deadline = if first {
+                        Some(first_deadline)
+                    } else {
+                        last_deadline
+                    };
+                    let next = match deadline {
+                        Some(deadline) => match timeout_at(deadline, stream.next()).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                stats.backend.record_timeout();
+                                return Some((timeout_error(), None));
+                            }
+                        },
+                        None => stream.next().await,
+                    };
+                    next.map(|frame| (frame, Some((stream, false))))
+                }
+            });
+            Ok(bounded.boxed())
+        })
+    }
+}
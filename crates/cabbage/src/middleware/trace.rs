@@ -0,0 +1,144 @@
+//! OpenTelemetry tracing of proxied commands.
+//!
+//! `ProxyTraceLayer` opens a client span for every command passing through the proxy, recording
+//! the command name, first key, and connection ID, and closes it once the final response frame
+//! has been forwarded, annotated with the observed latency and whether the backend replied with
+//! an error. Spans are reported through the global tracer provider, which `init_otlp` points at
+//! an OTLP collector.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use futures_util::StreamExt;
+use opentelemetry::trace::{Span as _, SpanKind, Status, Tracer as _};
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::WithExportConfig as _;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::middleware::OnComplete;
+use crate::service::ResponseStream;
+
+static TRACER_NAME: &str = "cabbage";
+
+/// Install a global tracer provider exporting spans over OTLP/HTTP to `endpoint`
+pub fn init_otlp(endpoint: &str) -> Result<SdkTracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(Error::other)?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(TRACER_NAME).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(provider)
+}
+
+pub struct ProxyTraceLayer {
+    connection_id: String,
+}
+
+impl ProxyTraceLayer {
+    pub fn new(connection_id: impl Into<String>) -> Self {
+        Self {
+            connection_id: connection_id.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for ProxyTraceLayer {
+    type Service = ProxyTrace<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ProxyTrace {
+            inner: service,
+            connection_id: self.connection_id.clone(),
+        }
+    }
+}
+
+pub struct ProxyTrace<S> {
+    inner: S,
+    connection_id: String,
+}
+
+impl<S> Service<BytesFrame> for ProxyTrace<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let tracer = global::tracer(TRACER_NAME);
+        let name = command::name(&req).unwrap_or_else(|| "UNKNOWN".to_string());
+
+        let mut attributes = vec![
+            KeyValue::new("db.system", "redis"),
+            KeyValue::new("db.operation", name.clone()),
+            KeyValue::new("cabbage.connection_id", self.connection_id.clone()),
+        ];
+        if let Some(key) = command::first_key(&req) {
+            attributes.push(KeyValue::new(
+                "db.redis.key",
+                String::from_utf8_lossy(key).into_owned(),
+            ));
+        }
+        let span = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Client)
+            .with_attributes(attributes)
+            .start(&tracer);
+        let span = Arc::new(Mutex::new(span));
+        let start = Instant::now();
+
+        let fut = self.inner.call(req);
+        let dispatch_span = span.clone();
+        Box::pin(
+            fut.map_ok(move |stream| {
+                let frame_span = span.clone();
+                let traced = stream.inspect(move |frame| {
+                    if let (BytesFrame::Error(message), Ok(mut span)) = (frame, frame_span.lock()) {
+                        span.set_status(Status::error(message.to_string()));
+                    }
+                });
+                OnComplete::new(traced.boxed(), move || {
+                    if let Ok(mut span) = span.lock() {
+                        span.set_attribute(KeyValue::new(
+                            "cabbage.latency_us",
+                            start.elapsed().as_micros() as i64,
+                        ));
+                        span.end();
+                    }
+                })
+                .boxed()
+            })
+            .map_err(move |e| {
+                let e = e.into();
+                if let Ok(mut span) = dispatch_span.lock() {
+                    span.set_status(Status::error(format!("backend dispatch failed: {e}")));
+                    span.end();
+                }
+                e
+            }),
+        )
+    }
+}
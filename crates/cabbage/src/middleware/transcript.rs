@@ -0,0 +1,242 @@
+//! Per-connection session transcripts.
+//!
+//! `TranscriptLayer` writes every request a client sends, and every reply frame it gets back, to
+//! a file of the connection's own, `<connection ID>.log` in the transcripts directory, so a
+//! single client's session can be pulled without picking it out of the interleaved logs of every
+//! other. Each line is stamped with the time and marked `>` for a request or `<` for a reply:
+//!
+//! ```text
+//! 1718030000.123456 > "AUTH" "<redacted>"
+//! 1718030000.123712 < +OK
+//! 1718030000.124001 > "MGET" "a" "b"
+//! 1718030000.124190 < ["1", (nil)]
+//! ```
+//!
+//! Sensitive values are masked by the same `RedactionRules` as the logs. Lines are handed to a
+//! dedicated writer thread through a bounded queue; if the disk can't keep up, lines are dropped
+//! (and counted) rather than slowing down clients.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write as _};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tokio::sync::mpsc;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tower::Layer;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::frame::{render, render_command};
+use crate::middleware::redact::{self, RedactionRules};
+use crate::service::ResponseStream;
+
+/// Lines waiting to be written beyond this many are dropped
+static QUEUE_LEN: usize = 64 * 1024;
+/// The most lines written between flushes
+static MAX_BATCH_LEN: usize = 1024;
+
+/// A line of a connection's transcript
+struct Line {
+    connection_id: Uuid,
+    line: Bytes,
+}
+
+/// The transcripts of every connection, written into a shared directory
+#[derive(Clone)]
+pub struct Transcripts {
+    lines: mpsc::Sender<Line>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Transcripts {
+    /// Start writing transcripts into `directory`, which is created if need be
+    pub fn start(directory: impl Into<PathBuf>) -> Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory).map_err(|e| {
+            let what = format!(
+                "Failed to create transcript directory {}",
+                directory.display()
+            );
+            Error::io(what, e)
+        })?;
+        log::info!("Writing connection transcripts to {}", directory.display());
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("cabbage-transcripts".to_string())
+            .spawn(move || write_transcripts(&directory, receiver))?;
+        Ok(Self {
+            lines: sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    fn write(&self, connection_id: Uuid, line: Bytes) {
+        if self
+            .lines
+            .try_send(Line {
+                connection_id,
+                line,
+            })
+            .is_err()
+            && self.dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            log::warn!("Transcripts are falling behind, dropping lines");
+        }
+    }
+
+    /// Lines not written because the writer had fallen behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Append lines to their connections' transcripts in `directory` until every `Transcripts` is
+/// dropped
+fn write_transcripts(directory: &Path, mut lines: mpsc::Receiver<Line>) {
+    let mut batch = Vec::new();
+    while let Some(line) = lines.blocking_recv() {
+        batch.push(line);
+        while batch.len() < MAX_BATCH_LEN
+            && let Ok(line) = lines.try_recv()
+        {
+            batch.push(line);
+        }
+        // Files are only held open for a batch, so nothing needs closing when a connection ends.
+        // The sort is stable, keeping each connection's lines in order.
+        batch.sort_by_key(|line| line.connection_id);
+        for session in batch.chunk_by(|a, b| a.connection_id == b.connection_id) {
+            let path = directory.join(format!("{}.log", session[0].connection_id));
+            if let Err(e) = append(&path, session) {
+                log::error!("Failed to write transcript {}: {e:#}", path.display());
+            }
+        }
+        batch.clear();
+    }
+}
+
+fn append(path: &Path, lines: &[Line]) -> Result<()> {
+    let mut out = BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?);
+    for line in lines {
+        out.write_all(&line.line)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// A new line, stamped with the time and marked with `direction`
+fn stamped(direction: u8) -> BytesMut {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = BytesMut::new();
+    line.put_slice(format!("{}.{:06} ", now.as_secs(), now.subsec_micros()).as_bytes());
+    line.put_u8(direction);
+    line.put_u8(b' ');
+    line
+}
+
+/// The transcript's line for the request `req`
+fn request_line(req: &BytesFrame) -> Bytes {
+    let mut line = stamped(b'>');
+    render_command(req, &mut line);
+    line.freeze()
+}
+
+/// The transcript's line for the reply frame `frame`
+fn reply_line(frame: &BytesFrame) -> Bytes {
+    let mut line = stamped(b'<');
+    render(frame, &mut line);
+    line.freeze()
+}
+
+pub struct TranscriptLayer {
+    transcripts: Transcripts,
+    connection_id: Uuid,
+    redaction: Arc<RedactionRules>,
+}
+
+impl TranscriptLayer {
+    /// Write the session of connection `connection_id` into `transcripts`
+    pub fn new(transcripts: Transcripts, connection_id: Uuid) -> Self {
+        Self {
+            transcripts,
+            connection_id,
+            redaction: Arc::new(RedactionRules::default()),
+        }
+    }
+
+    /// Mask sensitive values according to `redaction` rather than the default rules
+    pub fn with_redaction(mut self, redaction: impl Into<Arc<RedactionRules>>) -> Self {
+        self.redaction = redaction.into();
+        self
+    }
+}
+
+impl<S> Layer<S> for TranscriptLayer {
+    type Service = Transcriber<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        Transcriber {
+            inner: service,
+            transcripts: self.transcripts.clone(),
+            connection_id: self.connection_id,
+            redaction: self.redaction.clone(),
+        }
+    }
+}
+
+pub struct Transcriber<S> {
+    inner: S,
+    transcripts: Transcripts,
+    connection_id: Uuid,
+    redaction: Arc<RedactionRules>,
+}
+
+impl<S> Service<BytesFrame> for Transcriber<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        self.transcripts.write(
+            self.connection_id,
+            request_line(&self.redaction.request(&req)),
+        );
+        let masks_reply = self.redaction.masks_reply(&req);
+        let transcripts = self.transcripts.clone();
+        let connection_id = self.connection_id;
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let stream = fut.await.map_err(Into::into)?;
+            Ok(stream
+                .inspect(move |frame| {
+                    let line = if masks_reply {
+                        reply_line(&redact::reply(frame))
+                    } else {
+                        reply_line(frame)
+                    };
+                    transcripts.write(connection_id, line);
+                })
+                .boxed())
+        })
+    }
+}
@@ -0,0 +1,253 @@
+//! Expiry policy enforcement.
+//!
+//! `TtlPolicyLayer` holds the writes which set expiries to a `TtlPolicy` before they're
+//! forwarded, so a proxy can guarantee what it lets into the target expires:
+//!
+//! - A `SET` without an expiry (and without `KEEPTTL`) is given the default, as `PX`
+//! - Expiries set by `SET`, `GETEX`, `SETEX`, `PSETEX`, and the `EXPIRE` family are clamped to
+//!   the minimum and maximum, those which are changed being rewritten as relative milliseconds
+//!   (`PX`, `PSETEX`, or `PEXPIRE`), with any flags kept
+//! - With a default, `PERSIST` and `GETEX ... PERSIST` are refused, as they'd leave a key which
+//!   never expires
+//!
+//! Expiries in the past (which delete the key) and arguments which aren't integers are left for
+//! the target to act on. Other commands which create keys (`MSET`, `INCR`, `HSET`, and the like)
+//! can't be given expiries in the same step, so they're forwarded untouched; with
+//! `translate_legacy_commands`, `SETNX` and `GETSET` become `SET`s the policy applies to.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures::TryFutureExt as _;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::Deserialize;
+use tokio_util::bytes::Bytes;
+use tower::Layer;
+use tower::Service;
+
+use crate::command;
+use crate::error::Error;
+use crate::middleware::reply;
+use crate::service::ResponseStream;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlPolicy {
+    /// Expiry given to keys `SET` without one, in milliseconds
+    pub default_ms: Option<u64>,
+    /// Shortest expiry allowed, in milliseconds
+    pub min_ms: Option<u64>,
+    /// Longest expiry allowed, in milliseconds
+    pub max_ms: Option<u64>,
+}
+
+/// Where an expiry argument counts from, and in what unit
+#[derive(Clone, Copy)]
+enum Expiry {
+    Seconds,
+    Milliseconds,
+    UnixSeconds,
+    UnixMilliseconds,
+}
+
+impl Expiry {
+    /// The expiry option of `SET` or `GETEX` named `option`, if it is one
+    fn of_option(option: &[u8]) -> Option<Self> {
+        match option.to_ascii_uppercase().as_slice() {
+            b"EX" => Some(Self::Seconds),
+            b"PX" => Some(Self::Milliseconds),
+            b"EXAT" => Some(Self::UnixSeconds),
+            b"PXAT" => Some(Self::UnixMilliseconds),
+            _ => None,
+        }
+    }
+
+    /// How many milliseconds from now `value` expires in
+    fn millis(self, value: i64) -> i64 {
+        let now = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as i64)
+        };
+        match self {
+            Self::Seconds => value.saturating_mul(1000),
+            Self::Milliseconds => value,
+            Self::UnixSeconds => value.saturating_mul(1000).saturating_sub(now()),
+            Self::UnixMilliseconds => value.saturating_sub(now()),
+        }
+    }
+}
+
+fn bulk(arg: impl Into<Bytes>) -> BytesFrame {
+    BytesFrame::BulkString(arg.into())
+}
+
+fn integer(arg: &BytesFrame) -> Option<i64> {
+    std::str::from_utf8(command::arg_bytes(arg)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+impl TtlPolicy {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The expiry `ttl_ms` from now held to the minimum and maximum, unless it's already past
+    fn clamp(&self, ttl_ms: i64) -> i64 {
+        if ttl_ms <= 0 {
+            return ttl_ms;
+        }
+        let mut clamped = ttl_ms as u64;
+        if let Some(min) = self.min_ms {
+            clamped = clamped.max(min);
+        }
+        if let Some(max) = self.max_ms {
+            clamped = clamped.min(max);
+        }
+        clamped.try_into().unwrap_or(i64::MAX)
+    }
+
+    /// The expiry `value` of the kind `expiry` as clamped milliseconds, if clamping changes it
+    fn clamped(&self, expiry: Expiry, value: &BytesFrame) -> Option<i64> {
+        let ttl_ms = expiry.millis(integer(value)?);
+        let clamped = self.clamp(ttl_ms);
+        (clamped != ttl_ms).then_some(clamped)
+    }
+
+    /// Hold the expiry option among the arguments of `SET` or `GETEX` from `first_option` on to
+    /// the policy, or add the default if there's none and `add_default`
+    fn enforce_options(&self, args: &mut Vec<BytesFrame>, first_option: usize, add_default: bool) {
+        let mut i = first_option;
+        while i < args.len() {
+            let Some(option) = command::arg_bytes(&args[i]) else {
+                i += 1;
+                continue;
+            };
+            if option.eq_ignore_ascii_case(b"KEEPTTL") {
+                return;
+            }
+            if let Some(expiry) = Expiry::of_option(option) {
+                if let Some(value) = args.get(i + 1)
+                    && let Some(clamped) = self.clamped(expiry, value)
+                {
+                    args[i] = bulk("PX");
+                    args[i + 1] = bulk(clamped.to_string());
+                }
+                return;
+            }
+            i += 1;
+        }
+        if add_default && let Some(default) = self.default_ms {
+            args.extend([bulk("PX"), bulk(default.to_string())]);
+        }
+    }
+
+    /// `req` held to the policy, or why it's refused
+    fn enforce(&self, req: BytesFrame) -> Result<BytesFrame, String> {
+        let Some(name) = command::name(&req) else {
+            return Ok(req);
+        };
+        let Some(mut args) = command::args(&req).map(<[_]>::to_vec) else {
+            return Ok(req);
+        };
+        match name.as_str() {
+            "SET" if args.len() >= 3 => self.enforce_options(&mut args, 3, true),
+            "GETEX" if args.len() >= 2 => {
+                let persists = args[2..]
+                    .iter()
+                    .filter_map(command::arg_bytes)
+                    .any(|option| option.eq_ignore_ascii_case(b"PERSIST"));
+                if persists && self.default_ms.is_some() {
+                    return Err(
+                        "ERR GETEX ... PERSIST isn't allowed through this proxy, as keys must \
+                         expire"
+                            .to_string(),
+                    );
+                }
+                self.enforce_options(&mut args, 2, false);
+            }
+            "PERSIST" if self.default_ms.is_some() => {
+                return Err(
+                    "ERR PERSIST isn't allowed through this proxy, as keys must expire".to_string(),
+                );
+            }
+            "SETEX" | "PSETEX" if args.len() == 4 => {
+                let expiry = match name.as_str() {
+                    "SETEX" => Expiry::Seconds,
+                    _ => Expiry::Milliseconds,
+                };
+                if let Some(clamped) = self.clamped(expiry, &args[2]) {
+                    args[0] = bulk("PSETEX");
+                    args[2] = bulk(clamped.to_string());
+                }
+            }
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" if args.len() >= 3 => {
+                let expiry = match name.as_str() {
+                    "EXPIRE" => Expiry::Seconds,
+                    "PEXPIRE" => Expiry::Milliseconds,
+                    "EXPIREAT" => Expiry::UnixSeconds,
+                    _ => Expiry::UnixMilliseconds,
+                };
+                if let Some(clamped) = self.clamped(expiry, &args[2]) {
+                    args[0] = bulk("PEXPIRE");
+                    args[2] = bulk(clamped.to_string());
+                }
+            }
+            _ => return Ok(req),
+        }
+        Ok(BytesFrame::Array(args))
+    }
+}
+
+#[derive(Clone)]
+pub struct TtlPolicyLayer {
+    policy: TtlPolicy,
+}
+
+impl TtlPolicyLayer {
+    pub fn new(policy: TtlPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl<S> Layer<S> for TtlPolicyLayer {
+    type Service = TtlEnforcement<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        TtlEnforcement {
+            inner: service,
+            policy: self.policy,
+        }
+    }
+}
+
+pub struct TtlEnforcement<S> {
+    inner: S,
+    policy: TtlPolicy,
+}
+
+impl<S> Service<BytesFrame> for TtlEnforcement<S>
+where
+    S: Service<BytesFrame, Response = ResponseStream>,
+    S::Error: Into<Error> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        match self.policy.enforce(req) {
+            Ok(req) => Box::pin(self.inner.call(req).map_err(Into::into)),
+            Err(message) => Box::pin(async move { Ok(reply(command::error(message))) }),
+        }
+    }
+}
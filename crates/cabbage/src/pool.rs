@@ -0,0 +1,338 @@
+//! The target connection pool.
+//!
+//! Each client connection is served by target connections of its own, and a `TargetPool` is
+//! where they come from. It keeps `min_size` connections connected and handshaken ahead of need,
+//! so a new client is handed one at once rather than waiting on a connect and `AUTH` of its own,
+//! and a burst of clients (as after a restart) doesn't become a burst of connections to the
+//! target. It's filled before the proxy starts accepting clients, and topped up in the background
+//! as connections are taken. Connections are made to wherever the target currently is: after a
+//! Sentinel or failover list moves the target, those to the old address are dropped and new ones
+//! made to the new.
+//!
+//! With a `max_size`, no more than that many target connections are open at once, counting those
+//! handed out until they close as well as those ready. A client needing a connection beyond it
+//! is dealt with by the `PoolOverflow` policy: it waits up to `max_wait` for another client's to
+//! close, is given a temporary connection beyond the limit, or is answered with an error at once.
+//! Checkouts, waits, and overflows are counted in `Stats::pool`.
+//!
+//! Only clients which haven't `SELECT`ed a database are handed ready connections, as they were
+//! made with the handshake's.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::error::{Error, Result, bail};
+use crate::failover::FailoverTargets;
+use crate::sentinel::SentinelMaster;
+use crate::service::{Handshake, QueueCapacities, Resp2Backend};
+use crate::stats::Stats;
+
+/// How many connections are made at once while filling
+static FILL_CONCURRENCY: usize = 8;
+/// How often the pool is checked for closed connections and target moves, between takes
+static RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Delay before filling again after failing to connect
+static RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What a client needing a target connection gets when `max_size` are already open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolOverflow {
+    /// Wait up to `max_wait` for a connection to close
+    #[default]
+    Queue,
+    /// A connection beyond the limit, closed along with its client
+    Temporary,
+    /// An error, at once
+    Fail,
+}
+
+impl std::str::FromStr for PoolOverflow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "queue" => Ok(PoolOverflow::Queue),
+            "temporary" => Ok(PoolOverflow::Temporary),
+            "fail" => Ok(PoolOverflow::Fail),
+            _ => bail!(Config, "Unrecognized pool overflow policy '{s}'"),
+        }
+    }
+}
+
+/// A checkout turned away because the pool was at its `max_size`
+#[derive(Debug)]
+pub struct PoolExhausted {
+    /// How long the client waited first, if it was queued
+    pub waited: Option<Duration>,
+}
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.waited {
+            Some(waited) => write!(f, "no target connection came free within {waited:?}"),
+            None => write!(f, "target connection pool is exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for PoolExhausted {}
+
+/// Where pooled connections are made to
+pub enum PoolTarget {
+    Single(String),
+    /// Whichever address the master currently has
+    Sentinel(Arc<SentinelMaster>),
+    /// The target in use of a failover list
+    Failover(Arc<FailoverTargets>),
+}
+
+impl PoolTarget {
+    fn address(&self) -> Option<String> {
+        match self {
+            Self::Single(address) => Some(address.clone()),
+            Self::Sentinel(master) => master.current(),
+            Self::Failover(targets) => Some(targets.current()),
+        }
+    }
+}
+
+/// Target connections for clients, some connected and handshaken ahead of them
+pub struct TargetPool {
+    target: PoolTarget,
+    handshake: Handshake,
+    capacities: QueueCapacities,
+    min_size: usize,
+    max_size: Option<usize>,
+    max_wait: Duration,
+    overflow: PoolOverflow,
+    stats: Arc<Stats>,
+    ready: Mutex<Vec<(String, Resp2Backend)>>,
+    /// Connections handed out and still open, or being made
+    open: Arc<AtomicUsize>,
+    /// Signalled when a connection handed out closes, or one is made ready
+    released: Arc<Notify>,
+    /// Signalled when the pool needs topping up
+    taken: Arc<Notify>,
+}
+
+impl TargetPool {
+    /// Make connections to `target`, each performing `handshake`, keeping `min_size` ready
+    pub fn new(target: PoolTarget, handshake: Handshake, min_size: usize) -> Self {
+        Self {
+            target,
+            handshake,
+            capacities: QueueCapacities::default(),
+            min_size,
+            max_size: None,
+            max_wait: Duration::ZERO,
+            overflow: PoolOverflow::default(),
+            stats: Stats::new(),
+            ready: Mutex::new(vec![]),
+            open: Arc::new(AtomicUsize::new(0)),
+            released: Arc::new(Notify::new()),
+            taken: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Start connections' backends with queues of the given `capacities`
+    pub fn with_capacities(mut self, capacities: QueueCapacities) -> Self {
+        self.capacities = capacities;
+        self
+    }
+
+    /// Keep at most `max_size` connections open, handling checkouts beyond that by `overflow`,
+    /// queueing for up to `max_wait`
+    pub fn with_max_size(
+        mut self,
+        max_size: usize,
+        max_wait: Duration,
+        overflow: PoolOverflow,
+    ) -> Self {
+        self.max_size = Some(max_size);
+        self.max_wait = max_wait;
+        self.overflow = overflow;
+        self
+    }
+
+    /// Count checkouts, waits, and overflows in `stats`
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// A connection to `address` for a client which has selected database `db`, if any: a ready
+    /// one if there is one, otherwise a new one, if the pool has room or its overflow policy
+    /// allows. Fails with `PoolExhausted` if it has no room.
+    pub async fn checkout(&self, address: &str, db: Option<u32>) -> Result<Resp2Backend> {
+        let handshake = Handshake {
+            db: db.or(self.handshake.db),
+            ..self.handshake.clone()
+        };
+        let reusable = handshake.db == self.handshake.db;
+        let started = Instant::now();
+        let mut queued = false;
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let reserved = {
+                let Ok(mut ready) = self.ready.lock() else {
+                    bail!(BackendUnavailable, "Target connection pool lock poisoned");
+                };
+                ready.retain(|(a, backend)| a == address && !backend.is_disconnected());
+                if reusable && let Some((_, backend)) = ready.pop() {
+                    self.open.fetch_add(1, Ordering::Relaxed);
+                    drop(ready);
+                    let waited = queued.then(|| started.elapsed());
+                    self.stats.pool.record_checkout(true, waited);
+                    return Ok(self.hand_out(backend));
+                }
+                let open = self.open.load(Ordering::Relaxed) + ready.len();
+                let has_room = self.max_size.is_none_or(|max| open < max);
+                if has_room || self.overflow == PoolOverflow::Temporary {
+                    self.open.fetch_add(1, Ordering::Relaxed);
+                    if !has_room {
+                        self.stats.pool.record_temporary();
+                    }
+                    true
+                } else {
+                    false
+                }
+            };
+            if reserved {
+                let connected =
+                    Resp2Backend::connect_with_capacities(address, &handshake, self.capacities)
+                        .await;
+                return match connected {
+                    Ok(backend) => {
+                        let waited = queued.then(|| started.elapsed());
+                        self.stats.pool.record_checkout(false, waited);
+                        Ok(self.hand_out(backend))
+                    }
+                    Err(e) => {
+                        self.release();
+                        Err(e)
+                    }
+                };
+            }
+
+            let remaining = self.max_wait.saturating_sub(started.elapsed());
+            if self.overflow == PoolOverflow::Fail || remaining.is_zero() {
+                self.stats.pool.record_exhausted();
+                let waited = queued.then(|| started.elapsed());
+                return Err(PoolExhausted { waited }.into());
+            }
+            queued = true;
+            let _ = tokio::time::timeout(remaining, released).await;
+        }
+    }
+
+    /// Track `backend`, handed to a client, until it closes
+    fn hand_out(&self, backend: Resp2Backend) -> Resp2Backend {
+        let closed = backend.disconnected();
+        let open = self.open.clone();
+        let released = self.released.clone();
+        let taken = self.taken.clone();
+        tokio::spawn(async move {
+            closed.await;
+            open.fetch_sub(1, Ordering::Relaxed);
+            released.notify_one();
+            taken.notify_one();
+        });
+        self.taken.notify_one();
+        backend
+    }
+
+    /// Give back room reserved for a connection which failed to connect
+    fn release(&self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+        self.released.notify_one();
+    }
+
+    /// Connections ready, after dropping any closed or to an old address
+    fn prune(&self, address: &str) -> usize {
+        let Ok(mut ready) = self.ready.lock() else {
+            return 0;
+        };
+        ready.retain(|(a, backend)| a == address && !backend.is_disconnected());
+        let size = ready.len();
+        self.stats
+            .pool
+            .set_sizes(self.open.load(Ordering::Relaxed), size);
+        size
+    }
+
+    /// Reserve room for up to `wanted` more ready connections, answering with how many fit
+    fn reserve(&self, wanted: usize) -> usize {
+        let Ok(ready) = self.ready.lock() else {
+            return 0;
+        };
+        let open = self.open.load(Ordering::Relaxed) + ready.len();
+        let room = self
+            .max_size
+            .map_or(wanted, |max| wanted.min(max.saturating_sub(open)));
+        self.open.fetch_add(room, Ordering::Relaxed);
+        room
+    }
+
+    /// Make connections until `min_size` are ready, or the pool is full, answering with how
+    /// many are ready
+    pub async fn fill(&self) -> Result<usize> {
+        let Some(address) = self.target.address() else {
+            bail!(BackendUnavailable, "The target hasn't been resolved");
+        };
+        let missing = self.reserve(self.min_size.saturating_sub(self.prune(&address)));
+        let mut connects = futures::stream::iter(0..missing)
+            .map(|_| {
+                Resp2Backend::connect_with_capacities(&address, &self.handshake, self.capacities)
+            })
+            .buffer_unordered(FILL_CONCURRENCY);
+        let mut failure = None;
+        while let Some(result) = connects.next().await {
+            match result {
+                Ok(backend) => {
+                    if let Ok(mut ready) = self.ready.lock() {
+                        ready.push((address.clone(), backend));
+                    }
+                    self.open.fetch_sub(1, Ordering::Relaxed);
+                    self.released.notify_one();
+                }
+                Err(e) => {
+                    self.release();
+                    failure = Some(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(e.context(format!("Failed to warm connections to {address}"))),
+            None => Ok(self.prune(&address)),
+        }
+    }
+
+    /// Top the pool up whenever a connection is taken, or one closes or the target moves, for
+    /// as long as the process runs
+    pub async fn keep_warm(self: Arc<Self>) {
+        let mut failing = false;
+        loop {
+            let _ = tokio::time::timeout(RECHECK_INTERVAL, self.taken.notified()).await;
+            match self.fill().await {
+                Ok(_) => failing = false,
+                Err(e) => {
+                    if !failing {
+                        log::warn!("{e:#}");
+                    }
+                    failing = true;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Protocols carried by the proxy core.
+//!
+//! Serving a client connection (`proxy::serve_connection`) and multiplexing requests over a
+//! target connection (`service::Backend`) only depend on a protocol's framing, so they're generic
+//! over it: `ClientCodec` frames a client connection, and `Protocol` names the frames a target
+//! speaks, the codec for them, and which requests leave a connection streaming pushed frames.
+//! RESP2 (`Resp2Protocol`) is what the middleware and every built-in frontend run on.
+
+use std::error::Error;
+use std::fmt::Debug;
+
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::streaming::{BulkChunk, BulkStreamingCodec};
+use crate::{command, resp3};
+
+/// A request/reply protocol spoken to targets
+pub trait Protocol: Send + Sync + 'static {
+    /// A request or reply
+    type Frame: Debug + Send + Sync + 'static;
+    /// Framing for target connections
+    type Codec: Decoder<Item = Self::Frame, Error: Error + Send + Sync + 'static>
+        + Encoder<Self::Frame, Error: Error + Send + Sync + 'static>
+        + Send
+        + 'static;
+
+    /// Whether `request` leaves the connection in push mode, where every frame the target sends
+    /// is part of the request's reply until one `ends_push_mode`
+    fn starts_push_mode(_request: &Self::Frame) -> bool {
+        false
+    }
+
+    /// Whether `reply` returns a connection in push mode to request/reply
+    fn ends_push_mode(_reply: &Self::Frame) -> bool {
+        false
+    }
+
+    /// Whether `request` may wait for its reply indefinitely, so it has no deadline
+    fn is_blocking(_request: &Self::Frame) -> bool {
+        false
+    }
+
+    /// The reply to each request left unanswered when the target connection is lost
+    fn disconnected_reply() -> Self::Frame;
+
+    /// The reply to a request whose reply didn't arrive by its deadline
+    fn timeout_reply() -> Self::Frame;
+
+    /// Where `reply` falls in a reply streamed as several frames, if it's part of one
+    fn reply_part(_reply: &Self::Frame) -> Option<ReplyPart> {
+        None
+    }
+
+    /// `frame` as it's passed on, and whether the target pushed it of its own accord rather than
+    /// sending it in reply to a request
+    fn pushed(frame: Self::Frame) -> (Self::Frame, bool) {
+        (frame, false)
+    }
+}
+
+/// A frame's place in a reply streamed as several frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplyPart {
+    pub starts: bool,
+    pub ends: bool,
+}
+
+/// RESP2, with subscriptions and `MONITOR` streaming pushed frames
+pub struct Resp2Protocol;
+
+impl Protocol for Resp2Protocol {
+    type Frame = BytesFrame;
+    type Codec = BulkStreamingCodec;
+
+    fn starts_push_mode(request: &BytesFrame) -> bool {
+        command::starts_push_mode(request)
+    }
+
+    fn is_blocking(request: &BytesFrame) -> bool {
+        command::is_blocking(request)
+    }
+
+    fn disconnected_reply() -> BytesFrame {
+        command::error("ERR proxy backend connection lost")
+    }
+
+    fn timeout_reply() -> BytesFrame {
+        command::error("ERR proxy timeout")
+    }
+
+    /// Large bulk strings are streamed in chunks, when `BulkStreamingCodec` is set to
+    fn reply_part(reply: &BytesFrame) -> Option<ReplyPart> {
+        BulkChunk::of(reply).map(|chunk| ReplyPart {
+            starts: chunk.starts(),
+            ends: chunk.ends(),
+        })
+    }
+
+    /// A target spoken to in RESP3 pushes frames, which `BulkStreamingCodec` marks
+    fn pushed(frame: BytesFrame) -> (BytesFrame, bool) {
+        resp3::pushed(frame)
+    }
+
+    /// The final unsubscription ends push mode
+    fn ends_push_mode(reply: &BytesFrame) -> bool {
+        let BytesFrame::Array(parts) = reply else {
+            return false;
+        };
+        let kind = parts.first().and_then(command::arg_bytes);
+        matches!(
+            (kind, parts.get(2)),
+            (Some(kind), Some(BytesFrame::Integer(0)))
+                if [&b"unsubscribe"[..], b"punsubscribe", b"sunsubscribe"]
+                    .iter()
+                    .any(|k| kind.eq_ignore_ascii_case(k))
+        )
+    }
+}
+
+/// A request decoded from a client, along with what its replies need for encoding to the client
+/// (as a `Shape` does for RESP3)
+pub enum ClientRequest<T, F = BytesFrame> {
+    /// A command to forward through the service
+    Command(F, T),
+    /// A request the codec answers itself, with this reply
+    Answer(F, T),
+}
+
+/// Framing for client connections: requests are decoded along with how their replies are to be
+/// encoded, and replies encoded accordingly
+pub trait ClientCodec<F>:
+    Decoder<Item = ClientRequest<Self::Encoding, F>, Error: std::fmt::Display>
+    + Encoder<(F, Self::Encoding), Error = <Self as Decoder>::Error>
+{
+    /// What a reply needs for encoding; the default is used for `decode_error_reply`
+    type Encoding: Clone + Default + Send + 'static;
+
+    /// The reply telling a client why its connection is closing after what it sent couldn't be
+    /// decoded
+    fn decode_error_reply(error: &<Self as Decoder>::Error) -> F;
+
+    /// The reply to a command which couldn't be sent through the connection's service
+    fn dispatch_error_reply() -> F;
+
+    /// The reply in place of one dropped because the client wasn't reading its replies
+    fn dropped_reply() -> F;
+
+    /// Roughly how many bytes `reply` takes to send, for output buffer limits
+    fn reply_len(reply: &F) -> usize;
+}
@@ -1,74 +1,1438 @@
-use std::pin::Pin;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use futures::stream::Stream;
+use futures::stream::{BoxStream, Stream};
+use futures::{Future, FutureExt as _, Sink, TryFutureExt as _};
 use futures_util::{SinkExt, StreamExt};
+use redis_protocol::error::{RedisProtocolError, RedisProtocolErrorKind};
 use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio_util::codec::Framed;
-use tower::Layer;
-use tower::Service;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+use tokio::net::TcpSocket;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{Notify, Semaphore, mpsc};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Instant, sleep_until};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::AbortOnDropHandle;
+use tower::{Service, ServiceExt as _};
 use uuid::Uuid;
 
-static MAX_OUTSTANDING_RESPONSE_STREAMS: usize = 100;
+use crate::buffer::BUFFERS;
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::frame;
+use crate::hooks::{ConnectionHooks, Hooked};
+use crate::listener::{ClientAddr, ClientStream, IpNetwork, Listener, SourceRules};
+use crate::memcached;
+use crate::protocol::{ClientCodec, ClientRequest};
+use crate::proxy_protocol;
+use crate::resp3::{self, ClientProtocol, Shape};
+use crate::service::{Handshake, QueueCapacities, SocketOptions};
+use crate::stats::{QUEUES, Queued, Stats, Traffic};
+use crate::streaming::BulkChunk;
 
-// TODO(akesling): Add connection timeout, etc.
-pub async fn handle_connection(
-    client_socket: TcpStream,
+/// Pending connections allowed per listener bound by `bind_reuseport`
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+static LISTEN_BACKLOG: u32 = 1024;
+/// Reply frames written to a client before they're flushed, even if more are ready
+static MAX_UNFLUSHED_FRAMES: usize = 128;
+/// How long a connection has to send its PROXY protocol header
+static PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Connections' lifetimes are cut short by up to this fraction at random, so connections opened
+/// together (as when clients reconnect after a restart) aren't all closed together
+static MAX_LIFETIME_JITTER: f64 = 0.1;
+/// How often a connection past its lifetime checks whether it's quiet enough to close
+static RETIRE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What `serve_with` does with a new client connection when the connection limit is reached
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Stop accepting until a connection closes, leaving new clients in the listen backlog
+    #[default]
+    Hold,
+    /// Accept the connection, answer with an error, and close it
+    Reject,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hold" => Ok(OverflowPolicy::Hold),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => bail!(Config, "Unrecognized overflow policy '{s}'"),
+        }
+    }
+}
+
+/// The protocol clients speak to `serve_with`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frontend {
+    /// RESP2, RESP3, or inline commands, as Redis accepts
+    #[default]
+    Resp,
+    /// The memcached text protocol, translated to and from RESP (see `memcached`)
+    Memcached,
+}
+
+impl std::str::FromStr for Frontend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "resp" => Ok(Frontend::Resp),
+            "memcached" => Ok(Frontend::Memcached),
+            _ => bail!(Config, "Unrecognized frontend '{s}'"),
+        }
+    }
+}
+
+/// What's done with a client whose replies have piled up past its output buffer limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowClientPolicy {
+    /// Close the connection, as Redis does
+    #[default]
+    Disconnect,
+    /// Answer with an error in place of each reply which doesn't fit, and stop taking replies
+    /// until the client has read enough of those waiting
+    Drop,
+}
+
+impl std::str::FromStr for SlowClientPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "drop" => Ok(SlowClientPolicy::Drop),
+            _ => bail!(Config, "Unrecognized slow client policy '{s}'"),
+        }
+    }
+}
+
+/// How many bytes of replies may wait for a client which isn't reading them, like Redis's
+/// `client-output-buffer-limit`. Without one, replies wait in their streams, and a client which
+/// stops reading holds up its target connections for as long as it stays connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputBufferLimit {
+    /// Act as soon as more than this many bytes are waiting
+    pub hard_bytes: Option<usize>,
+    /// Act once more than this many bytes have been waiting for `soft_secs`
+    pub soft_bytes: Option<usize>,
+    pub soft_secs: u64,
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for OutputBufferLimit {
+    fn default() -> Self {
+        Self {
+            hard_bytes: None,
+            soft_bytes: None,
+            soft_secs: 60,
+            policy: SlowClientPolicy::default(),
+        }
+    }
+}
+
+impl OutputBufferLimit {
+    /// Fails unless there's a limit, or if the soft limit is above the hard
+    pub fn validate(&self) -> Result<()> {
+        match (self.hard_bytes, self.soft_bytes) {
+            (None, None) => bail!(Config, "An output buffer limit needs hard or soft bytes"),
+            (Some(hard), Some(soft)) if soft > hard => {
+                bail!(
+                    Config,
+                    "An output buffer's soft limit can't be above its hard limit"
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Why `bytes` waiting is too many, noting when they first went over the soft limit in
+    /// `over_soft_since`
+    fn exceeded(&self, bytes: usize, over_soft_since: &mut Option<Instant>) -> Option<String> {
+        if let Some(hard) = self.hard_bytes
+            && bytes > hard
+        {
+            return Some(format!(
+                "{bytes} bytes of replies waiting, over the limit of {hard}"
+            ));
+        }
+        let soft = self.soft_bytes?;
+        if bytes <= soft {
+            *over_soft_since = None;
+            return None;
+        }
+        let since = *over_soft_since.get_or_insert_with(Instant::now);
+        let soft_duration = Duration::from_secs(self.soft_secs);
+        (since.elapsed() >= soft_duration).then(|| {
+            format!("over {soft} bytes of replies waiting for {soft_duration:?}, the soft limit")
+        })
+    }
+
+    /// The bytes waiting below which a client which had replies dropped gets more
+    fn resume_below(&self) -> usize {
+        self.soft_bytes.or(self.hard_bytes).unwrap_or(usize::MAX)
+    }
+}
+
+/// What each client connection is allowed, as set through `ServeOptions`
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+    /// Close the connection once it has sent nothing for this long with no replies outstanding
+    pub idle_timeout: Option<Duration>,
+    /// Refuse to buffer more than this much of a frame still being received
+    pub max_frame_bytes: Option<usize>,
+    /// Replies outstanding before the connection stops reading commands
+    pub max_response_streams: usize,
+    /// Close the connection at its first quiet point after it has been open about this long
+    pub max_lifetime: Option<Duration>,
+    /// Deal with the connection if this much of its replies wait for it to read them
+    pub output_buffer: Option<OutputBufferLimit>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            max_frame_bytes: None,
+            max_response_streams: QueueCapacities::default().response_streams,
+            max_lifetime: None,
+            output_buffer: None,
+        }
+    }
+}
+
+/// How `serve_with` runs the accept loop
+#[derive(Clone)]
+pub struct ServeOptions {
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    max_connections: Option<usize>,
+    overflow: OverflowPolicy,
+    limits: ConnectionLimits,
+    /// The networks of load balancers trusted to send PROXY protocol headers, if they're expected
+    proxy_protocol: Option<Arc<[IpNetwork]>>,
+    source_rules: SourceRules,
+    frontend: Frontend,
+    socket_options: SocketOptions,
+    stats: Arc<Stats>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            shutdown: CancellationToken::new(),
+            drain_timeout: Duration::MAX,
+            max_connections: None,
+            overflow: OverflowPolicy::default(),
+            limits: ConnectionLimits::default(),
+            proxy_protocol: None,
+            source_rules: SourceRules::default(),
+            frontend: Frontend::default(),
+            socket_options: SocketOptions::default(),
+            stats: Stats::new(),
+            hooks: None,
+        }
+    }
+}
+
+impl ServeOptions {
+    /// Stop serving once `shutdown` is cancelled: no new connections are accepted, every
+    /// connection stops reading commands and is closed once the replies to those already read
+    /// have been sent, and any connections still open after `drain_timeout` are dropped.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken, drain_timeout: Duration) -> Self {
+        self.shutdown = shutdown;
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// The token which stops serving when cancelled
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Serve at most `max_connections` clients at once, handling more according to `overflow`
+    pub fn with_max_connections(
+        mut self,
+        max_connections: usize,
+        overflow: OverflowPolicy,
+    ) -> Self {
+        self.max_connections = Some(max_connections);
+        self.overflow = overflow;
+        self
+    }
+
+    /// Close client connections which have sent nothing for `idle_timeout` while no replies are
+    /// outstanding (so blocked and subscribed clients are left alone, as with Redis's `timeout`)
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Close client connections once they've been open for `max_lifetime`, less up to a tenth at
+    /// random, as soon as no replies are outstanding, so clients reconnect (and their target
+    /// connections, which close with them, are made afresh). Subscribed clients, whose replies
+    /// never end, are left alone.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.limits.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Buffer replies for client connections as fast as targets send them, up to `limit`, rather
+    /// than leaving a client which has stopped reading to hold up its target connections
+    pub fn with_output_buffer_limit(mut self, limit: OutputBufferLimit) -> Self {
+        self.limits.output_buffer = Some(limit);
+        self
+    }
+
+    /// Set `socket_options` on every TCP client connection as it's accepted
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Self {
+        self.socket_options = socket_options;
+        self
+    }
+
+    /// Stop reading from a client once it has sent `max_frame_bytes` without completing a frame,
+    /// answering with a protocol error and closing the connection, as Redis does for oversized
+    /// requests
+    pub fn with_max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.limits.max_frame_bytes = Some(max_frame_bytes);
+        self
+    }
+
+    /// Let each client connection have `capacities.response_streams` replies outstanding before
+    /// it stops reading commands (the other capacities are for backends)
+    pub fn with_queue_capacities(mut self, capacities: QueueCapacities) -> Self {
+        self.limits.max_response_streams = capacities.response_streams;
+        self
+    }
+
+    /// Expect every connection to start with a PROXY protocol (v1 or v2) header, as sent by a
+    /// load balancer in front of the proxy, and treat the client address it advertises as the
+    /// connection's. Since anyone can send a header, only connections from load balancers in the
+    /// `trusted` networks are accepted (as are those to Unix domain sockets and named pipes, which
+    /// are local); others are closed, as are connections without a valid header.
+    pub fn with_proxy_protocol(mut self, trusted: impl IntoIterator<Item = IpNetwork>) -> Self {
+        self.proxy_protocol = Some(trusted.into_iter().collect());
+        self
+    }
+
+    /// Close connections from clients `source_rules` don't permit as soon as they're accepted
+    /// (judged by the PROXY protocol's client address, if one is expected)
+    pub fn with_source_rules(mut self, source_rules: SourceRules) -> Self {
+        self.source_rules = source_rules;
+        self
+    }
+
+    /// Speak `frontend` to clients rather than RESP
+    pub fn with_frontend(mut self, frontend: Frontend) -> Self {
+        self.frontend = frontend;
+        self
+    }
+
+    /// Count connections in `stats`
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Where connections are counted, and can be closed with `ConnectionCounts::close`
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Run `hooks` as each connection is accepted, served, and closed
+    pub fn with_hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+}
+
+/// Accept client connections from `listener` forever, building a service for each one with
+/// `make_service` and proxying the connection's traffic through it.
+pub async fn serve<M, F, S>(listener: impl Into<Listener>, make_service: M) -> Result<()>
+where
+    M: FnMut(Uuid, ClientAddr) -> F,
+    F: Future<Output = Result<S>> + Send + 'static,
+    S: Service<BytesFrame> + Send + 'static,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error> + Send,
+    S::Future: Send,
+{
+    serve_with(listener, make_service, ServeOptions::default()).await
+}
+
+/// Like `serve`, with connection limits and shutdown configured by `options`
+pub async fn serve_with<M, F, S>(
+    listener: impl Into<Listener>,
+    make_service: M,
+    options: ServeOptions,
+) -> Result<()>
+where
+    M: FnMut(Uuid, ClientAddr) -> F,
+    F: Future<Output = Result<S>> + Send + 'static,
+    S: Service<BytesFrame> + Send + 'static,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error> + Send,
+    S::Future: Send,
+{
+    serve_listeners(vec![listener.into()], make_service, options).await
+}
+
+/// Like `serve_with`, accepting from each of `listeners` in its own task, whether they're bound
+/// to different addresses or by `bind_reuseport` (so the kernel spreads new connections across
+/// them), and serving every connection alike
+pub async fn serve_listeners<M, F, S>(
+    listeners: Vec<Listener>,
+    mut make_service: M,
+    options: ServeOptions,
+) -> Result<()>
+where
+    M: FnMut(Uuid, ClientAddr) -> F,
+    F: Future<Output = Result<S>> + Send + 'static,
+    S: Service<BytesFrame> + Send + 'static,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error> + Send,
+    S::Future: Send,
+{
+    let limits = options.limits;
+    let frontend = options.frontend;
+    let hooks = options.hooks.clone();
+    accept_loop(
+        listeners,
+        options,
+        move |client_socket, connection_id, client_addr, shutdown, traffic| {
+            let service = make_service(connection_id, client_addr);
+            let hooks = hooks.clone();
+            async move {
+                let service = service.await?;
+                if let Some(hooks) = &hooks {
+                    hooks.on_backend_connect(connection_id);
+                }
+                let service = Hooked::new(service, hooks, connection_id);
+                match frontend {
+                    Frontend::Resp => {
+                        handle_connection(
+                            client_socket,
+                            service,
+                            connection_id,
+                            shutdown,
+                            limits,
+                            traffic,
+                        )
+                        .await
+                    }
+                    Frontend::Memcached => {
+                        memcached::handle_connection(
+                            client_socket,
+                            service,
+                            connection_id,
+                            shutdown,
+                            limits,
+                            traffic,
+                        )
+                        .await
+                    }
+                }
+            }
+        },
+    )
+    .await
+}
+
+/// Relay client connections accepted from `listeners` to `target_addr` byte for byte, after
+/// performing `handshake` on each target connection.
+///
+/// This is the fast path for deployments which need none of the middleware: frames are never
+/// decoded, so nothing is logged, limited, or measured per command, and a client can't be closed
+/// between commands. On shutdown, connections are relayed until they close or `options`' drain
+/// timeout elapses, and idle timeouts, lifetimes, and request size limits don't apply.
+pub async fn relay_listeners(
+    listeners: Vec<Listener>,
     target_addr: String,
-    connection_id: Uuid,
-) -> anyhow::Result<()> {
-    let target_socket = TcpStream::connect(&target_addr).await?;
+    handshake: Handshake,
+    options: ServeOptions,
+) -> Result<()> {
+    let handshake = Arc::new(handshake);
+    accept_loop(
+        listeners,
+        options,
+        move |client_socket, connection_id, _client_addr, _shutdown, traffic| {
+            relay_connection(
+                client_socket,
+                target_addr.clone(),
+                handshake.clone(),
+                connection_id,
+                traffic,
+            )
+        },
+    )
+    .await
+}
+
+/// Accept client connections until shutdown, handling each one with `handle` in its own task,
+/// then drain them. Each connection's traffic is summarized in the log once it closes.
+async fn accept_loop<H, F>(
+    listeners: Vec<Listener>,
+    options: ServeOptions,
+    mut handle: H,
+) -> Result<()>
+where
+    H: FnMut(ClientStream, Uuid, ClientAddr, CancellationToken, Arc<Traffic>) -> F,
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    if listeners.is_empty() {
+        bail!(Config, "No listeners to serve");
+    }
+    let ServeOptions {
+        shutdown,
+        drain_timeout,
+        max_connections,
+        overflow,
+        proxy_protocol,
+        source_rules,
+        socket_options,
+        stats,
+        hooks,
+        ..
+    } = options;
+    let mut acceptor = Acceptor::new(listeners, proxy_protocol);
+    let connection_slots = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+    let mut connections = JoinSet::new();
+    loop {
+        let held_slot = match (&connection_slots, overflow) {
+            (Some(slots), OverflowPolicy::Hold) => tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                slot = slots.clone().acquire_owned() => Some(slot.map_err(Error::other)?),
+            },
+            _ => None,
+        };
+        let (client_socket, client_addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = acceptor.accept() => accepted?,
+        };
+        if !source_rules.permits(&client_addr) {
+            log::warn!("Refusing connection from {client_addr}: source address not allowed");
+            stats.connections.record_refused();
+            continue;
+        }
+        if let ClientStream::Tcp(socket) = &client_socket
+            && let Err(e) = socket_options.apply(socket)
+        {
+            log::warn!("Failed to set socket options for {client_addr}: {e}");
+        }
+
+        let slot = match (held_slot, &connection_slots) {
+            (Some(slot), _) => Some(slot),
+            (None, Some(slots)) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    log::warn!("Rejecting connection from {client_addr}: too many connections");
+                    stats.connections.record_rejected();
+                    tokio::spawn(reject_connection(client_socket));
+                    continue;
+                }
+            },
+            (None, None) => None,
+        };
+
+        let connection_id = Uuid::new_v4();
+        if let Some(hooks) = &hooks
+            && !hooks.on_accept(connection_id, &client_addr)
+        {
+            log::info!("Refusing connection from {client_addr}: refused by hooks");
+            continue;
+        }
+        log::info!("New connection from {client_addr} (ID#{connection_id})");
+
+        // Cancelled on shutdown, or to close just this connection
+        let closer = shutdown.child_token();
+        let open = stats
+            .connections
+            .open(connection_id, client_addr.clone(), closer.clone());
+        let handled = handle(
+            client_socket,
+            connection_id,
+            client_addr,
+            closer,
+            open.traffic(),
+        );
+        let hooks = hooks.clone();
+        connections.spawn(async move {
+            tokio::select! {
+                handled = handled => if let Err(e) = handled {
+                    log::error!("Connection error: {}", e);
+                },
+                _ = open.killed() => log::info!("Connection {connection_id} killed"),
+            }
+            log::info!("Connection {connection_id} closed: {}", open.traffic());
+            if let Some(hooks) = hooks {
+                hooks.on_close(connection_id, &open.traffic());
+            }
+            drop((slot, open));
+        });
+    }
+
+    drop(acceptor);
     log::info!(
-        "connection {connection_id}: connected with target at: {}",
-        target_addr
+        "Shutting down, draining {} connection(s) for up to {:?}",
+        connections.len(),
+        drain_timeout
     );
+    let drained = tokio::time::timeout(drain_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log::warn!(
+            "Drain timeout elapsed, dropping {} connection(s)",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    Ok(())
+}
 
-    let client_framed = Framed::new(client_socket, Resp2::default());
-    let target_framed = Framed::new(target_socket, Resp2::default());
+/// Where `serve_listeners` takes new client connections from
+enum Acceptor {
+    Direct(Listener),
+    /// Connections accepted by a background task per listener, along with the client address
+    /// (advertised by their PROXY protocol header, if one is expected)
+    Tasks {
+        accepted: mpsc::Receiver<std::io::Result<(ClientStream, ClientAddr)>>,
+        tasks: Vec<JoinHandle<()>>,
+    },
+}
 
-    let (client_sink, mut client_stream) = client_framed.split();
-    let connection_id_string = connection_id.to_string();
-    let logger = crate::middleware::ProxyLoggerLayer::new(&connection_id_string);
-    let mut target_service = logger.layer(crate::service::Resp2Backend::new(target_framed));
-
-    let (response_forwarder_tx, mut response_forwarder_rx) = mpsc::channel::<
-        Box<dyn Stream<Item = BytesFrame> + Send>,
-    >(MAX_OUTSTANDING_RESPONSE_STREAMS);
-    let forward_task_join_handle = tokio::spawn(async move {
-        let mut client_sink = client_sink;
-        // Flatten streams of responses --
-        // they interleave as req > [ resp > resp > resp ] > req > ...  This takes the stream of
-        // streams and flattens it.
-        while let Some(response_stream) = response_forwarder_rx.recv().await {
-            let mut pinned = Pin::from(response_stream);
-            while let Some(response_frame) = pinned.as_mut().next().await {
-                if client_sink.send(response_frame).await.is_err() {
-                    log::error!("Failed to send response to client on connection {connection_id}");
+impl Acceptor {
+    fn new(mut listeners: Vec<Listener>, proxy_protocol: Option<Arc<[IpNetwork]>>) -> Self {
+        if listeners.len() == 1 && proxy_protocol.is_none() {
+            return Self::Direct(listeners.remove(0));
+        }
+        let (sender, accepted) = mpsc::channel(listeners.len());
+        let tasks = listeners
+            .into_iter()
+            .map(|listener| {
+                tokio::spawn(accept_into(
+                    listener,
+                    proxy_protocol.clone(),
+                    sender.clone(),
+                ))
+            })
+            .collect();
+        Self::Tasks { accepted, tasks }
+    }
+
+    async fn accept(&mut self) -> std::io::Result<(ClientStream, ClientAddr)> {
+        match self {
+            Self::Direct(listener) => listener.accept().await,
+            Self::Tasks { accepted, .. } => match accepted.recv().await {
+                Some(accepted) => accepted,
+                None => Err(std::io::Error::other("every acceptor stopped")),
+            },
+        }
+    }
+}
+
+impl Drop for Acceptor {
+    fn drop(&mut self) {
+        if let Self::Tasks { tasks, .. } = self {
+            tasks.iter().for_each(JoinHandle::abort);
+        }
+    }
+}
+
+/// Accept connections from `listener` and pass them to `accepted`. With `proxy_protocol`, the
+/// networks of trusted load balancers, each one's header is read concurrently so a slow or silent
+/// peer can't hold up the others.
+async fn accept_into(
+    listener: Listener,
+    proxy_protocol: Option<Arc<[IpNetwork]>>,
+    accepted: mpsc::Sender<std::io::Result<(ClientStream, ClientAddr)>>,
+) {
+    let mut headers = JoinSet::new();
+    loop {
+        let (mut socket, peer_addr) = tokio::select! {
+            Some(_) = headers.join_next(), if !headers.is_empty() => continue,
+            result = listener.accept() => match result {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let _ = accepted.send(Err(e)).await;
+                    return;
+                }
+            },
+        };
+        let Some(trusted) = &proxy_protocol else {
+            if accepted.send(Ok((socket, peer_addr))).await.is_err() {
+                return;
+            }
+            continue;
+        };
+        if let ClientAddr::Tcp(addr) = &peer_addr
+            && !trusted.iter().any(|network| network.contains(addr.ip()))
+        {
+            log::warn!("Closing connection from {peer_addr}: not a trusted PROXY protocol sender");
+            continue;
+        }
+        let accepted = accepted.clone();
+        headers.spawn(async move {
+            let header = tokio::time::timeout(
+                PROXY_HEADER_TIMEOUT,
+                proxy_protocol::read_header(&mut socket),
+            )
+            .await;
+            let client_addr = match header {
+                Ok(Ok(client_addr)) => client_addr.map_or_else(|| peer_addr.clone(), Into::into),
+                Ok(Err(e)) => {
+                    log::warn!("Closing connection from {peer_addr}: {e:#}");
                     return;
                 }
+                Err(_) => {
+                    log::warn!("Closing connection from {peer_addr}: no PROXY protocol header");
+                    return;
+                }
+            };
+            log::debug!("Connection from {peer_addr} is proxied for {client_addr}");
+            let _ = accepted.send(Ok((socket, client_addr))).await;
+        });
+    }
+}
+
+/// Bind `count` listeners to `addr` with `SO_REUSEPORT`, for `serve_listeners` to accept from in
+/// parallel
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+pub fn bind_reuseport(addr: SocketAddr, count: usize) -> std::io::Result<Vec<TcpListener>> {
+    (0..count.max(1))
+        .map(|_| {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            socket.set_reuseport(true)?;
+            socket.bind(addr)?;
+            socket.listen(LISTEN_BACKLOG)
+        })
+        .collect()
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+pub fn bind_reuseport(_addr: SocketAddr, _count: usize) -> std::io::Result<Vec<TcpListener>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT isn't supported on this platform",
+    ))
+}
+
+/// Write the frames of each response stream from `streams` to `sink` in turn, until `streams`
+/// closes.
+///
+/// Streams of responses interleave as req > [ resp > resp > resp ] > req > ...; this flattens
+/// them. Frames are only flushed once no more are immediately available (or after
+/// `MAX_UNFLUSHED_FRAMES`), so the replies to a pipeline go out in as few writes as possible.
+async fn forward_responses<K, T>(
+    sink: &mut K,
+    streams: &mut mpsc::Receiver<Queued<BoxStream<'static, T>>>,
+    forwarded: &AtomicUsize,
+) -> Result<(), K::Error>
+where
+    K: Sink<T> + Unpin,
+{
+    let mut unflushed = 0;
+    loop {
+        let mut response_stream = match streams.try_recv() {
+            Ok(response_stream) => response_stream.into_inner(),
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {
+                if unflushed > 0 {
+                    sink.flush().await?;
+                    unflushed = 0;
+                }
+                match streams.recv().await {
+                    Some(response_stream) => response_stream.into_inner(),
+                    None => break,
+                }
+            }
+        };
+        loop {
+            let response_frame = match response_stream.next().now_or_never() {
+                Some(Some(response_frame)) => response_frame,
+                Some(None) => break,
+                None => {
+                    if unflushed > 0 {
+                        sink.flush().await?;
+                        unflushed = 0;
+                    }
+                    match response_stream.next().await {
+                        Some(response_frame) => response_frame,
+                        None => break,
+                    }
+                }
+            };
+            sink.feed(response_frame).await?;
+            unflushed += 1;
+            if unflushed >= MAX_UNFLUSHED_FRAMES {
+                sink.flush().await?;
+                unflushed = 0;
+            }
+        }
+        forwarded.fetch_sub(1, Ordering::Relaxed);
+    }
+    sink.flush().await
+}
+
+/// Why `forward_limited` stopped early
+enum ForwardError {
+    /// Replies couldn't be written to the client
+    Sink,
+    /// The client's output buffer limit was exceeded, with how
+    Overflow(String),
+}
+
+/// Like `forward_responses`, but taking replies from their streams as they arrive rather than
+/// as the client reads them, holding them until `sink` takes them. Those which would be more
+/// than `limit` allows are dealt with by its policy: the client is disconnected, or each run of
+/// replies which don't fit is answered with one `dropped` reply (encoded like the first), and
+/// no further streams are taken until enough of those waiting have been sent. Replies are sized
+/// by `len`.
+async fn forward_limited<K, T>(
+    sink: &mut K,
+    streams: &mut mpsc::Receiver<Queued<BoxStream<'static, T>>>,
+    forwarded: &AtomicUsize,
+    limit: OutputBufferLimit,
+    len: impl Fn(&T) -> usize,
+    dropped: impl Fn(&T) -> T,
+    traffic: &Traffic,
+) -> Result<(), ForwardError>
+where
+    K: Sink<T> + Unpin,
+{
+    let waiting = AtomicUsize::new(0);
+    let sent = Notify::new();
+    // Each reply, and `None` once a stream has ended
+    let (output_tx, mut output_rx) = mpsc::unbounded_channel::<Option<(T, usize)>>();
+
+    let (waiting, sent) = (&waiting, &sent);
+    let buffer = async move {
+        let mut over_soft_since = None;
+        while let Some(response_stream) = streams.recv().await {
+            let mut response_stream = response_stream.into_inner();
+            if limit.policy == SlowClientPolicy::Drop {
+                while waiting.load(Ordering::Relaxed) >= limit.resume_below() {
+                    sent.notified().await;
+                }
             }
+            let mut dropping = false;
+            while let Some(response_frame) = response_stream.next().await {
+                let size = len(&response_frame);
+                let total = waiting.load(Ordering::Relaxed) + size;
+                let Some(exceeded) = limit.exceeded(total, &mut over_soft_since) else {
+                    dropping = false;
+                    waiting.fetch_add(size, Ordering::Relaxed);
+                    let _ = output_tx.send(Some((response_frame, size)));
+                    continue;
+                };
+                if limit.policy == SlowClientPolicy::Disconnect {
+                    return Err(exceeded);
+                }
+                traffic.record_dropped_reply();
+                if !dropping {
+                    let error = dropped(&response_frame);
+                    let size = len(&error);
+                    waiting.fetch_add(size, Ordering::Relaxed);
+                    let _ = output_tx.send(Some((error, size)));
+                    dropping = true;
+                }
+            }
+            let _ = output_tx.send(None);
         }
+        Ok(())
+    };
+    let write = async {
+        let mut unflushed = 0;
+        loop {
+            let output = match output_rx.try_recv() {
+                Ok(output) => output,
+                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Empty) => {
+                    if unflushed > 0 {
+                        sink.flush().await?;
+                        unflushed = 0;
+                    }
+                    match output_rx.recv().await {
+                        Some(output) => output,
+                        None => break,
+                    }
+                }
+            };
+            let Some((response_frame, size)) = output else {
+                forwarded.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            };
+            sink.feed(response_frame).await?;
+            waiting.fetch_sub(size, Ordering::Relaxed);
+            sent.notify_one();
+            unflushed += 1;
+            if unflushed >= MAX_UNFLUSHED_FRAMES {
+                sink.flush().await?;
+                unflushed = 0;
+            }
+        }
+        sink.flush().await
+    }
+    .map_err(|_| ForwardError::Sink);
+    tokio::pin!(write);
+    tokio::select! {
+        buffered = buffer => match buffered {
+            // What's waiting is still sent
+            Ok(()) => write.await,
+            Err(exceeded) => Err(ForwardError::Overflow(exceeded)),
+        },
+        written = &mut write => written,
+    }
+}
+
+/// Connect to the target for a client and copy bytes between them until either side closes
+async fn relay_connection(
+    mut client_socket: ClientStream,
+    target_addr: String,
+    handshake: Arc<Handshake>,
+    connection_id: Uuid,
+    traffic: Arc<Traffic>,
+) -> Result<()> {
+    let target_socket = handshake.connect(&target_addr).await?;
+    let mut target_framed = Framed::new(target_socket, Resp2::default());
+    handshake.perform(&mut target_framed).await?;
+    // Each handshake command's reply has been read, so nothing is left buffered
+    let mut target_socket = target_framed.into_inner();
+    log::info!("connection {connection_id}: relaying to target at: {target_addr}");
+
+    let (sent, received) =
+        tokio::io::copy_bidirectional(&mut client_socket, &mut target_socket).await?;
+    traffic.record_relayed(sent, received);
+    Ok(())
+}
+
+/// Tell a client it can't be served, as Redis does when `maxclients` is reached
+async fn reject_connection(client_socket: ClientStream) {
+    let mut client_framed = Framed::new(client_socket, Resp2::default());
+    let _ = client_framed
+        .send(command::error("ERR max number of clients reached"))
+        .await;
+}
+
+/// A token cancelled when the process receives SIGINT or (on Unix) SIGTERM
+pub fn shutdown_on_signal() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+    let token = shutdown.clone();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                }
+                Err(e) => {
+                    log::error!("Failed to listen for SIGTERM: {e}");
+                    std::future::pending::<()>().await
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+            _ = terminate => log::info!("Received SIGTERM"),
+        }
+        token.cancel();
     });
+    shutdown
+}
 
-    while let Some(frame_result) = client_stream.next().await {
-        match frame_result {
-            Ok(frame) => {
-                match target_service.call(frame).await {
-                    Ok(response_stream) => {
-                        // Response streams are flattened by the response forwarder
-                        if response_forwarder_tx.send(response_stream).await.is_err() {
-                            log::error!("Failed to send response stream to handler");
-                            break;
+/// Notified each time the process receives SIGHUP, asking for the configuration to be reloaded.
+/// Never notified on other platforms, though anything else holding it may notify it too.
+pub fn reload_on_signal() -> Arc<Notify> {
+    let reload = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        let notify = reload.clone();
+        tokio::spawn(async move {
+            let mut hangup =
+                match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                    Ok(hangup) => hangup,
+                    Err(e) => {
+                        log::error!("Failed to listen for SIGHUP: {e}");
+                        return;
+                    }
+                };
+            while hangup.recv().await.is_some() {
+                log::info!("Received SIGHUP");
+                notify.notify_one();
+            }
+        });
+    }
+    reload
+}
+
+/// Longest inline command a client may send, as Redis limits them (`PROTO_INLINE_MAX_SIZE`)
+static MAX_INLINE_BYTES: usize = 64 * 1024;
+
+/// The leading bytes of RESP2 frames; a request starting with anything else is an inline command
+static RESP2_TYPE_BYTES: &[u8] = b"*$+-:";
+
+/// A decode error which leaves the client's stream unreadable, so its connection is closed after
+/// being told why
+fn protocol_error(message: impl Into<String>) -> RedisProtocolError {
+    RedisProtocolError::new(RedisProtocolErrorKind::DecodeError, message.into())
+}
+
+/// The leading byte of `frame` when encoded
+fn type_byte(frame: &BytesFrame) -> char {
+    match frame {
+        BytesFrame::SimpleString(_) => '+',
+        BytesFrame::Error(_) => '-',
+        BytesFrame::Integer(_) => ':',
+        BytesFrame::BulkString(_) | BytesFrame::Null => '$',
+        BytesFrame::Array(_) => '*',
+    }
+}
+
+/// Whether a complete frame from a client is a command, which Redis requires to be an array of
+/// bulk strings. Empty and null arrays aren't, but Redis ignores them rather than answering, so
+/// they're `Ok(false)`. Anything else is answered with the error, but since the frame was read
+/// in full the connection can carry on, where forwarding it would break the target's.
+fn check_command(frame: &BytesFrame) -> Result<bool, String> {
+    let args = match frame {
+        BytesFrame::Array(args) if args.is_empty() => return Ok(false),
+        BytesFrame::Array(args) => args,
+        BytesFrame::Null => return Ok(false),
+        frame => return Err(format!("expected '*', got '{}'", type_byte(frame))),
+    };
+    for arg in args {
+        match arg {
+            BytesFrame::BulkString(_) => {}
+            BytesFrame::Null => return Err("invalid bulk length".to_string()),
+            arg => return Err(format!("expected '$', got '{}'", type_byte(arg))),
+        }
+    }
+    Ok(true)
+}
+
+/// Take an inline command (`PING\r\n`, as redis-cli and health checkers send) from `src` as the
+/// array frame it stands for, splitting its arguments as Redis does: on whitespace, with double
+/// quotes allowing escapes (`\n`, `\x41`, ...) and single quotes allowing only `\'`
+fn decode_inline(src: &mut BytesMut) -> Result<Option<BytesFrame>, RedisProtocolError> {
+    loop {
+        let Some(end) = src.iter().position(|&b| b == b'\n') else {
+            if src.len() > MAX_INLINE_BYTES {
+                return Err(protocol_error("too big inline request"));
+            }
+            return Ok(None);
+        };
+        let line = src.split_to(end + 1);
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let args = split_inline(line)?;
+        // Redis ignores blank lines, so a stray newline between commands is harmless
+        if !args.is_empty() {
+            return Ok(Some(BytesFrame::Array(
+                args.into_iter()
+                    .map(|arg| BytesFrame::BulkString(arg.into()))
+                    .collect(),
+            )));
+        }
+        if src.is_empty() || RESP2_TYPE_BYTES.contains(&src[0]) {
+            return Ok(None);
+        }
+    }
+}
+
+/// The arguments of an inline command, split as Redis's `sdssplitargs` splits them
+fn split_inline(line: &[u8]) -> Result<Vec<Vec<u8>>, RedisProtocolError> {
+    let unbalanced = || protocol_error("unbalanced quotes in request");
+    let mut args = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(first) = bytes.next() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            b'"' => loop {
+                match bytes.next().ok_or_else(unbalanced)? {
+                    b'"' => break,
+                    b'\\' => {
+                        let escaped = bytes.next().ok_or_else(unbalanced)?;
+                        let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+                        let mut ahead = bytes.clone();
+                        if escaped == b'x'
+                            && let Some(high) = ahead.next().and_then(hex)
+                            && let Some(low) = ahead.next().and_then(hex)
+                        {
+                            arg.push(high << 4 | low);
+                            bytes = ahead;
+                            continue;
                         }
+                        arg.push(match escaped {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    b => arg.push(b),
+                }
+            },
+            b'\'' => loop {
+                match bytes.next().ok_or_else(unbalanced)? {
+                    b'\'' => break,
+                    b'\\' if bytes.peek() == Some(&b'\'') => {
+                        bytes.next();
+                        arg.push(b'\'');
+                    }
+                    b => arg.push(b),
+                }
+            },
+            b => {
+                arg.push(b);
+                while let Some(b) = bytes.next_if(|b| !b.is_ascii_whitespace()) {
+                    arg.push(b);
+                }
+            }
+        }
+        // A closing quote must end its argument
+        if matches!(first, b'"' | b'\'') && bytes.peek().is_some_and(|b| !b.is_ascii_whitespace()) {
+            return Err(unbalanced());
+        }
+        args.push(arg);
+    }
+}
+
+/// RESP2 framing for client connections, refusing to buffer more than `max_frame_bytes` of a
+/// frame still being received and counting the frames passing through in `traffic`. Inline
+/// commands are accepted too, decoded into the array frames they stand for, and replies are
+/// encoded in RESP3 for clients which negotiate it. Frames which aren't commands are answered
+/// with a protocol error by the codec itself (see `check_command`).
+#[derive(Default)]
+struct RespCodec {
+    resp2: Resp2,
+    protocol: ClientProtocol,
+    max_frame_bytes: Option<usize>,
+    traffic: Arc<Traffic>,
+    /// Whether a bulk string streamed in chunks is partway written
+    streaming: bool,
+}
+
+impl Decoder for RespCodec {
+    type Item = ClientRequest<Shape>;
+    type Error = RedisProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let buffered = src.len();
+            let frame = match src.first() {
+                Some(b) if !RESP2_TYPE_BYTES.contains(b) => match decode_inline(src)? {
+                    // Only blank lines preceded the next RESP2 frame
+                    None if src.first().is_some_and(|b| RESP2_TYPE_BYTES.contains(b)) => {
+                        self.resp2.decode(src)?
                     }
+                    frame => frame,
+                },
+                _ => self.resp2.decode(src)?,
+            };
+            let Some(frame) = frame else {
+                if let Some(max) = self.max_frame_bytes
+                    && src.len() > max
+                {
+                    return Err(protocol_error(format!(
+                        "request exceeds the limit of {max} bytes"
+                    )));
+                }
+                return Ok(None);
+            };
+            match check_command(&frame) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(message) => {
+                    log::debug!("Answering malformed request with a protocol error: {message}");
+                    let error = command::error(format!("ERR Protocol error: {message}"));
+                    return Ok(Some(ClientRequest::Answer(error, Shape::Reply)));
+                }
+            }
+            self.traffic.record_command(buffered - src.len());
+            let (frame, shape) = resp3::negotiate(frame);
+            return Ok(Some(ClientRequest::Command(frame, shape)));
+        }
+    }
+}
+
+impl Encoder<(BytesFrame, Shape)> for RespCodec {
+    type Error = RedisProtocolError;
+
+    fn encode(&mut self, item: (BytesFrame, Shape), dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let buffered = dst.len();
+        let (frame, shape) = item;
+        let Some(chunk) = BulkChunk::of(&frame) else {
+            if self.streaming {
+                return Err(RedisProtocolError::new(
+                    RedisProtocolErrorKind::EncodeError,
+                    "Streamed bulk string reply was cut short",
+                ));
+            }
+            self.protocol.encode(&mut self.resp2, frame, shape, dst)?;
+            self.traffic.record_response(dst.len() - buffered);
+            return Ok(());
+        };
+        if chunk.starts() == self.streaming {
+            return Err(RedisProtocolError::new(
+                RedisProtocolErrorKind::EncodeError,
+                "Streamed bulk string reply chunk out of place",
+            ));
+        }
+        // Written as it comes, in whichever protocol, as a bulk string is the same in both
+        chunk.encode(dst);
+        self.streaming = !chunk.ends();
+        if self.streaming {
+            self.traffic
+                .record_relayed(0, (dst.len() - buffered) as u64);
+        } else {
+            self.traffic.record_response(dst.len() - buffered);
+        }
+        Ok(())
+    }
+}
+
+impl ClientCodec<BytesFrame> for RespCodec {
+    type Encoding = Shape;
+
+    fn decode_error_reply(error: &RedisProtocolError) -> BytesFrame {
+        command::error(format!("ERR Protocol error: {}", error.details()))
+    }
+
+    fn dispatch_error_reply() -> BytesFrame {
+        command::error("ERR proxy backend unavailable")
+    }
+
+    fn dropped_reply() -> BytesFrame {
+        command::error("ERR reply dropped, client output buffer limit reached")
+    }
+
+    fn reply_len(reply: &BytesFrame) -> usize {
+        frame::encoded_len(reply)
+    }
+}
+
+// TODO(akesling): Add connection timeout, etc.
+pub async fn handle_connection<S>(
+    client_socket: ClientStream,
+    target_service: S,
+    connection_id: Uuid,
+    shutdown: CancellationToken,
+    limits: ConnectionLimits,
+    traffic: Arc<Traffic>,
+) -> Result<()>
+where
+    S: Service<BytesFrame>,
+    S::Response: Stream<Item = BytesFrame> + Send + 'static,
+    S::Error: Into<Error>,
+{
+    let codec = RespCodec {
+        max_frame_bytes: limits.max_frame_bytes,
+        traffic: traffic.clone(),
+        ..RespCodec::default()
+    };
+    serve_connection(
+        client_socket,
+        codec,
+        target_service,
+        connection_id,
+        shutdown,
+        limits,
+        traffic,
+    )
+    .await
+}
+
+/// Proxy a client connection's requests, decoded by `codec`, through `target_service`, encoding
+/// the replies with `codec` as they arrive. Commands are sent to the service one at a time, and
+/// their replies written to the client in order, however many are outstanding. Replies dropped
+/// or disconnections for the connection's output buffer limit are counted in `traffic`.
+pub async fn serve_connection<S, C, F>(
+    client_socket: ClientStream,
+    codec: C,
+    mut target_service: S,
+    connection_id: Uuid,
+    shutdown: CancellationToken,
+    limits: ConnectionLimits,
+    traffic: Arc<Traffic>,
+) -> Result<()>
+where
+    S: Service<F>,
+    S::Response: Stream<Item = F> + Send + 'static,
+    S::Error: Into<Error>,
+    C: ClientCodec<F> + Send + 'static,
+    F: Send + 'static,
+{
+    let client_framed = BUFFERS.framed::<(F, C::Encoding), _, _>(client_socket, codec);
+    let (client_sink, mut client_stream) = client_framed.split();
+
+    let (response_forwarder_tx, mut response_forwarder_rx) =
+        mpsc::channel::<Queued<BoxStream<'static, (F, C::Encoding)>>>(limits.max_response_streams);
+    let queue = &QUEUES.response_streams;
+    // Response streams not yet fully forwarded; the connection isn't idle while there are any
+    let outstanding = Arc::new(AtomicUsize::new(0));
+    let forwarded = outstanding.clone();
+    // Cancelled when the client exceeds its output buffer limit and is to be disconnected
+    let overflowed = CancellationToken::new();
+    let overflow = overflowed.clone();
+    // Aborted if the connection is dropped (when killed), closing the client socket
+    let forward_task_join_handle = AbortOnDropHandle::new(tokio::spawn(async move {
+        let mut client_sink = client_sink;
+        let forwarding = match limits.output_buffer {
+            Some(limit) => {
+                forward_limited(
+                    &mut client_sink,
+                    &mut response_forwarder_rx,
+                    &forwarded,
+                    limit,
+                    |(frame, _)| C::reply_len(frame),
+                    |(_, encoding)| (C::dropped_reply(), encoding.clone()),
+                    &traffic,
+                )
+                .await
+            }
+            None => forward_responses(&mut client_sink, &mut response_forwarder_rx, &forwarded)
+                .await
+                .map_err(|_| ForwardError::Sink),
+        };
+        match forwarding {
+            Ok(()) => Some(client_sink),
+            Err(ForwardError::Sink) => {
+                log::error!("Failed to send response to client on connection {connection_id}");
+                None
+            }
+            Err(ForwardError::Overflow(exceeded)) => {
+                log::warn!("Connection {connection_id} closing as a slow consumer: {exceeded}");
+                traffic.record_output_limit_disconnection();
+                overflow.cancel();
+                None
+            }
+        }
+    }));
+
+    let idle_timeout = limits.idle_timeout;
+    let idle_deadline = |now: Instant| idle_timeout.map(|timeout| now + timeout);
+    let mut idle_at = idle_deadline(Instant::now());
+    let max_lifetime = limits.max_lifetime;
+    let mut retire_at = max_lifetime.map(|lifetime| {
+        Instant::now() + lifetime.mul_f64(1.0 - rand::random::<f64>() * MAX_LIFETIME_JITTER)
+    });
+    loop {
+        let frame_result = tokio::select! {
+            _ = shutdown.cancelled() => {
+                log::info!("Connection {connection_id} closing");
+                break;
+            }
+            _ = overflowed.cancelled() => break,
+            _ = sleep_until(idle_at.unwrap_or_else(Instant::now)), if idle_at.is_some() => {
+                if outstanding.load(Ordering::Relaxed) > 0 {
+                    idle_at = idle_deadline(Instant::now());
+                    continue;
+                }
+                log::info!("Connection {connection_id} closing after idling for {idle_timeout:?}");
+                break;
+            }
+            _ = sleep_until(retire_at.unwrap_or_else(Instant::now)), if retire_at.is_some() => {
+                if outstanding.load(Ordering::Relaxed) > 0 {
+                    retire_at = Some(Instant::now() + RETIRE_POLL_INTERVAL);
+                    continue;
+                }
+                log::info!(
+                    "Connection {connection_id} closing after reaching its lifetime of \
+                     {max_lifetime:?}"
+                );
+                break;
+            }
+            frame_result = client_stream.next() => match frame_result {
+                Some(frame_result) => frame_result,
+                None => break,
+            },
+        };
+        match frame_result {
+            Ok(ClientRequest::Answer(frame, encoding)) => {
+                idle_at = idle_deadline(Instant::now());
+                outstanding.fetch_add(1, Ordering::Relaxed);
+                let answer = futures::stream::once(async move { (frame, encoding) });
+                if queue
+                    .send(&response_forwarder_tx, answer.boxed())
+                    .await
+                    .is_err()
+                {
+                    log::error!("Failed to send response stream to handler");
+                    break;
+                }
+            }
+            Ok(ClientRequest::Command(frame, encoding)) => {
+                idle_at = idle_deadline(Instant::now());
+                // A service which fails to become ready can take no more commands, so the
+                // connection is closed once this one has been answered
+                let (dispatched, usable) = match target_service.ready().await {
+                    Ok(service) => (service.call(frame).await, true),
+                    Err(e) => (Err(e), false),
+                };
+                let response_stream = match dispatched {
+                    Ok(response_stream) => response_stream
+                        .map(move |frame| (frame, encoding.clone()))
+                        .boxed(),
                     Err(e) => {
-                        log::error!("Failed to send command to backend: {}", e);
+                        // Every command is answered, so the client's replies stay paired with
+                        // its commands
+                        log::error!("Failed to send command to backend: {}", e.into());
+                        let error = C::dispatch_error_reply();
+                        futures::stream::once(async move { (error, encoding) }).boxed()
                     }
+                };
+                // Response streams are flattened by the response forwarder
+                outstanding.fetch_add(1, Ordering::Relaxed);
+                if queue
+                    .send(&response_forwarder_tx, response_stream)
+                    .await
+                    .is_err()
+                {
+                    log::error!("Failed to send response stream to handler");
+                    break;
+                }
+                if !usable {
+                    break;
                 }
             }
             Err(e) => {
                 log::error!("Error reading from client: {}", e);
+                // The stream can't be resynchronized, but the client should know why it's closed
+                let error = C::decode_error_reply(&e);
+                outstanding.fetch_add(1, Ordering::Relaxed);
+                let error = futures::stream::once(async move { (error, C::Encoding::default()) });
+                let _ = queue.send(&response_forwarder_tx, error.boxed()).await;
                 break;
             }
         }
@@ -76,12 +1440,108 @@ pub async fn handle_connection(
 
     // Close channel and wait for task to complete
     drop(response_forwarder_tx);
-    if let Err(e) = forward_task_join_handle.await {
-        log::error!("Forward task failed: {}", e);
+    match forward_task_join_handle.await {
+        Ok(Some(client_sink)) => {
+            if let Ok(client_framed) = client_sink.reunite(client_stream) {
+                BUFFERS.recycle(client_framed);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Forward task failed: {}", e),
     }
-
-    log::info!("Connection closed");
     Ok(())
 }
 
-// TODO(akesling): Implement a "serve()" function which takes a "Listener" and a MakeService
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Result<Vec<String>, RedisProtocolError> {
+        let args = split_inline(line.as_bytes())?;
+        Ok(args
+            .into_iter()
+            .map(|arg| String::from_utf8(arg).unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn inline_arguments_are_split_on_whitespace() {
+        assert_eq!(
+            split("  SET\tkey  value ").unwrap(),
+            ["SET", "key", "value"]
+        );
+        assert!(split("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn double_quotes_allow_spaces_and_escapes() {
+        assert_eq!(
+            split(r#"SET key "hello world\n\t\"\\\x41\xZZ""#).unwrap(),
+            ["SET", "key", "hello world\n\t\"\\AxZZ"]
+        );
+        assert_eq!(split(r#"SET key """#).unwrap(), ["SET", "key", ""]);
+    }
+
+    #[test]
+    fn single_quotes_allow_only_escaped_quotes() {
+        assert_eq!(
+            split(r"SET key 'it\'s \n raw'").unwrap(),
+            ["SET", "key", r"it's \n raw"]
+        );
+    }
+
+    #[test]
+    fn unbalanced_quotes_are_refused() {
+        for line in [
+            r#"SET key "value"#,
+            "SET key 'value",
+            r#"SET key "value\"#,
+            r#"SET key "value"suffix"#,
+            "SET key 'value'suffix",
+        ] {
+            assert!(split(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn inline_commands_decode_to_arrays() {
+        let mut src = BytesMut::from("PING\r\nECHO hi\n");
+        let expected = [command::request(["PING"]), command::request(["ECHO", "hi"])];
+        for expected in expected {
+            assert_eq!(decode_inline(&mut src).unwrap(), Some(expected));
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn partial_inline_commands_wait_for_the_rest() {
+        let mut src = BytesMut::from("ECHO hel");
+        assert_eq!(decode_inline(&mut src).unwrap(), None);
+        assert_eq!(&src[..], b"ECHO hel");
+        src.extend_from_slice(b"lo\r\n");
+        assert_eq!(
+            decode_inline(&mut src).unwrap(),
+            Some(command::request(["ECHO", "hello"]))
+        );
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let mut src = BytesMut::from("\r\n \n\r\nPING\r\n");
+        assert_eq!(
+            decode_inline(&mut src).unwrap(),
+            Some(command::request(["PING"]))
+        );
+
+        // Up to a RESP2 frame, which is left for the RESP2 decoder
+        let mut src = BytesMut::from("\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(decode_inline(&mut src).unwrap(), None);
+        assert_eq!(&src[..], b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn overlong_inline_commands_are_refused() {
+        let mut src = BytesMut::from(&vec![b'a'; MAX_INLINE_BYTES + 1][..]);
+        assert!(decode_inline(&mut src).is_err());
+    }
+}
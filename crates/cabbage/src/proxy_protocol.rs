@@ -0,0 +1,117 @@
+//! HAProxy PROXY protocol headers.
+//!
+//! A load balancer speaking the PROXY protocol starts each connection it forwards with a header
+//! naming the client it accepted the connection from. `read_header` consumes a version 1 (text)
+//! or version 2 (binary) header from the start of a connection, leaving the client's traffic
+//! behind it unread, and returns the advertised source address. Headers which don't carry a TCP
+//! source (`UNKNOWN`, `LOCAL` health checks, UNIX sockets) yield `None`, meaning the connection's
+//! peer address stands.
+
+use std::array::TryFromSliceError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+use crate::error::{Error, Result, bail};
+
+static V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+static V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest possible version 1 header, including its CRLF
+static V1_MAX_LEN: usize = 107;
+
+/// Consume a PROXY protocol header from `stream`, returning the client address it advertises
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    // Every header is at least as long as the version 2 signature, so this never over-reads
+    let mut start = [0u8; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .map_err(|e| Error::io("connection closed before a PROXY protocol header", e))?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        bail!(
+            Protocol,
+            "connection didn't start with a PROXY protocol header"
+        )
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!(Protocol, "PROXY protocol header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| Error::Protocol("PROXY protocol header isn't ASCII".to_string()))?;
+
+    // PROXY <TCP4|TCP6|UNKNOWN> <src-ip> <dst-ip> <src-port> <dst-port>
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| Error::Protocol(format!("invalid source address '{source}'")))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                bail!(Protocol, "source address '{source}' isn't {protocol}");
+            }
+            let port = port
+                .parse()
+                .map_err(|_| Error::Protocol(format!("invalid source port '{port}'")))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!(Protocol, "malformed PROXY protocol header '{line}'"),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        bail!(
+            Protocol,
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    match version_command & 0x0f {
+        // LOCAL: the load balancer's own connection, e.g. a health check
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!(Protocol, "unsupported PROXY protocol command {command}"),
+    }
+    // Only TCP over IPv4 (0x11) and IPv6 (0x21) have a source worth reporting; any TLVs
+    // following the addresses are ignored
+    let source = match family {
+        0x11 if len >= 12 => {
+            let ip: [u8; 4] = addresses[0..4]
+                .try_into()
+                .map_err(|e: TryFromSliceError| Error::Protocol(e.to_string()))?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        0x21 if len >= 36 => {
+            let ip: [u8; 16] = addresses[0..16]
+                .try_into()
+                .map_err(|e: TryFromSliceError| Error::Protocol(e.to_string()))?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        0x11 | 0x21 => bail!(
+            Protocol,
+            "PROXY protocol header too short for its addresses"
+        ),
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
@@ -0,0 +1,190 @@
+//! Replaying captured traffic.
+//!
+//! `replay` re-sends the requests recorded by a `Capture` to a target, each recorded client
+//! connection over a target connection of its own and in its original order, with a bounded
+//! number of connections replayed at once. Optionally each reply is compared with the one
+//! recorded for that request, reporting where they differ. Replies are matched to requests by
+//! position, so a connection is only replayed up to its first subscription: everything it
+//! received after that was pushed messages rather than replies.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::{Service as _, ServiceExt as _};
+use uuid::Uuid;
+
+use crate::capture::{Direction, Record};
+use crate::error::Result;
+use crate::service::Resp2Backend;
+use crate::{command, frame};
+
+/// The traffic of one recorded client connection
+pub struct Session {
+    pub connection_id: Uuid,
+    /// Requests in the order they were sent, each with the reply recorded for it (if any)
+    pub exchanges: Vec<(BytesFrame, Option<BytesFrame>)>,
+    /// Requests left out because they followed a subscription
+    pub skipped: usize,
+}
+
+/// Group captured records into the sessions they belong to, in the order each first appears
+pub fn sessions(records: impl IntoIterator<Item = Result<Record>>) -> Result<Vec<Session>> {
+    struct Builder {
+        session: Session,
+        replies: usize,
+        subscribed: bool,
+    }
+
+    let mut builders: Vec<Builder> = vec![];
+    let mut by_id: HashMap<Uuid, usize> = HashMap::new();
+    for record in records {
+        let record = record?;
+        let index = *by_id.entry(record.connection_id).or_insert_with(|| {
+            builders.push(Builder {
+                session: Session {
+                    connection_id: record.connection_id,
+                    exchanges: vec![],
+                    skipped: 0,
+                },
+                replies: 0,
+                subscribed: false,
+            });
+            builders.len() - 1
+        });
+        let builder = &mut builders[index];
+
+        match record.direction {
+            Direction::Request if builder.subscribed => builder.session.skipped += 1,
+            Direction::Request if command::starts_push_mode(&record.frame) => {
+                builder.subscribed = true;
+                builder.session.skipped += 1;
+            }
+            Direction::Request => builder.session.exchanges.push((record.frame, None)),
+            Direction::Response => {
+                if let Some((_, reply)) = builder.session.exchanges.get_mut(builder.replies) {
+                    *reply = Some(record.frame);
+                    builder.replies += 1;
+                }
+            }
+        }
+    }
+    Ok(builders.into_iter().map(|b| b.session).collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    /// Address of the target to replay against
+    pub target: String,
+    /// Replay at most this many connections at once
+    pub concurrency: usize,
+    /// Compare each reply with the recorded one
+    pub compare: bool,
+}
+
+/// A replayed request whose reply differs from the recorded one
+#[derive(Clone, Debug)]
+pub struct ReplayMismatch {
+    pub connection_id: Uuid,
+    /// Position of the request within its connection's traffic
+    pub index: usize,
+    pub command: String,
+    pub summary: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub connections: usize,
+    /// Connections which couldn't be replayed in full, with the reason
+    pub failed: Vec<(Uuid, String)>,
+    pub commands: usize,
+    pub skipped: usize,
+    pub compared: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+    pub elapsed: Duration,
+}
+
+/// Replay `sessions` against the target described by `options`
+pub async fn replay(sessions: Vec<Session>, options: &ReplayOptions) -> ReplaySummary {
+    let started = Instant::now();
+    let mut summary = ReplaySummary {
+        connections: sessions.len(),
+        ..ReplaySummary::default()
+    };
+    let mut replayed = futures::stream::iter(sessions)
+        .map(|session| replay_session(session, options))
+        .buffer_unordered(options.concurrency.max(1));
+    while let Some(result) = replayed.next().await {
+        summary.commands += result.commands;
+        summary.skipped += result.skipped;
+        summary.compared += result.compared;
+        summary.mismatches.extend(result.mismatches);
+        if let Some(error) = result.error {
+            summary.failed.push((result.connection_id, error));
+        }
+    }
+    summary.elapsed = started.elapsed();
+    summary
+}
+
+struct SessionResult {
+    connection_id: Uuid,
+    commands: usize,
+    skipped: usize,
+    compared: usize,
+    mismatches: Vec<ReplayMismatch>,
+    error: Option<String>,
+}
+
+async fn replay_session(session: Session, options: &ReplayOptions) -> SessionResult {
+    let mut result = SessionResult {
+        connection_id: session.connection_id,
+        commands: 0,
+        skipped: session.skipped,
+        compared: 0,
+        mismatches: vec![],
+        error: None,
+    };
+    let mut backend = match Resp2Backend::connect(&options.target).await {
+        Ok(backend) => backend,
+        Err(e) => {
+            result.error = Some(format!("{e:#}"));
+            return result;
+        }
+    };
+
+    for (index, (request, recorded)) in session.exchanges.into_iter().enumerate() {
+        let command = command::name(&request).unwrap_or_default();
+        let reply = match backend.ready().await {
+            Ok(backend) => backend.call(request).await,
+            Err(e) => Err(e),
+        };
+        let replies = match reply {
+            Ok(stream) => stream.collect::<Vec<_>>().await,
+            Err(e) => {
+                result.error = Some(format!("{command} (request {index}) failed: {e:#}"));
+                return result;
+            }
+        };
+        result.commands += 1;
+
+        let Some(recorded) = recorded.filter(|_| options.compare) else {
+            continue;
+        };
+        result.compared += 1;
+        let diff = match replies.as_slice() {
+            [replayed] => frame::diff(&recorded, replayed),
+            replies => Some(format!("expected a single reply, got {}", replies.len())),
+        };
+        if let Some(summary) = diff {
+            result.mismatches.push(ReplayMismatch {
+                connection_id: session.connection_id,
+                index,
+                command,
+                summary,
+            });
+        }
+    }
+    result
+}
@@ -0,0 +1,272 @@
+//! RESP3 for clients and targets.
+//!
+//! The proxy runs on RESP2 frames, but a client may ask for RESP3 with `HELLO 3`, as newer
+//! client libraries do by default. The proxy forwards `HELLO 2` in its place and, once the
+//! target has accepted it, re-encodes everything it sends that client: nulls as `_`, the `HELLO`
+//! reply as a map, and pub/sub messages as push frames. Inline commands need no negotiation
+//! either, so one listener serves clients speaking RESP2, RESP3, or inline commands alike.
+//!
+//! Targets are spoken to in RESP2 unless `Handshake::resp3` is set, for features only offered to
+//! RESP3 connections. Then every target connection is switched with `HELLO 3`, and kept in RESP3
+//! by forwarding every client's `HELLO` as `HELLO 3`. Its replies are translated back as Redis
+//! shapes them for RESP2 connections (`to_resp2`): maps as arrays of keys and values, sets as
+//! arrays, doubles and big numbers as bulk strings, and booleans as 1 or 0. Pushed frames become
+//! the arrays of pub/sub messages (see `from_target`), and are dropped outside of a subscription,
+//! where a RESP2 connection has nowhere for them. RESP3 clients are given what a RESP2 target
+//! would have sent them. A `RESET` leaves a target connection in RESP2, which is still
+//! understood, until a client's next `HELLO`.
+
+use redis_protocol::codec::Resp2;
+use redis_protocol::error::RedisProtocolError;
+use redis_protocol::resp2::types::BytesFrame;
+use redis_protocol::resp3::types::BytesFrame as Resp3Frame;
+use tokio_util::bytes::{BufMut as _, Bytes, BytesMut};
+use tokio_util::codec::Encoder as _;
+
+use crate::command;
+
+/// Marks a frame a RESP3 target pushed; no target can send it, as simple strings can't contain
+/// CRLF
+static PUSHED_TAG: &[u8] = b"\r\npushed";
+/// The channel RESP2 connections are sent invalidated keys on
+static INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+/// Reply frames which are pub/sub messages (or subscription confirmations), pushed in RESP3
+static PUSH_KINDS: &[&[u8]] = &[
+    b"message",
+    b"pmessage",
+    b"smessage",
+    b"subscribe",
+    b"psubscribe",
+    b"ssubscribe",
+    b"unsubscribe",
+    b"punsubscribe",
+    b"sunsubscribe",
+];
+
+/// What the frames of a response stream answer, which decides how they're encoded for RESP3
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Shape {
+    #[default]
+    Reply,
+    /// A `HELLO` reply, switching to the given protocol version (or keeping the current one)
+    Hello(Option<i64>),
+    /// Frames of a subscription or `MONITOR`, which may be pushed
+    Push,
+}
+
+/// `req` as it should be forwarded to a RESP2 target, and how its replies are shaped
+pub fn negotiate(req: BytesFrame) -> (BytesFrame, Shape) {
+    if command::starts_push_mode(&req) {
+        return (req, Shape::Push);
+    }
+    if command::name(&req).as_deref() != Some("HELLO") {
+        return (req, Shape::Reply);
+    }
+    let version = command::args(&req)
+        .and_then(|args| args.get(1))
+        .and_then(command::arg_bytes)
+        .map(|arg| {
+            std::str::from_utf8(arg)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+        });
+    match version {
+        None => (req, Shape::Hello(None)),
+        Some(Some(2)) => (req, Shape::Hello(Some(2))),
+        Some(Some(3)) => {
+            let BytesFrame::Array(mut args) = req else {
+                unreachable!("HELLO has arguments, so it's an array");
+            };
+            args[1] = BytesFrame::BulkString(Bytes::from_static(b"2"));
+            (BytesFrame::Array(args), Shape::Hello(Some(3)))
+        }
+        // The target refuses any other version itself
+        Some(_) => (req, Shape::Reply),
+    }
+}
+
+/// `req` as it's forwarded to a RESP3 target: a `HELLO` asking for a protocol asks for RESP3,
+/// which the target connection has to keep speaking whatever its clients speak
+pub fn keep_resp3(req: BytesFrame) -> BytesFrame {
+    if command::name(&req).as_deref() != Some("HELLO") {
+        return req;
+    }
+    match req {
+        BytesFrame::Array(mut args) if args.len() > 1 => {
+            args[1] = BytesFrame::BulkString(Bytes::from_static(b"3"));
+            BytesFrame::Array(args)
+        }
+        req => req,
+    }
+}
+
+/// A frame from a RESP3 target as a RESP2 target would have sent it, marking those it pushed
+/// for `pushed` to tell apart from replies. Pub/sub pushes have the same shape as the messages
+/// sent to RESP2 connections, and invalidations, pushed to RESP3 connections as `invalidate`
+/// with the keys, become messages on `__redis__:invalidate` as they are for RESP2 connections.
+pub fn from_target(frame: Resp3Frame) -> BytesFrame {
+    let Resp3Frame::Push { data, .. } = frame else {
+        return to_resp2(frame);
+    };
+    let mut parts: Vec<_> = data.into_iter().map(to_resp2).collect();
+    let invalidates = parts.len() == 2
+        && command::arg_bytes(&parts[0])
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(b"invalidate"));
+    if invalidates {
+        parts.splice(
+            0..1,
+            [
+                BytesFrame::BulkString(Bytes::from_static(b"message")),
+                BytesFrame::BulkString(Bytes::from_static(INVALIDATE_CHANNEL)),
+            ],
+        );
+    }
+    BytesFrame::Array(vec![
+        BytesFrame::SimpleString(Bytes::from_static(PUSHED_TAG)),
+        BytesFrame::Array(parts),
+    ])
+}
+
+/// `frame` without the mark `from_target` gives pushed frames, and whether it had it
+pub fn pushed(frame: BytesFrame) -> (BytesFrame, bool) {
+    match frame {
+        BytesFrame::Array(mut parts)
+            if matches!(
+                parts.as_slice(),
+                [BytesFrame::SimpleString(tag), _] if tag == PUSHED_TAG
+            ) =>
+        {
+            (parts.swap_remove(1), true)
+        }
+        frame => (frame, false),
+    }
+}
+
+/// A reply from a RESP3 target as a RESP2 target would have sent it
+pub fn to_resp2(frame: Resp3Frame) -> BytesFrame {
+    match frame {
+        Resp3Frame::BlobString { data, .. } | Resp3Frame::BigNumber { data, .. } => {
+            BytesFrame::BulkString(data)
+        }
+        Resp3Frame::VerbatimString { data, .. } | Resp3Frame::ChunkedString(data) => {
+            BytesFrame::BulkString(data)
+        }
+        Resp3Frame::SimpleString { data, .. } => BytesFrame::SimpleString(data),
+        Resp3Frame::SimpleError { data, .. } => BytesFrame::Error(data),
+        Resp3Frame::BlobError { data, .. } => {
+            command::error(String::from_utf8_lossy(&data).into_owned())
+        }
+        Resp3Frame::Boolean { data, .. } => BytesFrame::Integer(i64::from(data)),
+        Resp3Frame::Number { data, .. } => BytesFrame::Integer(data),
+        Resp3Frame::Double { data, .. } => {
+            let double = if data.is_nan() {
+                "nan".to_string()
+            } else {
+                data.to_string()
+            };
+            BytesFrame::BulkString(Bytes::from(double))
+        }
+        Resp3Frame::Null => BytesFrame::Null,
+        Resp3Frame::Array { data, .. } | Resp3Frame::Push { data, .. } => {
+            BytesFrame::Array(data.into_iter().map(to_resp2).collect())
+        }
+        Resp3Frame::Set { data, .. } => BytesFrame::Array(data.into_iter().map(to_resp2).collect()),
+        Resp3Frame::Map { data, .. } => BytesFrame::Array(
+            data.into_iter()
+                .flat_map(|(key, value)| [to_resp2(key), to_resp2(value)])
+                .collect(),
+        ),
+        // Only ever sent by clients
+        Resp3Frame::Hello { .. } => command::error("ERR unexpected HELLO from the target"),
+    }
+}
+
+/// The protocol a client connection has negotiated, encoding the frames sent to it accordingly
+#[derive(Default)]
+pub struct ClientProtocol {
+    resp3: bool,
+}
+
+impl ClientProtocol {
+    pub fn encode(
+        &mut self,
+        resp2: &mut Resp2,
+        frame: BytesFrame,
+        shape: Shape,
+        dst: &mut BytesMut,
+    ) -> Result<(), RedisProtocolError> {
+        let frame = match (shape, frame) {
+            (Shape::Hello(version), BytesFrame::Array(fields)) => {
+                self.resp3 = version.map_or(self.resp3, |version| version == 3);
+                let fields = with_proto(fields, if self.resp3 { 3 } else { 2 });
+                if self.resp3 {
+                    dst.put_slice(format!("%{}\r\n", fields.len() / 2).as_bytes());
+                    fields
+                        .iter()
+                        .for_each(|field| encode_resp3(field, false, dst));
+                    return Ok(());
+                }
+                BytesFrame::Array(fields)
+            }
+            (_, frame) => frame,
+        };
+        if !self.resp3 {
+            return resp2.encode(frame, dst);
+        }
+        encode_resp3(&frame, shape == Shape::Push && is_push(&frame), dst);
+        Ok(())
+    }
+}
+
+/// The fields of a `HELLO` reply, reporting `version` as the protocol in use
+fn with_proto(mut fields: Vec<BytesFrame>, version: i64) -> Vec<BytesFrame> {
+    let proto = fields
+        .chunks(2)
+        .position(|pair| command::arg_bytes(&pair[0]).is_some_and(|key| key.as_ref() == b"proto"));
+    if let Some(i) = proto
+        && let Some(value) = fields.get_mut(i * 2 + 1)
+    {
+        *value = BytesFrame::Integer(version);
+    }
+    fields
+}
+
+fn is_push(frame: &BytesFrame) -> bool {
+    let BytesFrame::Array(parts) = frame else {
+        return false;
+    };
+    parts
+        .first()
+        .and_then(command::arg_bytes)
+        .is_some_and(|kind| PUSH_KINDS.iter().any(|k| kind.eq_ignore_ascii_case(k)))
+}
+
+/// Write `frame` to `dst` in RESP3, as a push frame if `push` and it's an array
+fn encode_resp3(frame: &BytesFrame, push: bool, dst: &mut BytesMut) {
+    match frame {
+        BytesFrame::SimpleString(s) => {
+            dst.put_u8(b'+');
+            dst.put_slice(s);
+        }
+        BytesFrame::Error(e) => {
+            dst.put_u8(b'-');
+            dst.put_slice(e.as_bytes());
+        }
+        BytesFrame::Integer(i) => dst.put_slice(format!(":{i}").as_bytes()),
+        BytesFrame::BulkString(b) => {
+            dst.put_slice(format!("${}\r\n", b.len()).as_bytes());
+            dst.put_slice(b);
+        }
+        BytesFrame::Array(frames) => {
+            let kind = if push { '>' } else { '*' };
+            dst.put_slice(format!("{kind}{}\r\n", frames.len()).as_bytes());
+            frames
+                .iter()
+                .for_each(|frame| encode_resp3(frame, false, dst));
+            return;
+        }
+        BytesFrame::Null => dst.put_u8(b'_'),
+    }
+    dst.put_slice(b"\r\n");
+}
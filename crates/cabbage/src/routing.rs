@@ -0,0 +1,372 @@
+//! Routing commands to targets by name or key.
+//!
+//! `RoutingBackend` sends each command matching a `RouteRule` (by name, or by name prefix, as in
+//! `FT.*`) or whose first key matches a `KeyRouteRule` (by glob, as in `session:*`, or by regular
+//! expression) to that rule's target, and everything else to the backend it wraps. Command rules
+//! are tried before key rules, and the first matching rule applies. Only the first key counts, so
+//! a multi-key command goes wherever its first key does. Each client connection gets its own
+//! connection to each route target, made when the first command routed there arrives and set up
+//! with the same handshake as the main target's. Replies still reach the client in the order it
+//! sent the commands.
+//!
+//! Key routes split the keyspace between targets, so `SCAN` (unless routed by name) walks the
+//! main target and then each key route target in turn, with cursors translated as described in
+//! `scan`.
+//!
+//! Only the main target sees `MULTI`/`EXEC` and `SELECT`, so a routed command is never part of a
+//! transaction and runs against the database its target's handshake selected.
+//!
+//! Users authenticated to the proxy (see `middleware::auth`) can be given targets of their own
+//! with `Routes::with_user_targets`: once a client has authenticated as such a user, every
+//! command it sends goes to that user's target, transactions included, whatever the other rules
+//! say.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use regex::bytes::Regex;
+use serde::Deserialize;
+use tower::{Service, ServiceExt as _};
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::middleware::auth::ClientUser;
+use crate::scan::{self, ShardedScan};
+use crate::service::{Handshake, LazyBackend, ProxyService, ResponseStream};
+
+/// Send commands named `pattern` to `address`, parsed from `PATTERN=ADDRESS`, where a pattern
+/// ending in `*` matches every command name starting with the rest of it
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RouteRule {
+    pattern: String,
+    address: String,
+}
+
+impl RouteRule {
+    fn matches(&self, name: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == self.pattern,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl std::str::FromStr for RouteRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, address)) = s.split_once('=') else {
+            bail!(Config, "Route '{s}' should be PATTERN=ADDRESS");
+        };
+        let (pattern, address) = (pattern.trim(), address.trim());
+        if pattern.is_empty() || address.is_empty() {
+            bail!(Config, "Route '{s}' should be PATTERN=ADDRESS");
+        }
+        Ok(Self {
+            pattern: pattern.to_ascii_uppercase(),
+            address: address.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for RouteRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// How a `KeyRouteRule` matches keys
+#[derive(Clone, Debug)]
+enum KeyPattern {
+    /// A Redis glob, as taken by `KEYS`
+    Glob(Vec<u8>),
+    Regex(Regex),
+}
+
+impl KeyPattern {
+    fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Self::Glob(glob) => glob_matches(glob, key),
+            Self::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+/// Send commands whose first key matches `pattern` to `address`, parsed from `GLOB=ADDRESS` or
+/// `/REGEX/=ADDRESS`, where a glob is as taken by `KEYS` and a regular expression must match
+/// some part of the key (anchor it with `^` and `$` to match all of it)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyRouteRule {
+    pattern: KeyPattern,
+    address: String,
+    /// The rule as given, for comparing rules
+    spec: String,
+}
+
+impl PartialEq for KeyRouteRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.spec == other.spec
+    }
+}
+
+impl Eq for KeyRouteRule {}
+
+impl std::str::FromStr for KeyRouteRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Patterns may contain `=`, but addresses don't
+        let Some((pattern, address)) = s.rsplit_once('=') else {
+            bail!(
+                Config,
+                "Key route '{s}' should be GLOB=ADDRESS or /REGEX/=ADDRESS"
+            );
+        };
+        let address = address.trim();
+        if pattern.is_empty() || address.is_empty() {
+            bail!(
+                Config,
+                "Key route '{s}' should be GLOB=ADDRESS or /REGEX/=ADDRESS"
+            );
+        }
+        let pattern = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(regex) => KeyPattern::Regex(
+                Regex::new(regex)
+                    .map_err(|e| Error::Config(format!("Invalid regex in key route '{s}': {e}")))?,
+            ),
+            None => KeyPattern::Glob(pattern.as_bytes().to_vec()),
+        };
+        Ok(Self {
+            pattern,
+            address: address.to_string(),
+            spec: s.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for KeyRouteRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Whether `key` matches the Redis glob `glob`: `*` matches any run of bytes, `?` any one byte,
+/// `[...]` any byte in a set (with `^` negating it and `a-z` ranges), and `\` escapes the next
+/// byte
+pub(crate) fn glob_matches(glob: &[u8], key: &[u8]) -> bool {
+    let (Some(&first), rest) = (glob.first(), glob.get(1..).unwrap_or_default()) else {
+        return key.is_empty();
+    };
+    match first {
+        b'*' => (0..=key.len()).any(|skip| glob_matches(rest, &key[skip..])),
+        b'?' => !key.is_empty() && glob_matches(rest, &key[1..]),
+        b'[' => {
+            let Some(&byte) = key.first() else {
+                return false;
+            };
+            let (negated, set) = match rest.first() {
+                Some(b'^') => (true, &rest[1..]),
+                _ => (false, rest),
+            };
+            let mut i = 0;
+            let mut matched = false;
+            while i < set.len() && set[i] != b']' {
+                if set[i] == b'\\' && i + 1 < set.len() {
+                    matched |= set[i + 1] == byte;
+                    i += 2;
+                } else if i + 2 < set.len() && set[i + 1] == b'-' && set[i + 2] != b']' {
+                    let (low, high) = (set[i].min(set[i + 2]), set[i].max(set[i + 2]));
+                    matched |= (low..=high).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= set[i] == byte;
+                    i += 1;
+                }
+            }
+            // An unclosed set runs to the end of the pattern, as in Redis
+            let after = set.get(i + 1..).unwrap_or_default();
+            matched != negated && glob_matches(after, &key[1..])
+        }
+        b'\\' if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob_matches(&rest[1..], &key[1..])
+        }
+        literal => key.first() == Some(&literal) && glob_matches(rest, &key[1..]),
+    }
+}
+
+/// The route rules, and the handshake performed on each connection to their targets
+#[derive(Clone, Debug, Default)]
+pub struct Routes {
+    rules: Vec<RouteRule>,
+    key_rules: Vec<KeyRouteRule>,
+    /// The address of the target for each user which has one
+    user_targets: HashMap<String, String>,
+    handshake: Handshake,
+}
+
+impl Routes {
+    pub fn new(rules: Vec<RouteRule>, key_rules: Vec<KeyRouteRule>, handshake: Handshake) -> Self {
+        Self {
+            rules,
+            key_rules,
+            user_targets: HashMap::new(),
+            handshake,
+        }
+    }
+
+    /// Send every command of clients authenticated as a user to that user's target, given as
+    /// `(user, address)` pairs
+    pub fn with_user_targets(
+        mut self,
+        targets: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        self.user_targets.extend(targets);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.key_rules.is_empty() && self.user_targets.is_empty()
+    }
+
+    /// The address of the target `req`, sent by a client authenticated as `user`, is routed to,
+    /// if any
+    fn target(&self, req: &BytesFrame, user: Option<&str>) -> Option<&str> {
+        if let Some(address) = user.and_then(|user| self.user_targets.get(user)) {
+            return Some(address);
+        }
+        let name = command::name(req)?;
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(&name)) {
+            return Some(&rule.address);
+        }
+        let key = command::first_key(req)?;
+        self.key_rules
+            .iter()
+            .find(|rule| rule.pattern.matches(key))
+            .map(|rule| rule.address.as_str())
+    }
+}
+
+/// A backend sending some commands elsewhere, on behalf of a single client connection
+pub struct RoutingBackend {
+    default: ProxyService,
+    routes: Arc<Routes>,
+    /// A connection per route target address, shared by the rules naming it
+    targets: HashMap<String, Arc<tokio::sync::Mutex<LazyBackend>>>,
+    /// The key route targets `SCAN` walks after the main target, in rule order
+    scan_targets: Vec<Arc<tokio::sync::Mutex<LazyBackend>>>,
+    user: ClientUser,
+}
+
+impl RoutingBackend {
+    pub fn new(default: ProxyService, routes: Arc<Routes>) -> Self {
+        let addresses = routes
+            .rules
+            .iter()
+            .map(|rule| &rule.address)
+            .chain(routes.key_rules.iter().map(|rule| &rule.address))
+            .chain(routes.user_targets.values());
+        let mut targets = HashMap::new();
+        for address in addresses {
+            targets.entry(address.clone()).or_insert_with(|| {
+                let backend = LazyBackend::new(address, routes.handshake.clone());
+                Arc::new(tokio::sync::Mutex::new(backend))
+            });
+        }
+        let mut scan_addresses: Vec<&String> = vec![];
+        for rule in &routes.key_rules {
+            if !scan_addresses.contains(&&rule.address) {
+                scan_addresses.push(&rule.address);
+            }
+        }
+        let scan_targets = scan_addresses
+            .into_iter()
+            .filter_map(|address| targets.get(address).cloned())
+            .collect();
+        Self {
+            default,
+            routes,
+            targets,
+            scan_targets,
+            user: ClientUser::default(),
+        }
+    }
+
+    /// Route by the targets of the user the client authenticates as, recorded in `user`
+    pub fn with_user(mut self, user: ClientUser) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Continue a `SCAN` over the main target and then each key route target
+    fn scan(
+        &mut self,
+        req: BytesFrame,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponseStream>> + Send>> {
+        let (scan, req) = match ShardedScan::start(&req, self.scan_targets.len() + 1) {
+            Ok(started) => started,
+            Err(error) => {
+                return Box::pin(async move { Ok(futures::stream::once(async { error }).boxed()) });
+            }
+        };
+        let responses = match scan.shard().checked_sub(1) {
+            None => self.default.call(req),
+            Some(shard) => {
+                let backend = self.scan_targets[shard].clone();
+                Box::pin(async move {
+                    let mut backend = backend.lock_owned().await;
+                    backend.ready().await?.call(req).await
+                })
+            }
+        };
+        Box::pin(async move {
+            Ok(responses
+                .await?
+                .map(move |reply| scan.finish(reply))
+                .boxed())
+        })
+    }
+}
+
+impl Service<BytesFrame> for RoutingBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.default.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let user = self.user.get();
+        let address = self.routes.target(&req, user.as_deref());
+        if address.is_none() && !self.scan_targets.is_empty() && scan::is_scan(&req) {
+            return self.scan(req);
+        }
+        let route = address
+            .and_then(|address| self.targets.get(address))
+            .cloned();
+        let Some(backend) = route else {
+            return self.default.call(req);
+        };
+        // The lock is taken in call order, so commands reach the route's target in order
+        Box::pin(async move {
+            let mut backend = backend.lock_owned().await;
+            backend.ready().await?.call(req).await
+        })
+    }
+}
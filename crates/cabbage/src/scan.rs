@@ -0,0 +1,179 @@
+//! `SCAN` across several targets.
+//!
+//! When the keyspace is split between targets (a cluster's nodes, or the targets of key routes),
+//! each target's `SCAN` only walks its own share, and the cursors of different targets mean
+//! nothing to each other. `ShardedScan` gives clients one cursor over all of them: the target
+//! being scanned (its shard) is kept in the low bits of the cursor the client sees, and the
+//! target's own cursor in the rest. Once a target's iteration ends, the next call starts on the
+//! next one, and the client's cursor returns to 0 after the last.
+//!
+//! Cursors stay within 64 bits as long as each target's do within 54, which Redis's do for any
+//! keyspace that fits in memory. An iteration running while targets join or leave may miss or
+//! repeat keys, as one over a resharding cluster can anyway.
+
+use redis_protocol::resp2::types::BytesFrame;
+
+use crate::command;
+
+/// Bits of a client's cursor naming the shard being scanned
+static SHARD_BITS: u32 = 10;
+
+/// The most shards a cursor can name
+pub static MAX_SHARDS: usize = 1 << SHARD_BITS;
+
+/// Whether `req` is a `SCAN` (rather than `SSCAN` and friends, which iterate a single key)
+pub fn is_scan(req: &BytesFrame) -> bool {
+    command::name(req).as_deref() == Some("SCAN")
+}
+
+/// A client's `SCAN` iteration over `shards` targets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShardedScan {
+    shard: usize,
+    shards: usize,
+}
+
+impl ShardedScan {
+    /// Split the cursor of the `SCAN` request `req` into the shard to scan and the request to
+    /// send it, or the error to answer with if the cursor is invalid
+    pub fn start(req: &BytesFrame, shards: usize) -> Result<(Self, BytesFrame), BytesFrame> {
+        let Some(args) = command::args(req).filter(|args| args.len() >= 2) else {
+            return Err(command::error(
+                "ERR wrong number of arguments for 'scan' command",
+            ));
+        };
+        let cursor = command::arg_bytes(&args[1])
+            .and_then(|cursor| std::str::from_utf8(cursor).ok())
+            .and_then(|cursor| cursor.parse::<u128>().ok());
+        let Some(cursor) = cursor.filter(|_| (1..=MAX_SHARDS).contains(&shards)) else {
+            return Err(command::error("ERR invalid cursor"));
+        };
+        let shard = (cursor & (MAX_SHARDS as u128 - 1)) as usize;
+        let Ok(target_cursor) = u64::try_from(cursor >> SHARD_BITS) else {
+            return Err(command::error("ERR invalid cursor"));
+        };
+        if shard >= shards {
+            return Err(command::error("ERR invalid cursor"));
+        }
+        let mut args = args.to_vec();
+        args[1] = BytesFrame::BulkString(target_cursor.to_string().into());
+        Ok((Self { shard, shards }, BytesFrame::Array(args)))
+    }
+
+    /// The shard to send the request to, counting from 0
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// Turn the shard's reply into the client's, carrying on to the next shard once the shard's
+    /// iteration is over
+    pub fn finish(&self, reply: BytesFrame) -> BytesFrame {
+        let BytesFrame::Array(mut parts) = reply else {
+            return reply;
+        };
+        let cursor = parts
+            .first()
+            .and_then(command::arg_bytes)
+            .and_then(|cursor| std::str::from_utf8(cursor).ok())
+            .and_then(|cursor| cursor.parse::<u64>().ok());
+        let Some(cursor) = cursor else {
+            return BytesFrame::Array(parts);
+        };
+        let next = match (cursor, self.shard + 1) {
+            (0, next) if next == self.shards => 0,
+            (0, next) => next as u128,
+            (cursor, _) => (u128::from(cursor) << SHARD_BITS) | self.shard as u128,
+        };
+        parts[0] = BytesFrame::BulkString(next.to_string().into());
+        BytesFrame::Array(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+
+    fn request(cursor: &str) -> BytesFrame {
+        command::request(["SCAN", cursor, "MATCH", "user:*"])
+    }
+
+    /// A shard's reply to `SCAN`, continuing from `cursor`
+    fn reply(cursor: &str) -> BytesFrame {
+        BytesFrame::Array(vec![
+            BytesFrame::BulkString(Bytes::copy_from_slice(cursor.as_bytes())),
+            BytesFrame::Array(vec![]),
+        ])
+    }
+
+    /// The cursor the client is given when `scan`'s shard replies with `cursor`
+    fn client_cursor(scan: &ShardedScan, cursor: &str) -> String {
+        let BytesFrame::Array(parts) = scan.finish(reply(cursor)) else {
+            panic!("SCAN replies are arrays");
+        };
+        let cursor = command::arg_bytes(&parts[0]).unwrap();
+        String::from_utf8(cursor.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn iteration_starts_on_the_first_shard() {
+        let (scan, req) = ShardedScan::start(&request("0"), 3).unwrap();
+        assert_eq!(scan.shard(), 0);
+        assert_eq!(req, request("0"));
+    }
+
+    #[test]
+    fn cursors_carry_the_shard_and_its_cursor() {
+        for (shard, cursor) in [(0, 17), (2, 17), (1, u64::MAX)] {
+            let scan = ShardedScan { shard, shards: 3 };
+            let resumed_from = client_cursor(&scan, &cursor.to_string());
+            let (resumed, req) = ShardedScan::start(&request(&resumed_from), 3).unwrap();
+            assert_eq!(resumed, scan);
+            assert_eq!(req, request(&cursor.to_string()));
+        }
+    }
+
+    #[test]
+    fn finished_shards_move_on_to_the_next() {
+        let first = ShardedScan {
+            shard: 0,
+            shards: 3,
+        };
+        let (next, req) = ShardedScan::start(&request(&client_cursor(&first, "0")), 3).unwrap();
+        assert_eq!(next.shard(), 1);
+        assert_eq!(req, request("0"));
+
+        let last = ShardedScan {
+            shard: 2,
+            shards: 3,
+        };
+        assert_eq!(client_cursor(&last, "0"), "0");
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        let too_big = ((u128::from(u64::MAX) + 1) << SHARD_BITS).to_string();
+        // 5 names shard 5 of 3
+        for cursor in ["cursor", "-1", "1.5", "5", &too_big] {
+            assert!(ShardedScan::start(&request(cursor), 3).is_err(), "{cursor}");
+        }
+        assert!(ShardedScan::start(&command::request(["SCAN"]), 3).is_err());
+        for shards in [0, MAX_SHARDS + 1] {
+            assert!(
+                ShardedScan::start(&request("0"), shards).is_err(),
+                "{shards}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_replies_pass_through() {
+        let scan = ShardedScan {
+            shard: 0,
+            shards: 3,
+        };
+        let error = command::error("ERR busy");
+        assert_eq!(scan.finish(error.clone()), error);
+    }
+}
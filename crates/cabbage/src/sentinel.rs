@@ -0,0 +1,274 @@
+//! Master discovery through Redis Sentinel.
+//!
+//! `SentinelMaster` asks a set of Sentinels for the current address of a named master and keeps
+//! it up to date by listening for `+switch-master` events. `SentinelBackend` forwards each client
+//! connection's traffic to whichever address is current, reconnecting after a failover or when
+//! the connection to the old primary fails. Reconnections switch to the database the client last
+//! selected rather than the handshake's.
+
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Future;
+use futures_util::StreamExt;
+use redis_protocol::resp2::types::BytesFrame;
+use tower::Service;
+
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::pool::TargetPool;
+use crate::service::{CallOne as _, Handshake, Resp2Backend, ResponseStream, SelectedDb};
+
+/// Delay before re-subscribing to Sentinel events after losing the subscription
+static RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// The Sentinel-published address of a named master
+pub struct SentinelMaster {
+    sentinels: Vec<String>,
+    master_name: String,
+    address: RwLock<Option<String>>,
+}
+
+impl SentinelMaster {
+    pub fn new(sentinels: Vec<String>, master_name: String) -> Self {
+        Self {
+            sentinels,
+            master_name,
+            address: RwLock::new(None),
+        }
+    }
+
+    pub fn master_name(&self) -> &str {
+        &self.master_name
+    }
+
+    /// The last known master address, if one has been resolved
+    pub fn current(&self) -> Option<String> {
+        self.address.read().ok().and_then(|a| a.clone())
+    }
+
+    fn set_current(&self, address: String) {
+        if let Ok(mut current) = self.address.write()
+            && current.as_deref() != Some(address.as_str())
+        {
+            log::info!(
+                "Master '{}' is now at {address} (was {:?})",
+                self.master_name,
+                *current
+            );
+            *current = Some(address);
+        }
+    }
+
+    /// Ask the Sentinels, in order, for the master's current address
+    pub async fn resolve(&self) -> Result<String> {
+        let mut last_error = Error::Config("no sentinels configured".to_string());
+        for sentinel in &self.sentinels {
+            match self.query(sentinel).await {
+                Ok(address) => {
+                    self.set_current(address.clone());
+                    return Ok(address);
+                }
+                Err(e) => {
+                    log::warn!("Sentinel {sentinel} could not resolve master: {e}");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn query(&self, sentinel: &str) -> Result<String> {
+        let mut backend = Resp2Backend::connect(sentinel).await?;
+        let reply = backend
+            .call_one(command::request([
+                "SENTINEL",
+                "get-master-addr-by-name",
+                self.master_name.as_str(),
+            ]))
+            .await?;
+
+        match &reply {
+            BytesFrame::Array(parts) if parts.len() == 2 => {
+                let host = command::arg_bytes(&parts[0]);
+                let port = command::arg_bytes(&parts[1]);
+                match (host, port) {
+                    (Some(host), Some(port)) => Ok(format!(
+                        "{}:{}",
+                        String::from_utf8_lossy(host),
+                        String::from_utf8_lossy(port)
+                    )),
+                    _ => bail!(
+                        Protocol,
+                        "Malformed master address from sentinel: {reply:?}"
+                    ),
+                }
+            }
+            BytesFrame::Null => bail!(
+                BackendUnavailable,
+                "Sentinel does not know master '{}'",
+                self.master_name
+            ),
+            _ => bail!(Protocol, "Unexpected reply from sentinel: {reply:?}"),
+        }
+    }
+
+    /// Follow `+switch-master` announcements for as long as the process runs, cycling through
+    /// the Sentinels whenever a subscription is lost.
+    pub async fn watch_failovers(self: Arc<Self>) {
+        for sentinel in self.sentinels.iter().cycle() {
+            if let Err(e) = self.follow(sentinel).await {
+                log::warn!("Lost failover subscription on sentinel {sentinel}: {e}");
+            }
+            // A failover may have happened while we weren't listening.
+            if let Err(e) = self.resolve().await {
+                log::error!("Failed to re-resolve master '{}': {e}", self.master_name);
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn follow(&self, sentinel: &str) -> Result<()> {
+        let mut backend = Resp2Backend::connect(sentinel).await?;
+        let mut events = backend
+            .call(command::request(["SUBSCRIBE", "+switch-master"]))
+            .await?;
+        log::debug!("Subscribed to failover events on sentinel {sentinel}");
+
+        while let Some(event) = events.next().await {
+            let BytesFrame::Array(parts) = &event else {
+                continue;
+            };
+            let (Some(kind), Some(payload)) = (
+                parts.first().and_then(command::arg_bytes),
+                parts.get(2).and_then(command::arg_bytes),
+            ) else {
+                continue;
+            };
+            if kind.as_ref() != b"message" {
+                continue;
+            }
+
+            // <master-name> <old-ip> <old-port> <new-ip> <new-port>
+            let payload = String::from_utf8_lossy(payload);
+            let fields: Vec<&str> = payload.split_whitespace().collect();
+            if let [name, _, _, ip, port] = fields[..]
+                && name == self.master_name
+            {
+                self.set_current(format!("{ip}:{port}"));
+            }
+        }
+        bail!(BackendUnavailable, "subscription stream ended")
+    }
+}
+
+/// A backend which follows the current master of a Sentinel-managed deployment
+pub struct SentinelBackend {
+    master: Arc<SentinelMaster>,
+    handshake: Arc<Handshake>,
+    selected: SelectedDb,
+    connection: Arc<tokio::sync::Mutex<Option<(String, Resp2Backend)>>>,
+    pool: Option<Arc<TargetPool>>,
+}
+
+impl SentinelBackend {
+    pub fn new(master: Arc<SentinelMaster>) -> Self {
+        Self {
+            master,
+            handshake: Arc::new(Handshake::default()),
+            selected: SelectedDb::new(),
+            connection: Arc::new(tokio::sync::Mutex::new(None)),
+            pool: None,
+        }
+    }
+
+    /// Perform `handshake` on every connection made to the master
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = Arc::new(handshake);
+        self
+    }
+
+    /// Check connections out of `pool` rather than making them directly
+    pub fn with_pool(mut self, pool: Arc<TargetPool>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+}
+
+impl Service<BytesFrame> for SentinelBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let master = self.master.clone();
+        let handshake = self.handshake.clone();
+        let selected = self.selected.clone();
+        let connection = self.connection.clone();
+        let pool = self.pool.clone();
+
+        Box::pin(async move {
+            let mut connection = connection.lock().await;
+            let mut re_resolved = false;
+            loop {
+                let address = match master.current() {
+                    Some(address) if !re_resolved => address,
+                    _ => master.resolve().await?,
+                };
+
+                if connection
+                    .as_ref()
+                    .map(|(a, _)| a != &address)
+                    .unwrap_or(true)
+                {
+                    *connection = None;
+                    let handshake = selected.handshake(&handshake);
+                    let connected = match &pool {
+                        Some(pool) => pool.checkout(&address, selected.get()).await,
+                        None => Resp2Backend::connect_with(&address, &handshake).await,
+                    };
+                    match connected {
+                        Ok(backend) => {
+                            log::info!(
+                                "Connected to master '{}' at {address}",
+                                master.master_name()
+                            );
+                            *connection = Some((address, backend));
+                        }
+                        Err(e) if !re_resolved => {
+                            log::warn!("Failed to connect to master at {address}: {e}");
+                            re_resolved = true;
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                let Some((_, backend)) = connection.as_mut() else {
+                    bail!(
+                        BackendUnavailable,
+                        "No connection to master '{}'",
+                        master.master_name()
+                    );
+                };
+                // A failed dispatch means the request never reached the target, so it is safe to
+                // resend it to a freshly resolved master.
+                match backend.call(req.clone()).await {
+                    Ok(responses) => return Ok(selected.track(&req, responses)),
+                    Err(e) if !re_resolved => {
+                        log::warn!("Connection to master failed, re-resolving: {e}");
+                        *connection = None;
+                        re_resolved = true;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
@@ -1,104 +1,1142 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
 
-use anyhow::bail;
 use futures::Future;
-use futures::stream::Stream;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
 use futures_util::{SinkExt, StreamExt};
 use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, watch};
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::codec::Framed;
+use tokio_util::sync::{PollSemaphore, PollSender};
 use tower::Service;
+use uuid::Uuid;
 
+use crate::buffer::BUFFERS;
+use crate::command;
+use crate::error::{Error, Result, bail};
+use crate::protocol::{Protocol, Resp2Protocol};
+use crate::stats::{QUEUES, Queued, Stats};
+use crate::streaming::BulkStreamingCodec;
+
+static MAX_OUTSTANDING_RESPONSE_STREAMS: usize = 100;
 static MAX_OUTSTANDING_RESPONSE_STREAM_MESSAGES: usize = 100;
 static MAX_OUTSTANDING_REQUEST_MESSAGES: usize = 100;
+/// Requests a `Resp2Backend` will have awaiting replies before it stops being ready
+static MAX_PENDING_REQUESTS: usize = 1000;
+/// Each round trip's weight in a target connection's average, as a fraction (1/N)
+static ROUND_TRIP_WEIGHT: u64 = 5;
+/// The round trip a target connection is assumed to take until one has been measured
+static DEFAULT_ROUND_TRIP: Duration = Duration::from_millis(1);
+/// Requests written to a target connection before it's flushed, even while more are queued
+static MAX_UNFLUSHED_REQUESTS: usize = 64;
+/// Where the next connection to a target starts in the addresses its name resolves to, when
+/// rotating through them
+static NEXT_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// The stream of frames produced in response to a single request
+pub type ResponseStream = BoxStream<'static, BytesFrame>;
 
-struct RequestMessage {
-    frame: BytesFrame,
-    response_sender: mpsc::Sender<BytesFrame>,
+/// A type-erased request/response service, as assembled for each proxied connection
+pub type ProxyService = tower::util::BoxService<BytesFrame, ResponseStream, Error>;
+
+/// Requests of any service speaking frames, for callers wanting a single reply frame rather than
+/// a `ResponseStream`, such as simple middleware and tests
+pub trait CallOne: Service<BytesFrame, Response = ResponseStream> {
+    /// Wait for the service to be ready, send `req`, and return its reply.
+    ///
+    /// Fails if `req` would start push mode (`SUBSCRIBE`, `MONITOR`, and the like), or if the
+    /// reply isn't exactly one frame: none because the target went away, or more because it was
+    /// streamed in parts.
+    fn call_one(&mut self, req: BytesFrame) -> impl Future<Output = Result<BytesFrame>> + Send
+    where
+        Self: Send + Sized,
+        Self::Error: Into<Error>,
+        Self::Future: Send,
+    {
+        async move {
+            if command::starts_push_mode(&req) {
+                bail!(
+                    Protocol,
+                    "{} has no single reply",
+                    command::name(&req).unwrap_or_default()
+                );
+            }
+            futures::future::poll_fn(|cx| self.poll_ready(cx))
+                .await
+                .map_err(Into::into)?;
+            let mut responses = self.call(req).await.map_err(Into::into)?;
+            let Some(reply) = responses.next().await else {
+                bail!(
+                    BackendUnavailable,
+                    "The target closed the connection before replying"
+                );
+            };
+            if responses.next().await.is_some() {
+                bail!(Protocol, "The reply was more than one frame");
+            }
+            Ok(reply)
+        }
+    }
 }
 
-struct CloseMessage {
-    conn_sender: tokio::sync::oneshot::Sender<Framed<TcpStream, Resp2>>,
+impl<S: Service<BytesFrame, Response = ResponseStream>> CallOne for S {}
+
+/// How much is queued between a client connection, the backend serving it, and the target.
+///
+/// Deeper queues let a client pipeline further ahead of the target; shallower ones hold less
+/// memory per connection and push back on clients sooner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueCapacities {
+    /// Replies a client connection has outstanding before it stops reading commands
+    pub response_streams: usize,
+    /// Frames of a reply buffered before the target connection waits for the client to take them
+    pub response_stream_frames: usize,
+    /// Requests queued for a target connection before callers wait for it to take them
+    pub requests: usize,
 }
 
-enum Message {
-    Request(RequestMessage),
-    #[allow(dead_code)]
-    Close(CloseMessage),
+impl Default for QueueCapacities {
+    fn default() -> Self {
+        Self {
+            response_streams: MAX_OUTSTANDING_RESPONSE_STREAMS,
+            response_stream_frames: MAX_OUTSTANDING_RESPONSE_STREAM_MESSAGES,
+            requests: MAX_OUTSTANDING_REQUEST_MESSAGES,
+        }
+    }
 }
 
-pub struct Resp2Backend {
-    request_sender: mpsc::Sender<Message>,
+impl QueueCapacities {
+    /// Fails if any queue has no room at all, which would stall every connection
+    pub fn validate(&self) -> Result<()> {
+        if self.response_streams == 0 || self.response_stream_frames == 0 || self.requests == 0 {
+            bail!(Config, "Queue capacities must be positive");
+        }
+        Ok(())
+    }
 }
 
-impl Resp2Backend {
-    pub fn new(target_framed: Framed<TcpStream, Resp2>) -> Self {
+/// Options set on TCP sockets, each left at the operating system's default if unset
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    /// Send small writes at once rather than coalescing them (`TCP_NODELAY`)
+    pub nodelay: Option<bool>,
+    /// Probe the peer once the connection has been idle for this many seconds (`SO_KEEPALIVE`)
+    pub keepalive_secs: Option<u64>,
+    /// Seconds between unanswered keepalive probes
+    pub keepalive_interval_secs: Option<u64>,
+    /// Unanswered keepalive probes before the connection is dropped
+    pub keepalive_retries: Option<u32>,
+    pub send_buffer_bytes: Option<usize>,
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Fails if keepalive probes are tuned without being turned on
+    pub fn validate(&self) -> Result<()> {
+        if self.keepalive_secs.is_none()
+            && (self.keepalive_interval_secs.is_some() || self.keepalive_retries.is_some())
+        {
+            bail!(
+                Config,
+                "Keepalive intervals and retries require keepalive_secs"
+            );
+        }
+        Ok(())
+    }
+
+    /// Set these options on `socket`
+    pub fn apply(&self, socket: &TcpStream) -> std::io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        let socket = SockRef::from(socket);
+        if let Some(keepalive_secs) = self.keepalive_secs {
+            let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(keepalive_secs));
+            if let Some(interval_secs) = self.keepalive_interval_secs {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval_secs));
+            }
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(bytes) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.recv_buffer_bytes {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Commands sent on every new target connection before it carries client traffic, so clients
+/// needn't know the target's credentials or database layout
+#[derive(Clone, Debug, Default)]
+pub struct Handshake {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<u32>,
+    pub client_name: Option<String>,
+    /// Set on every new target connection before the commands are sent
+    pub socket: SocketOptions,
+    /// Spread connections across every address a target's name resolves to, rather than
+    /// preferring the first
+    pub rotate_addresses: bool,
+    /// Pass bulk string replies of at least this many bytes on in chunks as they arrive (see
+    /// `streaming`)
+    pub stream_bulk_bytes: Option<usize>,
+    /// Switch target connections to RESP3 once the commands are sent, translating their replies
+    /// to RESP2 (see `resp3`)
+    pub resp3: bool,
+}
+
+impl Handshake {
+    pub fn is_empty(&self) -> bool {
+        self.password.is_none() && self.db.is_none() && self.client_name.is_none()
+    }
+
+    /// The handshake commands, each paired with a description safe to log
+    fn requests(&self) -> Vec<(&'static str, BytesFrame)> {
+        let mut requests = vec![];
+        if let Some(password) = &self.password {
+            let auth = match &self.username {
+                Some(username) => command::request(["AUTH", username.as_str(), password.as_str()]),
+                None => command::request(["AUTH", password.as_str()]),
+            };
+            requests.push(("AUTH", auth));
+        }
+        if let Some(db) = self.db {
+            requests.push(("SELECT", command::request(["SELECT", &db.to_string()])));
+        }
+        if let Some(client_name) = &self.client_name {
+            requests.push((
+                "CLIENT SETNAME",
+                command::request(["CLIENT", "SETNAME", client_name.as_str()]),
+            ));
+        }
+        requests
+    }
+
+    /// Dial `target_addr`, setting the handshake's socket options on the new connection.
+    ///
+    /// A name is resolved afresh for every connection, never cached here, so a target moved by
+    /// changing its DNS records is followed as soon as the system's resolver (which honors the
+    /// records' TTLs) sees the change. Each address it resolves to is tried in turn until one
+    /// accepts, starting from the first or, when rotating, from the next in a rotation shared by
+    /// every connection.
+    pub async fn connect(&self, target_addr: &str) -> std::io::Result<TcpStream> {
+        let mut addresses: Vec<SocketAddr> = tokio::net::lookup_host(target_addr).await?.collect();
+        if self.rotate_addresses && !addresses.is_empty() {
+            let first = NEXT_ADDRESS.fetch_add(1, Ordering::Relaxed) % addresses.len();
+            addresses.rotate_left(first);
+        }
+        let mut failure = None;
+        for address in addresses {
+            match TcpStream::connect(address).await {
+                Ok(socket) => {
+                    log::debug!("Connected to {target_addr} at {address}");
+                    self.socket.apply(&socket)?;
+                    return Ok(socket);
+                }
+                Err(e) => {
+                    log::debug!("Failed to connect to {target_addr} at {address}: {e}");
+                    failure = Some(e);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{target_addr} didn't resolve to any address"),
+            )
+        }))
+    }
+
+    /// Run the handshake over a freshly established connection
+    pub async fn perform(&self, target_framed: &mut Framed<TcpStream, Resp2>) -> Result<()> {
+        for (description, request) in self.requests() {
+            target_framed.send(request).await?;
+            match target_framed.next().await {
+                Some(Ok(BytesFrame::Error(e))) => {
+                    bail!(BackendUnavailable, "Backend {description} failed: {e}")
+                }
+                Some(Ok(_)) => log::debug!("Backend {description} succeeded"),
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!(
+                    BackendUnavailable,
+                    "Target closed the connection during {description}"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch a connection the handshake has been performed over to RESP3
+    async fn switch_to_resp3(
+        &self,
+        target_framed: &mut Framed<TcpStream, BulkStreamingCodec>,
+    ) -> Result<()> {
+        target_framed.send(command::request(["HELLO", "3"])).await?;
+        match target_framed.next().await {
+            Some(Ok(BytesFrame::Error(e))) => {
+                bail!(BackendUnavailable, "Backend HELLO 3 failed: {e}")
+            }
+            Some(Ok(_)) => log::debug!("Backend HELLO 3 succeeded"),
+            Some(Err(e)) => return Err(e.into()),
+            None => bail!(
+                BackendUnavailable,
+                "Target closed the connection during HELLO 3"
+            ),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct DbSelection {
+    db: Option<u32>,
+    in_transaction: bool,
+    /// A `SELECT` queued in the current transaction, which takes effect if it is executed
+    queued: Option<u32>,
+}
+
+/// The logical database a client connection has switched to with `SELECT`.
+///
+/// A backend which connects to the target on a client's behalf after the client has started
+/// (reconnecting after a failure or failover, or checking a connection out of a pool) must switch
+/// that connection to the same database before forwarding anything, or the client's commands
+/// silently land in the handshake's database instead. Clones share the same selection.
+#[derive(Clone, Debug, Default)]
+pub struct SelectedDb(Arc<Mutex<DbSelection>>);
+
+impl SelectedDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The database selected by the client, if it has selected one
+    pub fn get(&self) -> Option<u32> {
+        self.0.lock().ok().and_then(|selection| selection.db)
+    }
+
+    /// `handshake`, switching to the client's selected database instead of its own
+    pub fn handshake(&self, handshake: &Handshake) -> Handshake {
+        Handshake {
+            db: self.get().or(handshake.db),
+            ..handshake.clone()
+        }
+    }
+
+    /// Follow the effect of `request` on the selected database, as confirmed by its reply on
+    /// `responses`
+    pub fn track(&self, request: &BytesFrame, responses: ResponseStream) -> ResponseStream {
+        let Some(name) = command::name(request) else {
+            return responses;
+        };
+        let Ok(mut selection) = self.0.lock() else {
+            return responses;
+        };
+        match name.as_str() {
+            "MULTI" => {
+                selection.in_transaction = true;
+                selection.queued = None;
+            }
+            "DISCARD" => {
+                selection.in_transaction = false;
+                selection.queued = None;
+            }
+            "SELECT" => {
+                let db = match command::args(request) {
+                    Some([_, db]) => command::arg_bytes(db)
+                        .and_then(|db| std::str::from_utf8(db).ok())
+                        .and_then(|db| db.parse().ok()),
+                    _ => None,
+                };
+                if let Some(db) = db {
+                    if selection.in_transaction {
+                        selection.queued = Some(db);
+                    } else {
+                        let this = self.clone();
+                        return responses
+                            .inspect(move |frame| {
+                                if matches!(frame, BytesFrame::SimpleString(ok) if ok == "OK") {
+                                    this.set(db);
+                                }
+                            })
+                            .boxed();
+                    }
+                }
+            }
+            "EXEC" => {
+                selection.in_transaction = false;
+                if let Some(db) = selection.queued.take() {
+                    let this = self.clone();
+                    return responses
+                        .inspect(move |frame| {
+                            if matches!(frame, BytesFrame::Array(_)) {
+                                this.set(db);
+                            }
+                        })
+                        .boxed();
+                }
+            }
+            "RESET" => {
+                *selection = DbSelection::default();
+                let this = self.clone();
+                return responses
+                    .inspect(move |frame| {
+                        if matches!(frame, BytesFrame::SimpleString(reset) if reset == "RESET") {
+                            this.set(0);
+                        }
+                    })
+                    .boxed();
+            }
+            _ => {}
+        }
+        responses
+    }
+
+    fn set(&self, db: u32) {
+        if let Ok(mut selection) = self.0.lock() {
+            selection.db = Some(db);
+        }
+    }
+}
+
+/// An exponentially weighted moving average of a target connection's round trips, from writing
+/// a request to its reply's first frame, shared by the backend and its task
+#[derive(Debug, Default)]
+struct RoundTrips {
+    /// In microseconds, zero until a round trip has been measured
+    average_micros: AtomicU64,
+}
+
+impl RoundTrips {
+    fn record(&self, round_trip: Duration) {
+        let micros = (round_trip.as_micros() as u64).max(1);
+        let _ = self
+            .average_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => micros,
+                    average => average - average / ROUND_TRIP_WEIGHT + micros / ROUND_TRIP_WEIGHT,
+                })
+            });
+    }
+
+    fn average(&self) -> Option<Duration> {
+        match self.average_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
+
+/// Where the frames of a reply are queued for its response stream
+type ReplySender<F> = mpsc::Sender<Queued<F>>;
+
+struct RequestMessage<P: Protocol> {
+    frame: P::Frame,
+    response_sender: ReplySender<P::Frame>,
+    /// Counts the request against the backend's limit until its reply arrives
+    permit: Option<OwnedSemaphorePermit>,
+    /// When to stop waiting for the reply, answering with `Protocol::timeout_reply` instead
+    deadline: Option<Instant>,
+}
+
+struct CloseMessage<P: Protocol> {
+    conn_sender: tokio::sync::oneshot::Sender<Framed<TcpStream, P::Codec>>,
+}
+
+enum Message<P: Protocol> {
+    Request(RequestMessage<P>),
+    Close(CloseMessage<P>),
+}
+
+/// A connection to the target, multiplexing requests over it in order.
+///
+/// `poll_ready` is only ready once the backend task can take another request and fewer than
+/// `MAX_PENDING_REQUESTS` are awaiting replies, so a caller which waits for readiness (as
+/// `handle_connection` does) stops reading from its client while the target is saturated.
+///
+/// With a request timeout, a request whose reply hasn't arrived in time is answered with
+/// `Protocol::timeout_reply`, so a hung reply only holds up the requests behind it until their
+/// own deadlines. The late reply is discarded when it does arrive, keeping the replies after it
+/// paired with their requests.
+///
+/// Its `tower::load::Load` is its requests in flight weighted by the average round trip of its
+/// target connection, so backends can be balanced with `tower::balance::p2c` and the like.
+///
+/// Cloning a backend is cheap, giving another handle on the same target connection for layers
+/// which need cloneable services: the clones' requests are interleaved on it, share its
+/// `MAX_PENDING_REQUESTS`, and see what any of them changes about it (the selected database, a
+/// subscription, ...). `into_framed` or `close` on any clone ends it for all of them.
+pub struct Backend<P: Protocol> {
+    request_sender: PollSender<Queued<Message<P>>>,
+    /// Set once the target connection is lost, and closed once the backend task stops
+    disconnected: watch::Receiver<bool>,
+    pending: PollSemaphore,
+    permit: Option<OwnedSemaphorePermit>,
+    /// Whether `poll_ready` has reserved a slot in the request channel
+    reserved: bool,
+    /// Whether `poll_ready` is waiting for a slot, the request channel having been full
+    blocked: bool,
+    request_timeout: Option<Duration>,
+    /// Capacity of each request's response stream
+    response_stream_frames: usize,
+    round_trips: Arc<RoundTrips>,
+}
+
+/// A connection to a Redis target
+pub type Resp2Backend = Backend<Resp2Protocol>;
+
+fn stopped() -> Error {
+    Error::BackendUnavailable("Backend connection handler has stopped".to_string())
+}
+
+impl<P: Protocol> Backend<P> {
+    pub fn new(target_framed: Framed<TcpStream, P::Codec>) -> Self {
+        Self::with_capacities(target_framed, QueueCapacities::default())
+    }
+
+    /// Like `new`, with queues of the given `capacities`
+    pub fn with_capacities(
+        target_framed: Framed<TcpStream, P::Codec>,
+        capacities: QueueCapacities,
+    ) -> Self {
         let (request_sender, request_receiver) =
-            mpsc::channel::<Message>(MAX_OUTSTANDING_REQUEST_MESSAGES);
+            mpsc::channel::<Queued<Message<P>>>(capacities.requests);
+
+        let (lost, disconnected) = watch::channel(false);
+        let round_trips = Arc::new(RoundTrips::default());
+        tokio::spawn(backend_task(
+            target_framed,
+            request_receiver,
+            lost,
+            round_trips.clone(),
+        ));
+
+        Self {
+            request_sender: PollSender::new(request_sender),
+            disconnected,
+            pending: PollSemaphore::new(Arc::new(Semaphore::new(MAX_PENDING_REQUESTS))),
+            permit: None,
+            reserved: false,
+            blocked: false,
+            request_timeout: None,
+            response_stream_frames: capacities.response_stream_frames,
+            round_trips,
+        }
+    }
+
+    /// Stop waiting for a reply after `timeout`, except to blocking and push mode requests
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
 
-        tokio::spawn(backend_task(target_framed, request_receiver));
+    /// Stop taking requests, wait for the replies to those already sent, and take back the
+    /// connection, for handing it to something else (a pool, or a protocol other than the
+    /// backend's request/reply). Fails if the connection has failed or is in push mode
+    /// (subscribed or monitoring), since its replies would never end.
+    pub async fn into_framed(self) -> Result<Framed<TcpStream, P::Codec>> {
+        let Some(request_sender) = self.request_sender.get_ref().cloned() else {
+            bail!(BackendUnavailable, "Backend connection handler has stopped");
+        };
+        let (conn_sender, conn_receiver) = tokio::sync::oneshot::channel();
+        let close = Message::Close(CloseMessage { conn_sender });
+        QUEUES
+            .requests
+            .send(&request_sender, close)
+            .await
+            .map_err(|_| stopped())?;
+        conn_receiver.await.map_err(|_| {
+            Error::BackendUnavailable("Backend connection couldn't be handed over".to_string())
+        })
+    }
+
+    /// Resolves to true once the target connection has closed or failed, when any requests
+    /// awaiting replies are answered with `Protocol::disconnected_reply` and every later request
+    /// fails, or to false once the backend has stopped for any other reason
+    pub fn disconnected(&self) -> impl Future<Output = bool> + Send + 'static {
+        let mut disconnected = self.disconnected.clone();
+        async move { disconnected.wait_for(|lost| *lost).await.is_ok() }
+    }
 
-        Self { request_sender }
+    /// Whether the target connection has closed or failed
+    pub fn is_disconnected(&self) -> bool {
+        *self.disconnected.borrow()
+    }
+
+    /// Requests sent (by this backend or its clones) which are still awaiting their replies
+    pub fn in_flight(&self) -> usize {
+        let reserved = usize::from(self.permit.is_some());
+        MAX_PENDING_REQUESTS - self.pending.available_permits() - reserved
+    }
+
+    /// The moving average of the target connection's round trips (other than those of blocking
+    /// and push mode requests), once one has been measured
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trips.average()
+    }
+
+    /// Close the client connection `connection_id`, as counted in `stats`, once the target
+    /// connection is lost, so a client served by nothing but this backend reconnects rather than
+    /// having every command fail
+    pub fn close_client_on_disconnect(&self, stats: Arc<Stats>, connection_id: Uuid) {
+        let disconnected = self.disconnected();
+        tokio::spawn(async move {
+            if disconnected.await && stats.connections.close(connection_id) {
+                log::info!("Connection {connection_id} closing after losing its target connection");
+            }
+        });
+    }
+
+    /// Like `into_framed`, then shut the connection down cleanly
+    pub async fn close(self) -> Result<()> {
+        let mut framed = self.into_framed().await?;
+        framed
+            .close()
+            .await
+            .map_err(|e| Error::Protocol(e.to_string()))?;
+        BUFFERS.recycle(framed);
+        Ok(())
+    }
+}
+
+impl Resp2Backend {
+    /// Dial `target_addr` and start a backend over the new connection
+    pub async fn connect(target_addr: &str) -> Result<Self> {
+        Self::connect_with(target_addr, &Handshake::default()).await
+    }
+
+    /// Dial `target_addr`, perform `handshake`, and start a backend over the new connection
+    pub async fn connect_with(target_addr: &str, handshake: &Handshake) -> Result<Self> {
+        Self::connect_with_capacities(target_addr, handshake, QueueCapacities::default()).await
+    }
+
+    /// Like `connect_with`, starting a backend with queues of the given `capacities`
+    pub async fn connect_with_capacities(
+        target_addr: &str,
+        handshake: &Handshake,
+        capacities: QueueCapacities,
+    ) -> Result<Self> {
+        let target_socket = handshake.connect(target_addr).await.map_err(|e| {
+            Error::BackendUnavailable(format!("Failed to connect to {target_addr}: {e}"))
+        })?;
+        let mut target_framed = BUFFERS.framed::<BytesFrame, _, _>(target_socket, Resp2::default());
+        handshake.perform(&mut target_framed).await?;
+        let mut target_framed = target_framed.map_codec(|_| {
+            let codec = BulkStreamingCodec::new(handshake.stream_bulk_bytes);
+            if handshake.resp3 {
+                codec.speaking_resp3()
+            } else {
+                codec
+            }
+        });
+        if handshake.resp3 {
+            handshake.switch_to_resp3(&mut target_framed).await?;
+        }
+        Ok(Self::with_capacities(target_framed, capacities))
+    }
+}
+
+impl<P: Protocol> Clone for Backend<P> {
+    /// Another handle on the connection, which has reserved nothing yet
+    fn clone(&self) -> Self {
+        Self {
+            request_sender: self.request_sender.clone(),
+            disconnected: self.disconnected.clone(),
+            pending: self.pending.clone(),
+            permit: None,
+            reserved: false,
+            blocked: false,
+            request_timeout: self.request_timeout,
+            response_stream_frames: self.response_stream_frames,
+            round_trips: self.round_trips.clone(),
+        }
     }
 }
 
-impl Service<BytesFrame> for Resp2Backend {
-    type Response = Box<dyn Stream<Item = BytesFrame> + Unpin + Send>;
-    type Error = anyhow::Error;
+impl<P: Protocol> tower::load::Load for Backend<P> {
+    type Metric = f64;
+
+    /// The seconds the requests in flight, and one more, would take at the average round trip
+    fn load(&self) -> f64 {
+        let round_trip = self.round_trip().unwrap_or(DEFAULT_ROUND_TRIP);
+        (self.in_flight() + 1) as f64 * round_trip.as_secs_f64()
+    }
+}
+
+impl<P: Protocol> Service<P::Frame> for Backend<P> {
+    type Response = BoxStream<'static, P::Frame>;
+    type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.permit.is_none() {
+            match ready!(self.pending.poll_acquire(cx)) {
+                Some(permit) => self.permit = Some(permit),
+                None => {
+                    return Poll::Ready(Err(Error::BackendUnavailable(
+                        "Backend request limit closed".to_string(),
+                    )));
+                }
+            }
+        }
+        if !self.reserved {
+            let reserved = self.request_sender.poll_reserve(cx);
+            if reserved.is_pending() && !std::mem::replace(&mut self.blocked, true) {
+                QUEUES.requests.record_full();
+            }
+            ready!(reserved).map_err(|_| stopped())?;
+            self.blocked = false;
+            self.reserved = true;
+        }
         Poll::Ready(Ok(()))
     }
 
+    fn call(&mut self, req: P::Frame) -> Self::Future {
+        let (response_sender, response_receiver) = mpsc::channel(self.response_stream_frames);
+        let deadline = self
+            .request_timeout
+            .filter(|_| !P::starts_push_mode(&req) && !P::is_blocking(&req))
+            .map(|timeout| Instant::now() + timeout);
+        let request = Message::Request(RequestMessage {
+            frame: req,
+            response_sender,
+            permit: self.permit.take(),
+            deadline,
+        });
+
+        // Callers which skipped `poll_ready` have no reserved slot, so wait for one instead
+        let unreserved = if std::mem::take(&mut self.reserved) {
+            if self
+                .request_sender
+                .send_item(QUEUES.requests.enqueue(request))
+                .is_err()
+            {
+                return Box::pin(async {
+                    bail!(
+                        BackendUnavailable,
+                        "Failed to send request to handler: closed"
+                    )
+                });
+            }
+            None
+        } else {
+            Some((request, self.request_sender.get_ref().cloned()))
+        };
+        Box::pin(async move {
+            if let Some((request, request_sender)) = unreserved {
+                let Some(request_sender) = request_sender else {
+                    bail!(
+                        BackendUnavailable,
+                        "Failed to send request to handler: closed"
+                    );
+                };
+                if let Err(e) = QUEUES.requests.send(&request_sender, request).await {
+                    bail!(
+                        BackendUnavailable,
+                        "Failed to send request to handler: {}",
+                        e
+                    );
+                }
+            }
+            Ok(ReceiverStream::new(response_receiver)
+                .map(Queued::into_inner)
+                .boxed())
+        })
+    }
+}
+
+/// A target connection made when the first request arrives rather than up front, so an idle
+/// client holds no target socket and a down target doesn't fail its accept.
+///
+/// `poll_ready` dials the target (and performs the handshake) the first time it's called, which
+/// `handle_connection` only does once a command has arrived. If that fails, the service is still
+/// ready: the next call is answered with an error reply, and the one after that tries again.
+pub struct LazyBackend {
+    target_addr: Arc<str>,
+    handshake: Arc<Handshake>,
+    request_timeout: Option<Duration>,
+    capacities: QueueCapacities,
+    state: LazyState,
+}
+
+enum LazyState {
+    Idle,
+    Connecting(BoxFuture<'static, Result<Resp2Backend>>),
+    Connected(Resp2Backend),
+    Failed(Error),
+}
+
+impl LazyBackend {
+    pub fn new(target_addr: &str, handshake: Handshake) -> Self {
+        Self {
+            target_addr: target_addr.into(),
+            handshake: Arc::new(handshake),
+            request_timeout: None,
+            capacities: QueueCapacities::default(),
+            state: LazyState::Idle,
+        }
+    }
+
+    /// Size the connection's queues by `capacities`
+    pub fn with_capacities(mut self, capacities: QueueCapacities) -> Self {
+        self.capacities = capacities;
+        self
+    }
+
+    /// Give the connection a request timeout, as `Backend::with_request_timeout`
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+}
+
+impl Service<BytesFrame> for LazyBackend {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            match &mut self.state {
+                LazyState::Idle => {
+                    let target_addr = self.target_addr.clone();
+                    let handshake = self.handshake.clone();
+                    let (request_timeout, capacities) = (self.request_timeout, self.capacities);
+                    self.state = LazyState::Connecting(Box::pin(async move {
+                        let backend = Resp2Backend::connect_with_capacities(
+                            &target_addr,
+                            &handshake,
+                            capacities,
+                        )
+                        .await?;
+                        Ok(match request_timeout {
+                            Some(timeout) => backend.with_request_timeout(timeout),
+                            None => backend,
+                        })
+                    }));
+                }
+                LazyState::Connecting(connecting) => {
+                    self.state = match ready!(connecting.as_mut().poll(cx)) {
+                        Ok(backend) => {
+                            log::info!("Connected with target at: {}", self.target_addr);
+                            LazyState::Connected(backend)
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "Failed to connect with target at {}: {e}",
+                                self.target_addr
+                            );
+                            LazyState::Failed(e)
+                        }
+                    };
+                }
+                LazyState::Connected(backend) => return backend.poll_ready(cx),
+                LazyState::Failed(_) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
     fn call(&mut self, req: BytesFrame) -> Self::Future {
-        let request_sender = self.request_sender.clone();
+        match std::mem::replace(&mut self.state, LazyState::Idle) {
+            LazyState::Connected(mut backend) => {
+                let responses = backend.call(req);
+                self.state = LazyState::Connected(backend);
+                responses
+            }
+            LazyState::Failed(e) => {
+                let error = command::error(format!("ERR cabbage: target unavailable: {e}"));
+                Box::pin(async move { Ok(stream::once(async move { error }).boxed()) })
+            }
+            state => {
+                self.state = state;
+                Box::pin(async {
+                    bail!(
+                        BackendUnavailable,
+                        "Lazy backend called before it was ready"
+                    )
+                })
+            }
+        }
+    }
+}
+
+struct PendingResponse<F> {
+    /// Taken once the request has been answered with a timeout, its reply to be discarded
+    sender: Option<ReplySender<F>>,
+    starts_push_mode: bool,
+    blocking: bool,
+    deadline: Option<Instant>,
+    /// When the request was sent, for measuring its round trip
+    sent: Instant,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Correlates reply frames from the target with the requests which produced them.
+///
+/// Each request receives exactly one reply frame, after which its response stream is closed.
+/// Once a subscription or `MONITOR` is started, every subsequent frame belongs to that request's
+/// stream (which stays open) until the final unsubscription; requests made in the meantime get
+/// empty streams, their replies arriving in order on the push stream instead.
+///
+/// A request which times out keeps its place in line, so the reply it's still owed is matched
+/// to it (and dropped) rather than to the requests behind it, as does a request whose client has
+/// stopped waiting for its reply. Frames a RESP3 target pushes outside of push mode (such as
+/// invalidations) answer no request, so they're dropped.
+struct PendingResponses<P: Protocol> {
+    pending: VecDeque<PendingResponse<P::Frame>>,
+    push_sender: Option<ReplySender<P::Frame>>,
+    /// Where the rest of a reply streamed as several frames goes, once its first has arrived
+    /// (nowhere, if it's being discarded)
+    streaming: Option<Option<ReplySender<P::Frame>>>,
+    round_trips: Arc<RoundTrips>,
+}
+
+impl<P: Protocol> PendingResponses<P> {
+    fn new(round_trips: Arc<RoundTrips>) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            push_sender: None,
+            streaming: None,
+            round_trips,
+        }
+    }
+
+    fn expect(
+        &mut self,
+        request: &P::Frame,
+        sender: ReplySender<P::Frame>,
+        permit: Option<OwnedSemaphorePermit>,
+        deadline: Option<Instant>,
+    ) {
+        if self.push_sender.is_some() {
+            return;
+        }
+        self.pending.push_back(PendingResponse {
+            sender: Some(sender),
+            starts_push_mode: P::starts_push_mode(request),
+            blocking: P::is_blocking(request),
+            deadline,
+            sent: Instant::now(),
+            _permit: permit,
+        });
+    }
+
+    /// The earliest deadline of a request still awaiting its reply
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .iter()
+            .filter(|pending| pending.sender.is_some())
+            .filter_map(|pending| pending.deadline)
+            .min()
+    }
 
-        let fut = async move {
-            let (response_sender, response_receiver) =
-                mpsc::channel(MAX_OUTSTANDING_RESPONSE_STREAM_MESSAGES);
+    /// Answer every request whose deadline has passed by `now` with `Protocol::timeout_reply`
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .pending
+            .iter_mut()
+            .filter(|pending| pending.deadline.is_some_and(|deadline| deadline <= now))
+            .filter_map(|pending| pending.sender.take());
+        for sender in expired {
+            log::warn!("Target didn't reply in time, answering with a timeout");
+            // A full or closed channel means the client isn't waiting on it
+            let _ = sender.try_send(QUEUES.response_stream_frames.enqueue(P::timeout_reply()));
+        }
+    }
+
+    /// Whether every request's reply has arrived in full, outside of push mode
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.push_sender.is_none() && self.streaming.is_none()
+    }
 
-            let request = RequestMessage {
-                frame: req,
-                response_sender,
+    fn in_push_mode(&self) -> bool {
+        self.push_sender.is_some()
+    }
+
+    /// The response stream holding the connection for as long as the target pleases: the push
+    /// stream, or that of a blocking request next in line for a reply
+    fn held_by(&self) -> Option<ReplySender<P::Frame>> {
+        if let Some(push_sender) = &self.push_sender {
+            return Some(push_sender.clone());
+        }
+        self.pending
+            .front()
+            .filter(|pending| pending.blocking)
+            .and_then(|pending| pending.sender.clone())
+    }
+
+    /// Answer every request still awaiting its reply, and the push stream if there is one, with
+    /// `Protocol::disconnected_reply`. A reply cut off partway through streaming gets one too,
+    /// which the client's codec refuses, closing the client connection.
+    fn fail_all(&mut self) {
+        let senders = self.pending.drain(..).filter_map(|pending| pending.sender);
+        let streaming = self.streaming.take().flatten();
+        for sender in senders.chain(self.push_sender.take()).chain(streaming) {
+            // A full or closed channel means the client isn't waiting on it
+            let reply = QUEUES
+                .response_stream_frames
+                .enqueue(P::disconnected_reply());
+            let _ = sender.try_send(reply);
+        }
+    }
+
+    /// Where `frame` goes, if anywhere, `pushed` being whether the target pushed it unbidden
+    fn route(&mut self, frame: &P::Frame, pushed: bool) -> Option<ReplySender<P::Frame>> {
+        // Only a subscription takes pushed frames, such as invalidations; a request/reply
+        // connection has no stream for them
+        let subscribing = self
+            .pending
+            .front()
+            .is_some_and(|pending| pending.starts_push_mode);
+        if pushed && self.push_sender.is_none() && !subscribing {
+            log::debug!("Discarding a frame the target pushed outside of push mode: {frame:?}");
+            return None;
+        }
+        let part = P::reply_part(frame);
+        if let Some(part) = part
+            && !part.starts
+        {
+            let streaming = if part.ends {
+                self.streaming.take()
+            } else {
+                self.streaming.clone()
             };
-            if let Err(e) = request_sender.send(Message::Request(request)).await {
-                bail!("Failed to send request to handler: {}", e);
+            let Some(sender) = streaming else {
+                log::error!("Part of a reply received without its start: {frame:?}");
+                return None;
+            };
+            return sender;
+        }
+        let sender = self.route_whole(frame);
+        if part.is_some_and(|part| !part.ends) {
+            self.streaming = Some(sender.clone());
+        }
+        sender
+    }
+
+    /// Where a reply goes, by the request it answers, or the push stream
+    fn route_whole(&mut self, frame: &P::Frame) -> Option<ReplySender<P::Frame>> {
+        if let Some(push_sender) = &self.push_sender {
+            let sender = push_sender.clone();
+            if P::ends_push_mode(frame) {
+                self.push_sender = None;
             }
+            return Some(sender);
+        }
 
-            Ok(Box::new(ReceiverStream::new(response_receiver)) as Self::Response)
+        let Some(PendingResponse {
+            sender,
+            starts_push_mode,
+            blocking,
+            sent,
+            ..
+        }) = self.pending.pop_front()
+        else {
+            log::error!("Response received without a known request to associate: {frame:?}");
+            return None;
         };
-
-        Box::pin(fut)
+        // Those of requests answered whenever the target pleases say nothing of its speed
+        if !starts_push_mode && !blocking {
+            self.round_trips.record(sent.elapsed());
+        }
+        let Some(sender) = sender else {
+            log::debug!("Discarding a reply which arrived after its request timed out");
+            return None;
+        };
+        if starts_push_mode {
+            self.push_sender = Some(sender.clone());
+            self.pending.clear();
+        } else if sender.is_closed() {
+            log::debug!("Discarding a reply its client stopped waiting for");
+            return None;
+        }
+        Some(sender)
     }
 }
 
-async fn backend_task(
-    target_framed: Framed<TcpStream, Resp2>,
-    mut request_receiver: mpsc::Receiver<Message>,
-) -> anyhow::Result<()> {
+async fn backend_task<P: Protocol>(
+    target_framed: Framed<TcpStream, P::Codec>,
+    mut request_receiver: mpsc::Receiver<Queued<Message<P>>>,
+    disconnected: watch::Sender<bool>,
+    round_trips: Arc<RoundTrips>,
+) -> Result<()> {
     let (mut sender, mut receiver) = target_framed.split();
-    let mut current_response_sender: Option<mpsc::Sender<BytesFrame>> = None;
+    let mut pending = PendingResponses::<P>::new(round_trips);
 
     let mut response_next = Box::pin(receiver.next());
-    let mut close_sender: Option<tokio::sync::oneshot::Sender<Framed<TcpStream, Resp2>>> = None;
+    let mut close_sender: Option<tokio::sync::oneshot::Sender<Framed<TcpStream, P::Codec>>> = None;
+    // Whether the target connection closed, failed, or was given up on, rather than the backend
+    // being done with it
+    let mut lost = false;
+    // Requests are buffered while more are queued behind them, and flushed together, so a
+    // pipeline costs the target connection a write per batch rather than per request
+    let mut unflushed = 0;
     loop {
+        let deadline = pending.next_deadline();
+        let held_by = pending.held_by();
         tokio::select! {
-            request = request_receiver.recv() => {
-                match request {
-                    Some(Message::Request(RequestMessage { frame, response_sender })) => {
-                        current_response_sender = Some(response_sender);
-                        if let Err(e) = sender.send(frame).await {
+            // Once closing, no more requests are taken while the pending ones drain
+            request = request_receiver.recv(), if close_sender.is_none() => {
+                match request.map(Queued::into_inner) {
+                    Some(Message::Request(RequestMessage {
+                        frame,
+                        response_sender,
+                        permit,
+                        deadline,
+                    })) => {
+                        pending.expect(&frame, response_sender, permit, deadline);
+                        if let Err(e) = sender.feed(frame).await {
                             log::error!("Failed to send request to target: {}", e);
+                            lost = true;
                             break;
                         }
+                        unflushed += 1;
+                        if request_receiver.is_empty() || unflushed >= MAX_UNFLUSHED_REQUESTS {
+                            unflushed = 0;
+                            if let Err(e) = sender.flush().await {
+                                log::error!("Failed to send request to target: {}", e);
+                                lost = true;
+                                break;
+                            }
+                        }
                     }
                     Some(Message::Close(CloseMessage { conn_sender })) => {
+                        // Requests queued ahead of the close are only buffered so far
+                        if std::mem::take(&mut unflushed) > 0
+                            && let Err(e) = sender.flush().await
+                        {
+                            log::error!("Failed to send request to target: {}", e);
+                            lost = true;
+                            break;
+                        }
+                        if pending.in_push_mode() {
+                            // Dropping the sender tells the closer the connection can't be had
+                            log::warn!("Refusing to hand over a connection in push mode");
+                            continue;
+                        }
                         close_sender = Some(conn_sender);
-                        break;
+                        if pending.is_empty() {
+                            break;
+                        }
                     }
                     None => {
                         log::info!("Request channel closed, shutting down connection handler");
@@ -109,39 +1147,68 @@ async fn backend_task(
             response = &mut response_next => {
                 match response {
                     Some(Ok(frame)) => {
-                        if let Some(ref sender) = current_response_sender {
-                            if sender.send(frame).await.is_err() {
-                                drop(current_response_sender.take());
-                            }
-                        } else {
-                            log::error!(
-                                "Response received without a known request to associate: {:?}",
-                                frame);
+                        let (frame, pushed) = P::pushed(frame);
+                        if let Some(response_sender) = pending.route(&frame, pushed) {
+                            // A closed receiver just means the client no longer wants this reply
+                            let queue = &QUEUES.response_stream_frames;
+                            let _ = queue.send(&response_sender, frame).await;
                         }
 
+                        if close_sender.is_some() && pending.in_push_mode() {
+                            log::warn!("Refusing to hand over a connection entering push mode");
+                            close_sender = None;
+                        } else if close_sender.is_some() && pending.is_empty() {
+                            break;
+                        }
                         response_next = Box::pin(receiver.next());
                     }
                     Some(Err(e)) => {
                         log::error!("Error reading response from target: {}", e);
+                        lost = true;
                         break;
                     }
                     None => {
                         log::info!("Target connection closed");
+                        lost = true;
                         break;
                     }
                 }
             }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() => {
+                pending.expire(Instant::now());
+            }
+            // Only closing the connection frees the target of a subscription, `MONITOR`, or
+            // blocking command nobody is waiting on any more
+            _ = async { held_by.as_ref()?.closed().await; Some(()) }, if held_by.is_some() => {
+                log::info!("Closing target connection held for a client which has gone");
+                lost = true;
+                break;
+            }
         }
     }
 
+    if lost {
+        // Nothing more will arrive, so clients still awaiting replies are told why
+        pending.fail_all();
+        disconnected.send_replace(true);
+    }
+
     if let Some(conn_sender) = close_sender {
-        let framed = sender.reunite(receiver)?;
+        let framed = sender
+            .reunite(receiver)
+            .map_err(|_| Error::Protocol("Target connection halves didn't match".to_string()))?;
         if conn_sender.send(framed).is_err() {
-            bail!(concat!(
-                "Failed to send owned Framed back on receipt of ",
-                "close message in backend task"
-            ))
+            bail!(
+                BackendUnavailable,
+                concat!(
+                    "Failed to send owned Framed back on receipt of ",
+                    "close message in backend task"
+                )
+            )
         }
+    } else if let Ok(framed) = sender.reunite(receiver) {
+        BUFFERS.recycle(framed);
     }
     Ok(())
 }
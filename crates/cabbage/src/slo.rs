@@ -0,0 +1,279 @@
+//! Latency SLO alerting.
+//!
+//! An `SloMonitor` compares each command's p99 latency over the last interval, as the proxy
+//! measures it from a client's command to the last frame of its reply, with the threshold of the
+//! first `LatencySlo` matching the command, once per interval. When the p99 first exceeds the
+//! threshold it logs a warning carrying a JSON alert, and POSTs the same JSON to a `Webhook` if
+//! one is set; once the p99 is back within the threshold it does the same with a `recovered`
+//! alert:
+//!
+//! ```text
+//! {"alert":"latency_slo","state":"breached","command":"GET","p99_ms":12.3,"threshold_ms":5.0,
+//!  "window_secs":60,"commands":5120,"tenant":null}
+//! ```
+//!
+//! An interval in which a command ran fewer than `MIN_COMMANDS` times doesn't judge it, as its
+//! p99 would be noise. Webhooks are plain `http://` URLs, each alert being sent once, in the
+//! background, and dropped if the webhook fails.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+
+use crate::error::{Error, Result, bail};
+use crate::stats::Stats;
+
+/// Commands an interval must see of a command for its p99 to be judged
+static MIN_COMMANDS: u64 = 20;
+/// Time a webhook has to answer an alert
+static WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Alert when the p99 latency of the command named `command` (or of every command) exceeds
+/// `threshold`, parsed from `COMMAND=MS` (`*` for every command)
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct LatencySlo {
+    command: Option<String>,
+    threshold: Duration,
+}
+
+impl LatencySlo {
+    fn matches(&self, name: &str) -> bool {
+        match &self.command {
+            Some(command) => name == command,
+            None => true,
+        }
+    }
+}
+
+impl std::str::FromStr for LatencySlo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((command, ms)) = s.split_once('=') else {
+            bail!(Config, "Latency SLO '{s}' should be COMMAND=MS");
+        };
+        let ms: f64 = ms
+            .trim()
+            .parse()
+            .map_err(|_| Error::Config(format!("Invalid threshold '{ms}' in latency SLO")))?;
+        if !ms.is_finite() || ms <= 0.0 {
+            bail!(Config, "Latency SLO '{s}' must have a positive threshold");
+        }
+        let command = match command.trim() {
+            "*" => None,
+            command => Some(command.to_ascii_uppercase()),
+        };
+        Ok(Self {
+            command,
+            threshold: Duration::from_secs_f64(ms / 1000.0),
+        })
+    }
+}
+
+impl TryFrom<String> for LatencySlo {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Where alerts are POSTed, parsed from an `http://HOST[:PORT][/PATH]` URL
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Webhook {
+    /// The URL's authority, for the `Host` header
+    host: String,
+    /// `host` with its port, defaulting to 80
+    address: String,
+    path: String,
+}
+
+impl std::str::FromStr for Webhook {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("http://") else {
+            bail!(Config, "Webhook URL '{s}' should start with http://");
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            bail!(Config, "Webhook URL '{s}' doesn't name a host");
+        }
+        // A colon outside an IPv6 address's brackets starts the port
+        let has_port = host.rsplit_once(':').is_some_and(|(_, p)| !p.contains(']'));
+        let address = if has_port {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+        Ok(Self {
+            host: host.to_string(),
+            address,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Webhook {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.host, self.path)
+    }
+}
+
+impl Webhook {
+    /// POST the JSON `body`, failing unless the webhook answers with a 2xx status
+    pub async fn post(&self, body: &str) -> Result<()> {
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            body.len()
+        );
+        let exchange = async {
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = vec![0; 64];
+            let read = stream.read(&mut response).await?;
+            Ok::<_, Error>(String::from_utf8_lossy(&response[..read]).into_owned())
+        };
+        let response = tokio::time::timeout(WEBHOOK_TIMEOUT, exchange)
+            .await
+            .map_err(|_| Error::Timeout(WEBHOOK_TIMEOUT))??;
+        let status = response.split(' ').nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            bail!(
+                Other,
+                "Answered with '{}'",
+                response.lines().next().unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct SloMonitor {
+    slos: Vec<LatencySlo>,
+    interval: Duration,
+    webhook: Option<Webhook>,
+    tenant: Option<String>,
+    /// Each command's histogram as of the last check
+    previous: HashMap<String, Histogram<u64>>,
+    /// The commands whose p99 exceeded their threshold when last judged
+    breached: HashSet<String>,
+}
+
+impl SloMonitor {
+    /// Judge commands by the first of `slos` matching them, over every `interval`
+    pub fn new(slos: Vec<LatencySlo>, interval: Duration) -> Self {
+        Self {
+            slos,
+            interval,
+            webhook: None,
+            tenant: None,
+            previous: HashMap::new(),
+            breached: HashSet::new(),
+        }
+    }
+
+    /// Also POST each alert to `webhook`
+    pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+        self.webhook = Some(webhook);
+        self
+    }
+
+    /// Name `tenant` in each alert, the statistics being a tenant's
+    pub fn for_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Judge the latency `stats` records every interval until the process exits
+    pub async fn watch_periodically(mut self, stats: Arc<Stats>) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+        self.previous = stats.latency.histograms();
+        loop {
+            ticker.tick().await;
+            self.check(&stats);
+        }
+    }
+
+    fn check(&mut self, stats: &Stats) {
+        let current = stats.latency.histograms();
+        for (command, histogram) in &current {
+            let Some(slo) = self.slos.iter().find(|slo| slo.matches(command)) else {
+                continue;
+            };
+            // What was recorded during the interval, or everything if the histogram was reset
+            let mut window = histogram.clone();
+            if let Some(previous) = self.previous.get(command)
+                && window.subtract(previous).is_err()
+            {
+                window = histogram.clone();
+            }
+            if window.len() < MIN_COMMANDS {
+                continue;
+            }
+            let p99 = Duration::from_micros(window.value_at_quantile(0.99));
+            let breaching = p99 > slo.threshold;
+            if breaching == self.breached.contains(command) {
+                continue;
+            }
+            let state = if breaching {
+                self.breached.insert(command.clone());
+                "breached"
+            } else {
+                self.breached.remove(command);
+                "recovered"
+            };
+            self.alert(state, command, p99, slo.threshold, window.len());
+        }
+        self.previous = current;
+    }
+
+    fn alert(&self, state: &str, command: &str, p99: Duration, threshold: Duration, count: u64) {
+        let alert = json!({
+            "alert": "latency_slo",
+            "state": state,
+            "command": command,
+            "p99_ms": p99.as_secs_f64() * 1000.0,
+            "threshold_ms": threshold.as_secs_f64() * 1000.0,
+            "window_secs": self.interval.as_secs(),
+            "commands": count,
+            "tenant": self.tenant,
+        });
+        if state == "breached" {
+            log::warn!("Latency SLO breached: {alert}");
+        } else {
+            log::info!("Latency SLO recovered: {alert}");
+        }
+        if let Some(webhook) = self.webhook.clone() {
+            let body = alert.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = webhook.post(&body).await {
+                    log::warn!("Failed to send a latency SLO alert to {webhook}: {e:#}");
+                }
+            });
+        }
+    }
+}
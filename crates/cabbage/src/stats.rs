@@ -0,0 +1,1939 @@
+//! Proxy-wide statistics.
+//!
+//! A single `Stats` instance is shared by every connection's middleware stack; layers record into
+//! it and operators read it back through periodic reports or the `CABBAGE.*` admin commands.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hdrhistogram::Histogram;
+use redis_protocol::resp2::types::BytesFrame;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::bytes::Bytes;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::cluster::SLOT_COUNT;
+use crate::command;
+use crate::error::{Error, Result};
+use crate::listener::ClientAddr;
+
+/// Limit on distinct command names tracked, so junk commands can't grow the tables unboundedly
+static MAX_TRACKED_COMMANDS: usize = 1024;
+/// Name under which commands beyond `MAX_TRACKED_COMMANDS` are aggregated
+static OTHER_COMMANDS: &str = "OTHER";
+/// Number of the most recent slowlog entries included in reports
+static REPORTED_SLOWLOG_ENTRIES: usize = 10;
+/// Number of the busiest commands included in reports
+static REPORTED_TOP_COMMANDS: usize = 10;
+/// Number of the hottest keys included in reports
+static REPORTED_HOT_KEYS: usize = 10;
+/// Number of the most used namespaces included in reports
+static REPORTED_NAMESPACES: usize = 20;
+/// Number of the busiest cluster slots included in reports
+static REPORTED_SLOTS: usize = 10;
+/// Limit on distinct namespaces tracked, so keys without a shared structure can't grow the table
+/// unboundedly
+static MAX_TRACKED_NAMESPACES: usize = 1024;
+/// Name under which namespaces beyond `MAX_TRACKED_NAMESPACES` are aggregated
+static OTHER_NAMESPACES: &[u8] = b"(other)";
+/// Name under which keys without a `:` are counted
+static NO_NAMESPACE: &[u8] = b"(none)";
+/// Number of the most recent canary mismatches included in reports
+static REPORTED_CANARY_MISMATCHES: usize = 10;
+/// Lines buffered for each monitoring client before the slowest start missing some
+static MONITOR_BUFFER_LINES: usize = 4096;
+
+pub struct Stats {
+    pub started: Instant,
+    pub latency: LatencyHistograms,
+    pub commands: CommandCounts,
+    pub slowlog: Slowlog,
+    pub hot_keys: HotKeys,
+    pub key_space: KeySpace,
+    pub key_slots: KeySlots,
+    pub backend: BackendHealth,
+    pub connections: ConnectionCounts,
+    pub mirror: MirrorCounts,
+    pub canary: CanaryReport,
+    pub cache: CacheCounts,
+    pub command_cache: CommandCacheCounts,
+    pub pool: PoolCounts,
+    pub quotas: QuotaCounts,
+    pub saturation: SaturationCounts,
+    pub checksums: ChecksumCounts,
+    pub monitor: MonitorFeed,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            latency: LatencyHistograms::default(),
+            commands: CommandCounts::default(),
+            slowlog: Slowlog::default(),
+            hot_keys: HotKeys::default(),
+            key_space: KeySpace::default(),
+            key_slots: KeySlots::default(),
+            backend: BackendHealth::default(),
+            connections: ConnectionCounts::default(),
+            mirror: MirrorCounts::default(),
+            canary: CanaryReport::default(),
+            cache: CacheCounts::default(),
+            command_cache: CommandCacheCounts::default(),
+            pool: PoolCounts::default(),
+            quotas: QuotaCounts::default(),
+            saturation: SaturationCounts::default(),
+            checksums: ChecksumCounts::default(),
+            monitor: MonitorFeed::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Render a human-readable summary of everything tracked
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "connections: active={} accepted={} rejected={} refused={}",
+            self.connections.active(),
+            self.connections.accepted(),
+            self.connections.rejected(),
+            self.connections.refused()
+        );
+        let traffic = self.connections.traffic();
+        let _ = writeln!(report, "traffic: {traffic}");
+        if traffic.output_limit_disconnections() > 0 || traffic.dropped_replies() > 0 {
+            let _ = writeln!(
+                report,
+                "output buffers: disconnections={} dropped_replies={}",
+                traffic.output_limit_disconnections(),
+                traffic.dropped_replies()
+            );
+        }
+        for l in self.latency.summary() {
+            let _ = writeln!(
+                report,
+                "latency {}: count={} p50={:?} p95={:?} p99={:?}",
+                l.command, l.count, l.p50, l.p95, l.p99
+            );
+        }
+        for (rank, c) in self.commands.top(REPORTED_TOP_COMMANDS).iter().enumerate() {
+            let _ = writeln!(
+                report,
+                "top command #{} {}: calls={} errors={} total={:?} mean={:?}",
+                rank + 1,
+                c.command,
+                c.calls,
+                c.errors,
+                c.total_latency,
+                c.mean_latency()
+            );
+        }
+        if !self.slowlog.is_empty() {
+            let _ = writeln!(report, "slowlog: {} entries", self.slowlog.len());
+        }
+        for entry in self.slowlog.entries(REPORTED_SLOWLOG_ENTRIES) {
+            let _ = writeln!(
+                report,
+                "slowlog #{} conn={} {:?}: {}",
+                entry.id,
+                entry.connection_id,
+                entry.duration,
+                entry.command_line()
+            );
+        }
+        for (rank, hot) in self.hot_keys.top(REPORTED_HOT_KEYS).iter().enumerate() {
+            let _ = writeln!(
+                report,
+                "hot key #{} {}: sampled={} (±{})",
+                rank + 1,
+                String::from_utf8_lossy(&hot.key),
+                hot.count,
+                hot.error
+            );
+        }
+        let sampled_keys = self.key_space.sampled();
+        for ns in self.key_space.top(REPORTED_NAMESPACES) {
+            let _ = writeln!(
+                report,
+                "keyspace {}: sampled={} share={:.1}% reads={} writes={}",
+                String::from_utf8_lossy(&ns.namespace),
+                ns.keys(),
+                100.0 * ns.keys() as f64 / sampled_keys.max(1) as f64,
+                ns.reads,
+                ns.writes
+            );
+        }
+        let sampled_slots = self.key_slots.sampled();
+        for (slot, sampled) in self.key_slots.top(REPORTED_SLOTS) {
+            let _ = writeln!(
+                report,
+                "keyslot {slot}: sampled={sampled} share={:.1}%",
+                100.0 * sampled as f64 / sampled_slots.max(1) as f64,
+            );
+        }
+        let timeouts = self.backend.timeouts();
+        if timeouts > 0 {
+            let _ = writeln!(
+                report,
+                "backend: timeouts={timeouts} suspect={}",
+                self.backend.is_suspect()
+            );
+        }
+        if self.backend.health_checks() > 0 {
+            let _ = writeln!(
+                report,
+                "health checks: checks={} failed={} healthy={}",
+                self.backend.health_checks(),
+                self.backend.failed_health_checks(),
+                self.backend.is_healthy()
+            );
+        }
+        if self.mirror.sent() > 0 {
+            let _ = writeln!(
+                report,
+                "mirror: replied={} errors={} failed={} dropped={}",
+                self.mirror.replied(),
+                self.mirror.errors(),
+                self.mirror.failed(),
+                self.mirror.dropped()
+            );
+        }
+        if self.canary.compared() > 0 || self.canary.dropped() > 0 {
+            let _ = writeln!(
+                report,
+                "canary: compared={} mismatched={} dropped={}",
+                self.canary.compared(),
+                self.canary.mismatched(),
+                self.canary.dropped()
+            );
+        }
+        for mismatch in self.canary.mismatches(REPORTED_CANARY_MISMATCHES) {
+            let _ = writeln!(report, "canary mismatch {}", mismatch.description());
+        }
+        if self.cache.lookups() > 0 {
+            let _ = writeln!(
+                report,
+                "cache: hits={} misses={} invalidations={} evictions={} stale_hits={}",
+                self.cache.hits(),
+                self.cache.misses(),
+                self.cache.invalidations(),
+                self.cache.evictions(),
+                self.cache.stale_hits()
+            );
+        }
+        if self.command_cache.lookups() > 0 {
+            let _ = writeln!(
+                report,
+                "command cache: hits={} misses={} invalidations={}",
+                self.command_cache.hits(),
+                self.command_cache.misses(),
+                self.command_cache.invalidations()
+            );
+        }
+        if self.pool.checkouts() > 0 || self.pool.exhausted() > 0 {
+            let _ = writeln!(
+                report,
+                "pool: open={} ready={} checkouts={} warm={} waited={} mean_wait={:?} \
+                 temporary={} exhausted={}",
+                self.pool.open(),
+                self.pool.ready(),
+                self.pool.checkouts(),
+                self.pool.warm_checkouts(),
+                self.pool.waits(),
+                self.pool.mean_wait(),
+                self.pool.temporary(),
+                self.pool.exhausted()
+            );
+        }
+        if self.quotas.identities() > 0 {
+            let _ = writeln!(
+                report,
+                "quotas: identities={} rate_refusals={} in_flight_refusals={} bytes_refusals={}",
+                self.quotas.identities(),
+                self.quotas.rate_refusals(),
+                self.quotas.in_flight_refusals(),
+                self.quotas.bytes_refusals()
+            );
+        }
+        if self.saturation.is_limited() {
+            let _ = writeln!(
+                report,
+                "saturation: in_flight={} connection_refusals={} total_refusals={}",
+                self.saturation.in_flight(),
+                self.saturation.connection_refusals(),
+                self.saturation.total_refusals()
+            );
+        }
+        if self.checksums.is_checking() {
+            let _ = writeln!(
+                report,
+                "checksums: verified={} corrupted={} unchecked={}",
+                self.checksums.verified(),
+                self.checksums.corrupted(),
+                self.checksums.unchecked()
+            );
+        }
+        let queues: Vec<_> = QUEUES
+            .each()
+            .into_iter()
+            .map(|(name, queue)| format!("{name}={}/{}", queue.depth(), queue.full()))
+            .collect();
+        let _ = writeln!(report, "queues (depth/full): {}", queues.join(" "));
+        if let Some(runtime) = RuntimeSnapshot::current() {
+            let fields: Vec<_> = runtime
+                .fields()
+                .into_iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            let _ = writeln!(report, "runtime: {}", fields.join(" "));
+        }
+        report
+    }
+
+    /// The cumulative statistics, to be restored after a restart
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            taken: Some(SystemTime::now()),
+            connections_accepted: self.connections.accepted(),
+            connections_rejected: self.connections.rejected(),
+            connections_refused: self.connections.refused(),
+            traffic: self.connections.traffic().totals(),
+            commands: self.commands.top(usize::MAX),
+            slowlog: self
+                .slowlog
+                .entries(usize::MAX)
+                .into_iter()
+                .map(SlowlogSnapshot::from)
+                .collect(),
+        }
+    }
+
+    /// Count what `snapshot` (taken by an earlier proxy) counted on top of what's counted here
+    pub fn restore(&self, snapshot: StatsSnapshot) {
+        self.connections.restore(
+            snapshot.connections_accepted,
+            snapshot.connections_rejected,
+            snapshot.connections_refused,
+        );
+        self.connections.traffic().restore(&snapshot.traffic);
+        self.commands.restore(&snapshot.commands);
+        self.slowlog
+            .restore(snapshot.slowlog.into_iter().map(SlowlogEntry::from));
+    }
+
+    /// Write `snapshot` to `path`, replacing the file whole so a crash partway through can't
+    /// leave half a snapshot
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(&self.snapshot()).map_err(Error::other)?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        std::fs::write(&partial, json)
+            .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))?;
+        std::fs::rename(&partial, path)
+            .map_err(|e| Error::io(format!("Failed to replace {}", path.display()), e))
+    }
+}
+
+/// The cumulative statistics of a proxy (its connection, traffic, and per-command totals, and
+/// its slowlog) as kept in a file across restarts, so long-horizon dashboards and capacity data
+/// don't drop to zero with each one
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StatsSnapshot {
+    pub taken: Option<SystemTime>,
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    pub connections_refused: u64,
+    pub traffic: TrafficTotals,
+    pub commands: Vec<CommandTotals>,
+    /// Newest first
+    pub slowlog: Vec<SlowlogSnapshot>,
+}
+
+impl StatsSnapshot {
+    /// The snapshot saved at `path`, or `None` if there's none yet
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::io(format!("Failed to read {}", path.display()), e)),
+        };
+        serde_json::from_slice(&json).map(Some).map_err(|e| {
+            Error::Format(format!(
+                "{} isn't a statistics snapshot: {e}",
+                path.display()
+            ))
+        })
+    }
+}
+
+/// A slowlog entry as kept in a `StatsSnapshot`, its arguments as text
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SlowlogSnapshot {
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub duration: Duration,
+    pub args: Vec<String>,
+    pub connection_id: String,
+}
+
+impl From<SlowlogEntry> for SlowlogSnapshot {
+    fn from(entry: SlowlogEntry) -> Self {
+        Self {
+            id: entry.id,
+            timestamp: entry.timestamp,
+            duration: entry.duration,
+            args: entry
+                .args
+                .iter()
+                .map(|a| String::from_utf8_lossy(a).into_owned())
+                .collect(),
+            connection_id: entry.connection_id,
+        }
+    }
+}
+
+impl From<SlowlogSnapshot> for SlowlogEntry {
+    fn from(snapshot: SlowlogSnapshot) -> Self {
+        Self {
+            id: snapshot.id,
+            timestamp: snapshot.timestamp,
+            duration: snapshot.duration,
+            args: snapshot.args.into_iter().map(Bytes::from).collect(),
+            connection_id: snapshot.connection_id,
+        }
+    }
+}
+
+/// Periodically save `Stats::snapshot` to `path` until the process exits
+pub async fn save_periodically(stats: Arc<Stats>, path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = stats.save(&path) {
+            log::warn!("Failed to save statistics: {e}");
+        }
+    }
+}
+
+/// Periodically log `Stats::report` until the process exits, labelled with `tenant` if the
+/// stats are a tenant's
+pub async fn log_periodically(stats: Arc<Stats>, interval: Duration, tenant: Option<String>) {
+    let label = match tenant {
+        Some(tenant) => format!("stats[{tenant}]"),
+        None => "stats".to_string(),
+    };
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        for line in stats.report().lines() {
+            log::info!("{label}: {line}");
+        }
+    }
+}
+
+/// A client connection currently being served
+#[derive(Clone, Debug)]
+pub struct ClientConnection {
+    pub id: Uuid,
+    pub addr: ClientAddr,
+    pub since: Instant,
+    /// The name the client set with `CLIENT SETNAME`
+    pub name: Option<String>,
+    pub traffic: Arc<Traffic>,
+    /// Closes the connection once the replies to the commands it has sent are written
+    closer: CancellationToken,
+    /// Closes the connection at once
+    killer: CancellationToken,
+}
+
+/// Bytes and frames exchanged with clients, by one connection or across all of them
+#[derive(Debug, Default)]
+pub struct Traffic {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    commands: AtomicU64,
+    responses: AtomicU64,
+    output_limit_disconnections: AtomicU64,
+    dropped_replies: AtomicU64,
+    /// The totals a connection's traffic also counts towards
+    total: Option<Arc<Traffic>>,
+}
+
+impl Traffic {
+    /// Count a command of `bytes` read from the client
+    pub fn record_command(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_command(bytes);
+        }
+    }
+
+    /// Count a response frame of `bytes` written to the client
+    pub fn record_response(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_response(bytes);
+        }
+    }
+
+    /// Count bytes relayed without being decoded, as in passthrough mode
+    pub fn record_relayed(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_relayed(bytes_in, bytes_out);
+        }
+    }
+
+    /// Count a connection closed for letting too many replies wait for it
+    pub fn record_output_limit_disconnection(&self) {
+        self.output_limit_disconnections
+            .fetch_add(1, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_output_limit_disconnection();
+        }
+    }
+
+    /// Count a reply dropped for want of room in the client's output buffer
+    pub fn record_dropped_reply(&self) {
+        self.dropped_replies.fetch_add(1, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.record_dropped_reply();
+        }
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    pub fn responses(&self) -> u64 {
+        self.responses.load(Ordering::Relaxed)
+    }
+
+    pub fn output_limit_disconnections(&self) -> u64 {
+        self.output_limit_disconnections.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_replies(&self) -> u64 {
+        self.dropped_replies.load(Ordering::Relaxed)
+    }
+
+    pub fn totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            bytes_in: self.bytes_in(),
+            bytes_out: self.bytes_out(),
+            commands: self.commands(),
+            responses: self.responses(),
+            output_limit_disconnections: self.output_limit_disconnections(),
+            dropped_replies: self.dropped_replies(),
+        }
+    }
+
+    /// Count `totals` on top of what's counted here (and only here)
+    pub fn restore(&self, totals: &TrafficTotals) {
+        self.bytes_in.fetch_add(totals.bytes_in, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(totals.bytes_out, Ordering::Relaxed);
+        self.commands.fetch_add(totals.commands, Ordering::Relaxed);
+        self.responses
+            .fetch_add(totals.responses, Ordering::Relaxed);
+        self.output_limit_disconnections
+            .fetch_add(totals.output_limit_disconnections, Ordering::Relaxed);
+        self.dropped_replies
+            .fetch_add(totals.dropped_replies, Ordering::Relaxed);
+    }
+}
+
+/// A `Traffic`'s counts at one time
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TrafficTotals {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub commands: u64,
+    pub responses: u64,
+    pub output_limit_disconnections: u64,
+    pub dropped_replies: u64,
+}
+
+impl std::fmt::Display for Traffic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "commands={} responses={} bytes_in={} bytes_out={}",
+            self.commands(),
+            self.responses(),
+            self.bytes_in(),
+            self.bytes_out()
+        )
+    }
+}
+
+/// Client connection counters, maintained by the accept loop
+#[derive(Default)]
+pub struct ConnectionCounts {
+    accepted: AtomicU64,
+    active: Arc<Mutex<HashMap<Uuid, ClientConnection>>>,
+    rejected: AtomicU64,
+    refused: AtomicU64,
+    traffic: Arc<Traffic>,
+}
+
+/// Keeps a connection counted as active until dropped
+pub struct OpenConnection {
+    id: Uuid,
+    active: Arc<Mutex<HashMap<Uuid, ClientConnection>>>,
+    traffic: Arc<Traffic>,
+    killer: CancellationToken,
+}
+
+impl OpenConnection {
+    /// Where the connection's traffic is counted
+    pub fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    /// Resolves once the connection is killed with `ConnectionCounts::kill`
+    pub async fn killed(&self) {
+        self.killer.cancelled().await
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+impl ConnectionCounts {
+    /// Count a newly accepted connection, active for as long as the returned guard lives and
+    /// closed by `close` cancelling `closer`
+    pub fn open(&self, id: Uuid, addr: ClientAddr, closer: CancellationToken) -> OpenConnection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        let traffic = Arc::new(Traffic {
+            total: Some(self.traffic.clone()),
+            ..Traffic::default()
+        });
+        let killer = CancellationToken::new();
+        if let Ok(mut active) = self.active.lock() {
+            active.insert(
+                id,
+                ClientConnection {
+                    id,
+                    addr,
+                    since: Instant::now(),
+                    name: None,
+                    traffic: traffic.clone(),
+                    closer,
+                    killer: killer.clone(),
+                },
+            );
+        }
+        OpenConnection {
+            id,
+            active: self.active.clone(),
+            traffic,
+            killer,
+        }
+    }
+
+    /// Record the name an active connection's client has set for itself
+    pub fn set_name(&self, id: Uuid, name: Option<String>) {
+        if let Ok(mut active) = self.active.lock()
+            && let Some(connection) = active.get_mut(&id)
+        {
+            connection.name = name;
+        }
+    }
+
+    /// Close an active connection once the replies to the commands it has sent are written,
+    /// returning whether there was one with `id`
+    pub fn close(&self, id: Uuid) -> bool {
+        let Ok(active) = self.active.lock() else {
+            return false;
+        };
+        let Some(connection) = active.get(&id) else {
+            return false;
+        };
+        connection.closer.cancel();
+        true
+    }
+
+    /// Close an active connection at once, dropping any replies still to be written and its
+    /// target connections, returning whether there was one with `id`
+    pub fn kill(&self, id: Uuid) -> bool {
+        let Ok(active) = self.active.lock() else {
+            return false;
+        };
+        let Some(connection) = active.get(&id) else {
+            return false;
+        };
+        connection.killer.cancel();
+        true
+    }
+
+    /// The connections currently active, oldest first
+    pub fn list(&self) -> Vec<ClientConnection> {
+        let Ok(active) = self.active.lock() else {
+            return vec![];
+        };
+        let mut list: Vec<ClientConnection> = active.values().cloned().collect();
+        list.sort_by_key(|c| c.since);
+        list
+    }
+
+    /// Count a connection turned away because the connection limit was reached
+    pub fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection closed on accept because its source address isn't allowed
+    pub fn record_refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.lock().map(|a| a.len()).unwrap_or_default()
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Traffic across every connection, open or closed
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Count connections accepted, rejected, and refused by an earlier proxy on top of these
+    pub fn restore(&self, accepted: u64, rejected: u64, refused: u64) {
+        self.accepted.fetch_add(accepted, Ordering::Relaxed);
+        self.rejected.fetch_add(rejected, Ordering::Relaxed);
+        self.refused.fetch_add(refused, Ordering::Relaxed);
+    }
+}
+
+/// Outcomes of requests copied to a shadow target
+#[derive(Default)]
+pub struct MirrorCounts {
+    replied: AtomicU64,
+    errors: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl MirrorCounts {
+    /// Count a request the shadow answered
+    pub fn record_replied(&self) {
+        self.replied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request the shadow answered with an error
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request which couldn't be dispatched to the shadow
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a request not mirrored because the shadow had fallen too far behind
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replied(&self) -> u64 {
+        self.replied.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Every request mirrored or meant to be
+    pub fn sent(&self) -> u64 {
+        self.replied() + self.errors() + self.failed() + self.dropped()
+    }
+}
+
+/// The live feed of commands sent through the proxy, as `MONITOR` shows them
+pub struct MonitorFeed {
+    lines: broadcast::Sender<Bytes>,
+}
+
+impl Default for MonitorFeed {
+    fn default() -> Self {
+        Self {
+            lines: broadcast::channel(MONITOR_BUFFER_LINES).0,
+        }
+    }
+}
+
+impl MonitorFeed {
+    /// Whether any client is monitoring, so lines are worth formatting
+    pub fn is_watched(&self) -> bool {
+        self.lines.receiver_count() > 0
+    }
+
+    /// Show `line` to every monitoring client
+    pub fn publish(&self, line: Bytes) {
+        // Failing just means nobody is monitoring any more
+        let _ = self.lines.send(line);
+    }
+
+    /// Start receiving the lines published from now on
+    pub fn watch(&self) -> broadcast::Receiver<Bytes> {
+        self.lines.subscribe()
+    }
+}
+
+/// The Tokio runtime's view of the proxy's tasks, for diagnosing stalls which don't show at the
+/// RESP layer (a worker stuck in one task, or work piling up in the queues).
+///
+/// Poll times and per-worker queues are only measured by builds with `--cfg tokio_unstable` in
+/// `RUSTFLAGS`.
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeSnapshot {
+    pub workers: usize,
+    /// Tasks spawned and not yet finished
+    pub alive_tasks: usize,
+    /// Tasks waiting in the runtime's shared queue
+    pub global_queue_depth: usize,
+    /// Time the workers have spent running tasks, summed
+    pub busy: Duration,
+    /// Times the workers have run out of work and parked
+    pub parks: u64,
+    /// Tasks waiting in the workers' own queues
+    #[cfg(tokio_unstable)]
+    pub local_queue_depth: usize,
+    /// Tasks spawned since the runtime started
+    #[cfg(tokio_unstable)]
+    pub spawned_tasks: u64,
+    /// The workers' mean time per poll, averaged
+    #[cfg(tokio_unstable)]
+    pub mean_poll_time: Duration,
+}
+
+impl RuntimeSnapshot {
+    /// The current runtime's metrics, if called from within one
+    pub fn current() -> Option<Self> {
+        let metrics = tokio::runtime::Handle::try_current().ok()?.metrics();
+        let workers = metrics.num_workers();
+        Some(Self {
+            workers,
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            busy: (0..workers)
+                .map(|worker| metrics.worker_total_busy_duration(worker))
+                .sum(),
+            parks: (0..workers)
+                .map(|worker| metrics.worker_park_count(worker))
+                .sum(),
+            #[cfg(tokio_unstable)]
+            local_queue_depth: (0..workers)
+                .map(|worker| metrics.worker_local_queue_depth(worker))
+                .sum(),
+            #[cfg(tokio_unstable)]
+            spawned_tasks: metrics.spawned_tasks_count(),
+            #[cfg(tokio_unstable)]
+            mean_poll_time: (0..workers)
+                .map(|worker| metrics.worker_mean_poll_time(worker))
+                .sum::<Duration>()
+                / workers.max(1) as u32,
+        })
+    }
+
+    /// Each metric's name and value
+    pub fn fields(&self) -> Vec<(&'static str, u128)> {
+        #[allow(unused_mut)]
+        let mut fields = vec![
+            ("workers", self.workers as u128),
+            ("alive_tasks", self.alive_tasks as u128),
+            ("global_queue_depth", self.global_queue_depth as u128),
+            ("busy_usec", self.busy.as_micros()),
+            ("parks", u128::from(self.parks)),
+        ];
+        #[cfg(tokio_unstable)]
+        fields.extend([
+            ("local_queue_depth", self.local_queue_depth as u128),
+            ("spawned_tasks", u128::from(self.spawned_tasks)),
+            ("mean_poll_usec", self.mean_poll_time.as_micros()),
+        ]);
+        fields
+    }
+}
+
+/// The bounded queues between each client connection, the backend serving it, and the target
+/// connection, as `QueueCapacities` sizes them. Like the runtime's, they're counted process-wide,
+/// every tenant's together.
+pub static QUEUES: PipelineQueues = PipelineQueues {
+    response_streams: QueueCounts::new(),
+    response_stream_frames: QueueCounts::new(),
+    requests: QueueCounts::new(),
+};
+
+pub struct PipelineQueues {
+    /// Replies each client connection has outstanding, not yet being forwarded to it
+    pub response_streams: QueueCounts,
+    /// Frames of each reply from the target not yet taken for its client
+    pub response_stream_frames: QueueCounts,
+    /// Requests not yet taken by their target connection
+    pub requests: QueueCounts,
+}
+
+impl PipelineQueues {
+    /// Each queue's name, as in `QueueCapacities`, and counts, in order from the client
+    pub fn each(&self) -> [(&'static str, &QueueCounts); 3] {
+        [
+            ("response_streams", &self.response_streams),
+            ("response_stream_frames", &self.response_stream_frames),
+            ("requests", &self.requests),
+        ]
+    }
+}
+
+/// Items in one kind of queue, summed over every queue of the kind, and the times a sender found
+/// one full. A queue which is often full is where the pipeline saturates: everything before it
+/// waits on whatever takes from it.
+pub struct QueueCounts {
+    depth: AtomicU64,
+    full: AtomicU64,
+}
+
+impl QueueCounts {
+    const fn new() -> Self {
+        Self {
+            depth: AtomicU64::new(0),
+            full: AtomicU64::new(0),
+        }
+    }
+
+    /// `item`, counted as queued until it's taken out again with `Queued::into_inner` (or
+    /// dropped, with its queue)
+    pub fn enqueue<T>(&'static self, item: T) -> Queued<T> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        Queued {
+            item,
+            _slot: QueueSlot(self),
+        }
+    }
+
+    /// Count a sender finding a queue full, and so waiting for room
+    pub fn record_full(&self) {
+        self.full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Queue `item` on `sender`, counting the queue full if it has to wait for room
+    pub async fn send<T>(
+        &'static self,
+        sender: &mpsc::Sender<Queued<T>>,
+        item: T,
+    ) -> Result<(), mpsc::error::SendError<Queued<T>>> {
+        match sender.try_send(self.enqueue(item)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(queued)) => {
+                self.record_full();
+                sender.send(queued).await
+            }
+            Err(mpsc::error::TrySendError::Closed(queued)) => Err(mpsc::error::SendError(queued)),
+        }
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn full(&self) -> u64 {
+        self.full.load(Ordering::Relaxed)
+    }
+}
+
+/// An item counted into a queue by `QueueCounts::enqueue`
+pub struct Queued<T> {
+    item: T,
+    _slot: QueueSlot,
+}
+
+impl<T> Queued<T> {
+    /// The item, counted out of its queue
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+}
+
+struct QueueSlot(&'static QueueCounts);
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.0.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Read cache activity
+#[derive(Default)]
+pub struct CacheCounts {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+    evictions: AtomicU64,
+    stale_hits: AtomicU64,
+}
+
+impl CacheCounts {
+    /// Count a read answered from the cache
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a cacheable read which had to go to the target
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a cached reply dropped because its key was written
+    pub fn record_invalidation(&self) {
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a cached reply dropped to make room for another
+    pub fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a read answered from the cache, however old, because the target couldn't answer
+    pub fn record_stale_hit(&self) {
+        self.stale_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn stale_hits(&self) -> u64 {
+        self.stale_hits.load(Ordering::Relaxed)
+    }
+
+    /// Every cacheable read, hit or miss
+    pub fn lookups(&self) -> u64 {
+        self.hits() + self.misses()
+    }
+}
+
+/// Activity of the cache of the target's command table
+#[derive(Default)]
+pub struct CommandCacheCounts {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl CommandCacheCounts {
+    /// Count a `COMMAND` request answered from the cache
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a cacheable `COMMAND` request which had to go to the target
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the cache being cleared after a failed health check
+    pub fn record_invalidation(&self) {
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn invalidations(&self) -> u64 {
+        self.invalidations.load(Ordering::Relaxed)
+    }
+
+    /// Every cacheable request, hit or miss
+    pub fn lookups(&self) -> u64 {
+        self.hits() + self.misses()
+    }
+}
+
+/// Target connection pool activity
+#[derive(Default)]
+pub struct PoolCounts {
+    checkouts: AtomicU64,
+    warm_checkouts: AtomicU64,
+    waits: AtomicU64,
+    wait_micros: AtomicU64,
+    temporary: AtomicU64,
+    exhausted: AtomicU64,
+    open: AtomicU64,
+    ready: AtomicU64,
+}
+
+impl PoolCounts {
+    /// Count a connection handed to a client, `warm` if it was ready-made, after queueing for
+    /// `waited` if it had to
+    pub fn record_checkout(&self, warm: bool, waited: Option<Duration>) {
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        if warm {
+            self.warm_checkouts.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(waited) = waited {
+            self.waits.fetch_add(1, Ordering::Relaxed);
+            self.wait_micros
+                .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a connection made beyond the pool's limit
+    pub fn record_temporary(&self) {
+        self.temporary.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a client turned away with the pool at its limit
+    pub fn record_exhausted(&self) {
+        self.exhausted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note the connections open (handed out or being made) and ready
+    pub fn set_sizes(&self, open: usize, ready: usize) {
+        self.open.store(open as u64, Ordering::Relaxed);
+        self.ready.store(ready as u64, Ordering::Relaxed);
+    }
+
+    pub fn checkouts(&self) -> u64 {
+        self.checkouts.load(Ordering::Relaxed)
+    }
+
+    /// Checkouts handed a ready-made connection
+    pub fn warm_checkouts(&self) -> u64 {
+        self.warm_checkouts.load(Ordering::Relaxed)
+    }
+
+    /// Checkouts which queued for room
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
+
+    /// Time spent queueing, across every checkout which did
+    pub fn total_wait(&self) -> Duration {
+        Duration::from_micros(self.wait_micros.load(Ordering::Relaxed))
+    }
+
+    /// Mean time queued, among checkouts which queued
+    pub fn mean_wait(&self) -> Duration {
+        let micros = self.wait_micros.load(Ordering::Relaxed);
+        Duration::from_micros(micros.checked_div(self.waits()).unwrap_or(0))
+    }
+
+    pub fn temporary(&self) -> u64 {
+        self.temporary.load(Ordering::Relaxed)
+    }
+
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Connections handed out and still open, or being made, as of the last check
+    pub fn open(&self) -> u64 {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Connections ready to hand out, as of the last check
+    pub fn ready(&self) -> u64 {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// Commands refused for exceeding a client identity's quota
+#[derive(Default)]
+pub struct QuotaCounts {
+    identities: AtomicU64,
+    rate_refusals: AtomicU64,
+    in_flight_refusals: AtomicU64,
+    bytes_refusals: AtomicU64,
+}
+
+impl QuotaCounts {
+    /// Note the client identities whose usage is tracked
+    pub fn set_identities(&self, identities: usize) {
+        self.identities.store(identities as u64, Ordering::Relaxed);
+    }
+
+    /// Count a command over its identity's commands per second
+    pub fn record_rate_refusal(&self) {
+        self.rate_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a command over its identity's commands in flight
+    pub fn record_in_flight_refusal(&self) {
+        self.in_flight_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a command from an identity which has spent the day's bytes
+    pub fn record_bytes_refusal(&self) {
+        self.bytes_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn identities(&self) -> u64 {
+        self.identities.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_refusals(&self) -> u64 {
+        self.rate_refusals.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight_refusals(&self) -> u64 {
+        self.in_flight_refusals.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_refusals(&self) -> u64 {
+        self.bytes_refusals.load(Ordering::Relaxed)
+    }
+}
+
+/// Commands in flight, and those refused for there being too many
+#[derive(Default)]
+pub struct SaturationCounts {
+    limited: AtomicBool,
+    in_flight: AtomicU64,
+    connection_refusals: AtomicU64,
+    total_refusals: AtomicU64,
+}
+
+impl SaturationCounts {
+    /// Note that commands in flight are limited, so there's something to report
+    pub fn set_limited(&self) {
+        self.limited.store(true, Ordering::Relaxed);
+    }
+
+    /// Note the commands in flight across every connection
+    pub fn set_in_flight(&self, in_flight: usize) {
+        self.in_flight.store(in_flight as u64, Ordering::Relaxed);
+    }
+
+    /// Count a command over its connection's commands in flight
+    pub fn record_connection_refusal(&self) {
+        self.connection_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a command over the commands in flight across every connection
+    pub fn record_total_refusal(&self) {
+        self.total_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.limited.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_refusals(&self) -> u64 {
+        self.connection_refusals.load(Ordering::Relaxed)
+    }
+
+    pub fn total_refusals(&self) -> u64 {
+        self.total_refusals.load(Ordering::Relaxed)
+    }
+}
+
+/// Values read back and checked against their checksums
+#[derive(Default)]
+pub struct ChecksumCounts {
+    checking: AtomicBool,
+    verified: AtomicU64,
+    corrupted: AtomicU64,
+    unchecked: AtomicU64,
+}
+
+impl ChecksumCounts {
+    /// Note that values are checked, so there's something to report
+    pub fn set_checking(&self) {
+        self.checking.store(true, Ordering::Relaxed);
+    }
+
+    /// Count a value which matched its checksum
+    pub fn record_verified(&self) {
+        self.verified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a value which didn't match its checksum, or was missing one it needed
+    pub fn record_corrupted(&self) {
+        self.corrupted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a value passed through without a checksum to check
+    pub fn record_unchecked(&self) {
+        self.unchecked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn is_checking(&self) -> bool {
+        self.checking.load(Ordering::Relaxed)
+    }
+
+    pub fn verified(&self) -> u64 {
+        self.verified.load(Ordering::Relaxed)
+    }
+
+    pub fn corrupted(&self) -> u64 {
+        self.corrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn unchecked(&self) -> u64 {
+        self.unchecked.load(Ordering::Relaxed)
+    }
+}
+
+/// Limit on canary mismatches retained, newest first
+static CANARY_MISMATCHES_RETAINED: usize = 128;
+
+/// A command the canary target answered differently from the primary
+#[derive(Clone, Debug)]
+pub struct CanaryMismatch {
+    pub command: String,
+    pub key: Option<Bytes>,
+    /// Where the replies first diverge
+    pub summary: String,
+}
+
+impl CanaryMismatch {
+    /// The command, its first key if any, and the summary on one line
+    pub fn description(&self) -> String {
+        match &self.key {
+            Some(key) => format!(
+                "{} {}: {}",
+                self.command,
+                String::from_utf8_lossy(key),
+                self.summary
+            ),
+            None => format!("{}: {}", self.command, self.summary),
+        }
+    }
+}
+
+/// Results of comparing the canary target's replies with the primary's
+#[derive(Default)]
+pub struct CanaryReport {
+    compared: AtomicU64,
+    mismatched: AtomicU64,
+    dropped: AtomicU64,
+    recent: Mutex<VecDeque<CanaryMismatch>>,
+}
+
+impl CanaryReport {
+    /// Count a command both targets answered alike
+    pub fn record_match(&self) {
+        self.compared.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mismatch(&self, mismatch: CanaryMismatch) {
+        self.compared.fetch_add(1, Ordering::Relaxed);
+        self.mismatched.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut recent) = self.recent.lock() {
+            recent.push_front(mismatch);
+            recent.truncate(CANARY_MISMATCHES_RETAINED);
+        }
+    }
+
+    /// Count a command not compared because the canary had fallen too far behind
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn compared(&self) -> u64 {
+        self.compared.load(Ordering::Relaxed)
+    }
+
+    pub fn mismatched(&self) -> u64 {
+        self.mismatched.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Up to `count` of the most recent mismatches, newest first
+    pub fn mismatches(&self, count: usize) -> Vec<CanaryMismatch> {
+        self.recent
+            .lock()
+            .map(|recent| recent.iter().take(count).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Signs of trouble with the target, observed by the middleware
+#[derive(Default)]
+pub struct BackendHealth {
+    timeouts: AtomicU64,
+    suspect: AtomicBool,
+    health_checks: AtomicU64,
+    failed_health_checks: AtomicU64,
+    unhealthy: AtomicBool,
+}
+
+impl BackendHealth {
+    /// Note a request which the target failed to answer in time, flagging it for health checking
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+        self.suspect.store(true, Ordering::Relaxed);
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Whether the target has misbehaved since it was last found healthy
+    pub fn is_suspect(&self) -> bool {
+        self.suspect.load(Ordering::Relaxed)
+    }
+
+    /// Record that the target has been checked and found healthy
+    pub fn mark_healthy(&self) {
+        self.suspect.store(false, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of a health check
+    pub fn record_health_check(&self, passed: bool) {
+        self.health_checks.fetch_add(1, Ordering::Relaxed);
+        if passed {
+            self.unhealthy.store(false, Ordering::Relaxed);
+            self.mark_healthy();
+        } else {
+            self.failed_health_checks.fetch_add(1, Ordering::Relaxed);
+            self.unhealthy.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the last health check passed (or none has run)
+    pub fn is_healthy(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed)
+    }
+
+    pub fn health_checks(&self) -> u64 {
+        self.health_checks.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_health_checks(&self) -> u64 {
+        self.failed_health_checks.load(Ordering::Relaxed)
+    }
+}
+
+/// Latency percentiles for a single command name
+#[derive(Clone, Debug)]
+pub struct CommandLatency {
+    pub command: String,
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// End-to-end latency histograms, keyed by command name, recorded in microseconds
+#[derive(Default)]
+pub struct LatencyHistograms {
+    by_command: Mutex<HashMap<String, Histogram<u64>>>,
+}
+
+impl LatencyHistograms {
+    pub fn record(&self, command: &str, latency: Duration) {
+        let Ok(mut by_command) = self.by_command.lock() else {
+            return;
+        };
+        let command = if by_command.contains_key(command) || by_command.len() < MAX_TRACKED_COMMANDS
+        {
+            command
+        } else {
+            OTHER_COMMANDS
+        };
+        if !by_command.contains_key(command) {
+            let Ok(histogram) = Histogram::new(3) else {
+                return;
+            };
+            by_command.insert(command.to_string(), histogram);
+        }
+        if let Some(histogram) = by_command.get_mut(command) {
+            histogram.saturating_record(latency.as_micros() as u64);
+        }
+    }
+
+    /// Percentiles for every command seen, busiest first
+    pub fn summary(&self) -> Vec<CommandLatency> {
+        let Ok(by_command) = self.by_command.lock() else {
+            return vec![];
+        };
+        let mut summary: Vec<CommandLatency> = by_command
+            .iter()
+            .map(|(command, h)| CommandLatency {
+                command: command.clone(),
+                count: h.len(),
+                p50: Duration::from_micros(h.value_at_quantile(0.50)),
+                p95: Duration::from_micros(h.value_at_quantile(0.95)),
+                p99: Duration::from_micros(h.value_at_quantile(0.99)),
+            })
+            .collect();
+        summary.sort_by(|a, b| b.count.cmp(&a.count).then(a.command.cmp(&b.command)));
+        summary
+    }
+
+    /// A copy of every command's histogram, to tell what's been recorded since
+    pub fn histograms(&self) -> HashMap<String, Histogram<u64>> {
+        self.by_command
+            .lock()
+            .map(|by_command| by_command.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Sampled key accesses within a single namespace
+#[derive(Clone, Debug)]
+pub struct NamespaceUsage {
+    pub namespace: Bytes,
+    /// Keys accessed by read-only commands
+    pub reads: u64,
+    /// Keys accessed by any other command
+    pub writes: u64,
+}
+
+impl NamespaceUsage {
+    pub fn keys(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Sampled key accesses aggregated by namespace, taken to be the key up to its first `:`
+#[derive(Default)]
+pub struct KeySpace {
+    by_namespace: Mutex<HashMap<Bytes, (u64, u64)>>,
+}
+
+impl KeySpace {
+    pub fn record(&self, key: &Bytes, read_only: bool) {
+        let namespace = match key.iter().position(|&b| b == b':') {
+            Some(end) => key.slice(..end),
+            None => Bytes::from_static(NO_NAMESPACE),
+        };
+        let Ok(mut by_namespace) = self.by_namespace.lock() else {
+            return;
+        };
+        let namespace = if by_namespace.contains_key(&namespace)
+            || by_namespace.len() < MAX_TRACKED_NAMESPACES
+        {
+            namespace
+        } else {
+            Bytes::from_static(OTHER_NAMESPACES)
+        };
+        let (reads, writes) = by_namespace.entry(namespace).or_default();
+        if read_only {
+            *reads += 1;
+        } else {
+            *writes += 1;
+        }
+    }
+
+    /// Key accesses sampled across every namespace
+    pub fn sampled(&self) -> u64 {
+        self.by_namespace
+            .lock()
+            .map(|n| n.values().map(|(reads, writes)| reads + writes).sum())
+            .unwrap_or_default()
+    }
+
+    /// The `count` namespaces with the most sampled key accesses, busiest first
+    pub fn top(&self, count: usize) -> Vec<NamespaceUsage> {
+        let Ok(by_namespace) = self.by_namespace.lock() else {
+            return vec![];
+        };
+        let mut top: Vec<NamespaceUsage> = by_namespace
+            .iter()
+            .map(|(namespace, &(reads, writes))| NamespaceUsage {
+                namespace: namespace.clone(),
+                reads,
+                writes,
+            })
+            .collect();
+        top.sort_by(|a, b| b.keys().cmp(&a.keys()).then(a.namespace.cmp(&b.namespace)));
+        top.truncate(count);
+        top
+    }
+}
+
+/// Sampled key accesses counted by the Redis Cluster slot their key hashes to, showing how evenly
+/// a workload would spread across a cluster's shards
+pub struct KeySlots {
+    by_slot: Box<[AtomicU64]>,
+}
+
+impl Default for KeySlots {
+    fn default() -> Self {
+        Self {
+            by_slot: (0..SLOT_COUNT).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl KeySlots {
+    pub fn record(&self, key: &[u8]) {
+        let slot = redis_protocol::redis_keyslot(key) as usize;
+        if let Some(count) = self.by_slot.get(slot) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Key accesses sampled across every slot
+    pub fn sampled(&self) -> u64 {
+        self.by_slot.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// The `count` slots with the most sampled key accesses and their counts, busiest first
+    pub fn top(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut top: Vec<(u16, u64)> = self
+            .by_slot
+            .iter()
+            .enumerate()
+            .map(|(slot, c)| (slot as u16, c.load(Ordering::Relaxed)))
+            .filter(|&(_, sampled)| sampled > 0)
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top.truncate(count);
+        top
+    }
+}
+
+/// Totals for a single command name
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CommandTotals {
+    pub command: String,
+    pub calls: u64,
+    /// Calls answered with an error reply
+    pub errors: u64,
+    /// Latency summed over every call
+    pub total_latency: Duration,
+}
+
+impl CommandTotals {
+    pub fn mean_latency(&self) -> Duration {
+        let micros = self.total_latency.as_micros() / u128::from(self.calls.max(1));
+        Duration::from_micros(micros as u64)
+    }
+}
+
+#[derive(Default)]
+struct CommandCounters {
+    calls: u64,
+    errors: u64,
+    micros: u64,
+}
+
+/// Call, error, and latency totals, keyed by command name, showing what a workload consists of
+#[derive(Default)]
+pub struct CommandCounts {
+    by_command: Mutex<HashMap<String, CommandCounters>>,
+}
+
+impl CommandCounts {
+    pub fn record(&self, command: &str, latency: Duration, error: bool) {
+        self.add(command, 1, u64::from(error), latency);
+    }
+
+    /// Count `totals` (of an earlier proxy) on top of these
+    pub fn restore(&self, totals: &[CommandTotals]) {
+        for t in totals {
+            self.add(&t.command, t.calls, t.errors, t.total_latency);
+        }
+    }
+
+    fn add(&self, command: &str, calls: u64, errors: u64, latency: Duration) {
+        let Ok(mut by_command) = self.by_command.lock() else {
+            return;
+        };
+        let command = if by_command.contains_key(command) || by_command.len() < MAX_TRACKED_COMMANDS
+        {
+            command
+        } else {
+            OTHER_COMMANDS
+        };
+        let counters = by_command.entry(command.to_string()).or_default();
+        counters.calls += calls;
+        counters.errors += errors;
+        counters.micros = counters.micros.saturating_add(latency.as_micros() as u64);
+    }
+
+    /// The `count` most called commands, busiest first
+    pub fn top(&self, count: usize) -> Vec<CommandTotals> {
+        let Ok(by_command) = self.by_command.lock() else {
+            return vec![];
+        };
+        let mut top: Vec<CommandTotals> = by_command
+            .iter()
+            .map(|(command, c)| CommandTotals {
+                command: command.clone(),
+                calls: c.calls,
+                errors: c.errors,
+                total_latency: Duration::from_micros(c.micros),
+            })
+            .collect();
+        top.sort_by(|a, b| b.calls.cmp(&a.calls).then(a.command.cmp(&b.command)));
+        top.truncate(count);
+        top
+    }
+}
+
+/// Like Redis's SLOWLOG, only this many arguments of a command are kept...
+static SLOWLOG_MAX_ARGS: usize = 32;
+/// ...and each is cut to at most this many bytes
+static SLOWLOG_MAX_ARG_LEN: usize = 128;
+static DEFAULT_SLOWLOG_CAPACITY: usize = 128;
+
+/// A command which took longer than the slowlog threshold, end to end through the proxy
+#[derive(Clone, Debug)]
+pub struct SlowlogEntry {
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub duration: Duration,
+    pub args: Vec<Bytes>,
+    pub connection_id: String,
+}
+
+impl SlowlogEntry {
+    /// The command and its (truncated) arguments, space separated
+    pub fn command_line(&self) -> String {
+        self.args
+            .iter()
+            .map(|a| String::from_utf8_lossy(a))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// A bounded, newest-first record of slow commands
+pub struct Slowlog {
+    entries: Mutex<VecDeque<SlowlogEntry>>,
+    capacity: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+impl Default for Slowlog {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            capacity: AtomicUsize::new(DEFAULT_SLOWLOG_CAPACITY),
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Slowlog {
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.truncate(capacity);
+        }
+    }
+
+    pub fn record(&self, request: &BytesFrame, duration: Duration, connection_id: &str) {
+        let args = command::args(request)
+            .unwrap_or_default()
+            .iter()
+            .take(SLOWLOG_MAX_ARGS)
+            .filter_map(command::arg_bytes)
+            .map(|a| a.slice(..a.len().min(SLOWLOG_MAX_ARG_LEN)))
+            .collect();
+        let entry = SlowlogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now(),
+            duration,
+            args,
+            connection_id: connection_id.to_string(),
+        };
+
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.push_front(entry);
+            entries.truncate(capacity);
+        }
+    }
+
+    /// Up to `count` of the most recent entries, newest first
+    pub fn entries(&self, count: usize) -> Vec<SlowlogEntry> {
+        self.entries
+            .lock()
+            .map(|entries| entries.iter().take(count).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Keep `entries` (an earlier proxy's, newest first) as older than those recorded here, as
+    /// many as fit
+    pub fn restore(&self, entries: impl IntoIterator<Item = SlowlogEntry>) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if let Ok(mut log) = self.entries.lock() {
+            for entry in entries {
+                // New entries are numbered after the restored ones, as if there'd been no restart
+                self.next_id.fetch_max(entry.id + 1, Ordering::Relaxed);
+                log.push_back(entry);
+            }
+            log.truncate(capacity);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().map(|e| e.len()).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+static DEFAULT_HOT_KEY_CAPACITY: usize = 256;
+
+/// A frequently accessed key and its estimated (sampled) access count
+#[derive(Clone, Debug)]
+pub struct HotKey {
+    pub key: Bytes,
+    pub count: u64,
+    /// Upper bound on how much `count` may overestimate the true sampled count
+    pub error: u64,
+}
+
+/// Approximate top-k key access counts, maintained with the Space-Saving algorithm.
+///
+/// At most `capacity` keys are tracked; a newly seen key evicts the least frequent one and
+/// inherits its count (recorded as the estimate's error bound), so genuinely hot keys always
+/// surface while memory stays bounded.
+pub struct HotKeys {
+    counters: Mutex<HashMap<Bytes, (u64, u64)>>,
+    capacity: AtomicUsize,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        Self {
+            counters: Mutex::new(HashMap::new()),
+            capacity: AtomicUsize::new(DEFAULT_HOT_KEY_CAPACITY),
+        }
+    }
+}
+
+impl HotKeys {
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity.max(1), Ordering::Relaxed);
+    }
+
+    pub fn record(&self, key: &Bytes) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let Ok(mut counters) = self.counters.lock() else {
+            return;
+        };
+
+        if let Some((count, _)) = counters.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if counters.len() < capacity {
+            counters.insert(key.clone(), (1, 0));
+            return;
+        }
+
+        let Some((coldest, min)) = counters
+            .iter()
+            .min_by_key(|(_, (count, _))| *count)
+            .map(|(k, (count, _))| (k.clone(), *count))
+        else {
+            return;
+        };
+        counters.remove(&coldest);
+        counters.insert(key.clone(), (min + 1, min));
+    }
+
+    /// The `n` keys with the highest estimated counts, hottest first
+    pub fn top(&self, n: usize) -> Vec<HotKey> {
+        let Ok(counters) = self.counters.lock() else {
+            return vec![];
+        };
+        let mut top: Vec<HotKey> = counters
+            .iter()
+            .map(|(key, (count, error))| HotKey {
+                key: key.clone(),
+                count: *count,
+                error: *error,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        top.truncate(n);
+        top
+    }
+
+    pub fn reset(&self) {
+        if let Ok(mut counters) = self.counters.lock() {
+            counters.clear();
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Pushing statistics to StatsD.
+//!
+//! A `StatsdExporter` sends what `Stats` tracks to a StatsD server over UDP on an interval, for
+//! deployments which collect metrics that way rather than by scraping. Totals (connections
+//! accepted, calls and errors per command, bytes relayed, ...) are sent as counters of what's
+//! changed since the last push, current values (active connections, backend health) as gauges,
+//! and latency as a timer of each command's mean over the interval, alongside gauges of its
+//! p50/p95/p99 since startup. Every metric is named under a prefix, `cabbage` by default, with a
+//! tenant's name added for a tenant's statistics:
+//!
+//! ```text
+//! cabbage.connections.active:12|g
+//! cabbage.commands.get.calls:5120|c
+//! cabbage.commands.get.latency:0.412|ms
+//! cabbage.latency.get.p99:1.318|g
+//! ```
+//!
+//! Metrics are packed into as few datagrams as fit; like any StatsD traffic, those lost on the
+//! way aren't resent.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::error::{Error, Result};
+use crate::stats::{QUEUES, Stats};
+
+/// Metrics are packed into datagrams of at most this many bytes, small enough to cross most
+/// networks unfragmented
+static MAX_DATAGRAM_BYTES: usize = 1432;
+
+/// `name` as a component of a metric name, lowercase, and with anything StatsD treats specially
+/// replaced
+fn metric_component(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '-' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+/// `duration` in milliseconds, to the microsecond
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    address: SocketAddr,
+    prefix: String,
+    /// Each counter's total as of the last push
+    totals: HashMap<String, u64>,
+    /// Lines for the next push
+    lines: Vec<String>,
+    /// Whether the last datagram failed to send
+    failing: bool,
+}
+
+impl StatsdExporter {
+    /// Send metrics named under `prefix`, qualified with `tenant` if the stats are a tenant's, to
+    /// the StatsD server at `address`
+    pub async fn connect(address: &str, prefix: &str, tenant: Option<&str>) -> Result<Self> {
+        let address = tokio::net::lookup_host(address)
+            .await
+            .map_err(|e| Error::io(format!("Failed to resolve StatsD address {address}"), e))?
+            .next()
+            .ok_or_else(|| Error::Config(format!("StatsD address {address} didn't resolve")))?;
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .connect(address)
+            .await
+            .map_err(|e| Error::io(format!("Failed to connect to StatsD at {address}"), e))?;
+        let mut prefix = prefix.trim_end_matches('.').to_string();
+        if let Some(tenant) = tenant {
+            prefix = format!("{prefix}.{}", metric_component(tenant));
+        }
+        log::info!("Pushing statistics to StatsD at {address} as {prefix}.*");
+        Ok(Self {
+            socket,
+            address,
+            prefix,
+            totals: HashMap::new(),
+            lines: vec![],
+            failing: false,
+        })
+    }
+
+    /// Push `stats` every `interval` until the process exits
+    pub async fn push_periodically(mut self, stats: Arc<Stats>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.push(&stats).await;
+        }
+    }
+
+    /// Send the current value of every metric
+    pub async fn push(&mut self, stats: &Stats) {
+        let connections = &stats.connections;
+        self.gauge("connections.active", connections.active());
+        self.counter("connections.accepted", connections.accepted());
+        self.counter("connections.rejected", connections.rejected());
+        self.counter("connections.refused", connections.refused());
+        let traffic = connections.traffic();
+        self.counter("traffic.bytes_in", traffic.bytes_in());
+        self.counter("traffic.bytes_out", traffic.bytes_out());
+        self.counter("traffic.commands", traffic.commands());
+        self.counter("traffic.responses", traffic.responses());
+        self.counter(
+            "traffic.output_limit_disconnections",
+            traffic.output_limit_disconnections(),
+        );
+        self.counter("traffic.dropped_replies", traffic.dropped_replies());
+
+        for c in stats.commands.top(usize::MAX) {
+            let name = format!("commands.{}", metric_component(&c.command));
+            let calls = self.counter(&format!("{name}.calls"), c.calls);
+            self.counter(&format!("{name}.errors"), c.errors);
+            let micros = c.total_latency.as_micros() as u64;
+            let interval_micros = self.counter_delta(&format!("{name}.latency_us"), micros);
+            if let Some(mean) = interval_micros.checked_div(calls) {
+                let mean = Duration::from_micros(mean);
+                self.line(&format!("{name}.latency"), millis(mean), "ms");
+            }
+        }
+        for l in stats.latency.summary() {
+            let name = format!("latency.{}", metric_component(&l.command));
+            self.gauge(&format!("{name}.p50"), millis(l.p50));
+            self.gauge(&format!("{name}.p95"), millis(l.p95));
+            self.gauge(&format!("{name}.p99"), millis(l.p99));
+        }
+
+        let backend = &stats.backend;
+        self.counter("backend.timeouts", backend.timeouts());
+        if backend.health_checks() > 0 {
+            self.counter("backend.health_checks", backend.health_checks());
+            self.counter(
+                "backend.failed_health_checks",
+                backend.failed_health_checks(),
+            );
+            self.gauge("backend.healthy", u8::from(backend.is_healthy()));
+        }
+        let mirror = &stats.mirror;
+        self.counter("mirror.replied", mirror.replied());
+        self.counter("mirror.errors", mirror.errors());
+        self.counter("mirror.failed", mirror.failed());
+        self.counter("mirror.dropped", mirror.dropped());
+        let canary = &stats.canary;
+        self.counter("canary.compared", canary.compared());
+        self.counter("canary.mismatched", canary.mismatched());
+        self.counter("canary.dropped", canary.dropped());
+        let cache = &stats.cache;
+        self.counter("cache.hits", cache.hits());
+        self.counter("cache.misses", cache.misses());
+        self.counter("cache.invalidations", cache.invalidations());
+        self.counter("cache.evictions", cache.evictions());
+        self.counter("cache.stale_hits", cache.stale_hits());
+        let command_cache = &stats.command_cache;
+        self.counter("command_cache.hits", command_cache.hits());
+        self.counter("command_cache.misses", command_cache.misses());
+        self.counter("command_cache.invalidations", command_cache.invalidations());
+        let pool = &stats.pool;
+        if pool.checkouts() > 0 || pool.exhausted() > 0 {
+            self.gauge("pool.open", pool.open());
+            self.gauge("pool.ready", pool.ready());
+            self.counter("pool.checkouts", pool.checkouts());
+            self.counter("pool.warm_checkouts", pool.warm_checkouts());
+            let waits = self.counter("pool.waits", pool.waits());
+            let micros = pool.total_wait().as_micros() as u64;
+            let interval_micros = self.counter_delta("pool.wait_us", micros);
+            if let Some(mean) = interval_micros.checked_div(waits) {
+                self.line("pool.wait", millis(Duration::from_micros(mean)), "ms");
+            }
+            self.counter("pool.temporary", pool.temporary());
+            self.counter("pool.exhausted", pool.exhausted());
+        }
+        let quotas = &stats.quotas;
+        if quotas.identities() > 0 {
+            self.gauge("quota.identities", quotas.identities());
+            self.counter("quota.rate_refusals", quotas.rate_refusals());
+            self.counter("quota.in_flight_refusals", quotas.in_flight_refusals());
+            self.counter("quota.bytes_refusals", quotas.bytes_refusals());
+        }
+        let saturation = &stats.saturation;
+        if saturation.is_limited() {
+            self.gauge("saturation.in_flight", saturation.in_flight());
+            self.counter(
+                "saturation.connection_refusals",
+                saturation.connection_refusals(),
+            );
+            self.counter("saturation.total_refusals", saturation.total_refusals());
+        }
+        let checksums = &stats.checksums;
+        if checksums.is_checking() {
+            self.counter("checksums.verified", checksums.verified());
+            self.counter("checksums.corrupted", checksums.corrupted());
+            self.counter("checksums.unchecked", checksums.unchecked());
+        }
+        for (name, queue) in QUEUES.each() {
+            self.gauge(&format!("queues.{name}.depth"), queue.depth());
+            self.counter(&format!("queues.{name}.full"), queue.full());
+        }
+
+        self.send().await;
+    }
+
+    /// How much the counter `name` has grown to `total` since the last push
+    fn counter_delta(&mut self, name: &str, total: u64) -> u64 {
+        let previous = self.totals.insert(name.to_string(), total).unwrap_or(0);
+        total.saturating_sub(previous)
+    }
+
+    /// Add the growth of the counter `name` to the next push, answering with it
+    fn counter(&mut self, name: &str, total: u64) -> u64 {
+        let delta = self.counter_delta(name, total);
+        if delta > 0 {
+            self.line(name, delta, "c");
+        }
+        delta
+    }
+
+    fn gauge(&mut self, name: &str, value: impl std::fmt::Display) {
+        self.line(name, value, "g");
+    }
+
+    fn line(&mut self, name: &str, value: impl std::fmt::Display, kind: &str) {
+        self.lines
+            .push(format!("{}.{name}:{}|{kind}", self.prefix, value));
+    }
+
+    /// Send the lines for this push, as few to a datagram as fit
+    async fn send(&mut self) {
+        let mut datagram = String::new();
+        for line in std::mem::take(&mut self.lines) {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_BYTES {
+                self.send_datagram(&datagram).await;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.send_datagram(&datagram).await;
+        }
+    }
+
+    async fn send_datagram(&mut self, datagram: &str) {
+        match self.socket.send(datagram.as_bytes()).await {
+            Ok(_) => self.failing = false,
+            Err(e) => {
+                if !self.failing {
+                    log::warn!(
+                        "Failed to send statistics to StatsD at {}: {e}",
+                        self.address
+                    );
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
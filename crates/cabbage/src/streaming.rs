@@ -0,0 +1,194 @@
+//! Streaming of large bulk string replies.
+//!
+//! A reply is normally decoded whole before it's forwarded, so a huge value is buffered in full
+//! on the way from the target (and again as it's encoded for the client), and the client sees
+//! none of it until the last byte has arrived. With a threshold set, `BulkStreamingCodec` passes
+//! top-level bulk string replies of at least that many bytes on in chunks as they're read
+//! instead. Each chunk travels through its request's response stream as a frame `BulkChunk`
+//! recognizes, and the client's codec writes it out as its part of the bulk string: the header
+//! with the first, the data as it comes, and the terminator with the last.
+//!
+//! Bulk strings nested in arrays are still decoded whole. Middleware which reads or rewrites the
+//! values in replies can't make sense of chunks, so streaming is ruled out alongside it (see
+//! `Config::validate`). A client whose streamed reply is cut short, as when the target
+//! connection is lost partway, can't be told so in-band, so its connection is closed.
+
+use redis_protocol::codec::{Resp2, Resp3};
+use redis_protocol::error::{RedisProtocolError, RedisProtocolErrorKind};
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::resp3;
+
+/// Marks a chunk frame; no target can send it, as simple strings can't contain CRLF
+static CHUNK_TAG: &[u8] = b"\r\nbulk chunk";
+/// Bytes read before a chunk is passed on, unless they're the rest of the string
+static MIN_CHUNK_BYTES: usize = 16 * 1024;
+/// Longest a bulk string header can be: `$`, a length, and CRLF
+static MAX_HEADER_BYTES: usize = 24;
+
+/// Part of a bulk string reply, carried as a frame of its own
+pub struct BulkChunk<'a> {
+    /// Length of the whole string
+    pub len: usize,
+    /// Where in the string the chunk starts
+    pub offset: usize,
+    pub data: &'a Bytes,
+}
+
+impl<'a> BulkChunk<'a> {
+    /// The chunk `frame` carries, if it's one
+    pub fn of(frame: &'a BytesFrame) -> Option<Self> {
+        let BytesFrame::Array(parts) = frame else {
+            return None;
+        };
+        match parts.as_slice() {
+            [
+                BytesFrame::SimpleString(tag),
+                BytesFrame::Integer(len),
+                BytesFrame::Integer(offset),
+                BytesFrame::BulkString(data),
+            ] if tag == CHUNK_TAG => Some(Self {
+                len: usize::try_from(*len).ok()?,
+                offset: usize::try_from(*offset).ok()?,
+                data,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether this is the first chunk of its string
+    pub fn starts(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Whether this is the last chunk of its string
+    pub fn ends(&self) -> bool {
+        self.offset + self.data.len() >= self.len
+    }
+
+    /// Write the chunk to `dst` as its part of the RESP bulk string
+    pub fn encode(&self, dst: &mut BytesMut) {
+        if self.starts() {
+            dst.put_u8(b'$');
+            dst.put_slice(self.len.to_string().as_bytes());
+            dst.put_slice(b"\r\n");
+        }
+        dst.put_slice(self.data);
+        if self.ends() {
+            dst.put_slice(b"\r\n");
+        }
+    }
+}
+
+fn chunk_frame(len: usize, offset: usize, data: Bytes) -> BytesFrame {
+    BytesFrame::Array(vec![
+        BytesFrame::SimpleString(Bytes::from_static(CHUNK_TAG)),
+        BytesFrame::Integer(len as i64),
+        BytesFrame::Integer(offset as i64),
+        BytesFrame::BulkString(data),
+    ])
+}
+
+/// The length of the header of the bulk string `src` starts with, and of the string, once the
+/// header has arrived
+fn bulk_header(src: &[u8]) -> Option<(usize, usize)> {
+    if src.first() != Some(&b'$') {
+        return None;
+    }
+    let end = src
+        .iter()
+        .take(MAX_HEADER_BYTES)
+        .position(|&b| b == b'\r')?;
+    if src.get(end + 1) != Some(&b'\n') {
+        return None;
+    }
+    // A null bulk string's length of -1 isn't one
+    let len = std::str::from_utf8(&src[1..end]).ok()?.parse().ok()?;
+    Some((end + 2, len))
+}
+
+/// RESP2 framing for target connections, passing bulk string replies of at least `threshold`
+/// bytes on in chunks as they arrive. Speaking RESP3, replies are decoded as RESP3 and
+/// translated to RESP2 (see `resp3`).
+#[derive(Default)]
+pub struct BulkStreamingCodec {
+    resp2: Resp2,
+    resp3: Option<Resp3>,
+    threshold: Option<usize>,
+    /// The length of the string being streamed, and how much of it has been passed on
+    streaming: Option<(usize, usize)>,
+}
+
+impl BulkStreamingCodec {
+    /// Stream bulk strings of at least `threshold` bytes, if set, and decode every frame whole
+    /// otherwise
+    pub fn new(threshold: Option<usize>) -> Self {
+        Self {
+            threshold,
+            ..Self::default()
+        }
+    }
+
+    /// Decode replies from a target connection switched to RESP3, keeping it in RESP3
+    pub fn speaking_resp3(mut self) -> Self {
+        self.resp3 = Some(Resp3::default());
+        self
+    }
+}
+
+impl Decoder for BulkStreamingCodec {
+    type Item = BytesFrame;
+    type Error = RedisProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some((len, offset)) = self.streaming {
+            let remaining = len - offset;
+            if remaining > 0 {
+                if src.len() < remaining.min(MIN_CHUNK_BYTES) {
+                    return Ok(None);
+                }
+                let data = src.split_to(remaining.min(src.len())).freeze();
+                self.streaming = Some((len, offset + data.len()));
+                return Ok(Some(chunk_frame(len, offset, data)));
+            }
+            // The last chunk has been passed on, but not the string's terminator
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            if &src[..2] != b"\r\n" {
+                return Err(RedisProtocolError::new(
+                    RedisProtocolErrorKind::DecodeError,
+                    "Streamed bulk string isn't terminated",
+                ));
+            }
+            src.advance(2);
+            self.streaming = None;
+        }
+        if let Some(threshold) = self.threshold
+            && let Some((header_len, len)) = bulk_header(src)
+            && len >= threshold
+        {
+            src.advance(header_len);
+            self.streaming = Some((len, 0));
+            return self.decode(src);
+        }
+        match &mut self.resp3 {
+            Some(codec) => Ok(codec.decode(src)?.map(resp3::from_target)),
+            None => self.resp2.decode(src),
+        }
+    }
+}
+
+impl Encoder<BytesFrame> for BulkStreamingCodec {
+    type Error = RedisProtocolError;
+
+    fn encode(&mut self, item: BytesFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Requests are the same in RESP2 and RESP3
+        match self.resp3 {
+            Some(_) => self.resp2.encode(resp3::keep_resp3(item), dst),
+            None => self.resp2.encode(item, dst),
+        }
+    }
+}
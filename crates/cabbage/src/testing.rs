@@ -0,0 +1,327 @@
+//! Test support.
+//!
+//! `MockRedis` is a minimal in-memory stand-in for a Redis server, understanding `PING`, `GET`,
+//! `SET` (with `EX`/`PX`/`NX`/`XX`), `DEL`, `EXISTS`, `EXPIRE`, and `TTL`, and answering anything
+//! else with an error. It is a `Service<BytesFrame>` like any other backend, so it can sit at the
+//! bottom of a middleware stack directly, or be served over TCP to exercise the proxy end to end.
+//! Clones share the same data.
+//!
+//! `TestProxy` serves the proxy on an ephemeral local port, each client connection going through
+//! a service built for it (a `MockRedis`, a middleware stack over one, or a `Resp2Backend` to a
+//! real server), so a stack can be tested end to end with any Redis client:
+//!
+//! ```no_run
+//! # async fn example() -> cabbage::Result<()> {
+//! use cabbage::command;
+//! use cabbage::service::CallOne as _;
+//! use cabbage::testing::{MockRedis, TestProxy};
+//!
+//! let proxy = TestProxy::start(MockRedis::new()).await?;
+//! let mut client = proxy.client().await?;
+//! client.call_one(command::request(["SET", "greeting", "hello"])).await?;
+//! proxy.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::Stream;
+use futures_util::{SinkExt, StreamExt};
+use redis_protocol::{codec::Resp2, resp2::types::BytesFrame};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use uuid::Uuid;
+
+use crate::command;
+use crate::error::{Error, Result};
+use crate::middleware::reply;
+use crate::proxy::{ServeOptions, serve_with};
+use crate::service::{CallOne as _, Resp2Backend, ResponseStream};
+
+/// How long `TestProxy::start` waits for the proxy to answer
+static READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Entry {
+    value: Bytes,
+    expires: Option<Instant>,
+}
+
+impl Entry {
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MockRedis {
+    data: Arc<Mutex<HashMap<Bytes, Entry>>>,
+}
+
+impl MockRedis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve the shared data over TCP on an ephemeral local port, returning its address
+    pub async fn listen(&self) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mock = self.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let mock = mock.clone();
+                tokio::spawn(async move {
+                    let mut framed = Framed::new(socket, Resp2::default());
+                    while let Some(Ok(request)) = framed.next().await {
+                        if framed.send(mock.execute(&request)).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    /// The value currently stored at `key`, for inspecting what a test has written
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<Bytes> {
+        let data = self.data.lock().ok()?;
+        data.get(key.as_ref())
+            .filter(|entry| entry.is_live(Instant::now()))
+            .map(|entry| entry.value.clone())
+    }
+
+    /// Run a single command, returning its reply
+    pub fn execute(&self, request: &BytesFrame) -> BytesFrame {
+        let Some(args) = command::args(request) else {
+            return command::error("ERR Protocol error: expected an array of bulk strings");
+        };
+        let Some(args) = args
+            .iter()
+            .map(command::arg_bytes)
+            .collect::<Option<Vec<_>>>()
+        else {
+            return command::error("ERR Protocol error: expected an array of bulk strings");
+        };
+        let name = String::from_utf8_lossy(args[0]).to_ascii_uppercase();
+        let Ok(mut data) = self.data.lock() else {
+            return command::error("ERR mock state poisoned");
+        };
+        let now = Instant::now();
+        data.retain(|_, entry| entry.is_live(now));
+
+        match (name.as_str(), &args[1..]) {
+            ("PING", []) => BytesFrame::SimpleString("PONG".into()),
+            ("PING", [message]) => BytesFrame::BulkString((*message).clone()),
+            ("GET", [key]) => match data.get(*key) {
+                Some(entry) => BytesFrame::BulkString(entry.value.clone()),
+                None => BytesFrame::Null,
+            },
+            ("SET", [key, value, options @ ..]) => {
+                let mut expires = None;
+                let mut condition = None;
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+                    match option.as_str() {
+                        "NX" | "XX" => condition = Some(option),
+                        "EX" | "PX" => {
+                            let Some(amount) = options.next().and_then(|a| parse_u64(a)) else {
+                                return command::error(
+                                    "ERR value is not an integer or out of range",
+                                );
+                            };
+                            expires = Some(
+                                now + if option == "EX" {
+                                    Duration::from_secs(amount)
+                                } else {
+                                    Duration::from_millis(amount)
+                                },
+                            );
+                        }
+                        _ => return command::error("ERR syntax error"),
+                    }
+                }
+                let exists = data.contains_key(*key);
+                match condition.as_deref() {
+                    Some("NX") if exists => return BytesFrame::Null,
+                    Some("XX") if !exists => return BytesFrame::Null,
+                    _ => {}
+                }
+                data.insert(
+                    (*key).clone(),
+                    Entry {
+                        value: (*value).clone(),
+                        expires,
+                    },
+                );
+                BytesFrame::SimpleString("OK".into())
+            }
+            ("DEL", keys) if !keys.is_empty() => BytesFrame::Integer(
+                keys.iter()
+                    .filter(|key| data.remove(**key).is_some())
+                    .count() as i64,
+            ),
+            ("EXISTS", keys) if !keys.is_empty() => BytesFrame::Integer(
+                keys.iter().filter(|key| data.contains_key(**key)).count() as i64,
+            ),
+            ("EXPIRE", [key, seconds]) => {
+                let Some(seconds) = parse_u64(seconds) else {
+                    return command::error("ERR value is not an integer or out of range");
+                };
+                match data.get_mut(*key) {
+                    Some(entry) => {
+                        entry.expires = Some(now + Duration::from_secs(seconds));
+                        BytesFrame::Integer(1)
+                    }
+                    None => BytesFrame::Integer(0),
+                }
+            }
+            ("TTL", [key]) => BytesFrame::Integer(match data.get(*key) {
+                Some(Entry {
+                    expires: Some(expires),
+                    ..
+                }) => expires.saturating_duration_since(now).as_secs_f64().ceil() as i64,
+                Some(_) => -1,
+                None => -2,
+            }),
+            ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "EXPIRE" | "TTL", _) => command::error(
+                format!("ERR wrong number of arguments for '{name}' command"),
+            ),
+            _ => command::error(format!("ERR unknown command '{name}'")),
+        }
+    }
+}
+
+fn parse_u64(arg: &Bytes) -> Option<u64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+impl Service<BytesFrame> for MockRedis {
+    type Response = ResponseStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BytesFrame) -> Self::Future {
+        let frame = self.execute(&req);
+        Box::pin(async move { Ok(reply(frame)) })
+    }
+}
+
+/// A proxy serving on an ephemeral local port, stopped when dropped if not shut down before
+pub struct TestProxy {
+    addr: SocketAddr,
+    shutdown: CancellationToken,
+    join: Option<JoinHandle<Result<()>>>,
+}
+
+impl TestProxy {
+    /// Serve each client connection through a clone of `backend`, returning once the proxy
+    /// answers
+    pub async fn start<S>(backend: S) -> Result<Self>
+    where
+        S: Service<BytesFrame> + Clone + Send + 'static,
+        S::Response: Stream<Item = BytesFrame> + Send + 'static,
+        S::Error: Into<Error> + Send,
+        S::Future: Send,
+    {
+        Self::start_with(
+            move |_| {
+                let backend = backend.clone();
+                async move { Ok(backend) }
+            },
+            ServeOptions::default(),
+        )
+        .await
+    }
+
+    /// Serve each client connection through the service `make_service` builds for it, as
+    /// `options` say, returning once the proxy answers
+    pub async fn start_with<M, F, S>(mut make_service: M, options: ServeOptions) -> Result<Self>
+    where
+        M: FnMut(Uuid) -> F + Send + 'static,
+        F: Future<Output = Result<S>> + Send + 'static,
+        S: Service<BytesFrame> + Send + 'static,
+        S::Response: Stream<Item = BytesFrame> + Send + 'static,
+        S::Error: Into<Error> + Send,
+        S::Future: Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shutdown = options.shutdown_token();
+        let join = tokio::spawn(serve_with(
+            listener,
+            move |connection_id, _client_addr| make_service(connection_id),
+            options,
+        ));
+        let proxy = Self {
+            addr,
+            shutdown,
+            join: Some(join),
+        };
+        proxy.ready(READY_TIMEOUT).await?;
+        Ok(proxy)
+    }
+
+    /// The address clients connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A new client connection to the proxy
+    pub async fn client(&self) -> Result<Resp2Backend> {
+        Resp2Backend::connect(&self.addr.to_string()).await
+    }
+
+    /// Wait until the proxy answers a `PING` (with any reply, error replies included), failing
+    /// if it hasn't within `timeout`
+    pub async fn ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let answered = match self.client().await {
+                Ok(mut client) => client.call_one(command::request(["PING"])).await,
+                Err(e) => Err(e),
+            };
+            match answered {
+                Ok(_) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(e.context(format!("The proxy didn't answer within {timeout:?}")));
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    /// Stop accepting clients, close connections once their outstanding replies are sent, and
+    /// wait for the proxy to stop
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.shutdown.cancel();
+        match self.join.take() {
+            Some(join) => join
+                .await
+                .map_err(|e| Error::Other(format!("The proxy task panicked: {e}").into()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
@@ -0,0 +1,227 @@
+//! Live proxy dashboard.
+//!
+//! `Top` polls a running proxy's `CABBAGE.*` admin commands over an ordinary client connection
+//! and renders what they report as a screenful of text: command rates and error rates (from how
+//! `CABBAGE.TOPCOMMANDS` totals grew since the last poll), latency percentiles from
+//! `CABBAGE.LATENCY`, the hottest sampled keys from `CABBAGE.HOTKEYS`, and the busiest client
+//! connections from `CABBAGE.CONNECTIONS`. `cabbage top` redraws it in place on an interval, like
+//! `redis-cli --stat` does for a Redis server.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::time::Instant;
+
+use redis_protocol::resp2::types::BytesFrame;
+
+use crate::command;
+use crate::error::{Result, bail};
+use crate::service::{CallOne as _, Handshake, Resp2Backend};
+
+/// Count asked of `CABBAGE.TOPCOMMANDS` so every command seen is listed
+static ALL_COMMANDS: &str = "100000";
+
+/// Totals as of a poll, so the next can work out rates
+struct Snapshot {
+    at: Instant,
+    /// Calls and errors of each command
+    commands: HashMap<String, (u64, u64)>,
+    /// Commands, bytes in, and bytes out of each connection, by ID
+    connections: HashMap<String, (u64, u64, u64)>,
+}
+
+pub struct Top {
+    backend: Resp2Backend,
+    /// Rows shown in each table
+    rows: usize,
+    previous: Option<Snapshot>,
+}
+
+impl Top {
+    /// Connect to the proxy at `address`, authenticating as `handshake` says, showing `rows` rows
+    /// in each table
+    pub async fn connect(address: &str, handshake: &Handshake, rows: usize) -> Result<Self> {
+        Ok(Self {
+            backend: Resp2Backend::connect_with(address, handshake).await?,
+            rows,
+            previous: None,
+        })
+    }
+
+    async fn admin(&mut self, args: &[&str]) -> Result<BytesFrame> {
+        match self.backend.call_one(command::request(args)).await? {
+            BytesFrame::Error(e) => bail!(BackendUnavailable, "{} failed: {e}", args.join(" ")),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Poll the proxy, rendering what it reports. Rates are over the time since the last poll,
+    /// so the first shows none.
+    pub async fn poll(&mut self) -> Result<String> {
+        let rows = self.rows.to_string();
+        let info = text(&self.admin(&["CABBAGE.INFO"]).await?);
+        let commands = self.admin(&["CABBAGE.TOPCOMMANDS", ALL_COMMANDS]).await?;
+        let latency = self.admin(&["CABBAGE.LATENCY"]).await?;
+        let hot_keys = self.admin(&["CABBAGE.HOTKEYS", &rows]).await?;
+        let connections = text(&self.admin(&["CABBAGE.CONNECTIONS"]).await?);
+
+        let now = Snapshot {
+            at: Instant::now(),
+            commands: elements(&commands, 3)
+                .iter()
+                .map(|c| (text(&c[0]), (int(&c[1]), int(&c[2]))))
+                .collect(),
+            connections: connections
+                .lines()
+                .filter_map(|line| {
+                    let fields = fields(line);
+                    let field = |name| fields.get(name).and_then(|v| v.parse().ok());
+                    Some((
+                        fields.get("id")?.to_string(),
+                        (field("commands")?, field("bytes_in")?, field("bytes_out")?),
+                    ))
+                })
+                .collect(),
+        };
+        let previous = self.previous.as_ref();
+        let secs = previous.map(|p| now.at.duration_since(p.at).as_secs_f64().max(f64::EPSILON));
+        let rate = |total: u64, before: Option<u64>| -> String {
+            match secs {
+                Some(secs) => format!(
+                    "{:.1}",
+                    total.saturating_sub(before.unwrap_or(0)) as f64 / secs
+                ),
+                None => "-".to_string(),
+            }
+        };
+
+        let mut screen = String::new();
+        let info_fields: HashMap<&str, &str> = info
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .collect();
+        let info_field = |name| info_fields.get(name).copied().unwrap_or("?");
+        let total: u64 = now.commands.values().map(|&(calls, _)| calls).sum();
+        let total_before = previous.map(|p| p.commands.values().map(|&(calls, _)| calls).sum());
+        let _ = writeln!(
+            screen,
+            "cabbage {}, up {}s, {} client(s), {} commands/s\n",
+            info_field("cabbage_version"),
+            info_field("uptime_in_seconds"),
+            info_field("connected_clients"),
+            rate(total, total_before),
+        );
+
+        // The busiest commands since the last poll, or in all, first
+        let mut command_rows: Vec<_> = now.commands.iter().collect();
+        let calls_since = |name: &String, calls: u64| {
+            calls.saturating_sub(
+                previous
+                    .and_then(|p| p.commands.get(name))
+                    .map_or(0, |&(c, _)| c),
+            )
+        };
+        command_rows.sort_by(|a, b| {
+            calls_since(b.0, b.1.0)
+                .cmp(&calls_since(a.0, a.1.0))
+                .then(a.0.cmp(b.0))
+        });
+        let percentiles: HashMap<String, (u64, u64, u64)> = elements(&latency, 5)
+            .iter()
+            .map(|l| (text(&l[0]), (int(&l[2]), int(&l[3]), int(&l[4]))))
+            .collect();
+        let _ = writeln!(
+            screen,
+            "{:<20} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "COMMAND", "CALLS/S", "ERRORS/S", "P50 µs", "P95 µs", "P99 µs"
+        );
+        for (name, &(calls, errors)) in command_rows.into_iter().take(self.rows) {
+            let before = previous.and_then(|p| p.commands.get(name));
+            let (p50, p95, p99) = percentiles.get(name).copied().unwrap_or_default();
+            let _ = writeln!(
+                screen,
+                "{name:<20} {:>10} {:>10} {p50:>10} {p95:>10} {p99:>10}",
+                rate(calls, before.map(|&(c, _)| c)),
+                rate(errors, before.map(|&(_, e)| e)),
+            );
+        }
+
+        let _ = writeln!(screen, "\n{:<40} {:>10}", "HOT KEY", "SAMPLED");
+        for key in elements(&hot_keys, 2) {
+            let _ = writeln!(screen, "{:<40} {:>10}", text(&key[0]), int(&key[1]));
+        }
+
+        // The connections sending the most commands since the last poll first
+        let mut connection_rows: Vec<_> = connections.lines().map(fields).collect();
+        let commands_since = |fields: &HashMap<&str, &str>| {
+            let id = fields.get("id").copied().unwrap_or_default();
+            let commands = |connections: &HashMap<String, (u64, u64, u64)>| {
+                connections.get(id).map_or(0, |&(c, _, _)| c)
+            };
+            commands(&now.connections)
+                .saturating_sub(previous.map_or(0, |p| commands(&p.connections)))
+        };
+        connection_rows.sort_by_key(|fields| std::cmp::Reverse(commands_since(fields)));
+        let _ = writeln!(
+            screen,
+            "\n{:<24} {:<16} {:>8} {:>12} {:>12} {:>12}",
+            "CONNECTION", "NAME", "AGE S", "COMMANDS/S", "BYTES IN/S", "BYTES OUT/S"
+        );
+        for fields in connection_rows.into_iter().take(self.rows) {
+            let field = |name| fields.get(name).copied().unwrap_or_default();
+            let id = field("id");
+            let (Some(&(commands, bytes_in, bytes_out)), before) = (
+                now.connections.get(id),
+                previous.and_then(|p| p.connections.get(id)),
+            ) else {
+                continue;
+            };
+            let _ = writeln!(
+                screen,
+                "{:<24} {:<16} {:>8} {:>12} {:>12} {:>12}",
+                field("addr"),
+                field("name"),
+                field("age"),
+                rate(commands, before.map(|&(c, _, _)| c)),
+                rate(bytes_in, before.map(|&(_, b, _)| b)),
+                rate(bytes_out, before.map(|&(_, _, b)| b)),
+            );
+        }
+
+        self.previous = Some(now);
+        Ok(screen)
+    }
+}
+
+/// The elements of an array reply which are arrays of at least `len` fields themselves
+fn elements(frame: &BytesFrame, len: usize) -> Vec<&[BytesFrame]> {
+    match frame {
+        BytesFrame::Array(items) => items
+            .iter()
+            .filter_map(|item| match item {
+                BytesFrame::Array(fields) if fields.len() >= len => Some(&fields[..]),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn text(frame: &BytesFrame) -> String {
+    command::arg_bytes(frame)
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+        .unwrap_or_default()
+}
+
+fn int(frame: &BytesFrame) -> u64 {
+    match frame {
+        BytesFrame::Integer(n) => (*n).max(0) as u64,
+        _ => 0,
+    }
+}
+
+/// The `name=value` fields of a `CABBAGE.CONNECTIONS` line
+fn fields(line: &str) -> HashMap<&str, &str> {
+    line.split_whitespace()
+        .filter_map(|field| field.split_once('='))
+        .collect()
+}
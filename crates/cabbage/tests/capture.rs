@@ -0,0 +1,111 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use cabbage::capture::{Capture, CaptureReader, Direction, Record, Rollover};
+use cabbage::command;
+use cabbage::middleware::capture::CaptureLayer;
+use cabbage::proxy::ServeOptions;
+use cabbage::service::CallOne as _;
+use cabbage::testing::{MockRedis, TestProxy};
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::Layer as _;
+use uuid::Uuid;
+
+static ROLLOVER: Rollover = Rollover {
+    max_file_bytes: 1 << 20,
+    max_file_age: Duration::from_secs(3600),
+};
+
+/// Every record captured into `directory`, once there are `count` of them
+fn wait_for_records(directory: &Path, count: usize) -> cabbage::Result<Vec<Record>> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut records = vec![];
+        for entry in std::fs::read_dir(directory)? {
+            for record in CaptureReader::open(entry?.path())? {
+                records.push(record?);
+            }
+        }
+        if records.len() >= count || Instant::now() >= deadline {
+            return Ok(records);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn frames(records: &[Record], direction: Direction) -> Vec<BytesFrame> {
+    records
+        .iter()
+        .filter(|record| record.direction == direction)
+        .map(|record| record.frame.clone())
+        .collect()
+}
+
+#[test]
+fn records_read_back_as_written() -> cabbage::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let capture = Capture::start(directory.path(), ROLLOVER)?;
+    let connection_id = Uuid::new_v4();
+    let request = command::request(["SET", "greeting", "hello"]);
+    let response = BytesFrame::SimpleString("OK".into());
+    capture.record(connection_id, Direction::Request, &request);
+    capture.record(connection_id, Direction::Response, &response);
+    drop(capture);
+
+    let records = wait_for_records(directory.path(), 2)?;
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|r| r.connection_id == connection_id));
+    assert_eq!(frames(&records, Direction::Request), [request]);
+    assert_eq!(frames(&records, Direction::Response), [response]);
+    Ok(())
+}
+
+#[test]
+fn files_without_the_magic_are_refused() {
+    let input: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+    assert!(CaptureReader::new(input).is_err());
+}
+
+#[tokio::test]
+async fn proxied_traffic_is_captured_with_credentials_redacted() -> cabbage::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let capture = Capture::start(directory.path(), ROLLOVER)?;
+    let mock = MockRedis::new();
+    let proxy = TestProxy::start_with(
+        move |connection_id| {
+            let service = CaptureLayer::new(capture.clone(), connection_id).layer(mock.clone());
+            async move { Ok(service) }
+        },
+        ServeOptions::default(),
+    )
+    .await?;
+    let mut client = proxy.client().await?;
+    client
+        .call_one(command::request(["AUTH", "admin", "hunter2"]))
+        .await?;
+    client
+        .call_one(command::request(["SET", "greeting", "hello"]))
+        .await?;
+    let value = client
+        .call_one(command::request(["GET", "greeting"]))
+        .await?;
+    drop(client);
+    proxy.shutdown().await?;
+
+    let records = wait_for_records(directory.path(), 8)?;
+    let requests = frames(&records, Direction::Request);
+    let redacted = BytesFrame::BulkString(Bytes::from_static(b"<redacted>"));
+    assert!(requests.contains(&command::request(["SET", "greeting", "hello"])));
+    assert!(requests.contains(&command::request(["GET", "greeting"])));
+    let auth = requests
+        .iter()
+        .find(|req| command::name(req).as_deref() == Some("AUTH"))
+        .expect("AUTH was captured");
+    assert_eq!(
+        command::args(auth).map(|args| &args[1..]),
+        Some(&[redacted.clone(), redacted][..])
+    );
+    assert!(frames(&records, Direction::Response).contains(&value));
+    Ok(())
+}
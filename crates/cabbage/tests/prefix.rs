@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+
+use cabbage::command;
+use cabbage::error::Error;
+use cabbage::middleware::prefix::KeyPrefixLayer;
+use cabbage::middleware::reply;
+use cabbage::proxy::ServeOptions;
+use cabbage::service::{CallOne as _, ResponseStream};
+use cabbage::testing::{MockRedis, TestProxy};
+use redis_protocol::resp2::types::BytesFrame;
+use tokio_util::bytes::Bytes;
+use tower::{Layer as _, service_fn};
+
+fn bulk(s: &str) -> BytesFrame {
+    BytesFrame::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+}
+
+async fn prefixed_proxy(mock: &MockRedis) -> cabbage::Result<TestProxy> {
+    let mock = mock.clone();
+    TestProxy::start_with(
+        move |_| {
+            let service = KeyPrefixLayer::new("tenant:").layer(mock.clone());
+            async move { Ok(service) }
+        },
+        ServeOptions::default(),
+    )
+    .await
+}
+
+/// The request a `KeyPrefix` forwards for `req`, or its own reply if it refuses `req`
+async fn forwarded(req: BytesFrame) -> cabbage::Result<BytesFrame> {
+    let seen = Arc::new(Mutex::new(None));
+    let inner = service_fn({
+        let seen = seen.clone();
+        move |req: BytesFrame| {
+            *seen.lock().unwrap() = Some(req);
+            async { Ok::<ResponseStream, Error>(reply(BytesFrame::SimpleString("OK".into()))) }
+        }
+    });
+    let reply = KeyPrefixLayer::new("tenant:")
+        .layer(inner)
+        .call_one(req)
+        .await?;
+    let forwarded = seen.lock().unwrap().take();
+    Ok(forwarded.unwrap_or(reply))
+}
+
+#[tokio::test]
+async fn keys_are_stored_under_the_prefix() -> cabbage::Result<()> {
+    let mock = MockRedis::new();
+    let proxy = prefixed_proxy(&mock).await?;
+    let mut client = proxy.client().await?;
+
+    client
+        .call_one(command::request(["SET", "greeting", "hello"]))
+        .await?;
+    assert_eq!(
+        mock.get("tenant:greeting"),
+        Some(Bytes::from_static(b"hello"))
+    );
+    assert_eq!(mock.get("greeting"), None);
+
+    let value = client
+        .call_one(command::request(["GET", "greeting"]))
+        .await?;
+    assert_eq!(value, bulk("hello"));
+
+    proxy.shutdown().await
+}
+
+#[tokio::test]
+async fn keys_outside_the_prefix_are_unreachable() -> cabbage::Result<()> {
+    let mock = MockRedis::new();
+    mock.execute(&command::request(["SET", "secret", "hidden"]));
+    let proxy = prefixed_proxy(&mock).await?;
+    let mut client = proxy.client().await?;
+
+    let value = client.call_one(command::request(["GET", "secret"])).await?;
+    assert_eq!(value, BytesFrame::Null);
+    let deleted = client
+        .call_one(command::request(["DEL", "secret", "other"]))
+        .await?;
+    assert_eq!(deleted, BytesFrame::Integer(0));
+    assert_eq!(mock.get("secret"), Some(Bytes::from_static(b"hidden")));
+
+    proxy.shutdown().await
+}
+
+#[tokio::test]
+async fn commands_with_unknown_keys_are_refused() -> cabbage::Result<()> {
+    let mock = MockRedis::new();
+    let proxy = prefixed_proxy(&mock).await?;
+    let mut client = proxy.client().await?;
+
+    let reply = client
+        .call_one(command::request([
+            "MIGRATE", "other", "6379", "", "0", "5000", "KEYS", "secret",
+        ]))
+        .await?;
+    assert!(matches!(reply, BytesFrame::Error(_)), "{reply:?}");
+
+    proxy.shutdown().await
+}
+
+#[tokio::test]
+async fn commands_spanning_the_keyspace_are_refused() -> cabbage::Result<()> {
+    let requests = [
+        vec!["FLUSHDB"],
+        vec!["FLUSHALL", "ASYNC"],
+        vec!["SWAPDB", "0", "1"],
+        vec!["DBSIZE"],
+        vec!["RANDOMKEY"],
+        vec!["MOVE", "greeting", "1"],
+    ];
+    for request in requests {
+        let reply = forwarded(command::request(&request)).await?;
+        assert!(
+            matches!(reply, BytesFrame::Error(_)),
+            "{request:?}: {reply:?}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn every_key_of_multi_key_commands_is_prefixed() -> cabbage::Result<()> {
+    let cases = [
+        (
+            vec!["BITOP", "AND", "dest", "a", "b"],
+            vec!["BITOP", "AND", "tenant:dest", "tenant:a", "tenant:b"],
+        ),
+        (
+            vec!["ZUNIONSTORE", "dest", "2", "a", "b", "WEIGHTS", "1", "2"],
+            vec![
+                "ZUNIONSTORE",
+                "tenant:dest",
+                "2",
+                "tenant:a",
+                "tenant:b",
+                "WEIGHTS",
+                "1",
+                "2",
+            ],
+        ),
+        (
+            vec!["LMPOP", "2", "a", "b", "LEFT"],
+            vec!["LMPOP", "2", "tenant:a", "tenant:b", "LEFT"],
+        ),
+        (
+            vec!["XREAD", "COUNT", "1", "STREAMS", "a", "b", "0", "0"],
+            vec![
+                "XREAD", "COUNT", "1", "STREAMS", "tenant:a", "tenant:b", "0", "0",
+            ],
+        ),
+    ];
+    for (request, expected) in cases {
+        let forwarded = forwarded(command::request(&request)).await?;
+        assert_eq!(forwarded, command::request(&expected), "{request:?}");
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use std::net::SocketAddr;
+
+use cabbage::proxy_protocol::read_header;
+use tokio::io::AsyncReadExt as _;
+
+static V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(version_command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+}
+
+#[tokio::test]
+async fn v1_tcp4_header_gives_the_source() -> cabbage::Result<()> {
+    let mut input: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6379\r\nPING\r\n";
+    let source = read_header(&mut input).await?;
+    assert_eq!(
+        source,
+        Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap())
+    );
+
+    // The client's traffic is left unread behind the header
+    let mut rest = String::new();
+    input.read_to_string(&mut rest).await?;
+    assert_eq!(rest, "PING\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn v1_tcp6_header_gives_the_source() -> cabbage::Result<()> {
+    let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 6379\r\n";
+    let source = read_header(&mut input).await?;
+    assert_eq!(
+        source,
+        Some("[2001:db8::1]:4000".parse::<SocketAddr>().unwrap())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn v1_unknown_header_keeps_the_peer() -> cabbage::Result<()> {
+    let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_header(&mut input).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn malformed_v1_headers_are_rejected() {
+    let headers: [&[u8]; 4] = [
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+        b"PROXY TCP4 2001:db8::1 2001:db8::2 4000 6379\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 port 6379\r\n",
+        b"*1\r\n$4\r\nPING\r\n",
+    ];
+    for mut input in headers {
+        assert!(read_header(&mut input).await.is_err(), "{input:?}");
+    }
+}
+
+#[tokio::test]
+async fn v1_header_without_an_end_is_rejected() {
+    let mut header = b"PROXY TCP4 ".to_vec();
+    header.resize(200, b'1');
+    assert!(read_header(&mut header.as_slice()).await.is_err());
+}
+
+#[tokio::test]
+async fn v2_tcp4_header_gives_the_source() -> cabbage::Result<()> {
+    let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+    addresses.extend_from_slice(&56324u16.to_be_bytes());
+    addresses.extend_from_slice(&6379u16.to_be_bytes());
+    let mut header = v2_header(0x21, 0x11, &addresses);
+    header.extend_from_slice(b"PING\r\n");
+
+    let mut input = header.as_slice();
+    let source = read_header(&mut input).await?;
+    assert_eq!(
+        source,
+        Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap())
+    );
+    assert_eq!(input, b"PING\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn v2_tcp6_header_gives_the_source() -> cabbage::Result<()> {
+    let source: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+    let destination: std::net::Ipv6Addr = "2001:db8::2".parse().unwrap();
+    let mut addresses = source.octets().to_vec();
+    addresses.extend_from_slice(&destination.octets());
+    addresses.extend_from_slice(&4000u16.to_be_bytes());
+    addresses.extend_from_slice(&6379u16.to_be_bytes());
+    let header = v2_header(0x21, 0x21, &addresses);
+
+    let source = read_header(&mut header.as_slice()).await?;
+    assert_eq!(
+        source,
+        Some("[2001:db8::1]:4000".parse::<SocketAddr>().unwrap())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn v2_local_header_keeps_the_peer() -> cabbage::Result<()> {
+    let header = v2_header(0x20, 0x00, &[]);
+    assert_eq!(read_header(&mut header.as_slice()).await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn malformed_v2_headers_are_rejected() {
+    let headers = [
+        // Version 1 in a binary header
+        v2_header(0x11, 0x11, &[0; 12]),
+        // An unknown command
+        v2_header(0x22, 0x11, &[0; 12]),
+        // Too short for TCP over IPv4
+        v2_header(0x21, 0x11, &[0; 4]),
+    ];
+    for header in headers {
+        assert!(
+            read_header(&mut header.as_slice()).await.is_err(),
+            "{header:?}"
+        );
+    }
+}
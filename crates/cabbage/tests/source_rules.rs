@@ -0,0 +1,107 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use cabbage::listener::{IpNetwork, SourceRules};
+use cabbage::proxy::{ServeOptions, serve_with};
+use cabbage::stats::Stats;
+use cabbage::testing::MockRedis;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+
+fn networks(networks: &[&str]) -> Vec<IpNetwork> {
+    networks.iter().map(|n| n.parse().unwrap()).collect()
+}
+
+/// What a client sending `preamble` then a `PING` hears back from the proxy serving as
+/// `options` say, which is nothing if the proxy closes (or resets) the connection
+async fn ping(options: ServeOptions, preamble: &[u8]) -> cabbage::Result<Vec<u8>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let shutdown = options.shutdown_token();
+    let mock = MockRedis::new();
+    let serving = tokio::spawn(serve_with(
+        listener,
+        move |_, _| {
+            let mock = mock.clone();
+            async move { Ok(mock) }
+        },
+        options,
+    ));
+
+    let mut reply = vec![];
+    let exchange = async {
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(preamble).await?;
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+        let mut buffer = [0; 64];
+        while !reply.ends_with(b"\r\n") {
+            match client.read(&mut buffer).await? {
+                0 => break,
+                read => reply.extend_from_slice(&buffer[..read]),
+            }
+        }
+        std::io::Result::Ok(())
+    };
+    match tokio::time::timeout(Duration::from_secs(5), exchange).await {
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionReset => reply.clear(),
+        Ok(result) => result?,
+        Err(_) => panic!("the proxy neither answered nor closed the connection"),
+    }
+
+    shutdown.cancel();
+    serving.await.expect("the proxy task panicked")?;
+    Ok(reply)
+}
+
+#[tokio::test]
+async fn allowed_sources_are_served() -> cabbage::Result<()> {
+    let rules = SourceRules::default().allow(networks(&["127.0.0.0/8"]));
+    let reply = ping(ServeOptions::default().with_source_rules(rules), b"").await?;
+    assert_eq!(reply, b"+PONG\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn denied_and_unlisted_sources_are_closed_and_counted() -> cabbage::Result<()> {
+    let rules = [
+        SourceRules::default().deny(networks(&["127.0.0.1/32"])),
+        SourceRules::default().allow(networks(&["192.0.2.0/24"])),
+        SourceRules::default()
+            .allow(networks(&["127.0.0.0/8"]))
+            .deny(networks(&["127.0.0.1/32"])),
+    ];
+    for rules in rules {
+        let stats = Stats::new();
+        let options = ServeOptions::default()
+            .with_source_rules(rules)
+            .with_stats(stats.clone());
+        assert_eq!(ping(options, b"").await?, b"");
+        assert_eq!(stats.connections.refused(), 1);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn rules_apply_to_the_source_a_trusted_balancer_names() -> cabbage::Result<()> {
+    let options = || {
+        ServeOptions::default()
+            .with_proxy_protocol(networks(&["127.0.0.0/8"]))
+            .with_source_rules(SourceRules::default().deny(networks(&["192.0.2.0/24"])))
+    };
+    let denied = b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 6379\r\n";
+    assert_eq!(ping(options(), denied).await?, b"");
+    let allowed = b"PROXY TCP4 198.51.100.1 127.0.0.1 56324 6379\r\n";
+    assert_eq!(ping(options(), allowed).await?, b"+PONG\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn headers_from_untrusted_peers_are_refused() -> cabbage::Result<()> {
+    let options = ServeOptions::default()
+        .with_proxy_protocol(networks(&["192.0.2.0/24"]))
+        .with_source_rules(SourceRules::default().allow(networks(&["198.51.100.0/24"])));
+    // Claiming to be an allowed client doesn't help a peer which isn't a trusted balancer
+    let header = b"PROXY TCP4 198.51.100.1 127.0.0.1 56324 6379\r\n";
+    assert_eq!(ping(options, header).await?, b"");
+    Ok(())
+}